@@ -0,0 +1,99 @@
+// Per-peer phase of the route computation for a large mesh, sequential vs parallel.
+//
+//     cargo bench --bench route_computation [-- <peers> <routes per peer>]
+//
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+use wg_netmanager::configuration::RouteMetric;
+use wg_netmanager::route_engine::*;
+use wg_netmanager::routedb::RouteInfo;
+
+const ROUNDS: u32 = 10;
+
+fn ip(n: u32) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n))
+}
+
+// Each peer offers the same destinations, half of them via a distant gateway
+fn route_dbs(nr_peers: u32, nr_routes: u32) -> Vec<HashMap<IpAddr, RouteInfo>> {
+    (0..nr_peers)
+        .map(|peer| {
+            (0..nr_routes)
+                .map(|n| {
+                    let to = ip(1000 + n);
+                    let ri = RouteInfo {
+                        to,
+                        local_admin_port: 50000,
+                        hop_cnt: ((n + peer) % 5) as usize,
+                        gateway: (n % 2 == 0).then(|| ip(1000 + (n + 1) % nr_routes)),
+                        area_summary: None,
+                        exported_nets: vec![],
+                        cost: (n * peer) % 100,
+                        bandwidth_mbit: None,
+                    };
+                    (to, ri)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn measure(ctx: &RouteContext, peers: &[PeerRoutes], parallel: bool) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        std::hint::black_box(all_candidates(ctx, peers, parallel));
+    }
+    start.elapsed() / ROUNDS
+}
+
+fn main() {
+    let args = std::env::args()
+        .skip(1)
+        .filter_map(|arg| arg.parse::<u32>().ok())
+        .collect::<Vec<_>>();
+    let nr_peers = args.first().copied().unwrap_or(50);
+    let nr_routes = args.get(1).copied().unwrap_or(5000);
+
+    let dbs = route_dbs(nr_peers, nr_routes);
+    let peers = dbs
+        .iter()
+        .enumerate()
+        .map(|(i, route_for)| PeerRoutes {
+            wg_ip: ip(i as u32 + 1),
+            link_cost: 10,
+            link_bandwidth: None,
+            tier: 1,
+            route_for,
+        })
+        .collect::<Vec<_>>();
+    let areas = BTreeMap::new();
+    let ctx = RouteContext {
+        wg_ip: ip(0),
+        metric: RouteMetric::Hops,
+        my_area: None,
+        use_areas: false,
+        areas: &areas,
+        subnet: "10.0.0.0/8".parse().unwrap(),
+        my_exported_nets: &[],
+        distant_nodes: (1000..1000 + nr_routes).map(ip).collect::<HashSet<_>>(),
+    };
+
+    // Same candidates in the same order
+    assert_eq!(
+        all_candidates(&ctx, &peers, false),
+        all_candidates(&ctx, &peers, true)
+    );
+    let sequential = measure(&ctx, &peers, false);
+    let parallel = measure(&ctx, &peers, true);
+    println!(
+        "{} peers with {} routes each on {} threads: sequential {:?}, parallel {:?}, speedup {:.2}",
+        nr_peers,
+        nr_routes,
+        rayon::current_num_threads(),
+        sequential,
+        parallel,
+        sequential.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
@@ -0,0 +1,209 @@
+// Automatic assignment of the wg_ip with `wgIp: auto`.
+//
+// A new node only needs network.yaml. Before the interface is set up, it sends an
+// AddressRequest to the endpoints of the static peers and uses the first AddressLease.
+// A static peer offers the address, which it has leased to the identity of the node
+// before. Otherwise the first free address of the subnet from a position derived from
+// the identity, so all static peers usually offer the same address. Never offered are
+// the addresses of the static peers, of known nodes with another identity and of the
+// leases of other identities.
+//
+// The node renews the lease via the tunnels after half of LEASE_TIME. If its address
+// is in conflict by now, the static peer offers another one and the node changes its
+// wg_ip like on reload of peer.yaml, see crate::readdress. With an identity file, a
+// restarted node gets the same address again.
+//
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use ipnet::IpNet;
+use log::*;
+
+use crate::configuration::{is_host_address, PublicPeer, StaticConfiguration};
+use crate::crypt_udp::*;
+use crate::error::*;
+use crate::identity::PublicIdentity;
+use crate::link_local::scoped;
+use crate::readdress::readdress;
+
+pub const LEASE_TIME: u64 = 3600;
+const REQUEST_ROUNDS: usize = 5;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+// Limits the search for a free address in large ipv6 subnets
+const MAX_PROBES: u128 = 65536;
+
+struct Lease {
+    identity: PublicIdentity,
+    until: u64,
+}
+
+// The leases handed out by a static peer
+#[derive(Default)]
+pub struct AddressLeases {
+    leases: HashMap<IpAddr, Lease>,
+}
+impl AddressLeases {
+    // in_use() is true for addresses of nodes with another identity
+    pub fn offer<F: Fn(&IpAddr) -> bool>(
+        &mut self,
+        now: u64,
+        subnet: &IpNet,
+        request: &AddressRequestPacket,
+        in_use: F,
+    ) -> Option<AddressLeasePacket> {
+        self.leases.retain(|_, lease| now < lease.until);
+        let free = |wg_ip: &IpAddr| {
+            is_host_address(subnet, wg_ip)
+                && !in_use(wg_ip)
+                && self
+                    .leases
+                    .get(wg_ip)
+                    .map(|lease| lease.identity == request.identity)
+                    .unwrap_or(true)
+        };
+        let previous = self
+            .leases
+            .iter()
+            .find(|(_, lease)| lease.identity == request.identity)
+            .map(|(wg_ip, _)| *wg_ip);
+        let wg_ip = request
+            .requested
+            .filter(free)
+            .or_else(|| previous.filter(free))
+            .or_else(|| candidates(subnet, &request.identity).find(free))?;
+
+        self.leases
+            .retain(|_, lease| lease.identity != request.identity);
+        self.leases.insert(
+            wg_ip,
+            Lease {
+                identity: request.identity,
+                until: now + LEASE_TIME,
+            },
+        );
+        Some(AddressLeasePacket {
+            identity: request.identity,
+            wg_ip,
+            lease_time: LEASE_TIME,
+        })
+    }
+}
+
+// The addresses of the subnet starting at a position given by the identity
+fn candidates<'a>(
+    subnet: &'a IpNet,
+    identity: &PublicIdentity,
+) -> impl Iterator<Item = IpAddr> + 'a {
+    let host_bits = u32::from(subnet.max_prefix_len() - subnet.prefix_len());
+    let size = 1u128.checked_shl(host_bits).unwrap_or(u128::MAX);
+    let mut seed = [0u8; 16];
+    seed.copy_from_slice(&identity[..16]);
+    let start = u128::from_le_bytes(seed) % size;
+    let network = match subnet.network() {
+        IpAddr::V4(ip) => u128::from(u32::from(ip)),
+        IpAddr::V6(ip) => u128::from(ip),
+    };
+    (0..size.min(MAX_PROBES)).map(move |i| {
+        let offset = (start + i) % size;
+        match subnet {
+            IpNet::V4(_) => IpAddr::V4(Ipv4Addr::from((network + offset) as u32)),
+            IpNet::V6(_) => IpAddr::V6(Ipv6Addr::from(network + offset)),
+        }
+    })
+}
+
+// The renewal is due after half of the lease time
+pub fn renewal_due(now: u64, static_config: &StaticConfiguration) -> bool {
+    static_config
+        .wg_ip_lease
+        .map(|until| now + LEASE_TIME / 2 >= until)
+        .unwrap_or(false)
+}
+
+pub fn renewal_request(static_config: &StaticConfiguration) -> UdpPacket {
+    UdpPacket::AddressRequest(AddressRequestPacket {
+        identity: static_config.identity.public(),
+        requested: Some(static_config.wg_ip),
+    })
+}
+
+// Returns the previous wg_ip, if the lease is for another address
+pub fn apply_lease(
+    now: u64,
+    static_config: &mut StaticConfiguration,
+    lease: &AddressLeasePacket,
+) -> BoxResult<Option<IpAddr>> {
+    if static_config.wg_ip_lease.is_none() || lease.identity != static_config.identity.public() {
+        return Ok(None);
+    }
+    let previous = if lease.wg_ip == static_config.wg_ip {
+        None
+    } else {
+        Some(readdress(now, static_config, lease.wg_ip)?)
+    };
+    static_config.wg_ip_lease = Some(now + lease.lease_time);
+    Ok(previous)
+}
+
+// Ask the static peers for an address until the first one answers
+pub fn request_lease(
+    peers: &HashMap<IpAddr, PublicPeer>,
+    shared_key: &[u8],
+    identity: &PublicIdentity,
+) -> BoxResult<AddressLeasePacket> {
+    if peers.is_empty() {
+        return strerror("wgIp auto needs static peers");
+    }
+    let mut sockets = vec![];
+    for unspecified in [
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    ] {
+        if let Ok(socket) = CryptUdp::bind(unspecified, 0) {
+            let socket = socket.key(shared_key)?;
+            socket.set_read_timeout(Some(REQUEST_TIMEOUT / 2))?;
+            sockets.push(socket);
+        }
+    }
+    let request = UdpPacket::AddressRequest(AddressRequestPacket {
+        identity: *identity,
+        requested: None,
+    });
+    let buf = bincode::serialize(&request).unwrap();
+
+    for _ in 0..REQUEST_ROUNDS {
+        for peer in peers.values() {
+            let ips = match peer.resolve_endpoint() {
+                Ok(ips) => ips,
+                Err(e) => {
+                    warn!(target: "lease", "Cannot resolve {}: {}", peer.endpoint, e);
+                    continue;
+                }
+            };
+            for ip in ips {
+                let destination = scoped(ip, peer.admin_port, peer.scope_id());
+                if let Some(socket) = sockets.iter_mut().find(
+                    |socket| matches!(socket.local_addr(), Ok(sa) if sa.is_ipv4() == ip.is_ipv4()),
+                ) {
+                    debug!(target: "lease", "Send AddressRequest to {}", destination);
+                    socket.send_to(&buf, destination)?;
+                }
+            }
+        }
+        let mut buf = [0; MAX_PACKET_SIZE];
+        for socket in sockets.iter() {
+            while let Ok((received, src_addr)) = socket.recv_from(&mut buf) {
+                match UdpPacket::decode(&buf[..received]) {
+                    Ok(UdpPacket::AddressLease(lease)) if lease.identity == *identity => {
+                        info!(target: "lease", "Leased {} for {}s from {}", lease.wg_ip, lease.lease_time, src_addr);
+                        return Ok(lease);
+                    }
+                    Ok(_) => {}
+                    Err(e) => debug!(target: "lease", "Error in decode: {:?}", e),
+                }
+            }
+        }
+    }
+    strerror("No static peer has offered an address")
+}
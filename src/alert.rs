@@ -0,0 +1,309 @@
+// Detection of abnormal conditions of the mesh.
+//
+// The AlertManager is fed with the MeshEvents of the NetworkManager and checks
+// the state of the NetworkManager every second. An alert stays active as long as
+// its condition holds. Raising and clearing is logged, the active alerts are shown
+// in a dedicated pane of the tui, so they do not get lost in the scrolled log.
+//
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+
+use log::*;
+
+use crate::manager::NetworkManager;
+use crate::mesh_event::MeshEvent;
+
+// No static peer is reachable for this long
+const PARTITION_GRACE: u64 = 30;
+// A gateway is the next hop for more than this number of nodes
+const GATEWAY_OVERLOAD: usize = 32;
+// Window for counting key rotations, decrypt failures and route changes
+const WINDOW: u64 = 60;
+// Two nodes with the same wg_ip make the public key flap
+const KEY_CONFLICT_ROTATIONS: usize = 3;
+const DECRYPT_FAILURE_STORM: u64 = 100;
+const ROUTE_CHURN: usize = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    Partitioned,
+    GatewayOverloaded(IpAddr),
+    KeyConflict(IpAddr),
+    WgIpConflict(IpAddr),
+    JoinPending,
+    DecryptFailureStorm,
+    RouteChurn,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub severity: Severity,
+    pub message: String,
+    // Unix time, when the alert has been raised
+    pub since: u64,
+}
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.severity, self.message)
+    }
+}
+
+#[derive(Default)]
+pub struct AlertManager {
+    active: HashMap<AlertKind, Alert>,
+    no_static_peer_since: Option<u64>,
+    key_rotations: HashMap<IpAddr, VecDeque<u64>>,
+    route_changes: VecDeque<u64>,
+    // Start of the current window and total decrypt failures at that time
+    decrypt_window: Option<(u64, u64)>,
+    raised: Vec<Alert>,
+}
+impl AlertManager {
+    pub fn new() -> Self {
+        AlertManager::default()
+    }
+    pub fn process_mesh_event(&mut self, now: u64, evt: &MeshEvent) {
+        match evt {
+            MeshEvent::KeyRotated { wg_ip, .. } => {
+                self.key_rotations.entry(*wg_ip).or_default().push_back(now);
+            }
+            MeshEvent::RouteAdded { .. }
+            | MeshEvent::RouteReplaced { .. }
+            | MeshEvent::RouteRemoved { .. } => {
+                self.route_changes.push_back(now);
+            }
+            _ => {}
+        }
+    }
+    // Called every second. Returns the newly raised alerts.
+    pub fn check(&mut self, now: u64, network_manager: &NetworkManager) -> Vec<Alert> {
+        let peers = network_manager.peer_infos(now);
+
+        let has_static_peers = peers.iter().any(|p| p.endpoint_class == "static");
+        let static_peer_alive = peers
+            .iter()
+            .any(|p| p.endpoint_class == "static" && p.alive);
+        if has_static_peers && !static_peer_alive {
+            let since = *self.no_static_peer_since.get_or_insert(now);
+            self.set(
+                now,
+                AlertKind::Partitioned,
+                now >= since + PARTITION_GRACE,
+                Severity::Critical,
+                || format!("No static peer reachable for {}s", now - since),
+            );
+        } else {
+            self.no_static_peer_since = None;
+            self.set(
+                now,
+                AlertKind::Partitioned,
+                false,
+                Severity::Critical,
+                String::new,
+            );
+        }
+
+        let mut load: HashMap<IpAddr, usize> = HashMap::new();
+        for ri in network_manager.routes() {
+            if let Some(gateway) = ri.gateway {
+                *load.entry(gateway).or_default() += 1;
+            }
+        }
+        let overloaded = self
+            .active
+            .keys()
+            .filter_map(|kind| match kind {
+                AlertKind::GatewayOverloaded(gateway) => Some(*gateway),
+                _ => None,
+            })
+            .chain(load.keys().cloned())
+            .collect::<Vec<_>>();
+        for gateway in overloaded {
+            let cnt = load.get(&gateway).cloned().unwrap_or(0);
+            self.set(
+                now,
+                AlertKind::GatewayOverloaded(gateway),
+                cnt > GATEWAY_OVERLOAD,
+                Severity::Warning,
+                || format!("Gateway {} relays for {} nodes", gateway, cnt),
+            );
+        }
+
+        expire(&mut self.route_changes, now);
+        let cnt = self.route_changes.len();
+        self.set(
+            now,
+            AlertKind::RouteChurn,
+            cnt > ROUTE_CHURN,
+            Severity::Warning,
+            || format!("{} route changes within {}s", cnt, WINDOW),
+        );
+
+        let mut conflicts = vec![];
+        self.key_rotations.retain(|wg_ip, rotations| {
+            expire(rotations, now);
+            conflicts.push((*wg_ip, rotations.len()));
+            !rotations.is_empty()
+        });
+        for kind in self.active.keys() {
+            if let AlertKind::KeyConflict(wg_ip) = kind {
+                if !self.key_rotations.contains_key(wg_ip) {
+                    conflicts.push((*wg_ip, 0));
+                }
+            }
+        }
+        for (wg_ip, cnt) in conflicts {
+            self.set(
+                now,
+                AlertKind::KeyConflict(wg_ip),
+                cnt >= KEY_CONFLICT_ROTATIONS,
+                Severity::Critical,
+                || {
+                    format!(
+                        "Public key of {} changed {} times within {}s, wg_ip used twice ?",
+                        wg_ip, cnt, WINDOW
+                    )
+                },
+            );
+        }
+
+        let wg_ip_conflicts = network_manager.wg_ip_conflicts();
+        let conflicts = self
+            .active
+            .keys()
+            .filter_map(|kind| match kind {
+                AlertKind::WgIpConflict(wg_ip) => Some(*wg_ip),
+                _ => None,
+            })
+            .chain(wg_ip_conflicts.keys().cloned())
+            .collect::<Vec<_>>();
+        for wg_ip in conflicts {
+            let conflict = wg_ip_conflicts.get(&wg_ip);
+            self.set(
+                now,
+                AlertKind::WgIpConflict(wg_ip),
+                conflict.is_some(),
+                Severity::Critical,
+                || {
+                    format!(
+                        "wg_ip {} is used by two nodes, key {} is quarantined",
+                        wg_ip,
+                        conflict
+                            .map(|c| c.quarantined_key.as_str())
+                            .unwrap_or_default()
+                    )
+                },
+            );
+        }
+
+        let pending = network_manager.pending_peers();
+        self.set(
+            now,
+            AlertKind::JoinPending,
+            !pending.is_empty(),
+            Severity::Warning,
+            || {
+                let names = pending
+                    .iter()
+                    .map(|p| format!("{} ({})", p.wg_ip, p.name))
+                    .collect::<Vec<_>>();
+                format!("Waiting for approval: {}", names.join(", "))
+            },
+        );
+
+        let decrypt_monitor = network_manager.decrypt_monitor();
+        let total = decrypt_monitor.total_failures();
+        let (start, start_total) = *self.decrypt_window.get_or_insert((now, total));
+        let failures = total - start_total;
+        let hints = decrypt_monitor.hints();
+        if failures > DECRYPT_FAILURE_STORM || !hints.is_empty() {
+            self.set(
+                now,
+                AlertKind::DecryptFailureStorm,
+                true,
+                Severity::Warning,
+                || {
+                    let peers = hints
+                        .iter()
+                        .map(|h| h.wg_ip.to_string())
+                        .collect::<Vec<_>>();
+                    if peers.is_empty() {
+                        format!("{} undecryptable packets within {}s", failures, WINDOW)
+                    } else {
+                        format!("Likely shared key mismatch with {}", peers.join(", "))
+                    }
+                },
+            );
+        }
+        if now >= start + WINDOW {
+            if failures <= DECRYPT_FAILURE_STORM && hints.is_empty() {
+                self.set(
+                    now,
+                    AlertKind::DecryptFailureStorm,
+                    false,
+                    Severity::Warning,
+                    String::new,
+                );
+            }
+            self.decrypt_window = Some((now, total));
+        }
+        std::mem::take(&mut self.raised)
+    }
+    fn set<F: FnOnce() -> String>(
+        &mut self,
+        now: u64,
+        kind: AlertKind,
+        condition: bool,
+        severity: Severity,
+        message: F,
+    ) {
+        if condition {
+            let message = message();
+            match self.active.get_mut(&kind) {
+                Some(alert) => alert.message = message,
+                None => {
+                    let alert = Alert {
+                        kind,
+                        severity,
+                        message,
+                        since: now,
+                    };
+                    warn!(target: "alert", "Raised {}", alert);
+                    self.raised.push(alert.clone());
+                    self.active.insert(kind, alert);
+                }
+            }
+        } else if let Some(alert) = self.active.remove(&kind) {
+            info!(target: "alert", "Cleared {}", alert);
+        }
+    }
+    // Most severe first, then the oldest
+    pub fn alerts(&self) -> Vec<&Alert> {
+        let mut alerts = self.active.values().collect::<Vec<_>>();
+        alerts.sort_by(|a, b| {
+            b.severity
+                .cmp(&a.severity)
+                .then(a.since.cmp(&b.since))
+                .then(a.message.cmp(&b.message))
+        });
+        alerts
+    }
+}
+
+fn expire(timestamps: &mut VecDeque<u64>, now: u64) {
+    while timestamps
+        .front()
+        .map(|t| t + WINDOW <= now)
+        .unwrap_or(false)
+    {
+        timestamps.pop_front();
+    }
+}
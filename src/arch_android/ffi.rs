@@ -0,0 +1,179 @@
+// Entry points for embedding wg_netmanager into an android app.
+//
+// The VpnService establishes the tun interface for the subnet and hands the detached
+// file descriptor to start(). The network manager then runs in its own thread until
+// stop() is called. Both are available as C functions and via JNI for this class:
+//
+//   package com.github.gin66.wgnetmanager;
+//   public class WgNetManager {
+//       public static native long start(String networkYaml, String name, String wgIp, int tunFd);
+//       public static native void stop(long handle);
+//   }
+//
+use std::ffi::CStr;
+use std::net::IpAddr;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::io::RawFd;
+use std::thread::JoinHandle;
+
+use jni::objects::{JClass, JString};
+use jni::sys::{jint, jlong};
+use jni::JNIEnv;
+use log::*;
+
+use crate::configuration::*;
+use crate::crypto::generate_key_pair;
+use crate::error::*;
+use crate::event::{channel, Event, EventSender};
+
+use super::WireguardDeviceAndroid;
+
+// Not configurable on android: the tun interface is named by the system
+const DEFAULT_WG_NAME: &str = "tun0";
+
+pub struct Handle {
+    tx: EventSender,
+    thread: Option<JoinHandle<()>>,
+}
+
+pub fn start(network_yaml: &str, name: &str, wg_ip: &str, tun_fd: RawFd) -> BoxResult<Handle> {
+    android_logger::init_once(
+        android_logger::Config::default()
+            .with_max_level(LevelFilter::Debug)
+            .with_tag("wg_netmanager"),
+    );
+
+    let network = NetworkConfiguration::from_yaml_str(network_yaml)?;
+    let wg_ip: IpAddr = wg_ip.parse()?;
+    if !network.subnet.contains(&wg_ip) {
+        return Err(format!("{} is outside of subnet {}", wg_ip, network.subnet).into());
+    }
+    let (wg_port, admin_port) = network
+        .ports_for(&wg_ip)
+        .ok_or("Explicit port allocation is not supported on android")?;
+    let wg_name = network
+        .name
+        .as_deref()
+        .map(interface_name_for_network)
+        .unwrap_or_else(|| DEFAULT_WG_NAME.to_string());
+
+    let (my_private_key, my_public_key) = generate_key_pair();
+    let static_config = StaticConfiguration::builder()
+        .name(name)
+        .ip_list(vec![])
+        .wg_ip(wg_ip)
+        .wg_name(wg_name.as_str())
+        .wg_port(wg_port)
+        .wg_hopping(false)
+        .admin_port(admin_port)
+        .subnet(network.subnet)
+        .shared_key(network.shared_key)
+        .accepted_shared_keys(network.accepted_shared_keys)
+        .my_public_key(PublicKeyWithTime {
+            key: my_public_key,
+            priv_key_creation_time: crate::util::now(),
+        })
+        .my_private_key(my_private_key)
+        .peers(network.peers)
+        .areas(network.areas)
+        .persistent_keepalive(network.persistent_keepalive)
+        .use_tui(false)
+        // The VpnService owns the interface
+        .use_existing_interface(true)
+        .network_yaml_filename("")
+        .network_name(network.name)
+        .build();
+
+    let (tx, rx) = channel();
+    let tx_loop = tx.clone();
+    let thread = std::thread::spawn(move || {
+        let wg_dev = WireguardDeviceAndroid::init(wg_name, tun_fd);
+        if let Err(e) =
+            crate::run_loop::run_with_channel(&static_config, Box::new(wg_dev), tx_loop, rx)
+        {
+            error!("{}", e);
+        }
+        info!("Network manager stopped");
+    });
+    Ok(Handle {
+        tx,
+        thread: Some(thread),
+    })
+}
+
+pub fn stop(mut handle: Handle) {
+    handle.tx.send(Event::CtrlC).ok();
+    if let Some(thread) = handle.thread.take() {
+        thread.join().ok();
+    }
+}
+
+/// # Safety
+///
+/// The strings need to be valid and nul terminated. The tun_fd is taken over.
+/// Returns null on error.
+#[no_mangle]
+pub unsafe extern "C" fn wg_netmanager_start(
+    network_yaml: *const c_char,
+    name: *const c_char,
+    wg_ip: *const c_char,
+    tun_fd: c_int,
+) -> *mut Handle {
+    let network_yaml = CStr::from_ptr(network_yaml).to_string_lossy();
+    let name = CStr::from_ptr(name).to_string_lossy();
+    let wg_ip = CStr::from_ptr(wg_ip).to_string_lossy();
+    match start(&network_yaml, &name, &wg_ip, tun_fd) {
+        Ok(handle) => Box::into_raw(Box::new(handle)),
+        Err(e) => {
+            error!("Cannot start: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// # Safety
+///
+/// The handle needs to be returned by wg_netmanager_start() and is invalid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wg_netmanager_stop(handle: *mut Handle) {
+    if !handle.is_null() {
+        stop(*Box::from_raw(handle));
+    }
+}
+
+// Throws IllegalArgumentException on error and returns 0
+#[no_mangle]
+pub extern "system" fn Java_com_github_gin66_wgnetmanager_WgNetManager_start(
+    mut env: JNIEnv,
+    _class: JClass,
+    network_yaml: JString,
+    name: JString,
+    wg_ip: JString,
+    tun_fd: jint,
+) -> jlong {
+    let rc = (|| -> BoxResult<Handle> {
+        let network_yaml: String = env.get_string(&network_yaml)?.into();
+        let name: String = env.get_string(&name)?.into();
+        let wg_ip: String = env.get_string(&wg_ip)?.into();
+        start(&network_yaml, &name, &wg_ip, tun_fd)
+    })();
+    match rc {
+        Ok(handle) => Box::into_raw(Box::new(handle)) as jlong,
+        Err(e) => {
+            error!("Cannot start: {}", e);
+            env.throw_new("java/lang/IllegalArgumentException", e.to_string())
+                .ok();
+            0
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_github_gin66_wgnetmanager_WgNetManager_stop(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) {
+    // Safety: the handle has been returned by start()
+    unsafe { wg_netmanager_stop(handle as *mut Handle) }
+}
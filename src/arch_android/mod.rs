@@ -1,7 +1,16 @@
-use std::net::IpAddr;
+pub mod ffi;
+mod wg_dev_android;
 
 use crate::arch_def::Architecture;
-use crate::wg_dev::*;
 
+pub use wg_dev_android::WireguardDeviceAndroid;
+
+// The wireguard device needs the tun file descriptor of the VpnService,
+// so it is created by ffi::start() and not via get_wg_dev().
 pub struct ArchitectureAndroid {}
-impl Architecture for ArchitectureAndroid {}
+impl Architecture for ArchitectureAndroid {
+    fn ipv4v6_socket_setup() -> (bool, bool, bool) {
+        // same as linux: ipv6 socket reads/sends ipv4 messages
+        (false, false, true)
+    }
+}
@@ -0,0 +1,139 @@
+// WireguardDevice on top of a tun interface, which is owned by an android VpnService.
+//
+// The app creates the tun interface with VpnService.Builder, which already sets the
+// address and the route for the subnet, and hands over the file descriptor.
+// Android does not allow to change the routes of the tun interface afterwards.
+// This is not needed anyway: all traffic for the subnet enters the tun and is
+// forwarded to the peer with the best matching allowed ips.
+//
+// There is no kernel wireguard, so the wireguard protocol is run in-process, see
+// crate::wg_userspace.
+//
+// Only the subnet is routed into the tun, so the udp socket does not need to be
+// protected with VpnService.protect().
+//
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use ipnet::{IpNet, Ipv6Net};
+use log::*;
+
+use crate::error::*;
+use crate::wg_dev::*;
+use crate::wg_userspace::UserspaceWireguard;
+
+pub struct WireguardDeviceAndroid {
+    device_name: String,
+    wireguard: UserspaceWireguard,
+}
+impl WireguardDeviceAndroid {
+    // Takes ownership of the tun file descriptor (ParcelFileDescriptor.detachFd())
+    pub fn init<T: Into<String>>(wg_name: T, tun_fd: RawFd) -> Self {
+        // Safety: the caller hands over an open file descriptor, which is not used elsewhere
+        let tun = unsafe { File::from_raw_fd(tun_fd) };
+        let device_name = wg_name.into();
+        WireguardDeviceAndroid {
+            wireguard: UserspaceWireguard::new(&device_name, tun),
+            device_name,
+        }
+    }
+}
+
+impl WireguardDevice for WireguardDeviceAndroid {
+    fn check_device(&self) -> BoxResult<bool> {
+        // The tun interface is created by the VpnService
+        Ok(true)
+    }
+    fn create_device(&self) -> BoxResult<()> {
+        debug!("Device {} is provided by the VpnService", self.device_name);
+        Ok(())
+    }
+    fn take_down_device(&self) -> BoxResult<()> {
+        debug!("Take down device");
+        self.wireguard.stop();
+        Ok(())
+    }
+    fn set_ip(
+        &mut self,
+        ip: &IpAddr,
+        subnet: &IpNet,
+        _probe_net: Option<Ipv6Net>,
+    ) -> BoxResult<()> {
+        // VpnService.Builder.addAddress()/addRoute() have to be called with these values
+        debug!(
+            "Device {} expected to be configured with {}/{}",
+            self.device_name,
+            ip,
+            subnet.prefix_len()
+        );
+        Ok(())
+    }
+    fn add_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        _attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        trace!(
+            "Route to {} via {:?} is covered by the subnet",
+            host,
+            gateway
+        );
+        Ok(())
+    }
+    fn replace_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        _attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        trace!(
+            "Route to {} via {:?} is covered by the subnet",
+            host,
+            gateway
+        );
+        Ok(())
+    }
+    fn del_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        _attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        trace!(
+            "Route to {} via {:?} is covered by the subnet",
+            host,
+            gateway
+        );
+        Ok(())
+    }
+    fn add_net_route(&self, net: IpNet) -> BoxResult<()> {
+        // The app has to add the route with VpnService.Builder.addRoute()
+        debug!("Route to {} needs to be added to the VpnService", net);
+        Ok(())
+    }
+    fn del_net_route(&self, net: IpNet) -> BoxResult<()> {
+        trace!("Route to {} is removed with the VpnService", net);
+        Ok(())
+    }
+    fn flush_all(&self) -> BoxResult<()> {
+        Ok(())
+    }
+    fn set_conf(&self, conf: &str) -> BoxResult<()> {
+        self.wireguard.update_conf(conf, true)
+    }
+    fn sync_conf(&self, conf: &str) -> BoxResult<()> {
+        self.wireguard.update_conf(conf, false)
+    }
+    fn retrieve_conf(&self) -> BoxResult<HashMap<String, SocketAddr>> {
+        Ok(self.wireguard.endpoints())
+    }
+    fn latest_handshakes(&self) -> BoxResult<HashMap<String, u64>> {
+        Ok(self.wireguard.latest_handshakes())
+    }
+    fn transfer(&self) -> BoxResult<HashMap<String, (u64, u64)>> {
+        Ok(self.wireguard.transfer())
+    }
+}
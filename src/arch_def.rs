@@ -1,13 +1,36 @@
 use std::net::IpAddr;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 
 use clap::ArgMatches;
 
-use crate::configuration::StaticConfiguration;
+use crate::configuration::{Backend, StaticConfiguration};
 use crate::error::BoxResult;
-use crate::event::Event;
+use crate::event::EventSender;
 use crate::wg_dev::WireguardDevice;
 
+// Creates the device for an interface name
+pub type BackendFactory = Arc<dyn Fn(&str) -> BoxResult<Box<dyn WireguardDevice>> + Send + Sync>;
+
+// Backends replaced within this process, e.g. by a mock in tests
+static REGISTRY: Mutex<Vec<(Backend, BackendFactory)>> = Mutex::new(Vec::new());
+
+pub fn register_backend(backend: Backend, factory: BackendFactory) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.retain(|(b, _)| *b != backend);
+    registry.push((backend, factory));
+}
+pub fn unregister_backend(backend: Backend) {
+    REGISTRY.lock().unwrap().retain(|(b, _)| *b != backend);
+}
+pub fn registered_backend(backend: Backend) -> Option<BackendFactory> {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(b, _)| *b == backend)
+        .map(|(_, factory)| factory.clone())
+}
+
 pub trait Architecture {
     fn default_path_to_network_yaml() -> &'static str {
         "network.yaml"
@@ -21,13 +44,49 @@ pub trait Architecture {
     fn get_local_interfaces() -> Vec<IpAddr> {
         vec![]
     }
+    // The interface indices with a link-local ipv6 address
+    fn get_link_scope_ids() -> Vec<u32> {
+        vec![]
+    }
     #[allow(unused_variables)]
-    fn arch_specific_init(tx: mpsc::Sender<Event>) {}
+    fn arch_specific_init(tx: EventSender) {}
     #[allow(unused_variables)]
     fn get_wg_dev<T: Into<String>>(wg_name: T) -> Box<dyn WireguardDevice> {
         unimplemented!();
     }
     #[allow(unused_variables)]
+    fn get_kernel_wg_dev<T: Into<String>>(wg_name: T) -> BoxResult<Box<dyn WireguardDevice>> {
+        Err("The kernel backend is not available on this platform".into())
+    }
+    #[allow(unused_variables)]
+    fn get_wg_quick_wg_dev<T: Into<String>>(wg_name: T) -> BoxResult<Box<dyn WireguardDevice>> {
+        Err("The wg-quick backend is not available on this platform".into())
+    }
+    #[allow(unused_variables)]
+    fn get_userspace_wg_dev<T: Into<String>>(wg_name: T) -> BoxResult<Box<dyn WireguardDevice>> {
+        Err("The userspace backend is not available on this platform".into())
+    }
+    // A registered backend takes precedence over the built-in one
+    fn get_wg_dev_for_backend<T: Into<String>>(
+        wg_name: T,
+        backend: Backend,
+    ) -> BoxResult<Box<dyn WireguardDevice>> {
+        if let Some(factory) = registered_backend(backend) {
+            return factory(&wg_name.into());
+        }
+        match backend {
+            // The device of an existing interface is not created
+            Backend::Auto | Backend::Existing => Ok(Self::get_wg_dev(wg_name)),
+            Backend::Kernel => Self::get_kernel_wg_dev(wg_name),
+            Backend::WgQuick => Self::get_wg_quick_wg_dev(wg_name),
+            Backend::Userspace => Self::get_userspace_wg_dev(wg_name),
+        }
+    }
+    #[allow(unused_variables)]
+    fn drop_privileges(user: &str, keep_net_admin: bool) -> BoxResult<()> {
+        Err("Dropping privileges is not supported on this platform".into())
+    }
+    #[allow(unused_variables)]
     fn command_install(matches: &ArgMatches, static_config: StaticConfiguration) -> BoxResult<()> {
         unimplemented!();
     }
@@ -0,0 +1,88 @@
+// Keep CAP_NET_ADMIN over dropping the root privileges.
+//
+// With PR_SET_KEEPCAPS the permitted capabilities survive setuid(). Afterwards all
+// capabilities except CAP_NET_ADMIN are dropped. CAP_NET_ADMIN is raised as ambient
+// capability, so the spawned ip and wg commands inherit it and sudo is not needed.
+// This needs linux 4.3 or later. Capabilities are per thread, so this is done on the
+// thread of the main loop, which spawns the commands.
+//
+use nix::libc;
+
+use crate::error::*;
+
+const CAP_NET_ADMIN: u32 = 12;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn last_os_error(what: &str) -> Box<dyn std::error::Error> {
+    format!("{}: {}", what, std::io::Error::last_os_error()).into()
+}
+
+pub fn keep_on_setuid() -> BoxResult<()> {
+    if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } != 0 {
+        return Err(last_os_error("Cannot keep the capabilities"));
+    }
+    Ok(())
+}
+
+// To be called after setuid()
+pub fn retain_net_admin() -> BoxResult<()> {
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    // CAP_NET_ADMIN is in the lower one of the two 32 bit sets
+    let mask = 1 << CAP_NET_ADMIN;
+    let data = [
+        CapUserData {
+            effective: mask,
+            permitted: mask,
+            inheritable: mask,
+        },
+        CapUserData::default(),
+    ];
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(last_os_error("Cannot retain CAP_NET_ADMIN"));
+    }
+    let raise = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_RAISE,
+            CAP_NET_ADMIN as libc::c_ulong,
+            0,
+            0,
+        )
+    };
+    if raise != 0 {
+        return Err(last_os_error("Cannot pass CAP_NET_ADMIN to the commands"));
+    }
+    unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) };
+    Ok(())
+}
+
+// True, if the spawned commands get CAP_NET_ADMIN without sudo
+pub fn has_ambient_net_admin() -> bool {
+    let is_set = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_IS_SET,
+            CAP_NET_ADMIN as libc::c_ulong,
+            0,
+            0,
+        )
+    };
+    is_set == 1
+}
@@ -2,6 +2,8 @@ use std::net::IpAddr;
 
 use log::*;
 
+use crate::link_local::{interface_index, needs_scope};
+
 pub fn get() -> Vec<IpAddr> {
     let ifaces = ifcfg::IfCfg::get().expect("could not get interfaces");
     let mut ip_list: Vec<IpAddr> = vec![];
@@ -26,3 +28,29 @@ pub fn get() -> Vec<IpAddr> {
     debug!("Interfaces: {:#?}", ip_list);
     ip_list
 }
+
+pub fn link_scope_ids() -> Vec<u32> {
+    let ifaces = match ifcfg::IfCfg::get() {
+        Ok(ifaces) => ifaces,
+        Err(e) => {
+            warn!("Cannot get interfaces: {:?}", e);
+            return vec![];
+        }
+    };
+    let mut scope_ids = ifaces
+        .iter()
+        .filter(|iface| {
+            iface.addresses.iter().any(|addr| {
+                addr.address
+                    .as_ref()
+                    .map(|sa| sa.is_ipv6() && needs_scope(&sa.ip()))
+                    .unwrap_or(false)
+            })
+        })
+        .filter_map(|iface| interface_index(&iface.name).ok())
+        .collect::<Vec<_>>();
+    scope_ids.sort_unstable();
+    scope_ids.dedup();
+    debug!("Interfaces with link-local address: {:?}", scope_ids);
+    scope_ids
+}
@@ -1,8 +1,12 @@
+mod capabilities;
 mod interfaces;
 mod wg_dev_linuxkernel;
+#[cfg(feature = "netlink")]
+mod wg_dev_netlink;
+#[cfg(feature = "userspace")]
+mod wg_dev_userspace;
 
 use std::net::IpAddr;
-use std::sync::mpsc;
 
 use clap::ArgMatches;
 use simple_signal::{self, Signal};
@@ -10,10 +14,11 @@ use simple_signal::{self, Signal};
 use crate::arch_def::Architecture;
 use crate::configuration::StaticConfiguration;
 use crate::error::BoxResult;
-use crate::event::Event;
+use crate::event::{Event, EventSender};
+use crate::systemd;
 use crate::wg_dev::WireguardDevice;
 
-use wg_dev_linuxkernel::WireguardDeviceLinux;
+use wg_dev_linuxkernel::{Creation, WireguardDeviceLinux};
 
 pub struct ArchitectureLinux {}
 impl Architecture for ArchitectureLinux {
@@ -30,9 +35,48 @@ impl Architecture for ArchitectureLinux {
     fn get_local_interfaces() -> Vec<IpAddr> {
         interfaces::get()
     }
+    fn get_link_scope_ids() -> Vec<u32> {
+        interfaces::link_scope_ids()
+    }
     fn get_wg_dev<T: Into<String>>(wg_name: T) -> Box<dyn WireguardDevice> {
+        let wg_name = wg_name.into();
+        #[cfg(feature = "netlink")]
+        match wg_dev_netlink::WireguardDeviceNetlink::init(wg_name.as_str()) {
+            Ok(wg_dev) => return Box::new(wg_dev),
+            Err(e) => {
+                log::warn!("netlink not usable ({}), fall back to ip/wg commands", e);
+            }
+        }
         Box::new(WireguardDeviceLinux::init(wg_name))
     }
+    fn get_kernel_wg_dev<T: Into<String>>(wg_name: T) -> BoxResult<Box<dyn WireguardDevice>> {
+        let wg_name = wg_name.into();
+        // netlink creates the device only via the kernel module
+        #[cfg(feature = "netlink")]
+        if let Ok(wg_dev) = wg_dev_netlink::WireguardDeviceNetlink::init(wg_name.as_str()) {
+            return Ok(Box::new(wg_dev));
+        }
+        Ok(Box::new(WireguardDeviceLinux::with_creation(
+            wg_name,
+            Creation::KernelModule,
+        )))
+    }
+    fn get_wg_quick_wg_dev<T: Into<String>>(wg_name: T) -> BoxResult<Box<dyn WireguardDevice>> {
+        Ok(Box::new(WireguardDeviceLinux::with_creation(
+            wg_name,
+            Creation::UserspaceBinary,
+        )))
+    }
+    #[cfg(feature = "userspace")]
+    fn get_userspace_wg_dev<T: Into<String>>(wg_name: T) -> BoxResult<Box<dyn WireguardDevice>> {
+        Ok(Box::new(wg_dev_userspace::WireguardDeviceUserspace::init(
+            wg_name,
+        )))
+    }
+    #[cfg(not(feature = "userspace"))]
+    fn get_userspace_wg_dev<T: Into<String>>(_wg_name: T) -> BoxResult<Box<dyn WireguardDevice>> {
+        Err("wg_netmanager is built without the feature userspace".into())
+    }
     fn command_install(matches: &ArgMatches, static_config: StaticConfiguration) -> BoxResult<()> {
         let kill_candidates = [
             "/run/current-system/sw/bin/kill",
@@ -41,48 +85,53 @@ impl Architecture for ArchitectureLinux {
         ];
         let kill_fname = kill_candidates
             .into_iter()
-            .filter(|fname| std::path::Path::new(fname).exists())
-            .collect::<Vec<_>>();
+            .find(|fname| std::path::Path::new(fname).exists());
+        let exe = std::env::current_exe()?;
+        let exe = exe
+            .to_str()
+            .ok_or("Path to executable is not valid unicode")?;
+
+        let content = systemd::unit_file(&static_config, exe, kill_fname);
+        let written = systemd::install_unit_file(
+            std::path::Path::new(systemd::UNIT_FILE),
+            &content,
+            matches.is_present("force"),
+            matches.is_present("dryRun"),
+        )?;
+        if written {
+            println!();
+            println!("Then execute:");
+            println!("    sudo systemctl daemon-reload");
+            println!("    sudo systemctl enable --now wg_netmanager");
+        }
+        Ok(())
+    }
+    fn drop_privileges(user: &str, keep_net_admin: bool) -> BoxResult<()> {
+        use nix::unistd::{setgid, setgroups, setuid, Uid, User};
+
+        if !Uid::effective().is_root() {
+            return Err("Dropping privileges requires to be started as root".into());
+        }
+        let user = User::from_name(user)?.ok_or(format!("Unknown user {}", user))?;
+
+        // Without, setuid() clears the permitted capabilities
+        if keep_net_admin {
+            capabilities::keep_on_setuid()?;
+        }
+        // Order is important: groups and gid can only be changed as long as being root
+        setgroups(&[user.gid])?;
+        setgid(user.gid)?;
+        setuid(user.uid)?;
+        if keep_net_admin {
+            capabilities::retain_net_admin()?;
+        }
 
-        let _ = matches.is_present("force");
-        let mut lines: Vec<String> = vec![];
-        lines.push(
-            "Copy the following lines to /etc/systemd/system/wg_netmanager.service".to_string(),
-        );
-        lines.push("#================================".to_string());
-        lines.push("[Unit]".to_string());
-        lines.push("Description= The Wireguard network manager".to_string());
-        lines.push(format!(
-            "ConditionPathExists={}",
-            static_config.network_yaml_filename
-        ));
-        if let Some(fname) = static_config.peer_yaml_filename.as_ref() {
-            lines.push(format!("ConditionPathExists={}", fname));
+        if setuid(Uid::from_raw(0)).is_ok() {
+            return Err("Could regain root privileges after dropping them".into());
         }
-        lines.push("After=network.target".to_string());
-        lines.push("".to_string());
-        lines.push("[Service]".to_string());
-        lines.push("Type=simple ".to_string());
-        lines.push(format!(
-            "ExecStart={}",
-            std::env::current_exe().unwrap().to_str().unwrap()
-        ));
-        lines.push(format!("ExecStop={} -HUP $MAINPID", kill_fname[0]));
-        lines.push("Restart=always".to_string());
-        lines.push("RestartSec=1".to_string());
-        lines.push("".to_string());
-        lines.push("[Install]".to_string());
-        lines.push("WantedBy=multi-user.target".to_string());
-        lines.push("#================================".to_string());
-        lines.push("".to_string());
-        lines.push("Then execute:".to_string());
-        lines.push("    sudo systemctl daemon-reload".to_string());
-        lines.push("    sudo systemctl enable wg_netmanager".to_string());
-        lines.push("".to_string());
-        println!("{}", lines.join("\n"));
         Ok(())
     }
-    fn arch_specific_init(tx: mpsc::Sender<Event>) {
+    fn arch_specific_init(tx: EventSender) {
         simple_signal::set_handler(&[Signal::Int, Signal::Term, Signal::Hup], move |_signals| {
             tx.send(Event::CtrlC).unwrap();
         });
@@ -1,23 +1,39 @@
 use std::collections::HashMap;
 use std::io::Write;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
 use std::process::{Command, Stdio};
 
-use ipnet::Ipv4Net;
+use ipnet::{IpNet, Ipv6Net};
 use log::*;
 
 use crate::error::*;
+use crate::userspace_process;
 use crate::wg_dev::*;
 
+// How create_device creates the interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Creation {
+    // The kernel module, or else wireguard-go or boringtun
+    Any,
+    KernelModule,
+    // $WG_QUICK_USERSPACE_IMPLEMENTATION like wg-quick, or else wireguard-go or boringtun
+    UserspaceBinary,
+}
+
 pub struct WireguardDeviceLinux {
     device_name: String,
-    ip: Ipv4Addr,
+    ip: IpAddr,
+    creation: Creation,
 }
 impl WireguardDeviceLinux {
     pub fn init<T: Into<String>>(wg_name: T) -> Self {
+        WireguardDeviceLinux::with_creation(wg_name, Creation::Any)
+    }
+    pub fn with_creation<T: Into<String>>(wg_name: T, creation: Creation) -> Self {
         WireguardDeviceLinux {
             device_name: wg_name.into(),
             ip: "0.0.0.0".parse().unwrap(),
+            creation,
         }
     }
     fn internal_execute_command(
@@ -26,9 +42,10 @@ impl WireguardDeviceLinux {
         input: Option<&str>,
     ) -> BoxResult<std::process::Output> {
         let mut args_with_sudo = vec![];
-        if !nix::unistd::getuid().is_root() {
+        if !nix::unistd::getuid().is_root() && !super::capabilities::has_ambient_net_admin() {
+            // An assignment on the command line needs SETENV in sudoers, so the
+            // variable of .env() below is passed on via env_keep instead
             args_with_sudo.push("sudo");
-            args_with_sudo.push("WG_I_PREFER_BUGGY_USERSPACE_TO_POLISHED_KMOD=1")
         }
         args_with_sudo.append(&mut args);
 
@@ -74,6 +91,38 @@ impl WireguardDeviceLinux {
             e
         })
     }
+    fn start_userspace(&self, binary: &str) -> BoxResult<()> {
+        let prefix = if nix::unistd::getuid().is_root() {
+            vec![]
+        } else {
+            vec!["sudo"]
+        };
+        userspace_process::start(binary, &self.device_name, &prefix, &|args, input| {
+            self.internal_execute_command(args, input)
+        })?;
+        Ok(())
+    }
+    fn ip_route(
+        &self,
+        cmd: &str,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        let host_net = host_net(host).to_string();
+        let mut args = vec!["ip", "route", cmd, &host_net];
+        let gateway = gateway.map(|gateway| gateway.to_string());
+        if let Some(gateway) = gateway.as_ref() {
+            args.extend(["via", gateway]);
+        }
+        args.extend(["dev", &self.device_name]);
+        let mtu = attributes.mtu.map(|mtu| mtu.to_string());
+        if let Some(mtu) = mtu.as_ref() {
+            args.extend(["mtu", "lock", mtu]);
+        }
+        self.execute_command(args, None)?;
+        Ok(())
+    }
     fn update_conf(&self, conf: &str, set_new: bool) -> BoxResult<()> {
         debug!(target: "wireguard", "Update configuration: {}", conf);
         let wg_cmd = if set_new { "setconf" } else { "syncconf" };
@@ -84,9 +133,9 @@ impl WireguardDeviceLinux {
         let fname = tmpfname.trim();
         trace!(target: "wireguard", "temp file {}", fname);
 
-        let _ = self.execute_command(vec!["tee", "-a", &*fname], Some(conf))?;
-        let _ = self.execute_command(vec!["wg", wg_cmd, &self.device_name, &*fname], None)?;
-        let _ = self.execute_command(vec!["rm", &*fname], None)?;
+        let _ = self.execute_command(vec!["tee", "-a", fname], Some(conf))?;
+        let _ = self.execute_command(vec!["wg", wg_cmd, &self.device_name, fname], None)?;
+        let _ = self.execute_command(vec!["rm", fname], None)?;
         Ok(())
     }
 }
@@ -101,59 +150,81 @@ impl WireguardDevice for WireguardDeviceLinux {
         //let kernel_unicast = netlink_sys::SocketAddr::new(0, 0);
         //let socket = netlink_sys::Socket::new(netlink_sys::protocols::NETLINK_AUDIT)?;
 
-        debug!("Create device via ip link add");
-        let mut result = self.execute_command(
-            vec!["ip", "link", "add", &self.device_name, "type", "wireguard"],
-            None,
-        );
+        let mut result = Err("no way to create the device".into());
+        if self.creation != Creation::UserspaceBinary {
+            debug!("Create device via ip link add");
+            result = self
+                .execute_command(
+                    vec!["ip", "link", "add", &self.device_name, "type", "wireguard"],
+                    None,
+                )
+                .map(|_| ());
+        }
 
-        if result.is_err() {
+        if result.is_err() && self.creation == Creation::UserspaceBinary {
+            if let Ok(binary) = std::env::var("WG_QUICK_USERSPACE_IMPLEMENTATION") {
+                debug!("Create device via {}", binary);
+                result = self
+                    .execute_command(vec![&binary, &self.device_name], None)
+                    .map(|_| ());
+            }
+        }
+
+        if result.is_err() && self.creation != Creation::KernelModule {
             // try wireguard-go
             debug!("Create device via wireguard-go");
             // Setting the environment variable works only with sudo
-            result = self.execute_command(vec!["wireguard-go", &self.device_name], None);
+            result = self.start_userspace("wireguard-go");
         }
 
-        if result.is_err() {
+        if result.is_err() && self.creation != Creation::KernelModule {
             // try boringtun
             debug!("Create device via boringtun");
-            result = self.execute_command(vec!["boringtun", &self.device_name], None);
+            result = self.start_userspace("boringtun");
         }
 
         if result.is_ok() {
             debug!("Interface {} created", self.device_name);
         }
 
-        result.map(|_| ())
+        result
     }
     fn take_down_device(&self) -> BoxResult<()> {
         debug!("Take down device");
         let _ = self.execute_command(vec!["ip", "link", "del", &self.device_name], None);
+        // Usually exits with the link, but not if it is hanging
+        userspace_process::stop(&self.device_name, &|args, input| {
+            self.internal_execute_command(args, input)
+        });
         debug!("Interface {} destroyed", self.device_name);
         Ok(())
     }
-    fn set_ip(&mut self, ip: &Ipv4Addr, subnet: &Ipv4Net) -> BoxResult<()> {
+    fn set_ip(&mut self, ip: &IpAddr, subnet: &IpNet, probe_net: Option<Ipv6Net>) -> BoxResult<()> {
         debug!("Set IP {}", ip);
         // The option noprefixroute of ip addr add would be ideal, but is not supported on older linux/ip
         self.ip = *ip;
         let ip_extend = format!("{}/{}", ip, subnet.prefix_len());
-        let ipv6_extend = format!("{}/{}", map_to_ipv6(ip), 96 + subnet.prefix_len());
+        let probe_extend = probe_net.map(|net| net.to_string());
         self.execute_command(
             vec!["ip", "addr", "add", &ip_extend, "dev", &self.device_name],
             None,
         )?;
-        self.execute_command(
-            vec!["ip", "addr", "add", &ipv6_extend, "dev", &self.device_name],
-            None,
-        )?;
+        if let Some(probe_extend) = probe_extend.as_ref() {
+            self.execute_command(
+                vec!["ip", "addr", "add", probe_extend, "dev", &self.device_name],
+                None,
+            )?;
+        }
 
         self.execute_command(vec!["ip", "link", "set", &self.device_name, "up"], None)?;
         debug!("Interface {} up", self.device_name);
 
-        self.execute_command(
-            vec!["ip", "route", "add", &ipv6_extend, "dev", &self.device_name],
-            None,
-        )?;
+        if let Some(probe_extend) = probe_extend.as_ref() {
+            self.execute_command(
+                vec!["ip", "route", "add", probe_extend, "dev", &self.device_name],
+                None,
+            )?;
+        }
 
         // This is allowed to fail
         let _ = self.execute_command(
@@ -171,58 +242,93 @@ impl WireguardDevice for WireguardDeviceLinux {
         debug!("Interface {} set ip", self.device_name);
         Ok(())
     }
-    fn add_route(&self, host: Ipv4Addr, gateway: Option<Ipv4Addr>) -> BoxResult<()> {
-        debug!("Set route to {} via {:?}", host, gateway);
-        if let Some(gateway) = gateway {
-            self.execute_command(
-                vec![
-                    "ip",
-                    "route",
-                    "add",
-                    &format!("{}/32", host),
-                    "via",
-                    &gateway.to_string(),
-                    "dev",
-                    &self.device_name,
-                ],
-                None,
-            )?;
+    fn add_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        debug!(
+            "Set route to {} via {:?} with {:?}",
+            host, gateway, attributes
+        );
+        if gateway.is_some() || !attributes.is_empty() {
+            self.ip_route("add", host, gateway, attributes)?;
         } else {
             // I have already a static route for the subnet
         }
         debug!("Interface {} set route", self.device_name);
         Ok(())
     }
-    fn replace_route(&self, host: Ipv4Addr, gateway: Option<Ipv4Addr>) -> BoxResult<()> {
-        debug!("Replace route to {} via {:?}", host, gateway);
-        if let Some(gateway) = gateway {
-            self.execute_command(
-                vec![
-                    "ip",
-                    "route",
-                    "replace",
-                    &format!("{}/32", host),
-                    "via",
-                    &gateway.to_string(),
-                    "dev",
-                    &self.device_name,
-                ],
-                None,
-            )?;
+    fn replace_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        debug!(
+            "Replace route to {} via {:?} with {:?}",
+            host, gateway, attributes
+        );
+        if gateway.is_some() || !attributes.is_empty() {
+            self.ip_route("replace", host, gateway, attributes)?;
         } else {
-            // There is no static route for a peer
+            // The subnet route is sufficient, so remove a previous host route.
+            // This is allowed to fail
+            let _ = self.execute_command(
+                vec!["ip", "route", "del", &host_net(host).to_string()],
+                None,
+            );
         }
         debug!("Interface {} set route", self.device_name);
         Ok(())
     }
-    fn del_route(&self, host: Ipv4Addr, gateway: Option<Ipv4Addr>) -> BoxResult<()> {
-        if gateway.is_some() {
+    fn del_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        if gateway.is_some() || !attributes.is_empty() {
             debug!("Delete route to {}", host);
-            self.execute_command(vec!["ip", "route", "del", &format!("{}/32", host)], None)?;
+            self.execute_command(
+                vec!["ip", "route", "del", &host_net(host).to_string()],
+                None,
+            )?;
             debug!("Interface {} deleted route", self.device_name);
         }
         Ok(())
     }
+    fn add_net_route(&self, net: IpNet) -> BoxResult<()> {
+        debug!("Set route to {}", net);
+        self.execute_command(
+            vec![
+                "ip",
+                "route",
+                "replace",
+                &net.to_string(),
+                "dev",
+                &self.device_name,
+            ],
+            None,
+        )?;
+        Ok(())
+    }
+    fn del_net_route(&self, net: IpNet) -> BoxResult<()> {
+        debug!("Delete route to {}", net);
+        self.execute_command(
+            vec![
+                "ip",
+                "route",
+                "del",
+                &net.to_string(),
+                "dev",
+                &self.device_name,
+            ],
+            None,
+        )?;
+        Ok(())
+    }
     fn flush_all(&self) -> BoxResult<()> {
         for what in ["route", "addr"] {
             debug!("Flush {}", what);
@@ -250,26 +356,91 @@ impl WireguardDevice for WireguardDeviceLinux {
         for peer_ini in ini.section_all(Some("Peer")) {
             if let Some(endpoint) = peer_ini.get("Endpoint") {
                 if let Some(pub_key) = peer_ini.get("PublicKey") {
-                    if let Ok(sa) = v6_strip_interface(endpoint) {
-                        if let Ok(sock_addr) = sa.parse::<SocketAddr>() {
+                    match parse_wg_endpoint(endpoint) {
+                        Ok(sock_addr) => {
                             trace!("{} is endpoint of {}", sock_addr, pub_key);
                             pubkey_to_endpoint.insert(pub_key.to_string(), sock_addr);
                         }
+                        Err(e) => {
+                            warn!("Cannot parse endpoint {} of {}: {:?}", endpoint, pub_key, e);
+                        }
                     }
                 }
             }
         }
         Ok(pubkey_to_endpoint)
     }
-    fn create_key_pair(&self) -> BoxResult<(String, String)> {
-        let result_priv_key = self.execute_command(vec!["wg", "genkey"], None)?;
-        let raw_priv_key = String::from_utf8_lossy(&result_priv_key.stdout);
-        let priv_key = raw_priv_key.trim();
-
-        let result_pub_key = self.execute_command(vec!["wg", "pubkey"], Some(priv_key))?;
-        let raw_pub_key = String::from_utf8_lossy(&result_pub_key.stdout);
-        let pub_key = raw_pub_key.trim();
-
-        Ok((priv_key.to_string(), pub_key.to_string()))
+    fn retrieve_peers(&self) -> BoxResult<Vec<WgPeerConf>> {
+        let result = self.execute_command(vec!["wg", "showconf", &self.device_name], None)?;
+        Ok(WgConf::parse(&String::from_utf8_lossy(&result.stdout))?.peers)
+    }
+    fn latest_handshakes(&self) -> BoxResult<HashMap<String, u64>> {
+        let result = self.execute_command(
+            vec!["wg", "show", &self.device_name, "latest-handshakes"],
+            None,
+        )?;
+        Ok(parse_latest_handshakes(&String::from_utf8_lossy(
+            &result.stdout,
+        )))
+    }
+    fn altnames(&self) -> BoxResult<Vec<String>> {
+        let result =
+            self.execute_command(vec!["ip", "-o", "link", "show", &self.device_name], None)?;
+        let output = String::from_utf8_lossy(&result.stdout);
+        let mut altnames = vec![];
+        let mut words = output.split_whitespace();
+        while let Some(word) = words.next() {
+            if word == "altname" {
+                altnames.extend(words.next().map(|s| s.to_string()));
+            }
+        }
+        Ok(altnames)
+    }
+    fn add_altname(&self, altname: &str) -> BoxResult<()> {
+        debug!("Add altname {}", altname);
+        let _ = self.execute_command(
+            vec![
+                "ip",
+                "link",
+                "property",
+                "add",
+                "dev",
+                &self.device_name,
+                "altname",
+                altname,
+            ],
+            None,
+        )?;
+        Ok(())
+    }
+    fn add_ip(&self, ip: &IpAddr, prefix_len: u8) -> BoxResult<()> {
+        debug!("Add IP {}/{}", ip, prefix_len);
+        self.execute_command(
+            vec![
+                "ip",
+                "addr",
+                "add",
+                &format!("{}/{}", ip, prefix_len),
+                "dev",
+                &self.device_name,
+            ],
+            None,
+        )?;
+        Ok(())
+    }
+    fn del_ip(&self, ip: &IpAddr, prefix_len: u8) -> BoxResult<()> {
+        debug!("Delete IP {}/{}", ip, prefix_len);
+        self.execute_command(
+            vec![
+                "ip",
+                "addr",
+                "del",
+                &format!("{}/{}", ip, prefix_len),
+                "dev",
+                &self.device_name,
+            ],
+            None,
+        )?;
+        Ok(())
     }
 }
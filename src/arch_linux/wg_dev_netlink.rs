@@ -0,0 +1,547 @@
+// WireguardDevice using netlink directly instead of the ip/wg commands.
+//
+// Interface, addresses and routes are managed via rtnetlink and the wireguard
+// configuration via the generic netlink interface of the kernel module.
+// Consequently neither sudo nor ip/wg need to be installed, but the process
+// needs CAP_NET_ADMIN. Userspace implementations like wireguard-go are not
+// supported by this backend.
+//
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::{IpNet, Ipv6Net};
+use log::*;
+use netlink_packet_core::{
+    NetlinkHeader, NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL,
+    NLM_F_REPLACE, NLM_F_REQUEST,
+};
+use netlink_packet_route::address::{AddressAttribute, AddressMessage};
+use netlink_packet_route::link::{LinkAttribute, LinkFlags, LinkMessage, Prop};
+use netlink_packet_route::route::{
+    RouteAttribute, RouteHeader, RouteMessage, RouteMetric, RouteProtocol, RouteScope, RouteType,
+};
+use netlink_packet_route::{AddressFamily, RouteNetlinkMessage};
+use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr as NetlinkSocketAddr};
+use wireguard_uapi::set::{AllowedIp, Device, Peer, WgDeviceF, WgPeerF};
+use wireguard_uapi::{DeviceInterface, RouteSocket, WgSocket};
+
+use crate::error::*;
+use crate::wg_dev::*;
+
+struct RtNetlink {
+    socket: Socket,
+    sequence_number: Cell<u32>,
+}
+impl RtNetlink {
+    fn connect() -> BoxResult<Self> {
+        let mut socket = Socket::new(NETLINK_ROUTE)?;
+        socket.bind_auto()?;
+        socket.connect(&NetlinkSocketAddr::new(0, 0))?;
+        Ok(RtNetlink {
+            socket,
+            sequence_number: Cell::new(0),
+        })
+    }
+    fn request(&self, msg: RouteNetlinkMessage, flags: u16) -> BoxResult<Vec<RouteNetlinkMessage>> {
+        let sequence_number = self.sequence_number.get().wrapping_add(1);
+        self.sequence_number.set(sequence_number);
+
+        let mut header = NetlinkHeader::default();
+        header.flags = NLM_F_REQUEST | flags;
+        header.sequence_number = sequence_number;
+        let mut nl_msg = NetlinkMessage::new(header, NetlinkPayload::InnerMessage(msg));
+        nl_msg.finalize();
+        let mut buf = vec![0; nl_msg.buffer_len()];
+        nl_msg.serialize(&mut buf);
+        self.socket.send(&buf, 0)?;
+
+        let mut replies = vec![];
+        loop {
+            let (rx_buf, _) = self.socket.recv_from_full()?;
+            let mut offset = 0;
+            while offset < rx_buf.len() {
+                let reply = NetlinkMessage::<RouteNetlinkMessage>::deserialize(&rx_buf[offset..])?;
+                offset += reply.header.length as usize;
+                if reply.header.sequence_number != sequence_number {
+                    continue;
+                }
+                match reply.payload {
+                    NetlinkPayload::Done(_) => return Ok(replies),
+                    NetlinkPayload::Error(e) => {
+                        return match e.code {
+                            // an error message without code is the ACK
+                            None => Ok(replies),
+                            Some(code) => Err(io::Error::from_raw_os_error(-code.get()).into()),
+                        };
+                    }
+                    NetlinkPayload::InnerMessage(m) => replies.push(m),
+                    _ => {}
+                }
+                if reply.header.length == 0 {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+pub struct WireguardDeviceNetlink {
+    device_name: String,
+    rtnl: RtNetlink,
+    wg: RefCell<WgSocket>,
+}
+impl WireguardDeviceNetlink {
+    pub fn init<T: Into<String>>(wg_name: T) -> BoxResult<Self> {
+        Ok(WireguardDeviceNetlink {
+            device_name: wg_name.into(),
+            rtnl: RtNetlink::connect()?,
+            // This fails, if the kernel module is not available
+            wg: RefCell::new(WgSocket::connect()?),
+        })
+    }
+    fn index(&self) -> BoxResult<u32> {
+        Ok(nix::net::if_::if_nametoindex(self.device_name.as_str())?)
+    }
+    fn add_address(&self, ip: IpAddr, prefix_len: u8) -> BoxResult<()> {
+        let mut msg = AddressMessage::default();
+        msg.header.family = address_family(&ip);
+        msg.header.prefix_len = prefix_len;
+        msg.header.index = self.index()?;
+        if ip.is_ipv4() {
+            msg.attributes.push(AddressAttribute::Local(ip));
+        }
+        msg.attributes.push(AddressAttribute::Address(ip));
+        self.rtnl.request(
+            RouteNetlinkMessage::NewAddress(msg),
+            NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+        )?;
+        Ok(())
+    }
+    fn del_address(&self, ip: IpAddr, prefix_len: u8) -> BoxResult<()> {
+        let mut msg = AddressMessage::default();
+        msg.header.family = address_family(&ip);
+        msg.header.prefix_len = prefix_len;
+        msg.header.index = self.index()?;
+        if ip.is_ipv4() {
+            msg.attributes.push(AddressAttribute::Local(ip));
+        }
+        msg.attributes.push(AddressAttribute::Address(ip));
+        self.rtnl
+            .request(RouteNetlinkMessage::DelAddress(msg), NLM_F_ACK)?;
+        Ok(())
+    }
+    fn set_link_up(&self) -> BoxResult<()> {
+        let mut msg = LinkMessage::default();
+        msg.header.index = self.index()?;
+        msg.header.flags = LinkFlags::Up;
+        msg.header.change_mask = LinkFlags::Up;
+        self.rtnl
+            .request(RouteNetlinkMessage::SetLink(msg), NLM_F_ACK)?;
+        Ok(())
+    }
+    fn route_message(&self, net: IpNet, gateway: Option<IpAddr>) -> BoxResult<RouteMessage> {
+        let mut msg = RouteMessage::default();
+        msg.header.address_family = address_family(&net.addr());
+        msg.header.destination_prefix_length = net.prefix_len();
+        msg.header.table = RouteHeader::RT_TABLE_MAIN;
+        msg.header.protocol = RouteProtocol::Boot;
+        msg.header.kind = RouteType::Unicast;
+        msg.header.scope = if gateway.is_some() {
+            RouteScope::Universe
+        } else {
+            RouteScope::Link
+        };
+        msg.attributes
+            .push(RouteAttribute::Destination(net.network().into()));
+        if let Some(gateway) = gateway {
+            msg.attributes.push(RouteAttribute::Gateway(gateway.into()));
+        }
+        msg.attributes.push(RouteAttribute::Oif(self.index()?));
+        Ok(msg)
+    }
+    fn update_route(
+        &self,
+        net: IpNet,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+        flags: u16,
+    ) -> BoxResult<()> {
+        let mut msg = self.route_message(net, gateway)?;
+        if let Some(mtu) = attributes.mtu {
+            msg.attributes.push(RouteAttribute::Metrics(vec![
+                RouteMetric::Mtu(mtu),
+                RouteMetric::Lock(1 << RTAX_MTU),
+            ]));
+        }
+        self.rtnl
+            .request(RouteNetlinkMessage::NewRoute(msg), NLM_F_ACK | flags)?;
+        Ok(())
+    }
+    fn delete_route(&self, net: IpNet) -> BoxResult<()> {
+        let mut msg = RouteMessage::default();
+        msg.header.address_family = address_family(&net.addr());
+        msg.header.destination_prefix_length = net.prefix_len();
+        msg.header.table = RouteHeader::RT_TABLE_MAIN;
+        msg.attributes
+            .push(RouteAttribute::Destination(net.network().into()));
+        self.rtnl
+            .request(RouteNetlinkMessage::DelRoute(msg), NLM_F_ACK)?;
+        Ok(())
+    }
+    fn update_conf(&self, conf: &str, set_new: bool) -> BoxResult<()> {
+        debug!(target: "wireguard", "Update configuration: {}", conf);
+        let conf = WgConf::parse(conf)?;
+
+        // syncconf semantic: remove all peers, which are not in the configuration
+        // anymore, without disturbing the others
+        let mut removed_peers = vec![];
+        if !set_new {
+            let current = self
+                .wg
+                .borrow_mut()
+                .get_device(DeviceInterface::from_name(&self.device_name))?;
+            let keep = conf
+                .peers
+                .iter()
+                .map(|p| p.public_key)
+                .collect::<HashSet<_>>();
+            removed_peers = current
+                .peers
+                .iter()
+                .map(|p| p.public_key)
+                .filter(|key| !keep.contains(key))
+                .collect::<Vec<_>>();
+        }
+
+        let allowed_ips = conf
+            .peers
+            .iter()
+            .map(|p| {
+                p.allowed_ips
+                    .iter()
+                    .map(|net| (net.addr(), net.prefix_len()))
+                    .collect()
+            })
+            .collect::<Vec<Vec<(IpAddr, u8)>>>();
+
+        let mut peers = vec![];
+        for key in removed_peers.iter() {
+            peers.push(Peer::from_public_key(key).flags(vec![WgPeerF::RemoveMe]));
+        }
+        for (peer, ips) in conf.peers.iter().zip(allowed_ips.iter()) {
+            let mut wg_peer = Peer::from_public_key(&peer.public_key)
+                .flags(vec![WgPeerF::ReplaceAllowedIps])
+                .allowed_ips(
+                    ips.iter()
+                        .map(|(ip, prefix_len)| AllowedIp {
+                            ipaddr: ip,
+                            cidr_mask: Some(*prefix_len),
+                        })
+                        .collect(),
+                );
+            if let Some(endpoint) = peer.endpoint.as_ref() {
+                wg_peer = wg_peer.endpoint(endpoint);
+            }
+            if let Some(preshared_key) = peer.preshared_key.as_ref() {
+                wg_peer = wg_peer.preshared_key(preshared_key);
+            }
+            // 0 disables a keepalive, which has been set before
+            wg_peer = wg_peer.persistent_keepalive_interval(peer.persistent_keepalive.unwrap_or(0));
+            peers.push(wg_peer);
+        }
+
+        let mut device = Device::from_ifname(self.device_name.as_str()).peers(peers);
+        if set_new {
+            device = device.flags(vec![WgDeviceF::ReplacePeers]);
+        }
+        if let Some(private_key) = conf.private_key.as_ref() {
+            device = device.private_key(private_key);
+        }
+        if let Some(listen_port) = conf.listen_port {
+            device = device.listen_port(listen_port);
+        }
+        self.wg.borrow_mut().set_device(device)?;
+        Ok(())
+    }
+}
+
+// Index of the mtu in the route metrics, which is used for the lock bitmask
+const RTAX_MTU: u32 = 2;
+
+fn address_family(ip: &IpAddr) -> AddressFamily {
+    match ip {
+        IpAddr::V4(_) => AddressFamily::Inet,
+        IpAddr::V6(_) => AddressFamily::Inet6,
+    }
+}
+
+impl WireguardDevice for WireguardDeviceNetlink {
+    fn check_device(&self) -> BoxResult<bool> {
+        debug!("Check for device {}", self.device_name);
+        Ok(self.index().is_ok())
+    }
+    fn create_device(&self) -> BoxResult<()> {
+        debug!("Create device via netlink");
+        RouteSocket::connect()?.add_device(&self.device_name)?;
+        debug!("Interface {} created", self.device_name);
+        Ok(())
+    }
+    fn take_down_device(&self) -> BoxResult<()> {
+        debug!("Take down device");
+        let _ = RouteSocket::connect().map(|mut s| s.del_device(&self.device_name));
+        debug!("Interface {} destroyed", self.device_name);
+        Ok(())
+    }
+    fn set_ip(&mut self, ip: &IpAddr, subnet: &IpNet, probe_net: Option<Ipv6Net>) -> BoxResult<()> {
+        debug!("Set IP {}", ip);
+        self.add_address(*ip, subnet.prefix_len())?;
+        if let Some(probe_net) = probe_net {
+            self.add_address(IpAddr::V6(probe_net.addr()), probe_net.prefix_len())?;
+        }
+
+        self.set_link_up()?;
+        debug!("Interface {} up", self.device_name);
+
+        if let Some(probe_net) = probe_net {
+            self.update_route(
+                IpNet::V6(probe_net.trunc()),
+                None,
+                &RouteAttributes::default(),
+                NLM_F_CREATE | NLM_F_EXCL,
+            )?;
+        }
+
+        // This is allowed to fail
+        let _ = self.update_route(
+            subnet.trunc(),
+            None,
+            &RouteAttributes::default(),
+            NLM_F_CREATE | NLM_F_EXCL,
+        );
+
+        debug!("Interface {} set ip", self.device_name);
+        Ok(())
+    }
+    fn add_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        debug!(
+            "Set route to {} via {:?} with {:?}",
+            host, gateway, attributes
+        );
+        if gateway.is_some() || !attributes.is_empty() {
+            self.update_route(
+                host_net(host),
+                gateway,
+                attributes,
+                NLM_F_CREATE | NLM_F_EXCL,
+            )?;
+        } else {
+            // I have already a static route for the subnet
+        }
+        debug!("Interface {} set route", self.device_name);
+        Ok(())
+    }
+    fn replace_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        debug!(
+            "Replace route to {} via {:?} with {:?}",
+            host, gateway, attributes
+        );
+        if gateway.is_some() || !attributes.is_empty() {
+            self.update_route(
+                host_net(host),
+                gateway,
+                attributes,
+                NLM_F_CREATE | NLM_F_REPLACE,
+            )?;
+        } else {
+            // The subnet route is sufficient, so remove a previous host route.
+            // This is allowed to fail
+            let _ = self.delete_route(host_net(host));
+        }
+        debug!("Interface {} set route", self.device_name);
+        Ok(())
+    }
+    fn del_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        if gateway.is_some() || !attributes.is_empty() {
+            debug!("Delete route to {}", host);
+            self.delete_route(host_net(host))?;
+            debug!("Interface {} deleted route", self.device_name);
+        }
+        Ok(())
+    }
+    fn add_net_route(&self, net: IpNet) -> BoxResult<()> {
+        debug!("Set route to {}", net);
+        self.update_route(
+            net,
+            None,
+            &RouteAttributes::default(),
+            NLM_F_CREATE | NLM_F_REPLACE,
+        )
+    }
+    fn del_net_route(&self, net: IpNet) -> BoxResult<()> {
+        debug!("Delete route to {}", net);
+        self.delete_route(net)
+    }
+    fn flush_all(&self) -> BoxResult<()> {
+        let index = match self.index() {
+            Ok(index) => index,
+            Err(_) => return Ok(()),
+        };
+
+        debug!("Flush route");
+        let routes = self.rtnl.request(
+            RouteNetlinkMessage::GetRoute(RouteMessage::default()),
+            NLM_F_DUMP,
+        )?;
+        for reply in routes {
+            if let RouteNetlinkMessage::NewRoute(msg) = reply {
+                if msg.attributes.contains(&RouteAttribute::Oif(index)) {
+                    let _ = self
+                        .rtnl
+                        .request(RouteNetlinkMessage::DelRoute(msg), NLM_F_ACK);
+                }
+            }
+        }
+        debug!("route flushed");
+
+        debug!("Flush addr");
+        let addresses = self.rtnl.request(
+            RouteNetlinkMessage::GetAddress(AddressMessage::default()),
+            NLM_F_DUMP,
+        )?;
+        for reply in addresses {
+            if let RouteNetlinkMessage::NewAddress(msg) = reply {
+                if msg.header.index == index {
+                    let _ = self
+                        .rtnl
+                        .request(RouteNetlinkMessage::DelAddress(msg), NLM_F_ACK);
+                }
+            }
+        }
+        debug!("addr flushed");
+        Ok(())
+    }
+    fn set_conf(&self, conf: &str) -> BoxResult<()> {
+        self.update_conf(conf, true)
+    }
+    fn sync_conf(&self, conf: &str) -> BoxResult<()> {
+        self.update_conf(conf, false)
+    }
+    fn retrieve_conf(&self) -> BoxResult<HashMap<String, SocketAddr>> {
+        let mut pubkey_to_endpoint = HashMap::new();
+        let device = self
+            .wg
+            .borrow_mut()
+            .get_device(DeviceInterface::from_name(&self.device_name))?;
+        for peer in device.peers {
+            if let Some(endpoint) = peer.endpoint {
+                let pub_key = base64::encode(peer.public_key);
+                let sock_addr = parse_wg_endpoint(&endpoint.to_string())?;
+                trace!("{} is endpoint of {}", sock_addr, pub_key);
+                pubkey_to_endpoint.insert(pub_key, sock_addr);
+            }
+        }
+        Ok(pubkey_to_endpoint)
+    }
+    fn retrieve_peers(&self) -> BoxResult<Vec<WgPeerConf>> {
+        let device = self
+            .wg
+            .borrow_mut()
+            .get_device(DeviceInterface::from_name(&self.device_name))?;
+        let mut peers = vec![];
+        for peer in device.peers {
+            let mut allowed_ips = vec![];
+            for allowed_ip in peer.allowed_ips {
+                let net = format!("{}/{}", allowed_ip.ipaddr, allowed_ip.cidr_mask);
+                allowed_ips.push(net.parse::<IpNet>()?);
+            }
+            let endpoint = match peer.endpoint {
+                Some(endpoint) => Some(parse_wg_endpoint(&endpoint.to_string())?),
+                None => None,
+            };
+            peers.push(WgPeerConf {
+                public_key: peer.public_key,
+                // All zero, if there is none
+                preshared_key: Some(peer.preshared_key).filter(|key| *key != [0; 32]),
+                endpoint,
+                allowed_ips,
+                persistent_keepalive: Some(peer.persistent_keepalive_interval).filter(|s| *s > 0),
+            });
+        }
+        Ok(peers)
+    }
+    fn latest_handshakes(&self) -> BoxResult<HashMap<String, u64>> {
+        let device = self
+            .wg
+            .borrow_mut()
+            .get_device(DeviceInterface::from_name(&self.device_name))?;
+        Ok(device
+            .peers
+            .iter()
+            .filter(|peer| !peer.last_handshake_time.is_zero())
+            .map(|peer| {
+                (
+                    base64::encode(peer.public_key),
+                    peer.last_handshake_time.as_secs(),
+                )
+            })
+            .collect())
+    }
+    fn altnames(&self) -> BoxResult<Vec<String>> {
+        let mut msg = LinkMessage::default();
+        msg.header.index = self.index()?;
+        let mut altnames = vec![];
+        for reply in self.rtnl.request(RouteNetlinkMessage::GetLink(msg), 0)? {
+            if let RouteNetlinkMessage::NewLink(msg) = reply {
+                for attr in msg.attributes {
+                    if let LinkAttribute::PropList(props) = attr {
+                        for prop in props {
+                            if let Prop::AltIfName(altname) = prop {
+                                altnames.push(altname);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(altnames)
+    }
+    fn add_altname(&self, altname: &str) -> BoxResult<()> {
+        debug!("Add altname {}", altname);
+        let mut msg = LinkMessage::default();
+        msg.header.index = self.index()?;
+        msg.attributes
+            .push(LinkAttribute::PropList(vec![Prop::AltIfName(
+                altname.to_string(),
+            )]));
+        self.rtnl.request(
+            RouteNetlinkMessage::NewLinkProp(msg),
+            NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+        )?;
+        Ok(())
+    }
+    fn add_ip(&self, ip: &IpAddr, prefix_len: u8) -> BoxResult<()> {
+        debug!("Add IP {}/{}", ip, prefix_len);
+        self.add_address(*ip, prefix_len)
+    }
+    fn del_ip(&self, ip: &IpAddr, prefix_len: u8) -> BoxResult<()> {
+        debug!("Delete IP {}/{}", ip, prefix_len);
+        self.del_address(*ip, prefix_len)
+    }
+}
@@ -0,0 +1,179 @@
+// WireguardDevice without the kernel module and without external wireguard binaries.
+//
+// The tun interface is created by this process, and the wireguard protocol runs
+// in-process, see crate::wg_userspace. The addresses and routes of the interface are
+// set via ip like for the kernel module. The interface exists as long as the tun is
+// open, so it vanishes with the process. An existing interface cannot be used.
+//
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+
+use ipnet::{IpNet, Ipv6Net};
+use log::*;
+
+use super::wg_dev_linuxkernel::WireguardDeviceLinux;
+use crate::error::*;
+use crate::wg_dev::*;
+use crate::wg_userspace::UserspaceWireguard;
+
+fn open_tun(name: &str) -> BoxResult<File> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(format!("Interface name {} is too long", name).into());
+    }
+    let tun = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")?;
+    // Safety: ifreq is plain old data
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    ifr.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+    // Safety: ifr is valid for the duration of the call
+    if unsafe { libc::ioctl(tun.as_raw_fd(), libc::TUNSETIFF, &ifr) } < 0 {
+        return Err(format!(
+            "Cannot create tun interface {}: {}",
+            name,
+            std::io::Error::last_os_error()
+        )
+        .into());
+    }
+    Ok(tun)
+}
+
+pub struct WireguardDeviceUserspace {
+    device_name: String,
+    // For the ip commands
+    ip: WireguardDeviceLinux,
+    wireguard: Mutex<Option<UserspaceWireguard>>,
+}
+impl WireguardDeviceUserspace {
+    pub fn init<T: Into<String>>(wg_name: T) -> Self {
+        let device_name = wg_name.into();
+        WireguardDeviceUserspace {
+            ip: WireguardDeviceLinux::init(&device_name),
+            device_name,
+            wireguard: Mutex::new(None),
+        }
+    }
+    fn update_conf(&self, conf: &str, set_new: bool) -> BoxResult<()> {
+        match self.wireguard.lock().unwrap().as_ref() {
+            Some(wireguard) => wireguard.update_conf(conf, set_new),
+            None => Err(format!(
+                "Interface {} has not been created by the userspace backend",
+                self.device_name
+            )
+            .into()),
+        }
+    }
+}
+
+impl WireguardDevice for WireguardDeviceUserspace {
+    fn check_device(&self) -> BoxResult<bool> {
+        self.ip.check_device()
+    }
+    fn create_device(&self) -> BoxResult<()> {
+        debug!("Create device {} via tun", self.device_name);
+        let tun = open_tun(&self.device_name)?;
+        *self.wireguard.lock().unwrap() = Some(UserspaceWireguard::new(&self.device_name, tun));
+        debug!("Interface {} created", self.device_name);
+        Ok(())
+    }
+    fn take_down_device(&self) -> BoxResult<()> {
+        match self.wireguard.lock().unwrap().take() {
+            Some(wireguard) => {
+                debug!("Take down device");
+                // The interface vanishes, when the threads have closed the tun
+                wireguard.stop();
+                Ok(())
+            }
+            // A leftover interface of e.g. the kernel module
+            None => self.ip.take_down_device(),
+        }
+    }
+    fn set_ip(&mut self, ip: &IpAddr, subnet: &IpNet, probe_net: Option<Ipv6Net>) -> BoxResult<()> {
+        self.ip.set_ip(ip, subnet, probe_net)
+    }
+    fn add_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        self.ip.add_route(host, gateway, attributes)
+    }
+    fn replace_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        self.ip.replace_route(host, gateway, attributes)
+    }
+    fn del_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        self.ip.del_route(host, gateway, attributes)
+    }
+    fn add_net_route(&self, net: IpNet) -> BoxResult<()> {
+        self.ip.add_net_route(net)
+    }
+    fn del_net_route(&self, net: IpNet) -> BoxResult<()> {
+        self.ip.del_net_route(net)
+    }
+    fn flush_all(&self) -> BoxResult<()> {
+        self.ip.flush_all()
+    }
+    fn set_conf(&self, conf: &str) -> BoxResult<()> {
+        self.update_conf(conf, true)
+    }
+    fn sync_conf(&self, conf: &str) -> BoxResult<()> {
+        self.update_conf(conf, false)
+    }
+    fn retrieve_conf(&self) -> BoxResult<HashMap<String, SocketAddr>> {
+        Ok(self
+            .wireguard
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|wireguard| wireguard.endpoints())
+            .unwrap_or_default())
+    }
+    fn latest_handshakes(&self) -> BoxResult<HashMap<String, u64>> {
+        Ok(self
+            .wireguard
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|wireguard| wireguard.latest_handshakes())
+            .unwrap_or_default())
+    }
+    fn transfer(&self) -> BoxResult<HashMap<String, (u64, u64)>> {
+        Ok(self
+            .wireguard
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|wireguard| wireguard.transfer())
+            .unwrap_or_default())
+    }
+    fn altnames(&self) -> BoxResult<Vec<String>> {
+        self.ip.altnames()
+    }
+    fn add_altname(&self, altname: &str) -> BoxResult<()> {
+        self.ip.add_altname(altname)
+    }
+    fn add_ip(&self, ip: &IpAddr, prefix_len: u8) -> BoxResult<()> {
+        self.ip.add_ip(ip, prefix_len)
+    }
+    fn del_ip(&self, ip: &IpAddr, prefix_len: u8) -> BoxResult<()> {
+        self.ip.del_ip(ip, prefix_len)
+    }
+}
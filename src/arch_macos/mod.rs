@@ -2,7 +2,12 @@ pub mod wg_dev_macos;
 
 use std::net::IpAddr;
 
+use clap::ArgMatches;
+
 use crate::arch_def::Architecture;
+use crate::configuration::StaticConfiguration;
+use crate::error::BoxResult;
+use crate::launchd;
 use crate::wg_dev::*;
 
 use wg_dev_macos::WireguardDeviceMacos;
@@ -24,4 +29,31 @@ impl Architecture for ArchitectureMacOs {
     fn get_wg_dev<T: Into<String>>(wg_name: T) -> Box<dyn WireguardDevice> {
         Box::new(WireguardDeviceMacos::init(wg_name))
     }
+    // Always via wireguard-go
+    fn get_wg_quick_wg_dev<T: Into<String>>(wg_name: T) -> BoxResult<Box<dyn WireguardDevice>> {
+        Ok(Self::get_wg_dev(wg_name))
+    }
+    fn command_install(matches: &ArgMatches, static_config: StaticConfiguration) -> BoxResult<()> {
+        let exe = std::env::current_exe()?;
+        let exe = exe
+            .to_str()
+            .ok_or("Path to executable is not valid unicode")?;
+
+        let content = launchd::plist(&static_config, exe);
+        let written = launchd::install_plist(
+            std::path::Path::new(launchd::PLIST_FILE),
+            &content,
+            matches.is_present("force"),
+            matches.is_present("dryRun"),
+        )?;
+        if written {
+            println!();
+            println!("Then execute:");
+            println!(
+                "    sudo launchctl bootstrap system {}",
+                launchd::PLIST_FILE
+            );
+        }
+        Ok(())
+    }
 }
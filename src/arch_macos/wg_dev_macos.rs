@@ -1,17 +1,18 @@
 use std::collections::HashMap;
 use std::io::Write;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
 use std::process::{Command, Stdio};
 
-use ipnet::Ipv4Net;
+use ipnet::{IpNet, Ipv6Net};
 use log::*;
 
 use crate::error::*;
+use crate::userspace_process;
 use crate::wg_dev::*;
 
 pub struct WireguardDeviceMacos {
     device_name: String,
-    ip: Ipv4Addr,
+    ip: IpAddr,
 }
 impl WireguardDeviceMacos {
     pub fn init<T: Into<String>>(wg_name: T) -> Self {
@@ -79,9 +80,9 @@ impl WireguardDeviceMacos {
         let fname = tmpfname.trim();
         trace!(target: "wireguard", "temp file {}", fname);
 
-        let _ = self.execute_command(vec!["tee", "-a", &*fname], Some(conf))?;
-        let _ = self.execute_command(vec!["wg", wg_cmd, &self.device_name, &*fname], None)?;
-        let _ = self.execute_command(vec!["rm", &*fname], None)?;
+        let _ = self.execute_command(vec!["tee", "-a", fname], Some(conf))?;
+        let _ = self.execute_command(vec!["wg", wg_cmd, &self.device_name, fname], None)?;
+        let _ = self.execute_command(vec!["rm", fname], None)?;
         Ok(())
     }
 }
@@ -94,31 +95,57 @@ impl WireguardDevice for WireguardDeviceMacos {
     }
     fn create_device(&self) -> BoxResult<()> {
         debug!("Create device");
-        let _ = self.execute_command(vec!["wireguard-go", &self.device_name], None);
+        let _ = userspace_process::start(
+            "wireguard-go",
+            &self.device_name,
+            &["sudo"],
+            &|args, input| self.internal_execute_command(args, input),
+        );
         debug!("Interface {} created", self.device_name);
 
         Ok(())
     }
     fn take_down_device(&self) -> BoxResult<()> {
         debug!("Take down device");
-        let _ = self.execute_command(vec!["killall", "wireguard-go"], None);
+        // Only the process of this interface, the ones of other tunnels keep running
+        userspace_process::stop(&self.device_name, &|args, input| {
+            self.internal_execute_command(args, input)
+        });
         debug!("Interface {} destroyed", self.device_name);
         Ok(())
     }
-    fn set_ip(&mut self, ip: &Ipv4Addr, subnet: &Ipv4Net) -> BoxResult<()> {
+    fn set_ip(&mut self, ip: &IpAddr, subnet: &IpNet, probe_net: Option<Ipv6Net>) -> BoxResult<()> {
         debug!("Set IP {}", ip);
         // The option noprefixroute of ip addr add would be ideal, but is not supported on older linux/ip
         self.ip = *ip;
         let ip_extend = format!("{}", ip);
-        let ipv6_extend = format!("{}/{}", map_to_ipv6(ip), 96 + subnet.prefix_len());
-        let _ = self.execute_command(
-            vec!["ifconfig", &self.device_name, &ip_extend, &ip_extend],
-            None,
-        );
-        let _ = self.execute_command(
-            vec!["ifconfig", &self.device_name, "inet6", &ipv6_extend, "add"],
-            None,
-        );
+        match ip {
+            IpAddr::V4(_) => {
+                let _ = self.execute_command(
+                    vec!["ifconfig", &self.device_name, &ip_extend, &ip_extend],
+                    None,
+                );
+            }
+            IpAddr::V6(_) => {
+                let ipv6_extend = format!("{}/{}", ip, subnet.prefix_len());
+                let _ = self.execute_command(
+                    vec!["ifconfig", &self.device_name, "inet6", &ipv6_extend, "add"],
+                    None,
+                );
+            }
+        }
+        if let Some(probe_net) = probe_net {
+            let _ = self.execute_command(
+                vec![
+                    "ifconfig",
+                    &self.device_name,
+                    "inet6",
+                    &probe_net.to_string(),
+                    "add",
+                ],
+                None,
+            );
+        }
 
         // This is allowed to fail
         let _ = self.execute_command(
@@ -126,6 +153,7 @@ impl WireguardDevice for WireguardDeviceMacos {
                 "route",
                 "-n",
                 "add",
+                route_family(ip),
                 "-net",
                 &format!("{:?}", subnet),
                 &ip_extend,
@@ -136,12 +164,24 @@ impl WireguardDevice for WireguardDeviceMacos {
         debug!("Interface {} set ip", self.device_name);
         Ok(())
     }
-    fn add_route(&self, host: Ipv4Addr, gateway: Option<Ipv4Addr>) -> BoxResult<()> {
+    fn add_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        _attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        // Route attributes like mtu are not supported
         debug!("Set route to {} via {:?}", host, gateway);
         let ip = format!("{}", self.ip);
         if let Some(gateway) = gateway {
             let _ = self.execute_command(
-                vec!["route", "add", &host.to_string(), &gateway.to_string()],
+                vec![
+                    "route",
+                    "add",
+                    route_family(&host),
+                    &host.to_string(),
+                    &gateway.to_string(),
+                ],
                 None,
             );
         } else {
@@ -150,12 +190,23 @@ impl WireguardDevice for WireguardDeviceMacos {
         debug!("Interface {} set route", self.device_name);
         Ok(())
     }
-    fn replace_route(&self, host: Ipv4Addr, gateway: Option<Ipv4Addr>) -> BoxResult<()> {
+    fn replace_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        _attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
         debug!("Replace route to {} via {:?}", host, gateway);
         let ip = format!("{}", self.ip);
         if let Some(gateway) = gateway {
             let _ = self.execute_command(
-                vec!["route", "change", &host.to_string(), &gateway.to_string()],
+                vec![
+                    "route",
+                    "change",
+                    route_family(&host),
+                    &host.to_string(),
+                    &gateway.to_string(),
+                ],
                 None,
             );
         } else {
@@ -164,14 +215,50 @@ impl WireguardDevice for WireguardDeviceMacos {
         debug!("Interface {} set route", self.device_name);
         Ok(())
     }
-    fn del_route(&self, host: Ipv4Addr, gateway: Option<Ipv4Addr>) -> BoxResult<()> {
+    fn del_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        _attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
         if gateway.is_some() {
             debug!("Delete route to {}", host);
-            let _ = self.execute_command(vec!["route", "delete", &host.to_string()], None);
+            let _ = self.execute_command(
+                vec!["route", "delete", route_family(&host), &host.to_string()],
+                None,
+            );
             debug!("Interface {} deleted route", self.device_name);
         }
         Ok(())
     }
+    fn add_net_route(&self, net: IpNet) -> BoxResult<()> {
+        debug!("Set route to {}", net);
+        let _ = self.execute_command(
+            vec![
+                "route",
+                "add",
+                route_family(&net.addr()),
+                &net.to_string(),
+                "-interface",
+                &self.device_name,
+            ],
+            None,
+        );
+        Ok(())
+    }
+    fn del_net_route(&self, net: IpNet) -> BoxResult<()> {
+        debug!("Delete route to {}", net);
+        let _ = self.execute_command(
+            vec![
+                "route",
+                "delete",
+                route_family(&net.addr()),
+                &net.to_string(),
+            ],
+            None,
+        );
+        Ok(())
+    }
     fn flush_all(&self) -> BoxResult<()> {
         warn!("flush_all not implemented for macos");
         Ok(())
@@ -191,26 +278,56 @@ impl WireguardDevice for WireguardDeviceMacos {
         for peer_ini in ini.section_all(Some("Peer")) {
             if let Some(endpoint) = peer_ini.get("Endpoint") {
                 if let Some(pub_key) = peer_ini.get("PublicKey") {
-                    if let Ok(sa) = v6_strip_interface(endpoint) {
-                        if let Ok(sock_addr) = sa.parse::<SocketAddr>() {
+                    match parse_wg_endpoint(endpoint) {
+                        Ok(sock_addr) => {
                             trace!("{} is endpoint of {}", sock_addr, pub_key);
                             pubkey_to_endpoint.insert(pub_key.to_string(), sock_addr);
                         }
+                        Err(e) => {
+                            warn!("Cannot parse endpoint {} of {}: {:?}", endpoint, pub_key, e);
+                        }
                     }
                 }
             }
         }
         Ok(pubkey_to_endpoint)
     }
-    fn create_key_pair(&self) -> BoxResult<(String, String)> {
-        let result_priv_key = self.execute_command(vec!["wg", "genkey"], None)?;
-        let raw_priv_key = String::from_utf8_lossy(&result_priv_key.stdout);
-        let priv_key = raw_priv_key.trim();
-
-        let result_pub_key = self.execute_command(vec!["wg", "pubkey"], Some(priv_key))?;
-        let raw_pub_key = String::from_utf8_lossy(&result_pub_key.stdout);
-        let pub_key = raw_pub_key.trim();
+    fn retrieve_peers(&self) -> BoxResult<Vec<WgPeerConf>> {
+        let result = self.execute_command(vec!["wg", "showconf", &self.device_name], None)?;
+        Ok(WgConf::parse(&String::from_utf8_lossy(&result.stdout))?.peers)
+    }
+    fn latest_handshakes(&self) -> BoxResult<HashMap<String, u64>> {
+        let result = self.execute_command(
+            vec!["wg", "show", &self.device_name, "latest-handshakes"],
+            None,
+        )?;
+        Ok(parse_latest_handshakes(&String::from_utf8_lossy(
+            &result.stdout,
+        )))
+    }
+    fn del_ip(&self, ip: &IpAddr, prefix_len: u8) -> BoxResult<()> {
+        debug!("Delete IP {}/{}", ip, prefix_len);
+        let family = match ip {
+            IpAddr::V4(_) => "inet",
+            IpAddr::V6(_) => "inet6",
+        };
+        self.execute_command(
+            vec![
+                "ifconfig",
+                &self.device_name,
+                family,
+                &ip.to_string(),
+                "delete",
+            ],
+            None,
+        )?;
+        Ok(())
+    }
+}
 
-        Ok((priv_key.to_string(), pub_key.to_string()))
+fn route_family(ip: &IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "-inet",
+        IpAddr::V6(_) => "-inet6",
     }
 }
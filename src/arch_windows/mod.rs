@@ -1,7 +1,80 @@
+mod interfaces;
+pub mod service;
+mod wg_dev_windows;
+
 use std::net::IpAddr;
 
+use clap::ArgMatches;
+
 use crate::arch_def::Architecture;
-use crate::wg_dev::*;
+use crate::configuration::StaticConfiguration;
+use crate::error::BoxResult;
+use crate::event::EventSender;
+use crate::wg_dev::WireguardDevice;
+
+use wg_dev_windows::WireguardDeviceWindows;
 
 pub struct ArchitectureWindows {}
-impl Architecture for ArchitectureWindows {}
+impl Architecture for ArchitectureWindows {
+    fn default_path_to_network_yaml() -> &'static str {
+        r"C:\ProgramData\wg_netmanager\network.yaml"
+    }
+    fn default_path_to_peer_yaml() -> &'static str {
+        r"C:\ProgramData\wg_netmanager\peer.yaml"
+    }
+    fn ipv4v6_socket_setup() -> (bool, bool, bool) {
+        // ipv6 sockets are ipv6 only on windows
+        (true, true, true)
+    }
+    fn get_local_interfaces() -> Vec<IpAddr> {
+        interfaces::get()
+    }
+    fn arch_specific_init(tx: EventSender) {
+        service::set_run_loop_tx(tx);
+    }
+    fn get_wg_dev<T: Into<String>>(wg_name: T) -> Box<dyn WireguardDevice> {
+        Box::new(WireguardDeviceWindows::init(wg_name))
+    }
+    // wireguard-nt is a kernel driver
+    fn get_kernel_wg_dev<T: Into<String>>(wg_name: T) -> BoxResult<Box<dyn WireguardDevice>> {
+        Ok(Self::get_wg_dev(wg_name))
+    }
+    fn command_install(matches: &ArgMatches, static_config: StaticConfiguration) -> BoxResult<()> {
+        let _ = matches.is_present("force");
+        let exe = std::env::current_exe()?;
+        let exe = exe
+            .to_str()
+            .ok_or("Path to executable is not valid unicode")?;
+        let mut lines: Vec<String> = vec![];
+        lines.push("Execute the following lines in an administrator command prompt:".to_string());
+        lines.push("#================================".to_string());
+        let mut bin_path = format!(
+            "\\\"{}\\\" --windows-service -c \\\"{}\\\"",
+            exe, static_config.network_yaml_filename
+        );
+        if let Some(fname) = static_config.peer_yaml_filename.as_ref() {
+            bin_path.push_str(&format!(" -p \\\"{}\\\"", fname));
+        }
+        lines.push(format!(
+            "sc.exe create {} binPath= \"{}\" start= auto depend= Tcpip",
+            service::SERVICE_NAME,
+            bin_path
+        ));
+        lines.push(format!(
+            "sc.exe description {} \"The Wireguard network manager\"",
+            service::SERVICE_NAME
+        ));
+        lines.push(format!(
+            "sc.exe failure {} reset= 0 actions= restart/1000",
+            service::SERVICE_NAME
+        ));
+        lines.push(format!("sc.exe start {}", service::SERVICE_NAME));
+        lines.push("#================================".to_string());
+        lines.push("".to_string());
+        lines.push(
+            "wireguard.dll from wireguard-nt needs to be next to the executable.".to_string(),
+        );
+        println!("{}", lines.join("\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+// Glue to run wg_netmanager as windows service.
+//
+// The service control manager starts the binary with --windows-service.
+// main() then hands over to start(), which connects to the service control
+// manager and executes the usual main function in the service thread.
+// A stop request is forwarded as Event::CtrlC to the run loop.
+//
+use std::ffi::OsString;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use log::*;
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use crate::error::*;
+use crate::event::{Event, EventSender};
+
+pub const SERVICE_NAME: &str = "wg_netmanager";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+static SERVICE_RUN: OnceLock<fn() -> BoxResult<()>> = OnceLock::new();
+static RUN_LOOP_TX: Mutex<Option<EventSender>> = Mutex::new(None);
+
+pub fn start(run: fn() -> BoxResult<()>) -> BoxResult<()> {
+    let _ = SERVICE_RUN.set(run);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+    Ok(())
+}
+
+// Called from arch_specific_init, so a stop request can reach the run loop
+pub fn set_run_loop_tx(tx: EventSender) {
+    *RUN_LOOP_TX.lock().unwrap() = Some(tx);
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        error!("Service failed: {}", e);
+    }
+}
+
+fn status(current_state: ServiceState, exit_code: u32) -> ServiceStatus {
+    ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state,
+        controls_accepted: if current_state == ServiceState::Running {
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+        } else {
+            ServiceControlAccept::empty()
+        },
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
+
+fn run_service() -> BoxResult<()> {
+    let event_handler = move |control_event| -> ServiceControlHandlerResult {
+        match control_event {
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                if let Some(tx) = RUN_LOOP_TX.lock().unwrap().as_ref() {
+                    tx.send(Event::CtrlC).ok();
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+    let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)?;
+    status_handle.set_service_status(status(ServiceState::Running, 0))?;
+
+    let rc = match SERVICE_RUN.get() {
+        Some(run) => run(),
+        None => Err("Service started without main function".into()),
+    };
+    if let Err(e) = rc.as_ref() {
+        error!("{}", e);
+    }
+
+    // ERROR_SERVICE_SPECIFIC_ERROR lets the service control manager apply the failure actions
+    let exit_code = if rc.is_ok() { 0 } else { 1066 };
+    status_handle.set_service_status(status(ServiceState::Stopped, exit_code))?;
+    Ok(())
+}
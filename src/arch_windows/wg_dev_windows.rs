@@ -0,0 +1,337 @@
+// WireguardDevice for the wireguard-nt driver.
+//
+// The adapter and its wireguard configuration are managed via wireguard.dll,
+// which needs to be found next to the executable. Addresses and routes are set
+// with netsh. Everything requires administrator rights.
+//
+// wireguard-nt always replaces all peers on configuration changes. So for
+// sync_conf, the endpoints of peers without a configured endpoint are taken
+// over from the adapter in order to not lose roamed endpoints.
+//
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::process::{Command, Stdio};
+
+use ipnet::{IpNet, Ipv6Net};
+use log::*;
+use wireguard_nt::{Adapter, SetInterface, SetPeer, Wireguard};
+
+use crate::error::*;
+use crate::wg_dev::*;
+
+const ADAPTER_POOL: &str = "wg_netmanager";
+
+pub struct WireguardDeviceWindows {
+    device_name: String,
+    wireguard: Option<Wireguard>,
+    adapter: RefCell<Option<Adapter>>,
+}
+impl WireguardDeviceWindows {
+    pub fn init<T: Into<String>>(wg_name: T) -> Self {
+        // Safety: the library is loaded from the default search path, which starts
+        // with the directory of the executable
+        let wireguard = match unsafe { wireguard_nt::load() } {
+            Ok(wireguard) => Some(wireguard),
+            Err(e) => {
+                error!("Cannot load wireguard.dll: {}", e);
+                None
+            }
+        };
+        WireguardDeviceWindows {
+            device_name: wg_name.into(),
+            wireguard,
+            adapter: RefCell::new(None),
+        }
+    }
+    fn wireguard(&self) -> BoxResult<&Wireguard> {
+        self.wireguard
+            .as_ref()
+            .ok_or_else(|| "wireguard.dll is not loaded".into())
+    }
+    // Opens an existing adapter, if not yet opened or created
+    fn with_adapter<T>(&self, f: impl FnOnce(&Adapter) -> BoxResult<T>) -> BoxResult<T> {
+        let mut opt_adapter = self.adapter.borrow_mut();
+        if opt_adapter.is_none() {
+            *opt_adapter = Some(Adapter::open(self.wireguard()?, &self.device_name)?);
+        }
+        f(opt_adapter.as_ref().unwrap())
+    }
+    fn internal_execute_command(&self, args: Vec<&str>) -> BoxResult<std::process::Output> {
+        let output = Command::new(args[0])
+            .args(&args[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()?;
+
+        if output.status.success() {
+            Ok(output)
+        } else {
+            // netsh reports errors on stdout
+            #[allow(clippy::try_err)]
+            Err(format!(
+                "process failed with {}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ))?
+        }
+    }
+    fn execute_command(&self, args: Vec<&str>) -> BoxResult<std::process::Output> {
+        trace!(target: "shell", "{:?}", args);
+        self.internal_execute_command(args).map_err(|e| {
+            error!(target: "shell", "{:?}",e);
+            e
+        })
+    }
+    fn netsh_add_ipv6_address(&self, address: &str) -> BoxResult<()> {
+        let interface = format!("interface={}", self.device_name);
+        let _ = self.execute_command(vec![
+            "netsh",
+            "interface",
+            "ipv6",
+            "add",
+            "address",
+            &interface,
+            address,
+            "store=active",
+        ])?;
+        Ok(())
+    }
+    fn netsh_route(&self, cmd: &str, net: IpNet, gateway: Option<IpAddr>) -> BoxResult<()> {
+        let prefix = format!("prefix={}", net);
+        let interface = format!("interface={}", self.device_name);
+        let mut args = vec![
+            "netsh",
+            "interface",
+            netsh_family(&net.addr()),
+            cmd,
+            "route",
+            &prefix,
+            &interface,
+        ];
+        let nexthop = gateway.map(|gateway| format!("nexthop={}", gateway));
+        if let Some(nexthop) = nexthop.as_ref() {
+            args.push(nexthop);
+        }
+        args.push("store=active");
+        let _ = self.execute_command(args)?;
+        Ok(())
+    }
+    fn update_conf(&self, conf: &str, set_new: bool) -> BoxResult<()> {
+        debug!(target: "wireguard", "Update configuration: {}", conf);
+        let conf = WgConf::parse(conf)?;
+
+        let mut known_endpoints = HashMap::new();
+        if !set_new {
+            self.with_adapter(|adapter| {
+                for peer in adapter.get_config().peers {
+                    known_endpoints.insert(peer.public_key, peer.endpoint);
+                }
+                Ok(())
+            })?;
+        }
+
+        let unspecified: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        let peers = conf
+            .peers
+            .into_iter()
+            .map(|peer| SetPeer {
+                public_key: Some(peer.public_key),
+                preshared_key: peer.preshared_key,
+                keep_alive: peer.persistent_keepalive,
+                endpoint: peer
+                    .endpoint
+                    .or_else(|| known_endpoints.get(&peer.public_key).copied())
+                    .unwrap_or(unspecified),
+                allowed_ips: peer.allowed_ips,
+            })
+            .collect();
+        let interface = SetInterface {
+            listen_port: conf.listen_port,
+            public_key: None,
+            private_key: conf.private_key,
+            peers,
+        };
+        self.with_adapter(|adapter| Ok(adapter.set_config(&interface)?))
+    }
+}
+
+impl WireguardDevice for WireguardDeviceWindows {
+    fn check_device(&self) -> BoxResult<bool> {
+        debug!("Check for device {}", self.device_name);
+        Ok(self.with_adapter(|_| Ok(())).is_ok())
+    }
+    fn create_device(&self) -> BoxResult<()> {
+        debug!("Create device via wireguard-nt");
+        let adapter = Adapter::create(self.wireguard()?, ADAPTER_POOL, &self.device_name, None)?;
+        *self.adapter.borrow_mut() = Some(adapter);
+        debug!("Interface {} created", self.device_name);
+        Ok(())
+    }
+    fn take_down_device(&self) -> BoxResult<()> {
+        debug!("Take down device");
+        // Closing the handle removes an adapter created by this process.
+        // Adapters of crashed processes are removed by the driver.
+        self.adapter.borrow_mut().take();
+        debug!("Interface {} destroyed", self.device_name);
+        Ok(())
+    }
+    fn set_ip(&mut self, ip: &IpAddr, subnet: &IpNet, probe_net: Option<Ipv6Net>) -> BoxResult<()> {
+        debug!("Set IP {}", ip);
+        self.with_adapter(|adapter| Ok(adapter.up()?))?;
+        debug!("Interface {} up", self.device_name);
+
+        // Both also add the route for the subnet
+        match ip {
+            IpAddr::V4(_) => {
+                let name = format!("name={}", self.device_name);
+                let address = format!("address={}", ip);
+                let mask = format!("mask={}", subnet.netmask());
+                let _ = self.execute_command(vec![
+                    "netsh",
+                    "interface",
+                    "ipv4",
+                    "add",
+                    "address",
+                    &name,
+                    &address,
+                    &mask,
+                    "store=active",
+                ])?;
+            }
+            IpAddr::V6(_) => {
+                let address = format!("address={}/{}", ip, subnet.prefix_len());
+                self.netsh_add_ipv6_address(&address)?;
+            }
+        }
+        if let Some(probe_net) = probe_net {
+            let address = format!("address={}", probe_net);
+            self.netsh_add_ipv6_address(&address)?;
+        }
+        debug!("Interface {} set ip", self.device_name);
+        Ok(())
+    }
+    fn add_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        _attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        // Route attributes like mtu are not supported
+        debug!("Set route to {} via {:?}", host, gateway);
+        if gateway.is_some() {
+            self.netsh_route("add", host_net(host), gateway)?;
+        } else {
+            // I have already a route for the subnet
+        }
+        debug!("Interface {} set route", self.device_name);
+        Ok(())
+    }
+    fn replace_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        _attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        debug!("Replace route to {} via {:?}", host, gateway);
+        if gateway.is_some() {
+            // netsh set route cannot change the nexthop
+            let _ = self.netsh_route("delete", host_net(host), None);
+            self.netsh_route("add", host_net(host), gateway)?;
+        }
+        debug!("Interface {} set route", self.device_name);
+        Ok(())
+    }
+    fn del_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        _attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        if gateway.is_some() {
+            debug!("Delete route to {}", host);
+            self.netsh_route("delete", host_net(host), None)?;
+            debug!("Interface {} deleted route", self.device_name);
+        }
+        Ok(())
+    }
+    fn add_net_route(&self, net: IpNet) -> BoxResult<()> {
+        debug!("Set route to {}", net);
+        let _ = self.netsh_route("delete", net, None);
+        self.netsh_route("add", net, None)
+    }
+    fn del_net_route(&self, net: IpNet) -> BoxResult<()> {
+        debug!("Delete route to {}", net);
+        self.netsh_route("delete", net, None)
+    }
+    fn flush_all(&self) -> BoxResult<()> {
+        debug!("Flush routes and addresses");
+        let script = format!(
+            "Get-NetRoute -InterfaceAlias '{0}' -ErrorAction SilentlyContinue | Remove-NetRoute -Confirm:$false; \
+             Get-NetIPAddress -InterfaceAlias '{0}' -ErrorAction SilentlyContinue | Remove-NetIPAddress -Confirm:$false",
+            self.device_name
+        );
+        let _ = self.execute_command(vec!["powershell", "-NoProfile", "-Command", &script])?;
+        debug!("routes and addresses flushed");
+        Ok(())
+    }
+    fn set_conf(&self, conf: &str) -> BoxResult<()> {
+        self.update_conf(conf, true)
+    }
+    fn sync_conf(&self, conf: &str) -> BoxResult<()> {
+        self.update_conf(conf, false)
+    }
+    fn retrieve_conf(&self) -> BoxResult<HashMap<String, SocketAddr>> {
+        let mut pubkey_to_endpoint = HashMap::new();
+        let config = self.with_adapter(|adapter| Ok(adapter.get_config()))?;
+        for peer in config.peers {
+            if peer.endpoint.port() == 0 || peer.endpoint.ip() == IpAddr::from([0, 0, 0, 0]) {
+                continue;
+            }
+            let pub_key = base64::encode(peer.public_key);
+            let sock_addr = parse_wg_endpoint(&peer.endpoint.to_string())?;
+            trace!("{} is endpoint of {}", sock_addr, pub_key);
+            pubkey_to_endpoint.insert(pub_key, sock_addr);
+        }
+        Ok(pubkey_to_endpoint)
+    }
+    fn retrieve_peers(&self) -> BoxResult<Vec<WgPeerConf>> {
+        let config = self.with_adapter(|adapter| Ok(adapter.get_config()))?;
+        Ok(config
+            .peers
+            .into_iter()
+            .map(|peer| WgPeerConf {
+                public_key: peer.public_key,
+                // All zero, if there is none
+                preshared_key: Some(peer.preshared_key).filter(|key| *key != [0; 32]),
+                endpoint: Some(peer.endpoint).filter(|endpoint| {
+                    endpoint.port() != 0 && endpoint.ip() != IpAddr::from([0, 0, 0, 0])
+                }),
+                allowed_ips: peer.allowed_ips,
+                persistent_keepalive: Some(peer.persistent_keepalive).filter(|s| *s > 0),
+            })
+            .collect())
+    }
+    fn latest_handshakes(&self) -> BoxResult<HashMap<String, u64>> {
+        let config = self.with_adapter(|adapter| Ok(adapter.get_config()))?;
+        Ok(config
+            .peers
+            .iter()
+            .filter_map(|peer| {
+                let timestamp = peer
+                    .last_handshake?
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .ok()?;
+                Some((base64::encode(peer.public_key), timestamp.as_secs()))
+            })
+            .collect())
+    }
+}
+
+fn netsh_family(ip: &IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "ipv4",
+        IpAddr::V6(_) => "ipv6",
+    }
+}
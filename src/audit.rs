@@ -0,0 +1,257 @@
+// Mesh-wide consistency check.
+//
+// Starting with the local node, all nodes are asked for their view of the network
+// using the admin protocol (AuditRequest/Audit). Every route destination in a reply
+// is queried in turn, so the whole reachable mesh is covered.
+//
+// The collected reports are then cross-checked for:
+//      missing routes
+//      asymmetric gateways
+//      version skew of the copied route databases
+//      conflicting claims of one wg_ip (name or public key)
+//
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use log::*;
+
+use crate::configuration::*;
+use crate::crypt_udp::*;
+use crate::error::*;
+
+const ROUNDS: usize = 3;
+const ROUND_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, PartialEq)]
+pub enum AuditFinding {
+    NotReachable {
+        wg_ip: IpAddr,
+    },
+    MissingRoute {
+        on: IpAddr,
+        to: IpAddr,
+    },
+    AsymmetricGateway {
+        direct: IpAddr,
+        via_gateway: IpAddr,
+        gateway: IpAddr,
+    },
+    VersionSkew {
+        on: IpAddr,
+        of: IpAddr,
+        copy_version: usize,
+        current_version: usize,
+    },
+    ConflictingClaim {
+        wg_ip: IpAddr,
+        names: Vec<String>,
+        public_keys: Vec<String>,
+    },
+}
+impl fmt::Display for AuditFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use AuditFinding::*;
+        match self {
+            NotReachable { wg_ip } => write!(f, "{} has not answered", wg_ip),
+            MissingRoute { on, to } => write!(f, "{} has no route to {}", on, to),
+            AsymmetricGateway {
+                direct,
+                via_gateway,
+                gateway,
+            } => write!(
+                f,
+                "{} reaches {} directly, but {} uses gateway {}",
+                direct, via_gateway, via_gateway, gateway
+            ),
+            VersionSkew {
+                on,
+                of,
+                copy_version,
+                current_version,
+            } => write!(
+                f,
+                "{} uses route database version {} of {}, which is at version {}",
+                on, copy_version, of, current_version
+            ),
+            ConflictingClaim {
+                wg_ip,
+                names,
+                public_keys,
+            } => write!(
+                f,
+                "{} is claimed with names {:?} and public keys {:?}",
+                wg_ip, names, public_keys
+            ),
+        }
+    }
+}
+
+pub fn collect(static_config: &StaticConfiguration) -> BoxResult<HashMap<IpAddr, AuditPacket>> {
+    // The reports are requested via the overlay, so bind to the family of the wg_ip
+    let unspecified: IpAddr = match static_config.wg_ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let mut socket = CryptUdp::bind(unspecified, 0)?
+        .key(&static_config.shared_key)?
+        .accepted_keys(&static_config.accepted_shared_keys)?;
+    socket.set_read_timeout(Some(ROUND_TIMEOUT))?;
+
+    let mut admin_port_of = HashMap::new();
+    admin_port_of.insert(static_config.wg_ip, static_config.my_admin_port());
+    let mut reports: HashMap<IpAddr, AuditPacket> = HashMap::new();
+
+    for _ in 0..ROUNDS {
+        let pending = admin_port_of
+            .iter()
+            .filter(|(wg_ip, _)| !reports.contains_key(wg_ip))
+            .map(|(wg_ip, port)| SocketAddr::new(*wg_ip, *port))
+            .collect::<Vec<_>>();
+        if pending.is_empty() {
+            break;
+        }
+        let buf = bincode::serialize(&UdpPacket::audit_request()).unwrap();
+        for destination in pending {
+            debug!(target: "audit", "Send AuditRequest to {}", destination);
+            socket.send_to(&buf, destination)?;
+        }
+
+        // Collect answers until no more packets arrive within the timeout
+        let mut buf = [0; MAX_PACKET_SIZE];
+        while let Ok((received, src_addr)) = socket.recv_from(&mut buf) {
+            match UdpPacket::decode(&buf[..received]) {
+                Ok(UdpPacket::Audit(audit)) => {
+                    debug!(target: "audit", "Audit from {} via {}", audit.sender, src_addr);
+                    for ri in audit.known_routes.iter() {
+                        admin_port_of.entry(ri.to).or_insert(ri.local_admin_port);
+                    }
+                    reports.insert(audit.sender, audit);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!(target: "audit", "Error in decode: {:?}", e);
+                }
+            }
+        }
+    }
+
+    Ok(reports)
+}
+
+pub fn check(reports: &HashMap<IpAddr, AuditPacket>) -> Vec<AuditFinding> {
+    let mut findings = vec![];
+
+    let mut all_nodes = BTreeSet::new();
+    for audit in reports.values() {
+        all_nodes.insert(audit.sender);
+        for ri in audit.known_routes.iter() {
+            all_nodes.insert(ri.to);
+        }
+    }
+    for wg_ip in all_nodes.iter() {
+        if !reports.contains_key(wg_ip) {
+            findings.push(AuditFinding::NotReachable { wg_ip: *wg_ip });
+        }
+    }
+
+    let mut senders = reports.keys().copied().collect::<Vec<_>>();
+    senders.sort();
+    let routes_of = |wg_ip: &IpAddr| {
+        reports[wg_ip]
+            .known_routes
+            .iter()
+            .map(|ri| (ri.to, ri))
+            .collect::<BTreeMap<_, _>>()
+    };
+
+    for on in senders.iter() {
+        let routes = routes_of(on);
+        for to in all_nodes.iter() {
+            if to != on && !routes.contains_key(to) {
+                findings.push(AuditFinding::MissingRoute { on: *on, to: *to });
+            }
+        }
+        for (to, ri) in routes.iter() {
+            if ri.gateway.is_some() || !reports.contains_key(to) {
+                continue;
+            }
+            if let Some(gateway) = routes_of(to).get(on).and_then(|back| back.gateway) {
+                findings.push(AuditFinding::AsymmetricGateway {
+                    direct: *on,
+                    via_gateway: *to,
+                    gateway,
+                });
+            }
+        }
+        for ni in reports[on].known_nodes.iter() {
+            if let (Some(copy_version), Some(of)) = (ni.routedb_version, reports.get(&ni.wg_ip)) {
+                if copy_version != of.routedb_version {
+                    findings.push(AuditFinding::VersionSkew {
+                        on: *on,
+                        of: ni.wg_ip,
+                        copy_version,
+                        current_version: of.routedb_version,
+                    });
+                }
+            }
+        }
+    }
+
+    // Collect all claims for a wg_ip: the node's own and what the others know
+    let mut claims: BTreeMap<IpAddr, (BTreeSet<String>, BTreeSet<String>)> = BTreeMap::new();
+    for audit in reports.values() {
+        let claim = claims.entry(audit.sender).or_default();
+        claim.0.insert(audit.name.clone());
+        claim.1.insert(audit.public_key.key.clone());
+        for ni in audit.known_nodes.iter() {
+            let claim = claims.entry(ni.wg_ip).or_default();
+            claim.0.extend(ni.name.iter().cloned());
+            claim.1.extend(ni.public_key.iter().cloned());
+        }
+    }
+    for (wg_ip, (names, public_keys)) in claims.into_iter() {
+        if names.len() > 1 || public_keys.len() > 1 {
+            findings.push(AuditFinding::ConflictingClaim {
+                wg_ip,
+                names: names.into_iter().collect(),
+                public_keys: public_keys.into_iter().collect(),
+            });
+        }
+    }
+
+    findings
+}
+
+pub fn run(static_config: &StaticConfiguration) -> BoxResult<()> {
+    let reports = collect(static_config)?;
+    if reports.is_empty() {
+        return Err("No answer from the local node. Is wg_netmanager running ?".into());
+    }
+
+    let mut senders = reports.keys().collect::<Vec<_>>();
+    senders.sort();
+    println!("{} nodes have answered:", reports.len());
+    for wg_ip in senders {
+        let audit = &reports[wg_ip];
+        println!(
+            "  {:<15} {:<20} routedb version {:>5}, {} routes",
+            wg_ip,
+            audit.name,
+            audit.routedb_version,
+            audit.known_routes.len()
+        );
+    }
+
+    let findings = check(&reports);
+    if findings.is_empty() {
+        println!("No inconsistencies found");
+    } else {
+        println!("{} inconsistencies found:", findings.len());
+        for finding in findings.iter() {
+            println!("  {}", finding);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,152 @@
+// Join approval of new nodes via an allowlist of identities.
+//
+// With `--authorized-keys <path>`, a node, which is not a static peer, is only accepted
+// as peer, if its identity is listed in the file. The identity is used instead of the
+// wireguard key, because the keys rotate. One identity per line in base64 like
+// `identity` in network.yaml, optionally followed by a comment, e.g. the name:
+//
+//      <base64 identity> laptop
+//
+// The advertisements of other nodes are parked as pending. An admin approves a pending
+// node via the control socket, which appends it to the file. A missing file means,
+// that no node has been approved yet.
+//
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::crypt_udp::{AddressedTo, AdvertisementPacket};
+use crate::error::*;
+use crate::identity::PublicIdentity;
+
+// A pending node is forgotten, if it has not advertised for this long
+pub const PENDING_TIMEOUT: u64 = 600;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingPeer {
+    // base64
+    pub identity: String,
+    pub wg_ip: IpAddr,
+    pub name: String,
+    pub public_key: String,
+    pub endpoint: SocketAddr,
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+pub struct JoinApproval {
+    path: String,
+    authorized: HashSet<PublicIdentity>,
+    // With the reply to send on approval
+    pending: HashMap<PublicIdentity, (PendingPeer, AddressedTo)>,
+}
+impl JoinApproval {
+    // Without a readable file, no node is authorized
+    pub fn new<T: Into<String>>(path: T) -> Self {
+        let mut approval = JoinApproval {
+            path: path.into(),
+            authorized: HashSet::new(),
+            pending: HashMap::new(),
+        };
+        if let Err(e) = approval.reload() {
+            error!(target: "approval", "Cannot read {}: {}", approval.path, e);
+        }
+        approval
+    }
+    // Returns the number of authorized identities
+    pub fn reload(&mut self) -> BoxResult<usize> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut authorized = HashSet::new();
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let Some(identity) = line.split_whitespace().next() else {
+                continue;
+            };
+            let identity = base64::decode(identity)
+                .ok()
+                .and_then(|identity| PublicIdentity::try_from(identity).ok())
+                .ok_or_else(|| format!("Invalid identity {} in {}", identity, self.path))?;
+            authorized.insert(identity);
+        }
+        self.authorized = authorized;
+        self.pending
+            .retain(|identity, _| !self.authorized.contains(identity));
+        Ok(self.authorized.len())
+    }
+    pub fn is_authorized(&self, identity: &PublicIdentity) -> bool {
+        self.authorized.contains(identity)
+    }
+    // Park the advertisement of a node, which is not authorized
+    pub fn park(&mut self, now: u64, advertisement: &AdvertisementPacket, src_addr: SocketAddr) {
+        let identity = advertisement.identity;
+        let peer = PendingPeer {
+            identity: base64::encode(identity),
+            wg_ip: advertisement.wg_ip,
+            name: advertisement.name.clone(),
+            public_key: advertisement.public_key.key.clone(),
+            endpoint: src_addr,
+            first_seen: now,
+            last_seen: now,
+        };
+        let reply = advertisement.addressed_to.reply();
+        match self.pending.get_mut(&identity) {
+            Some((pending, pending_reply)) => {
+                *pending = PendingPeer {
+                    first_seen: pending.first_seen,
+                    ..peer
+                };
+                *pending_reply = reply;
+            }
+            None => {
+                warn!(target: "approval", "{} ({}) from {} waits for approval", peer.wg_ip, peer.name, src_addr);
+                self.pending.insert(identity, (peer, reply));
+            }
+        }
+    }
+    pub fn expire(&mut self, now: u64) {
+        self.pending
+            .retain(|_, (peer, _)| now < peer.last_seen + PENDING_TIMEOUT);
+    }
+    // Sorted by wg_ip
+    pub fn pending(&self) -> Vec<PendingPeer> {
+        let mut pending = self
+            .pending
+            .values()
+            .map(|(peer, _)| peer.clone())
+            .collect::<Vec<_>>();
+        pending.sort_by_key(|peer| peer.wg_ip);
+        pending
+    }
+    // A pending node given by identity, wg_ip or name is appended to the file.
+    // Returns the node and the reply to its advertisement.
+    pub fn approve(&mut self, peer: &str) -> BoxResult<(PendingPeer, AddressedTo)> {
+        let identity = self
+            .pending
+            .iter()
+            .find(|(_, (pending, _))| {
+                pending.identity == peer
+                    || pending.name == peer
+                    || pending.wg_ip.to_string() == peer
+            })
+            .map(|(identity, _)| *identity)
+            .ok_or_else(|| format!("{} is not pending", peer))?;
+        let (pending, _) = &self.pending[&identity];
+        let line = format!("{} {}\n", pending.identity, pending.name);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())?;
+        info!(target: "approval", "Approved {} ({}) in {}", pending.wg_ip, pending.name, self.path);
+        self.authorized.insert(identity);
+        Ok(self.pending.remove(&identity).unwrap())
+    }
+}
@@ -0,0 +1,152 @@
+// Chaos mode for lab use only.
+//
+// Outgoing admin packets are randomly dropped, delayed or duplicated and the route
+// computation is randomly restarted. All decisions are derived from one seed, so a
+// failing run can be reproduced.
+//
+// After each disruption the time until the routes are stable again is measured.
+// If the mesh does not converge within max_convergence_s, this is logged as error.
+//
+use std::time::Duration;
+
+use log::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+#[derive(Debug, Clone)]
+pub struct ChaosConfig {
+    pub seed: u64,
+    pub drop_percent: u32,
+    pub delay_percent: u32,
+    pub duplicate_percent: u32,
+    pub max_delay_ms: u64,
+    pub route_restart_percent: u32,
+    pub stable_after_s: u64,
+    pub max_convergence_s: u64,
+}
+impl ChaosConfig {
+    pub fn with_seed(seed: u64) -> Self {
+        ChaosConfig {
+            seed,
+            drop_percent: 10,
+            delay_percent: 10,
+            duplicate_percent: 5,
+            max_delay_ms: 2000,
+            route_restart_percent: 2,
+            stable_after_s: 30,
+            max_convergence_s: 300,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChaosAction {
+    Send,
+    Drop,
+    Delay(Duration),
+    Duplicate,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ChaosStats {
+    pub packets: usize,
+    pub dropped: usize,
+    pub delayed: usize,
+    pub duplicated: usize,
+    pub route_restarts: usize,
+    pub convergences: usize,
+    pub convergence_failures: usize,
+    pub max_convergence_time_s: u64,
+}
+
+pub struct Chaos {
+    config: ChaosConfig,
+    rng: StdRng,
+    stats: ChaosStats,
+    disrupted_since: Option<u64>,
+    last_route_change: u64,
+    failure_reported: bool,
+}
+impl Chaos {
+    pub fn new(config: ChaosConfig) -> Self {
+        warn!(target: "chaos", "Chaos mode active with seed {}", config.seed);
+        Chaos {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            stats: ChaosStats::default(),
+            disrupted_since: None,
+            last_route_change: 0,
+            failure_reported: false,
+        }
+    }
+    pub fn stats(&self) -> &ChaosStats {
+        &self.stats
+    }
+    fn percent(&mut self, percent: u32) -> bool {
+        self.rng.gen_range(0..100) < percent
+    }
+    fn disrupted(&mut self, now: u64) {
+        if self.disrupted_since.is_none() {
+            self.disrupted_since = Some(now);
+            self.failure_reported = false;
+        }
+    }
+    pub fn packet_action(&mut self, now: u64) -> ChaosAction {
+        self.stats.packets += 1;
+        let action = if self.percent(self.config.drop_percent) {
+            self.stats.dropped += 1;
+            ChaosAction::Drop
+        } else if self.percent(self.config.delay_percent) {
+            self.stats.delayed += 1;
+            let delay_ms = self.rng.gen_range(0..=self.config.max_delay_ms);
+            ChaosAction::Delay(Duration::from_millis(delay_ms))
+        } else if self.percent(self.config.duplicate_percent) {
+            self.stats.duplicated += 1;
+            ChaosAction::Duplicate
+        } else {
+            ChaosAction::Send
+        };
+        if action != ChaosAction::Send {
+            trace!(target: "chaos", "packet action {:?}", action);
+            self.disrupted(now);
+        }
+        action
+    }
+    pub fn restart_route_computation(&mut self, now: u64) -> bool {
+        if self.percent(self.config.route_restart_percent) {
+            debug!(target: "chaos", "restart route computation");
+            self.stats.route_restarts += 1;
+            self.disrupted(now);
+            true
+        } else {
+            false
+        }
+    }
+    pub fn routes_changed(&mut self, now: u64, nr_changes: usize) {
+        if nr_changes > 0 {
+            self.last_route_change = now;
+        }
+    }
+    // Returns the convergence time, if the mesh has just become stable again
+    pub fn check_convergence(&mut self, now: u64) -> Option<u64> {
+        let since = self.disrupted_since?;
+        let stable_since = self.last_route_change.max(since);
+        if now >= stable_since + self.config.stable_after_s {
+            let dt = stable_since - since;
+            self.disrupted_since = None;
+            self.stats.convergences += 1;
+            self.stats.max_convergence_time_s = self.stats.max_convergence_time_s.max(dt);
+            info!(target: "chaos", "mesh converged {} s after disruption", dt);
+            return Some(dt);
+        }
+        if now > since + self.config.max_convergence_s && !self.failure_reported {
+            self.failure_reported = true;
+            self.stats.convergence_failures += 1;
+            error!(target: "chaos", "mesh has not converged within {} s", self.config.max_convergence_s);
+        }
+        None
+    }
+    pub fn output_stats(&self) {
+        info!(target: "chaos", "{:?}", self.stats);
+    }
+}
@@ -0,0 +1,349 @@
+// Typed content of network.yaml and peer.yaml.
+//
+// Both files are deserialized with serde into the structs below and may be written
+// as TOML instead, e.g. network.toml, which is chosen by the file extension:
+//
+//      [network]
+//      sharedKey = "..."
+//      subnet = "10.1.1.0/24"
+//
+//      [[peers]]
+//      endPoint = "192.168.1.70:50000"
+//      adminPort = 50500
+//      wgIp = "10.1.1.1"
+//
+// Errors name the offending key, for yaml with line and column. Unknown keys are
+// kept in `other` and only warned about, so a newer file still works with an older
+// binary. The integer keys of `areas` are written as strings in TOML.
+//
+// The secrets, i.e. sharedKey, acceptedSharedKeys, the webhooks and httpToken, can be
+// given by reference and are resolved at load time, so network.yaml can be
+// world-readable:
+//
+//      ${WG_SHARED_KEY}            the environment variable
+//      file:/etc/wg/shared_key     the content of the file without trailing whitespace
+//
+// A secrets file must not be accessible by others.
+//
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use ipnet::IpNet;
+use log::*;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+use crate::configuration::{
+    Backend, MeshDns, RoutingPolicy, DEFAULT_DECRYPT_FAILURE_THRESHOLD, DEFAULT_GATEWAY_GRACE,
+    DEFAULT_TIER,
+};
+use crate::endpoint_resolver::EndpointResolver;
+use crate::error::*;
+use crate::ha_pair::DEFAULT_HA_PRIORITY;
+use crate::wg_dev::TeardownStep;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+}
+impl ConfigFormat {
+    // TOML for the extension .toml, otherwise yaml
+    pub fn of_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+}
+
+pub fn parse<T: DeserializeOwned>(content: &str, format: ConfigFormat) -> BoxResult<T> {
+    match format {
+        ConfigFormat::Yaml => Ok(serde_yaml::from_str(content)?),
+        ConfigFormat::Toml => {
+            let document = content.parse::<toml_edit::DocumentMut>()?;
+            let value = toml_table(document.as_table().iter());
+            // Deserialized from the yaml text for the path of the key in errors. Its
+            // lines do not match the TOML file, so the location is dropped.
+            let yaml = serde_yaml::to_string(&value)?;
+            serde_yaml::from_str(&yaml).map_err(|e| {
+                let e = e.to_string();
+                e.rsplit_once(" at line ")
+                    .map(|(e, _)| e.to_string())
+                    .unwrap_or(e)
+                    .into()
+            })
+        }
+    }
+}
+
+fn toml_table<'a, I: Iterator<Item = (&'a str, &'a toml_edit::Item)>>(items: I) -> Value {
+    let mut mapping = Mapping::new();
+    for (key, item) in items {
+        mapping.insert(toml_key(key), toml_item(item));
+    }
+    Value::Mapping(mapping)
+}
+// e.g. the area ids
+fn toml_key(key: &str) -> Value {
+    match key.parse::<i64>() {
+        Ok(number) => Value::Number(number.into()),
+        Err(_) => Value::String(key.to_string()),
+    }
+}
+fn toml_item(item: &toml_edit::Item) -> Value {
+    match item {
+        toml_edit::Item::None => Value::Null,
+        toml_edit::Item::Value(value) => toml_value(value),
+        toml_edit::Item::Table(table) => toml_table(table.iter()),
+        toml_edit::Item::ArrayOfTables(tables) => Value::Sequence(
+            tables
+                .iter()
+                .map(|table| toml_table(table.iter()))
+                .collect(),
+        ),
+    }
+}
+fn toml_value(value: &toml_edit::Value) -> Value {
+    match value {
+        toml_edit::Value::String(s) => Value::String(s.value().clone()),
+        toml_edit::Value::Integer(i) => Value::Number((*i.value()).into()),
+        toml_edit::Value::Float(f) => Value::Number((*f.value()).into()),
+        toml_edit::Value::Boolean(b) => Value::Bool(*b.value()),
+        toml_edit::Value::Datetime(dt) => Value::String(dt.value().to_string()),
+        toml_edit::Value::Array(array) => Value::Sequence(array.iter().map(toml_value).collect()),
+        toml_edit::Value::InlineTable(table) => {
+            let mut mapping = Mapping::new();
+            for (key, value) in table.iter() {
+                mapping.insert(toml_key(key), toml_value(value));
+            }
+            Value::Mapping(mapping)
+        }
+    }
+}
+
+// Resolve a secret given by reference. The key is for the errors.
+pub fn expand_secret(key: &str, value: &str) -> BoxResult<String> {
+    if let Some(var) = value.strip_prefix("${").and_then(|v| v.strip_suffix('}')) {
+        return std::env::var(var)
+            .map_err(|_| format!("{}: environment variable {} is not set", key, var).into());
+    }
+    let Some(path) = value.strip_prefix("file:") else {
+        return Ok(value.to_string());
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)
+            .map_err(|e| format!("{}: {}: {}", key, path, e))?
+            .permissions()
+            .mode();
+        if mode & 0o007 != 0 {
+            return Err(format!(
+                "{}: {} is accessible by others, remove with chmod o-rwx",
+                key, path
+            )
+            .into());
+        }
+    }
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}: {}", key, path, e))?;
+    Ok(content.trim_end().to_string())
+}
+
+// The names of the unknown keys below prefix
+fn unknown_keys(prefix: &str, other: &Mapping) -> Vec<String> {
+    other
+        .iter()
+        .map(|(key, _)| match key {
+            Value::String(key) => format!("{}{}", prefix, key),
+            key => format!("{}{:?}", prefix, key),
+        })
+        .collect()
+}
+pub fn warn_unknown_keys(source: &str, keys: &[String]) {
+    for key in keys.iter() {
+        warn!("{}: unknown key {} is ignored", source, key);
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AllocationStrategy {
+    Explicit,
+    LastByte,
+    Hash,
+    Fixed,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PortAllocationConfig {
+    pub strategy: AllocationStrategy,
+    // The base ports resp. the ports for fixed
+    pub wg_port: Option<u16>,
+    pub admin_port: Option<u16>,
+    pub range: Option<u16>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkSettings {
+    pub name: Option<String>,
+    pub shared_key: String,
+    #[serde(default)]
+    pub accepted_shared_keys: Vec<String>,
+    pub subnet: IpNet,
+    pub port_allocation: Option<PortAllocationConfig>,
+    #[serde(default)]
+    pub areas: BTreeMap<u16, IpNet>,
+    pub dns: Option<MeshDns>,
+    pub persistent_keepalive: Option<u16>,
+    #[serde(default)]
+    pub routing: RoutingPolicy,
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StaticPeerConfig {
+    // <host:wg_port>
+    pub end_point: String,
+    pub admin_port: u16,
+    pub wg_ip: IpAddr,
+    // base64
+    pub identity: Option<String>,
+    #[serde(default = "default_tier")]
+    pub tier: u8,
+    #[serde(default)]
+    pub resolver: EndpointResolver,
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+fn default_tier() -> u8 {
+    DEFAULT_TIER
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct NetworkConfig {
+    pub network: NetworkSettings,
+    pub peers: Vec<StaticPeerConfig>,
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+impl NetworkConfig {
+    pub fn unknown_keys(&self) -> Vec<String> {
+        let mut keys = unknown_keys("", &self.other);
+        keys.extend(unknown_keys("network.", &self.network.other));
+        for (i, peer) in self.peers.iter().enumerate() {
+            keys.extend(unknown_keys(&format!("peers[{}].", i), &peer.other));
+        }
+        keys
+    }
+}
+
+// peer.yaml. Each key can be overridden on the command line.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct PeerConfig {
+    pub name: Option<String>,
+    pub wg_interface: Option<String>,
+    // An ip or auto
+    pub wg_ip: Option<String>,
+    pub wg_port: Option<u16>,
+    pub admin_port: Option<u16>,
+    #[serde(default)]
+    pub extra_wg_ports: Vec<u16>,
+    #[serde(default)]
+    pub existing_interface: bool,
+    #[serde(default)]
+    pub adopt_peers: bool,
+    #[serde(default)]
+    pub teardown_skip: Vec<TeardownStep>,
+    #[serde(default)]
+    pub interface_altname: bool,
+    #[serde(default)]
+    pub disable_ipv6: bool,
+    #[serde(default)]
+    pub router_hairpinning: bool,
+    #[serde(default = "default_decrypt_failure_threshold")]
+    pub decrypt_failure_threshold: u32,
+    pub key_lifetime: Option<u64>,
+    pub log_sample: Option<u64>,
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+    #[serde(default)]
+    pub mdns: bool,
+    pub identity_file: Option<String>,
+    // Destination to gateway
+    #[serde(default)]
+    pub pinned_routes: HashMap<IpAddr, IpAddr>,
+    #[serde(default)]
+    pub exported_nets: Vec<IpNet>,
+    pub drop_privileges: Option<String>,
+    #[serde(default)]
+    pub keep_net_admin: bool,
+    pub backend: Option<Backend>,
+    pub policy_script: Option<String>,
+    pub upgrade_socket: Option<String>,
+    pub control_socket: Option<String>,
+    pub control_group: Option<String>,
+    pub control_admin_group: Option<String>,
+    pub status_file: Option<String>,
+    pub state_file: Option<String>,
+    pub metrics_port: Option<u16>,
+    pub metrics_address: Option<IpAddr>,
+    pub status_page_port: Option<u16>,
+    pub http_token: Option<String>,
+    pub mesh_domain: Option<String>,
+    pub hosts_file: Option<String>,
+    pub authorized_keys: Option<String>,
+    #[serde(default)]
+    pub fleet_admins: Vec<String>,
+    #[serde(default)]
+    pub fleet_commands: Vec<String>,
+    pub peer_names: Option<String>,
+    #[serde(default = "default_gateway_grace")]
+    pub gateway_grace: u64,
+    pub ha_partner: Option<SocketAddr>,
+    #[serde(default = "default_ha_priority")]
+    pub ha_priority: u8,
+    pub ha_notify: Option<String>,
+    pub import_peers: Option<String>,
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+fn default_decrypt_failure_threshold() -> u32 {
+    DEFAULT_DECRYPT_FAILURE_THRESHOLD
+}
+fn default_gateway_grace() -> u64 {
+    DEFAULT_GATEWAY_GRACE
+}
+fn default_ha_priority() -> u8 {
+    DEFAULT_HA_PRIORITY
+}
+impl Default for PeerConfig {
+    // With the defaults of the keys
+    fn default() -> Self {
+        parse("{}", ConfigFormat::Yaml).unwrap()
+    }
+}
+impl PeerConfig {
+    pub fn unknown_keys(&self) -> Vec<String> {
+        unknown_keys("", &self.other)
+    }
+    // The webhook urls and httpToken are secrets
+    pub fn expand_secrets(&mut self) -> BoxResult<()> {
+        for url in self.webhooks.iter_mut() {
+            *url = expand_secret("webhooks", url)?;
+        }
+        if let Some(token) = self.http_token.as_mut() {
+            *token = expand_secret("httpToken", token)?;
+        }
+        Ok(())
+    }
+}
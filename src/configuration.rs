@@ -1,43 +1,638 @@
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 //use log::*;
+use ipnet::{IpNet, Ipv6Net};
 use serde::{Deserialize, Serialize};
 
+use crate::config_file::*;
+use crate::endpoint_resolver::EndpointResolver;
+use crate::error::*;
+use crate::ha_pair::HaPair;
+use crate::identity::{decode_identity, NodeIdentity, PublicIdentity};
+use crate::link_local::split_scope;
 use crate::manager::*;
+use crate::readdress::PreviousWgIp;
+use crate::wg_dev::{host_net, Teardown, TeardownStep, WgPeerConf};
+
+const REDACTED: &str = "<redacted>";
+
+// Interface altnames starting with this prefix name the owning network
+pub const ALTNAME_PREFIX: &str = "wgnm-";
+// Linux limits interface names to IFNAMSIZ-1 characters
+const MAX_INTERFACE_NAME_LEN: usize = 15;
+pub const DEFAULT_DECRYPT_FAILURE_THRESHOLD: u32 = 10;
+// Warm-up of a node before it offers to be the gateway for others
+pub const DEFAULT_GATEWAY_GRACE: u64 = 60;
+// Keepalive interval for peers behind NAT, if not configured in network.yaml
+pub const DEFAULT_PERSISTENT_KEEPALIVE: u16 = 25;
+
+// Default unix socket for binary upgrades, one per interface
+pub fn upgrade_socket_for_interface(wg_name: &str) -> String {
+    format!("/run/wg_netmanager/{}.upgrade", wg_name)
+}
+pub fn control_socket_for_interface(wg_name: &str) -> String {
+    format!("/run/wg_netmanager/{}.control", wg_name)
+}
+
+// The last byte of the wg_ip
+pub fn default_port_offset(wg_ip: &IpAddr) -> u16 {
+    match wg_ip {
+        IpAddr::V4(ip) => ip.octets()[3] as u16,
+        IpAddr::V6(ip) => ip.octets()[15] as u16,
+    }
+}
+
+pub const DEFAULT_WG_PORT_BASE: u16 = 50000;
+pub const DEFAULT_ADMIN_PORT_BASE: u16 = 50500;
+pub const DEFAULT_PORT_RANGE: u16 = 500;
+
+// How a node derives its wireguard and admin port, if not given explicitly.
+// Configured in network.yaml, so all nodes of a network use the same strategy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortAllocation {
+    // The ports have to be configured for each node
+    Explicit,
+    // Base port plus the last byte of the wg_ip. Collides for subnets larger than /24
+    LastByte {
+        wg_base: u16,
+        admin_base: u16,
+    },
+    // Base port plus a hash over the full wg_ip modulo range
+    Hash {
+        wg_base: u16,
+        admin_base: u16,
+        range: u16,
+    },
+    // Same ports on all nodes
+    Fixed {
+        wg_port: u16,
+        admin_port: u16,
+    },
+}
+impl Default for PortAllocation {
+    fn default() -> Self {
+        PortAllocation::LastByte {
+            wg_base: DEFAULT_WG_PORT_BASE,
+            admin_base: DEFAULT_ADMIN_PORT_BASE,
+        }
+    }
+}
+impl PortAllocation {
+    // network/portAllocation. Missing means lastByte with the default base ports.
+    pub fn from_config(conf: Option<&PortAllocationConfig>) -> BoxResult<Self> {
+        let Some(conf) = conf else {
+            return Ok(PortAllocation::default());
+        };
+        let port = |port: Option<u16>, name: &str, default: u16| -> BoxResult<u16> {
+            match port {
+                Some(0) => Err(format!("portAllocation: {} is out of range", name).into()),
+                Some(port) => Ok(port),
+                None => Ok(default),
+            }
+        };
+        let wg_port = |default| port(conf.wg_port, "wgPort", default);
+        let admin_port = |default| port(conf.admin_port, "adminPort", default);
+        let allocation = match conf.strategy {
+            AllocationStrategy::Explicit => PortAllocation::Explicit,
+            AllocationStrategy::LastByte => PortAllocation::LastByte {
+                wg_base: wg_port(DEFAULT_WG_PORT_BASE)?,
+                admin_base: admin_port(DEFAULT_ADMIN_PORT_BASE)?,
+            },
+            AllocationStrategy::Hash => PortAllocation::Hash {
+                wg_base: wg_port(DEFAULT_WG_PORT_BASE)?,
+                admin_base: admin_port(DEFAULT_ADMIN_PORT_BASE)?,
+                range: port(conf.range, "range", DEFAULT_PORT_RANGE)?,
+            },
+            AllocationStrategy::Fixed => PortAllocation::Fixed {
+                wg_port: wg_port(DEFAULT_WG_PORT_BASE)?,
+                admin_port: admin_port(DEFAULT_ADMIN_PORT_BASE)?,
+            },
+        };
+        allocation.validate()?;
+        Ok(allocation)
+    }
+
+    // The wireguard and admin port ranges must neither overlap nor exceed u16
+    fn validate(&self) -> BoxResult<()> {
+        let (wg_base, admin_base, range) = match *self {
+            PortAllocation::Explicit => return Ok(()),
+            PortAllocation::LastByte {
+                wg_base,
+                admin_base,
+            } => (wg_base as u32, admin_base as u32, 256),
+            PortAllocation::Hash {
+                wg_base,
+                admin_base,
+                range,
+            } => (wg_base as u32, admin_base as u32, range as u32),
+            PortAllocation::Fixed {
+                wg_port,
+                admin_port,
+            } => (wg_port as u32, admin_port as u32, 1),
+        };
+        if wg_base + range > 65536 || admin_base + range > 65536 {
+            return Err("portAllocation: port range exceeds 65535".into());
+        }
+        if wg_base < admin_base + range && admin_base < wg_base + range {
+            return Err("portAllocation: wireguard and admin ports overlap".into());
+        }
+        Ok(())
+    }
+
+    // Returns (wg_port, admin_port) or None for explicit allocation
+    pub fn ports(&self, wg_ip: &IpAddr) -> Option<(u16, u16)> {
+        match *self {
+            PortAllocation::Explicit => None,
+            PortAllocation::LastByte {
+                wg_base,
+                admin_base,
+            } => {
+                let offset = default_port_offset(wg_ip);
+                Some((wg_base + offset, admin_base + offset))
+            }
+            PortAllocation::Hash {
+                wg_base,
+                admin_base,
+                range,
+            } => {
+                let offset = (ip_hash(wg_ip) % range as u32) as u16;
+                Some((wg_base + offset, admin_base + offset))
+            }
+            PortAllocation::Fixed {
+                wg_port,
+                admin_port,
+            } => Some((wg_port, admin_port)),
+        }
+    }
+}
+
+// FNV-1a, which is stable across rust versions and platforms unlike DefaultHasher
+fn ip_hash(ip: &IpAddr) -> u32 {
+    let octets = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    octets.iter().fold(0x811c9dc5, |hash, b| {
+        (hash ^ *b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+// DNS servers in the mesh and the domains resolved by them, e.g. a forwarder on a
+// static peer. Only the names of these domains are resolved via the mesh.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MeshDns {
+    pub servers: Vec<IpAddr>,
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+impl MeshDns {
+    // The domains may be given with the prefix ~ as for systemd-resolved
+    fn validated(mut self) -> BoxResult<Self> {
+        if self.servers.is_empty() {
+            return Err("dns: no servers defined".into());
+        }
+        for domain in self.domains.iter_mut() {
+            *domain = domain.trim_start_matches('~').to_string();
+        }
+        Ok(self)
+    }
+}
+
+// Selection between several paths to a node
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum RouteMetric {
+    // Fewest hops. The path cost only decides between paths of equal length.
+    #[default]
+    Hops,
+    // Lowest path cost
+    Cost,
+}
+
+// The implementation of the wireguard device
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Backend {
+    // The kernel module, or else wireguard-go or the boringtun binary
+    #[default]
+    Auto,
+    // Only the kernel module
+    Kernel,
+    // Only a userspace binary like wg-quick uses, i.e.
+    // $WG_QUICK_USERSPACE_IMPLEMENTATION, wireguard-go or boringtun
+    #[serde(rename = "wg-quick")]
+    WgQuick,
+    // boringtun in-process on a tun interface. Linux only with feature userspace.
+    Userspace,
+    // The interface exists already and is neither created nor taken down
+    Existing,
+}
+impl Backend {
+    pub const ALL: [Backend; 5] = [
+        Backend::Auto,
+        Backend::Kernel,
+        Backend::WgQuick,
+        Backend::Userspace,
+        Backend::Existing,
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Auto => "auto",
+            Backend::Kernel => "kernel",
+            Backend::WgQuick => "wg-quick",
+            Backend::Userspace => "userspace",
+            Backend::Existing => "existing",
+        }
+    }
+}
+impl std::str::FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Backend::ALL
+            .into_iter()
+            .find(|backend| backend.name() == s)
+            .ok_or_else(|| format!("unknown backend {}", s))
+    }
+}
+
+// Cost of a link without a configured weight
+pub const DEFAULT_LINK_WEIGHT: u32 = 10;
+
+// Preference and bandwidth of the link between two nodes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct LinkHint {
+    pub between: [IpAddr; 2],
+    // Replaces DEFAULT_LINK_WEIGHT in the cost of the link
+    pub weight: Option<u32>,
+    pub bandwidth_mbit: Option<u32>,
+}
+
+// Cost model of the routes, which is shared by all nodes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct RoutingPolicy {
+    pub metric: RouteMetric,
+    pub links: Vec<LinkHint>,
+}
+impl RoutingPolicy {
+    fn validate(&self) -> BoxResult<()> {
+        if self.links.iter().any(|link| link.bandwidth_mbit == Some(0)) {
+            return Err("routing: bandwidthMbit needs to be positive".into());
+        }
+        Ok(())
+    }
+    pub fn link(&self, a: &IpAddr, b: &IpAddr) -> Option<&LinkHint> {
+        self.links
+            .iter()
+            .find(|link| link.between == [*a, *b] || link.between == [*b, *a])
+    }
+    // Cost and bandwidth of the link to a direct peer. The cost is the weight plus the
+    // round trip time in ms plus 1000 divided by the bandwidth in Mbit/s.
+    pub fn link_cost(&self, me: &IpAddr, peer: &IpAddr, rtt_ms: Option<u32>) -> (u32, Option<u32>) {
+        let link = self.link(me, peer);
+        let bandwidth_mbit = link.and_then(|link| link.bandwidth_mbit);
+        let cost = link
+            .and_then(|link| link.weight)
+            .unwrap_or(DEFAULT_LINK_WEIGHT)
+            .saturating_add(rtt_ms.unwrap_or(0))
+            .saturating_add(bandwidth_mbit.map(|bw| 1000 / bw).unwrap_or(0));
+        (cost, bandwidth_mbit)
+    }
+}
+
+// Default interface name, if a name is given for the network, but no interface
+pub fn interface_name_for_network(network_name: &str) -> String {
+    let mut name = "wg".to_string();
+    name.extend(
+        network_name
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-'),
+    );
+    name.truncate(MAX_INTERFACE_NAME_LEN);
+    name
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
 pub struct PublicKeyWithTime {
     pub key: String, // base64 encoded
     pub priv_key_creation_time: u64,
 }
+impl PublicKeyWithTime {
+    pub fn new<T: Into<String>>(key: T, priv_key_creation_time: u64) -> Self {
+        PublicKeyWithTime {
+            key: key.into(),
+            priv_key_creation_time,
+        }
+    }
+}
+
+// Key pair replacing the current one at public_key.priv_key_creation_time
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingKeyPair {
+    pub private_key: String,
+    pub public_key: PublicKeyWithTime,
+}
 
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct PublicPeer {
     pub endpoint: String,
     pub wg_port: u16,
     pub admin_port: u16,
-    pub wg_ip: Ipv4Addr,
+    pub wg_ip: IpAddr,
+    // Only advertisements signed by this identity are accepted for wg_ip
+    pub identity: Option<PublicIdentity>,
+    // Static peers of a lower tier are contacted first, e.g. the hub of the own region
+    pub tier: u8,
+    // Source of the ips of the endpoint's host
+    pub resolver: EndpointResolver,
+}
+impl PublicPeer {
+    // Without identity, of the default tier and resolved via dns
+    pub fn new<T: Into<String>>(wg_ip: IpAddr, endpoint: T, wg_port: u16, admin_port: u16) -> Self {
+        PublicPeer {
+            endpoint: endpoint.into(),
+            wg_port,
+            admin_port,
+            wg_ip,
+            identity: None,
+            tier: DEFAULT_TIER,
+            resolver: EndpointResolver::default(),
+        }
+    }
+    pub fn resolve_endpoint(&self) -> BoxResult<Vec<IpAddr>> {
+        self.resolver.resolve(&self.endpoint)
+    }
+    // Of a link-local endpoint like [fe80::1%eth0]:50001, else 0
+    pub fn scope_id(&self) -> u32 {
+        split_scope(&self.endpoint)
+            .ok()
+            .and_then(|(_, scope_id)| scope_id)
+            .unwrap_or(0)
+    }
+}
+
+// Tier of a static peer without tier in network.yaml
+pub const DEFAULT_TIER: u8 = 1;
+
+// The area of a node is given by its wg_ip
+pub fn area_of(areas: &BTreeMap<u16, ipnet::IpNet>, wg_ip: &IpAddr) -> Option<u16> {
+    areas
+        .iter()
+        .find(|(_, net)| net.contains(wg_ip))
+        .map(|(id, _)| *id)
+}
+
+pub fn nets_overlap(a: &ipnet::IpNet, b: &ipnet::IpNet) -> bool {
+    a.contains(b) || b.contains(a)
+}
+
+// A wg_ip needs to be a host address of the subnet. The network and the broadcast
+// address of an ipv4 subnet are excluded, except for /31 and /32 (RFC 3021). On ipv6,
+// the network address is the subnet-router anycast address, except for /127 and /128.
+pub fn check_wg_ip(subnet: &ipnet::IpNet, wg_ip: &IpAddr) -> BoxResult<()> {
+    if !subnet.contains(wg_ip) {
+        return Err(format!("{} is outside of subnet {}", wg_ip, subnet).into());
+    }
+    let point_to_point = subnet.max_prefix_len() - subnet.prefix_len() <= 1;
+    if point_to_point {
+        return Ok(());
+    }
+    if *wg_ip == subnet.network() {
+        return Err(format!("{} is the network address of subnet {}", wg_ip, subnet).into());
+    }
+    if *wg_ip == subnet.broadcast() && wg_ip.is_ipv4() {
+        return Err(format!("{} is the broadcast address of subnet {}", wg_ip, subnet).into());
+    }
+    Ok(())
+}
+pub fn is_host_address(subnet: &ipnet::IpNet, wg_ip: &IpAddr) -> bool {
+    check_wg_ip(subnet, wg_ip).is_ok()
+}
+
+// network/areas, a map of area id to a part of the subnet
+fn validated_areas(
+    areas: &BTreeMap<u16, ipnet::IpNet>,
+    subnet: &ipnet::IpNet,
+) -> BoxResult<BTreeMap<u16, ipnet::IpNet>> {
+    let mut validated = BTreeMap::new();
+    for (id, net) in areas.iter() {
+        if !subnet.contains(net) {
+            return Err(format!("areas: area {} is outside of the subnet", id).into());
+        }
+        if validated.values().any(|other| nets_overlap(other, net)) {
+            return Err(format!("areas: area {} overlaps with another area", id).into());
+        }
+        validated.insert(*id, net.trunc());
+    }
+    Ok(validated)
+}
+
+// The content of network.yaml, which is shared by all nodes of a network
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct NetworkConfiguration {
+    pub name: Option<String>,
+    pub shared_key: Vec<u8>,
+    // Accepted for received admin packets, while sharedKey is used for sending
+    pub accepted_shared_keys: Vec<Vec<u8>>,
+    pub subnet: ipnet::IpNet,
+    pub port_allocation: PortAllocation,
+    // Area id and its part of the subnet
+    pub areas: BTreeMap<u16, ipnet::IpNet>,
+    pub dns: Option<MeshDns>,
+    // Keepalive for all direct peers, 0 disables it also for peers behind NAT
+    pub persistent_keepalive: Option<u16>,
+    pub routing: RoutingPolicy,
+    pub peers: HashMap<IpAddr, PublicPeer>,
+}
+impl NetworkConfiguration {
+    pub fn from_yaml_str(content: &str) -> BoxResult<Self> {
+        Self::from_str(content, ConfigFormat::Yaml, "network configuration")
+    }
+    // Unknown keys are warned about with source as origin
+    pub fn from_str(content: &str, format: ConfigFormat, source: &str) -> BoxResult<Self> {
+        let config: NetworkConfig = parse(content, format)?;
+        warn_unknown_keys(source, &config.unknown_keys());
+        Self::from_config(config)
+    }
+    pub fn from_config(config: NetworkConfig) -> BoxResult<Self> {
+        let network = config.network;
+        let shared_key = expand_secret("network.sharedKey", &network.shared_key)?;
+        let shared_key =
+            base64::decode(shared_key).map_err(|e| format!("network.sharedKey: {}", e))?;
+        let mut accepted_shared_keys = vec![];
+        for key in network.accepted_shared_keys.iter() {
+            let key = expand_secret("network.acceptedSharedKeys", key)?;
+            let key =
+                base64::decode(key).map_err(|e| format!("network.acceptedSharedKeys: {}", e))?;
+            if key.len() != 32 {
+                return Err("Invalid key length in acceptedSharedKeys".into());
+            }
+            accepted_shared_keys.push(key);
+        }
+        let subnet = network.subnet;
+        let port_allocation = PortAllocation::from_config(network.port_allocation.as_ref())?;
+        let areas = validated_areas(&network.areas, &subnet)?;
+        let dns = network.dns.map(MeshDns::validated).transpose()?;
+        network.routing.validate()?;
+
+        let mut peers: HashMap<IpAddr, PublicPeer> = HashMap::new();
+        for p in config.peers {
+            log::info!("STATIC PEER: {:#?}", p);
+            let wg_ip = p.wg_ip;
+            check_wg_ip(&subnet, &wg_ip).map_err(|e| format!("peer {}: {}", wg_ip, e))?;
+            let wg_port = p
+                .end_point
+                .rsplit_once(':')
+                .and_then(|(_, port)| port.parse::<u16>().ok())
+                .ok_or_else(|| format!("peer {}: endPoint should be <hostname/ip:port>", wg_ip))?;
+            if wg_port == p.admin_port {
+                return Err(format!("peer {}: endPoint uses the adminPort", wg_ip).into());
+            }
+            let identity = match p.identity.as_deref() {
+                Some(identity) => Some(decode_identity(identity)?),
+                None => None,
+            };
+            let pp = PublicPeer {
+                endpoint: p.end_point,
+                admin_port: p.admin_port,
+                wg_port,
+                wg_ip,
+                identity,
+                tier: p.tier,
+                resolver: p.resolver,
+            };
+            peers.insert(wg_ip, pp);
+        }
+
+        Ok(NetworkConfiguration {
+            name: network.name,
+            shared_key,
+            accepted_shared_keys,
+            subnet,
+            port_allocation,
+            areas,
+            dns,
+            persistent_keepalive: network.persistent_keepalive,
+            routing: network.routing,
+            peers,
+        })
+    }
+
+    // Ports for a node: a static peer uses the ports of its configured endpoint
+    pub fn ports_for(&self, wg_ip: &IpAddr) -> Option<(u16, u16)> {
+        match self.peers.get(wg_ip) {
+            Some(peer) => Some((peer.wg_port, peer.admin_port)),
+            None => self.port_allocation.ports(wg_ip),
+        }
+    }
+}
+
+// None, if the file does not exist
+fn read_config_file(path: &str) -> BoxResult<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(format!("Permission denied for {}", path).into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+// Reads network.yaml resp. network.toml
+pub fn load_network_config(path: &str) -> BoxResult<NetworkConfiguration> {
+    let content = read_config_file(path)?
+        .ok_or_else(|| format!("Cannot find required network configuration: {}", path))?;
+    Ok(
+        NetworkConfiguration::from_str(&content, ConfigFormat::of_path(path), path)
+            .map_err(|e| format!("{}: {}", path, e))?,
+    )
+}
+// Reads peer.yaml resp. peer.toml. Without the file, all options are taken from the
+// command line.
+pub fn load_peer_config(path: &str) -> BoxResult<PeerConfig> {
+    let Some(content) = read_config_file(path)? else {
+        return Ok(PeerConfig::default());
+    };
+    let mut config: PeerConfig =
+        parse(&content, ConfigFormat::of_path(path)).map_err(|e| format!("{}: {}", path, e))?;
+    warn_unknown_keys(path, &config.unknown_keys());
+    config
+        .expand_secrets()
+        .map_err(|e| format!("{}: {}", path, e))?;
+    Ok(config)
 }
 
 #[derive(Default)]
 pub struct StaticConfigurationBuilder {
     name: Option<String>,
     ip_list: Option<Vec<IpAddr>>,
-    wg_ip: Option<Ipv4Addr>,
+    link_scope_ids: Option<Vec<u32>>,
+    wg_ip: Option<IpAddr>,
     wg_name: Option<String>,
     wg_port: Option<u16>,
     wg_hopping: Option<bool>,
     admin_port: Option<u16>,
-    subnet: Option<ipnet::Ipv4Net>,
+    subnet: Option<ipnet::IpNet>,
     shared_key: Option<Vec<u8>>,
+    accepted_shared_keys: Vec<Vec<u8>>,
     my_private_key: Option<String>,
     my_public_key: Option<PublicKeyWithTime>,
-    peers: HashMap<Ipv4Addr, PublicPeer>,
+    peers: HashMap<IpAddr, PublicPeer>,
     use_tui: Option<bool>,
     use_existing_interface: Option<bool>,
+    router_hairpinning: Option<bool>,
     network_yaml_filename: Option<String>,
     peer_yaml_filename: Option<String>,
+    drop_privileges_to: Option<String>,
+    keep_net_admin: Option<bool>,
+    backend: Option<Backend>,
+    policy_script: Option<String>,
+    chaos_seed: Option<u64>,
+    network_name: Option<String>,
+    interface_altname: Option<bool>,
+    adopt_peers: Option<bool>,
+    teardown_skip: Option<Vec<TeardownStep>>,
+    fleet_admins: Vec<PublicIdentity>,
+    fleet_commands: Vec<String>,
+    decrypt_failure_threshold: Option<u32>,
+    upgrade_socket: Option<String>,
+    takeover: Option<bool>,
+    control_socket: Option<String>,
+    control_group: Option<String>,
+    control_admin_group: Option<String>,
+    status_file: Option<String>,
+    state_file: Option<String>,
+    metrics_port: Option<u16>,
+    metrics_address: Option<IpAddr>,
+    status_page_port: Option<u16>,
+    http_token: Option<String>,
+    mesh_domain: Option<String>,
+    hosts_file: Option<String>,
+    wg_ip_lease: Option<u64>,
+    ha_pair: Option<HaPair>,
+    authorized_keys: Option<String>,
+    peer_names: Option<String>,
+    gateway_grace: Option<u64>,
+    disable_ipv6: Option<bool>,
+    key_lifetime: Option<u64>,
+    webhooks: Vec<String>,
+    pinned_routes: HashMap<IpAddr, IpAddr>,
+    identity: Option<NodeIdentity>,
+    areas: BTreeMap<u16, ipnet::IpNet>,
+    exported_nets: Vec<ipnet::IpNet>,
+    dns: Option<MeshDns>,
+    persistent_keepalive: Option<u16>,
+    extra_wg_ports: Vec<u16>,
+    routing: RoutingPolicy,
+    mdns: Option<bool>,
+    imported_peers: Vec<PublicPeer>,
 }
 impl StaticConfigurationBuilder {
     pub fn new() -> Self {
@@ -51,7 +646,11 @@ impl StaticConfigurationBuilder {
         self.ip_list = Some(ip_list);
         self
     }
-    pub fn wg_ip<T: Into<Ipv4Addr>>(mut self, wg_ip: T) -> Self {
+    pub fn link_scope_ids(mut self, scope_ids: Vec<u32>) -> Self {
+        self.link_scope_ids = Some(scope_ids);
+        self
+    }
+    pub fn wg_ip<T: Into<IpAddr>>(mut self, wg_ip: T) -> Self {
         self.wg_ip = Some(wg_ip.into());
         self
     }
@@ -67,11 +666,15 @@ impl StaticConfigurationBuilder {
         self.wg_hopping = Some(hopping);
         self
     }
+    pub fn router_hairpinning(mut self, router_hairpinning: bool) -> Self {
+        self.router_hairpinning = Some(router_hairpinning);
+        self
+    }
     pub fn admin_port(mut self, port: u16) -> Self {
         self.admin_port = Some(port);
         self
     }
-    pub fn subnet(mut self, subnet: ipnet::Ipv4Net) -> Self {
+    pub fn subnet(mut self, subnet: ipnet::IpNet) -> Self {
         self.subnet = Some(subnet);
         self
     }
@@ -87,7 +690,7 @@ impl StaticConfigurationBuilder {
         self.my_public_key = Some(public_key);
         self
     }
-    pub fn peers(mut self, peers: HashMap<Ipv4Addr, PublicPeer>) -> Self {
+    pub fn peers(mut self, peers: HashMap<IpAddr, PublicPeer>) -> Self {
         self.peers = peers;
         self
     }
@@ -107,11 +710,189 @@ impl StaticConfigurationBuilder {
         self.peer_yaml_filename = Some(fname.into());
         self
     }
+    pub fn drop_privileges_to(mut self, user: Option<String>) -> Self {
+        self.drop_privileges_to = user;
+        self
+    }
+    pub fn keep_net_admin(mut self, keep_net_admin: bool) -> Self {
+        self.keep_net_admin = Some(keep_net_admin);
+        self
+    }
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+    pub fn policy_script(mut self, fname: Option<String>) -> Self {
+        self.policy_script = fname;
+        self
+    }
+    pub fn chaos_seed(mut self, seed: Option<u64>) -> Self {
+        self.chaos_seed = seed;
+        self
+    }
+    pub fn network_name(mut self, network_name: Option<String>) -> Self {
+        self.network_name = network_name;
+        self
+    }
+    pub fn interface_altname(mut self, interface_altname: bool) -> Self {
+        self.interface_altname = Some(interface_altname);
+        self
+    }
+    pub fn adopt_peers(mut self, adopt_peers: bool) -> Self {
+        self.adopt_peers = Some(adopt_peers);
+        self
+    }
+    pub fn teardown_skip(mut self, teardown_skip: Vec<TeardownStep>) -> Self {
+        self.teardown_skip = Some(teardown_skip);
+        self
+    }
+    pub fn fleet_admins(mut self, fleet_admins: Vec<PublicIdentity>) -> Self {
+        self.fleet_admins = fleet_admins;
+        self
+    }
+    pub fn fleet_commands(mut self, fleet_commands: Vec<String>) -> Self {
+        self.fleet_commands = fleet_commands;
+        self
+    }
+    pub fn decrypt_failure_threshold(mut self, threshold: u32) -> Self {
+        self.decrypt_failure_threshold = Some(threshold);
+        self
+    }
+    pub fn upgrade_socket(mut self, upgrade_socket: Option<String>) -> Self {
+        self.upgrade_socket = upgrade_socket;
+        self
+    }
+    pub fn takeover(mut self, takeover: bool) -> Self {
+        self.takeover = Some(takeover);
+        self
+    }
+    pub fn control_socket(mut self, control_socket: Option<String>) -> Self {
+        self.control_socket = control_socket;
+        self
+    }
+    pub fn control_group(mut self, control_group: Option<String>) -> Self {
+        self.control_group = control_group;
+        self
+    }
+    pub fn control_admin_group(mut self, control_admin_group: Option<String>) -> Self {
+        self.control_admin_group = control_admin_group;
+        self
+    }
+    pub fn status_file(mut self, status_file: Option<String>) -> Self {
+        self.status_file = status_file;
+        self
+    }
+    pub fn state_file(mut self, state_file: Option<String>) -> Self {
+        self.state_file = state_file;
+        self
+    }
+    pub fn metrics_port(mut self, metrics_port: Option<u16>) -> Self {
+        self.metrics_port = metrics_port;
+        self
+    }
+    pub fn metrics_address(mut self, metrics_address: Option<IpAddr>) -> Self {
+        self.metrics_address = metrics_address;
+        self
+    }
+    pub fn status_page_port(mut self, status_page_port: Option<u16>) -> Self {
+        self.status_page_port = status_page_port;
+        self
+    }
+    pub fn http_token(mut self, http_token: Option<String>) -> Self {
+        self.http_token = http_token;
+        self
+    }
+    pub fn mesh_domain(mut self, mesh_domain: Option<String>) -> Self {
+        self.mesh_domain = mesh_domain;
+        self
+    }
+    pub fn hosts_file(mut self, hosts_file: Option<String>) -> Self {
+        self.hosts_file = hosts_file;
+        self
+    }
+    pub fn peer_names(mut self, peer_names: Option<String>) -> Self {
+        self.peer_names = peer_names;
+        self
+    }
+    pub fn gateway_grace(mut self, gateway_grace: u64) -> Self {
+        self.gateway_grace = Some(gateway_grace);
+        self
+    }
+    pub fn wg_ip_lease(mut self, wg_ip_lease: Option<u64>) -> Self {
+        self.wg_ip_lease = wg_ip_lease;
+        self
+    }
+    pub fn ha_pair(mut self, ha_pair: Option<HaPair>) -> Self {
+        self.ha_pair = ha_pair;
+        self
+    }
+    pub fn authorized_keys(mut self, authorized_keys: Option<String>) -> Self {
+        self.authorized_keys = authorized_keys;
+        self
+    }
+    pub fn disable_ipv6(mut self, disable_ipv6: bool) -> Self {
+        self.disable_ipv6 = Some(disable_ipv6);
+        self
+    }
+    pub fn mdns(mut self, mdns: bool) -> Self {
+        self.mdns = Some(mdns);
+        self
+    }
+    pub fn imported_peers(mut self, imported_peers: Vec<PublicPeer>) -> Self {
+        self.imported_peers = imported_peers;
+        self
+    }
+    pub fn key_lifetime(mut self, key_lifetime: Option<u64>) -> Self {
+        self.key_lifetime = key_lifetime;
+        self
+    }
+    pub fn webhooks(mut self, webhooks: Vec<String>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+    pub fn pinned_routes(mut self, pinned_routes: HashMap<IpAddr, IpAddr>) -> Self {
+        self.pinned_routes = pinned_routes;
+        self
+    }
+    pub fn identity(mut self, identity: NodeIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+    pub fn exported_nets(mut self, exported_nets: Vec<ipnet::IpNet>) -> Self {
+        self.exported_nets = exported_nets;
+        self
+    }
+    pub fn persistent_keepalive(mut self, persistent_keepalive: Option<u16>) -> Self {
+        self.persistent_keepalive = persistent_keepalive;
+        self
+    }
+    pub fn extra_wg_ports(mut self, extra_wg_ports: Vec<u16>) -> Self {
+        self.extra_wg_ports = extra_wg_ports;
+        self
+    }
+    pub fn routing(mut self, routing: RoutingPolicy) -> Self {
+        self.routing = routing;
+        self
+    }
+    pub fn dns(mut self, dns: Option<MeshDns>) -> Self {
+        self.dns = dns;
+        self
+    }
+    pub fn areas(mut self, areas: BTreeMap<u16, ipnet::IpNet>) -> Self {
+        self.areas = areas;
+        self
+    }
+    pub fn accepted_shared_keys(mut self, keys: Vec<Vec<u8>>) -> Self {
+        self.accepted_shared_keys = keys;
+        self
+    }
     pub fn build(self) -> StaticConfiguration {
         let is_static = self.peers.contains_key(self.wg_ip.as_ref().unwrap());
-        StaticConfiguration {
+        let prefer_ipv6_endpoints = prefer_ipv6_endpoints(self.ip_list.as_ref().unwrap());
+        let mut static_config = StaticConfiguration {
             name: self.name.unwrap(),
             ip_list: self.ip_list.unwrap(),
+            link_scope_ids: self.link_scope_ids.unwrap_or_default(),
             wg_ip: self.wg_ip.unwrap(),
             wg_name: self.wg_name.unwrap(),
             wg_port: self.wg_port.unwrap(),
@@ -119,47 +900,249 @@ impl StaticConfigurationBuilder {
             admin_port: self.admin_port.unwrap(),
             subnet: self.subnet.unwrap(),
             shared_key: self.shared_key.unwrap(),
+            accepted_shared_keys: self.accepted_shared_keys,
             my_private_key: self.my_private_key.unwrap(),
             my_public_key: self.my_public_key.unwrap(),
             is_static,
+            prefer_ipv6_endpoints,
+            router_hairpinning: self.router_hairpinning.unwrap_or(false),
             peers: self.peers,
             use_tui: self.use_tui.unwrap(),
-            use_existing_interface: self.use_existing_interface.unwrap(),
+            use_existing_interface: self.use_existing_interface.unwrap()
+                || self.backend == Some(Backend::Existing),
             network_yaml_filename: self.network_yaml_filename.unwrap(),
             peer_yaml_filename: self.peer_yaml_filename,
-        }
+            drop_privileges_to: self.drop_privileges_to,
+            keep_net_admin: self.keep_net_admin.unwrap_or(false),
+            backend: self.backend.unwrap_or_default(),
+            policy_script: self.policy_script,
+            chaos_seed: self.chaos_seed,
+            network_name: self.network_name,
+            interface_altname: self.interface_altname.unwrap_or(false),
+            adopt_peers: self.adopt_peers.unwrap_or(false),
+            foreign_peers: vec![],
+            teardown_skip: self.teardown_skip.unwrap_or_default(),
+            fleet_admins: self.fleet_admins,
+            fleet_commands: self.fleet_commands,
+            decrypt_failure_threshold: self
+                .decrypt_failure_threshold
+                .unwrap_or(DEFAULT_DECRYPT_FAILURE_THRESHOLD),
+            upgrade_socket: self.upgrade_socket,
+            takeover: self.takeover.unwrap_or(false),
+            control_socket: self.control_socket,
+            control_group: self.control_group,
+            control_admin_group: self.control_admin_group,
+            status_file: self.status_file,
+            state_file: self.state_file,
+            metrics_port: self.metrics_port,
+            metrics_address: self.metrics_address,
+            status_page_port: self.status_page_port,
+            http_token: self.http_token,
+            mesh_domain: self.mesh_domain,
+            hosts_file: self.hosts_file,
+            wg_ip_lease: self.wg_ip_lease,
+            ha_pair: self.ha_pair,
+            authorized_keys: self.authorized_keys,
+            peer_names: self.peer_names,
+            gateway_grace: self.gateway_grace.unwrap_or(0),
+            disable_ipv6: self.disable_ipv6.unwrap_or(false),
+            key_lifetime: self.key_lifetime,
+            pending_key_pair: None,
+            previous_wg_ip: None,
+            webhooks: self.webhooks,
+            pinned_routes: self.pinned_routes,
+            identity: self.identity.unwrap_or_else(NodeIdentity::generate),
+            areas: self.areas,
+            exported_nets: self.exported_nets,
+            dns: self.dns,
+            persistent_keepalive: self.persistent_keepalive,
+            extra_wg_ports: self.extra_wg_ports,
+            routing: self.routing,
+            mdns: self.mdns.unwrap_or(false),
+            imported_peers: self.imported_peers,
+        };
+        static_config.merge_imported_peers();
+        static_config
     }
 }
 
-#[derive(Debug)]
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    // 100.64.0.0/10 is used for carrier grade NAT e.g. in DS-Lite setups
+    let is_shared = octets[0] == 100 && (octets[1] & 0xc0) == 64;
+    !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || is_shared)
+}
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    let is_unique_local = (first & 0xfe00) == 0xfc00;
+    let is_link_local = (first & 0xffc0) == 0xfe80;
+    !(ip.is_loopback() || ip.is_unspecified() || is_unique_local || is_link_local)
+}
+
+// In NAT64/DS-Lite environments only a global ipv6 address is reachable from outside,
+// while ipv4 is hidden behind the provider's NAT. Then ipv6 endpoints should be preferred.
+fn prefer_ipv6_endpoints(ip_list: &[IpAddr]) -> bool {
+    let has_public_ipv4 = ip_list.iter().any(|ip| match ip {
+        IpAddr::V4(ipv4) => is_public_ipv4(ipv4),
+        IpAddr::V6(_) => false,
+    });
+    let has_global_ipv6 = ip_list.iter().any(|ip| match ip {
+        IpAddr::V4(_) => false,
+        IpAddr::V6(ipv6) => is_global_ipv6(ipv6),
+    });
+    has_global_ipv6 && !has_public_ipv4
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct StaticConfiguration {
     pub name: String,
     pub ip_list: Vec<IpAddr>,
-    pub wg_ip: Ipv4Addr,
+    // Interfaces with a link-local ipv6 address
+    pub link_scope_ids: Vec<u32>,
+    pub wg_ip: IpAddr,
     pub wg_name: String,
     pub wg_port: u16,
     pub wg_hopping: bool,
     pub admin_port: u16,
-    pub subnet: ipnet::Ipv4Net,
+    pub subnet: ipnet::IpNet,
     pub shared_key: Vec<u8>,
+    // Additionally accepted for received admin packets during a change of the sharedKey
+    pub accepted_shared_keys: Vec<Vec<u8>>,
     pub my_private_key: String,
     pub my_public_key: PublicKeyWithTime,
-    pub peers: HashMap<Ipv4Addr, PublicPeer>,
+    pub peers: HashMap<IpAddr, PublicPeer>,
     pub is_static: bool,
+    pub prefer_ipv6_endpoints: bool,
+    // Without, the visible endpoint of a distant node behind the same NAT is not used
+    pub router_hairpinning: bool,
     pub use_tui: bool,
     pub use_existing_interface: bool,
     pub network_yaml_filename: String,
     pub peer_yaml_filename: Option<String>,
+    pub drop_privileges_to: Option<String>,
+    // Linux only: keep CAP_NET_ADMIN after dropping privileges instead of using sudo
+    pub keep_net_admin: bool,
+    pub backend: Backend,
+    // rhai script with the policy hooks, only used with feature scripting
+    pub policy_script: Option<String>,
+    // only used with feature chaos
+    pub chaos_seed: Option<u64>,
+    pub network_name: Option<String>,
+    pub interface_altname: bool,
+    // Keep the peers of other tooling on an existing interface
+    pub adopt_peers: bool,
+    // The adopted peers, which are kept verbatim in the wireguard configuration
+    pub foreign_peers: Vec<WgPeerConf>,
+    // Steps of the teardown, which leave the existing interface as is
+    pub teardown_skip: Vec<TeardownStep>,
+    // Identities, whose signed fleet commands are executed. Empty disables them.
+    pub fleet_admins: Vec<PublicIdentity>,
+    // The names of the executed fleet commands, see crate::fleet
+    pub fleet_commands: Vec<String>,
+    // Undecryptable packets per minute from a static peer before a key mismatch is assumed
+    pub decrypt_failure_threshold: u32,
+    // Unix socket for handing over to a new instance on binary upgrade
+    pub upgrade_socket: Option<String>,
+    // Take over from the instance listening on upgrade_socket
+    pub takeover: bool,
+    // Unix socket for the JSON-RPC control interface
+    pub control_socket: Option<String>,
+    // Members may use the read-only methods of the control socket
+    pub control_group: Option<String>,
+    // Members may use all methods of the control socket like root
+    pub control_admin_group: Option<String>,
+    // JSON file with the live state for other tools
+    pub status_file: Option<String>,
+    // Encrypted nodes and routes of the previous run to speed up the convergence
+    pub state_file: Option<String>,
+    // TCP port of the prometheus metrics listener
+    pub metrics_port: Option<u16>,
+    // Address of the metrics listener, by default the wireguard ip
+    pub metrics_address: Option<IpAddr>,
+    // TCP port of the html status page on the wireguard ip
+    pub status_page_port: Option<u16>,
+    // Bearer token needed by the metrics listener and the status page
+    pub http_token: Option<String>,
+    // Serve the node names as <name>.<mesh_domain> via dns on the wireguard ip
+    pub mesh_domain: Option<String>,
+    // Hosts file with a managed block of the node names, e.g. /etc/hosts
+    pub hosts_file: Option<String>,
+    // End of the lease of an automatically assigned wg_ip
+    pub wg_ip_lease: Option<u64>,
+    // This node is one of two nodes acting as one static hub
+    pub ha_pair: Option<HaPair>,
+    // Only nodes with an identity in this file are accepted as peers, besides the
+    // static peers
+    pub authorized_keys: Option<String>,
+    // Json file mapping the wireguard public keys to the node names
+    pub peer_names: Option<String>,
+    // Seconds with a reachable direct peer, before the routes via this node are
+    // offered to others. Not applied to static peers.
+    pub gateway_grace: u64,
+    // No ipv6 sockets, addresses and routes, e.g. for kernels booted with ipv6.disable=1
+    pub disable_ipv6: bool,
+    // Seconds after which the own wireguard key pair is replaced, None to keep it
+    pub key_lifetime: Option<u64>,
+    // New key pair announced to the peers during the overlap window
+    pub pending_key_pair: Option<PendingKeyPair>,
+    // The wg_ip before a change on reload during its grace period
+    pub previous_wg_ip: Option<PreviousWgIp>,
+    // Urls to POST mesh events to, only used with feature webhook
+    pub webhooks: Vec<String>,
+    // Gateway to use for a destination regardless of the hop count
+    pub pinned_routes: HashMap<IpAddr, IpAddr>,
+    // Signs the advertisements
+    pub identity: NodeIdentity,
+    // Nodes of other areas are only reached via the static peers as area border nodes
+    pub areas: BTreeMap<u16, ipnet::IpNet>,
+    // Networks behind this node, which the other nodes route via this node
+    pub exported_nets: Vec<ipnet::IpNet>,
+    // Registered with systemd-resolved for the wireguard interface, only used with
+    // feature resolved
+    pub dns: Option<MeshDns>,
+    // PersistentKeepalive of network.yaml, see persistent_keepalive_for()
+    pub persistent_keepalive: Option<u16>,
+    // Further ports forwarded to wg_port, e.g. one per uplink. The peers try them in
+    // turn, if the tunnel breaks.
+    pub extra_wg_ports: Vec<u16>,
+    // Metric and link hints for the selection of the routes
+    pub routing: RoutingPolicy,
+    // Announce the admin service via mDNS, only used with feature mdns
+    pub mdns: bool,
+    // Contacts of a peer database file. Used as static peers in addition to
+    // network.yaml.
+    pub imported_peers: Vec<PublicPeer>,
 }
 
 impl StaticConfiguration {
     pub fn builder() -> StaticConfigurationBuilder {
         StaticConfigurationBuilder::new()
     }
+    // After the static peers have been replaced, e.g. on reload of network.yaml
+    pub fn merge_imported_peers(&mut self) {
+        for peer in self.imported_peers.iter() {
+            if peer.wg_ip != self.wg_ip {
+                self.peers.entry(peer.wg_ip).or_insert_with(|| peer.clone());
+            }
+        }
+    }
     pub fn to_wg_configuration(&self, manager: &NetworkManager) -> String {
+        self.wg_configuration(manager, &self.my_private_key)
+    }
+    // The wireguard configuration, which is applied on startup before any peer is seen
+    pub fn initial_wg_configuration(&self, show_secrets: bool) -> String {
+        let manager = NetworkManager::new(self);
+        if show_secrets {
+            self.wg_configuration(&manager, &self.my_private_key)
+        } else {
+            self.wg_configuration(&manager, REDACTED)
+        }
+    }
+    fn wg_configuration(&self, manager: &NetworkManager, private_key: &str) -> String {
         let mut lines: Vec<String> = vec![];
         lines.push("[Interface]".to_string());
-        lines.push(format!("PrivateKey = {}", self.my_private_key));
+        lines.push(format!("PrivateKey = {}", private_key));
         let port = if self.wg_hopping {
             manager.my_local_wg_port
         } else {
@@ -170,20 +1153,358 @@ impl StaticConfiguration {
         };
         lines.push(format!("ListenPort = {}", port));
 
-        for node in manager.all_nodes.values() {
-            if let Some(mut peer_lines) = node.peer_wireguard_configuration() {
+        // sorted for reproducible output
+        let mut nodes = manager.all_nodes.iter().collect::<Vec<_>>();
+        nodes.sort_by_key(|(wg_ip, _)| **wg_ip);
+        for (wg_ip, node) in nodes {
+            // The adopted peer with the same key is kept instead
+            let adopted = node.public_key().is_some_and(|public_key| {
+                self.foreign_peers
+                    .iter()
+                    .any(|peer| peer.key() == public_key.key)
+            });
+            if adopted {
+                continue;
+            }
+            if let Some(mut peer_lines) = node.peer_wireguard_configuration(self) {
+                manager.port_validation().retain_endpoints(
+                    wg_ip,
+                    node.local_admin_port(),
+                    &mut peer_lines,
+                );
                 lines.push("".to_string());
                 lines.push("[Peer]".to_string());
                 lines.append(&mut peer_lines);
+                for net in manager
+                    .areas_via(wg_ip)
+                    .into_iter()
+                    .chain(manager.exported_nets_via(wg_ip))
+                    .chain(manager.previous_wg_ip_of(wg_ip).map(host_net))
+                {
+                    lines.push(format!("AllowedIPs = {}", net));
+                }
             }
         }
+        for peer in self.foreign_peers.iter() {
+            lines.push("".to_string());
+            lines.push("[Peer]".to_string());
+            lines.append(&mut peer.to_conf_lines());
+        }
 
         lines.join("\n")
     }
+    // The altname, which marks the wireguard interface as owned by this network
+    pub fn altname(&self) -> Option<String> {
+        self.network_name
+            .as_ref()
+            .map(|name| format!("{}{}", ALTNAME_PREFIX, name))
+    }
+    // Refuse to use an interface, which is marked as owned by another network
+    pub fn check_interface_owner(&self, altnames: &[String]) -> BoxResult<()> {
+        let my_altname = self.altname();
+        for altname in altnames.iter() {
+            if altname.starts_with(ALTNAME_PREFIX) && Some(altname) != my_altname.as_ref() {
+                return Err(format!(
+                    "Interface {} is already in use by network {}",
+                    self.wg_name,
+                    &altname[ALTNAME_PREFIX.len()..]
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+    // The peers on an existing interface, which do not belong to this network. A peer
+    // with an allowed ip in the subnet or in the nat probe addresses is a leftover of
+    // a previous run.
+    pub fn foreign_peers_of(&self, peers: Vec<WgPeerConf>) -> Vec<WgPeerConf> {
+        let mesh_nets = std::iter::once(self.subnet)
+            .chain(
+                crate::wg_dev::nat_probe_net(&self.subnet.network(), &self.subnet)
+                    .map(|net| net.trunc().into()),
+            )
+            .collect::<Vec<IpNet>>();
+        peers
+            .into_iter()
+            .filter(|peer| {
+                !peer
+                    .allowed_ips
+                    .iter()
+                    .any(|net| mesh_nets.iter().any(|mesh_net| mesh_net.contains(net)))
+            })
+            .collect()
+    }
+    // The routes to the adopted peers, as these are removed by flush_all(). A default
+    // route is left to the other tooling.
+    pub fn foreign_nets(&self) -> Vec<IpNet> {
+        let mut nets = self
+            .foreign_peers
+            .iter()
+            .flat_map(|peer| peer.allowed_ips.iter().copied())
+            .filter(|net| net.prefix_len() > 0)
+            .collect::<Vec<_>>();
+        nets.sort();
+        nets.dedup();
+        nets
+    }
+    // The ordered teardown on shutdown. Without the manager, e.g. after an error, the
+    // routes are left to the removal of the addresses or the link.
+    pub fn teardown(&self, manager: Option<&NetworkManager>) -> Teardown {
+        let mut conf = vec!["[Interface]".to_string()];
+        for peer in self.foreign_peers.iter() {
+            conf.push("".to_string());
+            conf.push("[Peer]".to_string());
+            conf.append(&mut peer.to_conf_lines());
+        }
+        let mut skip = self.teardown_skip.clone();
+        if self.use_existing_interface {
+            skip.push(TeardownStep::Link);
+        }
+        Teardown {
+            routes: manager
+                .map(|manager| manager.installed_routes())
+                .unwrap_or_default(),
+            nets: manager
+                .map(|manager| manager.exported_net_hops().into_keys().collect())
+                .unwrap_or_default(),
+            conf: conf.join("\n"),
+            addresses: std::iter::once((self.wg_ip, self.subnet.prefix_len()))
+                .chain(
+                    self.nat_probe_net()
+                        .map(|net| (IpAddr::V6(net.addr()), net.prefix_len())),
+                )
+                .collect(),
+            skip,
+        }
+    }
+    // The ipv6 address of a node for NAT traversal, if ipv6 is available
+    pub fn nat_probe_address(&self, wg_ip: &IpAddr) -> Option<Ipv6Addr> {
+        if self.disable_ipv6 {
+            return None;
+        }
+        crate::wg_dev::nat_probe_address(wg_ip)
+    }
+    pub fn nat_probe_net(&self) -> Option<Ipv6Net> {
+        if self.disable_ipv6 {
+            return None;
+        }
+        crate::wg_dev::nat_probe_net(&self.wg_ip, &self.subnet)
+    }
+    pub fn next_public_key(&self) -> Option<&PublicKeyWithTime> {
+        self.pending_key_pair
+            .as_ref()
+            .map(|pending| &pending.public_key)
+    }
+    // Without configuration, only peers behind NAT get a keepalive
+    pub fn persistent_keepalive_for(&self, behind_nat: bool) -> Option<u16> {
+        match self.persistent_keepalive {
+            Some(0) => None,
+            Some(seconds) => Some(seconds),
+            None => behind_nat.then_some(DEFAULT_PERSISTENT_KEEPALIVE),
+        }
+    }
+    pub fn area_of(&self, wg_ip: &IpAddr) -> Option<u16> {
+        area_of(&self.areas, wg_ip)
+    }
     pub fn my_admin_port(&self) -> u16 {
         self.peers
             .get(&self.wg_ip)
             .map(|peer| peer.admin_port)
             .unwrap_or(self.admin_port)
     }
+    pub fn output(&self, show_secrets: bool) -> ConfigurationOutput {
+        let secret = |s: String| {
+            if show_secrets {
+                s
+            } else {
+                REDACTED.to_string()
+            }
+        };
+        ConfigurationOutput {
+            name: self.name.clone(),
+            wg_ip: self.wg_ip,
+            wg_name: self.wg_name.clone(),
+            wg_port: self.wg_port,
+            wg_hopping: self.wg_hopping,
+            admin_port: self.admin_port,
+            subnet: self.subnet.to_string(),
+            shared_key: secret(base64::encode(&self.shared_key)),
+            accepted_shared_keys: self
+                .accepted_shared_keys
+                .iter()
+                .map(|key| secret(base64::encode(key)))
+                .collect(),
+            my_private_key: secret(self.my_private_key.clone()),
+            my_public_key: self.my_public_key.key.clone(),
+            identity: base64::encode(self.identity.public()),
+            ip_list: self.ip_list.clone(),
+            link_scope_ids: self.link_scope_ids.clone(),
+            is_static: self.is_static,
+            prefer_ipv6_endpoints: self.prefer_ipv6_endpoints,
+            router_hairpinning: self.router_hairpinning,
+            use_tui: self.use_tui,
+            use_existing_interface: self.use_existing_interface,
+            network_yaml_filename: self.network_yaml_filename.clone(),
+            peer_yaml_filename: self.peer_yaml_filename.clone(),
+            drop_privileges_to: self.drop_privileges_to.clone(),
+            keep_net_admin: self.keep_net_admin,
+            backend: self.backend,
+            policy_script: self.policy_script.clone(),
+            chaos_seed: self.chaos_seed,
+            network_name: self.network_name.clone(),
+            interface_altname: self.interface_altname,
+            adopt_peers: self.adopt_peers,
+            foreign_peers: self.foreign_peers.iter().map(|peer| peer.key()).collect(),
+            teardown_skip: self.teardown_skip.clone(),
+            fleet_admins: self.fleet_admins.iter().map(base64::encode).collect(),
+            fleet_commands: self.fleet_commands.clone(),
+            decrypt_failure_threshold: self.decrypt_failure_threshold,
+            upgrade_socket: self.upgrade_socket.clone(),
+            takeover: self.takeover,
+            control_socket: self.control_socket.clone(),
+            control_group: self.control_group.clone(),
+            control_admin_group: self.control_admin_group.clone(),
+            status_file: self.status_file.clone(),
+            state_file: self.state_file.clone(),
+            metrics_port: self.metrics_port,
+            metrics_address: self.metrics_address,
+            status_page_port: self.status_page_port,
+            http_token: self.http_token.clone().map(secret),
+            mesh_domain: self.mesh_domain.clone(),
+            hosts_file: self.hosts_file.clone(),
+            wg_ip_lease: self.wg_ip_lease,
+            ha_pair: self.ha_pair.clone(),
+            authorized_keys: self.authorized_keys.clone(),
+            peer_names: self.peer_names.clone(),
+            gateway_grace: self.gateway_grace,
+            disable_ipv6: self.disable_ipv6,
+            key_lifetime: self.key_lifetime,
+            webhooks: self.webhooks.clone(),
+            pinned_routes: self
+                .pinned_routes
+                .iter()
+                .map(|(to, gateway)| (*to, *gateway))
+                .collect(),
+            areas: self
+                .areas
+                .iter()
+                .map(|(id, net)| (*id, net.to_string()))
+                .collect(),
+            exported_nets: self
+                .exported_nets
+                .iter()
+                .map(|net| net.to_string())
+                .collect(),
+            dns: self.dns.clone(),
+            persistent_keepalive: self.persistent_keepalive,
+            extra_wg_ports: self.extra_wg_ports.clone(),
+            routing: self.routing.clone(),
+            mdns: self.mdns,
+            imported_peers: self.imported_peers.iter().map(|peer| peer.wg_ip).collect(),
+            peers: self
+                .peers
+                .iter()
+                .map(|(wg_ip, peer)| {
+                    (
+                        *wg_ip,
+                        PeerOutput {
+                            endpoint: peer.endpoint.clone(),
+                            wg_port: peer.wg_port,
+                            admin_port: peer.admin_port,
+                            identity: peer.identity.map(base64::encode),
+                            tier: peer.tier,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+// Machine readable view of StaticConfiguration for -O/--output.
+// Secrets are redacted unless requested otherwise and maps are sorted,
+// so the output of two runs can be compared.
+#[derive(Serialize, Debug)]
+pub struct ConfigurationOutput {
+    pub name: String,
+    pub wg_ip: IpAddr,
+    pub wg_name: String,
+    pub wg_port: u16,
+    pub wg_hopping: bool,
+    pub admin_port: u16,
+    pub subnet: String,
+    pub shared_key: String,
+    pub accepted_shared_keys: Vec<String>,
+    pub my_private_key: String,
+    pub my_public_key: String,
+    pub identity: String,
+    pub ip_list: Vec<IpAddr>,
+    pub link_scope_ids: Vec<u32>,
+    pub is_static: bool,
+    pub prefer_ipv6_endpoints: bool,
+    pub router_hairpinning: bool,
+    pub use_tui: bool,
+    pub use_existing_interface: bool,
+    pub network_yaml_filename: String,
+    pub peer_yaml_filename: Option<String>,
+    pub drop_privileges_to: Option<String>,
+    pub keep_net_admin: bool,
+    pub backend: Backend,
+    pub policy_script: Option<String>,
+    pub chaos_seed: Option<u64>,
+    pub network_name: Option<String>,
+    pub interface_altname: bool,
+    pub adopt_peers: bool,
+    pub foreign_peers: Vec<String>,
+    pub teardown_skip: Vec<TeardownStep>,
+    pub fleet_admins: Vec<String>,
+    pub fleet_commands: Vec<String>,
+    pub decrypt_failure_threshold: u32,
+    pub upgrade_socket: Option<String>,
+    pub takeover: bool,
+    pub control_socket: Option<String>,
+    pub control_group: Option<String>,
+    pub control_admin_group: Option<String>,
+    pub status_file: Option<String>,
+    pub state_file: Option<String>,
+    pub metrics_port: Option<u16>,
+    pub metrics_address: Option<IpAddr>,
+    pub status_page_port: Option<u16>,
+    pub http_token: Option<String>,
+    pub mesh_domain: Option<String>,
+    pub hosts_file: Option<String>,
+    pub wg_ip_lease: Option<u64>,
+    pub ha_pair: Option<HaPair>,
+    pub authorized_keys: Option<String>,
+    pub peer_names: Option<String>,
+    pub gateway_grace: u64,
+    pub disable_ipv6: bool,
+    pub key_lifetime: Option<u64>,
+    pub webhooks: Vec<String>,
+    pub pinned_routes: BTreeMap<IpAddr, IpAddr>,
+    pub areas: BTreeMap<u16, String>,
+    pub exported_nets: Vec<String>,
+    pub dns: Option<MeshDns>,
+    pub persistent_keepalive: Option<u16>,
+    pub extra_wg_ports: Vec<u16>,
+    pub routing: RoutingPolicy,
+    pub mdns: bool,
+    pub imported_peers: Vec<IpAddr>,
+    pub peers: BTreeMap<IpAddr, PeerOutput>,
+}
+#[derive(Serialize, Debug)]
+pub struct PeerOutput {
+    pub endpoint: String,
+    pub wg_port: u16,
+    pub admin_port: u16,
+    pub identity: Option<String>,
+    pub tier: u8,
+}
+impl ConfigurationOutput {
+    pub fn to_yaml(&self) -> BoxResult<String> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+    pub fn to_json(&self) -> BoxResult<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
 }
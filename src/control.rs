@@ -0,0 +1,778 @@
+// Local control interface for introspection of the running daemon.
+//
+// The daemon listens on a unix socket. The protocol is JSON-RPC 2.0 with one
+// request resp. response per line. Supported methods are:
+//
+//    version                       version of wg_netmanager
+//    status                        the node itself
+//    peers                         all known nodes
+//    routes                        the route database
+//    withdrawals                   the last deleted or replaced route per destination
+//                                  with the reason
+//    reload-config                 re-read network.yaml and apply changed static peers
+//                                  and keepalive. A changed wgIp in peer.yaml and the
+//                                  authorized keys are applied, too
+//    drop-peer {"wg_ip": "..."}    forget a node. It is rediscovered, if still alive
+//    add-peer {"wg_ip": "...", "endpoint": "host:port", "admin_port": ...}
+//                                  add a static peer to network.yaml and reload
+//    set-endpoint {"wg_ip": "...", "endpoint": "host:port"}
+//                                  change the endpoint of a static peer and reload
+//    force-relay {"peer": "...", "on": true, "duration": ...}
+//                                  route a direct peer (wg_ip or name) via a gateway.
+//                                  Expires after duration seconds
+//    export-peers                  the known contacts as signed peer database
+//    pending                       the nodes waiting for approval with authorized keys
+//    approve {"peer": "..."}       approve a pending node (identity, wg_ip or name)
+//    snapshot                      the state snapshot without the secrets
+//    log-sampling {"every": ...}   log every Nth occurrence of the hot paths. Without
+//                                  params, the current value is returned
+//
+// Each request is passed to the run loop as Event::Control, so the state is
+// only accessed from the main loop.
+//
+// The client is identified per connection via SO_PEERCRED resp. getpeereid. Root and
+// the user of the daemon may use all methods, members of the control admin group as
+// well. Members of the control group may only use the read-only methods version,
+// status, peers, routes, withdrawals, pending, snapshot and log-sampling without
+// params. Without any group, the socket is only accessible by its owner.
+//
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+
+use log::*;
+use nix::unistd::{Gid, Group, Uid, User};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::authorized_keys::PendingPeer;
+use crate::configuration::*;
+use crate::crypt_udp::{AddressedTo, Revocation};
+use crate::error::*;
+use crate::event::{Event, EventSender};
+use crate::key_rotation::KEY_ROTATION_OVERLAP;
+use crate::log_sampling;
+use crate::manager::{NetworkManager, PeerInfo, DEFAULT_FORCE_RELAY_DURATION};
+use crate::network_file::{NetworkFile, StaticPeerEntry};
+use crate::peer_db::PeerDatabase;
+use crate::readdress::readdress;
+use crate::state_snapshot::StateSnapshot;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// JSON-RPC error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+pub const PERMISSION_DENIED: i64 = -32001;
+
+const READ_ONLY_METHODS: [&str; 8] = [
+    "version",
+    "status",
+    "peers",
+    "routes",
+    "withdrawals",
+    "pending",
+    "snapshot",
+    "log-sampling",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ControlRequest {
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default)]
+    pub id: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ControlError {
+    pub code: i64,
+    pub message: String,
+}
+impl ControlError {
+    fn new<T: Into<String>>(code: i64, message: T) -> Self {
+        ControlError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ControlResponse {
+    pub jsonrpc: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ControlError>,
+    pub id: Value,
+}
+impl ControlResponse {
+    fn from_result(result: Result<Value, ControlError>, id: Value) -> Self {
+        let (result, error) = match result {
+            Ok(value) => (Some(value), None),
+            Err(e) => (None, Some(e)),
+        };
+        ControlResponse {
+            jsonrpc: "2.0".to_string(),
+            result,
+            error,
+            id,
+        }
+    }
+}
+
+impl ControlRequest {
+    // log-sampling with params changes the state
+    pub fn required_permission(&self) -> Permission {
+        if READ_ONLY_METHODS.contains(&self.method.as_str())
+            && (self.method != "log-sampling" || self.params["every"].is_null())
+        {
+            Permission::ReadOnly
+        } else {
+            Permission::Admin
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    ReadOnly,
+    Admin,
+}
+
+// Who may use the control socket besides root and the user of the daemon
+#[derive(Debug, Clone, Default)]
+pub struct ControlAccess {
+    group: Option<Gid>,
+    admin_group: Option<Gid>,
+}
+impl ControlAccess {
+    pub fn new(group: Option<&str>, admin_group: Option<&str>) -> BoxResult<Self> {
+        let gid = |name: &str| -> BoxResult<Gid> {
+            Ok(Group::from_name(name)?
+                .ok_or(format!("Unknown group {}", name))?
+                .gid)
+        };
+        Ok(ControlAccess {
+            group: group.map(gid).transpose()?,
+            admin_group: admin_group.map(gid).transpose()?,
+        })
+    }
+    pub fn of(static_config: &StaticConfiguration) -> BoxResult<Self> {
+        ControlAccess::new(
+            static_config.control_group.as_deref(),
+            static_config.control_admin_group.as_deref(),
+        )
+    }
+    // By the credentials of the client
+    pub fn permission(&self, uid: u32, gid: u32) -> Option<Permission> {
+        let is_member = |group: Option<Gid>| group.map(|group| is_member(group, uid, gid));
+        if uid == 0 || uid == Uid::effective().as_raw() || is_member(self.admin_group) == Some(true)
+        {
+            Some(Permission::Admin)
+        } else if is_member(self.group) == Some(true) {
+            Some(Permission::ReadOnly)
+        } else {
+            None
+        }
+    }
+    fn is_open(&self) -> bool {
+        self.group.is_some() || self.admin_group.is_some()
+    }
+}
+
+fn is_member(group: Gid, uid: u32, gid: u32) -> bool {
+    if group.as_raw() == gid {
+        return true;
+    }
+    // The supplementary groups are only known by the group database
+    match (
+        User::from_uid(Uid::from_raw(uid)).ok().flatten(),
+        Group::from_gid(group).ok().flatten(),
+    ) {
+        (Some(user), Some(group)) => group.mem.contains(&user.name),
+        _ => false,
+    }
+}
+
+// uid and gid of the connected process
+pub fn peer_credentials(stream: &UnixStream) -> BoxResult<(u32, u32)> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+        let credentials = getsockopt(stream.as_raw_fd(), PeerCredentials)?;
+        Ok((credentials.uid(), credentials.gid()))
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let mut uid = 0;
+        let mut gid = 0;
+        if unsafe { nix::libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok((uid, gid))
+    }
+}
+
+// A request from a client together with the channel for the response
+#[derive(Debug)]
+pub struct ControlCommand {
+    pub request: ControlRequest,
+    pub reply: Sender<ControlResponse>,
+}
+
+// Accept control connections in the background
+pub fn listen(path: &str, access: ControlAccess, tx: EventSender) -> BoxResult<()> {
+    let path = Path::new(path);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // The admin port is bound already, so no other instance uses this socket
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // With groups, everybody may connect and the permission is checked per connection
+    let mode = if access.is_open() { 0o666 } else { 0o600 };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    debug!(target: "control", "Listen for control requests on {}", path.display());
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    let access = access.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = serve(stream, &access, tx) {
+                            debug!(target: "control", "{}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!(target: "control", "{}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+pub fn remove_socket(path: &str) {
+    fs::remove_file(path).ok();
+}
+
+fn serve(stream: UnixStream, access: &ControlAccess, tx: EventSender) -> BoxResult<()> {
+    let (uid, gid) = peer_credentials(&stream)?;
+    let permission = access.permission(uid, gid);
+    if permission.is_none() {
+        warn!(target: "control", "Refused control connection of uid {}", uid);
+    }
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) if permission < Some(request.required_permission()) => {
+                ControlResponse::from_result(
+                    Err(ControlError::new(
+                        PERMISSION_DENIED,
+                        format!("Permission denied for {}", request.method),
+                    )),
+                    request.id,
+                )
+            }
+            Ok(request) => {
+                let (reply, rx) = channel();
+                let id = request.id.clone();
+                tx.send(Event::Control(ControlCommand { request, reply }))
+                    .map_err(|_| "Run loop has terminated")?;
+                rx.recv_timeout(TIMEOUT).unwrap_or_else(|_| {
+                    ControlResponse::from_result(
+                        Err(ControlError::new(INTERNAL_ERROR, "No response")),
+                        id,
+                    )
+                })
+            }
+            Err(e) => ControlResponse::from_result(
+                Err(ControlError::new(PARSE_ERROR, e.to_string())),
+                Value::Null,
+            ),
+        };
+        let mut buf = serde_json::to_vec(&response)?;
+        buf.push(b'\n');
+        writer.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+// Executed in the run loop. The returned events are to be processed afterwards.
+pub fn execute(
+    now: u64,
+    request: &ControlRequest,
+    network_manager: &mut NetworkManager,
+    static_config: &mut StaticConfiguration,
+) -> (ControlResponse, Vec<Event>) {
+    let mut events = vec![];
+    let result = match request.method.as_str() {
+        "version" => Ok(json!(env!("CARGO_PKG_VERSION"))),
+        "status" => Ok(json!({
+            "name": static_config.name,
+            "network": static_config.network_name,
+            "wg_name": static_config.wg_name,
+            "wg_ip": static_config.wg_ip,
+            "subnet": static_config.subnet.to_string(),
+            "public_key": static_config.my_public_key.key,
+            "identity": base64::encode(static_config.identity.public()),
+            "local_wg_port": network_manager.my_local_wg_port,
+            "visible_wg_endpoint": network_manager.my_visible_wg_endpoint,
+            "routedb_version": network_manager.db_version(),
+            "gateway_ready": network_manager.gateway_ready(),
+            "nodes": network_manager.all_nodes.len(),
+            "area": static_config.area_of(&static_config.wg_ip),
+            "area_gateways": network_manager.area_gateways(),
+            "last_route_diff": network_manager.last_route_diff(),
+            "nat_traversal": network_manager.nat_telemetry().report(),
+        })),
+        "peers" => to_value(network_manager.peer_infos(now)),
+        "routes" => to_value(network_manager.routes()),
+        "withdrawals" => to_value(network_manager.route_withdrawals()),
+        "export-peers" => {
+            let mut peer_db = PeerDatabase::of(now, static_config.wg_ip, network_manager);
+            match peer_db.sign(&static_config.shared_key) {
+                Ok(()) => to_value(peer_db),
+                Err(e) => Err(ControlError::new(INTERNAL_ERROR, e.to_string())),
+            }
+        }
+        "snapshot" => to_value(StateSnapshot {
+            created: now,
+            ..network_manager.state_snapshot(static_config).redacted()
+        }),
+        "log-sampling" => match &request.params["every"] {
+            Value::Null => Ok(json!(log_sampling::every())),
+            every => match every.as_u64().filter(|every| *every > 0) {
+                Some(every) => {
+                    log_sampling::set_every(every);
+                    info!(target: "control", "Log every {}th occurrence of the hot paths", every);
+                    Ok(json!(every))
+                }
+                None => Err(ControlError::new(INVALID_PARAMS, "Expected every > 0")),
+            },
+        },
+        "reload-config" => {
+            let readdressed = reload_wg_ip(now, static_config);
+            if let Ok(Some(previous)) = readdressed {
+                events.append(&mut network_manager.readdress(static_config, previous));
+            }
+            readdressed
+                .and_then(|_| {
+                    network_manager
+                        .reload_authorized_keys()
+                        .map_err(|e| ControlError::new(INTERNAL_ERROR, e.to_string()))
+                })
+                .and_then(|_| reload_config(static_config))
+                .map(|nr_peers| {
+                    network_manager.update_static_peers(static_config);
+                    events.push(Event::UpdateRoutes);
+                    json!({ "peers": nr_peers, "wg_ip": static_config.wg_ip })
+                })
+        }
+        "add-peer" | "set-endpoint" => {
+            edit_network_file(&request.method, &request.params, static_config)
+                .and_then(|_| reload_config(static_config))
+                .map(|nr_peers| {
+                    network_manager.update_static_peers(static_config);
+                    events.push(Event::UpdateRoutes);
+                    json!({ "peers": nr_peers })
+                })
+        }
+        "drop-peer" => match request.params["wg_ip"]
+            .as_str()
+            .map(|s| s.parse::<IpAddr>())
+        {
+            Some(Ok(wg_ip)) => {
+                let dropped = network_manager.drop_peer(&wg_ip, static_config);
+                if dropped {
+                    events.push(Event::UpdateRoutes);
+                }
+                Ok(json!(dropped))
+            }
+            _ => Err(ControlError::new(INVALID_PARAMS, "Expected wg_ip")),
+        },
+        "force-relay" => force_relay(now, &request.params, network_manager).map(|wg_ip| {
+            // The advertisement tells the peer to relay, too. It is sent before the
+            // routes change, so via the path both sides still use.
+            let admin_port = network_manager.all_nodes[&wg_ip].local_admin_port();
+            events.push(Event::SendAdvertisement {
+                addressed_to: AddressedTo::WireguardAddress,
+                to: std::net::SocketAddr::new(wg_ip, admin_port),
+                wg_ip,
+            });
+            events.push(Event::UpdateRoutes);
+            json!({ "wg_ip": wg_ip, "forced_relay_s": network_manager.force_relay_request(now, &wg_ip) })
+        }),
+        "pending" => to_value(network_manager.pending_peers()),
+        "approve" => match request.params["peer"].as_str() {
+            Some(peer) => match network_manager.approve_peer(peer) {
+                Ok((pending, mut approved_events)) => {
+                    events.append(&mut approved_events);
+                    to_value(pending)
+                }
+                Err(e) => Err(ControlError::new(INVALID_PARAMS, e.to_string())),
+            },
+            None => Err(ControlError::new(INVALID_PARAMS, "Expected peer")),
+        },
+        "revoke-and-rekey" => revoke_peer(now, &request.params, network_manager).map(|revocation| {
+            // The own key pair is replaced, before the direct peers are told about the
+            // revocation and the new key
+            events.push(Event::Rekey);
+            for (wg_ip, node) in network_manager.all_nodes.iter() {
+                if !node.is_distant_node() {
+                    events.push(Event::SendAdvertisement {
+                        addressed_to: AddressedTo::WireguardAddress,
+                        to: std::net::SocketAddr::new(*wg_ip, node.local_admin_port()),
+                        wg_ip: *wg_ip,
+                    });
+                }
+            }
+            events.push(Event::UpdateRoutes);
+            json!({
+                "wg_ip": revocation.wg_ip,
+                "public_key": revocation.public_key,
+                "identity": revocation.identity.map(base64::encode),
+            })
+        }),
+        method => Err(ControlError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method {}", method),
+        )),
+    };
+    (
+        ControlResponse::from_result(result, request.id.clone()),
+        events,
+    )
+}
+
+fn force_relay(
+    now: u64,
+    params: &Value,
+    network_manager: &mut NetworkManager,
+) -> Result<IpAddr, ControlError> {
+    let wg_ip = params["peer"]
+        .as_str()
+        .and_then(|peer| network_manager.resolve_node(peer))
+        .ok_or_else(|| ControlError::new(INVALID_PARAMS, "Expected known peer"))?;
+    let on = params["on"]
+        .as_bool()
+        .ok_or_else(|| ControlError::new(INVALID_PARAMS, "Expected on"))?;
+    let duration = match &params["duration"] {
+        Value::Null => DEFAULT_FORCE_RELAY_DURATION,
+        value => value
+            .as_u64()
+            .filter(|duration| *duration > 0)
+            .ok_or_else(|| ControlError::new(INVALID_PARAMS, "Expected duration in seconds"))?,
+    };
+    match network_manager.all_nodes.get(&wg_ip) {
+        Some(node) if !node.is_distant_node() => {}
+        _ => {
+            return Err(ControlError::new(
+                INVALID_PARAMS,
+                format!("{} is no direct peer", wg_ip),
+            ))
+        }
+    }
+    network_manager.force_relay(wg_ip, on.then_some(now + duration));
+    Ok(wg_ip)
+}
+
+fn revoke_peer(
+    now: u64,
+    params: &Value,
+    network_manager: &mut NetworkManager,
+) -> Result<Revocation, ControlError> {
+    let wg_ip = params["peer"]
+        .as_str()
+        .and_then(|peer| network_manager.resolve_node(peer))
+        .ok_or_else(|| ControlError::new(INVALID_PARAMS, "Expected known peer"))?;
+    network_manager
+        .revoke_peer(now, &wg_ip)
+        .ok_or_else(|| ControlError::new(INVALID_PARAMS, format!("No key of {} is known", wg_ip)))
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, ControlError> {
+    serde_json::to_value(value).map_err(|e| ControlError::new(INTERNAL_ERROR, e.to_string()))
+}
+
+// Change network.yaml. Other nodes need the same change for a consistent network.
+fn edit_network_file(
+    method: &str,
+    params: &Value,
+    static_config: &StaticConfiguration,
+) -> Result<(), ControlError> {
+    let wg_ip = params["wg_ip"]
+        .as_str()
+        .and_then(|s| s.parse::<IpAddr>().ok())
+        .ok_or_else(|| ControlError::new(INVALID_PARAMS, "Expected wg_ip"))?;
+    let endpoint = params["endpoint"]
+        .as_str()
+        .ok_or_else(|| ControlError::new(INVALID_PARAMS, "Expected endpoint"))?;
+    let fname = &static_config.network_yaml_filename;
+    let mut network_file =
+        NetworkFile::load(fname).map_err(|e| ControlError::new(INTERNAL_ERROR, e.to_string()))?;
+    let result = if method == "add-peer" {
+        let admin_port = params["admin_port"]
+            .as_u64()
+            .and_then(|port| u16::try_from(port).ok())
+            .ok_or_else(|| ControlError::new(INVALID_PARAMS, "Expected admin_port"))?;
+        network_file.add_peer(StaticPeerEntry::new(wg_ip, endpoint, admin_port))
+    } else {
+        network_file.set_endpoint(&wg_ip, endpoint)
+    };
+    result.map_err(|e| ControlError::new(INVALID_PARAMS, e.to_string()))?;
+    network_file
+        .save(fname)
+        .map_err(|e| ControlError::new(INTERNAL_ERROR, e.to_string()))?;
+    info!(target: "control", "{} {} in {}", method, wg_ip, fname);
+    Ok(())
+}
+
+// Only the static peers and the keepalive can be changed at runtime
+fn reload_config(static_config: &mut StaticConfiguration) -> Result<usize, ControlError> {
+    let network = load_network_config(&static_config.network_yaml_filename)
+        .map_err(|e| ControlError::new(INTERNAL_ERROR, e.to_string()))?;
+    if network.shared_key != static_config.shared_key
+        || network.accepted_shared_keys != static_config.accepted_shared_keys
+        || network.subnet != static_config.subnet
+        || network.areas != static_config.areas
+        || network.dns != static_config.dns
+    {
+        return Err(ControlError::new(
+            INTERNAL_ERROR,
+            "Changed sharedKey, acceptedSharedKeys, subnet, areas or dns needs a restart",
+        ));
+    }
+    info!(target: "control", "Reloaded {}", static_config.network_yaml_filename);
+    static_config.peers = network.peers;
+    static_config.merge_imported_peers();
+    static_config.persistent_keepalive = network.persistent_keepalive;
+    Ok(static_config.peers.len())
+}
+
+// A changed wgIp in peer.yaml is applied without restart, see crate::readdress.
+// Returns the previous wg_ip.
+fn reload_wg_ip(
+    now: u64,
+    static_config: &mut StaticConfiguration,
+) -> Result<Option<IpAddr>, ControlError> {
+    let Some(fname) = static_config.peer_yaml_filename.as_ref() else {
+        return Ok(None);
+    };
+    let wg_ip = load_peer_config(fname)
+        .map_err(|e| ControlError::new(INTERNAL_ERROR, e.to_string()))?
+        .wg_ip
+        .map(|ip| ip.parse::<IpAddr>())
+        .transpose()
+        .map_err(|e| ControlError::new(INTERNAL_ERROR, format!("wgIp: {}", e)))?;
+    match wg_ip {
+        Some(wg_ip) if wg_ip != static_config.wg_ip => {
+            let previous = readdress(now, static_config, wg_ip)
+                .map_err(|e| ControlError::new(INTERNAL_ERROR, e.to_string()))?;
+            info!(target: "control", "Changed wg_ip from {} to {}", previous, wg_ip);
+            Ok(Some(previous))
+        }
+        _ => Ok(None),
+    }
+}
+
+// Client side: send one request and wait for the response
+pub fn request(path: &str, method: &str, params: Value) -> BoxResult<ControlResponse> {
+    let mut stream =
+        UnixStream::connect(path).map_err(|e| format!("No running instance at {}: {}", path, e))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let request = json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 1});
+    let mut buf = serde_json::to_vec(&request)?;
+    buf.push(b'\n');
+    stream.write_all(&buf)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+// `wg_netmanager status`: print the known nodes of the running daemon
+pub fn print_status(path: &str) -> BoxResult<()> {
+    let response = request(path, "peers", Value::Null)?;
+    if let Some(e) = response.error {
+        return Err(format!("Control request failed: {}", e.message).into());
+    }
+    let peers: Vec<PeerInfo> = serde_json::from_value(response.result.unwrap_or_default())?;
+    print!("{}", status_table(&peers));
+    Ok(())
+}
+
+// `wg_netmanager export-peers <file>`
+pub fn export_peers(path: &str, fname: &str) -> BoxResult<()> {
+    let response = request(path, "export-peers", Value::Null)?;
+    if let Some(e) = response.error {
+        return Err(format!("Control request failed: {}", e.message).into());
+    }
+    let peer_db: PeerDatabase = serde_json::from_value(response.result.unwrap_or_default())?;
+    peer_db.write(fname)?;
+    println!("Exported {} peers to {}", peer_db.peers.len(), fname);
+    Ok(())
+}
+
+// `wg_netmanager save-state <file>`
+pub fn save_state(path: &str, fname: &str) -> BoxResult<()> {
+    let response = request(path, "snapshot", Value::Null)?;
+    if let Some(e) = response.error {
+        return Err(format!("Control request failed: {}", e.message).into());
+    }
+    let snapshot: StateSnapshot = serde_json::from_value(response.result.unwrap_or_default())?;
+    snapshot.write(fname)?;
+    println!("Saved state of {} nodes to {}", snapshot.nodes.len(), fname);
+    Ok(())
+}
+
+// `wg_netmanager force-relay <peer> on|off`
+pub fn request_force_relay(
+    path: &str,
+    peer: &str,
+    on: bool,
+    duration: Option<u64>,
+) -> BoxResult<()> {
+    let response = request(
+        path,
+        "force-relay",
+        json!({"peer": peer, "on": on, "duration": duration}),
+    )?;
+    if let Some(e) = response.error {
+        return Err(format!("Control request failed: {}", e.message).into());
+    }
+    let result = response.result.unwrap_or_default();
+    match result["forced_relay_s"].as_u64() {
+        Some(seconds) if seconds > 0 => {
+            println!("Relay to {} forced for {}s", result["wg_ip"], seconds)
+        }
+        _ => println!("Direct path to {} is used again", result["wg_ip"]),
+    }
+    Ok(())
+}
+
+// `wg_netmanager pending`
+pub fn print_pending(path: &str) -> BoxResult<()> {
+    let response = request(path, "pending", Value::Null)?;
+    if let Some(e) = response.error {
+        return Err(format!("Control request failed: {}", e.message).into());
+    }
+    let pending: Vec<PendingPeer> = serde_json::from_value(response.result.unwrap_or_default())?;
+    for peer in pending.iter() {
+        println!(
+            "{}  {}  {}  {}",
+            peer.wg_ip, peer.name, peer.endpoint, peer.identity
+        );
+    }
+    Ok(())
+}
+
+// `wg_netmanager approve <peer>`
+pub fn request_approve(path: &str, peer: &str) -> BoxResult<()> {
+    let response = request(path, "approve", json!({ "peer": peer }))?;
+    if let Some(e) = response.error {
+        return Err(format!("Control request failed: {}", e.message).into());
+    }
+    let approved: PendingPeer = serde_json::from_value(response.result.unwrap_or_default())?;
+    println!(
+        "Approved {} ({}) with identity {}",
+        approved.wg_ip, approved.name, approved.identity
+    );
+    Ok(())
+}
+
+// `wg_netmanager revoke-and-rekey <peer>`
+pub fn request_revoke_and_rekey(path: &str, peer: &str) -> BoxResult<()> {
+    let response = request(path, "revoke-and-rekey", json!({ "peer": peer }))?;
+    if let Some(e) = response.error {
+        return Err(format!("Control request failed: {}", e.message).into());
+    }
+    let result = response.result.unwrap_or_default();
+    println!(
+        "Revoked {} with public key {} and identity {}",
+        result["wg_ip"], result["public_key"], result["identity"]
+    );
+    println!(
+        "A new own key pair is used in {}s. Remove the peer from network.yaml, if it is a static peer.",
+        KEY_ROTATION_OVERLAP
+    );
+    Ok(())
+}
+
+// A pinned route or a relay is marked, or the pinned gateway is shown while it offers
+// no route
+fn gateway_cell(peer: &PeerInfo) -> String {
+    match (peer.gateway, peer.pinned_gateway) {
+        (Some(gw), Some(pinned)) if gw == pinned => format!("{} (pinned)", gw),
+        (Some(gw), Some(pinned)) => format!("{} (pin {} inactive)", gw, pinned),
+        (Some(gw), None) if peer.forced_relay_s.is_some() => format!("{} (forced relay)", gw),
+        (Some(gw), None) if peer.relay == Some(gw) => format!("{} (relay)", gw),
+        (Some(gw), None) => gw.to_string(),
+        (None, _) => "-".to_string(),
+    }
+}
+
+pub fn status_table(peers: &[PeerInfo]) -> String {
+    let opt = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let mut rows = vec![[
+        "NAME",
+        "WG_IP",
+        "CONNECTION",
+        "ENDPOINT",
+        "LAST SEEN",
+        "HOPS",
+        "RTT",
+        "GATEWAY",
+    ]
+    .map(|s| s.to_string())];
+    for peer in peers {
+        rows.push([
+            opt(peer.name.clone()),
+            peer.wg_ip.to_string(),
+            match peer.quarantined_key {
+                Some(_) => format!("{} (wg_ip conflict)", peer.endpoint_class),
+                None => peer.endpoint_class.clone(),
+            },
+            opt(peer.endpoint.map(|ep| ep.to_string())),
+            opt(peer.lastseen_s_ago.map(|s| format!("{}s ago", s))),
+            opt(peer.hop_cnt.map(|h| h.to_string())),
+            opt(peer.rtt_ms.map(|rtt| format!("{}ms", rtt))),
+            gateway_cell(peer),
+        ]);
+    }
+    let mut widths = [0; 8];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for row in rows.iter() {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
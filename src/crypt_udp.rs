@@ -1,19 +1,27 @@
+use std::collections::{HashSet, VecDeque};
 use std::fmt;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::IpAddr;
 use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
+use bincode::Options;
 use chacha20poly1305::aead::{Aead, NewAead};
 use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
 use crc::Crc;
+use ipnet::IpNet;
 use log::*;
 use serde::{Deserialize, Serialize};
 
 use crate::configuration::*;
 use crate::error::*;
+use crate::identity::{self, NodeIdentity, PublicIdentity};
+use crate::link_local::check_scope;
+use crate::log_sampling::sampled;
 use crate::node::Node;
 use crate::routedb::RouteInfo;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum AddressedTo {
     StaticAddress,
     LocalAddress,
@@ -58,31 +66,268 @@ impl AddressedTo {
 pub struct AdvertisementPacket {
     pub addressed_to: AddressedTo,
     pub public_key: PublicKeyWithTime,
+    // Replaces public_key at its creation time
+    pub next_public_key: Option<PublicKeyWithTime>,
     pub local_wg_port: u16,
     pub local_admin_port: u16,
-    pub wg_ip: Ipv4Addr,
+    pub wg_ip: IpAddr,
     pub name: String,
     pub my_visible_wg_endpoint: Option<SocketAddr>,
     pub your_visible_wg_endpoint: Option<SocketAddr>,
     pub routedb_version: usize,
+    // Networks behind the sender, which are routed via its wg_ip
+    pub exported_nets: Vec<IpNet>,
+    // Further ports of the sender's wireguard for multipath
+    pub extra_wg_ports: Vec<u16>,
+    // Seconds, for which the sender has forced the relay to the receiver. 0 for none
+    pub force_relay_s: u64,
+    // Recent revocations known to the sender, which are passed on by the receiver
+    pub revocations: Vec<Revocation>,
+    // The previous wg_ip of the sender during the grace period of a change
+    pub moved_from: Option<IpAddr>,
+    // The sender's identity and its signature over all other fields
+    pub identity: PublicIdentity,
+    pub signature: Vec<u8>,
+}
+impl AdvertisementPacket {
+    fn signed_data(&self) -> Vec<u8> {
+        bincode::serialize(&(
+            &self.addressed_to,
+            &self.public_key,
+            &self.next_public_key,
+            self.local_wg_port,
+            self.local_admin_port,
+            self.wg_ip,
+            &self.name,
+            self.my_visible_wg_endpoint,
+            self.your_visible_wg_endpoint,
+            self.routedb_version,
+            &self.exported_nets,
+            &self.extra_wg_ports,
+            self.force_relay_s,
+            &self.revocations,
+            self.moved_from,
+            self.identity,
+        ))
+        .unwrap()
+    }
+    pub fn sign(&mut self, identity: &NodeIdentity) {
+        self.identity = identity.public();
+        self.signature = identity.sign(&self.signed_data());
+    }
+    pub fn has_valid_signature(&self) -> bool {
+        identity::verify(&self.identity, &self.signed_data(), &self.signature)
+    }
+}
+// The keys of a node, which must not be accepted anymore, e.g. of a lost device.
+// Not the wg_ip is revoked, so the device can be replaced by one with new keys.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Revocation {
+    pub wg_ip: IpAddr,
+    pub public_key: Option<String>,
+    pub identity: Option<PublicIdentity>,
+    pub revoked_at: u64,
+}
+impl Revocation {
+    pub fn matches(&self, public_key: Option<&str>, identity: Option<&PublicIdentity>) -> bool {
+        (self.public_key.is_some() && self.public_key.as_deref() == public_key)
+            || (self.identity.is_some() && self.identity.as_ref() == identity)
+    }
 }
 #[derive(Serialize, Deserialize)]
 pub struct RouteDatabasePacket {
-    pub sender: Ipv4Addr,
+    pub sender: IpAddr,
     pub routedb_version: usize,
     pub nr_entries: usize,
+    // This packet is chunk number `chunk` out of `nr_chunks` for the database
+    pub chunk: usize,
+    pub nr_chunks: usize,
     pub known_routes: Vec<RouteInfo>,
 }
+// Serialized size of the routes per RouteDatabase packet. Together with the header
+// and the encryption, a packet fits into an ethernet MTU even via ipv6.
+pub const ROUTEDB_CHUNK_SIZE: usize = 1200;
+// Changes of the sender's route database since base_version
 #[derive(Serialize, Deserialize, Debug)]
+pub struct RouteDatabaseDeltaPacket {
+    pub sender: IpAddr,
+    pub base_version: usize,
+    pub routedb_version: usize,
+    // Entries of the database after applying the delta
+    pub nr_entries: usize,
+    pub changed_routes: Vec<RouteInfo>,
+    pub removed_routes: Vec<IpAddr>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct LocalContactPacket {
     pub public_key: PublicKeyWithTime,
+    pub next_public_key: Option<PublicKeyWithTime>,
     pub local_ip_list: Vec<IpAddr>,
     pub local_wg_port: u16,
     pub local_admin_port: u16,
     pub my_visible_wg_endpoint: Option<SocketAddr>,
-    pub wg_ip: Ipv4Addr,
+    pub wg_ip: IpAddr,
+    pub name: String,
+}
+// A node's view of the network as reported to the audit command
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditPacket {
+    pub sender: IpAddr,
     pub name: String,
+    pub public_key: PublicKeyWithTime,
+    pub routedb_version: usize,
+    pub known_routes: Vec<RouteInfo>,
+    pub known_nodes: Vec<AuditNodeInfo>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditNodeInfo {
+    pub wg_ip: IpAddr,
+    pub name: Option<String>,
+    pub public_key: Option<String>,
+    // version of the peer's route database as known to the sender
+    pub routedb_version: Option<usize>,
+}
+// Rendezvous of two peers behind NAT, which cannot reach each other. The request is
+// sent to the gateway and relayed towards the other peer. Each relay fills in the
+// observed visible endpoints of its direct peers. The last relay, which knows both
+// peers, sets the start time and sends the request to both peers, which then
+// punch through their NAT simultaneously.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConnectRequestPacket {
+    pub from: IpAddr,
+    pub to: IpAddr,
+    pub from_endpoint: Option<SocketAddr>,
+    pub to_endpoint: Option<SocketAddr>,
+    // Unix time in s, set by the last relay
+    pub start_time: u64,
+    // Remaining relays
+    pub ttl: u8,
+}
+
+// Request of a node with `wgIp: auto` to the static peers, see crate::address_lease
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddressRequestPacket {
+    pub identity: PublicIdentity,
+    // The current wg_ip on renewal
+    pub requested: Option<IpAddr>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddressLeasePacket {
+    pub identity: PublicIdentity,
+    pub wg_ip: IpAddr,
+    // in s
+    pub lease_time: u64,
+}
+
+// Exchanged by the nodes of a hub pair, see crate::ha_pair
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HaHeartbeatPacket {
+    pub priority: u8,
+    pub tie_breaker: u64,
+    pub active: bool,
+}
+// Sent to both nodes, which advertise the same wg_ip with alternating keys
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddressConflictPacket {
+    pub wg_ip: IpAddr,
+    pub kept_key: String,
+    pub quarantined_key: String,
 }
+
+// Maintenance command of an administrator to one node, see crate::fleet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum FleetCommand {
+    ReloadConfig,
+    SetLogLevel { level: String },
+    Status,
+}
+impl FleetCommand {
+    pub const NAMES: [&'static str; 3] = ["reload-config", "log-level", "status"];
+    pub fn name(&self) -> &'static str {
+        match self {
+            FleetCommand::ReloadConfig => "reload-config",
+            FleetCommand::SetLogLevel { .. } => "log-level",
+            FleetCommand::Status => "status",
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FleetCommandPacket {
+    // The wg_ip of the receiver, so the command cannot be replayed to another node
+    pub to: IpAddr,
+    // Random, and rejected on a second reception
+    pub nonce: u64,
+    // Unix time in s of the wall clock, because it is compared by other nodes
+    pub issued_at: u64,
+    pub command: FleetCommand,
+    // The administrator's identity and its signature over all other fields
+    pub identity: PublicIdentity,
+    pub signature: Vec<u8>,
+}
+impl FleetCommandPacket {
+    pub fn new(to: IpAddr, issued_at: u64, command: FleetCommand, identity: &NodeIdentity) -> Self {
+        let mut packet = FleetCommandPacket {
+            to,
+            nonce: rand::random(),
+            issued_at,
+            command,
+            identity: identity.public(),
+            signature: vec![],
+        };
+        packet.signature = identity.sign(&packet.signed_data());
+        packet
+    }
+    fn signed_data(&self) -> Vec<u8> {
+        bincode::serialize(&(
+            self.to,
+            self.nonce,
+            self.issued_at,
+            &self.command,
+            &self.identity,
+        ))
+        .unwrap()
+    }
+    pub fn has_valid_signature(&self) -> bool {
+        identity::verify(&self.identity, &self.signed_data(), &self.signature)
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FleetResultPacket {
+    pub sender: IpAddr,
+    pub nonce: u64,
+    // The output of the command or why it has been rejected
+    pub result: Result<String, String>,
+}
+
+// Limits of the decoded packets, so a corrupted or malicious packet cannot make the
+// receiver allocate huge amounts of memory or blow up the route database. All
+// received packets are checked by UdpPacket::decode() before processing.
+pub const MAX_PACKET_SIZE: usize = 2000;
+pub const MAX_ROUTES: usize = 4096;
+pub const MAX_IP_LIST: usize = 64;
+pub const MAX_NAME_LEN: usize = 255;
+pub const MAX_KEY_LEN: usize = 64;
+pub const MAX_NETS: usize = 64;
+pub const MAX_EXTRA_PORTS: usize = 16;
+pub const MAX_REVOCATIONS: usize = 16;
+
+fn check_limit(what: &str, len: usize, max: usize) -> BoxResult<()> {
+    if len > max {
+        return Err(format!("Too many {}: {} > {}", what, len, max).into());
+    }
+    Ok(())
+}
+fn check_routes(routes: &[RouteInfo]) -> BoxResult<()> {
+    check_limit("routes", routes.len(), MAX_ROUTES)?;
+    for ri in routes {
+        check_limit("exported networks", ri.exported_nets.len(), MAX_NETS)?;
+    }
+    Ok(())
+}
+fn check_key(key: &PublicKeyWithTime) -> BoxResult<()> {
+    check_limit("key characters", key.key.len(), MAX_KEY_LEN)
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum UdpPacket {
     Advertisement(AdvertisementPacket),
@@ -90,8 +335,117 @@ pub enum UdpPacket {
     RouteDatabase(RouteDatabasePacket),
     LocalContactRequest,
     LocalContact(LocalContactPacket),
+    AuditRequest,
+    Audit(AuditPacket),
+    // Asks for the changes since the given version
+    RouteDatabaseDeltaRequest { since: usize },
+    RouteDatabaseDelta(RouteDatabaseDeltaPacket),
+    // Round trip time measurement. The pong echoes the sender's time in ms.
+    Ping { sent_ms: u64 },
+    Pong { sent_ms: u64 },
+    ConnectRequest(ConnectRequestPacket),
+    AddressRequest(AddressRequestPacket),
+    AddressLease(AddressLeasePacket),
+    HaHeartbeat(HaHeartbeatPacket),
+    // Sent to the direct peers on shutdown, so they need not wait for the timeout
+    Goodbye,
+    AddressConflict(AddressConflictPacket),
+    FleetCommand(FleetCommandPacket),
+    FleetResult(FleetResultPacket),
 }
 impl UdpPacket {
+    // Deserialize and validate a received packet
+    pub fn decode(buf: &[u8]) -> BoxResult<Self> {
+        check_limit("bytes", buf.len(), MAX_PACKET_SIZE)?;
+        // Same encoding as bincode::serialize(), but lengths beyond the packet are
+        // rejected before any allocation
+        let packet: UdpPacket = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_PACKET_SIZE as u64)
+            .deserialize(buf)?;
+        packet.validate()?;
+        Ok(packet)
+    }
+    pub fn validate(&self) -> BoxResult<()> {
+        use UdpPacket::*;
+        match self {
+            Advertisement(ad) => {
+                check_key(&ad.public_key)?;
+                if let Some(next_public_key) = ad.next_public_key.as_ref() {
+                    check_key(next_public_key)?;
+                }
+                check_limit("name characters", ad.name.len(), MAX_NAME_LEN)?;
+                check_limit("exported networks", ad.exported_nets.len(), MAX_NETS)?;
+                check_limit("extra ports", ad.extra_wg_ports.len(), MAX_EXTRA_PORTS)?;
+                if ad.local_wg_port == ad.local_admin_port
+                    || ad.extra_wg_ports.contains(&ad.local_admin_port)
+                {
+                    return Err(format!("wg port equals admin port {}", ad.local_admin_port).into());
+                }
+                check_limit("revocations", ad.revocations.len(), MAX_REVOCATIONS)?;
+                for revocation in ad.revocations.iter() {
+                    let key_len = revocation.public_key.as_ref().map(|k| k.len());
+                    check_limit("key characters", key_len.unwrap_or(0), MAX_KEY_LEN)?;
+                }
+                check_limit("signature bytes", ad.signature.len(), MAX_KEY_LEN)
+            }
+            AddressConflict(conflict) => {
+                check_limit("key characters", conflict.kept_key.len(), MAX_KEY_LEN)?;
+                check_limit(
+                    "key characters",
+                    conflict.quarantined_key.len(),
+                    MAX_KEY_LEN,
+                )
+            }
+            RouteDatabase(db) => {
+                check_limit("route entries", db.nr_entries, MAX_ROUTES)?;
+                check_limit("route chunks", db.nr_chunks, MAX_ROUTES)?;
+                check_routes(&db.known_routes)
+            }
+            RouteDatabaseDelta(delta) => {
+                check_limit("route entries", delta.nr_entries, MAX_ROUTES)?;
+                check_routes(&delta.changed_routes)?;
+                check_limit("removed routes", delta.removed_routes.len(), MAX_ROUTES)
+            }
+            LocalContact(local) => {
+                check_key(&local.public_key)?;
+                if let Some(next_public_key) = local.next_public_key.as_ref() {
+                    check_key(next_public_key)?;
+                }
+                check_limit("local ips", local.local_ip_list.len(), MAX_IP_LIST)?;
+                check_limit("name characters", local.name.len(), MAX_NAME_LEN)
+            }
+            Audit(audit) => {
+                check_key(&audit.public_key)?;
+                check_limit("name characters", audit.name.len(), MAX_NAME_LEN)?;
+                check_routes(&audit.known_routes)?;
+                check_limit("nodes", audit.known_nodes.len(), MAX_ROUTES)
+            }
+            FleetCommand(packet) => {
+                if let crate::crypt_udp::FleetCommand::SetLogLevel { level } = &packet.command {
+                    check_limit("level characters", level.len(), MAX_NAME_LEN)?;
+                }
+                check_limit("signature bytes", packet.signature.len(), MAX_KEY_LEN)
+            }
+            FleetResult(packet) => {
+                let (Ok(output) | Err(output)) = &packet.result;
+                check_limit("result characters", output.len(), MAX_PACKET_SIZE)
+            }
+            RouteDatabaseRequest
+            | LocalContactRequest
+            | AuditRequest
+            | RouteDatabaseDeltaRequest { .. }
+            | Ping { .. }
+            | Pong { .. }
+            | ConnectRequest(_)
+            | AddressRequest(_)
+            | AddressLease(_)
+            | HaHeartbeat(_)
+            | Goodbye => Ok(()),
+        }
+    }
+    #[allow(clippy::too_many_arguments)]
     pub fn advertisement_from_config(
         static_config: &StaticConfiguration,
         routedb_version: usize,
@@ -99,10 +453,13 @@ impl UdpPacket {
         to_node: Option<&dyn Node>,
         local_wg_port: u16,
         my_visible_wg_endpoint: Option<SocketAddr>,
+        force_relay_s: u64,
+        revocations: Vec<Revocation>,
     ) -> Self {
-        UdpPacket::Advertisement(AdvertisementPacket {
+        let mut advertisement = AdvertisementPacket {
             addressed_to,
             public_key: static_config.my_public_key.clone(),
+            next_public_key: static_config.next_public_key().cloned(),
             local_wg_port,
             local_admin_port: static_config.admin_port,
             wg_ip: static_config.wg_ip,
@@ -110,23 +467,63 @@ impl UdpPacket {
             your_visible_wg_endpoint: to_node.and_then(|node| node.visible_wg_endpoint()),
             my_visible_wg_endpoint,
             routedb_version,
-        })
+            exported_nets: static_config.exported_nets.clone(),
+            extra_wg_ports: static_config.extra_wg_ports.clone(),
+            force_relay_s,
+            revocations,
+            moved_from: static_config
+                .previous_wg_ip
+                .as_ref()
+                .map(|previous| previous.wg_ip),
+            identity: [0; 32],
+            signature: vec![],
+        };
+        advertisement.sign(&static_config.identity);
+        UdpPacket::Advertisement(advertisement)
     }
     pub fn route_database_request() -> Self {
         UdpPacket::RouteDatabaseRequest {}
     }
+    pub fn route_database_delta_request(since: usize) -> Self {
+        UdpPacket::RouteDatabaseDeltaRequest { since }
+    }
+    // The routes are split into chunks of at most ROUTEDB_CHUNK_SIZE bytes. Only a
+    // route, which exceeds this size on its own, is sent in a larger chunk.
     pub fn make_route_database(
-        sender: Ipv4Addr,
+        sender: IpAddr,
         routedb_version: usize,
         nr_entries: usize,
         known_routes: Vec<&RouteInfo>,
-    ) -> Self {
-        UdpPacket::RouteDatabase(RouteDatabasePacket {
-            sender,
-            routedb_version,
-            nr_entries,
-            known_routes: known_routes.into_iter().cloned().collect(),
-        })
+    ) -> Vec<Self> {
+        let mut chunks: Vec<Vec<RouteInfo>> = vec![vec![]];
+        let mut chunk_size = 0;
+        for ri in known_routes {
+            let size = bincode::serialized_size(ri).unwrap_or(0) as usize;
+            if chunk_size + size > ROUTEDB_CHUNK_SIZE && chunk_size > 0 {
+                chunks.push(vec![]);
+                chunk_size = 0;
+            }
+            chunk_size += size;
+            chunks.last_mut().unwrap().push(ri.clone());
+        }
+        let nr_chunks = chunks.len();
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(chunk, known_routes)| {
+                UdpPacket::RouteDatabase(RouteDatabasePacket {
+                    sender,
+                    routedb_version,
+                    nr_entries,
+                    chunk,
+                    nr_chunks,
+                    known_routes,
+                })
+            })
+            .collect()
+    }
+    pub fn audit_request() -> Self {
+        UdpPacket::AuditRequest {}
     }
     pub fn local_contact_request() -> Self {
         UdpPacket::LocalContactRequest {}
@@ -138,6 +535,7 @@ impl UdpPacket {
     ) -> Self {
         UdpPacket::LocalContact(LocalContactPacket {
             public_key: static_config.my_public_key.clone(),
+            next_public_key: static_config.next_public_key().cloned(),
             local_ip_list: static_config.ip_list.clone(),
             local_wg_port,
             local_admin_port: static_config.admin_port,
@@ -155,6 +553,113 @@ impl fmt::Debug for UdpPacket {
             UdpPacket::RouteDatabase(_) => f.debug_struct("RouteDatabase").finish(),
             UdpPacket::LocalContactRequest => f.debug_struct("LocalContactRequest").finish(),
             UdpPacket::LocalContact(_) => f.debug_struct("LocalContact").finish(),
+            UdpPacket::AuditRequest => f.debug_struct("AuditRequest").finish(),
+            UdpPacket::Audit(_) => f.debug_struct("Audit").finish(),
+            UdpPacket::RouteDatabaseDeltaRequest { since } => f
+                .debug_struct("RouteDatabaseDeltaRequest")
+                .field("since", since)
+                .finish(),
+            UdpPacket::RouteDatabaseDelta(_) => f.debug_struct("RouteDatabaseDelta").finish(),
+            UdpPacket::Ping { sent_ms } => {
+                f.debug_struct("Ping").field("sent_ms", sent_ms).finish()
+            }
+            UdpPacket::Pong { sent_ms } => {
+                f.debug_struct("Pong").field("sent_ms", sent_ms).finish()
+            }
+            UdpPacket::ConnectRequest(req) => req.fmt(f),
+            UdpPacket::AddressRequest(req) => req.fmt(f),
+            UdpPacket::AddressLease(lease) => lease.fmt(f),
+            UdpPacket::HaHeartbeat(heartbeat) => heartbeat.fmt(f),
+            UdpPacket::Goodbye => f.debug_struct("Goodbye").finish(),
+            UdpPacket::AddressConflict(conflict) => conflict.fmt(f),
+            UdpPacket::FleetCommand(command) => command.fmt(f),
+            UdpPacket::FleetResult(result) => result.fmt(f),
+        }
+    }
+}
+
+// Returned by CryptUdp::recv_from, if a packet cannot be decrypted.
+// Usually the sender uses another sharedKey.
+#[derive(Debug)]
+pub struct DecryptError {
+    pub src_addr: SocketAddr,
+}
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Decryption error for packet from {}", self.src_addr)
+    }
+}
+impl std::error::Error for DecryptError {}
+
+// A valid packet has been received before
+#[derive(Debug)]
+pub struct ReplayError {
+    pub src_addr: SocketAddr,
+}
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Replayed packet from {}", self.src_addr)
+    }
+}
+impl std::error::Error for ReplayError {}
+
+// Packets with a larger difference of the timestamp to the local time are rejected
+pub const MAX_TIME_DIFF: u64 = 10;
+
+// The nonces of all accepted packets, which would still pass the timestamp check.
+// The nonces are random and not bound to the source address, so a captured packet
+// is rejected regardless of the address it is replayed from.
+#[derive(Default)]
+pub struct ReplayFilter {
+    seen: HashSet<[u8; 24]>,
+    // nonces in order of reception together with the local time
+    received: VecDeque<(u64, [u8; 24])>,
+}
+impl ReplayFilter {
+    // Returns false, if the nonce has been seen before
+    pub fn check(&mut self, now: u64, nonce: [u8; 24]) -> bool {
+        // A packet accepted at t has a timestamp >= t - MAX_TIME_DIFF and is
+        // rejected by the timestamp check after t + 2 * MAX_TIME_DIFF
+        while let Some((t, old_nonce)) = self.received.front() {
+            if t + 2 * MAX_TIME_DIFF >= now {
+                break;
+            }
+            self.seen.remove(old_nonce);
+            self.received.pop_front();
+        }
+        if !self.seen.insert(nonce) {
+            return false;
+        }
+        self.received.push_back((now, nonce));
+        true
+    }
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+// Packet counts of a socket and its clones for the metrics
+#[derive(Default, Debug)]
+pub struct UdpCounters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    decrypt_failures: AtomicU64,
+}
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct UdpCounts {
+    pub sent: u64,
+    pub received: u64,
+    pub decrypt_failures: u64,
+}
+impl UdpCounters {
+    pub fn counts(&self) -> UdpCounts {
+        UdpCounts {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            decrypt_failures: self.decrypt_failures.load(Ordering::Relaxed),
         }
     }
 }
@@ -165,63 +670,139 @@ impl fmt::Debug for UdpPacket {
 //
 // Encrypted data:
 //   p Bytes   Paylod
-//   ? bytes   padding to 8*x+2
+//   ? bytes   padding to 8*x+2, with the last byte being the frame version
 //   2 Bytes   Length of Payload
 //             ----- padded here to 8*x
 //   8 Bytes   Timestamp
 //   8 Bytes   CRC
+//
+// All numbers are little endian and of fixed size, so nodes of different byte
+// order and word size interoperate. Frames of version 0 have been sent without
+// the version byte and so have zero or no padding.
 
-pub struct CryptUdp {
-    socket: UdpSocket,
-    key: Option<[u8; 32]>,
-    udp_send_cnt: usize,
+// Version of the frames being sent
+pub const FRAME_VERSION: u8 = 1;
+
+// Content of a decoded frame
+#[derive(Debug, PartialEq)]
+pub struct Frame<'a> {
+    pub version: u8,
+    pub timestamp: u64,
+    pub payload: &'a [u8],
 }
 
-impl CryptUdp {
-    pub fn bind(ip: IpAddr, port: u16) -> BoxResult<Self> {
-        // bind to ipv4 AND ipv6
-        let socket = UdpSocket::bind(SocketAddr::new(ip, port))?;
-        Ok(CryptUdp {
-            socket,
-            key: None,
-            udp_send_cnt: 0,
-        })
+fn frame_crc(data: &[u8]) -> u64 {
+    let crc_gen = Crc::<u64>::new(&crc::CRC_64_ECMA_182);
+    let mut digest = crc_gen.digest();
+    digest.update(data);
+    digest.finalize()
+}
+
+// The frame to be encrypted for a payload
+pub fn encode_frame(payload: &[u8], timestamp: u64) -> BoxResult<Vec<u8>> {
+    let p = payload.len();
+    let p_u16 = match u16::try_from(p) {
+        Ok(p_u16) => p_u16,
+        Err(_) => strerror("payload too long")?,
+    };
+    let padded = (p + 3).div_ceil(8) * 8; // +3 for version and 2 Byte length
+
+    let mut buf = vec![0u8; padded + 16];
+    buf[..p].copy_from_slice(payload);
+    buf[padded - 3] = FRAME_VERSION;
+    buf[padded - 2..padded].copy_from_slice(&p_u16.to_le_bytes());
+    buf[padded..padded + 8].copy_from_slice(&timestamp.to_le_bytes());
+    let crc_result = frame_crc(&buf[..padded + 8]);
+    buf[padded + 8..padded + 16].copy_from_slice(&crc_result.to_le_bytes());
+    Ok(buf)
+}
+
+// Check a decrypted frame. Frames of a newer version are rejected.
+pub fn decode_frame(data: &[u8]) -> BoxResult<Frame<'_>> {
+    if !data.len().is_multiple_of(8) {
+        strerror("decrypted buffer is not octet-aligned")?;
     }
-    pub fn key(mut self, key: &[u8]) -> BoxResult<Self> {
-        if key.len() != 32 {
-            strerror("Invalid key length")?
-        } else {
-            let mut key_buf: [u8; 32] = Default::default();
-            key_buf.copy_from_slice(key);
-            self.key = Some(key_buf);
-            Ok(self)
-        }
+    if data.len() < 24 {
+        strerror("decrypted buffer is too short")?;
     }
-    pub fn try_clone(&self) -> BoxResult<Self> {
-        Ok(CryptUdp {
-            socket: self.socket.try_clone()?,
-            key: self.key,
-            udp_send_cnt: self.udp_send_cnt,
-        })
+    let padded = data.len() - 16;
+
+    let mut crc_buf = [0u8; 8];
+    crc_buf.copy_from_slice(&data[padded + 8..padded + 16]);
+    if u64::from_le_bytes(crc_buf) != frame_crc(&data[..padded + 8]) {
+        strerror("CRC mismatch")?;
     }
-    pub fn send_to(&mut self, payload: &[u8], addr: SocketAddr) -> BoxResult<usize> {
-        if let Some(raw_key) = self.key.as_ref() {
-            let p = payload.len();
-            let padded = ((p + 2 + 7) / 8) * 8; // +2 for 2 Byte length
-            let enc_length = padded + 16;
 
-            let timestamp = crate::util::now();
-            let mut buf = vec![0u8; enc_length];
-            buf[..p].copy_from_slice(payload);
-            buf[padded - 2..padded].copy_from_slice(&(p as u16).to_le_bytes());
-            buf[padded..padded + 8].copy_from_slice(&timestamp.to_le_bytes());
+    let mut ts_buf = [0u8; 8];
+    ts_buf.copy_from_slice(&data[padded..padded + 8]);
+    let timestamp = u64::from_le_bytes(ts_buf);
+
+    let mut p_buf = [0u8; 2];
+    p_buf.copy_from_slice(&data[padded - 2..padded]);
+    let p = u16::from_le_bytes(p_buf) as usize;
+    if p > padded - 2 {
+        strerror("invalid payload length")?;
+    }
+    let version = if p == padded - 2 { 0 } else { data[padded - 3] };
+    if version > FRAME_VERSION {
+        strerror("unsupported frame version")?;
+    }
+
+    Ok(Frame {
+        version,
+        timestamp,
+        payload: &data[..p],
+    })
+}
+
+// Encryption and checks of the admin packets independent of the socket, so the
+// packets of an in-memory transport are handled exactly like those via udp
+#[derive(Clone, Default)]
+pub struct PacketCipher {
+    key: Option<[u8; 32]>,
+    // Additionally accepted for received packets, e.g. during a change of the sharedKey
+    accepted_keys: Vec<[u8; 32]>,
+    send_cnt: usize,
+    // Shared by the clones of the socket
+    replay_filter: Arc<Mutex<ReplayFilter>>,
+    counters: Arc<UdpCounters>,
+}
 
-            let crc_gen = Crc::<u64>::new(&crc::CRC_64_ECMA_182);
-            let mut digest = crc_gen.digest();
-            digest.update(&buf[..padded + 8]);
-            let crc_result = digest.finalize();
+fn raw_key(key: &[u8]) -> BoxResult<[u8; 32]> {
+    if key.len() != 32 {
+        strerror("Invalid key length")?
+    } else {
+        let mut key_buf: [u8; 32] = Default::default();
+        key_buf.copy_from_slice(key);
+        Ok(key_buf)
+    }
+}
 
-            buf[padded + 8..padded + 16].copy_from_slice(&crc_result.to_le_bytes());
+impl PacketCipher {
+    pub fn set_key(&mut self, key: &[u8]) -> BoxResult<()> {
+        self.key = Some(raw_key(key)?);
+        Ok(())
+    }
+    pub fn set_accepted_keys(&mut self, keys: &[Vec<u8>]) -> BoxResult<()> {
+        self.accepted_keys = keys
+            .iter()
+            .map(|key| raw_key(key))
+            .collect::<BoxResult<Vec<_>>>()?;
+        Ok(())
+    }
+    pub fn share_replay_filter(&mut self, other: &PacketCipher) {
+        self.replay_filter = other.replay_filter.clone();
+    }
+    pub fn share_counters(&mut self, other: &PacketCipher) {
+        self.counters = other.counters.clone();
+    }
+    pub fn counters(&self) -> Arc<UdpCounters> {
+        self.counters.clone()
+    }
+    // The datagram for addr
+    pub fn encrypt(&mut self, payload: &[u8], addr: SocketAddr) -> BoxResult<Vec<u8>> {
+        if let Some(raw_key) = self.key.as_ref() {
+            let buf = encode_frame(payload, crate::util::wall_now())?;
 
             let nonce_raw: [u8; 24] = rand::random();
             let nonce = XNonce::from_slice(&nonce_raw);
@@ -231,18 +812,27 @@ impl CryptUdp {
                 .encrypt(nonce, &buf[..])
                 .map_err(|e| format!("{:?}", e))?;
             encrypted.append(&mut nonce_raw.to_vec());
-            self.udp_send_cnt += 1;
-            debug!(target: "udp", "#{}: send {} Bytes to {:?}", self.udp_send_cnt, encrypted.len(), addr);
-            Ok(self.socket.send_to(&encrypted, addr)?)
+            self.send_cnt += 1;
+            sampled!(Level::Debug, target: "udp", "#{}: send {} Bytes to {:?}", self.send_cnt, encrypted.len(), addr);
+            Ok(encrypted)
         } else {
             strerror("No encryption key")?
         }
     }
-    pub fn recv_from(&self, buf: &mut [u8]) -> BoxResult<(usize, SocketAddr)> {
+    // To be called, when the datagram has been sent
+    pub fn count_sent(&self) {
+        self.counters.sent.fetch_add(1, Ordering::Relaxed);
+    }
+    // Decrypt and check a received datagram. The payload is copied into buf.
+    pub fn decrypt(
+        &self,
+        enc_buf: &[u8],
+        src_addr: SocketAddr,
+        buf: &mut [u8],
+    ) -> BoxResult<usize> {
         if let Some(raw_key) = self.key.as_ref() {
-            let mut enc_buf: Vec<u8> = vec![0; 1500];
-            let (length, src_addr) = self.socket.recv_from(&mut enc_buf)?;
-            debug!(target: "udp", "received {} Bytes from {}", length, src_addr);
+            let length = enc_buf.len();
+            sampled!(Level::Debug, target: "udp", "received {} Bytes from {}", length, src_addr);
 
             if length <= 24 {
                 error!(target:"udp", "received buffer too short");
@@ -252,65 +842,172 @@ impl CryptUdp {
 
             let nonce_raw = enc_buf[new_length..length].to_vec();
             let nonce = XNonce::from_slice(&nonce_raw);
-            let key = Key::from_slice(raw_key);
-            let cipher = XChaCha20Poly1305::new(key);
-            let decrypted = cipher
-                .decrypt(nonce, &enc_buf[..new_length])
-                .map_err(|e| format!("Decryption error {:?}", e))?;
-
-            if decrypted.len() % 8 != 0 {
-                error!(target:"udp","decrypted buffer is not octet-aligned");
-                strerror("decrypted buffer is not octet-aligned")?;
-            }
-            if decrypted.len() < 24 {
-                error!(target:"udp","decrypted buffer is too short");
-                strerror("decrypted buffer is too short")?;
-            }
-
-            let padded = decrypted.len() - 16;
-
-            let crc_gen = Crc::<u64>::new(&crc::CRC_64_ECMA_182);
-            let mut digest = crc_gen.digest();
-            digest.update(&decrypted[..padded + 8]);
-            let crc_result = digest.finalize();
-
-            let mut crc_buf = [0u8; 8];
-            crc_buf.copy_from_slice(&decrypted[padded + 8..padded + 16]);
-            let crc_received = u64::from_le_bytes(crc_buf);
-
-            if crc_received != crc_result {
-                error!(target:"udp","CRC mismatch");
-                strerror("CRC mismatch")?;
-            }
-
-            let mut ts_buf = [0u8; 8];
-            ts_buf.copy_from_slice(&decrypted[padded..padded + 8]);
-            let ts_received = u64::from_le_bytes(ts_buf);
+            let decrypted = std::iter::once(raw_key)
+                .chain(self.accepted_keys.iter())
+                .find_map(|raw_key| {
+                    let cipher = XChaCha20Poly1305::new(Key::from_slice(raw_key));
+                    cipher.decrypt(nonce, &enc_buf[..new_length]).ok()
+                });
+            let decrypted = match decrypted {
+                Some(decrypted) => decrypted,
+                None => {
+                    self.counters
+                        .decrypt_failures
+                        .fetch_add(1, Ordering::Relaxed);
+                    return Err(Box::new(DecryptError { src_addr }));
+                }
+            };
 
-            let timestamp = crate::util::now();
-            let dt = if ts_received >= timestamp {
-                ts_received - timestamp
-            } else {
-                timestamp - ts_received
+            let frame = match decode_frame(&decrypted) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    error!(target:"udp", "{}", e);
+                    return Err(e);
+                }
             };
+
+            // The senders' clocks are compared, so not the mesh clock
+            let timestamp = crate::util::wall_now();
+            let dt = frame.timestamp.abs_diff(timestamp);
             if dt != 0 {
-                debug!("UDP TIMESTAMP {}", dt);
+                sampled!(Level::Debug, target: "udp", "UDP TIMESTAMP {}", dt);
             }
-            if dt > 10 {
+            if dt > MAX_TIME_DIFF {
                 error!(target:"udp","time mismatch {} seconds", dt);
                 strerror("time mismatch")?;
             }
 
-            let mut p_buf = [0u8; 2];
-            p_buf.copy_from_slice(&decrypted[padded - 2..padded]);
-            let p = u16::from_le_bytes(p_buf) as usize;
+            let mut nonce_buf = [0u8; 24];
+            nonce_buf.copy_from_slice(&nonce_raw);
+            if !self
+                .replay_filter
+                .lock()
+                .unwrap()
+                .check(timestamp, nonce_buf)
+            {
+                return Err(Box::new(ReplayError { src_addr }));
+            }
 
-            buf[..p].copy_from_slice(&decrypted[..p]);
+            let p = frame.payload.len();
+            if p > buf.len() {
+                error!(target:"udp","payload exceeds the receive buffer");
+                strerror("payload exceeds the receive buffer")?;
+            }
+            buf[..p].copy_from_slice(frame.payload);
 
-            Ok((p, src_addr))
+            self.counters.received.fetch_add(1, Ordering::Relaxed);
+            Ok(p)
         } else {
             error!(target:"udp","No encryption key");
             strerror("No encryption key")?
         }
     }
 }
+
+// A full send buffer of a non-blocking socket is retried every millisecond that often
+const SEND_RETRIES: u32 = 100;
+
+pub struct CryptUdp {
+    socket: UdpSocket,
+    cipher: PacketCipher,
+}
+
+impl CryptUdp {
+    pub fn bind(ip: IpAddr, port: u16) -> BoxResult<Self> {
+        // bind to ipv4 AND ipv6
+        let socket = UdpSocket::bind(SocketAddr::new(ip, port))?;
+        Ok(CryptUdp::from_socket(socket))
+    }
+    // For sockets handed over from another process
+    pub fn from_socket(socket: UdpSocket) -> Self {
+        CryptUdp {
+            socket,
+            cipher: PacketCipher::default(),
+        }
+    }
+    #[cfg(unix)]
+    pub fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        use std::os::unix::io::AsRawFd;
+        self.socket.as_raw_fd()
+    }
+    pub fn key(mut self, key: &[u8]) -> BoxResult<Self> {
+        self.cipher.set_key(key)?;
+        Ok(self)
+    }
+    // Keys accepted for received packets in addition to key, which is used for sending
+    pub fn accepted_keys(mut self, keys: &[Vec<u8>]) -> BoxResult<Self> {
+        self.cipher.set_accepted_keys(keys)?;
+        Ok(self)
+    }
+    // Packets received on either socket are checked against the same nonces
+    pub fn share_replay_filter(&mut self, other: &CryptUdp) {
+        self.cipher.share_replay_filter(&other.cipher);
+    }
+    // The counts of both sockets are reported as one
+    pub fn share_counters(&mut self, other: &CryptUdp) {
+        self.cipher.share_counters(&other.cipher);
+    }
+    pub fn counters(&self) -> Arc<UdpCounters> {
+        self.cipher.counters()
+    }
+    pub fn local_addr(&self) -> BoxResult<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+    pub fn set_read_timeout(&self, timeout: Option<std::time::Duration>) -> BoxResult<()> {
+        Ok(self.socket.set_read_timeout(timeout)?)
+    }
+    pub fn try_clone(&self) -> BoxResult<Self> {
+        Ok(CryptUdp {
+            socket: self.socket.try_clone()?,
+            cipher: self.cipher.clone(),
+        })
+    }
+    pub fn send_to(&mut self, payload: &[u8], addr: SocketAddr) -> BoxResult<usize> {
+        check_scope(&addr)?;
+        let encrypted = self.cipher.encrypt(payload, addr)?;
+        let mut retries = 0;
+        let sent = loop {
+            match self.socket.send_to(&encrypted, addr) {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock && retries < SEND_RETRIES => {
+                    retries += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+                result => break result?,
+            }
+        };
+        self.cipher.count_sent();
+        Ok(sent)
+    }
+    pub fn recv_from(&self, buf: &mut [u8]) -> BoxResult<(usize, SocketAddr)> {
+        let mut enc_buf: Vec<u8> = vec![0; 1500];
+        let (length, src_addr) = self.socket.recv_from(&mut enc_buf)?;
+        let p = self.cipher.decrypt(&enc_buf[..length], src_addr, buf)?;
+        Ok((p, src_addr))
+    }
+    // For receiving in a task of the async run loop. Needs to be called within the runtime.
+    // The clone shares the file description, so this socket becomes non-blocking, too.
+    // send_to() waits for a full send buffer like before, but recv_from() fails with
+    // WouldBlock, if nothing has been received.
+    pub fn try_clone_async(&self) -> BoxResult<AsyncCryptUdp> {
+        let socket = self.socket.try_clone()?;
+        socket.set_nonblocking(true)?;
+        Ok(AsyncCryptUdp {
+            socket: tokio::net::UdpSocket::from_std(socket)?,
+            cipher: self.cipher.clone(),
+        })
+    }
+}
+
+// Receiving side of a CryptUdp for the async runtime. Sending is done via CryptUdp.
+pub struct AsyncCryptUdp {
+    socket: tokio::net::UdpSocket,
+    cipher: PacketCipher,
+}
+impl AsyncCryptUdp {
+    pub async fn recv_from(&self, buf: &mut [u8]) -> BoxResult<(usize, SocketAddr)> {
+        let mut enc_buf: Vec<u8> = vec![0; 1500];
+        let (length, src_addr) = self.socket.recv_from(&mut enc_buf).await?;
+        let p = self.cipher.decrypt(&enc_buf[..length], src_addr, buf)?;
+        Ok((p, src_addr))
+    }
+}
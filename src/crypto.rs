@@ -0,0 +1,37 @@
+// Wireguard keys without the wg tools.
+//
+// A private key is 32 random bytes clamped for curve25519, the public key is its
+// x25519 base point multiplication. Both are base64 encoded like by wg:
+//
+//      generate_key_pair()         wg genkey | tee private | wg pubkey
+//      public_key_of(private)      wg pubkey < private
+//
+use crate::error::*;
+
+// Clamping of a curve25519 scalar
+fn clamp(mut raw_key: [u8; 32]) -> [u8; 32] {
+    raw_key[0] &= 248;
+    raw_key[31] &= 127;
+    raw_key[31] |= 64;
+    raw_key
+}
+
+fn public_key(raw_priv_key: [u8; 32]) -> String {
+    let secret = x25519_dalek::StaticSecret::from(raw_priv_key);
+    let public = x25519_dalek::PublicKey::from(&secret);
+    base64::encode(public.as_bytes())
+}
+
+// Returns the private and the public key
+pub fn generate_key_pair() -> (String, String) {
+    let raw_priv_key = clamp(rand::random());
+    (base64::encode(raw_priv_key), public_key(raw_priv_key))
+}
+
+pub fn public_key_of(private_key: &str) -> BoxResult<String> {
+    let raw_priv_key: [u8; 32] = base64::decode(private_key.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or("Invalid wireguard private key")?;
+    Ok(public_key(raw_priv_key))
+}
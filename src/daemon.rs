@@ -0,0 +1,80 @@
+// The lifecycle of an embedded daemon.
+//
+// run_loop::run() is meant for the command line tool and takes over Ctrl-C. A Daemon
+// runs the same main loop in an own thread instead. stop() ends it like Ctrl-C, so
+// the routes and the interface are cleaned up as on shutdown of the tool. Dropping
+// the Daemon stops it and waits for the thread.
+//
+use std::thread::JoinHandle;
+
+use crate::configuration::StaticConfiguration;
+use crate::error::*;
+use crate::event::{channel, Event, EventSender};
+use crate::run_loop::run_with_channel;
+use crate::wg_dev::WireguardDevice;
+use crate::{Arch, Architecture};
+
+pub struct Daemon {
+    tx: EventSender,
+    // The error is passed as string, because BoxResult is not Send
+    thread: Option<JoinHandle<Result<(), String>>>,
+}
+impl Daemon {
+    // With the wireguard device of the configured backend for the interface wg_name
+    pub fn start(static_config: StaticConfiguration) -> BoxResult<Self> {
+        Daemon::start_with(static_config, |static_config| {
+            Arch::get_wg_dev_for_backend(&static_config.wg_name, static_config.backend)
+        })
+    }
+    // The device is created within the thread of the daemon
+    pub fn start_with<F>(static_config: StaticConfiguration, new_device: F) -> BoxResult<Self>
+    where
+        F: FnOnce(&StaticConfiguration) -> BoxResult<Box<dyn WireguardDevice>> + Send + 'static,
+    {
+        let (tx, rx) = channel();
+        let tx_loop = tx.clone();
+        let thread = std::thread::Builder::new()
+            .name("wg_netmanager".to_string())
+            .spawn(move || {
+                new_device(&static_config)
+                    .and_then(|wg_dev| run_with_channel(&static_config, wg_dev, tx_loop, rx))
+                    .map_err(|e| e.to_string())
+            })?;
+        Ok(Daemon {
+            tx,
+            thread: Some(thread),
+        })
+    }
+    pub fn is_running(&self) -> bool {
+        self.thread
+            .as_ref()
+            .map(|thread| !thread.is_finished())
+            .unwrap_or(false)
+    }
+    // Only requests the shutdown. join() waits for it.
+    pub fn stop(&self) {
+        // Fails only, if the main loop has ended already
+        self.tx.send(Event::CtrlC).ok();
+    }
+    // Waits for the end of the main loop and returns its result
+    pub fn join(mut self) -> BoxResult<()> {
+        self.wait()
+    }
+    fn wait(&mut self) -> BoxResult<()> {
+        match self.thread.take() {
+            Some(thread) => match thread.join() {
+                Ok(rc) => rc.map_err(|e| e.into()),
+                Err(_) => strerror("The daemon has panicked"),
+            },
+            None => Ok(()),
+        }
+    }
+}
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.stop();
+            self.wait().ok();
+        }
+    }
+}
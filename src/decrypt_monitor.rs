@@ -0,0 +1,114 @@
+// Detection of sustained decrypt failures.
+//
+// If two nodes use different sharedKeys, all admin packets are dropped by CryptUdp
+// and the only visible effect are endless decryption errors. So failures are counted
+// per source ip within a time window. When a static peer's address exceeds the
+// threshold, a hint about a likely shared key mismatch is created. Any valid
+// packet from that address resets its count.
+//
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::configuration::*;
+
+const WINDOW: u64 = 60;
+// Repeat the hint for an ongoing mismatch only every 10 minutes
+const HINT_INTERVAL: u64 = 600;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMismatchHint {
+    pub wg_ip: IpAddr,
+    pub src_addr: SocketAddr,
+    pub failures: u32,
+}
+impl fmt::Display for KeyMismatchHint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "likely shared key mismatch with peer {}: {} undecryptable packets from {} within {}s",
+            self.wg_ip, self.failures, self.src_addr, WINDOW
+        )
+    }
+}
+
+struct FailureWindow {
+    start: u64,
+    count: u32,
+    hinted_at: Option<u64>,
+}
+
+pub struct DecryptMonitor {
+    // 0 disables the hints, but failures are still counted
+    threshold: u32,
+    sources: HashMap<IpAddr, FailureWindow>,
+    total_failures: u64,
+    hints: HashMap<IpAddr, KeyMismatchHint>,
+}
+impl DecryptMonitor {
+    pub fn new(threshold: u32) -> Self {
+        DecryptMonitor {
+            threshold,
+            sources: HashMap::new(),
+            total_failures: 0,
+            hints: HashMap::new(),
+        }
+    }
+    pub fn total_failures(&self) -> u64 {
+        self.total_failures
+    }
+    // The currently active hints, sorted by wg_ip
+    pub fn hints(&self) -> Vec<&KeyMismatchHint> {
+        let mut hints = self.hints.values().collect::<Vec<_>>();
+        hints.sort_by_key(|hint| hint.wg_ip);
+        hints
+    }
+    // Returns a hint, if this failure makes the source exceed the threshold
+    pub fn record_failure(
+        &mut self,
+        now: u64,
+        src_addr: SocketAddr,
+        peers: &HashMap<IpAddr, PublicPeer>,
+    ) -> Option<KeyMismatchHint> {
+        self.total_failures += 1;
+        let window = self.sources.entry(src_addr.ip()).or_insert(FailureWindow {
+            start: now,
+            count: 0,
+            hinted_at: None,
+        });
+        if now >= window.start + WINDOW {
+            window.start = now;
+            window.count = 0;
+        }
+        window.count += 1;
+
+        if self.threshold == 0 || window.count < self.threshold {
+            return None;
+        }
+        if let Some(hinted_at) = window.hinted_at {
+            if now < hinted_at + HINT_INTERVAL {
+                return None;
+            }
+        }
+
+        // Resolve the static endpoints only now and rarely, because this may need DNS
+        window.hinted_at = Some(now);
+        let wg_ip = peers.values().find_map(|peer| {
+            let endpoints = peer.resolve_endpoint().ok()?;
+            endpoints.contains(&src_addr.ip()).then_some(peer.wg_ip)
+        })?;
+        let hint = KeyMismatchHint {
+            wg_ip,
+            src_addr,
+            failures: window.count,
+        };
+        self.hints.insert(wg_ip, hint.clone());
+        Some(hint)
+    }
+    pub fn record_success(&mut self, src_addr: SocketAddr) {
+        if self.sources.remove(&src_addr.ip()).is_some() {
+            self.hints
+                .retain(|_, hint| hint.src_addr.ip() != src_addr.ip());
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// Resolution of the endpoints of static peers.
+//
+// By default the host of endPoint is resolved via DNS on every advertisement, so dyndns
+// hosts work. Per static peer in network.yaml, `resolver` selects another source to
+// integrate with an existing service discovery:
+//
+//   resolver:
+//     hostsFile: /etc/wg_netmanager/endpoints
+//
+// looks up the host in a file with lines `<ip> <name>...` like /etc/hosts, and
+//
+//   resolver:
+//     command: /usr/local/bin/lookup-endpoint
+//
+// calls the command with the host as only argument, which prints one ip per line. The
+// file is read and the command is called on each resolution, so changes apply at once.
+// An endpoint with an ip instead of a host name is never resolved.
+//
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::error::*;
+use crate::link_local::split_scope;
+
+// The resolution runs in the main loop
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum EndpointResolver {
+    #[default]
+    Dns,
+    HostsFile(String),
+    Command(String),
+}
+impl EndpointResolver {
+    // The ips of an endpoint <host:port>. The interface of a link-local ip is dropped,
+    // see PublicPeer::scope_id.
+    pub fn resolve(&self, endpoint: &str) -> BoxResult<Vec<IpAddr>> {
+        let (endpoint, _) = split_scope(endpoint)?;
+        let endpoint = endpoint.as_str();
+        if let Ok(sa) = endpoint.parse::<SocketAddr>() {
+            return Ok(vec![sa.ip()]);
+        }
+        let host = match endpoint.rsplit_once(':') {
+            Some((host, _port)) => host,
+            None => return strerror("endpoint should be <hostname/ip:port>"),
+        };
+        let ips = match self {
+            EndpointResolver::Dns => endpoint
+                .to_socket_addrs()?
+                .map(|sa| sa.ip())
+                .collect::<Vec<_>>(),
+            EndpointResolver::HostsFile(path) => lookup_hosts_file(path, host)?,
+            EndpointResolver::Command(command) => lookup_command(command, host)?,
+        };
+        if ips.is_empty() {
+            return Err(format!("No ip found for {}", host).into());
+        }
+        Ok(ips)
+    }
+}
+
+fn lookup_hosts_file(path: &str, host: &str) -> BoxResult<Vec<IpAddr>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut ips = vec![];
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(ip) = fields.next() else {
+            continue;
+        };
+        if fields.any(|name| name.eq_ignore_ascii_case(host)) {
+            ips.push(ip.parse()?);
+        }
+    }
+    Ok(ips)
+}
+
+fn lookup_command(command: &str, host: &str) -> BoxResult<Vec<IpAddr>> {
+    let mut child = Command::new(command)
+        .arg(host)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > COMMAND_TIMEOUT {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(format!("{} did not finish in time", command).into());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    if !status.success() {
+        return Err(format!("{} failed with {}", command, status).into());
+    }
+    let mut output = String::new();
+    if let Some(mut stdout) = child.stdout.take() {
+        stdout.read_to_string(&mut output)?;
+    }
+    let mut ips = vec![];
+    for line in output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        ips.push(line.parse()?);
+    }
+    Ok(ips)
+}
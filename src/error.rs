@@ -53,9 +53,9 @@ pub fn set_up_logging(log_filter: log::LevelFilter, opt_fname: Option<String>) -
                 message = message,
             ));
         })
-        // set the default log level. to filter out verbose log messages from dependencies, set
-        // this to Warn and overwrite the log level for your crate.
-        .level(log_filter)
+        // The log level is only limited by log::max_level(), so it can be raised at
+        // runtime, e.g. by a fleet command
+        .level(log::LevelFilter::Trace)
         // change log levels for individual modules. Note: This looks for the record's target
         // field which defaults to the module path but can be overwritten with the `target`
         // parameter:
@@ -69,6 +69,7 @@ pub fn set_up_logging(log_filter: log::LevelFilter, opt_fname: Option<String>) -
     }
 
     logger.apply().unwrap();
+    log::set_max_level(log_filter);
 
     debug!("finished setting up logging! yay!");
     Ok(())
@@ -1,33 +1,101 @@
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, SocketAddr};
 
-use crate::crypt_udp::{AddressedTo, UdpPacket};
+use crate::crypt_udp::{
+    AddressConflictPacket, AddressLeasePacket, AddressedTo, ConnectRequestPacket, UdpPacket,
+};
 use crate::tui_display::TuiAppEvent;
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Event {
     Udp(UdpPacket, SocketAddr),
+    DecryptFailure(SocketAddr),
     UpdateWireguardConfiguration,
+    // Apply the latest configuration queued by UpdateWireguardConfiguration
+    ApplyWireguardConfiguration,
+    // The earliest scheduled node is due
+    NodesDue,
+    // Announce a new own key pair now
+    Rekey,
     WireguardPortHop,
     CtrlC,
     SendAdvertisement {
         addressed_to: AddressedTo,
         to: SocketAddr,
-        wg_ip: Ipv4Addr,
+        wg_ip: IpAddr,
     },
     SendRouteDatabaseRequest {
-        to: SocketAddrV4,
+        to: SocketAddr,
     },
+    // With since, only the changes since this version are requested
     SendRouteDatabase {
-        to: SocketAddrV4,
+        to: SocketAddr,
+        since: Option<usize>,
     },
     SendLocalContactRequest {
-        to: SocketAddrV4,
+        to: SocketAddr,
     },
     SendLocalContact {
-        to: SocketAddrV4,
+        to: SocketAddr,
+    },
+    // Push the known local contact of a direct peer
+    SendLocalContactOf {
+        wg_ip: IpAddr,
+        to: SocketAddr,
+    },
+    SendAudit {
+        to: SocketAddr,
+    },
+    // Measure the round trip time to a direct peer
+    SendPing {
+        to: SocketAddr,
+    },
+    SendPong {
+        to: SocketAddr,
+        sent_ms: u64,
+    },
+    // Ask the gateway of a distant node for a rendezvous
+    SendConnectRequest {
+        wg_ip: IpAddr,
+    },
+    // Relay a rendezvous towards or back to the peers
+    ForwardConnectRequest {
+        to: SocketAddr,
+        request: ConnectRequestPacket,
+    },
+    // Answer of a static peer to an AddressRequest
+    SendAddressLease {
+        to: SocketAddr,
+        lease: AddressLeasePacket,
+    },
+    // Tell a node, that its wg_ip is used by another node, too
+    SendAddressConflict {
+        to: SocketAddr,
+        conflict: AddressConflictPacket,
+    },
+    // The own wg_ip has changed from previous
+    Readdress {
+        previous: IpAddr,
     },
     UpdateRoutes,
     TimerTick1s,
     TuiApp(TuiAppEvent),
     ReadWireguardConfiguration,
+    // A request via the control socket
+    #[cfg(unix)]
+    Control(crate::control::ControlCommand),
+    // A node of the own mesh announced via mDNS
+    #[cfg(feature = "mdns")]
+    MdnsPeer(crate::mdns::MdnsService),
+    // A new instance requests to take over
+    #[cfg(target_os = "linux")]
+    Handover(std::os::unix::net::UnixStream),
+}
+
+// The run loop is async, but events are sent from plain threads, too
+pub type EventSender = tokio::sync::mpsc::UnboundedSender<Event>;
+pub type EventReceiver = tokio::sync::mpsc::UnboundedReceiver<Event>;
+
+pub fn channel() -> (EventSender, EventReceiver) {
+    tokio::sync::mpsc::unbounded_channel()
 }
@@ -0,0 +1,314 @@
+// Maintenance commands of an administrator to selected nodes of the network.
+//
+// Any holder of the shared key can send admin packets. So a command is signed with the
+// administrator's identity (see crate::identity), and a node executes it only, if the
+// identity is listed with --fleet-admin and the command with --fleet-command. Both are
+// empty by default, which disables the commands.
+//
+// A command names the wg_ip of the receiver and carries a random nonce and the time of
+// issue. A command issued more than MAX_AGE ago or with a nonce seen before is
+// rejected, so a captured command cannot be replayed. The nonce is only recorded for an
+// accepted command. The sender and the receiver log every command with the target
+// fleet, whether executed or rejected.
+//
+// Only a listed administrator with a valid signature gets an answer. Otherwise the
+// command is dropped silently, so a node with disabled commands does not reveal itself
+// to other holders of the shared key.
+//
+//    reload-config       re-read network.yaml like the control method with this name
+//    log-level <level>   off, error, warn, info, debug or trace
+//    status              name, version, nodes and routes of the node
+//
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use log::*;
+use serde_json::json;
+
+use crate::audit;
+use crate::configuration::*;
+use crate::crypt_udp::*;
+use crate::error::*;
+use crate::event::Event;
+use crate::manager::NetworkManager;
+
+// Allows for some clock skew between the administrator and the nodes
+pub const MAX_AGE: u64 = 60;
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// The output of a command or why it has not been executed
+pub type FleetResult = Result<String, String>;
+
+// Why a command has not been executed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    // Not from a verified administrator, so not answered
+    Dropped(String),
+    // Answered to the administrator
+    Refused(String),
+}
+
+// The nonces of the commands accepted within MAX_AGE
+#[derive(Default)]
+pub struct FleetGuard {
+    seen: HashMap<u64, u64>,
+}
+impl FleetGuard {
+    // wall_now is compared with issued_at, see crate::util
+    pub fn check(
+        &mut self,
+        wall_now: u64,
+        static_config: &StaticConfiguration,
+        packet: &FleetCommandPacket,
+    ) -> Result<(), Rejection> {
+        if static_config.fleet_admins.is_empty() {
+            return Err(Rejection::Dropped(
+                "fleet commands are disabled".to_string(),
+            ));
+        }
+        if !static_config.fleet_admins.contains(&packet.identity) {
+            return Err(Rejection::Dropped("unknown administrator".to_string()));
+        }
+        if !packet.has_valid_signature() {
+            return Err(Rejection::Dropped("invalid signature".to_string()));
+        }
+        if packet.to != static_config.wg_ip {
+            return Err(Rejection::Refused(format!("addressed to {}", packet.to)));
+        }
+        if wall_now.abs_diff(packet.issued_at) > MAX_AGE {
+            return Err(Rejection::Refused(format!(
+                "issued at {}, now is {}",
+                packet.issued_at, wall_now
+            )));
+        }
+        self.seen
+            .retain(|_, issued_at| *issued_at + MAX_AGE >= wall_now);
+        if self.seen.contains_key(&packet.nonce) {
+            return Err(Rejection::Refused("replayed".to_string()));
+        }
+        let name = packet.command.name();
+        if !static_config.fleet_commands.iter().any(|c| c == name) {
+            return Err(Rejection::Refused(format!("{} is not whitelisted", name)));
+        }
+        self.seen.insert(packet.nonce, packet.issued_at);
+        Ok(())
+    }
+    // Checks and executes a received command. Returns the reply, if any, and the events
+    // to be processed afterwards.
+    // now is the mesh clock for the execution, wall_now for the check
+    pub fn process(
+        &mut self,
+        now: u64,
+        wall_now: u64,
+        packet: &FleetCommandPacket,
+        src_addr: SocketAddr,
+        network_manager: &mut NetworkManager,
+        static_config: &mut StaticConfiguration,
+    ) -> (Option<FleetResultPacket>, Vec<Event>) {
+        let admin = base64::encode(packet.identity);
+        let (result, events) = match self.check(wall_now, static_config, packet) {
+            Ok(()) => {
+                let (result, events) =
+                    execute(now, &packet.command, network_manager, static_config);
+                match result.as_ref() {
+                    Ok(output) => {
+                        info!(target: "fleet", "Executed {:?} of {} from {}: {}", packet.command, admin, src_addr, output)
+                    }
+                    Err(e) => {
+                        warn!(target: "fleet", "Failed {:?} of {} from {}: {}", packet.command, admin, src_addr, e)
+                    }
+                }
+                (result, events)
+            }
+            Err(Rejection::Dropped(reason)) => {
+                debug!(target: "fleet", "Dropped {:?} of {} from {}: {}", packet.command, admin, src_addr, reason);
+                return (None, vec![]);
+            }
+            Err(Rejection::Refused(reason)) => {
+                warn!(target: "fleet", "Rejected {:?} of {} from {}: {}", packet.command, admin, src_addr, reason);
+                (Err(format!("rejected: {}", reason)), vec![])
+            }
+        };
+        let reply = FleetResultPacket {
+            sender: static_config.wg_ip,
+            nonce: packet.nonce,
+            result,
+        };
+        (Some(reply), events)
+    }
+}
+
+pub fn execute(
+    now: u64,
+    command: &FleetCommand,
+    network_manager: &mut NetworkManager,
+    static_config: &mut StaticConfiguration,
+) -> (FleetResult, Vec<Event>) {
+    match command {
+        FleetCommand::ReloadConfig => reload_config(now, network_manager, static_config),
+        FleetCommand::SetLogLevel { level } => {
+            let result = level
+                .parse::<LevelFilter>()
+                .map(|level| {
+                    log::set_max_level(level);
+                    format!("log level {}", level)
+                })
+                .map_err(|_| format!("unknown log level {}", level));
+            (result, vec![])
+        }
+        FleetCommand::Status => {
+            let status = json!({
+                "name": static_config.name,
+                "wg_ip": static_config.wg_ip,
+                "version": env!("CARGO_PKG_VERSION"),
+                "nodes": network_manager.all_nodes.len(),
+                "routes": network_manager.routes().len(),
+                "routedb_version": network_manager.db_version(),
+                "gateway_ready": network_manager.gateway_ready(),
+            });
+            (Ok(status.to_string()), vec![])
+        }
+    }
+}
+
+#[cfg(unix)]
+fn reload_config(
+    now: u64,
+    network_manager: &mut NetworkManager,
+    static_config: &mut StaticConfiguration,
+) -> (FleetResult, Vec<Event>) {
+    let request = crate::control::ControlRequest {
+        method: "reload-config".to_string(),
+        params: serde_json::Value::Null,
+        id: serde_json::Value::Null,
+    };
+    let (response, events) = crate::control::execute(now, &request, network_manager, static_config);
+    let result = match response.error {
+        Some(e) => Err(e.message),
+        None => Ok(response.result.unwrap_or_default().to_string()),
+    };
+    (result, events)
+}
+#[cfg(not(unix))]
+fn reload_config(
+    _now: u64,
+    _network_manager: &mut NetworkManager,
+    _static_config: &mut StaticConfiguration,
+) -> (FleetResult, Vec<Event>) {
+    (Err("not supported on this platform".to_string()), vec![])
+}
+
+// Sends the command to the targets, or to all nodes answering the audit. The admin
+// ports are learned from the audit, too. Returns the reply per target, None if there
+// has been no answer.
+pub fn send(
+    static_config: &StaticConfiguration,
+    command: FleetCommand,
+    targets: &[IpAddr],
+) -> BoxResult<Vec<(IpAddr, Option<FleetResult>)>> {
+    let reports = audit::collect(static_config)?;
+    if reports.is_empty() {
+        return Err("No answer from the local node. Is wg_netmanager running ?".into());
+    }
+    let mut admin_port_of = HashMap::new();
+    admin_port_of.insert(static_config.wg_ip, static_config.my_admin_port());
+    for audit in reports.values() {
+        for ri in audit.known_routes.iter() {
+            admin_port_of.entry(ri.to).or_insert(ri.local_admin_port);
+        }
+    }
+    let mut targets = if targets.is_empty() {
+        reports.keys().copied().collect::<Vec<_>>()
+    } else {
+        targets.to_vec()
+    };
+    targets.sort();
+
+    let unspecified: IpAddr = match static_config.wg_ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let mut socket = CryptUdp::bind(unspecified, 0)?
+        .key(&static_config.shared_key)?
+        .accepted_keys(&static_config.accepted_shared_keys)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+
+    let admin = base64::encode(static_config.identity.public());
+    let mut pending = HashMap::new();
+    let mut results = HashMap::new();
+    for wg_ip in targets.iter() {
+        let Some(admin_port) = admin_port_of.get(wg_ip) else {
+            warn!(target: "fleet", "No route to {}", wg_ip);
+            results.insert(*wg_ip, None);
+            continue;
+        };
+        let issued_at = crate::util::wall_now();
+        let packet =
+            FleetCommandPacket::new(*wg_ip, issued_at, command.clone(), &static_config.identity);
+        info!(target: "fleet", "Send {:?} of {} to {}", command, admin, wg_ip);
+        let buf = bincode::serialize(&UdpPacket::FleetCommand(packet.clone())).unwrap();
+        socket.send_to(&buf, SocketAddr::new(*wg_ip, *admin_port))?;
+        pending.insert(packet.nonce, *wg_ip);
+    }
+
+    // Collect answers until no more packets arrive within the timeout
+    let mut buf = [0; MAX_PACKET_SIZE];
+    while !pending.is_empty() {
+        let Ok((received, src_addr)) = socket.recv_from(&mut buf) else {
+            break;
+        };
+        match UdpPacket::decode(&buf[..received]) {
+            Ok(UdpPacket::FleetResult(reply)) => match pending.remove(&reply.nonce) {
+                Some(wg_ip) => {
+                    info!(target: "fleet", "Result of {:?} from {}: {:?}", command, wg_ip, reply.result);
+                    results.insert(wg_ip, Some(reply.result));
+                }
+                None => debug!(target: "fleet", "Unexpected result from {}", src_addr),
+            },
+            Ok(_) => {}
+            Err(e) => {
+                error!(target: "fleet", "Error in decode: {:?}", e);
+            }
+        }
+    }
+    for wg_ip in pending.into_values() {
+        warn!(target: "fleet", "No result of {:?} from {}", command, wg_ip);
+    }
+
+    Ok(targets
+        .into_iter()
+        .map(|wg_ip| (wg_ip, results.remove(&wg_ip).flatten()))
+        .collect())
+}
+
+pub fn run(
+    static_config: &StaticConfiguration,
+    command: FleetCommand,
+    targets: &[IpAddr],
+) -> BoxResult<()> {
+    let results = send(static_config, command, targets)?;
+    let mut failed = 0;
+    for (wg_ip, result) in results.iter() {
+        match result {
+            Some(Ok(output)) => println!("  {:<15} ok: {}", wg_ip, output),
+            Some(Err(e)) => {
+                failed += 1;
+                println!("  {:<15} failed: {}", wg_ip, e);
+            }
+            None => {
+                failed += 1;
+                println!("  {:<15} no answer", wg_ip);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!(
+            "{} of {} nodes have not executed the command",
+            failed,
+            results.len()
+        )
+        .into());
+    }
+    Ok(())
+}
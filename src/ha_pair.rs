@@ -0,0 +1,138 @@
+// Warm-spare pair of two nodes acting as one static hub.
+//
+// Both nodes share the wg_ip, the identity file and the published endpoint, which is a
+// floating ip moved e.g. by VRRP. On start, a node is standby. The nodes exchange a
+// HaHeartbeat every second via the admin port of the partner's own address. Only
+// the active node advertises and answers admin packets, the standby node is silent.
+//
+//      standby => active: the partner has not been heard for HA_TIMEOUT seconds,
+//                         or both are standby and this node ranks higher
+//      active => standby: both are active and the partner ranks higher
+//
+// The rank is the configured priority and a random tie breaker. An active node is not
+// preempted by a higher priority partner, so a flapping partner does not move the hub
+// back and forth. On becoming active, the static peers are advertised to right away.
+// They accept the new wireguard key, because the advertisement is signed by the same
+// identity. The optional notify command is called with `active` resp. `standby`, e.g.
+// to move the floating ip.
+//
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::crypt_udp::{HaHeartbeatPacket, UdpPacket};
+
+pub const HA_TIMEOUT: u64 = 3;
+pub const DEFAULT_HA_PRIORITY: u8 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HaPair {
+    // The partner's own admin endpoint, not the floating ip
+    pub partner: SocketAddr,
+    pub priority: u8,
+    pub notify: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum HaState {
+    Standby,
+    Active,
+}
+
+pub struct HaCoordinator {
+    pair: HaPair,
+    state: HaState,
+    started: u64,
+    tie_breaker: u64,
+    // The last heartbeat of the partner and when it has been received
+    partner: Option<(HaHeartbeatPacket, u64)>,
+}
+impl HaCoordinator {
+    pub fn new(now: u64, pair: HaPair) -> Self {
+        HaCoordinator {
+            pair,
+            state: HaState::Standby,
+            started: now,
+            tie_breaker: rand::random(),
+            partner: None,
+        }
+    }
+    pub fn state(&self) -> HaState {
+        self.state
+    }
+    pub fn is_active(&self) -> bool {
+        self.state == HaState::Active
+    }
+    pub fn partner(&self) -> SocketAddr {
+        self.pair.partner
+    }
+    pub fn heartbeat(&self) -> UdpPacket {
+        UdpPacket::HaHeartbeat(HaHeartbeatPacket {
+            priority: self.pair.priority,
+            tie_breaker: self.tie_breaker,
+            active: self.is_active(),
+        })
+    }
+    // Heartbeats from other addresses are ignored
+    pub fn process_heartbeat(
+        &mut self,
+        now: u64,
+        heartbeat: HaHeartbeatPacket,
+        src_addr: SocketAddr,
+    ) -> Option<HaState> {
+        if src_addr.ip() != self.pair.partner.ip() {
+            warn!(target: "ha", "Heartbeat from {} is not from the partner => ignored", src_addr);
+            return None;
+        }
+        self.partner = Some((heartbeat, now));
+        self.decide(now)
+    }
+    // Called every second
+    pub fn process_timer(&mut self, now: u64) -> Option<HaState> {
+        self.decide(now)
+    }
+    // Returns the new state on a change
+    fn decide(&mut self, now: u64) -> Option<HaState> {
+        let my_rank = (self.pair.priority, self.tie_breaker);
+        let partner = self
+            .partner
+            .as_ref()
+            .filter(|(_, received)| now < received + HA_TIMEOUT)
+            .map(|(heartbeat, _)| heartbeat);
+        let next = match (self.state, partner) {
+            (HaState::Standby, None) if now >= self.started + HA_TIMEOUT => HaState::Active,
+            (HaState::Standby, Some(p)) if !p.active && my_rank > (p.priority, p.tie_breaker) => {
+                HaState::Active
+            }
+            (HaState::Active, Some(p)) if p.active && my_rank < (p.priority, p.tie_breaker) => {
+                HaState::Standby
+            }
+            (state, _) => state,
+        };
+        if next == self.state {
+            return None;
+        }
+        info!(target: "ha", "Change from {:?} to {:?}", self.state, next);
+        self.state = next;
+        self.notify();
+        Some(next)
+    }
+    fn notify(&self) {
+        let Some(command) = self.pair.notify.as_ref() else {
+            return;
+        };
+        let arg = match self.state {
+            HaState::Active => "active",
+            HaState::Standby => "standby",
+        };
+        match Command::new(command).arg(arg).stdin(Stdio::null()).spawn() {
+            // Not waited for in the main loop
+            Ok(mut child) => {
+                std::thread::spawn(move || child.wait());
+            }
+            Err(e) => error!(target: "ha", "Cannot run {}: {}", command, e),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// Minimal HTTP/1.1 of the metrics listener and the status page.
+//
+// Only GET without body is served and each connection is closed after the response.
+// With a token, a request needs the header `Authorization: Bearer <token>` and is
+// otherwise answered with 401.
+//
+// Every connection is served by an own thread, so a client, which stalls until the
+// read timeout, does not block the others. Beyond MAX_CONNECTIONS, new connections
+// are closed right away.
+//
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::*;
+
+use crate::error::*;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_CONNECTIONS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub bearer: Option<String>,
+}
+impl HttpRequest {
+    pub fn parse<R: BufRead>(reader: &mut R) -> BoxResult<Self> {
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or("").to_string();
+        let path = parts.next().unwrap_or("").to_string();
+
+        let mut bearer = None;
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 && !line.trim().is_empty() {
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("authorization") {
+                    let value = value.trim();
+                    if let Some((scheme, credentials)) = value.split_once(' ') {
+                        if scheme.eq_ignore_ascii_case("bearer") {
+                            bearer = Some(credentials.trim().to_string());
+                        }
+                    }
+                }
+            }
+            line.clear();
+        }
+        Ok(HttpRequest {
+            method,
+            path,
+            bearer,
+        })
+    }
+    // Without token, any request is authorized
+    pub fn is_authorized(&self, token: Option<&str>) -> bool {
+        match (token, self.bearer.as_deref()) {
+            (None, _) => true,
+            (Some(token), Some(bearer)) => constant_time_eq(token.as_bytes(), bearer.as_bytes()),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+// The time does not tell, how many leading bytes of the token are correct
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Read the request, answer it with the handler's status and body and close
+pub fn serve<F>(
+    stream: TcpStream,
+    content_type: &str,
+    token: Option<&str>,
+    handler: F,
+) -> BoxResult<()>
+where
+    F: FnOnce(&HttpRequest) -> (&'static str, String),
+{
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let request = HttpRequest::parse(&mut BufReader::new(stream))?;
+    if !request.is_authorized(token) {
+        write!(
+            writer,
+            "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )?;
+        return Ok(());
+    }
+    let (status, body) = handler(&request);
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+// Accept connections in a background thread and serve each in an own thread
+pub fn listen<F>(
+    listener: TcpListener,
+    target: &'static str,
+    content_type: &'static str,
+    token: Option<String>,
+    handler: F,
+) where
+    F: Fn(&HttpRequest) -> (&'static str, String) + Send + Sync + 'static,
+{
+    let token: Arc<Option<String>> = Arc::new(token);
+    let handler = Arc::new(handler);
+    let connections = Arc::new(AtomicUsize::new(0));
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!(target: target, "{}", e);
+                    continue;
+                }
+            };
+            if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                debug!(target: target, "Too many connections, close {:?}", stream.peer_addr());
+                continue;
+            }
+            let token = token.clone();
+            let handler = handler.clone();
+            let connections = connections.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = serve(stream, content_type, token.as_deref(), &*handler) {
+                    debug!(target: target, "{}", e);
+                }
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+}
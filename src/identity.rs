@@ -0,0 +1,83 @@
+// The ed25519 identity of a node, which signs its advertisements.
+//
+// Any holder of the shared key can send admin packets. So without a signature,
+// everyone in the network could claim any wg_ip. A static peer can be pinned to its
+// identity in network.yaml. For all other nodes, the first identity seen for a wg_ip
+// is bound to it, as long as the node stays reachable.
+//
+// Without an identity file, a new identity is created on every start. Then a
+// restarted node is only accepted again after the peers have considered it dead.
+//
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::*;
+
+use crate::error::*;
+use crate::persist::write_private;
+
+pub type PublicIdentity = [u8; 32];
+
+#[derive(Clone)]
+pub struct NodeIdentity {
+    signing_key: SigningKey,
+}
+impl NodeIdentity {
+    pub fn generate() -> Self {
+        Self::from_seed(&rand::random())
+    }
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        NodeIdentity {
+            signing_key: SigningKey::from_bytes(seed),
+        }
+    }
+    pub fn seed(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+    // Use an identity file. If not existing, a new identity is created.
+    pub fn from_file<P: AsRef<Path>>(fname: P) -> BoxResult<Self> {
+        let fname = fname.as_ref();
+        if let Ok(content) = fs::read_to_string(fname) {
+            return Ok(Self::from_seed(&decode_identity(content.trim())?));
+        }
+        let identity = Self::generate();
+        write_private(fname, base64::encode(identity.seed()).as_bytes())?;
+        info!("Created new identity file {}", fname.display());
+        Ok(identity)
+    }
+    pub fn public(&self) -> PublicIdentity {
+        self.signing_key.verifying_key().to_bytes()
+    }
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(data).to_bytes().to_vec()
+    }
+}
+impl fmt::Debug for NodeIdentity {
+    // Never print the secret
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeIdentity({})", base64::encode(self.public()))
+    }
+}
+
+pub fn verify(identity: &PublicIdentity, data: &[u8], signature: &[u8]) -> bool {
+    let signature = match Signature::from_slice(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    VerifyingKey::from_bytes(identity)
+        .map(|key| key.verify(data, &signature).is_ok())
+        .unwrap_or(false)
+}
+
+// A base64 encoded identity resp. seed
+pub fn decode_identity(key: &str) -> BoxResult<[u8; 32]> {
+    let raw = base64::decode(key)?;
+    if raw.len() != 32 {
+        return strerror("Invalid length of identity");
+    }
+    let mut buf = [0u8; 32];
+    buf.copy_from_slice(&raw);
+    Ok(buf)
+}
@@ -0,0 +1,111 @@
+// Scheduled rotation of the own wireguard key pair.
+//
+// After key_lifetime a new key pair is created, but not yet used. For KEY_ROTATION_OVERLAP
+// seconds it is announced as next_public_key in advertisements and local contacts next to
+// the current key. The creation time of the new public key is the end of this window.
+// Then this node and all peers, which have seen the announcement, switch to the new key.
+// So the peers are kept together with their routes and the tunnels are only interrupted
+// by the clock difference of the nodes.
+//
+use crate::configuration::{PendingKeyPair, PublicKeyWithTime, StaticConfiguration};
+use crate::error::*;
+
+// Longer than the ping interval of the peers, so every peer sees the announcement
+pub const KEY_ROTATION_OVERLAP: u64 = 120;
+pub const MIN_KEY_LIFETIME: u64 = 600;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyRotationStep {
+    // A new key pair is announced
+    Announced,
+    // The announced key pair is in use now
+    Switched,
+}
+
+// Called every second. create_key_pair returns the private and the public key.
+pub fn rotate_keys<F>(
+    now: u64,
+    static_config: &mut StaticConfiguration,
+    create_key_pair: F,
+) -> BoxResult<Option<KeyRotationStep>>
+where
+    F: FnOnce() -> BoxResult<(String, String)>,
+{
+    // An announced key pair is switched to even without key_lifetime, see rekey()
+    if let Some(pending) = static_config.pending_key_pair.take() {
+        if now < pending.public_key.priv_key_creation_time {
+            static_config.pending_key_pair = Some(pending);
+            return Ok(None);
+        }
+        static_config.my_private_key = pending.private_key;
+        static_config.my_public_key = pending.public_key;
+        return Ok(Some(KeyRotationStep::Switched));
+    }
+    let lifetime = match static_config.key_lifetime {
+        Some(lifetime) => lifetime,
+        None => return Ok(None),
+    };
+    let created = static_config.my_public_key.priv_key_creation_time;
+    if now + KEY_ROTATION_OVERLAP < created + lifetime {
+        return Ok(None);
+    }
+    announce_key_pair(now, static_config, create_key_pair)?;
+    Ok(Some(KeyRotationStep::Announced))
+}
+
+// Announce a new key pair now regardless of key_lifetime, e.g. after a peer has been
+// compromised. A pending announcement is replaced, because the peer may have seen it.
+pub fn rekey<F>(
+    now: u64,
+    static_config: &mut StaticConfiguration,
+    create_key_pair: F,
+) -> BoxResult<()>
+where
+    F: FnOnce() -> BoxResult<(String, String)>,
+{
+    announce_key_pair(now, static_config, create_key_pair)
+}
+
+fn announce_key_pair<F>(
+    now: u64,
+    static_config: &mut StaticConfiguration,
+    create_key_pair: F,
+) -> BoxResult<()>
+where
+    F: FnOnce() -> BoxResult<(String, String)>,
+{
+    let (private_key, key) = create_key_pair()?;
+    static_config.pending_key_pair = Some(PendingKeyPair {
+        private_key,
+        public_key: PublicKeyWithTime {
+            key,
+            priv_key_creation_time: now + KEY_ROTATION_OVERLAP,
+        },
+    });
+    Ok(())
+}
+
+// The announcement of a peer is only accepted, if it really replaces the current key later
+pub fn announced_key(
+    public_key: &PublicKeyWithTime,
+    next_public_key: Option<&PublicKeyWithTime>,
+) -> Option<PublicKeyWithTime> {
+    next_public_key
+        .filter(|next| {
+            next.key != public_key.key
+                && next.priv_key_creation_time > public_key.priv_key_creation_time
+        })
+        .cloned()
+}
+
+// Returns the announced key of a peer, if it is due
+pub fn due_key(
+    now: u64,
+    next_public_key: &mut Option<PublicKeyWithTime>,
+) -> Option<PublicKeyWithTime> {
+    if next_public_key.as_ref()?.priv_key_creation_time <= now {
+        next_public_key.take()
+    } else {
+        None
+    }
+}
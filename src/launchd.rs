@@ -0,0 +1,88 @@
+// Installation as launchd daemon on macos.
+//
+// `install` writes a plist to /Library/LaunchDaemons, which starts wg_netmanager at
+// boot and restarts it, if it exits. launchd only loads a plist owned by root and
+// not writable by others, so the file is written with mode 0644 and root:wheel.
+// wg and wireguard-go are usually installed via brew, which is not in the PATH of
+// launchd, so the PATH is set in the plist.
+//
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use crate::configuration::StaticConfiguration;
+use crate::error::*;
+use crate::util::{absolute_path, install_file};
+
+pub const LABEL: &str = "com.github.gin66.wg_netmanager";
+pub const PLIST_FILE: &str = "/Library/LaunchDaemons/com.github.gin66.wg_netmanager.plist";
+pub const LOG_FILE: &str = "/var/log/wg_netmanager.log";
+const PATH: &str = "/opt/homebrew/bin:/usr/local/bin:/usr/bin:/bin:/usr/sbin:/sbin";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn plist(static_config: &StaticConfiguration, exe: &str) -> String {
+    let mut arguments = vec![
+        exe.to_string(),
+        "-c".to_string(),
+        absolute_path(&static_config.network_yaml_filename),
+    ];
+    if let Some(fname) = static_config.peer_yaml_filename.as_ref() {
+        arguments.push("-p".to_string());
+        arguments.push(absolute_path(fname));
+    }
+
+    let mut lines: Vec<String> = vec![];
+    lines.push(r#"<?xml version="1.0" encoding="UTF-8"?>"#.to_string());
+    lines.push(r#"<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">"#.to_string());
+    lines.push(r#"<plist version="1.0">"#.to_string());
+    lines.push("<dict>".to_string());
+    lines.push("\t<key>Label</key>".to_string());
+    lines.push(format!("\t<string>{}</string>", LABEL));
+    lines.push("\t<key>ProgramArguments</key>".to_string());
+    lines.push("\t<array>".to_string());
+    for argument in arguments {
+        lines.push(format!("\t\t<string>{}</string>", escape(&argument)));
+    }
+    lines.push("\t</array>".to_string());
+    lines.push("\t<key>EnvironmentVariables</key>".to_string());
+    lines.push("\t<dict>".to_string());
+    lines.push("\t\t<key>PATH</key>".to_string());
+    lines.push(format!("\t\t<string>{}</string>", PATH));
+    lines.push("\t</dict>".to_string());
+    lines.push("\t<key>RunAtLoad</key>".to_string());
+    lines.push("\t<true/>".to_string());
+    lines.push("\t<key>KeepAlive</key>".to_string());
+    lines.push("\t<true/>".to_string());
+    lines.push("\t<key>StandardOutPath</key>".to_string());
+    lines.push(format!("\t<string>{}</string>", LOG_FILE));
+    lines.push("\t<key>StandardErrorPath</key>".to_string());
+    lines.push(format!("\t<string>{}</string>", LOG_FILE));
+    lines.push("</dict>".to_string());
+    lines.push("</plist>".to_string());
+    lines.push("".to_string());
+    lines.join("\n")
+}
+
+pub fn install_plist(path: &Path, content: &str, force: bool, dry_run: bool) -> BoxResult<bool> {
+    if !install_file(path, content, force, dry_run)? {
+        return Ok(false);
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(0o644))?;
+    // gid 0 is wheel
+    if fs::metadata(path)?.uid() != 0 {
+        std::os::unix::fs::chown(path, Some(0), Some(0)).map_err(|e| {
+            format!(
+                "Cannot change the owner of {} to root: {}",
+                path.display(),
+                e
+            )
+        })?;
+    }
+    Ok(true)
+}
@@ -1,28 +1,170 @@
+// The stable API of the crate is re-exported below: loading the configuration, the
+// lifecycle of the daemon, and the types of its events and snapshots. It follows
+// semver and is checked by tests/43_public_api.rs. All modules are internals of the
+// daemon and the command line tool, hence hidden, and may change with any release.
+//
+// The structs and enums of the facade are non_exhaustive and StaticConfiguration is
+// only created by its builder, so a new field or variant is not a breaking change.
+// Same for a new method of WireguardDevice, as long as it has a default body.
+//
+#[doc(inline)]
+pub use config_file::PeerConfig;
+#[doc(inline)]
+pub use configuration::{
+    load_network_config, load_peer_config, Backend, NetworkConfiguration, PublicKeyWithTime,
+    PublicPeer, StaticConfiguration, StaticConfigurationBuilder,
+};
+#[doc(inline)]
+pub use daemon::Daemon;
+#[doc(inline)]
+pub use error::BoxResult;
+#[doc(inline)]
+pub use mesh_event::MeshEvent;
+#[doc(inline)]
+pub use state_snapshot::{NodeState, RouteDBState, StateSnapshot};
+#[doc(inline)]
+pub use status_file::{Health, Status};
+#[doc(inline)]
+pub use wg_dev::{RouteAttributes, TeardownStep, WireguardDevice};
+
+#[doc(hidden)]
+pub mod address_lease;
+#[doc(hidden)]
+pub mod alert;
+#[doc(hidden)]
+pub mod audit;
+#[doc(hidden)]
+pub mod authorized_keys;
+#[cfg(feature = "chaos")]
+#[doc(hidden)]
+pub mod chaos;
+#[doc(hidden)]
+pub mod config_file;
+#[doc(hidden)]
 pub mod configuration;
+#[cfg(unix)]
+#[doc(hidden)]
+pub mod control;
+#[doc(hidden)]
 pub mod crypt_udp;
+#[doc(hidden)]
+pub mod crypto;
+mod daemon;
+#[doc(hidden)]
+pub mod decrypt_monitor;
+#[doc(hidden)]
+pub mod endpoint_resolver;
+#[doc(hidden)]
 pub mod error;
+#[doc(hidden)]
 pub mod event;
+#[doc(hidden)]
+pub mod fleet;
+#[doc(hidden)]
+pub mod ha_pair;
+#[doc(hidden)]
+pub mod http_server;
+#[doc(hidden)]
+pub mod identity;
+#[doc(hidden)]
+pub mod key_rotation;
+#[cfg(unix)]
+#[doc(hidden)]
+pub mod launchd;
+#[doc(hidden)]
+pub mod link_local;
+#[doc(hidden)]
+pub mod log_sampling;
+#[doc(hidden)]
 pub mod manager;
+#[cfg(feature = "mdns")]
+#[doc(hidden)]
+pub mod mdns;
+#[doc(hidden)]
+pub mod memory_udp;
+#[doc(hidden)]
+pub mod mesh_event;
+#[doc(hidden)]
+pub mod metrics;
+#[doc(hidden)]
+pub mod name_server;
+#[doc(hidden)]
+pub mod nat_telemetry;
+#[doc(hidden)]
+pub mod network_file;
+#[doc(hidden)]
 pub mod node;
+#[doc(hidden)]
+pub mod peer_db;
+#[doc(hidden)]
+pub mod peer_names;
+#[doc(hidden)]
+pub mod persist;
+#[cfg(feature = "scripting")]
+#[doc(hidden)]
+pub mod policy_script;
+#[doc(hidden)]
+pub mod port_validation;
+#[doc(hidden)]
+pub mod readdress;
+#[cfg(all(target_os = "linux", feature = "resolved"))]
+#[doc(hidden)]
+pub mod resolved;
+#[doc(hidden)]
+pub mod route_engine;
+#[doc(hidden)]
 pub mod routedb;
+#[doc(hidden)]
 pub mod run_loop;
+#[doc(hidden)]
+pub mod state_file;
+#[doc(hidden)]
+pub mod state_snapshot;
+#[doc(hidden)]
+pub mod status_file;
+#[doc(hidden)]
+pub mod status_page;
+#[cfg(target_os = "linux")]
+#[doc(hidden)]
+pub mod systemd;
+#[doc(hidden)]
+pub mod testing;
+#[doc(hidden)]
 pub mod tui_display;
+#[cfg(target_os = "linux")]
+#[doc(hidden)]
+pub mod upgrade;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[doc(hidden)]
+pub mod userspace_process;
+#[doc(hidden)]
 pub mod util;
+#[cfg(feature = "webhook")]
+#[doc(hidden)]
+pub mod webhook;
+#[doc(hidden)]
 pub mod wg_dev;
+#[cfg(any(target_os = "android", all(target_os = "linux", feature = "userspace")))]
+#[doc(hidden)]
+pub mod wg_userspace;
 
+#[doc(hidden)]
 pub mod arch_def;
-pub use arch_def::Architecture;
+#[doc(inline)]
+pub use arch_def::{register_backend, unregister_backend, Architecture, BackendFactory};
 
 #[cfg(target_os = "linux")]
-pub mod arch_linux;
+mod arch_linux;
 
 #[cfg(target_os = "macos")]
-pub mod arch_macos;
+mod arch_macos;
 
 #[cfg(target_os = "windows")]
+#[doc(hidden)]
 pub mod arch_windows;
 
 #[cfg(target_os = "android")]
+#[doc(hidden)]
 pub mod arch_android;
 
 #[cfg(target_os = "linux")]
@@ -0,0 +1,91 @@
+// Admin transport via ipv6 link-local addresses.
+//
+// Two nodes connected only by a cable have no other addresses than fe80::/10. Such an
+// address is only unique together with the interface, i.e. the scope id. The scope id
+// of a received packet is kept for the reply and for the wireguard endpoint. In an
+// endpoint like [fe80::1%eth0]:50001 the interface name is resolved to its index. The
+// interface of the link-local addresses in the local ip list of a peer is not known,
+// so these are probed on every interface with a link-local address. The endpoint
+// [ff02::1%eth0]:50001 reaches all nodes on the link, so a static peer is found
+// without knowing its address.
+//
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+
+use crate::error::*;
+
+// Unicast fe80::/10 or link-local multicast ff02::/16
+pub fn needs_scope(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(_) => false,
+        IpAddr::V6(ipv6) => {
+            let first = ipv6.segments()[0];
+            (first & 0xffc0) == 0xfe80 || (first & 0xff0f) == 0xff02
+        }
+    }
+}
+
+// The scope id is only applied to addresses, which need one
+pub fn scoped(ip: IpAddr, port: u16, scope_id: u32) -> SocketAddr {
+    match ip {
+        IpAddr::V6(ipv6) if needs_scope(&ip) => SocketAddrV6::new(ipv6, port, 0, scope_id).into(),
+        _ => SocketAddr::new(ip, port),
+    }
+}
+
+// Unlike SocketAddr::new(sa.ip(), port), the scope id is kept
+pub fn with_port(mut sa: SocketAddr, port: u16) -> SocketAddr {
+    sa.set_port(port);
+    sa
+}
+
+pub fn scope_id(sa: &SocketAddr) -> u32 {
+    match sa {
+        SocketAddr::V4(_) => 0,
+        SocketAddr::V6(sa) => sa.scope_id(),
+    }
+}
+
+// A link-local address without scope id cannot be sent to
+pub fn check_scope(sa: &SocketAddr) -> BoxResult<()> {
+    if needs_scope(&sa.ip()) && scope_id(sa) == 0 {
+        return Err(format!("{} is link-local and needs an interface like %eth0", sa).into());
+    }
+    Ok(())
+}
+
+// An interface name or index
+pub fn interface_index(interface: &str) -> BoxResult<u32> {
+    if let Ok(index) = interface.parse::<u32>() {
+        return Ok(index);
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let name = std::ffi::CString::new(interface)?;
+        // Safety: name is a valid C string for the duration of the call
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index != 0 {
+            return Ok(index);
+        }
+    }
+    Err(format!("Unknown interface {}", interface).into())
+}
+
+// Splits [fe80::1%eth0]:50001 resp. fe80::1%eth0 into the address without the
+// interface and the scope id
+pub fn split_scope(endpoint: &str) -> BoxResult<(String, Option<u32>)> {
+    let (before, rest) = match endpoint.split_once('%') {
+        Some(parts) => parts,
+        None => return Ok((endpoint.to_string(), None)),
+    };
+    let (interface, after) = match rest.find(']') {
+        Some(pos) => rest.split_at(pos),
+        None => (rest, ""),
+    };
+    if interface.is_empty() || interface.contains('%') || after.contains('%') {
+        return Err(format!("invalid address: {}", endpoint).into());
+    }
+    Ok((
+        format!("{}{}", before, after),
+        Some(interface_index(interface)?),
+    ))
+}
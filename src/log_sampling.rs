@@ -0,0 +1,59 @@
+// Sampled logging of the hot paths, e.g. each received admin packet.
+//
+// On a hub with many nodes, one log line per packet or advertisement keeps slow flash
+// storage busy. The logs of the hot paths go through `sampled!`, which logs only the
+// first and then every Nth occurrence of each call site together with the count:
+//
+//      received 412 bytes from 192.168.1.70:50500 [#201]
+//
+// N is set with `--log-sample <N>` resp. `logSample` in peer.yaml and is changed at
+// runtime via the control method log-sampling. N = 1 logs every occurrence.
+//
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const DEFAULT_LOG_SAMPLE: u64 = 100;
+
+static SAMPLE_EVERY: AtomicU64 = AtomicU64::new(DEFAULT_LOG_SAMPLE);
+
+// 0 is treated as 1
+pub fn set_every(every: u64) {
+    SAMPLE_EVERY.store(every.max(1), Ordering::Relaxed);
+}
+pub fn every() -> u64 {
+    SAMPLE_EVERY.load(Ordering::Relaxed)
+}
+
+// One per call site
+pub struct LogSampler {
+    count: AtomicU64,
+}
+impl LogSampler {
+    pub const fn new() -> Self {
+        LogSampler {
+            count: AtomicU64::new(0),
+        }
+    }
+    // The count of occurrences, if this one is to be logged
+    pub fn sample(&self) -> Option<u64> {
+        let count = self.count.fetch_add(1, Ordering::Relaxed);
+        count.is_multiple_of(every()).then_some(count + 1)
+    }
+}
+impl Default for LogSampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Like log!, but sampled. Nothing is counted while the level is disabled.
+macro_rules! sampled {
+    ($lvl:expr, target: $target:expr, $($arg:tt)+) => {{
+        static SAMPLER: $crate::log_sampling::LogSampler = $crate::log_sampling::LogSampler::new();
+        if log::log_enabled!(target: $target, $lvl) {
+            if let Some(count) = SAMPLER.sample() {
+                log::log!(target: $target, $lvl, "{} [#{}]", format_args!($($arg)+), count);
+            }
+        }
+    }};
+}
+pub(crate) use sampled;
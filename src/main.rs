@@ -1,46 +1,64 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Read;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::str::FromStr;
 
 use clap::{App, Arg, ArgMatches};
+use ipnet::IpNet;
 use log::*;
-use yaml_rust::{Yaml, YamlLoader};
 
+use wg_netmanager::address_lease::request_lease;
 use wg_netmanager::configuration::*;
+use wg_netmanager::crypt_udp::FleetCommand;
 use wg_netmanager::error::*;
+use wg_netmanager::ha_pair::{HaPair, DEFAULT_HA_PRIORITY};
+use wg_netmanager::identity::{decode_identity, NodeIdentity};
+use wg_netmanager::key_rotation::MIN_KEY_LIFETIME;
+use wg_netmanager::peer_db::PeerDatabase;
+use wg_netmanager::state_snapshot::{diff_report, StateSnapshot};
 use wg_netmanager::*;
 
-fn get_option_bool(matches: &ArgMatches, config: &Option<Yaml>, option_name: &'static str) -> bool {
-    if matches.is_present(option_name) {
-        return true;
-    }
-
-    if let Some(conf) = config.as_ref() {
-        if let Some(val) = conf[option_name].as_bool() {
-            return val;
-        }
-    }
-
-    false
+// A command line option takes precedence over peer.yaml
+fn get_option_bool(matches: &ArgMatches, option_name: &'static str, conf: bool) -> bool {
+    matches.is_present(option_name) || conf
 }
 fn get_option_string(
     matches: &ArgMatches,
-    config: &Option<Yaml>,
     option_name: &'static str,
-) -> BoxResult<String> {
-    if let Some(val) = matches.value_of(option_name) {
-        return Ok(val.to_string());
-    } else if let Some(conf) = config.as_ref() {
-        if let Some(val) = conf[option_name].as_str() {
-            return Ok(val.to_string());
-        }
+    conf: &Option<String>,
+) -> Option<String> {
+    matches
+        .value_of(option_name)
+        .map(|val| val.to_string())
+        .or_else(|| conf.clone())
+}
+fn get_option<T: FromStr>(
+    matches: &ArgMatches,
+    option_name: &'static str,
+    conf: Option<T>,
+) -> BoxResult<Option<T>>
+where
+    T::Err: std::error::Error + 'static,
+{
+    match matches.value_of(option_name) {
+        Some(val) => Ok(Some(val.parse()?)),
+        None => Ok(conf),
     }
-    Err(format!("Configuration option <{}> is not defined", option_name).into())
+}
+fn required<T>(value: Option<T>, option_name: &'static str) -> BoxResult<T> {
+    value.ok_or_else(|| format!("Configuration option <{}> is not defined", option_name).into())
 }
 
 fn main() -> BoxResult<()> {
-    let matches = App::new("Wireguard Network Manager")
+    // Started by the windows service control manager
+    #[cfg(target_os = "windows")]
+    if std::env::args().any(|arg| arg == "--windows-service") {
+        return wg_netmanager::arch_windows::service::start(run);
+    }
+    run()
+}
+
+fn run() -> BoxResult<()> {
+    let app = App::new("Wireguard Network Manager")
         .version(env!("CARGO_PKG_VERSION"))
         .author("Jochen Kiemes <jochen@kiemes.de>")
         .about("Manages a network of wireguard nodes with no central server.")
@@ -68,12 +86,35 @@ fn main() -> BoxResult<()> {
                 .long("existing-wg")
                 .help("Use an existing wireguard interface and do not try to create one"),
         )
+        .arg(
+            Arg::with_name("adoptPeers")
+                .long("adopt-peers")
+                .help("Keep the peers of other tooling on the existing wireguard interface"),
+        )
+        .arg(
+            Arg::with_name("teardownSkip")
+                .long("teardown-skip")
+                .value_name("STEP")
+                .possible_values(&["routes", "peers", "addresses"])
+                .help("Leave this part of the existing wireguard interface on shutdown, else routes, peers and addresses are removed in this order (can be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .value_name("BACKEND")
+                .possible_values(&["auto", "kernel", "wg-quick", "userspace", "existing"])
+                .help("Implementation of the wireguard device: auto (default: kernel module, else wireguard-go or boringtun binary), kernel (module only), wg-quick (userspace binary only), userspace (boringtun in-process, linux only) or existing (same as --existing-wg)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("wireguard_port")
                 .short("w")
                 .long("wireguard-port")
                 .value_name("PORT")
-                .help("Wireguard udp port aka Listen port, if not defined in config file as part of endpoint")
+                .help("Wireguard udp port aka Listen port, if not derived from the network's port allocation")
                 .takes_value(true),
         )
         .arg(
@@ -113,11 +154,238 @@ fn main() -> BoxResult<()> {
                 .help("Sets the wireguard interface")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("logSample")
+                .long("log-sample")
+                .value_name("N")
+                .help("Log only every Nth occurrence of the logs of the hot paths, e.g. received packets. 1 logs every occurrence")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gatewayGrace")
+                .long("gateway-grace")
+                .value_name("SECONDS")
+                .help("Warm-up with a reachable direct peer, before the routes via this node are offered to others. 0 disables the warm-up")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("decryptFailureThreshold")
+                .long("decrypt-failure-threshold")
+                .value_name("COUNT")
+                .help("Undecryptable packets per minute from a static peer, which are reported as likely sharedKey mismatch. 0 disables the hint")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("interfaceAltname")
+                .long("interface-altname")
+                .help("Mark the wireguard interface with the network name as altname"),
+        )
+        .arg(
+            Arg::with_name("upgradeSocket")
+                .long("upgrade-socket")
+                .value_name("PATH")
+                .help("Unix socket for handing over to a new instance on binary upgrade (linux only, default /run/wg_netmanager/<interface>.upgrade)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("takeover")
+                .long("takeover")
+                .help("Take over sockets, interface and state from the instance running on the upgrade socket"),
+        )
+        .arg(
+            Arg::with_name("controlSocket")
+                .long("control-socket")
+                .value_name("PATH")
+                .help("Unix socket for the JSON-RPC control interface (unix only, default /run/wg_netmanager/<interface>.control)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("controlGroup")
+                .long("control-group")
+                .value_name("GROUP")
+                .help("Members of this group may use the read-only methods of the control socket")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("controlAdminGroup")
+                .long("control-admin-group")
+                .value_name("GROUP")
+                .help("Members of this group may use all methods of the control socket")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("disableIpv6")
+                .long("disable-ipv6")
+                .help("Do not use ipv6 for sockets, addresses and NAT traversal. Default, if ipv6 is not available"),
+        )
+        .arg(
+            Arg::with_name("routerHairpinning")
+                .long("router-hairpinning")
+                .help("The NAT router supports hairpinning, so the visible endpoints of nodes behind the same NAT are tried, too"),
+        )
+        .arg(
+            Arg::with_name("keyLifetime")
+                .long("key-lifetime")
+                .value_name("SECONDS")
+                .help("Replace the wireguard key pair after this time. Default is to keep it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("identityFile")
+                .long("identity-file")
+                .value_name("PATH")
+                .help("Keep the identity signing the advertisements in this file, created if missing. Default is a new identity on every start")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pinRoute")
+                .long("pin-route")
+                .value_name("DEST=GATEWAY")
+                .help("Always reach DEST via GATEWAY, if the gateway offers a route, regardless of the hop count (can be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exportNet")
+                .long("export-net")
+                .value_name("CIDR")
+                .help("Network behind this node, e.g. a LAN, to be routed by the other nodes via this node (can be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("extraWgPort")
+                .long("extra-wg-port")
+                .value_name("PORT")
+                .help("Further udp port forwarded to the wireguard port, e.g. via another uplink. Peers switch to it, if the tunnel breaks (can be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("statusFile")
+                .long("status-file")
+                .value_name("PATH")
+                .help("Write the live state as json to this file every few seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stateFile")
+                .long("state-file")
+                .value_name("PATH")
+                .help("Keep the known nodes in this file to relearn the mesh faster after a restart")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("importPeers")
+                .long("import-peers")
+                .value_name("PATH")
+                .help("Use the peers of a file written by export-peers as static peers in addition to network.yaml")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metricsPort")
+                .long("metrics-port")
+                .value_name("PORT")
+                .help("Serve prometheus metrics via http on this tcp port")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metricsAddress")
+                .long("metrics-address")
+                .value_name("IP")
+                .help("Bind the metrics listener to this ip instead of the wireguard ip, e.g. 127.0.0.1 or ::")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("statusPagePort")
+                .long("status-page-port")
+                .value_name("PORT")
+                .help("Serve a html status page via http on this tcp port of the wireguard ip")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("httpToken")
+                .long("http-token")
+                .value_name("TOKEN")
+                .help("Bearer token needed for the metrics and the status page. Can be ${VAR} or file:<path>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("haPartner")
+                .long("ha-partner")
+                .value_name("IP:PORT")
+                .help("Run as one of two nodes of a static hub with this admin endpoint of the partner")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("haPriority")
+                .long("ha-priority")
+                .value_name("PRIORITY")
+                .help("The partner with the higher priority becomes active first (default 100)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("haNotify")
+                .long("ha-notify")
+                .value_name("COMMAND")
+                .help("Called with active resp. standby on a change of the hub pair state")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("meshDomain")
+                .long("mesh-domain")
+                .value_name("DOMAIN")
+                .help("Answer dns queries for <name>.<DOMAIN> of the mesh nodes on the wireguard ip")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hostsFile")
+                .long("hosts-file")
+                .value_name("PATH")
+                .help("Maintain the names of the mesh nodes in this hosts file, e.g. /etc/hosts")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("peerNames")
+                .long("peer-names")
+                .value_name("PATH")
+                .help("Maintain a json file, which maps the wireguard public keys to the node names")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("authorizedKeys")
+                .long("authorized-keys")
+                .value_name("PATH")
+                .help("Accept only nodes with an identity in this file as peers besides the static peers. Others wait for approval")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fleetAdmin")
+                .long("fleet-admin")
+                .value_name("IDENTITY")
+                .help("Execute the fleet commands signed by this identity, as printed by showkey on the administrator's node (can be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fleetCommand")
+                .long("fleet-command")
+                .value_name("COMMAND")
+                .possible_values(&FleetCommand::NAMES)
+                .help("Fleet command to be executed for the --fleet-admin (can be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("wgIp")
                 .short("a")
                 .long("wireguard-address")
-                .help("Sets the wireguard ip address (ipv4)")
+                .help("Sets the wireguard ip address (ipv4 or ipv6), or auto to lease one from the static peers")
                 .takes_value(true),
         )
         .arg(
@@ -127,41 +395,176 @@ fn main() -> BoxResult<()> {
                 .help("Sets the name for this computer")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("dropPrivileges")
+                .long("drop-privileges")
+                .value_name("USER")
+                .help("After interface setup continue as USER. Route/peer changes are then performed via sudo, which needs to be pre-authorized for ip and wg")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keepNetAdmin")
+                .long("keep-net-admin")
+                .help("Linux only: keep CAP_NET_ADMIN with --drop-privileges, so routes and peers are changed without sudo"),
+        )
         .arg(
             Arg::with_name("Output")
                 .short("O")
-                .help("Output the static configuration and exit immediately (for test only)"),
+                .long("output")
+                .value_name("FORMAT")
+                .possible_values(&["yaml", "json", "effective-wg"])
+                .min_values(0)
+                .max_values(1)
+                .help("Output the static configuration (default yaml) or the initial wireguard configuration (effective-wg) and exit immediately"),
+        )
+        .arg(
+            Arg::with_name("showSecrets")
+                .long("show-secrets")
+                .help("Include keys in the output of -O instead of redacting them"),
+        )
+        .arg(
+            Arg::with_name("windowsService")
+                .long("windows-service")
+                .hidden(true),
         )
-        .subcommand(App::new("install").about("Support installation as deamon"))
-        .get_matches();
+        .subcommand(
+            App::new("install")
+                .about("Support installation as deamon")
+                .arg(Arg::with_name("force").long("force").help("Replace an existing unit file"))
+                .arg(
+                    Arg::with_name("dryRun")
+                        .long("dry-run")
+                        .help("Only show the unit file to be written"),
+                ),
+        )
+        .subcommand(App::new("audit").about("Query all reachable nodes and report inconsistencies of the mesh"))
+        .subcommand(App::new("status").about("Show the known nodes of the running instance via the control socket"))
+        .subcommand(
+            App::new("fleet")
+                .about("Send a maintenance command signed with --identity-file to the nodes, which answer the audit")
+                .arg(Arg::with_name("command").required(true).possible_values(&FleetCommand::NAMES))
+                .arg(Arg::with_name("level").help("The log level for log-level"))
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("WG_IP")
+                        .help("Only to this node (can be repeated)")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            App::new("export-peers")
+                .about("Write the known peers of the running instance to a signed file for --import-peers")
+                .arg(Arg::with_name("file").required(true)),
+        )
+        .subcommand(
+            App::new("save-state")
+                .about("Write the state of the running instance without the secrets to a file for diff-state")
+                .arg(Arg::with_name("file").required(true)),
+        )
+        .subcommand(
+            App::new("diff-state")
+                .about("Show the changes of peers, endpoints and routes between two files written by save-state")
+                .arg(Arg::with_name("snapshot1").required(true))
+                .arg(Arg::with_name("snapshot2").required(true)),
+        )
+        .subcommand(App::new("genkey").about("Print a new wireguard key pair"))
+        .subcommand(
+            App::new("showkey")
+                .about("Print the identity of --identity-file for pinning the node as static peer in network.yaml. The file is created if missing"),
+        )
+        .subcommand(
+            App::new("force-relay")
+                .about("Route a direct peer via a gateway for debugging, or switch back")
+                .arg(Arg::with_name("peer").required(true).help("wg_ip or name of the peer"))
+                .arg(Arg::with_name("state").required(true).possible_values(&["on", "off"]))
+                .arg(
+                    Arg::with_name("duration")
+                        .long("duration")
+                        .takes_value(true)
+                        .help("Seconds until the direct path is used again (default 300)"),
+                ),
+        )
+        .subcommand(App::new("pending").about("Show the nodes waiting for approval with --authorized-keys"))
+        .subcommand(
+            App::new("approve")
+                .about("Approve a pending node and add its identity to the authorized keys")
+                .arg(Arg::with_name("peer").required(true).help("identity, wg_ip or name of the node")),
+        )
+        .subcommand(
+            App::new("revoke-and-rekey")
+                .about("Revoke the keys of a compromised peer in the network and replace the own key pair")
+                .arg(Arg::with_name("peer").required(true).help("wg_ip or name of the peer")),
+        );
+
+    #[cfg(feature = "chaos")]
+    let app = app.arg(
+        Arg::with_name("chaos")
+            .long("chaos")
+            .value_name("SEED")
+            .help("Randomly drop/delay/duplicate admin packets and restart route computation (lab use only)")
+            .takes_value(true),
+    );
+
+    #[cfg(feature = "mdns")]
+    let app = app.arg(Arg::with_name("mdns").long("mdns").help(
+        "Announce the admin service via mDNS and contact the nodes of the mesh found on the LAN",
+    ));
+
+    #[cfg(feature = "scripting")]
+    let app = app.arg(
+        Arg::with_name("policyScript")
+            .long("policy-script")
+            .value_name("FILE")
+            .help("rhai script with the hooks accept_peer, allow_route and select_gateway")
+            .takes_value(true),
+    );
+
+    #[cfg(feature = "webhook")]
+    let app = app.arg(
+        Arg::with_name("webhook")
+            .long("webhook")
+            .value_name("URL")
+            .help("POST peer up/down, route changes and key conflicts as json to this url (can be repeated)")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true),
+    );
+
+    let matches = app.get_matches();
+
+    // Comparing two saved states needs neither the configuration nor the daemon
+    if let ("diff-state", Some(sub_matches)) = matches.subcommand() {
+        let old = StateSnapshot::read(sub_matches.value_of("snapshot1").unwrap())?;
+        let new = StateSnapshot::read(sub_matches.value_of("snapshot2").unwrap())?;
+        print!("{}", diff_report(&old, &new));
+        return Ok(());
+    }
+    if matches.subcommand_name() == Some("genkey") {
+        let (private_key, public_key) = crypto::generate_key_pair();
+        println!("PrivateKey = {}", private_key);
+        println!("PublicKey = {}", public_key);
+        return Ok(());
+    }
 
     let use_tui = matches.is_present("tui");
 
-    let mut opt_peer_conf: Option<Yaml> = None;
     // unwrap() is ok here due to the default value in clap
     let peer_config = matches.value_of("peer_config").unwrap();
-    match File::open(peer_config) {
-        Ok(mut file) => {
-            let mut content = String::new();
-            file.read_to_string(&mut content)?;
-            let mut peer_conf_vec = YamlLoader::load_from_str(&content).unwrap();
-            if peer_conf_vec.len() != 1 {
-                return Err("Malformed peer configuration".into());
-            }
-            opt_peer_conf = Some(peer_conf_vec.remove(0));
-        }
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::PermissionDenied => {
-                return Err(format!("Permission denied for {}", peer_config).into());
-            }
-            std::io::ErrorKind::NotFound => {
-                // That's OK
-            }
-            _ => return Err(Box::new(e)),
-        },
+    let peer_conf = load_peer_config(peer_config)?;
+
+    // Pre-provisioning of a static peer needs neither the network nor root
+    if matches.subcommand_name() == Some("showkey") {
+        let fname = get_option_string(&matches, "identityFile", &peer_conf.identity_file)
+            .ok_or("showkey needs --identity-file or identityFile in peer.yaml")?;
+        let identity = NodeIdentity::from_file(fname)?;
+        println!("identity: {}", base64::encode(identity.public()));
+        return Ok(());
     }
 
-    let computer_name = get_option_string(&matches, &opt_peer_conf, "name")?;
+    let computer_name = required(get_option_string(&matches, "name", &peer_conf.name), "name")?;
 
     // Select logger based on command line flag
     //
@@ -187,112 +590,303 @@ fn main() -> BoxResult<()> {
         };
         set_up_logging(log_filter, opt_fname)?;
     }
+    if let Some(every) = get_option(&matches, "logSample", peer_conf.log_sample)? {
+        log_sampling::set_every(every);
+    }
 
     let network_config = matches.value_of("network_config").unwrap();
-    let network_conf: Yaml;
-    match File::open(network_config) {
-        Ok(mut file) => {
-            let mut content = String::new();
-            file.read_to_string(&mut content)?;
-            let mut network_conf_vec = YamlLoader::load_from_str(&content).unwrap();
-            if network_conf_vec.len() != 1 {
-                return Err("Malformed network configuration".into());
-            }
-            network_conf = network_conf_vec.remove(0);
-            debug!("Raw configuration:");
-            debug!("{:#?}", network_conf);
-        }
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::PermissionDenied => {
-                return Err(format!("Permission denied for {}", network_config).into());
-            }
-            std::io::ErrorKind::NotFound => {
-                return Err(format!(
-                    "Cannot find required network configuration: {}",
-                    network_config
-                )
-                .into());
-            }
-            _ => return Err(Box::new(e)),
-        },
-    }
+    let network = load_network_config(network_config)?;
+    debug!("Network configuration:");
+    debug!("{:#?}", network);
 
     let ip_list = Arch::get_local_interfaces();
+    let link_scope_ids = Arch::get_link_scope_ids();
 
-    let use_existing_interface = get_option_bool(&matches, &opt_peer_conf, "existingInterface");
-    let interface = get_option_string(&matches, &opt_peer_conf, "wgInterface")?;
-    let wg_ip_string = get_option_string(&matches, &opt_peer_conf, "wgIp")?;
-    let wg_ip: Ipv4Addr = wg_ip_string.parse()?;
-
-    // Due to default values in clap, the unwraps() before parse() are ok
-    let last = *(wg_ip.octets().last().unwrap()) as usize;
-    let wg_port: u16 = matches
-        .value_of("wireguard_port")
-        .unwrap_or(&format!("{}", 50000 + last))
-        .parse()?;
-    let admin_port: u16 = matches
-        .value_of("admin_port")
-        .unwrap_or(&format!("{}", 50500 + last))
-        .parse()?;
-    let wg_hopping = matches.is_present("wireguard_hopping");
-
-    let network = &network_conf["network"];
-    let shared_key = base64::decode(
-        &network["sharedKey"]
-            .as_str()
-            .ok_or("sharedKey is not defined or not a string")?,
+    let use_existing_interface =
+        get_option_bool(&matches, "existingInterface", peer_conf.existing_interface);
+    let adopt_peers = get_option_bool(&matches, "adoptPeers", peer_conf.adopt_peers);
+    let teardown_skip = match matches.values_of("teardownSkip") {
+        Some(steps) => steps
+            .map(|step| step.parse::<TeardownStep>())
+            .collect::<Result<Vec<_>, _>>()?,
+        None => peer_conf.teardown_skip.clone(),
+    };
+    let interface = get_option_string(&matches, "wgInterface", &peer_conf.wg_interface);
+    let interface_altname =
+        get_option_bool(&matches, "interfaceAltname", peer_conf.interface_altname);
+    let mut disable_ipv6 = get_option_bool(&matches, "disableIpv6", peer_conf.disable_ipv6);
+    if !disable_ipv6 && !wg_netmanager::util::ipv6_available() {
+        info!("ipv6 is not available => ipv4 only");
+        disable_ipv6 = true;
+    }
+    let router_hairpinning =
+        get_option_bool(&matches, "routerHairpinning", peer_conf.router_hairpinning);
+    let decrypt_failure_threshold = get_option(
+        &matches,
+        "decryptFailureThreshold",
+        Some(peer_conf.decrypt_failure_threshold),
+    )?
+    .unwrap_or(DEFAULT_DECRYPT_FAILURE_THRESHOLD);
+    let key_lifetime = get_option(&matches, "keyLifetime", peer_conf.key_lifetime)?;
+    if let Some(lifetime) = key_lifetime {
+        if lifetime < MIN_KEY_LIFETIME {
+            return Err(format!("Key lifetime needs to be at least {}s", MIN_KEY_LIFETIME).into());
+        }
+    }
+    let webhooks = match matches.values_of("webhook") {
+        Some(urls) => urls.map(|url| url.to_string()).collect::<Vec<_>>(),
+        None => peer_conf.webhooks.clone(),
+    };
+    if !webhooks.is_empty() && !cfg!(feature = "webhook") {
+        warn!("Webhooks need the feature webhook => ignored");
+    }
+    let policy_script = get_option_string(&matches, "policyScript", &peer_conf.policy_script);
+    if policy_script.is_some() && !cfg!(feature = "scripting") {
+        warn!("The policy script needs the feature scripting => ignored");
+    }
+    let mdns = get_option_bool(&matches, "mdns", peer_conf.mdns);
+    if mdns && !cfg!(feature = "mdns") {
+        warn!("mDNS needs the feature mdns => ignored");
+    }
+    let identity = match get_option_string(&matches, "identityFile", &peer_conf.identity_file) {
+        Some(fname) => NodeIdentity::from_file(fname)?,
+        None => NodeIdentity::generate(),
+    };
+    let wg_ip_string = required(
+        get_option_string(&matches, "wgIp", &peer_conf.wg_ip),
+        "wgIp",
     )?;
-    let subnet: ipnet::Ipv4Net = network["subnet"]
-        .as_str()
-        .ok_or("subnet is not defined or not a string")?
-        .parse()?;
+    let (wg_ip, wg_ip_lease): (IpAddr, Option<u64>) = match wg_ip_string.as_str() {
+        "auto" if matches!(matches.subcommand_name(), Some("audit" | "fleet")) => {
+            return Err("The audit and fleet subcommands need an explicit wgIp".into());
+        }
+        // The other subcommands talk to the running instance
+        "auto" if matches.subcommand_name().is_some() => (network.subnet.network(), None),
+        "auto" => {
+            let lease = request_lease(&network.peers, &network.shared_key, &identity.public())?;
+            info!("Use leased wg_ip {}", lease.wg_ip);
+            (
+                lease.wg_ip,
+                Some(wg_netmanager::util::now() + lease.lease_time),
+            )
+        }
+        wg_ip => (wg_ip.parse()?, None),
+    };
+    let pinned_routes = match matches.values_of("pinRoute") {
+        Some(pins) => pins
+            .map(|pin| match pin.split_once('=') {
+                Some((to, gateway)) => Ok((to.trim().parse()?, gateway.trim().parse()?)),
+                None => Err(format!("Pinned route {} is not DEST=GATEWAY", pin).into()),
+            })
+            .collect::<BoxResult<HashMap<IpAddr, IpAddr>>>()?,
+        None => peer_conf.pinned_routes.clone(),
+    };
+    for (to, gateway) in pinned_routes.iter() {
+        if to == gateway || *to == wg_ip || *gateway == wg_ip {
+            return Err(format!("Invalid pinned route {} via {}", to, gateway).into());
+        }
+    }
+
+    let exported_nets = match matches.values_of("exportNet") {
+        Some(nets) => nets
+            .map(|net| Ok(net.parse::<IpNet>()?.trunc()))
+            .collect::<BoxResult<Vec<IpNet>>>()?,
+        None => peer_conf
+            .exported_nets
+            .iter()
+            .map(|net| net.trunc())
+            .collect(),
+    };
 
-    if !subnet.contains(&wg_ip) {
-        return Err(format!("{} is outside of subnet {}", wg_ip, subnet).into());
+    let wg_hopping = matches.is_present("wireguard_hopping");
+    let drop_privileges_to =
+        get_option_string(&matches, "dropPrivileges", &peer_conf.drop_privileges);
+    let keep_net_admin = get_option_bool(&matches, "keepNetAdmin", peer_conf.keep_net_admin);
+    if keep_net_admin && drop_privileges_to.is_none() {
+        return Err("--keep-net-admin needs --drop-privileges".into());
     }
+    let chaos_seed = match matches.value_of("chaos") {
+        Some(seed) => Some(seed.parse::<u64>()?),
+        None => None,
+    };
 
-    let mut peers: HashMap<Ipv4Addr, PublicPeer> = HashMap::new();
-    for p in network_conf["peers"]
-        .as_vec()
-        .ok_or("no peers defined in config file")?
-    {
-        info!("STATIC PEER: {:#?}", p);
-        let endpoint = p["endPoint"]
-            .as_str()
-            .ok_or("no endpoint defined")?
-            .to_string();
-        let mut flds = endpoint.split(':').collect::<Vec<_>>();
-        let port_str = flds.pop().ok_or("endpoint should be <hostname/ip:port>")?;
-        let wg_port = (*port_str).parse::<u16>()?;
-        let admin_port = p["adminPort"]
-            .as_i64()
-            .ok_or("Cannot parse adminPort as integer")? as u16;
-        let wg_ip: Ipv4Addr = p["wgIp"]
-            .as_str()
-            .ok_or("wgIp not defined or not a string")?
-            .parse()?;
-        let pp = PublicPeer {
-            endpoint,
-            admin_port,
-            wg_port,
-            wg_ip,
+    // Explicit ports take precedence over the network's port allocation
+    let default_ports = network.ports_for(&wg_ip);
+    let port = |option_name: &'static str, default: Option<u16>| -> BoxResult<u16> {
+        let conf = match option_name {
+            "wireguard_port" => peer_conf.wg_port,
+            _ => peer_conf.admin_port,
         };
-        peers.insert(wg_ip, pp);
+        match get_option(&matches, option_name, conf)? {
+            Some(port) => Ok(port),
+            None => default.ok_or_else(|| {
+                format!(
+                    "Configuration option <{}> is required for explicit port allocation",
+                    option_name
+                )
+                .into()
+            }),
+        }
+    };
+    let wg_port = port("wireguard_port", default_ports.map(|p| p.0))?;
+    let admin_port = port("admin_port", default_ports.map(|p| p.1))?;
+    let extra_wg_ports = match matches.values_of("extraWgPort") {
+        Some(ports) => ports
+            .map(|port| Ok(port.parse::<u16>()?))
+            .collect::<BoxResult<Vec<u16>>>()?,
+        None => peer_conf.extra_wg_ports.clone(),
+    };
+    for (i, port) in extra_wg_ports.iter().enumerate() {
+        if *port == wg_port || *port == admin_port || extra_wg_ports[..i].contains(port) {
+            return Err(format!("Extra wireguard port {} is already in use", port).into());
+        }
+    }
+    if wg_hopping && !extra_wg_ports.is_empty() {
+        return Err("Extra wireguard ports cannot be used with port hopping".into());
+    }
+    let network_name = network.name;
+    let interface = interface
+        .or_else(|| network_name.as_deref().map(interface_name_for_network))
+        .ok_or("Configuration option <wgInterface> is not defined and network has no name")?;
+    let upgrade_socket = get_option_string(&matches, "upgradeSocket", &peer_conf.upgrade_socket)
+        .or_else(|| cfg!(target_os = "linux").then(|| upgrade_socket_for_interface(&interface)));
+    let takeover = matches.is_present("takeover");
+    let control_socket = get_option_string(&matches, "controlSocket", &peer_conf.control_socket)
+        .or_else(|| cfg!(unix).then(|| control_socket_for_interface(&interface)));
+    let control_group = get_option_string(&matches, "controlGroup", &peer_conf.control_group);
+    let control_admin_group = get_option_string(
+        &matches,
+        "controlAdminGroup",
+        &peer_conf.control_admin_group,
+    );
+    let status_file = get_option_string(&matches, "statusFile", &peer_conf.status_file);
+    let state_file = get_option_string(&matches, "stateFile", &peer_conf.state_file);
+    let mesh_domain = get_option_string(&matches, "meshDomain", &peer_conf.mesh_domain)
+        .map(|domain| domain.trim_matches('.').to_ascii_lowercase());
+    let hosts_file = get_option_string(&matches, "hostsFile", &peer_conf.hosts_file);
+    let authorized_keys = get_option_string(&matches, "authorizedKeys", &peer_conf.authorized_keys);
+    let fleet_admins = match matches.values_of("fleetAdmin") {
+        Some(admins) => admins.map(decode_identity).collect::<BoxResult<Vec<_>>>()?,
+        None => peer_conf
+            .fleet_admins
+            .iter()
+            .map(|admin| decode_identity(admin))
+            .collect::<BoxResult<Vec<_>>>()?,
+    };
+    let fleet_commands = match matches.values_of("fleetCommand") {
+        Some(commands) => commands.map(|c| c.to_string()).collect(),
+        None => peer_conf.fleet_commands.clone(),
+    };
+    for command in fleet_commands.iter() {
+        if !FleetCommand::NAMES.contains(&command.as_str()) {
+            return Err(format!("Unknown fleet command {}", command).into());
+        }
+    }
+    if fleet_admins.is_empty() != fleet_commands.is_empty() {
+        return Err("Fleet commands need both --fleet-admin and --fleet-command".into());
+    }
+    let peer_names = get_option_string(&matches, "peerNames", &peer_conf.peer_names);
+    let gateway_grace = get_option(&matches, "gatewayGrace", Some(peer_conf.gateway_grace))?
+        .unwrap_or(DEFAULT_GATEWAY_GRACE);
+    let metrics_port = get_option(&matches, "metricsPort", peer_conf.metrics_port)?;
+    let metrics_address = get_option(&matches, "metricsAddress", peer_conf.metrics_address)?;
+    let status_page_port = get_option(&matches, "statusPagePort", peer_conf.status_page_port)?;
+    let http_token = match matches.value_of("httpToken") {
+        Some(token) => Some(wg_netmanager::config_file::expand_secret(
+            "httpToken",
+            token,
+        )?),
+        None => peer_conf.http_token.clone(),
+    };
+    let ha_partner = get_option(&matches, "haPartner", peer_conf.ha_partner)?;
+    let ha_pair = match ha_partner {
+        Some(partner) => {
+            if !network.peers.contains_key(&wg_ip) {
+                return Err("A hub pair needs the wgIp of a static peer".into());
+            }
+            Some(HaPair {
+                partner,
+                priority: get_option(&matches, "haPriority", Some(peer_conf.ha_priority))?
+                    .unwrap_or(DEFAULT_HA_PRIORITY),
+                notify: get_option_string(&matches, "haNotify", &peer_conf.ha_notify),
+            })
+        }
+        None => None,
+    };
+    let shared_key = network.shared_key;
+    let accepted_shared_keys = network.accepted_shared_keys;
+    let imported_peers = match get_option_string(&matches, "importPeers", &peer_conf.import_peers) {
+        Some(fname) => {
+            let shared_keys = std::iter::once(&shared_key)
+                .chain(accepted_shared_keys.iter())
+                .map(|key| key.as_slice())
+                .collect::<Vec<_>>();
+            let peer_db = PeerDatabase::read(&fname, &shared_keys)
+                .map_err(|e| format!("Cannot import peers from {}: {}", fname, e))?;
+            let tier = network
+                .peers
+                .values()
+                .map(|peer| peer.tier)
+                .min()
+                .unwrap_or(DEFAULT_TIER);
+            info!(
+                "Import {} peers exported by {} from {}",
+                peer_db.peers.len(),
+                peer_db.exported_by,
+                fname
+            );
+            peer_db.public_peers(tier)?
+        }
+        None => vec![],
+    };
+    let subnet = network.subnet;
+    let peers = network.peers;
+    let areas = network.areas;
+    let dns = network.dns;
+    if dns.is_some() && !cfg!(all(target_os = "linux", feature = "resolved")) {
+        warn!("DNS of the mesh needs the feature resolved on linux => ignored");
+    }
+
+    // wgIp auto is the network address for the subcommands
+    if wg_ip_string != "auto" || matches.subcommand_name().is_none() {
+        check_wg_ip(&subnet, &wg_ip)?;
+    }
+    if disable_ipv6 && wg_ip.is_ipv6() {
+        return Err("An ipv6 subnet needs ipv6".into());
+    }
+    for net in exported_nets.iter() {
+        if nets_overlap(net, &subnet) {
+            return Err(format!("Exported network {} overlaps with subnet {}", net, subnet).into());
+        }
     }
 
-    let wg_dev = Arch::get_wg_dev(&interface);
+    let backend = match matches.value_of("backend") {
+        Some(backend) => backend.parse()?,
+        None => peer_conf.backend.unwrap_or_default(),
+    };
+    if backend == Backend::Userspace && use_existing_interface {
+        return Err("The userspace backend cannot use an existing interface".into());
+    }
+    let use_existing_interface = use_existing_interface || backend == Backend::Existing;
+    if adopt_peers && !use_existing_interface {
+        return Err("Peers can only be adopted on an existing interface (--existing-wg)".into());
+    }
+    if !teardown_skip.is_empty() && !use_existing_interface {
+        return Err(
+            "Teardown steps can only be skipped on an existing interface (--existing-wg)".into(),
+        );
+    }
+    let wg_dev = Arch::get_wg_dev_for_backend(&interface, backend)?;
     let (my_private_key, my_public_key) = wg_dev.create_key_pair()?;
     trace!("My private key: {}", my_private_key);
     trace!("My public key: {}", my_public_key);
     let timestamp = wg_netmanager::util::now();
-    let my_public_key_with_time = PublicKeyWithTime {
-        key: my_public_key,
-        priv_key_creation_time: timestamp,
-    };
+    let my_public_key_with_time = PublicKeyWithTime::new(my_public_key, timestamp);
 
     let static_config = StaticConfiguration::builder()
         .name(computer_name)
         .ip_list(ip_list)
+        .link_scope_ids(link_scope_ids)
         .wg_ip(wg_ip)
         .wg_name(interface)
         .wg_port(wg_port)
@@ -300,6 +894,7 @@ fn main() -> BoxResult<()> {
         .admin_port(admin_port)
         .subnet(subnet)
         .shared_key(shared_key)
+        .accepted_shared_keys(accepted_shared_keys)
         .my_public_key(my_public_key_with_time)
         .my_private_key(my_private_key)
         .peers(peers)
@@ -307,15 +902,178 @@ fn main() -> BoxResult<()> {
         .use_existing_interface(use_existing_interface)
         .network_yaml_filename(network_config)
         .peer_yaml_filename(peer_config)
+        .drop_privileges_to(drop_privileges_to)
+        .keep_net_admin(keep_net_admin)
+        .backend(backend)
+        .policy_script(policy_script)
+        .chaos_seed(chaos_seed)
+        .network_name(network_name)
+        .interface_altname(interface_altname)
+        .adopt_peers(adopt_peers)
+        .teardown_skip(teardown_skip)
+        .fleet_admins(fleet_admins)
+        .fleet_commands(fleet_commands)
+        .decrypt_failure_threshold(decrypt_failure_threshold)
+        .upgrade_socket(upgrade_socket)
+        .takeover(takeover)
+        .control_socket(control_socket)
+        .control_group(control_group)
+        .control_admin_group(control_admin_group)
+        .status_file(status_file)
+        .state_file(state_file)
+        .metrics_port(metrics_port)
+        .metrics_address(metrics_address)
+        .status_page_port(status_page_port)
+        .http_token(http_token)
+        .mesh_domain(mesh_domain)
+        .hosts_file(hosts_file)
+        .wg_ip_lease(wg_ip_lease)
+        .ha_pair(ha_pair)
+        .authorized_keys(authorized_keys)
+        .peer_names(peer_names)
+        .gateway_grace(gateway_grace)
+        .disable_ipv6(disable_ipv6)
+        .router_hairpinning(router_hairpinning)
+        .key_lifetime(key_lifetime)
+        .webhooks(webhooks)
+        .pinned_routes(pinned_routes)
+        .identity(identity)
+        .areas(areas)
+        .exported_nets(exported_nets)
+        .extra_wg_ports(extra_wg_ports)
+        .dns(dns)
+        .persistent_keepalive(network.persistent_keepalive)
+        .routing(network.routing)
+        .mdns(mdns)
+        .imported_peers(imported_peers)
         .build();
 
     let subcommand = matches.subcommand();
     if subcommand.0 == "install" {
         return Arch::command_install(subcommand.1.unwrap(), static_config);
     }
+    if subcommand.0 == "audit" {
+        return wg_netmanager::audit::run(&static_config);
+    }
+    if subcommand.0 == "fleet" {
+        let args = subcommand.1.unwrap();
+        let command = match args.value_of("command").unwrap() {
+            "reload-config" => FleetCommand::ReloadConfig,
+            "log-level" => {
+                let level = args.value_of("level").ok_or("log-level needs the level")?;
+                level
+                    .parse::<log::LevelFilter>()
+                    .map_err(|_| format!("Unknown log level {}", level))?;
+                FleetCommand::SetLogLevel {
+                    level: level.to_string(),
+                }
+            }
+            _ => FleetCommand::Status,
+        };
+        let targets = match args.values_of("to") {
+            Some(targets) => targets
+                .map(|wg_ip| Ok(wg_ip.parse::<IpAddr>()?))
+                .collect::<BoxResult<Vec<_>>>()?,
+            None => vec![],
+        };
+        return wg_netmanager::fleet::run(&static_config, command, &targets);
+    }
+    if subcommand.0 == "status" {
+        #[cfg(unix)]
+        return match static_config.control_socket.as_ref() {
+            Some(path) => wg_netmanager::control::print_status(path),
+            None => Err("No control socket configured".into()),
+        };
+        #[cfg(not(unix))]
+        return Err("The status subcommand is only available on unix".into());
+    }
+    if subcommand.0 == "export-peers" {
+        #[cfg(unix)]
+        return match static_config.control_socket.as_ref() {
+            Some(path) => wg_netmanager::control::export_peers(
+                path,
+                subcommand.1.unwrap().value_of("file").unwrap(),
+            ),
+            None => Err("No control socket configured".into()),
+        };
+        #[cfg(not(unix))]
+        return Err("The export-peers subcommand is only available on unix".into());
+    }
+    if subcommand.0 == "save-state" {
+        #[cfg(unix)]
+        return match static_config.control_socket.as_ref() {
+            Some(path) => wg_netmanager::control::save_state(
+                path,
+                subcommand.1.unwrap().value_of("file").unwrap(),
+            ),
+            None => Err("No control socket configured".into()),
+        };
+        #[cfg(not(unix))]
+        return Err("The save-state subcommand is only available on unix".into());
+    }
+    if subcommand.0 == "force-relay" {
+        #[cfg(unix)]
+        {
+            let sub_matches = subcommand.1.unwrap();
+            let duration = sub_matches
+                .value_of("duration")
+                .map(|duration| duration.parse::<u64>())
+                .transpose()?;
+            return match static_config.control_socket.as_ref() {
+                Some(path) => wg_netmanager::control::request_force_relay(
+                    path,
+                    sub_matches.value_of("peer").unwrap(),
+                    sub_matches.value_of("state") == Some("on"),
+                    duration,
+                ),
+                None => Err("No control socket configured".into()),
+            };
+        }
+        #[cfg(not(unix))]
+        return Err("The force-relay subcommand is only available on unix".into());
+    }
+    if subcommand.0 == "pending" {
+        #[cfg(unix)]
+        return match static_config.control_socket.as_ref() {
+            Some(path) => wg_netmanager::control::print_pending(path),
+            None => Err("No control socket configured".into()),
+        };
+        #[cfg(not(unix))]
+        return Err("The pending subcommand is only available on unix".into());
+    }
+    if subcommand.0 == "approve" {
+        #[cfg(unix)]
+        return match static_config.control_socket.as_ref() {
+            Some(path) => wg_netmanager::control::request_approve(
+                path,
+                subcommand.1.unwrap().value_of("peer").unwrap(),
+            ),
+            None => Err("No control socket configured".into()),
+        };
+        #[cfg(not(unix))]
+        return Err("The approve subcommand is only available on unix".into());
+    }
+    if subcommand.0 == "revoke-and-rekey" {
+        #[cfg(unix)]
+        return match static_config.control_socket.as_ref() {
+            Some(path) => wg_netmanager::control::request_revoke_and_rekey(
+                path,
+                subcommand.1.unwrap().value_of("peer").unwrap(),
+            ),
+            None => Err("No control socket configured".into()),
+        };
+        #[cfg(not(unix))]
+        return Err("The revoke-and-rekey subcommand is only available on unix".into());
+    }
 
     if matches.is_present("Output") {
-        println!("{:#?}", static_config);
+        let show_secrets = matches.is_present("showSecrets");
+        let output = match matches.value_of("Output").unwrap_or("yaml") {
+            "json" => static_config.output(show_secrets).to_json()?,
+            "effective-wg" => static_config.initial_wg_configuration(show_secrets),
+            _ => static_config.output(show_secrets).to_yaml()?,
+        };
+        println!("{}", output);
         return Ok(());
     }
 
@@ -21,46 +21,465 @@
 //      allow multiple instances of NetworkManager, which can be connected by glue code freely
 //
 
+use std::cmp::Reverse;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
 
+use ipnet::IpNet;
 use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+use crate::address_lease::AddressLeases;
+use crate::authorized_keys::{JoinApproval, PendingPeer};
 use crate::configuration::*;
 use crate::crypt_udp::*;
+use crate::decrypt_monitor::*;
+use crate::error::*;
 use crate::event::Event;
-use crate::node::{DistantNode, DynamicPeer, Node, StaticPeer};
+use crate::identity::PublicIdentity;
+use crate::mesh_event::*;
+use crate::nat_telemetry::NatTelemetry;
+use crate::node::{DistantNode, DynamicPeer, Node, StaticPeer, CONTACT_REFRESH, STALE_HANDSHAKE};
+#[cfg(feature = "scripting")]
+use crate::policy_script::PolicyScript;
+use crate::port_validation::PortValidation;
+use crate::readdress::READDRESS_GRACE;
+use crate::route_engine::*;
 use crate::routedb::RouteInfo;
+use crate::state_snapshot::*;
+use crate::wg_dev::RouteAttributes;
+
+// Why a route has been deleted or moved to another gateway
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteReason {
+    // The node is not known anymore, e.g. timed out or said goodbye
+    PeerDead,
+    // The direct tunnel is gone, but the node is still reached via a gateway
+    LinkLost,
+    // The gateway offers no route anymore
+    GatewayLost,
+    // A path with fewer hops or lower cost
+    BetterPath,
+    // A pinned route, a forced relay or changed route attributes
+    Policy,
+}
+impl RouteReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteReason::PeerDead => "peer_dead",
+            RouteReason::LinkLost => "link_lost",
+            RouteReason::GatewayLost => "gateway_lost",
+            RouteReason::BetterPath => "better_path",
+            RouteReason::Policy => "policy",
+        }
+    }
+}
+impl std::fmt::Display for RouteReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// The last deleted or replaced route per destination
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RouteWithdrawal {
+    pub to: IpAddr,
+    pub at: u64,
+    // The withdrawn gateway
+    pub gateway: Option<IpAddr>,
+    // Else replaced by a route via another gateway
+    pub deleted: bool,
+    pub reason: RouteReason,
+}
+
+// Destinations for which the last withdrawal is kept
+const MAX_ROUTE_WITHDRAWALS: usize = 1000;
 
 #[derive(Debug)]
 pub enum RouteChange {
     AddRoute {
-        to: Ipv4Addr,
-        gateway: Option<Ipv4Addr>,
+        to: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: RouteAttributes,
     },
     ReplaceRoute {
-        to: Ipv4Addr,
-        gateway: Option<Ipv4Addr>,
+        to: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: RouteAttributes,
+        reason: RouteReason,
     },
     DelRoute {
-        to: Ipv4Addr,
-        gateway: Option<Ipv4Addr>,
+        to: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: RouteAttributes,
+        reason: RouteReason,
+    },
+    // Networks exported by a node. The peer is selected by the wireguard configuration.
+    AddNetRoute {
+        net: IpNet,
+    },
+    DelNetRoute {
+        net: IpNet,
     },
 }
 
-#[derive(Default, Debug)]
+// Entries of each kind, which are listed in the info log of a route diff
+const ROUTE_DIFF_TOP: usize = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RouteDiffEntry {
+    // wg_ip or exported network
+    pub to: String,
+    pub gateway: Option<IpAddr>,
+    // Only known for replaced routes
+    pub previous_gateway: Option<IpAddr>,
+    // Only for deleted and replaced routes to nodes
+    #[serde(default)]
+    pub reason: Option<RouteReason>,
+}
+impl std::fmt::Display for RouteDiffEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let via = |gateway: Option<IpAddr>| match gateway {
+            Some(gw) => format!("via {}", gw),
+            None => "direct".to_string(),
+        };
+        write!(f, "{} {}", self.to, via(self.gateway))?;
+        match (self.previous_gateway, self.reason) {
+            (Some(previous), Some(reason)) => write!(f, " (was via {}, {})", previous, reason),
+            (Some(previous), None) => write!(f, " (was via {})", previous),
+            (None, Some(reason)) => write!(f, " ({})", reason),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+// Summary of a batch of route changes for the logs and the status api
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RouteDiff {
+    pub at: u64,
+    pub routedb_version: usize,
+    pub added: Vec<RouteDiffEntry>,
+    pub replaced: Vec<RouteDiffEntry>,
+    pub deleted: Vec<RouteDiffEntry>,
+}
+impl RouteDiff {
+    // previous holds the gateways of the routes before the changes
+    pub fn of(
+        at: u64,
+        routedb_version: usize,
+        changes: &[RouteChange],
+        previous: &HashMap<IpAddr, Option<IpAddr>>,
+    ) -> Self {
+        let mut diff = RouteDiff {
+            at,
+            routedb_version,
+            ..Default::default()
+        };
+        let entry = |to: String, gateway| RouteDiffEntry {
+            to,
+            gateway,
+            previous_gateway: None,
+            reason: None,
+        };
+        for change in changes.iter() {
+            match change {
+                RouteChange::AddRoute { to, gateway, .. } => {
+                    diff.added.push(entry(to.to_string(), *gateway))
+                }
+                RouteChange::ReplaceRoute {
+                    to,
+                    gateway,
+                    reason,
+                    ..
+                } => diff.replaced.push(RouteDiffEntry {
+                    previous_gateway: previous.get(to).copied().flatten(),
+                    reason: Some(*reason),
+                    ..entry(to.to_string(), *gateway)
+                }),
+                RouteChange::DelRoute {
+                    to,
+                    gateway,
+                    reason,
+                    ..
+                } => diff.deleted.push(RouteDiffEntry {
+                    reason: Some(*reason),
+                    ..entry(to.to_string(), *gateway)
+                }),
+                RouteChange::AddNetRoute { net } => diff.added.push(entry(net.to_string(), None)),
+                RouteChange::DelNetRoute { net } => diff.deleted.push(entry(net.to_string(), None)),
+            }
+        }
+        diff
+    }
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.replaced.is_empty() && self.deleted.is_empty()
+    }
+    // e.g. "2 added, 1 replaced, 0 deleted: +10.1.1.3 direct, +10.1.1.4 via 10.1.1.3, ~10.1.1.5 via 10.1.1.3 (was via 10.1.1.6)"
+    pub fn summary(&self) -> String {
+        self.format(ROUTE_DIFF_TOP)
+    }
+    pub fn details(&self) -> String {
+        self.format(usize::MAX)
+    }
+    fn format(&self, top: usize) -> String {
+        let mut entries = vec![];
+        for (sign, list) in [
+            ("+", &self.added),
+            ("~", &self.replaced),
+            ("-", &self.deleted),
+        ] {
+            entries.extend(list.iter().take(top).map(|e| format!("{}{}", sign, e)));
+            if list.len() > top {
+                entries.push(format!("{} {} more", sign, list.len() - top));
+            }
+        }
+        format!(
+            "{} added, {} replaced, {} deleted: {}",
+            self.added.len(),
+            self.replaced.len(),
+            self.deleted.len(),
+            entries.join(", ")
+        )
+    }
+}
+
+// A direct peer, which has asked for the local contact, is waiting for the contact
+// of a distant node. For this time, the contacts of new peers are pushed to it.
+pub const CONTACT_SUBSCRIPTION: u64 = 180;
+// Interval to ask the gateway of distant nodes with incomplete contact
+const CONTACT_SUBSCRIPTION_RENEWAL: u64 = 60;
+
+// Revocations are passed on with the advertisements for this time, so all nodes learn
+// them. The nodes keep them until restart. Their number is limited by the packet size.
+pub const REVOCATION_GOSSIP: u64 = 600;
+const MAX_GOSSIPED_REVOCATIONS: usize = 4;
+
+// Default duration of force-relay, after which the direct path is used again
+pub const DEFAULT_FORCE_RELAY_DURATION: u64 = 300;
+
+// Versions of the route database kept as base for deltas. A peer with an older
+// version gets the full database.
+pub const ROUTEDB_HISTORY: usize = 8;
+
+// Interval of the pings to the direct peers
+pub const RTT_PING_INTERVAL: u64 = 30;
+
+// Static peers of the next higher tier are contacted, if no static peer of the
+// contacted tiers has been reached for this long
+pub const TIER_FALLBACK: u64 = 120;
+
+// Delay of the NAT traversal after a rendezvous, so the request reaches both peers
+pub const CONNECT_DELAY: u64 = 2;
+// Maximum number of relays of a rendezvous request
+pub const CONNECT_REQUEST_TTL: u8 = 8;
+
+// The advertisements of the quarantined node of a wg_ip conflict are ignored until
+// this time after the last one
+pub const CONFLICT_QUARANTINE: u64 = 300;
+// Cloned nodes with the same wg_ip make the advertised key alternate. This number of
+// switches within the window is taken as conflict.
+pub const KEY_FLAP_WINDOW: u64 = 60;
+pub const KEY_FLAP_SWITCHES: usize = 3;
+
+// Two nodes advertise the same wg_ip, while both are alive, or their keys alternate.
+// The node seen first is kept: the bound identity resp. the key advertised first.
+// The creation time of a key is not trusted for this, because the claimant reports
+// it about itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WgIpConflict {
+    pub kept_key: String,
+    pub quarantined_key: String,
+    pub quarantined_identity: PublicIdentity,
+    // Unix time of detection and end of the quarantine
+    pub since: u64,
+    pub until: u64,
+}
+
+// A change of the advertised key of a wg_ip, see key_flap()
+struct AdvertisedKey {
+    at: u64,
+    key: PublicKeyWithTime,
+    identity: PublicIdentity,
+    src_addr: SocketAddr,
+}
+
+// Weight of a relay for the pair of nodes. Both nodes of the pair get the same weight.
+fn relay_weight(a: &IpAddr, b: &IpAddr, relay: &IpAddr) -> u64 {
+    let (low, high) = if a < b { (a, b) } else { (b, a) };
+    let mut hasher = Sha256::new();
+    for ip in [low, high, relay] {
+        hasher.update(ip.to_string().as_bytes());
+        hasher.update(b"/");
+    }
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+// The traffic to a direct peer is routed via a gateway until the given time.
+// wireguard accepts packets only from the peer, which the source is routed to.
+// So both sides need to relay, and the requesting side tells the other one via
+// its advertisements.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ForcedRelay {
+    until: u64,
+    by_peer: bool,
+}
+
+// Answer to "how would the mesh reach X?"
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteLookup {
+    pub to: IpAddr,
+    pub name: Option<String>,
+    pub gateway: Option<IpAddr>,
+    pub hop_cnt: usize,
+    // endpoint class of the next hop, which is the gateway if any
+    pub endpoint_class: &'static str,
+    pub alive: bool,
+    pub lastseen_s_ago: Option<u64>,
+}
+
+// A node as reported by the control interface
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub wg_ip: IpAddr,
+    pub name: Option<String>,
+    pub endpoint_class: String,
+    pub endpoint: Option<SocketAddr>,
+    pub gateway: Option<IpAddr>,
+    // None, if there is no route
+    pub hop_cnt: Option<usize>,
+    pub public_key: Option<String>,
+    pub alive: bool,
+    pub lastseen_s_ago: Option<u64>,
+    // The configured gateway, which may differ from gateway while it offers no route
+    #[serde(default)]
+    pub pinned_gateway: Option<IpAddr>,
+    #[serde(default)]
+    pub exported_nets: Vec<IpNet>,
+    // Remaining seconds of a forced relay
+    #[serde(default)]
+    pub forced_relay_s: Option<u64>,
+    // Path cost of the route and the measured round trip time of a direct peer
+    #[serde(default)]
+    pub cost: Option<u32>,
+    #[serde(default)]
+    pub rtt_ms: Option<u32>,
+    // The relay selected after the NAT traversal has failed
+    #[serde(default)]
+    pub relay: Option<IpAddr>,
+    // The key of the other node with this wg_ip, which is quarantined
+    #[serde(default)]
+    pub quarantined_key: Option<String>,
+}
+
+// The properties of a node, which are reported as MeshEvent on change
+#[derive(PartialEq)]
+struct NodeSnapshot {
+    reachable: bool,
+    name: Option<String>,
+    public_key: Option<String>,
+    endpoint: Option<SocketAddr>,
+}
+impl NodeSnapshot {
+    fn of(node: &dyn Node) -> Self {
+        NodeSnapshot {
+            reachable: node.is_reachable(),
+            name: node.name().map(|n| n.to_string()),
+            public_key: node.public_key().map(|pk| pk.key.clone()),
+            endpoint: node.visible_wg_endpoint(),
+        }
+    }
+}
+
+// Counted since start for the metrics
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct ManagerCounters {
+    pub advertisements_received: u64,
+    pub route_changes: u64,
+}
+
+#[derive(Default, Debug, Clone)]
 pub struct RouteDB {
     version: usize,
-    route_for: HashMap<Ipv4Addr, RouteInfo>,
+    route_for: HashMap<IpAddr, RouteInfo>,
 }
 
 pub struct NetworkManager {
-    wg_ip: Ipv4Addr,
+    wg_ip: IpAddr,
     pub my_visible_wg_endpoint: Option<SocketAddr>,
     pub my_local_wg_port: u16,
     route_db: RouteDB,
-    pub all_nodes: HashMap<Ipv4Addr, Box<dyn Node>>,
+    // The latest versions of route_db incl. the current one
+    routedb_history: VecDeque<RouteDB>,
+    pub all_nodes: HashMap<IpAddr, Box<dyn Node>>,
+    // Priority queue of the nodes' next processing time. Entries are not removed on
+    // reschedule, instead outdated ones are skipped by comparing with scheduled_at.
+    schedule: BinaryHeap<Reverse<(u64, IpAddr)>>,
+    scheduled_at: HashMap<IpAddr, u64>,
+    mesh_events: MeshEventBus,
+    last_route_diff: Option<RouteDiff>,
+    route_withdrawals: HashMap<IpAddr, RouteWithdrawal>,
+    decrypt_monitor: DecryptMonitor,
+    // Detected path mtu per destination and the attributes of the installed routes
+    path_mtu: HashMap<IpAddr, u32>,
+    route_attributes: HashMap<IpAddr, RouteAttributes>,
+    // Configured gateway per destination, which wins over shorter paths
+    pinned_routes: HashMap<IpAddr, IpAddr>,
+    // Gateway of the distant nodes, which cannot be reached by NAT traversal. A
+    // configured pin wins.
+    relays: HashMap<IpAddr, IpAddr>,
+    // The identity, which has signed the advertisements of a node
+    identities: HashMap<IpAddr, PublicIdentity>,
+    // Nodes, which have changed their wg_ip, with the previous one and the end of
+    // the grace period
+    moved_nodes: HashMap<IpAddr, (IpAddr, u64)>,
+    wg_ip_conflicts: HashMap<IpAddr, WgIpConflict>,
+    advertised_keys: HashMap<IpAddr, VecDeque<AdvertisedKey>>,
+    #[cfg(feature = "scripting")]
+    policy_script: Option<PolicyScript>,
+    // Only with authorized keys
+    join_approval: Option<JoinApproval>,
+    // Only used by a static peer
+    address_leases: AddressLeases,
+    // Within an area, only the routes of the own area are used. Other areas are
+    // reached via the area border node, which has sent the best summary.
+    areas: BTreeMap<u16, IpNet>,
+    my_area: Option<u16>,
+    is_area_border: bool,
+    area_gateways: BTreeMap<u16, IpAddr>,
+    // The networks exported by the direct peers as per their advertisements.
+    // Networks overlapping the subnet or the own exported networks are ignored.
+    advertised_nets: HashMap<IpAddr, Vec<IpNet>>,
+    subnet: IpNet,
+    my_exported_nets: Vec<IpNet>,
+    forced_relays: HashMap<IpAddr, ForcedRelay>,
+    // Local contacts of the direct peers with the time of reception, and the direct
+    // peers having asked for contacts. Pushes complement the polling of distant nodes.
+    peer_contacts: HashMap<IpAddr, (LocalContactPacket, u64)>,
+    contact_subscribers: HashMap<SocketAddr, u64>,
+    // Next request to a gateway of distant nodes with incomplete contact
+    contact_subscriptions: HashMap<IpAddr, u64>,
+    counters: ManagerCounters,
+    nat_telemetry: NatTelemetry,
+    port_validation: PortValidation,
+    // The endpoints of the peers as per wireguard
+    wg_endpoints: HashMap<String, SocketAddr>,
+    revocations: Vec<Revocation>,
+    routing: RoutingPolicy,
+    // Round trip times of the direct peers
+    next_rtt_ping: u64,
+    // Highest tier of the static peers being contacted, and since when no static
+    // peer of these tiers has been reached
+    bootstrap_tier: u8,
+    bootstrap_tier_since: Option<u64>,
+    // Routes via this node are only offered after gateway_grace seconds with a
+    // reachable direct peer. 0 for a static peer.
+    gateway_grace: u64,
+    gateway_since: Option<u64>,
+    gateway_ready: bool,
 }
 
 impl NetworkManager {
@@ -70,22 +489,301 @@ impl NetworkManager {
             .iter()
             .filter(|(wg_ip, _)| **wg_ip != static_config.wg_ip)
             .map(|(wg_ip, peer)| (*wg_ip, StaticPeer::from_public_peer(peer)))
-            .collect::<HashMap<Ipv4Addr, Box<dyn Node>>>();
+            .collect::<HashMap<IpAddr, Box<dyn Node>>>();
 
-        NetworkManager {
+        let mut mgr = NetworkManager {
             wg_ip: static_config.wg_ip,
             my_visible_wg_endpoint: None,
             my_local_wg_port: static_config.wg_port,
             route_db: RouteDB::default(),
+            routedb_history: VecDeque::new(),
             all_nodes,
+            schedule: BinaryHeap::new(),
+            scheduled_at: HashMap::new(),
+            mesh_events: MeshEventBus::default(),
+            last_route_diff: None,
+            route_withdrawals: HashMap::new(),
+            decrypt_monitor: DecryptMonitor::new(static_config.decrypt_failure_threshold),
+            path_mtu: HashMap::new(),
+            route_attributes: HashMap::new(),
+            pinned_routes: static_config.pinned_routes.clone(),
+            relays: HashMap::new(),
+            identities: HashMap::new(),
+            moved_nodes: HashMap::new(),
+            wg_ip_conflicts: HashMap::new(),
+            advertised_keys: HashMap::new(),
+            #[cfg(feature = "scripting")]
+            policy_script: None,
+            join_approval: static_config
+                .authorized_keys
+                .as_ref()
+                .map(JoinApproval::new),
+            address_leases: AddressLeases::default(),
+            areas: static_config.areas.clone(),
+            my_area: static_config.area_of(&static_config.wg_ip),
+            is_area_border: static_config.is_static,
+            area_gateways: BTreeMap::new(),
+            advertised_nets: HashMap::new(),
+            subnet: static_config.subnet,
+            my_exported_nets: static_config.exported_nets.clone(),
+            forced_relays: HashMap::new(),
+            peer_contacts: HashMap::new(),
+            contact_subscribers: HashMap::new(),
+            contact_subscriptions: HashMap::new(),
+            counters: ManagerCounters::default(),
+            nat_telemetry: NatTelemetry::default(),
+            port_validation: PortValidation::default(),
+            wg_endpoints: HashMap::new(),
+            revocations: vec![],
+            routing: static_config.routing.clone(),
+            next_rtt_ping: 0,
+            bootstrap_tier: static_config
+                .peers
+                .values()
+                .map(|peer| peer.tier)
+                .min()
+                .unwrap_or(DEFAULT_TIER),
+            bootstrap_tier_since: None,
+            gateway_grace: if static_config.is_static {
+                0
+            } else {
+                static_config.gateway_grace
+            },
+            gateway_since: None,
+            gateway_ready: static_config.is_static || static_config.gateway_grace == 0,
+        };
+        mgr.apply_bootstrap_tier(0);
+        mgr.schedule_all_nodes();
+        mgr
+    }
+
+    // Schedule the node to be processed not later than at the given time
+    fn schedule_node(&mut self, wg_ip: IpAddr, at: u64) {
+        match self.scheduled_at.entry(wg_ip) {
+            Entry::Occupied(mut e) => {
+                if *e.get() <= at {
+                    return;
+                }
+                e.insert(at);
+            }
+            Entry::Vacant(e) => {
+                e.insert(at);
+            }
+        }
+        self.schedule.push(Reverse((at, wg_ip)));
+    }
+    fn schedule_all_nodes(&mut self) {
+        let wg_ips = self.all_nodes.keys().copied().collect::<Vec<_>>();
+        for wg_ip in wg_ips {
+            self.schedule_node(wg_ip, 0);
+        }
+    }
+
+    #[cfg(feature = "scripting")]
+    pub fn set_policy_script(&mut self, policy_script: Option<PolicyScript>) {
+        self.policy_script = policy_script;
+    }
+    fn policy_accepts_peer(
+        &self,
+        _advertisement: &AdvertisementPacket,
+        _src_addr: SocketAddr,
+    ) -> bool {
+        #[cfg(feature = "scripting")]
+        if let Some(policy_script) = self.policy_script.as_ref() {
+            return policy_script.accept_peer(_advertisement, _src_addr);
+        }
+        true
+    }
+    fn policy_allows_route(&self, _route: &RouteInfo) -> bool {
+        #[cfg(feature = "scripting")]
+        if let Some(policy_script) = self.policy_script.as_ref() {
+            return policy_script.allow_route(_route);
+        }
+        true
+    }
+    // Only with a select_gateway hook, the candidates are collected
+    fn policy_selects_gateway(&self) -> bool {
+        #[cfg(feature = "scripting")]
+        if let Some(policy_script) = self.policy_script.as_ref() {
+            return policy_script.has_hook("select_gateway");
+        }
+        false
+    }
+    fn policy_gateway(&self, _to: IpAddr, _candidates: &[RouteInfo]) -> Option<IpAddr> {
+        #[cfg(feature = "scripting")]
+        if let Some(policy_script) = self.policy_script.as_ref() {
+            return policy_script.select_gateway(_to, _candidates);
+        }
+        None
+    }
+    // Receive MeshEvents for all changes from now on
+    pub fn subscribe(&mut self) -> std::sync::mpsc::Receiver<MeshEvent> {
+        self.mesh_events.subscribe()
+    }
+    fn snapshot(&self, wg_ip: &IpAddr) -> Option<NodeSnapshot> {
+        self.all_nodes
+            .get(wg_ip)
+            .map(|node| NodeSnapshot::of(node.as_ref()))
+    }
+    fn publish_changes(
+        &mut self,
+        wg_ip: IpAddr,
+        before: Option<NodeSnapshot>,
+        after: Option<NodeSnapshot>,
+    ) {
+        if before == after {
+            return;
+        }
+        let was_up = before.as_ref().map(|s| s.reachable) == Some(true);
+        let is_up = after.as_ref().map(|s| s.reachable) == Some(true);
+        if !was_up && is_up {
+            let name = after.as_ref().and_then(|s| s.name.clone());
+            self.mesh_events.publish(MeshEvent::PeerUp { wg_ip, name });
+        }
+        if let (Some(before), Some(after)) = (before.as_ref(), after.as_ref()) {
+            if let (Some(_), Some(public_key)) = (&before.public_key, &after.public_key) {
+                if before.public_key != after.public_key {
+                    self.mesh_events.publish(MeshEvent::KeyRotated {
+                        wg_ip,
+                        public_key: public_key.clone(),
+                    });
+                }
+            }
+            if before.endpoint != after.endpoint {
+                self.mesh_events.publish(MeshEvent::EndpointChanged {
+                    wg_ip,
+                    endpoint: after.endpoint,
+                });
+            }
+        }
+        if was_up && !is_up {
+            self.mesh_events.publish(MeshEvent::PeerDown { wg_ip });
         }
     }
 
     pub fn db_version(&self) -> usize {
         self.route_db.version
     }
+    pub fn state_snapshot(&self, static_config: &StaticConfiguration) -> StateSnapshot {
+        let mut nodes = self
+            .all_nodes
+            .values()
+            .filter_map(|node| node.state())
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|state| state.wg_ip());
+        let mut snapshot = StateSnapshot::new(static_config);
+        snapshot.wg_ip = self.wg_ip;
+        snapshot.my_local_wg_port = self.my_local_wg_port;
+        snapshot.my_visible_wg_endpoint = self.my_visible_wg_endpoint;
+        snapshot.routedb = RouteDBState::new(
+            self.route_db.version,
+            self.route_db.route_for.values().cloned().collect(),
+        );
+        snapshot.nodes = nodes;
+        snapshot
+    }
+    // Continue with the state of the previous instance. Static peers, which have
+    // been removed from the configuration meanwhile, are dropped.
+    pub fn restore_state(&mut self, now: u64, snapshot: &StateSnapshot) -> BoxResult<()> {
+        self.check_snapshot(snapshot)?;
+        self.my_local_wg_port = snapshot.my_local_wg_port;
+        self.my_visible_wg_endpoint = snapshot.my_visible_wg_endpoint;
+        self.route_db = RouteDB {
+            version: snapshot.routedb.version,
+            route_for: snapshot
+                .routedb
+                .routes
+                .iter()
+                .map(|ri| (ri.to, ri.clone()))
+                .collect(),
+        };
+        self.routedb_history.clear();
+        // The previous instance has been the gateway already
+        self.gateway_since = Some(now.saturating_sub(self.gateway_grace));
+        self.gateway_ready = true;
+        self.restore_nodes(now, &snapshot.nodes);
+        Ok(())
+    }
+    // The state file of a previous run only provides hints, which are verified by
+    // advertisements. The own route database is recalculated, the gateway grace
+    // applies and nodes, which do not show up, time out as usual.
+    pub fn restore_hints(&mut self, now: u64, snapshot: &StateSnapshot) -> BoxResult<()> {
+        self.check_snapshot(snapshot)?;
+        let mut nodes = snapshot.nodes.clone();
+        for state in nodes.iter_mut() {
+            match state {
+                NodeState::Static { is_alive, .. } => *is_alive = false,
+                NodeState::Dynamic { lastseen, .. } => *lastseen = now,
+            }
+        }
+        self.restore_nodes(now, &nodes);
+        info!(target: "state", "Restored {} nodes of the previous run", nodes.len());
+        Ok(())
+    }
+    fn check_snapshot(&self, snapshot: &StateSnapshot) -> BoxResult<()> {
+        if snapshot.wg_ip != self.wg_ip {
+            return Err(format!(
+                "State snapshot is for {} and not {}",
+                snapshot.wg_ip, self.wg_ip
+            )
+            .into());
+        }
+        Ok(())
+    }
+    fn restore_nodes(&mut self, now: u64, nodes: &[NodeState]) {
+        for state in nodes.iter() {
+            let wg_ip = state.wg_ip();
+            match state {
+                NodeState::Static { .. } => {
+                    if let Some(node) = self.all_nodes.get_mut(&wg_ip) {
+                        node.restore_state(state);
+                    }
+                }
+                NodeState::Dynamic { .. } => {
+                    if wg_ip == self.wg_ip || self.all_nodes.contains_key(&wg_ip) {
+                        continue;
+                    }
+                    if let Some(dp) = DynamicPeer::from_state(now, state) {
+                        self.all_nodes.insert(wg_ip, Box::new(dp));
+                    }
+                }
+            }
+        }
+        self.schedule_all_nodes();
+    }
     pub fn stats(&self) {
         trace!("Manager: {} nodes in network", self.all_nodes.len(),);
+        let failures = self.decrypt_monitor.total_failures();
+        if failures > 0 {
+            info!(target: "udp", "{} packets could not be decrypted", failures);
+            for hint in self.decrypt_monitor.hints() {
+                warn!(target: "udp", "{}", hint);
+            }
+        }
+    }
+    pub fn counters(&self) -> ManagerCounters {
+        self.counters
+    }
+    pub fn nat_telemetry(&self) -> &NatTelemetry {
+        &self.nat_telemetry
+    }
+    pub fn port_validation(&self) -> &PortValidation {
+        &self.port_validation
+    }
+    pub fn decrypt_monitor(&self) -> &DecryptMonitor {
+        &self.decrypt_monitor
+    }
+    pub fn decrypt_failure(
+        &mut self,
+        now: u64,
+        src_addr: SocketAddr,
+        peers: &HashMap<IpAddr, PublicPeer>,
+    ) -> Option<KeyMismatchHint> {
+        self.decrypt_monitor.record_failure(now, src_addr, peers)
+    }
+    pub fn decrypt_success(&mut self, now: u64, src_addr: SocketAddr) {
+        self.decrypt_monitor.record_success(src_addr);
+        self.port_validation.observed_admin(now, src_addr);
     }
     pub fn analyze_advertisement(
         &mut self,
@@ -94,14 +792,62 @@ impl NetworkManager {
         advertisement: AdvertisementPacket,
         src_addr: SocketAddr,
     ) -> Vec<Event> {
+        self.counters.advertisements_received += 1;
+        if !self.authenticate(now, static_config, &advertisement, src_addr) {
+            return vec![];
+        }
+        let (accepted, mut conflict_events) =
+            self.key_flap(now, static_config, &advertisement, src_addr);
+        if !accepted {
+            return conflict_events;
+        }
+        let next_key = advertisement
+            .next_public_key
+            .as_ref()
+            .map(|k| k.key.as_str());
+        if self.is_revoked(
+            Some(&advertisement.public_key.key),
+            Some(&advertisement.identity),
+        ) || self.is_revoked(next_key, None)
+        {
+            warn!(target: "revocation", "Advertisement of revoked {} from {} => ignored", advertisement.wg_ip, src_addr);
+            return vec![];
+        }
+        if !self.is_approved(now, static_config, &advertisement, src_addr) {
+            return vec![];
+        }
+        if !self.policy_accepts_peer(&advertisement, src_addr) {
+            debug!(target: "policy", "Advertisement of {} from {} rejected by the policy script", advertisement.wg_ip, src_addr);
+            return vec![];
+        }
+        let revoked = self.learn_revocations(static_config, &advertisement.revocations);
+        let moved = advertisement
+            .moved_from
+            .map(|previous| self.node_moved(now, previous, &advertisement))
+            .unwrap_or(false);
         if let Some(endpoint) = advertisement.your_visible_wg_endpoint.as_ref() {
             // Could be more than one
-            self.my_visible_wg_endpoint = Some(*endpoint);
+            if self.my_visible_wg_endpoint != Some(*endpoint) {
+                self.my_visible_wg_endpoint = Some(*endpoint);
+                for node in self.all_nodes.values_mut() {
+                    node.set_own_visible_endpoint(*endpoint);
+                }
+            }
         }
 
-        match self.all_nodes.entry(advertisement.wg_ip) {
+        let wg_ip = advertisement.wg_ip;
+        let request_contact = matches!(advertisement.addressed_to, AddressedTo::WireguardAddress)
+            && self.needs_contact_of(now, &wg_ip);
+        let relay_changed = self.relay_requested_by_peer(now, wg_ip, advertisement.force_relay_s);
+        let exported_nets = self.acceptable_nets(&advertisement.exported_nets);
+        let nets_changed = self.advertised_nets.get(&wg_ip) != Some(&exported_nets);
+        self.advertised_nets.insert(wg_ip, exported_nets);
+        self.schedule_node(wg_ip, 0);
+        let before = self.snapshot(&wg_ip);
+        let mut events = match self.all_nodes.entry(wg_ip) {
             Entry::Occupied(mut entry) => {
-                let now = crate::util::now();
+                let progress = entry.get().traversal();
+                let addressed_to = advertisement.addressed_to.clone();
                 let (opt_new_entry, events) = entry.get_mut().analyze_advertisement(
                     now,
                     static_config,
@@ -109,6 +855,10 @@ impl NetworkManager {
                     src_addr,
                 );
                 if let Some(new_entry) = opt_new_entry {
+                    if let Some(progress) = progress.filter(|_| !new_entry.is_distant_node()) {
+                        self.nat_telemetry
+                            .success(now, wg_ip, progress, &addressed_to);
+                    }
                     entry.insert(new_entry);
                 }
                 events
@@ -138,25 +888,750 @@ impl NetworkManager {
 
                 events
             }
+        };
+        let after = self.snapshot(&wg_ip);
+        self.publish_changes(wg_ip, before, after);
+        if nets_changed {
+            info!(target: "routing", "Exported networks of {} changed", wg_ip);
+        }
+        if (nets_changed || relay_changed || revoked || moved)
+            && !events.iter().any(|e| matches!(e, Event::UpdateRoutes))
+        {
+            events.push(Event::UpdateRoutes);
+        }
+        if moved
+            && !events
+                .iter()
+                .any(|e| matches!(e, Event::UpdateWireguardConfiguration))
+        {
+            events.push(Event::UpdateWireguardConfiguration);
+        }
+        if request_contact {
+            debug!(target: "probing", "Request local contact of {} for push", wg_ip);
+            events.push(Event::SendLocalContactRequest { to: src_addr });
+        }
+        events.append(&mut conflict_events);
+        events
+    }
+    // The previous node is only forgotten, if it has had the same identity
+    fn node_moved(
+        &mut self,
+        now: u64,
+        previous: IpAddr,
+        advertisement: &AdvertisementPacket,
+    ) -> bool {
+        let wg_ip = advertisement.wg_ip;
+        if previous == wg_ip
+            || previous == self.wg_ip
+            || self.identities.get(&previous) != Some(&advertisement.identity)
+        {
+            return false;
+        }
+        info!(target: "advertisement", "{} has moved to {}", previous, wg_ip);
+        self.forget_node(previous);
+        self.moved_nodes
+            .insert(wg_ip, (previous, now + READDRESS_GRACE));
+        self.mesh_events.publish(MeshEvent::PeerMoved {
+            from: previous,
+            to: wg_ip,
+        });
+        true
+    }
+    // The previous wg_ip of a moved node during the grace period
+    pub fn previous_wg_ip_of(&self, wg_ip: &IpAddr) -> Option<IpAddr> {
+        self.moved_nodes.get(wg_ip).map(|(previous, _)| *previous)
+    }
+    // The own wg_ip has changed. A node known with this wg_ip is outdated. The direct
+    // peers learn the new wg_ip right away.
+    pub fn readdress(
+        &mut self,
+        static_config: &StaticConfiguration,
+        previous: IpAddr,
+    ) -> Vec<Event> {
+        self.wg_ip = static_config.wg_ip;
+        self.my_area = static_config.area_of(&self.wg_ip);
+        if self.all_nodes.contains_key(&self.wg_ip) {
+            self.forget_node(self.wg_ip);
+        }
+        let mut events = vec![Event::Readdress { previous }];
+        for (wg_ip, node) in self.all_nodes.iter() {
+            if !node.is_distant_node() {
+                events.push(Event::SendAdvertisement {
+                    addressed_to: AddressedTo::WireguardAddress,
+                    to: SocketAddr::new(*wg_ip, node.local_admin_port()),
+                    wg_ip: *wg_ip,
+                });
+            }
+        }
+        events.push(Event::UpdateWireguardConfiguration);
+        events
+    }
+    // This node has taken over as active node of a hub pair
+    pub fn readvertise(&mut self) {
+        for node in self.all_nodes.values_mut() {
+            node.readvertise();
+        }
+        self.schedule_all_nodes();
+    }
+    // A static peer leases an address to a node with `wgIp: auto`
+    pub fn process_address_request(
+        &mut self,
+        now: u64,
+        static_config: &StaticConfiguration,
+        request: AddressRequestPacket,
+        src_addr: SocketAddr,
+    ) -> Vec<Event> {
+        if !static_config.peers.contains_key(&self.wg_ip) {
+            debug!(target: "lease", "Not a static peer => ignore AddressRequest from {}", src_addr);
+            return vec![];
+        }
+        let in_use = |wg_ip: &IpAddr| {
+            *wg_ip == self.wg_ip
+                || static_config.peers.contains_key(wg_ip)
+                || static_config.previous_wg_ip.as_ref().map(|p| p.wg_ip) == Some(*wg_ip)
+                || self
+                    .moved_nodes
+                    .values()
+                    .any(|(previous, _)| previous == wg_ip)
+                || ((self.all_nodes.contains_key(wg_ip)
+                    || self.route_db.route_for.contains_key(wg_ip))
+                    && self.identities.get(wg_ip) != Some(&request.identity))
+        };
+        match self
+            .address_leases
+            .offer(now, &static_config.subnet, &request, in_use)
+        {
+            Some(lease) => {
+                info!(target: "lease", "Lease {} to {}", lease.wg_ip, src_addr);
+                vec![Event::SendAddressLease {
+                    to: src_addr,
+                    lease,
+                }]
+            }
+            None => {
+                warn!(target: "lease", "No free address for {}", src_addr);
+                vec![]
+            }
+        }
+    }
+    // Only needed, if there is someone to push to
+    fn needs_contact_of(&self, now: u64, wg_ip: &IpAddr) -> bool {
+        let outdated = match self.peer_contacts.get(wg_ip) {
+            Some((_, received)) => now >= received + CONTACT_REFRESH,
+            None => true,
+        };
+        outdated
+            && self
+                .contact_subscribers
+                .iter()
+                .any(|(sa, since)| sa.ip() != *wg_ip && now < since + CONTACT_SUBSCRIPTION)
+    }
+    // A direct peer has asked for the local contact via the tunnel. The already known
+    // contacts of the other direct peers are pushed to a new subscriber.
+    pub fn local_contact_requested(&mut self, now: u64, src_addr: SocketAddr) -> Vec<Event> {
+        let is_direct_peer = self
+            .all_nodes
+            .get(&src_addr.ip())
+            .map(|node| !node.is_distant_node())
+            .unwrap_or(false);
+        if !is_direct_peer {
+            return vec![];
+        }
+        let is_new = self
+            .contact_subscribers
+            .insert(src_addr, now)
+            .map(|since| now >= since + CONTACT_SUBSCRIPTION)
+            .unwrap_or(true);
+        if !is_new {
+            return vec![];
+        }
+        self.peer_contacts
+            .keys()
+            .filter(|wg_ip| **wg_ip != src_addr.ip())
+            .map(|wg_ip| Event::SendLocalContactOf {
+                wg_ip: *wg_ip,
+                to: src_addr,
+            })
+            .collect()
+    }
+    pub fn local_contact_of(&self, wg_ip: &IpAddr) -> Option<&LocalContactPacket> {
+        self.peer_contacts.get(wg_ip).map(|(local, _)| local)
+    }
+    // A node of the own mesh has been found on a local network, e.g. via mDNS. Unless
+    // already in contact, it is advertised to via the local address. Whether the node
+    // is joined, is decided by the shared key as usual.
+    pub fn local_peer_discovered(
+        &self,
+        wg_ip: IpAddr,
+        admin_endpoints: &[SocketAddr],
+    ) -> Vec<Event> {
+        if wg_ip == self.wg_ip {
+            return vec![];
+        }
+        let in_contact = self
+            .all_nodes
+            .get(&wg_ip)
+            .map(|node| !node.is_distant_node() && node.is_reachable())
+            .unwrap_or(false);
+        if in_contact {
+            return vec![];
+        }
+        admin_endpoints
+            .iter()
+            .map(|to| Event::SendAdvertisement {
+                addressed_to: AddressedTo::LocalAddress,
+                to: *to,
+                wg_ip,
+            })
+            .collect()
+    }
+    // Follow the request of a peer to relay, unless forced locally
+    fn relay_requested_by_peer(&mut self, now: u64, wg_ip: IpAddr, force_relay_s: u64) -> bool {
+        match self.forced_relays.get(&wg_ip).copied() {
+            Some(relay) if !relay.by_peer => false,
+            Some(_) if force_relay_s == 0 => {
+                info!(target: "routing", "{} has stopped to force relay", wg_ip);
+                self.forced_relays.remove(&wg_ip);
+                true
+            }
+            None if force_relay_s == 0 => false,
+            current => {
+                let relay = ForcedRelay {
+                    until: now + force_relay_s,
+                    by_peer: true,
+                };
+                if current.is_none() {
+                    info!(target: "routing", "{} forces relay for {}s", wg_ip, force_relay_s);
+                }
+                self.forced_relays.insert(wg_ip, relay);
+                current.is_none()
+            }
+        }
+    }
+    // Route the traffic to a direct peer via a gateway until the given time, or
+    // use the direct path again with None. Returns true on change.
+    // The routes are updated by the next get_route_changes().
+    pub fn force_relay(&mut self, wg_ip: IpAddr, until: Option<u64>) -> bool {
+        match until {
+            Some(until) => {
+                let relay = ForcedRelay {
+                    until,
+                    by_peer: false,
+                };
+                info!(target: "routing", "Force relay for {} until {}", wg_ip, until);
+                self.forced_relays.insert(wg_ip, relay) != Some(relay)
+            }
+            None => self.forced_relays.remove(&wg_ip).is_some(),
+        }
+    }
+    // For the advertisement to the peer: 0, if the relay has not been forced locally
+    pub fn force_relay_request(&self, now: u64, wg_ip: &IpAddr) -> u64 {
+        self.forced_relays
+            .get(wg_ip)
+            .filter(|relay| !relay.by_peer)
+            .map(|relay| relay.until.saturating_sub(now).max(1))
+            .unwrap_or(0)
+    }
+    fn acceptable_nets(&self, nets: &[IpNet]) -> Vec<IpNet> {
+        acceptable_nets(nets, &self.subnet, &self.my_exported_nets)
+    }
+    // The advertisement needs to be signed by the identity of the claimed wg_ip:
+    // either the pinned one of a static peer, or the one bound to a reachable node.
+    fn authenticate(
+        &mut self,
+        now: u64,
+        static_config: &StaticConfiguration,
+        advertisement: &AdvertisementPacket,
+        src_addr: SocketAddr,
+    ) -> bool {
+        let wg_ip = advertisement.wg_ip;
+        if !advertisement.has_valid_signature() {
+            warn!(target: "advertisement", "Advertisement for {} from {} with invalid signature => ignored", wg_ip, src_addr);
+            return false;
+        }
+        let identity = advertisement.identity;
+        if let Some(conflict) = self.wg_ip_conflicts.get_mut(&wg_ip) {
+            // Clones may share the identity, but not the key
+            if conflict.quarantined_identity == identity
+                && conflict.kept_key != advertisement.public_key.key
+            {
+                conflict.until = now + CONFLICT_QUARANTINE;
+                debug!(target: "conflict", "Advertisement for {} from {} is quarantined => ignored", wg_ip, src_addr);
+                return false;
+            }
+        }
+        // Not even the own identity, which is e.g. shared by a warm-spare pair
+        if wg_ip == self.wg_ip {
+            let own = static_config.identity.public();
+            if identity != own {
+                self.wg_ip_conflict(
+                    now,
+                    wg_ip,
+                    &static_config.my_public_key.key,
+                    advertisement,
+                    src_addr,
+                );
+            }
+            return false;
+        }
+        let pinned = static_config.peers.get(&wg_ip).and_then(|p| p.identity);
+        if pinned.map(|pinned| pinned != identity) == Some(true) {
+            warn!(target: "advertisement", "Advertisement for {} from {} signed by another identity => ignored", wg_ip, src_addr);
+            return false;
+        }
+        let bound = self
+            .identities
+            .get(&wg_ip)
+            .copied()
+            .filter(|bound| pinned.is_none() && *bound != identity)
+            .filter(|_| self.all_nodes.get(&wg_ip).map(|n| n.is_reachable()) == Some(true));
+        // The bound identity is never replaced by another one, while the node is reachable
+        if bound.is_some() {
+            let known_key = self
+                .all_nodes
+                .get(&wg_ip)
+                .and_then(|node| node.public_key())
+                .map(|key| key.key.clone());
+            if let Some(known_key) = known_key {
+                self.wg_ip_conflict(now, wg_ip, &known_key, advertisement, src_addr);
+            }
+            warn!(target: "advertisement", "Advertisement for {} from {} signed by another identity => ignored", wg_ip, src_addr);
+            return false;
+        }
+        if self.identities.insert(wg_ip, identity) != Some(identity) {
+            info!(target: "advertisement", "Identity of {} is {}", wg_ip, base64::encode(identity));
+        }
+        true
+    }
+    // The advertised key is quarantined in favour of the kept one
+    fn wg_ip_conflict(
+        &mut self,
+        now: u64,
+        wg_ip: IpAddr,
+        kept_key: &str,
+        advertisement: &AdvertisementPacket,
+        src_addr: SocketAddr,
+    ) {
+        let conflict = WgIpConflict {
+            kept_key: kept_key.to_string(),
+            quarantined_key: advertisement.public_key.key.clone(),
+            quarantined_identity: advertisement.identity,
+            since: now,
+            until: now + CONFLICT_QUARANTINE,
+        };
+        self.record_wg_ip_conflict(wg_ip, conflict, src_addr);
+    }
+    fn record_wg_ip_conflict(
+        &mut self,
+        wg_ip: IpAddr,
+        conflict: WgIpConflict,
+        src_addr: SocketAddr,
+    ) {
+        match self.wg_ip_conflicts.get_mut(&wg_ip) {
+            Some(known) if known.quarantined_key == conflict.quarantined_key => {
+                known.until = conflict.until;
+            }
+            _ => {
+                error!(target: "conflict", "wg_ip {} is used by two nodes, advertised from {}: key {} is kept, key {} is quarantined",
+                    wg_ip, src_addr, conflict.kept_key, conflict.quarantined_key);
+                self.wg_ip_conflicts.insert(wg_ip, conflict);
+            }
+        }
+    }
+    // A rotated key is never advertised again. So if the advertised keys of a wg_ip
+    // alternate, two nodes use the same wg_ip, e.g. a cloned VM with the same
+    // peer.yaml. Then the key advertised first is kept, and both nodes are told with
+    // an AddressConflict. Returns, whether the advertisement is accepted.
+    fn key_flap(
+        &mut self,
+        now: u64,
+        static_config: &StaticConfiguration,
+        advertisement: &AdvertisementPacket,
+        src_addr: SocketAddr,
+    ) -> (bool, Vec<Event>) {
+        let wg_ip = advertisement.wg_ip;
+        let keys = self.advertised_keys.entry(wg_ip).or_default();
+        while keys.front().is_some_and(|k| now >= k.at + KEY_FLAP_WINDOW) {
+            keys.pop_front();
+        }
+        if keys.back().map(|k| &k.key.key) == Some(&advertisement.public_key.key) {
+            return (true, vec![]);
+        }
+        let alternates = keys
+            .iter()
+            .any(|k| k.key.key == advertisement.public_key.key);
+        keys.push_back(AdvertisedKey {
+            at: now,
+            key: advertisement.public_key.clone(),
+            identity: advertisement.identity,
+            src_addr,
+        });
+        if !alternates || keys.len() <= KEY_FLAP_SWITCHES {
+            return (true, vec![]);
+        }
+        let mut keys = self.advertised_keys.remove(&wg_ip).unwrap_or_default();
+        keys.pop_back();
+        let Some(previous) = keys.pop_back() else {
+            return (true, vec![]);
+        };
+        // The key advertised first within the window is kept. A key missing in the
+        // earlier ones has been seen last.
+        let first_seen = |key: &str| {
+            keys.iter()
+                .position(|k| k.key.key == key)
+                .unwrap_or(usize::MAX)
+        };
+        let accepted = first_seen(&advertisement.public_key.key) < first_seen(&previous.key.key);
+        let advertised = (&advertisement.public_key.key, advertisement.identity);
+        let previous_key = (&previous.key.key, previous.identity);
+        let (kept, quarantined) = if accepted {
+            (advertised, previous_key)
+        } else {
+            (previous_key, advertised)
+        };
+        let conflict = WgIpConflict {
+            kept_key: kept.0.clone(),
+            quarantined_key: quarantined.0.clone(),
+            quarantined_identity: quarantined.1,
+            since: now,
+            until: now + CONFLICT_QUARANTINE,
+        };
+        self.record_wg_ip_conflict(wg_ip, conflict, src_addr);
+        let conflict = &self.wg_ip_conflicts[&wg_ip];
+        let packet = AddressConflictPacket {
+            wg_ip,
+            kept_key: conflict.kept_key.clone(),
+            quarantined_key: conflict.quarantined_key.clone(),
+        };
+        let mut claimants = vec![previous.src_addr, src_addr];
+        claimants.dedup();
+        let events = claimants
+            .into_iter()
+            .map(|to| Event::SendAddressConflict {
+                to,
+                conflict: packet.clone(),
+            })
+            .collect();
+        // The node may know the quarantined key already. It accepts only newer keys,
+        // so start afresh.
+        let known_key = self
+            .all_nodes
+            .get(&wg_ip)
+            .and_then(|node| node.public_key());
+        if known_key.is_some_and(|k| k.key != packet.kept_key) {
+            self.forget_node(wg_ip);
+            if let Some(peer) = static_config.peers.get(&wg_ip) {
+                self.all_nodes
+                    .insert(wg_ip, StaticPeer::from_public_peer(peer));
+            }
         }
+        (accepted, events)
+    }
+    // Another node has detected, that the own wg_ip is used by a second node
+    pub fn process_address_conflict(
+        &mut self,
+        now: u64,
+        static_config: &StaticConfiguration,
+        packet: AddressConflictPacket,
+        src_addr: SocketAddr,
+    ) {
+        let own_key = &static_config.my_public_key.key;
+        if packet.wg_ip != self.wg_ip
+            || (packet.kept_key != *own_key && packet.quarantined_key != *own_key)
+        {
+            debug!(target: "conflict", "AddressConflict from {} not about this node => ignored", src_addr);
+            return;
+        }
+        error!(target: "conflict", "{} reports, that wg_ip {} is used by two nodes, e.g. with a cloned peer.yaml: key {} is kept, key {} is quarantined",
+            src_addr, packet.wg_ip, packet.kept_key, packet.quarantined_key);
+        if packet.quarantined_key == *own_key {
+            error!(target: "conflict", "This node is quarantined. Please change its wgIp !");
+        }
+        // Advertisements for the own wg_ip are ignored anyway, so the identity of
+        // the other node is not needed
+        let conflict = self
+            .wg_ip_conflicts
+            .entry(packet.wg_ip)
+            .or_insert(WgIpConflict {
+                kept_key: packet.kept_key,
+                quarantined_key: packet.quarantined_key,
+                quarantined_identity: static_config.identity.public(),
+                since: now,
+                until: now,
+            });
+        conflict.until = now + CONFLICT_QUARANTINE;
+    }
+    // The static peers are approved by network.yaml
+    fn is_approved(
+        &mut self,
+        now: u64,
+        static_config: &StaticConfiguration,
+        advertisement: &AdvertisementPacket,
+        src_addr: SocketAddr,
+    ) -> bool {
+        let Some(approval) = self.join_approval.as_mut() else {
+            return true;
+        };
+        if static_config.peers.contains_key(&advertisement.wg_ip)
+            || approval.is_authorized(&advertisement.identity)
+        {
+            return true;
+        }
+        approval.park(now, advertisement, src_addr);
+        false
+    }
+    pub fn pending_peers(&self) -> Vec<PendingPeer> {
+        self.join_approval
+            .as_ref()
+            .map(|approval| approval.pending())
+            .unwrap_or_default()
+    }
+    // The approved node is advertised to right away
+    pub fn approve_peer(&mut self, peer: &str) -> BoxResult<(PendingPeer, Vec<Event>)> {
+        let Some(approval) = self.join_approval.as_mut() else {
+            return strerror("No authorized keys configured");
+        };
+        let (pending, addressed_to) = approval.approve(peer)?;
+        let events = vec![Event::SendAdvertisement {
+            addressed_to,
+            to: pending.endpoint,
+            wg_ip: pending.wg_ip,
+        }];
+        Ok((pending, events))
+    }
+    // Returns the number of authorized identities
+    pub fn reload_authorized_keys(&mut self) -> BoxResult<Option<usize>> {
+        self.join_approval
+            .as_mut()
+            .map(|approval| approval.reload())
+            .transpose()
+    }
+    pub fn wg_ip_conflicts(&self) -> &HashMap<IpAddr, WgIpConflict> {
+        &self.wg_ip_conflicts
     }
     pub fn process_all_nodes_every_second(
         &mut self,
         now: u64,
         static_config: &StaticConfiguration,
+    ) -> Vec<Event> {
+        let mut events = self.process_timeouts(now);
+        events.append(&mut self.process_due_nodes(now, static_config));
+        events
+    }
+    // Static peers above the bootstrap tier are in standby
+    fn apply_bootstrap_tier(&mut self, now: u64) {
+        let mut woken_up = vec![];
+        for (wg_ip, node) in self.all_nodes.iter_mut() {
+            if let Some(tier) = node.tier() {
+                let standby = tier > self.bootstrap_tier;
+                if !standby {
+                    woken_up.push(*wg_ip);
+                }
+                node.set_standby(standby);
+            }
+        }
+        for wg_ip in woken_up {
+            self.schedule_node(wg_ip, now);
+        }
+    }
+    // The static peers are contacted tier by tier. If none of the contacted tiers is
+    // reached within TIER_FALLBACK, the next higher tier is contacted, too. As soon as
+    // a static peer of the lowest tiers is alive, the higher tiers go to standby again.
+    fn update_bootstrap_tier(&mut self, now: u64) {
+        let tiers = self
+            .all_nodes
+            .values()
+            .filter_map(|node| node.tier())
+            .collect::<BTreeSet<_>>();
+        let lowest_alive = self
+            .all_nodes
+            .values()
+            .filter(|node| node.is_reachable())
+            .filter_map(|node| node.tier())
+            .min();
+        let since = *self.bootstrap_tier_since.get_or_insert(now);
+        let tier = match lowest_alive {
+            Some(alive) if alive <= self.bootstrap_tier => {
+                self.bootstrap_tier_since = Some(now);
+                alive
+            }
+            _ if now >= since + TIER_FALLBACK => {
+                self.bootstrap_tier_since = Some(now);
+                tiers
+                    .range(self.bootstrap_tier.saturating_add(1)..)
+                    .next()
+                    .copied()
+                    .unwrap_or(self.bootstrap_tier)
+            }
+            _ => self.bootstrap_tier,
+        };
+        if tier != self.bootstrap_tier {
+            info!(target: "probing", "contact static peers up to tier {}", tier);
+            self.bootstrap_tier = tier;
+            self.apply_bootstrap_tier(now);
+        }
+    }
+    pub fn bootstrap_tier(&self) -> u8 {
+        self.bootstrap_tier
+    }
+    // Expiry of forced relays and contact subscriptions, the pings for the cost, the
+    // bootstrap tier and the relays
+    pub fn process_timeouts(&mut self, now: u64) -> Vec<Event> {
+        let mut events = vec![];
+        self.update_bootstrap_tier(now);
+        self.update_gateway_ready(now);
+        if let Some(approval) = self.join_approval.as_mut() {
+            approval.expire(now);
+        }
+        self.port_validation.expire(now);
+        self.wg_ip_conflicts.retain(|wg_ip, conflict| {
+            let active = now < conflict.until;
+            if !active {
+                info!(target: "conflict", "Quarantine of key {} for {} has ended", conflict.quarantined_key, wg_ip);
+            }
+            active
+        });
+        self.advertised_keys
+            .retain(|_, keys| keys.back().is_some_and(|k| now < k.at + KEY_FLAP_WINDOW));
+        if self.select_relays(now) {
+            events.push(Event::UpdateRoutes);
+        }
+        if now >= self.next_rtt_ping {
+            self.next_rtt_ping = now + RTT_PING_INTERVAL;
+            for to in self.direct_peer_admin_addresses() {
+                events.push(Event::SendPing { to });
+            }
+        }
+        let nr_forced_relays = self.forced_relays.len();
+        self.forced_relays.retain(|wg_ip, relay| {
+            let keep = relay.until > now;
+            if !keep {
+                info!(target: "routing", "Forced relay for {} expired", wg_ip);
+            }
+            keep
+        });
+        if self.forced_relays.len() != nr_forced_relays {
+            events.push(Event::UpdateRoutes);
+        }
+        self.contact_subscribers
+            .retain(|_, since| now < *since + CONTACT_SUBSCRIPTION);
+        let nr_moved_nodes = self.moved_nodes.len();
+        self.moved_nodes.retain(|_, (_, until)| *until > now);
+        if self.moved_nodes.len() != nr_moved_nodes {
+            events.push(Event::UpdateWireguardConfiguration);
+        }
+        events
+    }
+    // The warm-up restarts, if no direct peer is reachable
+    fn update_gateway_ready(&mut self, now: u64) {
+        if self.gateway_grace == 0 {
+            return;
+        }
+        let connected = self
+            .all_nodes
+            .values()
+            .any(|node| !node.is_distant_node() && node.is_reachable());
+        if connected {
+            self.gateway_since.get_or_insert(now);
+        } else {
+            self.gateway_since = None;
+        }
+        let ready = self
+            .gateway_since
+            .is_some_and(|since| now >= since + self.gateway_grace);
+        if ready == self.gateway_ready {
+            return;
+        }
+        if ready {
+            info!(target: "routing", "Warm-up of {}s has ended => offer routes as gateway", self.gateway_grace);
+        } else {
+            info!(target: "routing", "No direct peer is reachable => withhold routes as gateway");
+        }
+        self.gateway_ready = ready;
+        // The new version makes the peers request the route database. Without
+        // history, the full database is provided.
+        self.route_db.version += 1;
+        self.routedb_history.clear();
+        self.routedb_history.push_back(self.route_db.clone());
+    }
+    pub fn gateway_ready(&self) -> bool {
+        self.gateway_ready
+    }
+    // Unix time of the earliest scheduled node, if any
+    pub fn next_due(&mut self) -> Option<u64> {
+        while let Some(Reverse((at, node_wg_ip))) = self.schedule.peek().copied() {
+            if self.scheduled_at.get(&node_wg_ip) == Some(&at) {
+                return Some(at);
+            }
+            // outdated entry
+            self.schedule.pop();
+        }
+        None
+    }
+    // Only the nodes, whose processing time has come
+    pub fn process_due_nodes(
+        &mut self,
+        now: u64,
+        static_config: &StaticConfiguration,
     ) -> Vec<Event> {
         let mut events = vec![];
+        let mut gateways_to_ask = vec![];
         let mut node_to_delete = vec![];
-        for (node_wg_ip, node) in self.all_nodes.iter_mut() {
+        while let Some(Reverse((at, node_wg_ip))) = self.schedule.peek().copied() {
+            if at > now {
+                break;
+            }
+            self.schedule.pop();
+            if self.scheduled_at.get(&node_wg_ip) != Some(&at) {
+                // outdated entry
+                continue;
+            }
+            self.scheduled_at.remove(&node_wg_ip);
+            let node = match self.all_nodes.get_mut(&node_wg_ip) {
+                Some(node) => node,
+                None => continue,
+            };
             //    if !self.route_db.route_for.contains_key(node_wg_ip) {
             // have no route to this peer
             if node.ok_to_delete_without_route(now) {
-                node_to_delete.push(*node_wg_ip);
+                node_to_delete.push(node_wg_ip);
                 continue;
             }
             //    }
+            let before = NodeSnapshot::of(node.as_ref());
             let mut new_events = node.process_every_second(now, static_config);
+            if node.wants_local_contact() {
+                let polled = new_events
+                    .iter()
+                    .any(|evt| matches!(evt, Event::SendLocalContactRequest { .. }));
+                if let Some(gateway) = node.get_gateway().filter(|_| polled) {
+                    gateways_to_ask.push(gateway);
+                }
+            }
             events.append(&mut new_events);
+            let next = node.next_processing_time(now).max(now + 1);
+            let after = NodeSnapshot::of(node.as_ref());
+            self.schedule_node(node_wg_ip, next);
+            self.publish_changes(node_wg_ip, Some(before), Some(after));
+        }
+
+        // Subscribe at the gateway for a push of the missing contact
+        for gateway in gateways_to_ask {
+            if self
+                .contact_subscriptions
+                .get(&gateway)
+                .copied()
+                .unwrap_or(0)
+                > now
+            {
+                continue;
+            }
+            if let Some(node) = self.all_nodes.get(&gateway) {
+                self.contact_subscriptions
+                    .insert(gateway, now + CONTACT_SUBSCRIPTION_RENEWAL);
+                let destination = SocketAddr::new(gateway, node.local_admin_port());
+                events.push(Event::SendLocalContactRequest { to: destination });
+            }
         }
 
         if !node_to_delete.is_empty() {
@@ -166,118 +1641,634 @@ impl NetworkManager {
             for wg_ip in node_to_delete {
                 debug!(target: &wg_ip.to_string(), "is dead => remove");
                 debug!(target: "dead_peer", "Found dead peer {}", wg_ip);
+                let before = self.snapshot(&wg_ip);
                 self.all_nodes.remove(&wg_ip);
+                self.scheduled_at.remove(&wg_ip);
+                self.publish_changes(wg_ip, before, None);
             }
         }
 
         events
     }
-    pub fn provide_route_database(&self) -> Vec<UdpPacket> {
-        let mut known_routes = vec![];
-        for ri in self.route_db.route_for.values() {
-            known_routes.push(ri);
+    // latest_handshakes as reported by WireguardDevice::latest_handshakes()
+    pub fn check_handshakes(
+        &mut self,
+        now: u64,
+        static_config: &StaticConfiguration,
+        latest_handshakes: &HashMap<String, u64>,
+    ) -> Vec<Event> {
+        let mut events = vec![];
+        let mut changed = vec![];
+        for (wg_ip, node) in self.all_nodes.iter_mut() {
+            let latest_handshake = node
+                .public_key()
+                .and_then(|public_key| latest_handshakes.get(&public_key.key))
+                .copied();
+            if let Some(latest_handshake) = latest_handshake {
+                // A recent handshake confirms the wireguard endpoint
+                let endpoint = node
+                    .public_key()
+                    .and_then(|public_key| self.wg_endpoints.get(&public_key.key));
+                if let Some(endpoint) = endpoint {
+                    if now.saturating_sub(latest_handshake) <= STALE_HANDSHAKE {
+                        self.port_validation
+                            .confirmed_wireguard(latest_handshake, *endpoint);
+                    }
+                }
+                let mut node_events = node.check_handshake(now, static_config, latest_handshake);
+                if !node_events.is_empty() {
+                    changed.push(*wg_ip);
+                }
+                events.append(&mut node_events);
+            }
         }
-        let p = UdpPacket::make_route_database(
+        // A static peer may have switched to another wg port
+        for wg_ip in changed {
+            self.schedule_node(wg_ip, 0);
+        }
+        events
+    }
+    // A node in an area gets only the routes of its area. An area border node adds
+    // one summary per other area instead of the routes to its nodes.
+    pub fn provide_route_database(&self, requester: IpAddr) -> Vec<UdpPacket> {
+        let known_routes = self.routes_for_requester(requester, &self.route_db);
+        UdpPacket::make_route_database(
             self.wg_ip,
             self.route_db.version,
             known_routes.len(),
+            known_routes.iter().collect(),
+        )
+    }
+    // Only the changes since the requester's version, if this version is still in the
+    // history. Otherwise the full database.
+    pub fn provide_route_database_delta(&self, requester: IpAddr, since: usize) -> Vec<UdpPacket> {
+        let base = match self.routedb_history.iter().find(|db| db.version == since) {
+            Some(base) => base,
+            None => {
+                debug!(target: "routing", "Version {} of route db is unknown, so send full db to {}", since, requester);
+                return self.provide_route_database(requester);
+            }
+        };
+        let old_routes = self
+            .routes_for_requester(requester, base)
+            .into_iter()
+            .map(|ri| (ri.to, ri))
+            .collect::<HashMap<_, _>>();
+        let new_routes = self.routes_for_requester(requester, &self.route_db);
+        let removed_routes = old_routes
+            .keys()
+            .filter(|to| !new_routes.iter().any(|ri| ri.to == **to))
+            .copied()
+            .collect();
+        let nr_entries = new_routes.len();
+        let changed_routes: Vec<RouteInfo> = new_routes
+            .into_iter()
+            .filter(|ri| old_routes.get(&ri.to) != Some(ri))
+            .collect();
+        let delta = RouteDatabaseDeltaPacket {
+            sender: self.wg_ip,
+            base_version: since,
+            routedb_version: self.route_db.version,
+            nr_entries,
+            changed_routes,
+            removed_routes,
+        };
+        // A delta is not chunked, so the full database is sent for a large delta
+        let size = bincode::serialized_size(&delta).unwrap_or(u64::MAX);
+        if size > ROUTEDB_CHUNK_SIZE as u64 {
+            debug!(target: "routing", "Route db delta of {} bytes, so send full db to {}", size, requester);
+            return self.provide_route_database(requester);
+        }
+        vec![UdpPacket::RouteDatabaseDelta(delta)]
+    }
+    fn routes_for_requester(&self, requester: IpAddr, route_db: &RouteDB) -> Vec<RouteInfo> {
+        // No transit via this node during the warm-up
+        if !self.gateway_ready {
+            return vec![];
+        }
+        let requester_area = area_of(&self.areas, &requester).filter(|_| {
+            self.all_nodes.get(&requester).map(|n| n.endpoint_class()) != Some("static")
+        });
+        let mut known_routes = vec![];
+        let mut summaries: BTreeMap<u16, RouteInfo> = BTreeMap::new();
+        for ri in route_db.route_for.values() {
+            let area = area_of(&self.areas, &ri.to);
+            if requester_area.is_none() || area.is_none() || area == requester_area {
+                known_routes.push(ri.clone());
+            } else if let (true, Some(area)) = (self.is_area_border, area) {
+                let summary = summaries.entry(area).or_insert_with(|| RouteInfo {
+                    to: self.areas[&area].network(),
+                    local_admin_port: 0,
+                    hop_cnt: ri.hop_cnt,
+                    gateway: None,
+                    area_summary: Some(area),
+                    exported_nets: vec![],
+                    cost: ri.cost,
+                    bandwidth_mbit: None,
+                });
+                summary.hop_cnt = summary.hop_cnt.min(ri.hop_cnt);
+                summary.cost = summary.cost.min(ri.cost);
+            }
+        }
+        known_routes.extend(summaries.into_values());
+        known_routes
+    }
+    pub fn provide_audit(&self, static_config: &StaticConfiguration) -> UdpPacket {
+        let mut known_routes = self
+            .route_db
+            .route_for
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        known_routes.sort_by_key(|ri| ri.to);
+        let mut known_nodes = self
+            .all_nodes
+            .iter()
+            .map(|(wg_ip, node)| AuditNodeInfo {
+                wg_ip: *wg_ip,
+                name: node.name().map(|n| n.to_string()),
+                public_key: node.public_key().map(|pk| pk.key.clone()),
+                routedb_version: node
+                    .routedb_manager()
+                    .and_then(|mgr| mgr.routedb.as_ref())
+                    .map(|db| db.version),
+            })
+            .collect::<Vec<_>>();
+        known_nodes.sort_by_key(|ni| ni.wg_ip);
+        UdpPacket::Audit(AuditPacket {
+            sender: self.wg_ip,
+            name: static_config.name.clone(),
+            public_key: static_config.my_public_key.clone(),
+            routedb_version: self.route_db.version,
             known_routes,
-        );
-        vec![p]
+            known_nodes,
+        })
+    }
+    // The reply to a ping has been received from a direct peer
+    pub fn process_pong(&mut self, wg_ip: IpAddr, rtt_ms: u32) -> Vec<Event> {
+        let Some(rtt) = self
+            .all_nodes
+            .get_mut(&wg_ip)
+            .and_then(|n| n.link_rtt_mut())
+        else {
+            return vec![];
+        };
+        let old_ms = rtt.used_ms();
+        if !rtt.update(rtt_ms) {
+            return vec![];
+        }
+        debug!(target: "routing", "round trip time of {} changed from {:?} ms to {:?} ms", wg_ip, old_ms, rtt.used_ms());
+        // Only the cost depends on the round trip time
+        if self.routing.metric == RouteMetric::Cost {
+            vec![Event::UpdateRoutes]
+        } else {
+            vec![]
+        }
+    }
+    // Round trip time of a direct peer as used for the cost of the link
+    pub fn rtt_ms(&self, wg_ip: &IpAddr) -> Option<u32> {
+        self.all_nodes
+            .get(wg_ip)
+            .and_then(|n| n.link_rtt())
+            .and_then(|rtt| rtt.used_ms())
+    }
+    // Latest smoothed round trip time of a direct peer
+    pub fn smoothed_rtt_ms(&self, wg_ip: &IpAddr) -> Option<u32> {
+        self.all_nodes
+            .get(wg_ip)
+            .and_then(|n| n.link_rtt())
+            .and_then(|rtt| rtt.smoothed_ms())
     }
     pub fn process_route_database(&mut self, req: RouteDatabasePacket) -> Option<Vec<Event>> {
         debug!(target: "routing", "RouteDatabase: {:#?}", req.known_routes);
 
+        self.schedule_node(req.sender, 0);
         self.all_nodes
             .get_mut(&req.sender)
             .and_then(|node| node.process_route_database(req))
     }
-    pub fn process_local_contact(&mut self, local: LocalContactPacket) {
+    pub fn process_route_database_delta(
+        &mut self,
+        delta: RouteDatabaseDeltaPacket,
+    ) -> Option<Vec<Event>> {
+        debug!(target: "routing", "RouteDatabaseDelta: {:#?}", delta);
+
+        self.schedule_node(delta.sender, 0);
+        self.all_nodes
+            .get_mut(&delta.sender)
+            .and_then(|node| node.process_route_database_delta(delta))
+    }
+    // The version of the complete route database received from the node, which is
+    // the base for a delta
+    pub fn known_routedb_version(&self, wg_ip: &IpAddr) -> Option<usize> {
+        self.all_nodes
+            .get(wg_ip)
+            .and_then(|node| node.routedb_manager())
+            .and_then(|mgr| mgr.routedb.as_ref())
+            .map(|db| db.version)
+    }
+    // The contact of a direct peer is kept for the subscribers, a new or changed one is
+    // pushed to them
+    pub fn process_local_contact(&mut self, now: u64, local: LocalContactPacket) -> Vec<Event> {
         // Send advertisement to all local addresses
         debug!(target: &local.wg_ip.to_string(), "LocalContact: {:#?}", local);
         let wg_ip = local.wg_ip;
-        if let Some(node) = self.all_nodes.get_mut(&wg_ip) {
-            node.process_local_contact(local);
+        let next_key = local.next_public_key.as_ref().map(|k| k.key.as_str());
+        if self.is_revoked(Some(&local.public_key.key), None) || self.is_revoked(next_key, None) {
+            warn!(target: "revocation", "Local contact of revoked {} => ignored", wg_ip);
+            return vec![];
+        }
+        let before = self.snapshot(&wg_ip);
+        let mut events = vec![];
+        match self.all_nodes.get_mut(&wg_ip) {
+            Some(node) if node.is_distant_node() => {
+                node.process_local_contact(local);
+                self.schedule_node(wg_ip, 0);
+            }
+            Some(_) => {
+                let changed = self.local_contact_of(&wg_ip) != Some(&local);
+                self.peer_contacts.insert(wg_ip, (local, now));
+                if changed {
+                    for (sa, since) in self.contact_subscribers.iter() {
+                        if sa.ip() != wg_ip && now < since + CONTACT_SUBSCRIPTION {
+                            info!(target: "probing", "Push local contact of {} to {}", wg_ip, sa);
+                            events.push(Event::SendLocalContactOf { wg_ip, to: *sa });
+                        }
+                    }
+                }
+            }
+            None => {}
+        }
+        let after = self.snapshot(&wg_ip);
+        self.publish_changes(wg_ip, before, after);
+        events
+    }
+    // A distant node, which cannot be reached by NAT traversal, gets a relay. The
+    // relay needs to be a direct peer of both nodes, and static peers are preferred.
+    // Between the candidates, rendezvous hashing of the pair selects the same relay
+    // on both sides and spreads the pairs across the relays.
+    fn select_relays(&mut self, now: u64) -> bool {
+        let mut relays = HashMap::new();
+        let all_nodes = &self.all_nodes;
+        self.nat_telemetry.retain_failed(|wg_ip| {
+            all_nodes
+                .get(wg_ip)
+                .map(|node| node.is_distant_node())
+                .unwrap_or(false)
+        });
+        for (wg_ip, node) in self.all_nodes.iter() {
+            if !node.is_distant_node() || !node.traversal_failed(now) {
+                continue;
+            }
+            if let Some(progress) = node.traversal() {
+                self.nat_telemetry.failure(now, *wg_ip, progress);
+            }
+            let relay = self
+                .all_nodes
+                .iter()
+                .filter(|(_, candidate)| !candidate.is_distant_node() && candidate.is_reachable())
+                .filter(|(_, candidate)| {
+                    candidate
+                        .routedb_manager()
+                        .and_then(|mgr| mgr.routedb.as_ref())
+                        .and_then(|db| db.route_for.get(wg_ip))
+                        .map(|ri| ri.gateway.is_none())
+                        .unwrap_or(false)
+                })
+                .max_by_key(|(relay, candidate)| {
+                    (
+                        candidate.tier().is_some(),
+                        relay_weight(&self.wg_ip, wg_ip, relay),
+                    )
+                })
+                .map(|(relay, _)| *relay);
+            if let Some(relay) = relay {
+                relays.insert(*wg_ip, relay);
+            }
+        }
+        if relays == self.relays {
+            return false;
+        }
+        for (wg_ip, relay) in relays.iter() {
+            if self.relays.get(wg_ip) != Some(relay) {
+                info!(target: "routing", "NAT traversal to {} failed => relay via {}", wg_ip, relay);
+            }
+        }
+        for wg_ip in self.relays.keys() {
+            if !relays.contains_key(wg_ip) {
+                info!(target: "routing", "Relay for {} not used anymore", wg_ip);
+            }
+        }
+        self.relays = relays;
+        true
+    }
+    pub fn relays(&self) -> &HashMap<IpAddr, IpAddr> {
+        &self.relays
+    }
+    // The rendezvous request for a distant node and the admin address of its gateway
+    pub fn connect_request(&self, wg_ip: &IpAddr) -> Option<(SocketAddr, UdpPacket)> {
+        let gateway = self.all_nodes.get(wg_ip)?.get_gateway()?;
+        let admin_port = self.all_nodes.get(&gateway)?.local_admin_port();
+        let request = ConnectRequestPacket {
+            from: self.wg_ip,
+            to: *wg_ip,
+            from_endpoint: self.my_visible_wg_endpoint,
+            to_endpoint: None,
+            start_time: 0,
+            ttl: CONNECT_REQUEST_TTL,
+        };
+        Some((
+            SocketAddr::new(gateway, admin_port),
+            UdpPacket::ConnectRequest(request),
+        ))
+    }
+    // As one of the peers, the rendezvous starts the NAT traversal. Otherwise the
+    // request is relayed towards the other peer or, if this is a direct peer,
+    // completed and sent to both peers.
+    pub fn process_connect_request(
+        &mut self,
+        now: u64,
+        mut request: ConnectRequestPacket,
+    ) -> Vec<Event> {
+        if request.from == self.wg_ip || request.to == self.wg_ip {
+            let (peer, endpoint) = if request.to == self.wg_ip {
+                (request.from, request.from_endpoint)
+            } else {
+                (request.to, request.to_endpoint)
+            };
+            let mut events = vec![];
+            match (self.all_nodes.get_mut(&peer), endpoint) {
+                (Some(node), Some(endpoint))
+                    if node.is_distant_node() && request.start_time > 0 =>
+                {
+                    let start_time = crate::util::from_wall_time(request.start_time);
+                    events = node.process_connect_request(endpoint, start_time);
+                    self.schedule_node(peer, 0);
+                }
+                _ => debug!(target: "probing", "Ignore rendezvous with {}", peer),
+            }
+            return events;
+        }
+        if request.ttl == 0 {
+            warn!(target: "probing", "Drop rendezvous of {} and {} after too many relays", request.from, request.to);
+            return vec![];
+        }
+        request.ttl -= 1;
+
+        // The own observation of a direct peer is more reliable than its own view
+        for (wg_ip, endpoint) in [
+            (request.from, &mut request.from_endpoint),
+            (request.to, &mut request.to_endpoint),
+        ] {
+            if let Some(node) = self.all_nodes.get(&wg_ip) {
+                if !node.is_distant_node() {
+                    if let Some(observed) = node.visible_wg_endpoint() {
+                        *endpoint = Some(observed);
+                    }
+                }
+            }
+        }
+        let admin_address = |wg_ip: IpAddr| {
+            self.all_nodes
+                .get(&wg_ip)
+                .map(|node| SocketAddr::new(wg_ip, node.local_admin_port()))
+        };
+        match self.all_nodes.get(&request.to) {
+            Some(node) if node.is_distant_node() => {
+                match node.get_gateway().and_then(admin_address) {
+                    Some(to) => {
+                        info!(target: "probing", "Relay rendezvous of {} and {} to {}", request.from, request.to, to);
+                        vec![Event::ForwardConnectRequest { to, request }]
+                    }
+                    None => vec![],
+                }
+            }
+            Some(_) => {
+                if request.from_endpoint.is_none() || request.to_endpoint.is_none() {
+                    info!(target: "probing", "No visible endpoints for rendezvous of {} and {}", request.from, request.to);
+                    return vec![];
+                }
+                info!(target: "probing", "Rendezvous of {} and {} at {}", request.from, request.to, now + CONNECT_DELAY);
+                // Both peers convert back onto their own mesh clock
+                request.start_time = crate::util::to_wall_time(now + CONNECT_DELAY);
+                [request.to, request.from]
+                    .into_iter()
+                    .filter_map(admin_address)
+                    .map(|to| Event::ForwardConnectRequest {
+                        to,
+                        request: request.clone(),
+                    })
+                    .collect()
+            }
+            None => vec![],
         }
     }
     pub fn get_route_changes(&mut self) -> Vec<RouteChange> {
         let mut route_changes = vec![];
+        // Changes of the route database, which do not affect the installed routes
+        let mut routedb_changed = false;
         trace!(target: "routing", "Recalculate routes");
-        let mut new_routes: HashMap<Ipv4Addr, RouteInfo> = HashMap::new();
+        let mut new_routes: HashMap<IpAddr, RouteInfo> = HashMap::new();
+        // Direct paths to peers with forced relay, if there is no gateway
+        let mut relay_fallbacks = vec![];
 
         for (wg_ip, node) in self.all_nodes.iter() {
             if node.is_distant_node() {
                 continue;
             }
             trace!(target: "routing", "Include direct path to static/dynamic peer to new routes: {}", wg_ip);
+            // After a takeover, the networks are known from the route database until
+            // the next advertisement
+            let exported_nets = self
+                .advertised_nets
+                .get(wg_ip)
+                .or_else(|| {
+                    self.route_db
+                        .route_for
+                        .get(wg_ip)
+                        .map(|ri| &ri.exported_nets)
+                })
+                .cloned()
+                .unwrap_or_default();
+            let (cost, bandwidth_mbit) =
+                self.routing
+                    .link_cost(&self.wg_ip, wg_ip, self.rtt_ms(wg_ip));
             let ri = RouteInfo {
                 to: *wg_ip,
                 local_admin_port: node.local_admin_port(),
                 hop_cnt: 0,
                 gateway: None,
+                area_summary: None,
+                exported_nets,
+                cost,
+                bandwidth_mbit,
             };
+            if self.forced_relays.contains_key(wg_ip) {
+                relay_fallbacks.push(ri);
+                continue;
+            }
             new_routes.insert(*wg_ip, ri);
         }
         // Then add all indirect routes from the node's routedb
 
         let mut new_nodes = vec![];
-        for (wg_ip, node) in self.all_nodes.iter() {
-            if let Some(routedb) = node.routedb_manager().and_then(|mgr| mgr.routedb.as_ref()) {
-                for ri in routedb.route_for.values() {
-                    if ri.to == self.wg_ip {
-                        trace!(target: "routing", "Route to myself => ignore");
+        let use_areas = self.my_area.is_some() && !self.is_area_border;
+        let metric = self.routing.metric;
+        // Between equal paths, the gateway of the lowest tier wins
+        let tier_of = |gateway: Option<&IpAddr>| {
+            gateway
+                .and_then(|gateway| self.all_nodes.get(gateway))
+                .and_then(|node| node.tier())
+                .unwrap_or(u8::MAX)
+        };
+        let mut area_candidates: BTreeMap<u16, AreaRank> = BTreeMap::new();
+        // Gateway and destination of all offered routes for the reason of a withdrawal
+        let mut offered: HashSet<(IpAddr, IpAddr)> = HashSet::new();
+        let peers = self
+            .all_nodes
+            .iter()
+            .filter_map(|(wg_ip, node)| {
+                let routedb = node.routedb_manager()?.routedb.as_ref()?;
+                let (link_cost, link_bandwidth) =
+                    self.routing
+                        .link_cost(&self.wg_ip, wg_ip, self.rtt_ms(wg_ip));
+                Some(PeerRoutes {
+                    wg_ip: *wg_ip,
+                    link_cost,
+                    link_bandwidth,
+                    tier: tier_of(Some(wg_ip)),
+                    route_for: &routedb.route_for,
+                })
+            })
+            .collect::<Vec<_>>();
+        let ctx = RouteContext {
+            wg_ip: self.wg_ip,
+            metric,
+            my_area: self.my_area,
+            use_areas,
+            areas: &self.areas,
+            subnet: self.subnet,
+            my_exported_nets: &self.my_exported_nets,
+            distant_nodes: self
+                .all_nodes
+                .iter()
+                .filter(|(_, node)| node.is_distant_node())
+                .map(|(wg_ip, _)| *wg_ip)
+                .collect(),
+        };
+        let parallel = use_parallel(&peers);
+        let select_gateway = self.policy_selects_gateway();
+        let mut gateway_candidates: HashMap<IpAddr, Vec<RouteInfo>> = HashMap::new();
+        for (peer, candidates) in peers.iter().zip(all_candidates(&ctx, &peers, parallel)) {
+            let wg_ip = &peer.wg_ip;
+            for candidate in candidates {
+                let (ri_new, ri) = match candidate {
+                    Candidate::Route { route, offered } => (route, offered),
+                    Candidate::Area { area, rank } => {
+                        let best = area_candidates.entry(area).or_insert(rank);
+                        *best = (*best).min(rank);
                         continue;
                     }
-                    let mut hop_cnt = 1;
-                    if let Some(gateway) = ri.gateway.as_ref() {
-                        // Ignore routes to myself as gateway
-                        if *gateway == self.wg_ip {
-                            trace!(target: "routing", "Route to myself as gateway => ignore");
-                            continue;
-                        }
-                        if self.all_nodes.get(gateway).map(|n| n.is_distant_node()) != Some(true) {
-                            trace!(target: "routing", "Route using any of my peers as gateway => ignore");
-                            continue;
-                        }
-
-                        hop_cnt = ri.hop_cnt + 1;
+                };
+                offered.insert((*wg_ip, ri.to));
+                if !self.policy_allows_route(&ri_new) {
+                    debug!(target: "policy", "route to {} via {} rejected by the policy script", ri.to, wg_ip);
+                    continue;
+                }
+                if select_gateway {
+                    gateway_candidates
+                        .entry(ri.to)
+                        .or_default()
+                        .push(ri_new.clone());
+                }
+                match new_routes.entry(ri.to) {
+                    Entry::Vacant(e) => {
+                        e.insert(ri_new);
                     }
-
-                    // to-host can be reached via wg_ip
-                    trace!(target: "routing", "Include to routes: {} via {:?} and hop_cnt {}", ri.to, wg_ip, hop_cnt);
-                    let ri_new = RouteInfo {
-                        to: ri.to,
-                        local_admin_port: ri.local_admin_port,
-                        hop_cnt,
-                        gateway: Some(*wg_ip),
-                    };
-                    match new_routes.entry(ri.to) {
-                        Entry::Vacant(e) => {
-                            e.insert(ri_new);
-                        }
-                        Entry::Occupied(mut e) => {
-                            let current = e.get_mut();
-                            if current.hop_cnt > ri_new.hop_cnt {
-                                // new route is better, so replace
-                                *current = ri_new;
-                            }
+                    Entry::Occupied(mut e) => {
+                        let current = e.get_mut();
+                        // A direct path is never overridden by a pin, because wireguard
+                        // selects the peer by the destination address
+                        let pinned = self
+                            .pinned_routes
+                            .get(&ri.to)
+                            .or_else(|| self.relays.get(&ri.to))
+                            .filter(|_| current.gateway.is_some());
+                        if pinned == Some(wg_ip) {
+                            trace!(target: "routing", "pinned route to {} via {}", ri.to, wg_ip);
+                            *current = ri_new;
+                        } else if pinned.is_some() && pinned == current.gateway.as_ref() {
+                            trace!(target: "routing", "keep pinned route to {}", ri.to);
+                        } else if current.gateway.is_some()
+                            && (
+                                path_rank(metric, current.hop_cnt, current.cost),
+                                tier_of(current.gateway.as_ref()),
+                            ) > (path_rank(metric, ri_new.hop_cnt, ri_new.cost), peer.tier)
+                        {
+                            // new route is better, so replace
+                            *current = ri_new;
                         }
                     }
-                    if !self.all_nodes.contains_key(&ri.to) {
-                        info!(target: "probing", "detected a new node {} via {:?}", ri.to, ri.gateway);
-                        let node = DistantNode::from(ri);
-                        new_nodes.push((ri.to, node));
+                }
+                if !self.all_nodes.contains_key(&ri.to) {
+                    info!(target: "probing", "detected a new node {} via {:?}", ri.to, ri.gateway);
+                    let mut node = DistantNode::from(ri);
+                    if let Some(endpoint) = self.my_visible_wg_endpoint {
+                        node.set_own_visible_endpoint(endpoint);
                     }
+                    new_nodes.push((ri.to, node));
+                }
+            }
+        }
+        // The script chooses among several gateways, but a direct path and a pin are kept
+        for (to, candidates) in gateway_candidates {
+            let current = &new_routes[&to];
+            if candidates.len() < 2
+                || current.gateway.is_none()
+                || self.pinned_routes.contains_key(&to)
+                || self.relays.contains_key(&to)
+            {
+                continue;
+            }
+            if let Some(gateway) = self.policy_gateway(to, &candidates) {
+                if current.gateway != Some(gateway) {
+                    debug!(target: "policy", "route to {} via {} selected by the policy script", to, gateway);
+                    let ri = candidates
+                        .into_iter()
+                        .find(|ri| ri.gateway == Some(gateway));
+                    new_routes.insert(to, ri.unwrap());
                 }
             }
         }
         for (wg_ip, node) in new_nodes {
             self.all_nodes.insert(wg_ip, Box::new(node));
+            self.schedule_node(wg_ip, 0);
+        }
+        for ri in relay_fallbacks {
+            if let Entry::Vacant(e) = new_routes.entry(ri.to) {
+                debug!(target: "routing", "no gateway to relay to {} => use direct path", ri.to);
+                e.insert(ri);
+            }
+        }
+        let area_gateways = area_candidates
+            .into_iter()
+            .map(|(area, (_, _, gateway))| (area, gateway))
+            .collect::<BTreeMap<_, _>>();
+        if area_gateways != self.area_gateways {
+            info!(target: "routing", "area border nodes: {:?}", area_gateways);
+            self.area_gateways = area_gateways;
         }
 
         for entry in new_routes.iter() {
             debug!(target: "routing", "new routes' entry: {:?}", entry);
         }
+        for (to, gateway) in self.pinned_routes.iter() {
+            if let Some(ri) = new_routes.get(to) {
+                if ri.gateway.is_some() && ri.gateway != Some(*gateway) {
+                    debug!(target: "routing", "pinned gateway {} offers no route to {} => use {:?}", gateway, to, ri.gateway);
+                }
+            }
+        }
         for ri in self.route_db.route_for.values_mut() {
             debug!(target: "routing", "Existing route: {:?}", ri);
         }
@@ -300,6 +2291,57 @@ impl NetworkManager {
         // remove all distant nodes without a route
         self.all_nodes
             .retain(|wg_ip, node| !node.is_distant_node() || new_routes.contains_key(wg_ip));
+        let all_nodes = &self.all_nodes;
+        self.scheduled_at
+            .retain(|wg_ip, _| all_nodes.contains_key(wg_ip));
+        self.identities
+            .retain(|wg_ip, _| all_nodes.contains_key(wg_ip));
+        self.advertised_nets
+            .retain(|wg_ip, _| all_nodes.contains_key(wg_ip));
+        self.forced_relays
+            .retain(|wg_ip, _| all_nodes.contains_key(wg_ip));
+        self.relays.retain(|wg_ip, _| all_nodes.contains_key(wg_ip));
+        self.peer_contacts
+            .retain(|wg_ip, _| all_nodes.get(wg_ip).map(|n| !n.is_distant_node()) == Some(true));
+        self.contact_subscribers
+            .retain(|sa, _| all_nodes.contains_key(&sa.ip()));
+        self.contact_subscriptions
+            .retain(|wg_ip, _| all_nodes.contains_key(wg_ip));
+        let old_nets = self.exported_net_hops();
+        let old_gateways = self
+            .route_db
+            .route_for
+            .iter()
+            .map(|(to, ri)| (*to, ri.gateway))
+            .collect::<HashMap<_, _>>();
+
+        // The previous gateway and for a replaced route the new one
+        let withdrawal_reason =
+            |to: IpAddr, gateway: Option<IpAddr>, new: Option<Option<IpAddr>>| {
+                let pinned = |gw: &IpAddr| {
+                    self.pinned_routes.get(&to) == Some(gw) || self.relays.get(&to) == Some(gw)
+                };
+                match (gateway, new) {
+                    (_, Some(new)) if new == gateway => RouteReason::Policy,
+                    (None, _) if self.forced_relays.contains_key(&to) => RouteReason::Policy,
+                    (None, _) if self.all_nodes.contains_key(&to) => RouteReason::LinkLost,
+                    (None, _) => RouteReason::PeerDead,
+                    (Some(gw), None) => {
+                        if self
+                            .all_nodes
+                            .get(&gw)
+                            .is_some_and(|node| node.is_reachable())
+                        {
+                            RouteReason::PeerDead
+                        } else {
+                            RouteReason::GatewayLost
+                        }
+                    }
+                    (Some(gw), Some(_)) if !offered.contains(&(gw, to)) => RouteReason::GatewayLost,
+                    (Some(_), Some(Some(new))) if pinned(&new) => RouteReason::Policy,
+                    (Some(_), Some(_)) => RouteReason::BetterPath,
+                }
+            };
 
         // So update route_db and mark changes
         //
@@ -311,6 +2353,8 @@ impl NetworkManager {
                 route_changes.push(RouteChange::DelRoute {
                     to: ri.to,
                     gateway: ri.gateway,
+                    attributes: self.route_attributes.remove(&ri.to).unwrap_or_default(),
+                    reason: withdrawal_reason(ri.to, ri.gateway, None),
                 });
 
                 to_be_deleted.push(ri.to);
@@ -327,6 +2371,16 @@ impl NetworkManager {
         // finally routes to be updated / added
         for (to, ri) in new_routes.into_iter() {
             trace!(target: "routing", "process route {} via {:?}", to, ri.gateway);
+            let attributes = RouteAttributes {
+                mtu: self.path_mtu.get(&to).copied(),
+            };
+            let attributes_changed =
+                self.route_attributes.get(&to).copied().unwrap_or_default() != attributes;
+            if attributes.is_empty() {
+                self.route_attributes.remove(&to);
+            } else {
+                self.route_attributes.insert(to, attributes);
+            }
             match self.route_db.route_for.entry(to) {
                 Entry::Vacant(e) => {
                     // new node with route
@@ -334,12 +2388,17 @@ impl NetworkManager {
                     route_changes.push(RouteChange::AddRoute {
                         to,
                         gateway: ri.gateway,
+                        attributes,
                     });
                     let mut ri_new = RouteInfo {
                         to,
                         local_admin_port: ri.local_admin_port,
                         hop_cnt: ri.hop_cnt,
                         gateway: ri.gateway,
+                        area_summary: None,
+                        exported_nets: ri.exported_nets,
+                        cost: ri.cost,
+                        bandwidth_mbit: ri.bandwidth_mbit,
                     };
                     if ri.gateway.is_some() {
                         ri_new.hop_cnt += 1;
@@ -348,33 +2407,467 @@ impl NetworkManager {
                 }
                 Entry::Occupied(mut e) => {
                     // update route
-                    if e.get().to != ri.to || e.get().gateway != ri.gateway {
+                    if e.get().to != ri.to || e.get().gateway != ri.gateway || attributes_changed {
                         trace!(target: "routing", "replace existing route {}", to);
                         route_changes.push(RouteChange::ReplaceRoute {
                             to,
                             gateway: ri.gateway,
+                            attributes,
+                            reason: withdrawal_reason(to, e.get().gateway, Some(ri.gateway)),
                         });
                         *e.get_mut() = RouteInfo {
                             to,
                             local_admin_port: ri.local_admin_port,
                             hop_cnt: ri.hop_cnt,
                             gateway: ri.gateway,
+                            area_summary: None,
+                            exported_nets: ri.exported_nets,
+                            cost: ri.cost,
+                            bandwidth_mbit: ri.bandwidth_mbit,
                         };
+                    } else {
+                        if e.get().local_admin_port != ri.local_admin_port {
+                            // e.g. the peer has restarted with another admin port
+                            trace!(target: "routing", "update admin port of route {}", to);
+                            e.get_mut().local_admin_port = ri.local_admin_port;
+                            routedb_changed = true;
+                        }
+                        if e.get().exported_nets != ri.exported_nets {
+                            trace!(target: "routing", "update exported networks of route {}", to);
+                            e.get_mut().exported_nets = ri.exported_nets;
+                            routedb_changed = true;
+                        }
+                        if e.get().cost != ri.cost || e.get().bandwidth_mbit != ri.bandwidth_mbit {
+                            trace!(target: "routing", "update cost of route {}", to);
+                            e.get_mut().cost = ri.cost;
+                            e.get_mut().bandwidth_mbit = ri.bandwidth_mbit;
+                            routedb_changed = true;
+                        }
                     }
                 }
             }
             trace!(target: "routing", "route changes: {}", route_changes.len());
         }
+        let new_nets = self.exported_net_hops();
+        for net in old_nets.keys().filter(|net| !new_nets.contains_key(net)) {
+            route_changes.push(RouteChange::DelNetRoute { net: *net });
+        }
+        for net in new_nets.keys().filter(|net| !old_nets.contains_key(net)) {
+            route_changes.push(RouteChange::AddNetRoute { net: *net });
+        }
         if !route_changes.is_empty() {
             trace!(target: "routing", "{} route changes", route_changes.len());
             for change in route_changes.iter() {
                 trace!(target: "routing", "route changes {:?}", change);
             }
+            routedb_changed = true;
+        }
+        self.counters.route_changes += route_changes.len() as u64;
+        if routedb_changed {
             self.route_db.version += 1;
+            self.routedb_history.push_back(self.route_db.clone());
+            if self.routedb_history.len() > ROUTEDB_HISTORY {
+                self.routedb_history.pop_front();
+            }
+        }
+        if !route_changes.is_empty() {
+            let diff = RouteDiff::of(
+                crate::util::now(),
+                self.route_db.version,
+                &route_changes,
+                &old_gateways,
+            );
+            info!(target: "routing", "Route changes {}", diff.summary());
+            debug!(target: "routing", "Route changes {}", diff.details());
+            self.last_route_diff = Some(diff);
+        }
+        let now = crate::util::now();
+        for change in route_changes.iter() {
+            let (to, deleted, reason) = match change {
+                RouteChange::DelRoute { to, reason, .. } => (*to, true, *reason),
+                RouteChange::ReplaceRoute { to, reason, .. } => (*to, false, *reason),
+                _ => continue,
+            };
+            let withdrawal = RouteWithdrawal {
+                to,
+                at: now,
+                gateway: old_gateways.get(&to).copied().flatten(),
+                deleted,
+                reason,
+            };
+            self.route_withdrawals.insert(to, withdrawal);
+        }
+        while self.route_withdrawals.len() > MAX_ROUTE_WITHDRAWALS {
+            let oldest = self
+                .route_withdrawals
+                .values()
+                .min_by_key(|withdrawal| withdrawal.at)
+                .map(|withdrawal| withdrawal.to);
+            match oldest {
+                Some(oldest) => self.route_withdrawals.remove(&oldest),
+                None => break,
+            };
+        }
+        for change in route_changes.iter() {
+            let evt = match change {
+                RouteChange::AddRoute { to, gateway, .. } => MeshEvent::RouteAdded {
+                    to: *to,
+                    gateway: *gateway,
+                },
+                RouteChange::ReplaceRoute { to, gateway, .. } => MeshEvent::RouteReplaced {
+                    to: *to,
+                    gateway: *gateway,
+                },
+                RouteChange::DelRoute { to, .. } => MeshEvent::RouteRemoved { to: *to },
+                RouteChange::AddNetRoute { .. } | RouteChange::DelNetRoute { .. } => continue,
+            };
+            self.mesh_events.publish(evt);
         }
         route_changes
     }
-    pub fn get_ips_for_peer(&self, peer: Ipv4Addr) -> Vec<Ipv4Addr> {
+    // The latest batch of route changes
+    pub fn last_route_diff(&self) -> Option<&RouteDiff> {
+        self.last_route_diff.as_ref()
+    }
+    // The last withdrawal per destination ordered by wg_ip
+    pub fn route_withdrawals(&self) -> Vec<RouteWithdrawal> {
+        let mut withdrawals = self.route_withdrawals.values().cloned().collect::<Vec<_>>();
+        withdrawals.sort_by_key(|withdrawal| withdrawal.to);
+        withdrawals
+    }
+    // Clamp the mtu of the route to a single destination, e.g. after PMTU probing has
+    // detected a lower mtu on this path. None removes the clamp.
+    // The route is updated by the next get_route_changes().
+    pub fn set_path_mtu(&mut self, to: IpAddr, mtu: Option<u32>) -> bool {
+        let changed = match mtu {
+            Some(mtu) => self.path_mtu.insert(to, mtu) != Some(mtu),
+            None => self.path_mtu.remove(&to).is_some(),
+        };
+        if changed {
+            info!(target: "routing", "path mtu to {} set to {:?}", to, mtu);
+        }
+        changed
+    }
+    // target can be a wireguard ip or the name of a node
+    // A node given by wg_ip or name
+    pub fn resolve_node(&self, target: &str) -> Option<IpAddr> {
+        match target.parse::<IpAddr>() {
+            Ok(wg_ip) => Some(wg_ip),
+            Err(_) => self
+                .all_nodes
+                .iter()
+                .find(|(_, node)| node.name() == Some(target))
+                .map(|(wg_ip, _)| *wg_ip),
+        }
+    }
+    pub fn route_lookup(&self, now: u64, target: &str) -> Option<RouteLookup> {
+        let to = self.resolve_node(target)?;
+        let ri = self.route_db.route_for.get(&to)?;
+        let node = self.all_nodes.get(&to);
+        let next_hop = self.all_nodes.get(ri.gateway.as_ref().unwrap_or(&to))?;
+        let lastseen = next_hop.lastseen();
+        Some(RouteLookup {
+            to,
+            name: node.and_then(|n| n.name()).map(|n| n.to_string()),
+            gateway: ri.gateway,
+            hop_cnt: ri.hop_cnt,
+            endpoint_class: next_hop.endpoint_class(),
+            alive: next_hop.is_reachable(),
+            lastseen_s_ago: lastseen.map(|t| now.saturating_sub(t)),
+        })
+    }
+    pub fn peer_infos(&self, now: u64) -> Vec<PeerInfo> {
+        let mut peers = self
+            .all_nodes
+            .iter()
+            .map(|(wg_ip, node)| PeerInfo {
+                wg_ip: *wg_ip,
+                name: node.name().map(|n| n.to_string()),
+                endpoint_class: node.endpoint_class().to_string(),
+                endpoint: node.visible_wg_endpoint(),
+                gateway: self.route_db.route_for.get(wg_ip).and_then(|ri| ri.gateway),
+                hop_cnt: self.route_db.route_for.get(wg_ip).map(|ri| ri.hop_cnt),
+                public_key: node.public_key().map(|pk| pk.key.clone()),
+                alive: node.is_reachable(),
+                lastseen_s_ago: node.lastseen().map(|t| now.saturating_sub(t)),
+                pinned_gateway: self.pinned_routes.get(wg_ip).copied(),
+                exported_nets: self
+                    .route_db
+                    .route_for
+                    .get(wg_ip)
+                    .map(|ri| ri.exported_nets.clone())
+                    .unwrap_or_default(),
+                forced_relay_s: self
+                    .forced_relays
+                    .get(wg_ip)
+                    .map(|relay| relay.until.saturating_sub(now)),
+                cost: self.route_db.route_for.get(wg_ip).map(|ri| ri.cost),
+                rtt_ms: self.smoothed_rtt_ms(wg_ip),
+                relay: self.relays.get(wg_ip).copied(),
+                quarantined_key: self
+                    .wg_ip_conflicts
+                    .get(wg_ip)
+                    .map(|conflict| conflict.quarantined_key.clone()),
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|p| p.wg_ip);
+        peers
+    }
+    // The area border node for each other area
+    pub fn area_gateways(&self) -> &BTreeMap<u16, IpAddr> {
+        &self.area_gateways
+    }
+    // The installed host routes, the ones via a gateway first, as these depend on
+    // the direct routes
+    pub fn installed_routes(&self) -> Vec<(IpAddr, Option<IpAddr>, RouteAttributes)> {
+        let mut routes = self
+            .route_db
+            .route_for
+            .values()
+            .map(|ri| {
+                let attributes = self.route_attributes.get(&ri.to).copied();
+                (ri.to, ri.gateway, attributes.unwrap_or_default())
+            })
+            .collect::<Vec<_>>();
+        routes.sort_by_key(|(to, gateway, _)| (gateway.is_none(), *to));
+        routes
+    }
+    // The next hop for each exported network. If several nodes export the same
+    // network, the nearest one is used.
+    pub fn exported_net_hops(&self) -> BTreeMap<IpNet, IpAddr> {
+        let mut best: BTreeMap<IpNet, (usize, IpAddr, IpAddr)> = BTreeMap::new();
+        for ri in self.route_db.route_for.values() {
+            let candidate = (ri.hop_cnt, ri.to, ri.gateway.unwrap_or(ri.to));
+            for net in ri.exported_nets.iter() {
+                let entry = best.entry(*net).or_insert(candidate);
+                *entry = (*entry).min(candidate);
+            }
+        }
+        best.into_iter()
+            .map(|(net, (_, _, next_hop))| (net, next_hop))
+            .collect()
+    }
+    // The exported networks, which are listed as allowed ips of the peer's wireguard entry
+    pub fn exported_nets_via(&self, wg_ip: &IpAddr) -> Vec<IpNet> {
+        self.exported_net_hops()
+            .into_iter()
+            .filter(|(_, next_hop)| next_hop == wg_ip)
+            .map(|(net, _)| net)
+            .collect()
+    }
+    // The areas, which are listed as allowed ips of the peer's wireguard entry
+    pub fn areas_via(&self, wg_ip: &IpAddr) -> Vec<IpNet> {
+        self.area_gateways
+            .iter()
+            .filter(|(_, gateway)| *gateway == wg_ip)
+            .map(|(area, _)| self.areas[area])
+            .collect()
+    }
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        let mut routes = self
+            .route_db
+            .route_for
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        routes.sort_by_key(|ri| ri.to);
+        routes
+    }
+    // Admin addresses of the reachable direct peers via wireguard
+    pub fn direct_peer_admin_addresses(&self) -> Vec<SocketAddr> {
+        self.all_nodes
+            .iter()
+            .filter(|(_, node)| !node.is_distant_node() && node.is_reachable())
+            .map(|(wg_ip, node)| SocketAddr::new(*wg_ip, node.local_admin_port()))
+            .collect()
+    }
+    // A direct peer shuts down. Only accepted via its wireguard tunnel, because
+    // then wireguard has authenticated the sender.
+    pub fn process_goodbye(
+        &mut self,
+        static_config: &StaticConfiguration,
+        src_addr: SocketAddr,
+    ) -> Vec<Event> {
+        let wg_ip = src_addr.ip();
+        match self.all_nodes.get(&wg_ip) {
+            Some(node) if !node.is_distant_node() => {}
+            _ => {
+                debug!(target: "shutdown", "Ignore goodbye from {}", src_addr);
+                return vec![];
+            }
+        }
+        info!(target: "shutdown", "{} shuts down => remove", wg_ip);
+        self.drop_peer(&wg_ip, static_config);
+        vec![Event::UpdateWireguardConfiguration, Event::UpdateRoutes]
+    }
+    // Forget everything about the node. A static peer starts from scratch and
+    // all other nodes are rediscovered, if still alive.
+    pub fn drop_peer(&mut self, wg_ip: &IpAddr, static_config: &StaticConfiguration) -> bool {
+        let before = self.snapshot(wg_ip);
+        if self.all_nodes.remove(wg_ip).is_none() {
+            return false;
+        }
+        self.scheduled_at.remove(wg_ip);
+        if let Some(peer) = static_config.peers.get(wg_ip) {
+            self.all_nodes
+                .insert(*wg_ip, StaticPeer::from_public_peer(peer));
+            self.schedule_node(*wg_ip, 0);
+        }
+        let after = self.snapshot(wg_ip);
+        self.publish_changes(*wg_ip, before, after);
+        true
+    }
+    // Revoke the keys of a compromised node and forget all about it.
+    // None, if neither its public key nor its identity is known.
+    pub fn revoke_peer(&mut self, now: u64, wg_ip: &IpAddr) -> Option<Revocation> {
+        let revocation = Revocation {
+            wg_ip: *wg_ip,
+            public_key: self
+                .all_nodes
+                .get(wg_ip)
+                .and_then(|node| node.public_key())
+                .map(|key| key.key.clone()),
+            identity: self.identities.get(wg_ip).copied(),
+            revoked_at: now,
+        };
+        if *wg_ip == self.wg_ip
+            || (revocation.public_key.is_none() && revocation.identity.is_none())
+        {
+            return None;
+        }
+        self.apply_revocation(revocation.clone());
+        Some(revocation)
+    }
+    // Revocations received from other nodes. Returns true, if a known node is affected.
+    pub fn learn_revocations(
+        &mut self,
+        static_config: &StaticConfiguration,
+        revocations: &[Revocation],
+    ) -> bool {
+        let my_key = static_config.my_public_key.key.as_str();
+        let my_identity = static_config.identity.public();
+        let mut affected = false;
+        for revocation in revocations {
+            if revocation.wg_ip == self.wg_ip
+                || revocation.matches(Some(my_key), Some(&my_identity))
+            {
+                warn!(target: "revocation", "Revocation of this node => ignored");
+                continue;
+            }
+            if self
+                .revocations
+                .iter()
+                .any(|r| r.public_key == revocation.public_key && r.identity == revocation.identity)
+            {
+                continue;
+            }
+            affected |= self.apply_revocation(revocation.clone());
+        }
+        affected
+    }
+    // A static peer is removed, too. So no advertisements are sent to it anymore.
+    fn apply_revocation(&mut self, revocation: Revocation) -> bool {
+        let wg_ip = revocation.wg_ip;
+        warn!(target: "revocation", "Revoked key {:?} of {}", revocation.public_key, wg_ip);
+        // The wg_ip may be in use by a replacement with other keys already
+        let public_key = self
+            .all_nodes
+            .get(&wg_ip)
+            .and_then(|node| node.public_key())
+            .map(|key| key.key.clone());
+        let affected = revocation.matches(public_key.as_deref(), self.identities.get(&wg_ip));
+        self.revocations.push(revocation);
+        if affected {
+            self.forget_node(wg_ip);
+        }
+        affected
+    }
+    fn forget_node(&mut self, wg_ip: IpAddr) {
+        let before = self.snapshot(&wg_ip);
+        self.all_nodes.remove(&wg_ip);
+        self.scheduled_at.remove(&wg_ip);
+        self.publish_changes(wg_ip, before, None);
+        self.identities.remove(&wg_ip);
+        self.peer_contacts.remove(&wg_ip);
+        self.advertised_nets.remove(&wg_ip);
+        self.forced_relays.remove(&wg_ip);
+        self.path_mtu.remove(&wg_ip);
+        self.contact_subscriptions.remove(&wg_ip);
+    }
+    pub fn is_revoked(&self, public_key: Option<&str>, identity: Option<&PublicIdentity>) -> bool {
+        self.revocations
+            .iter()
+            .any(|revocation| revocation.matches(public_key, identity))
+    }
+    pub fn revocations(&self) -> &[Revocation] {
+        &self.revocations
+    }
+    // The most recent revocations to be passed on with the advertisements
+    pub fn revocations_to_gossip(&self, now: u64) -> Vec<Revocation> {
+        self.revocations
+            .iter()
+            .rev()
+            .filter(|revocation| revocation.revoked_at + REVOCATION_GOSSIP > now)
+            .take(MAX_GOSSIPED_REVOCATIONS)
+            .cloned()
+            .collect()
+    }
+    // Bring the static peers in line with a reloaded configuration
+    pub fn update_static_peers(&mut self, static_config: &StaticConfiguration) {
+        let removed = self
+            .all_nodes
+            .iter()
+            .filter(|(wg_ip, node)| {
+                node.endpoint_class() == "static" && !static_config.peers.contains_key(wg_ip)
+            })
+            .map(|(wg_ip, _)| *wg_ip)
+            .collect::<Vec<_>>();
+        for wg_ip in removed {
+            info!(
+                "static peer {} has been removed from the configuration",
+                wg_ip
+            );
+            self.drop_peer(&wg_ip, static_config);
+        }
+        for (wg_ip, peer) in static_config.peers.iter() {
+            if *wg_ip == self.wg_ip {
+                continue;
+            }
+            let is_static = self.all_nodes.get(wg_ip).map(|n| n.endpoint_class()) == Some("static");
+            if !is_static {
+                info!("static peer {} has been added to the configuration", wg_ip);
+                let before = self.snapshot(wg_ip);
+                self.all_nodes
+                    .insert(*wg_ip, StaticPeer::from_public_peer(peer));
+                self.scheduled_at.remove(wg_ip);
+                self.schedule_node(*wg_ip, 0);
+                let after = self.snapshot(wg_ip);
+                self.publish_changes(*wg_ip, before, after);
+            }
+        }
+    }
+    // The direct contacts of all known nodes with their identity, if known
+    pub fn known_contacts(&self) -> Vec<PublicPeer> {
+        let mut contacts = self
+            .all_nodes
+            .iter()
+            .filter(|(wg_ip, _)| **wg_ip != self.wg_ip)
+            .filter_map(|(wg_ip, node)| {
+                let mut contact = node.contact()?;
+                contact.identity = contact.identity.or(self.identities.get(wg_ip).copied());
+                Some(contact)
+            })
+            .collect::<Vec<_>>();
+        contacts.sort_by_key(|contact| contact.wg_ip);
+        contacts
+    }
+    pub fn node_classes(&self) -> Vec<(IpAddr, &'static str)> {
+        self.all_nodes
+            .iter()
+            .map(|(wg_ip, node)| (*wg_ip, node.endpoint_class()))
+            .collect()
+    }
+    pub fn get_ips_for_peer(&self, peer: IpAddr) -> Vec<IpAddr> {
         let mut ips = vec![];
 
         for ri in self.route_db.route_for.values() {
@@ -385,10 +2878,10 @@ impl NetworkManager {
 
         ips
     }
-    pub fn node_for(&mut self, wg_ip: &Ipv4Addr) -> Option<&dyn Node> {
+    pub fn node_for(&mut self, wg_ip: &IpAddr) -> Option<&dyn Node> {
         self.all_nodes.get(wg_ip).map(|n| n.as_ref())
     }
-    pub fn knows_peer(&mut self, wg_ip: &Ipv4Addr) -> bool {
+    pub fn knows_peer(&mut self, wg_ip: &IpAddr) -> bool {
         self.all_nodes.contains_key(wg_ip)
     }
     pub fn output(&self) {
@@ -400,8 +2893,16 @@ impl NetworkManager {
         &mut self,
         mut pubkey_to_endpoint: HashMap<String, SocketAddr>,
     ) {
-        for node in self.all_nodes.values_mut() {
+        self.wg_endpoints = pubkey_to_endpoint.clone();
+        let mut changes = vec![];
+        for (wg_ip, node) in self.all_nodes.iter_mut() {
+            let before = NodeSnapshot::of(node.as_ref());
             node.update_from_wireguard_configuration(&mut pubkey_to_endpoint);
+            changes.push((*wg_ip, before, NodeSnapshot::of(node.as_ref())));
+        }
+        for (wg_ip, before, after) in changes {
+            self.publish_changes(wg_ip, Some(before), Some(after));
         }
+        self.schedule_all_nodes();
     }
 }
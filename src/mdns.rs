@@ -0,0 +1,443 @@
+// Announcement of the admin service via mDNS/DNS-SD.
+//
+// Every node announces `<name>@<wg_ip>._wgnetmgr._udp.local` with its admin port, the
+// wg_ip and a fingerprint of the shared key on the local ipv4 networks. So all nodes
+// on a LAN can be listed for diagnostics, even those of other meshes. A node of the
+// own mesh is sent an advertisement via its local address. Joining still needs the
+// shared key, because only decrypted advertisements are accepted.
+//
+// Only the records of this service are encoded and decoded, and the socket shares
+// port 5353 with a running avahi or mDNSResponder.
+//
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use log::*;
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::configuration::StaticConfiguration;
+use crate::error::*;
+use crate::event::{Event, EventSender};
+
+pub const SERVICE: &str = "_wgnetmgr._udp.local";
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+// Interval of the announcements and queries. The records are valid for twice as long.
+const ANNOUNCE_INTERVAL: u64 = 60;
+const TTL: u32 = 2 * ANNOUNCE_INTERVAL as u32;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000;
+
+// Identifies the mesh without revealing the shared key
+pub fn mesh_fingerprint(shared_key: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"wg_netmanager mdns");
+    hasher.update(shared_key);
+    hasher.finalize()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MdnsService {
+    // Unique per node: name@wg_ip
+    pub instance: String,
+    pub host: String,
+    pub addresses: Vec<IpAddr>,
+    pub admin_port: u16,
+    pub wg_ip: IpAddr,
+    pub mesh: String,
+}
+impl MdnsService {
+    pub fn from_config(static_config: &StaticConfiguration) -> Self {
+        let mut instance = format!("{}@{}", static_config.name, static_config.wg_ip);
+        while instance.len() > 63 {
+            instance.remove(0);
+        }
+        let host = format!(
+            "wgnetmgr-{}.local",
+            static_config.wg_ip.to_string().replace(['.', ':'], "-")
+        );
+        MdnsService {
+            instance,
+            host,
+            addresses: static_config
+                .ip_list
+                .iter()
+                .filter(|ip| !ip.is_loopback())
+                .copied()
+                .collect(),
+            admin_port: static_config.admin_port,
+            wg_ip: static_config.wg_ip,
+            mesh: mesh_fingerprint(&static_config.shared_key),
+        }
+    }
+    // The instance is a single label, even with dots in the wg_ip
+    fn full_name(&self) -> Vec<&str> {
+        let mut name = vec![self.instance.as_str()];
+        name.extend(labels(SERVICE));
+        name
+    }
+    pub fn admin_endpoints(&self) -> Vec<SocketAddr> {
+        self.addresses
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.admin_port))
+            .collect()
+    }
+    // Unsolicited response with all records of the service
+    pub fn announcement(&self) -> Vec<u8> {
+        let records = 3 + self.addresses.len() as u16;
+        let mut packet = header(0x8400, 0, records);
+        let full_name = self.full_name();
+
+        push_record(&mut packet, &labels(SERVICE), TYPE_PTR, CLASS_IN, |p| {
+            push_name(p, &full_name)
+        });
+        push_record(
+            &mut packet,
+            &full_name,
+            TYPE_SRV,
+            CLASS_IN | CACHE_FLUSH,
+            |p| {
+                p.extend_from_slice(&[0, 0, 0, 0]);
+                p.extend_from_slice(&self.admin_port.to_be_bytes());
+                push_name(p, &labels(&self.host));
+            },
+        );
+        push_record(
+            &mut packet,
+            &full_name,
+            TYPE_TXT,
+            CLASS_IN | CACHE_FLUSH,
+            |p| {
+                for entry in [
+                    format!("wg_ip={}", self.wg_ip),
+                    format!("mesh={}", self.mesh),
+                ] {
+                    p.push(entry.len() as u8);
+                    p.extend_from_slice(entry.as_bytes());
+                }
+            },
+        );
+        for ip in self.addresses.iter() {
+            match ip {
+                IpAddr::V4(ip) => push_record(
+                    &mut packet,
+                    &labels(&self.host),
+                    TYPE_A,
+                    CLASS_IN | CACHE_FLUSH,
+                    |p| p.extend_from_slice(&ip.octets()),
+                ),
+                IpAddr::V6(ip) => push_record(
+                    &mut packet,
+                    &labels(&self.host),
+                    TYPE_AAAA,
+                    CLASS_IN | CACHE_FLUSH,
+                    |p| p.extend_from_slice(&ip.octets()),
+                ),
+            }
+        }
+        packet
+    }
+}
+
+// Query for all instances of the service
+pub fn query() -> Vec<u8> {
+    let mut packet = header(0, 1, 0);
+    push_name(&mut packet, &labels(SERVICE));
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet
+}
+
+fn header(flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    let mut packet = vec![0, 0];
+    for value in [flags, questions, answers, 0, 0] {
+        packet.extend_from_slice(&value.to_be_bytes());
+    }
+    packet
+}
+fn labels(name: &str) -> Vec<&str> {
+    name.split('.').filter(|label| !label.is_empty()).collect()
+}
+fn push_name(packet: &mut Vec<u8>, name: &[&str]) {
+    for label in name.iter() {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+fn push_record<F: FnOnce(&mut Vec<u8>)>(
+    packet: &mut Vec<u8>,
+    name: &[&str],
+    rtype: u16,
+    class: u16,
+    rdata: F,
+) {
+    push_name(packet, name);
+    packet.extend_from_slice(&rtype.to_be_bytes());
+    packet.extend_from_slice(&class.to_be_bytes());
+    packet.extend_from_slice(&TTL.to_be_bytes());
+    let len_pos = packet.len();
+    packet.extend_from_slice(&[0, 0]);
+    rdata(packet);
+    let len = (packet.len() - len_pos - 2) as u16;
+    packet[len_pos..len_pos + 2].copy_from_slice(&len.to_be_bytes());
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MdnsPacket {
+    // A query for the service, which is answered with the announcement
+    pub asks_for_service: bool,
+    pub services: Vec<MdnsService>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    fn slice(&self, pos: usize, len: usize) -> BoxResult<&'a [u8]> {
+        match self.data.get(pos..pos + len) {
+            Some(bytes) => Ok(bytes),
+            None => strerror("mdns packet too short"),
+        }
+    }
+    fn bytes(&mut self, len: usize) -> BoxResult<&'a [u8]> {
+        let bytes = self.slice(self.pos, len)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn u16(&mut self) -> BoxResult<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+    // Names may be compressed by pointers to earlier names
+    fn name(&mut self) -> BoxResult<String> {
+        let mut labels = vec![];
+        let mut pos = self.pos;
+        let mut jumps = 0;
+        loop {
+            let len = self.slice(pos, 1)?[0] as usize;
+            if len & 0xc0 == 0xc0 {
+                let low = self.slice(pos + 1, 1)?[0] as usize;
+                if jumps == 0 {
+                    self.pos = pos + 2;
+                }
+                jumps += 1;
+                if jumps > 16 {
+                    return strerror("mdns name with pointer loop");
+                }
+                pos = ((len & 0x3f) << 8) | low;
+                continue;
+            }
+            if len == 0 {
+                if jumps == 0 {
+                    self.pos = pos + 1;
+                }
+                return Ok(labels.join("."));
+            }
+            let label = self.slice(pos + 1, len)?;
+            labels.push(String::from_utf8_lossy(label).to_string());
+            pos += 1 + len;
+        }
+    }
+}
+
+pub fn decode(data: &[u8]) -> BoxResult<MdnsPacket> {
+    let mut reader = Reader { data, pos: 0 };
+    let header = reader.bytes(12)?;
+    let count = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]) as usize;
+    let is_response = header[2] & 0x80 != 0;
+    let nr_questions = count(4);
+    let nr_records = count(6) + count(8) + count(10);
+
+    let mut packet = MdnsPacket::default();
+    for _ in 0..nr_questions {
+        let name = reader.name()?;
+        let qtype = reader.u16()?;
+        reader.u16()?;
+        if !is_response && name.eq_ignore_ascii_case(SERVICE) {
+            packet.asks_for_service |= qtype == TYPE_PTR || qtype == TYPE_ANY;
+        }
+    }
+    if !is_response {
+        return Ok(packet);
+    }
+
+    let mut srv = HashMap::new();
+    let mut txt: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut addresses: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for _ in 0..nr_records {
+        let name = reader.name()?.to_ascii_lowercase();
+        let rtype = reader.u16()?;
+        reader.bytes(6)?;
+        let len = reader.u16()? as usize;
+        let end = reader.pos + len;
+        match rtype {
+            TYPE_SRV => {
+                reader.bytes(4)?;
+                let port = reader.u16()?;
+                let target = reader.name()?.to_ascii_lowercase();
+                srv.insert(name, (port, target));
+            }
+            TYPE_TXT => {
+                let mut entries = Reader {
+                    data: reader.bytes(len)?,
+                    pos: 0,
+                };
+                let values = txt.entry(name).or_default();
+                while entries.pos < len {
+                    let entry_len = entries.bytes(1)?[0] as usize;
+                    let entry = String::from_utf8_lossy(entries.bytes(entry_len)?).to_string();
+                    if let Some((key, value)) = entry.split_once('=') {
+                        values.insert(key.to_string(), value.to_string());
+                    }
+                }
+            }
+            TYPE_A if len == 4 => {
+                let octets: [u8; 4] = reader.bytes(4)?.try_into().unwrap();
+                addresses
+                    .entry(name)
+                    .or_default()
+                    .push(Ipv4Addr::from(octets).into());
+            }
+            TYPE_AAAA if len == 16 => {
+                let octets: [u8; 16] = reader.bytes(16)?.try_into().unwrap();
+                addresses
+                    .entry(name)
+                    .or_default()
+                    .push(Ipv6Addr::from(octets).into());
+            }
+            _ => {}
+        }
+        reader.pos = end;
+    }
+
+    let suffix = format!(".{}", SERVICE);
+    for (name, (admin_port, host)) in srv.into_iter() {
+        let Some(instance) = name.strip_suffix(&suffix) else {
+            continue;
+        };
+        let Some(values) = txt.get(&name) else {
+            continue;
+        };
+        let (Some(wg_ip), Some(mesh)) = (
+            values.get("wg_ip").and_then(|ip| ip.parse().ok()),
+            values.get("mesh"),
+        ) else {
+            continue;
+        };
+        packet.services.push(MdnsService {
+            instance: instance.to_string(),
+            addresses: addresses.get(&host).cloned().unwrap_or_default(),
+            host,
+            admin_port,
+            wg_ip,
+            mesh: mesh.clone(),
+        });
+    }
+    packet.services.sort_by(|a, b| a.instance.cmp(&b.instance));
+    Ok(packet)
+}
+
+fn bind(ip_list: &[IpAddr]) -> BoxResult<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), MDNS_PORT);
+    socket.bind(&addr.into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    // On multi-homed hosts, join on every local network
+    for ip in ip_list.iter() {
+        if let IpAddr::V4(ip) = ip {
+            if !ip.is_loopback() {
+                if let Err(e) = socket.join_multicast_v4(&MDNS_GROUP, ip) {
+                    debug!(target: "mdns", "Cannot join multicast group on {}: {}", ip, e);
+                }
+            }
+        }
+    }
+    let socket: UdpSocket = socket.into();
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    Ok(socket)
+}
+
+// Announces the own service and forwards the nodes of the own mesh as Event::MdnsPeer.
+// Nodes of other meshes are only logged.
+pub fn start(static_config: &StaticConfiguration, tx: EventSender) -> BoxResult<()> {
+    let socket = bind(&static_config.ip_list)?;
+    let service = MdnsService::from_config(static_config);
+    info!(target: "mdns", "Announce {} via mDNS", service.instance);
+    std::thread::spawn(move || {
+        if let Err(e) = run(socket, service, tx) {
+            warn!(target: "mdns", "mDNS stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+fn run(socket: UdpSocket, service: MdnsService, tx: EventSender) -> BoxResult<()> {
+    let group = SocketAddr::new(MDNS_GROUP.into(), MDNS_PORT);
+    let announcement = service.announcement();
+    let mut next_announcement = 0;
+    let mut last_answer = 0;
+    let mut other_meshes = HashSet::new();
+    let mut buf = [0u8; 9000];
+    loop {
+        let now = crate::util::now();
+        if now >= next_announcement {
+            next_announcement = now + ANNOUNCE_INTERVAL;
+            socket.send_to(&announcement, group)?;
+            socket.send_to(&query(), group)?;
+        }
+        let (len, src_addr) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let packet = match decode(&buf[..len]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                trace!(target: "mdns", "Invalid packet from {}: {}", src_addr, e);
+                continue;
+            }
+        };
+        // At most one answer per second
+        if packet.asks_for_service && now > last_answer {
+            last_answer = now;
+            socket.send_to(&announcement, group)?;
+        }
+        for found in packet.services.into_iter() {
+            if found.instance.eq_ignore_ascii_case(&service.instance) {
+                continue;
+            }
+            if found.mesh != service.mesh {
+                if other_meshes.insert(found.instance.clone()) {
+                    info!(target: "mdns", "Found {} of another mesh {} at {:?}", found.instance, found.mesh, found.addresses);
+                }
+                continue;
+            }
+            debug!(target: "mdns", "Found {} at {:?}", found.instance, found.addresses);
+            if tx.send(Event::MdnsPeer(found)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,528 @@
+// In-memory transport of the admin packets, e.g. for tests of several nodes without
+// root or real interfaces.
+//
+// A MemoryNetwork connects MemoryUdp sockets, which have the send/recv surface of
+// CryptUdp and encrypt alike. A socket is bound to an address of the underlay. With
+// a MockWireguardDevice attached via tunnel(), the socket is reachable on its port at
+// the addresses of the device, too. Such a packet passes only, if the configuration
+// of the sending device has a peer with an allowed ip for the destination and the
+// current endpoint of this peer reaches the listen port of the receiving device. The
+// receiving device learns the endpoint of the sender like wireguard does, and the
+// handshake and the transfer are recorded on both devices. Like udp, undeliverable
+// packets are dropped silently.
+//
+// For simulations, a link between two hosts may lose or delay packets or be down,
+// and hosts may be behind a NAT. The delays are measured on the mesh clock, which is
+// advanced by advance_to(). Without this clock, packets are never delayed.
+//
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use log::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::crypt_udp::{PacketCipher, UdpCounters};
+use crate::error::*;
+use crate::link_local::check_scope;
+use crate::wg_dev::{MockDeviceState, MockWireguardDevice};
+
+// Conditions of the link between two hosts
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    pub loss_percent: u32,
+    pub latency_ms: u64,
+    // Additional random delay up to jitter_ms, so packets may be reordered
+    pub jitter_ms: u64,
+    pub down: bool,
+}
+impl LinkConditions {
+    pub fn down() -> Self {
+        LinkConditions {
+            down: true,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    // Any host may send to a mapped port
+    FullCone,
+    // Only the hosts and ports, which have been sent to, may send to a mapped port
+    PortRestricted,
+    // Another mapped port for each destination, so NAT traversal fails
+    Symmetric,
+}
+
+struct NatMapping {
+    inside: SocketAddr,
+    // Only for Symmetric
+    remote: Option<SocketAddr>,
+    public_port: u16,
+    contacted: HashSet<SocketAddr>,
+}
+
+struct Nat {
+    public_ip: IpAddr,
+    nat_type: NatType,
+    inside: Vec<IpAddr>,
+    mappings: Vec<NatMapping>,
+    next_port: u16,
+}
+impl Nat {
+    fn outbound(&mut self, src: SocketAddr, dst: SocketAddr) -> SocketAddr {
+        let symmetric = self.nat_type == NatType::Symmetric;
+        let pos = self
+            .mappings
+            .iter()
+            .position(|m| m.inside == src && (!symmetric || m.remote == Some(dst)));
+        let pos = match pos {
+            Some(pos) => pos,
+            None => {
+                // The port is kept, if possible
+                let port_in_use = |port| self.mappings.iter().any(|m| m.public_port == port);
+                let public_port = if symmetric || port_in_use(src.port()) {
+                    while port_in_use(self.next_port) {
+                        self.next_port = self.next_port.wrapping_add(1).max(1024);
+                    }
+                    self.next_port
+                } else {
+                    src.port()
+                };
+                self.mappings.push(NatMapping {
+                    inside: src,
+                    remote: symmetric.then_some(dst),
+                    public_port,
+                    contacted: HashSet::new(),
+                });
+                self.mappings.len() - 1
+            }
+        };
+        let mapping = &mut self.mappings[pos];
+        mapping.contacted.insert(dst);
+        SocketAddr::new(self.public_ip, mapping.public_port)
+    }
+    fn inbound(&self, src: SocketAddr, dst: SocketAddr) -> Option<SocketAddr> {
+        let mapping = self.mappings.iter().find(|m| m.public_port == dst.port())?;
+        let allowed = match self.nat_type {
+            NatType::FullCone => true,
+            NatType::PortRestricted => mapping.contacted.contains(&src),
+            NatType::Symmetric => mapping.remote == Some(src),
+        };
+        allowed.then_some(mapping.inside)
+    }
+}
+
+// Forwarding loops end after so many nodes
+const MAX_HOPS: usize = 8;
+
+struct Endpoint {
+    addr: SocketAddr,
+    device: Option<MockWireguardDevice>,
+    // Received datagrams with the source address
+    queue: VecDeque<(Vec<u8>, SocketAddr)>,
+}
+
+struct InFlight {
+    due_ms: u64,
+    to: u64,
+    datagram: Vec<u8>,
+    src_addr: SocketAddr,
+}
+
+struct Sockets {
+    next_id: u64,
+    endpoints: HashMap<u64, Endpoint>,
+    delivered: usize,
+    dropped: usize,
+    conditions: LinkConditions,
+    // Per pair of hosts in the order of the addresses
+    links: HashMap<(IpAddr, IpAddr), LinkConditions>,
+    nats: Vec<Nat>,
+    clock_ms: Option<u64>,
+    in_flight: Vec<InFlight>,
+    rng: StdRng,
+}
+impl Default for Sockets {
+    fn default() -> Self {
+        Sockets {
+            next_id: 0,
+            endpoints: HashMap::new(),
+            delivered: 0,
+            dropped: 0,
+            conditions: LinkConditions::default(),
+            links: HashMap::new(),
+            nats: vec![],
+            clock_ms: None,
+            in_flight: vec![],
+            rng: StdRng::seed_from_u64(0),
+        }
+    }
+}
+
+fn link_key(a: IpAddr, b: IpAddr) -> (IpAddr, IpAddr) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+impl Sockets {
+    // The source as seen by the destination and the final destination. None, if the
+    // destination is not reachable from src, e.g. behind a NAT.
+    fn route(&mut self, src: SocketAddr, dst: SocketAddr) -> Option<(SocketAddr, SocketAddr)> {
+        let src_nat = self.nats.iter().position(|n| n.inside.contains(&src.ip()));
+        let dst_nat = self.nats.iter().position(|n| n.inside.contains(&dst.ip()));
+        if src_nat.is_some() && src_nat == dst_nat {
+            return Some((src, dst));
+        }
+        if dst_nat.is_some() {
+            // A private address behind another NAT
+            return None;
+        }
+        let seen_src = match src_nat {
+            Some(i) => self.nats[i].outbound(src, dst),
+            None => src,
+        };
+        match self.nats.iter().find(|n| n.public_ip == dst.ip()) {
+            Some(nat) => Some((seen_src, nat.inbound(seen_src, dst)?)),
+            None => Some((seen_src, dst)),
+        }
+    }
+    fn conditions(&self, a: IpAddr, b: IpAddr) -> LinkConditions {
+        self.links
+            .get(&link_key(a, b))
+            .copied()
+            .unwrap_or(self.conditions)
+    }
+    // Rolls the dice for a packet on the link between the hosts, returns the delay
+    fn pass(&mut self, a: IpAddr, b: IpAddr) -> Option<u64> {
+        let conditions = self.conditions(a, b);
+        if conditions.down || self.rng.gen_range(0..100) < conditions.loss_percent {
+            return None;
+        }
+        let jitter_ms = match conditions.jitter_ms {
+            0 => 0,
+            jitter_ms => self.rng.gen_range(0..=jitter_ms),
+        };
+        Some(conditions.latency_ms + jitter_ms)
+    }
+    // Via the wireguard tunnel, if the device of the sender routes the destination.
+    // A node forwards a packet for another address into its tunnel again like the
+    // kernel does. Returns the receiving socket, the source address seen by it and the
+    // delay, resp. None inside if the packet is lost.
+    fn tunnel(
+        &mut self,
+        device: &MockWireguardDevice,
+        host: IpAddr,
+        port: u16,
+        to: SocketAddr,
+        len: u64,
+    ) -> Option<Option<(u64, SocketAddr, u64)>> {
+        let state = device.state();
+        state.peer_for(&to.ip())?;
+        Some(self.forward(device.clone(), state, host, port, to, len))
+    }
+    fn forward(
+        &mut self,
+        mut device: MockWireguardDevice,
+        mut state: MockDeviceState,
+        mut host: IpAddr,
+        port: u16,
+        to: SocketAddr,
+        len: u64,
+    ) -> Option<(u64, SocketAddr, u64)> {
+        let src_ip = state
+            .ips()
+            .into_iter()
+            .find(|ip| ip.is_ipv4() == to.is_ipv4())?;
+        let now = self
+            .clock_ms
+            .map(|ms| crate::util::to_wall_time(ms / 1000))
+            .unwrap_or_else(crate::util::wall_now);
+        let mut delay_ms = 0;
+        for _ in 0..MAX_HOPS {
+            let peer_key = state.peer_for(&to.ip())?;
+            let public_key = state.public_key()?;
+            let endpoint = *state.endpoints.get(&peer_key)?;
+            let wg_src = SocketAddr::new(host, state.listen_port()?);
+            let (seen_wg_src, wg_dst) = self.route(wg_src, endpoint)?;
+            let (peer_device, peer_state) = self.endpoints.values().find_map(|e| {
+                let peer_device = e.device.as_ref()?;
+                let peer_state = peer_device.state();
+                (e.addr.ip() == wg_dst.ip()
+                    && peer_state.listen_port() == Some(wg_dst.port())
+                    && peer_state.public_key().as_deref() == Some(&peer_key))
+                .then(|| (peer_device.clone(), peer_state))
+            })?;
+            delay_ms += self.pass(host, wg_dst.ip())?;
+            device.record_handshake(&peer_key, now);
+            device.record_transfer(&peer_key, 0, len);
+            peer_device.record_handshake(&public_key, now);
+            peer_device.record_endpoint(&public_key, seen_wg_src);
+            peer_device.record_transfer(&public_key, len, 0);
+            // Wireguard accepts only sources within the allowed ips of the peer
+            if peer_state.peer_for(&src_ip).as_deref() != Some(&public_key) {
+                return None;
+            }
+            if peer_state.ips().contains(&to.ip()) {
+                let id = self
+                    .endpoints
+                    .iter()
+                    .find(|(_, e)| e.addr.ip() == wg_dst.ip() && e.addr.port() == to.port())
+                    .map(|(id, _)| *id)?;
+                return Some((id, SocketAddr::new(src_ip, port), delay_ms));
+            }
+            device = peer_device;
+            state = peer_state;
+            host = wg_dst.ip();
+        }
+        None
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    sockets: Arc<(Mutex<Sockets>, Condvar)>,
+}
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        MemoryNetwork::default()
+    }
+    // The losses and delays are reproducible for a given seed
+    pub fn with_seed(seed: u64) -> Self {
+        let network = MemoryNetwork::default();
+        network.sockets.0.lock().unwrap().rng = StdRng::seed_from_u64(seed);
+        network
+    }
+    pub fn delivered(&self) -> usize {
+        self.sockets.0.lock().unwrap().delivered
+    }
+    pub fn dropped(&self) -> usize {
+        self.sockets.0.lock().unwrap().dropped
+    }
+    // For all links without own conditions
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.sockets.0.lock().unwrap().conditions = conditions;
+    }
+    pub fn set_link(&self, a: IpAddr, b: IpAddr, conditions: LinkConditions) {
+        let mut sockets = self.sockets.0.lock().unwrap();
+        sockets.links.insert(link_key(a, b), conditions);
+    }
+    pub fn clear_links(&self) {
+        self.sockets.0.lock().unwrap().links.clear();
+    }
+    // The hosts with the inside addresses reach the others via public_ip
+    pub fn add_nat(&self, public_ip: IpAddr, nat_type: NatType, inside: &[IpAddr]) {
+        self.sockets.0.lock().unwrap().nats.push(Nat {
+            public_ip,
+            nat_type,
+            inside: inside.to_vec(),
+            mappings: vec![],
+            next_port: 40000,
+        });
+    }
+    // Delivers the delayed packets, which are due
+    pub fn advance_to(&self, now_ms: u64) {
+        let (lock, cvar) = &*self.sockets;
+        let mut sockets = lock.lock().unwrap();
+        sockets.clock_ms = Some(now_ms);
+        let (due, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut sockets.in_flight)
+            .into_iter()
+            .partition(|p| p.due_ms <= now_ms);
+        sockets.in_flight = pending;
+        let mut due = due;
+        due.sort_by_key(|p| p.due_ms);
+        for p in due {
+            if let Some(endpoint) = sockets.endpoints.get_mut(&p.to) {
+                endpoint.queue.push_back((p.datagram, p.src_addr));
+                sockets.delivered += 1;
+            }
+        }
+        cvar.notify_all();
+    }
+    // Packets, which are delayed and not yet delivered
+    pub fn in_flight(&self) -> usize {
+        self.sockets.0.lock().unwrap().in_flight.len()
+    }
+    fn bind(&self, addr: SocketAddr) -> BoxResult<u64> {
+        let mut sockets = self.sockets.0.lock().unwrap();
+        if sockets.endpoints.values().any(|e| e.addr == addr) {
+            return Err(format!("{} is in use", addr).into());
+        }
+        sockets.next_id += 1;
+        let id = sockets.next_id;
+        sockets.endpoints.insert(
+            id,
+            Endpoint {
+                addr,
+                device: None,
+                queue: VecDeque::new(),
+            },
+        );
+        Ok(id)
+    }
+    fn unbind(&self, id: u64) {
+        self.sockets.0.lock().unwrap().endpoints.remove(&id);
+    }
+    fn attach(&self, id: u64, device: &MockWireguardDevice) {
+        if let Some(endpoint) = self.sockets.0.lock().unwrap().endpoints.get_mut(&id) {
+            endpoint.device = Some(device.clone());
+        }
+    }
+    // Via the tunnel, if the device of the sender routes the destination
+    fn deliver(&self, from: u64, to: SocketAddr, datagram: Vec<u8>) {
+        let (lock, cvar) = &*self.sockets;
+        let mut sockets = lock.lock().unwrap();
+        let (sender_addr, sender_device) = match sockets.endpoints.get(&from) {
+            Some(sender) => (sender.addr, sender.device.clone()),
+            None => return,
+        };
+        let len = datagram.len() as u64;
+        let tunnel = sender_device.as_ref().and_then(|device| {
+            sockets.tunnel(device, sender_addr.ip(), sender_addr.port(), to, len)
+        });
+        let receiver = match tunnel {
+            Some(via_tunnel) => via_tunnel,
+            None => sockets.route(sender_addr, to).and_then(|(src_addr, dst)| {
+                let id = sockets
+                    .endpoints
+                    .iter()
+                    .find(|(_, e)| e.addr == dst)
+                    .map(|(id, _)| *id)?;
+                let delay_ms = sockets.pass(sender_addr.ip(), dst.ip())?;
+                Some((id, src_addr, delay_ms))
+            }),
+        };
+        let (id, src_addr, delay_ms) = match receiver {
+            Some(receiver) => receiver,
+            None => {
+                trace!(target: "udp", "Drop packet from {} to {}", sender_addr, to);
+                sockets.dropped += 1;
+                return;
+            }
+        };
+        match sockets.clock_ms {
+            Some(clock_ms) if delay_ms > 0 => sockets.in_flight.push(InFlight {
+                due_ms: clock_ms + delay_ms,
+                to: id,
+                datagram,
+                src_addr,
+            }),
+            _ => {
+                if let Some(endpoint) = sockets.endpoints.get_mut(&id) {
+                    endpoint.queue.push_back((datagram, src_addr));
+                }
+                sockets.delivered += 1;
+                cvar.notify_all();
+            }
+        }
+    }
+    // Waits up to timeout for a datagram, forever without timeout
+    fn receive(&self, id: u64, timeout: Option<Duration>) -> Option<(Vec<u8>, SocketAddr)> {
+        let (lock, cvar) = &*self.sockets;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut sockets = lock.lock().unwrap();
+        loop {
+            if let Some(datagram) = sockets
+                .endpoints
+                .get_mut(&id)
+                .and_then(|e| e.queue.pop_front())
+            {
+                return Some(datagram);
+            }
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    sockets = cvar.wait_timeout(sockets, deadline - now).unwrap().0;
+                }
+                None => sockets = cvar.wait(sockets).unwrap(),
+            }
+        }
+    }
+}
+
+pub struct MemoryUdp {
+    network: MemoryNetwork,
+    id: u64,
+    local_addr: SocketAddr,
+    cipher: PacketCipher,
+    read_timeout: Option<Duration>,
+}
+impl MemoryUdp {
+    pub fn bind(network: &MemoryNetwork, ip: IpAddr, port: u16) -> BoxResult<Self> {
+        let local_addr = SocketAddr::new(ip, port);
+        Ok(MemoryUdp {
+            network: network.clone(),
+            id: network.bind(local_addr)?,
+            local_addr,
+            cipher: PacketCipher::default(),
+            read_timeout: None,
+        })
+    }
+    pub fn key(mut self, key: &[u8]) -> BoxResult<Self> {
+        self.cipher.set_key(key)?;
+        Ok(self)
+    }
+    pub fn accepted_keys(mut self, keys: &[Vec<u8>]) -> BoxResult<Self> {
+        self.cipher.set_accepted_keys(keys)?;
+        Ok(self)
+    }
+    // Reachable at the addresses of the device, too
+    pub fn tunnel(self, device: &MockWireguardDevice) -> Self {
+        self.network.attach(self.id, device);
+        self
+    }
+    pub fn counters(&self) -> Arc<UdpCounters> {
+        self.cipher.counters()
+    }
+    pub fn local_addr(&self) -> BoxResult<SocketAddr> {
+        Ok(self.local_addr)
+    }
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> BoxResult<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+    pub fn send_to(&mut self, payload: &[u8], addr: SocketAddr) -> BoxResult<usize> {
+        check_scope(&addr)?;
+        let encrypted = self.cipher.encrypt(payload, addr)?;
+        let sent = encrypted.len();
+        self.network.deliver(self.id, addr, encrypted);
+        self.cipher.count_sent();
+        Ok(sent)
+    }
+    // Like a udp socket with read timeout, an error on timeout
+    pub fn recv_from(&self, buf: &mut [u8]) -> BoxResult<(usize, SocketAddr)> {
+        match self.network.receive(self.id, self.read_timeout) {
+            Some((datagram, src_addr)) => {
+                let p = self.cipher.decrypt(&datagram, src_addr, buf)?;
+                Ok((p, src_addr))
+            }
+            None => Err(Box::new(std::io::Error::from(
+                std::io::ErrorKind::WouldBlock,
+            ))),
+        }
+    }
+    // None, if no datagram is waiting
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> BoxResult<Option<(usize, SocketAddr)>> {
+        match self.network.receive(self.id, Some(Duration::ZERO)) {
+            Some((datagram, src_addr)) => {
+                let p = self.cipher.decrypt(&datagram, src_addr, buf)?;
+                Ok(Some((p, src_addr)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+impl Drop for MemoryUdp {
+    fn drop(&mut self) {
+        self.network.unbind(self.id);
+    }
+}
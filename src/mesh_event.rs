@@ -0,0 +1,61 @@
+// Typed events about changes of the mesh as seen by the NetworkManager.
+//
+// In contrast to crate::event::Event, which drives the run loop, these are
+// notifications only. Any number of subscribers can receive them via an
+// own channel. Subscribers, which have dropped their receiver, are removed.
+//
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[non_exhaustive]
+pub enum MeshEvent {
+    PeerUp {
+        wg_ip: IpAddr,
+        name: Option<String>,
+    },
+    PeerDown {
+        wg_ip: IpAddr,
+    },
+    RouteAdded {
+        to: IpAddr,
+        gateway: Option<IpAddr>,
+    },
+    RouteReplaced {
+        to: IpAddr,
+        gateway: Option<IpAddr>,
+    },
+    RouteRemoved {
+        to: IpAddr,
+    },
+    EndpointChanged {
+        wg_ip: IpAddr,
+        endpoint: Option<SocketAddr>,
+    },
+    KeyRotated {
+        wg_ip: IpAddr,
+        public_key: String,
+    },
+    // A node has changed its wg_ip
+    PeerMoved {
+        from: IpAddr,
+        to: IpAddr,
+    },
+}
+
+#[derive(Default)]
+pub struct MeshEventBus {
+    subscribers: Vec<Sender<MeshEvent>>,
+}
+impl MeshEventBus {
+    pub fn subscribe(&mut self) -> Receiver<MeshEvent> {
+        let (tx, rx) = channel();
+        self.subscribers.push(tx);
+        rx
+    }
+    pub fn publish(&mut self, evt: MeshEvent) {
+        self.subscribers.retain(|tx| tx.send(evt.clone()).is_ok());
+    }
+}
@@ -0,0 +1,289 @@
+// Prometheus metrics of the running daemon.
+//
+// The page is rendered by the main loop every INTERVAL seconds and a listener thread
+// serves the latest rendering. So a scrape never waits for the main loop, and wg is
+// not queried for the handshakes on every scrape.
+//
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+use log::*;
+
+use crate::crypt_udp::UdpCounts;
+use crate::error::*;
+use crate::http_server;
+use crate::manager::{ManagerCounters, NetworkManager};
+use crate::nat_telemetry::TraversalCount;
+
+const INTERVAL: u64 = 5;
+// The window for the route changes per minute
+const RATE_WINDOW: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Metrics {
+    // Number of nodes per endpoint class
+    pub nodes: BTreeMap<String, usize>,
+    pub routes: usize,
+    pub routedb_version: usize,
+    pub advertisements_sent: u64,
+    pub manager: ManagerCounters,
+    pub udp: UdpCounts,
+    pub route_changes_per_minute: u64,
+    // wg_ip, name and seconds since the latest handshake of the peers
+    pub handshake_ages: Vec<(IpAddr, String, u64)>,
+    pub nat_traversals: Vec<TraversalCount>,
+}
+impl Metrics {
+    pub fn of(
+        now: u64,
+        network_manager: &NetworkManager,
+        udp: UdpCounts,
+        advertisements_sent: u64,
+        latest_handshakes: &HashMap<String, u64>,
+    ) -> Self {
+        let mut nodes = BTreeMap::new();
+        for (_, class) in network_manager.node_classes() {
+            *nodes.entry(class.to_string()).or_default() += 1;
+        }
+        let handshake_ages = network_manager
+            .peer_infos(now)
+            .into_iter()
+            .filter_map(|p| {
+                let latest = latest_handshakes.get(p.public_key.as_ref()?)?;
+                Some((
+                    p.wg_ip,
+                    p.name.unwrap_or_default(),
+                    now.saturating_sub(*latest),
+                ))
+            })
+            .collect();
+        Metrics {
+            nodes,
+            routes: network_manager.routes().len(),
+            routedb_version: network_manager.db_version(),
+            advertisements_sent,
+            manager: network_manager.counters(),
+            udp,
+            route_changes_per_minute: 0,
+            handshake_ages,
+            nat_traversals: network_manager.nat_telemetry().counts(),
+        }
+    }
+    // Text exposition format of prometheus
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+            writeln!(out, "# HELP wg_netmanager_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE wg_netmanager_{} {}", name, kind).unwrap();
+            for (labels, value) in samples {
+                writeln!(out, "wg_netmanager_{}{} {}", name, labels, value).unwrap();
+            }
+        };
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|(class, cnt)| (format!("{{class=\"{}\"}}", class), *cnt as u64))
+            .collect::<Vec<_>>();
+        metric("nodes", "gauge", "Known nodes by class", &nodes);
+        metric(
+            "routes",
+            "gauge",
+            "Entries of the route table",
+            &[(String::new(), self.routes as u64)],
+        );
+        metric(
+            "routedb_version",
+            "gauge",
+            "Version of the route database",
+            &[(String::new(), self.routedb_version as u64)],
+        );
+        metric(
+            "advertisements_sent_total",
+            "counter",
+            "Sent advertisements",
+            &[(String::new(), self.advertisements_sent)],
+        );
+        metric(
+            "advertisements_received_total",
+            "counter",
+            "Received advertisements",
+            &[(String::new(), self.manager.advertisements_received)],
+        );
+        metric(
+            "udp_packets_sent_total",
+            "counter",
+            "Sent packets on the admin port",
+            &[(String::new(), self.udp.sent)],
+        );
+        metric(
+            "udp_packets_received_total",
+            "counter",
+            "Accepted packets on the admin port",
+            &[(String::new(), self.udp.received)],
+        );
+        metric(
+            "udp_decrypt_failures_total",
+            "counter",
+            "Packets on the admin port, which could not be decrypted",
+            &[(String::new(), self.udp.decrypt_failures)],
+        );
+        metric(
+            "route_changes_total",
+            "counter",
+            "Changes of the installed routes",
+            &[(String::new(), self.manager.route_changes)],
+        );
+        metric(
+            "route_changes_per_minute",
+            "gauge",
+            "Changes of the installed routes within the last minute",
+            &[(String::new(), self.route_changes_per_minute)],
+        );
+        let ages = self
+            .handshake_ages
+            .iter()
+            .map(|(wg_ip, name, age)| {
+                (
+                    format!("{{wg_ip=\"{}\",name=\"{}\"}}", wg_ip, escape_label(name)),
+                    *age,
+                )
+            })
+            .collect::<Vec<_>>();
+        metric(
+            "handshake_age_seconds",
+            "gauge",
+            "Seconds since the latest wireguard handshake per peer",
+            &ages,
+        );
+        let traversal_labels = |c: &TraversalCount| {
+            format!(
+                "{{candidate=\"{}\",outcome=\"{}\"}}",
+                c.candidate.as_str(),
+                c.outcome.as_str()
+            )
+        };
+        let traversals = self
+            .nat_traversals
+            .iter()
+            .map(|c| (traversal_labels(c), c.count))
+            .collect::<Vec<_>>();
+        metric(
+            "nat_traversals_total",
+            "counter",
+            "NAT traversals to distant nodes by candidate and outcome",
+            &traversals,
+        );
+        let durations = self
+            .nat_traversals
+            .iter()
+            .map(|c| (traversal_labels(c), c.duration_s))
+            .collect::<Vec<_>>();
+        metric(
+            "nat_traversal_seconds_total",
+            "counter",
+            "Time to success resp. failure of the NAT traversals",
+            &durations,
+        );
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub struct MetricsExporter {
+    page: Arc<Mutex<String>>,
+    local_addr: SocketAddr,
+    last_rendered: Option<u64>,
+    // Total route changes over the last RATE_WINDOW seconds
+    route_changes: VecDeque<(u64, u64)>,
+}
+impl MetricsExporter {
+    // With a token, scrapes need it as bearer token
+    pub fn listen(addr: SocketAddr, token: Option<String>) -> BoxResult<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        info!(target: "metrics", "Serve metrics on {}", local_addr);
+        let page = Arc::new(Mutex::new(String::new()));
+        let page_clone = page.clone();
+        http_server::listen(
+            listener,
+            "metrics",
+            "text/plain; version=0.0.4",
+            token,
+            move |request| {
+                if request.method == "GET" && (request.path == "/metrics" || request.path == "/") {
+                    ("200 OK", page_clone.lock().unwrap().clone())
+                } else {
+                    ("404 Not Found", String::new())
+                }
+            },
+        );
+        Ok(MetricsExporter {
+            page,
+            local_addr,
+            last_rendered: None,
+            route_changes: VecDeque::new(),
+        })
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+    // The page is rendered at most every INTERVAL seconds
+    pub fn is_due(&self, now: u64) -> bool {
+        self.last_rendered
+            .map(|last_rendered| now >= last_rendered + INTERVAL)
+            .unwrap_or(true)
+    }
+    // Called every second. Only every INTERVAL seconds the page is rendered.
+    pub fn update<F>(
+        &mut self,
+        now: u64,
+        network_manager: &NetworkManager,
+        udp: UdpCounts,
+        advertisements_sent: u64,
+        latest_handshakes: F,
+    ) -> bool
+    where
+        F: FnOnce() -> BoxResult<HashMap<String, u64>>,
+    {
+        if !self.is_due(now) {
+            return false;
+        }
+        self.last_rendered = Some(now);
+        let latest_handshakes = latest_handshakes().unwrap_or_else(|e| {
+            debug!(target: "metrics", "Cannot get latest handshakes: {:?}", e);
+            HashMap::new()
+        });
+        let mut metrics = Metrics::of(
+            now,
+            network_manager,
+            udp,
+            advertisements_sent,
+            &latest_handshakes,
+        );
+
+        let total = metrics.manager.route_changes;
+        self.route_changes.push_back((now, total));
+        while let Some((t, _)) = self.route_changes.front() {
+            if t + RATE_WINDOW >= now {
+                break;
+            }
+            self.route_changes.pop_front();
+        }
+        metrics.route_changes_per_minute = self
+            .route_changes
+            .front()
+            .map(|(_, oldest)| total - oldest)
+            .unwrap_or(0);
+
+        *self.page.lock().unwrap() = metrics.to_prometheus();
+        true
+    }
+}
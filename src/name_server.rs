@@ -0,0 +1,358 @@
+// Names of the mesh nodes for the members of the mesh.
+//
+// The names of the advertisements are served as `<name>.<mesh_domain>` by a minimal
+// authoritative dns responder on the wireguard ip. Only A and AAAA queries for the mesh
+// domain are answered, all others are refused. So it does not replace a resolver, which
+// needs to forward the mesh domain to it, e.g. systemd-resolved via `dns` in network.yaml.
+//
+// Where a dns responder is not desired, the names are written as managed block into a
+// hosts file instead, either /etc/hosts or a separate file e.g. for addn-hosts of dnsmasq.
+// The names are updated by the main loop on every change of the peers or routes.
+//
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use log::*;
+
+use crate::configuration::StaticConfiguration;
+use crate::error::*;
+use crate::manager::NetworkManager;
+
+pub const DNS_PORT: u16 = 53;
+// The names may change any time, so resolvers should not cache them for long
+const TTL: u32 = 30;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const RCODE_FORMERR: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+
+// A node name as dns label: lower case letters, digits and dashes
+pub fn hostname(name: &str) -> Option<String> {
+    let mut label = name
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .trim_matches('-')
+        .to_string();
+    label.truncate(63);
+    (!label.is_empty()).then_some(label)
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeshNames {
+    pub domain: Option<String>,
+    // Nodes with the same name share it
+    pub hosts: BTreeMap<String, Vec<IpAddr>>,
+}
+impl MeshNames {
+    pub fn of(
+        now: u64,
+        network_manager: &NetworkManager,
+        static_config: &StaticConfiguration,
+    ) -> Self {
+        let mut hosts: BTreeMap<String, Vec<IpAddr>> = BTreeMap::new();
+        let own = (Some(static_config.name.clone()), static_config.wg_ip);
+        let nodes = network_manager
+            .peer_infos(now)
+            .into_iter()
+            .map(|peer| (peer.name, peer.wg_ip));
+        for (name, wg_ip) in std::iter::once(own).chain(nodes) {
+            if let Some(host) = name.as_deref().and_then(hostname) {
+                let ips = hosts.entry(host).or_default();
+                if !ips.contains(&wg_ip) {
+                    ips.push(wg_ip);
+                    ips.sort();
+                }
+            }
+        }
+        MeshNames {
+            domain: static_config.mesh_domain.clone(),
+            hosts,
+        }
+    }
+    // One line per address with the fully qualified and the short name
+    pub fn hosts_fragment(&self) -> String {
+        let mut fragment = String::new();
+        for (host, ips) in self.hosts.iter() {
+            for ip in ips.iter() {
+                match self.domain.as_ref() {
+                    Some(domain) => {
+                        fragment.push_str(&format!("{}\t{}.{} {}\n", ip, host, domain, host))
+                    }
+                    None => fragment.push_str(&format!("{}\t{}\n", ip, host)),
+                }
+            }
+        }
+        fragment
+    }
+    // The response to a dns query. Responses are never answered.
+    pub fn answer(&self, query: &[u8]) -> BoxResult<Vec<u8>> {
+        if query.len() < 12 {
+            return strerror("dns query too short");
+        }
+        let flags = u16::from_be_bytes([query[2], query[3]]);
+        if flags & 0x8000 != 0 {
+            return strerror("not a dns query");
+        }
+        let response = |rcode: u16, question: &[u8], answers: &[IpAddr]| {
+            // authoritative only for the mesh domain, recursion desired is copied
+            let aa = if rcode == RCODE_REFUSED { 0 } else { 0x0400 };
+            let mut packet = query[0..2].to_vec();
+            let nr_questions = u16::from(!question.is_empty());
+            for value in [
+                0x8000 | aa | (flags & 0x0100) | rcode,
+                nr_questions,
+                answers.len() as u16,
+                0,
+                0,
+            ] {
+                packet.extend_from_slice(&value.to_be_bytes());
+            }
+            packet.extend_from_slice(question);
+            for ip in answers.iter() {
+                // The name is a pointer to the question
+                packet.extend_from_slice(&[0xc0, 12]);
+                let (rtype, rdata) = match ip {
+                    IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+                    IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+                };
+                packet.extend_from_slice(&rtype.to_be_bytes());
+                packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+                packet.extend_from_slice(&TTL.to_be_bytes());
+                packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                packet.extend_from_slice(&rdata);
+            }
+            packet
+        };
+        if (flags >> 11) & 0xf != 0 {
+            return Ok(response(RCODE_NOTIMP, &[], &[]));
+        }
+        if u16::from_be_bytes([query[4], query[5]]) != 1 {
+            return Ok(response(RCODE_FORMERR, &[], &[]));
+        }
+
+        // Names in questions are not compressed
+        let mut labels = vec![];
+        let mut pos = 12;
+        loop {
+            let Some(&len) = query.get(pos) else {
+                return Ok(response(RCODE_FORMERR, &[], &[]));
+            };
+            let len = len as usize;
+            if len == 0 {
+                pos += 1;
+                break;
+            }
+            match query.get(pos + 1..pos + 1 + len) {
+                Some(label) if len <= 63 => {
+                    labels.push(String::from_utf8_lossy(label).to_ascii_lowercase())
+                }
+                _ => return Ok(response(RCODE_FORMERR, &[], &[])),
+            }
+            pos += 1 + len;
+        }
+        let Some(fixed) = query.get(pos..pos + 4) else {
+            return Ok(response(RCODE_FORMERR, &[], &[]));
+        };
+        let question = &query[12..pos + 4];
+        let qtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let qclass = u16::from_be_bytes([fixed[2], fixed[3]]);
+
+        let name = labels.join(".");
+        let Some(domain) = self.domain.as_ref() else {
+            return Ok(response(RCODE_REFUSED, question, &[]));
+        };
+        if qclass != CLASS_IN {
+            return Ok(response(RCODE_REFUSED, question, &[]));
+        }
+        if name == *domain {
+            return Ok(response(0, question, &[]));
+        }
+        let Some(host) = name.strip_suffix(&format!(".{}", domain)) else {
+            return Ok(response(RCODE_REFUSED, question, &[]));
+        };
+        let Some(ips) = self.hosts.get(host) else {
+            return Ok(response(RCODE_NXDOMAIN, question, &[]));
+        };
+        let answers = ips
+            .iter()
+            .filter(|ip| match qtype {
+                TYPE_A => ip.is_ipv4(),
+                TYPE_AAAA => ip.is_ipv6(),
+                TYPE_ANY => true,
+                _ => false,
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        Ok(response(0, question, &answers))
+    }
+}
+
+pub struct NameServer {
+    names: Arc<Mutex<MeshNames>>,
+    local_addr: SocketAddr,
+}
+impl NameServer {
+    pub fn listen(addr: SocketAddr) -> BoxResult<Self> {
+        NameServer::serve(NameServer::bind(addr)?)
+    }
+    // Port 53 needs to be bound before dropping privileges. On linux, the address may
+    // not exist yet, so this is possible before the interface is set up.
+    pub fn bind(addr: SocketAddr) -> BoxResult<UdpSocket> {
+        #[cfg(target_os = "linux")]
+        {
+            use nix::sys::socket::*;
+            use std::os::unix::io::FromRawFd;
+
+            let family = if addr.is_ipv4() {
+                AddressFamily::Inet
+            } else {
+                AddressFamily::Inet6
+            };
+            let fd = socket(family, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+            // Closed on error
+            let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+            setsockopt(fd, sockopt::IpFreebind, &true)?;
+            bind(fd, &SockAddr::new_inet(InetAddr::from_std(&addr)))?;
+            Ok(socket)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Ok(UdpSocket::bind(addr)?)
+    }
+    pub fn serve(socket: UdpSocket) -> BoxResult<Self> {
+        let local_addr = socket.local_addr()?;
+        info!(target: "dns", "Serve the names of the mesh on {}", local_addr);
+        let names = Arc::new(Mutex::new(MeshNames::default()));
+        let names_clone = names.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (len, src_addr) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!(target: "dns", "dns responder stopped: {}", e);
+                        return;
+                    }
+                };
+                let result = names_clone.lock().unwrap().answer(&buf[..len]);
+                match result {
+                    Ok(response) => {
+                        if let Err(e) = socket.send_to(&response, src_addr) {
+                            debug!(target: "dns", "Cannot answer {}: {}", src_addr, e);
+                        }
+                    }
+                    Err(e) => trace!(target: "dns", "Invalid query from {}: {}", src_addr, e),
+                }
+            }
+        });
+        Ok(NameServer { names, local_addr })
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+    pub fn update(&self, names: &MeshNames) {
+        *self.names.lock().unwrap() = names.clone();
+    }
+}
+
+// The names are maintained as managed block, so the file may be shared with other
+// entries, e.g. /etc/hosts. Lines outside of the block are kept as they are.
+pub struct HostsFile {
+    path: String,
+    begin: String,
+    end: String,
+    written: Option<String>,
+}
+impl HostsFile {
+    // The interface name distinguishes the blocks of several instances
+    pub fn new<T: Into<String>>(path: T, wg_name: &str) -> Self {
+        HostsFile {
+            path: path.into(),
+            begin: format!("# BEGIN wg_netmanager {}", wg_name),
+            end: format!("# END wg_netmanager {}", wg_name),
+            written: None,
+        }
+    }
+    // Returns true, if the file has been rewritten
+    pub fn update(&mut self, names: &MeshNames) -> BoxResult<bool> {
+        let block = format!("{}\n{}{}\n", self.begin, names.hosts_fragment(), self.end);
+        if self.written.as_ref() == Some(&block) {
+            return Ok(false);
+        }
+        self.write(Some(&block))?;
+        self.written = Some(block);
+        Ok(true)
+    }
+    // The existing content without the previous block, and the new one appended
+    fn with_block(&self, content: &str, block: Option<&str>) -> String {
+        let mut result = String::new();
+        // Lines of a block without end are kept
+        let mut replaced: Option<String> = None;
+        for line in content.lines() {
+            if line == self.begin {
+                result.push_str(&replaced.take().unwrap_or_default());
+                replaced = Some(format!("{}\n", line));
+            } else if let Some(lines) = replaced.as_mut() {
+                lines.push_str(line);
+                lines.push('\n');
+                if line == self.end {
+                    replaced = None;
+                }
+            } else {
+                result.push_str(line);
+                result.push('\n');
+            }
+        }
+        if let Some(lines) = replaced {
+            result.push_str(&lines);
+        }
+        result.push_str(block.unwrap_or_default());
+        result
+    }
+    fn write(&self, block: Option<&str>) -> BoxResult<()> {
+        let path = Path::new(&self.path);
+        let (content, permissions) = match fs::read_to_string(path) {
+            Ok(content) => (content, Some(fs::metadata(path)?.permissions())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (String::new(), None),
+            Err(e) => return Err(e.into()),
+        };
+        let content = self.with_block(&content, block);
+        if content.is_empty() && permissions.is_some() {
+            // The file contained only the names
+            fs::remove_file(path)?;
+            return Ok(());
+        }
+        // Readers never see a partially written file
+        let tmp_path = path.with_file_name(format!(
+            ".{}.tmp",
+            path.file_name()
+                .ok_or("hosts file path has no file name")?
+                .to_string_lossy()
+        ));
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        if let Some(permissions) = permissions {
+            fs::set_permissions(&tmp_path, permissions)?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+    // Stale names are removed on exit
+    pub fn remove(&self) {
+        if let Err(e) = self.write(None) {
+            warn!(target: "dns", "Cannot remove the names from {}: {}", self.path, e);
+        }
+    }
+}
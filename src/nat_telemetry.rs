@@ -0,0 +1,208 @@
+// Telemetry of the NAT traversal to distant nodes for tuning the topology.
+//
+// An attempt starts with the first try to reach a distant node directly and ends
+// either with a success, when the node has become a direct peer, or with a failure,
+// when the relay fallback is taken. The candidate is the kind of endpoint, which has
+// worked resp. has been tried last:
+//
+//      local:       the local ips of the node, as per its local contact
+//      visible:     blind sends to the visible endpoint of the node
+//      rendezvous:  simultaneous sends after a rendezvous by the gateway
+//
+// Per candidate and outcome, the attempts and their total duration are counted. The
+// most recent attempts are kept in addition. E.g. a site behind symmetric NAT shows
+// up with rendezvous/timeout only, so a relay should be added there.
+//
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::net::IpAddr;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::crypt_udp::AddressedTo;
+
+pub const RECENT_ATTEMPTS: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Candidate {
+    Local,
+    Visible,
+    Rendezvous,
+}
+impl Candidate {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Candidate::Local => "local",
+            Candidate::Visible => "visible",
+            Candidate::Rendezvous => "rendezvous",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    // The failure reasons
+    NoVisibleEndpoint,
+    NoRendezvous,
+    Timeout,
+}
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::NoVisibleEndpoint => "no_visible_endpoint",
+            Outcome::NoRendezvous => "no_rendezvous",
+            Outcome::Timeout => "timeout",
+        }
+    }
+}
+
+// The state of the traversal of a distant node
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TraversalProgress {
+    // Time of the first attempt
+    pub since: Option<u64>,
+    pub attempts: u32,
+    pub rendezvous: u32,
+    pub has_visible_endpoint: bool,
+}
+impl TraversalProgress {
+    // The candidate, which has been tried last
+    fn tried(&self) -> Candidate {
+        if self.rendezvous > 0 {
+            Candidate::Rendezvous
+        } else if self.has_visible_endpoint {
+            Candidate::Visible
+        } else {
+            Candidate::Local
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TraversalAttempt {
+    pub wg_ip: IpAddr,
+    pub candidate: Candidate,
+    pub outcome: Outcome,
+    pub attempts: u32,
+    // Unix time of the first attempt and the time to success resp. failure
+    pub started: u64,
+    pub duration_s: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TraversalCount {
+    pub candidate: Candidate,
+    pub outcome: Outcome,
+    pub count: u64,
+    pub duration_s: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct TraversalReport {
+    pub counts: Vec<TraversalCount>,
+    // Oldest first
+    pub recent: Vec<TraversalAttempt>,
+}
+
+#[derive(Default)]
+pub struct NatTelemetry {
+    // Number and total duration
+    counts: BTreeMap<(Candidate, Outcome), (u64, u64)>,
+    recent: VecDeque<TraversalAttempt>,
+    // Distant nodes, whose failure has been recorded already
+    failed: HashSet<IpAddr>,
+}
+impl NatTelemetry {
+    // The distant node has become a direct peer via an advertisement addressed to
+    // the given address
+    pub fn success(
+        &mut self,
+        now: u64,
+        wg_ip: IpAddr,
+        progress: TraversalProgress,
+        addressed_to: &AddressedTo,
+    ) {
+        use AddressedTo::*;
+        self.failed.remove(&wg_ip);
+        let candidate = match addressed_to {
+            LocalAddress | ReplyFromLocalAddress => Candidate::Local,
+            WireguardV6Address | ReplyFromWireguardV6Address if progress.rendezvous > 0 => {
+                Candidate::Rendezvous
+            }
+            WireguardV6Address | ReplyFromWireguardV6Address => Candidate::Visible,
+            _ => return,
+        };
+        self.record(now, wg_ip, candidate, Outcome::Success, progress);
+    }
+    // The relay fallback is taken. Recorded once per distant node.
+    pub fn failure(&mut self, now: u64, wg_ip: IpAddr, progress: TraversalProgress) {
+        if !self.failed.insert(wg_ip) {
+            return;
+        }
+        let outcome = if !progress.has_visible_endpoint {
+            Outcome::NoVisibleEndpoint
+        } else if progress.rendezvous == 0 {
+            Outcome::NoRendezvous
+        } else {
+            Outcome::Timeout
+        };
+        self.record(now, wg_ip, progress.tried(), outcome, progress);
+    }
+    // Forget the failures of nodes, which are not distant anymore
+    pub fn retain_failed<F: Fn(&IpAddr) -> bool>(&mut self, is_distant: F) {
+        self.failed.retain(is_distant);
+    }
+    fn record(
+        &mut self,
+        now: u64,
+        wg_ip: IpAddr,
+        candidate: Candidate,
+        outcome: Outcome,
+        progress: TraversalProgress,
+    ) {
+        // Attempts from the other side only are not of interest
+        let Some(started) = progress.since else {
+            return;
+        };
+        let duration_s = now.saturating_sub(started);
+        info!(target: "probing", "NAT traversal to {}: {} after {} attempts in {}s via {}",
+            wg_ip, outcome.as_str(), progress.attempts, duration_s, candidate.as_str());
+        let (count, total) = self.counts.entry((candidate, outcome)).or_default();
+        *count += 1;
+        *total += duration_s;
+        if self.recent.len() >= RECENT_ATTEMPTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(TraversalAttempt {
+            wg_ip,
+            candidate,
+            outcome,
+            attempts: progress.attempts,
+            started,
+            duration_s,
+        });
+    }
+    pub fn counts(&self) -> Vec<TraversalCount> {
+        self.counts
+            .iter()
+            .map(
+                |((candidate, outcome), (count, duration_s))| TraversalCount {
+                    candidate: *candidate,
+                    outcome: *outcome,
+                    count: *count,
+                    duration_s: *duration_s,
+                },
+            )
+            .collect()
+    }
+    pub fn report(&self) -> TraversalReport {
+        TraversalReport {
+            counts: self.counts(),
+            recent: self.recent.iter().cloned().collect(),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// Programmatic editing of network.yaml, e.g. for provisioning tools.
+//
+// The file is loaded into typed structs, changed and written back. The leading
+// comment block and unknown keys are kept, and the peers keep their order. Other
+// comments are lost, because the yaml parser drops them. Before writing, the result
+// is validated with the same parser as used by the daemon.
+//
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config_file::ConfigFormat;
+use crate::configuration::NetworkConfiguration;
+use crate::error::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkSection {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub shared_key: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accepted_shared_keys: Vec<String>,
+    pub subnet: String,
+    // e.g. portAllocation, which is kept as is
+    #[serde(flatten)]
+    pub other: serde_yaml::Mapping,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StaticPeerEntry {
+    pub end_point: String,
+    pub admin_port: u16,
+    pub wg_ip: IpAddr,
+    #[serde(flatten)]
+    pub other: serde_yaml::Mapping,
+}
+impl StaticPeerEntry {
+    pub fn new<T: Into<String>>(wg_ip: IpAddr, end_point: T, admin_port: u16) -> Self {
+        StaticPeerEntry {
+            end_point: end_point.into(),
+            admin_port,
+            wg_ip,
+            other: serde_yaml::Mapping::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetworkFile {
+    // The comments at the start of the file
+    #[serde(skip)]
+    pub header: String,
+    pub network: NetworkSection,
+    #[serde(default)]
+    pub peers: Vec<StaticPeerEntry>,
+    #[serde(flatten)]
+    pub other: serde_yaml::Mapping,
+}
+impl NetworkFile {
+    pub fn from_yaml_str(content: &str) -> BoxResult<Self> {
+        let mut file: NetworkFile = serde_yaml::from_str(content)?;
+        file.header = content
+            .lines()
+            .take_while(|line| line.trim().is_empty() || line.trim_start().starts_with('#'))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        Ok(file)
+    }
+    pub fn load<P: AsRef<Path>>(path: P) -> BoxResult<Self> {
+        if ConfigFormat::of_path(&path) == ConfigFormat::Toml {
+            return strerror("Only network.yaml can be edited");
+        }
+        Self::from_yaml_str(&fs::read_to_string(path)?)
+    }
+    pub fn to_yaml_string(&self) -> BoxResult<String> {
+        let yaml = serde_yaml::to_string(self)?;
+        let yaml = yaml.strip_prefix("---\n").unwrap_or(&yaml);
+        let content = format!("{}{}", self.header, yaml);
+        // Never write a file, which the daemon would reject
+        NetworkConfiguration::from_yaml_str(&content)?;
+        Ok(content)
+    }
+    // The file is replaced atomically and keeps its permissions
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> BoxResult<()> {
+        let path = path.as_ref();
+        let content = self.to_yaml_string()?;
+        let tmp_path = path.with_file_name(format!(
+            ".{}.tmp",
+            path.file_name()
+                .ok_or("network file path has no file name")?
+                .to_string_lossy()
+        ));
+        let mut file = fs::File::create(&tmp_path)?;
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+    pub fn configuration(&self) -> BoxResult<NetworkConfiguration> {
+        NetworkConfiguration::from_yaml_str(&self.to_yaml_string()?)
+    }
+    pub fn peer(&self, wg_ip: &IpAddr) -> Option<&StaticPeerEntry> {
+        self.peers.iter().find(|p| p.wg_ip == *wg_ip)
+    }
+    pub fn add_peer(&mut self, peer: StaticPeerEntry) -> BoxResult<()> {
+        if self.peer(&peer.wg_ip).is_some() {
+            return Err(format!("Static peer {} exists already", peer.wg_ip).into());
+        }
+        self.peers.push(peer);
+        Ok(())
+    }
+    pub fn remove_peer(&mut self, wg_ip: &IpAddr) -> bool {
+        let len = self.peers.len();
+        self.peers.retain(|p| p.wg_ip != *wg_ip);
+        self.peers.len() != len
+    }
+    pub fn set_endpoint<T: Into<String>>(&mut self, wg_ip: &IpAddr, end_point: T) -> BoxResult<()> {
+        let peer = self
+            .peers
+            .iter_mut()
+            .find(|p| p.wg_ip == *wg_ip)
+            .ok_or_else(|| format!("No static peer {}", wg_ip))?;
+        peer.end_point = end_point.into();
+        Ok(())
+    }
+}
@@ -1,14 +1,73 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
 
 use log::*;
+use serde::{Deserialize, Serialize};
 
-use crate::configuration::{PublicKeyWithTime, PublicPeer, StaticConfiguration};
-use crate::crypt_udp::{AddressedTo, AdvertisementPacket, LocalContactPacket, RouteDatabasePacket};
+use crate::configuration::{PublicKeyWithTime, PublicPeer, StaticConfiguration, DEFAULT_TIER};
+use crate::crypt_udp::{
+    AddressedTo, AdvertisementPacket, LocalContactPacket, RouteDatabaseDeltaPacket,
+    RouteDatabasePacket,
+};
+use crate::endpoint_resolver::EndpointResolver;
 use crate::event::Event;
+use crate::key_rotation::{announced_key, due_key};
+use crate::link_local::*;
+use crate::nat_telemetry::TraversalProgress;
 use crate::routedb::{RouteDBManager, RouteInfo};
-use crate::wg_dev::map_to_ipv6;
+use crate::state_snapshot::NodeState;
+use crate::wg_dev::{host_net, nat_probe_address};
+
+// With keepalive, wireguard renews the handshake every two minutes. An older one
+// indicates a lost NAT mapping or a changed endpoint.
+pub const STALE_HANDSHAKE: u64 = 180;
+
+// A static peer advertises via the tunnel every 60s. Without such an advertisement
+// for this long, the next wg port of a static peer with extra_wg_ports is used.
+pub const PATH_TIMEOUT: u64 = 150;
+
+// The round trip time in the cost of a link follows the measurement only on a change
+// by more than this percentage, so jitter does not change the routes
+pub const RTT_HYSTERESIS_PERCENT: u32 = 20;
+
+// Round trip time of the link to a direct peer as measured by ping/pong
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LinkRtt {
+    smoothed_ms: Option<u32>,
+    used_ms: Option<u32>,
+}
+impl LinkRtt {
+    // Returns true, if the value used for the cost of the link has changed
+    pub fn update(&mut self, sample_ms: u32) -> bool {
+        let (smoothed, used) = match (self.smoothed_ms, self.used_ms) {
+            (Some(smoothed), Some(used)) => (
+                (3 * smoothed as u64 + sample_ms as u64).div_ceil(4) as u32,
+                used,
+            ),
+            _ => {
+                self.smoothed_ms = Some(sample_ms);
+                self.used_ms = Some(sample_ms);
+                return true;
+            }
+        };
+        self.smoothed_ms = Some(smoothed);
+        let diff = smoothed.abs_diff(used);
+        if diff == 0 || diff as u64 * 100 <= used as u64 * RTT_HYSTERESIS_PERCENT as u64 {
+            return false;
+        }
+        self.used_ms = Some(smoothed);
+        true
+    }
+    // Follows every measurement
+    pub fn smoothed_ms(&self) -> Option<u32> {
+        self.smoothed_ms
+    }
+    // Used for the cost of the link
+    pub fn used_ms(&self) -> Option<u32> {
+        self.used_ms
+    }
+}
 
 pub trait Node {
     fn routedb_manager(&self) -> Option<&RouteDBManager> {
@@ -17,10 +76,24 @@ pub trait Node {
     fn routedb_manager_mut(&mut self) -> Option<&mut RouteDBManager> {
         None
     }
+    // Only direct peers are pinged
+    fn link_rtt(&self) -> Option<&LinkRtt> {
+        None
+    }
+    fn link_rtt_mut(&mut self) -> Option<&mut LinkRtt> {
+        None
+    }
     fn process_route_database(&mut self, req: RouteDatabasePacket) -> Option<Vec<Event>> {
         self.routedb_manager_mut()
             .map(|db| db.process_route_database(req))
     }
+    fn process_route_database_delta(
+        &mut self,
+        delta: RouteDatabaseDeltaPacket,
+    ) -> Option<Vec<Event>> {
+        self.routedb_manager_mut()
+            .map(|db| db.process_route_database_delta(delta))
+    }
     fn local_admin_port(&self) -> u16;
     fn is_reachable(&self) -> bool {
         false
@@ -28,11 +101,11 @@ pub trait Node {
     fn is_distant_node(&self) -> bool {
         false
     }
-    fn get_gateway(&self) -> Option<Ipv4Addr> {
+    fn get_gateway(&self) -> Option<IpAddr> {
         None
     }
-    fn set_gateway(&mut self, _gateway: Option<Ipv4Addr>) {}
-    fn get_gateway_for(&mut self) -> Option<&mut HashSet<Ipv4Addr>> {
+    fn set_gateway(&mut self, _gateway: Option<IpAddr>) {}
+    fn get_gateway_for(&mut self) -> Option<&mut HashSet<IpAddr>> {
         None
     }
     fn clear_gateway_for(&mut self) {
@@ -40,7 +113,7 @@ pub trait Node {
             gf.clear();
         }
     }
-    fn add_gateway_for(&mut self, node: Ipv4Addr) {
+    fn add_gateway_for(&mut self, node: IpAddr) {
         if let Some(gf) = self.get_gateway_for() {
             gf.insert(node);
         }
@@ -58,12 +131,33 @@ pub trait Node {
     fn visible_wg_endpoint(&self) -> Option<SocketAddr> {
         None
     }
+    fn name(&self) -> Option<&str> {
+        None
+    }
+    // How this node is reached: static, local, dynamic, passive or distant
+    fn endpoint_class(&self) -> &'static str;
+    fn lastseen(&self) -> Option<u64> {
+        None
+    }
+    fn public_key(&self) -> Option<&PublicKeyWithTime> {
+        None
+    }
+    // Called by the NetworkManager not every second, but only when due as per
+    // next_processing_time(). So all periodic actions need to be based on deadlines.
     fn process_every_second(&mut self, now: u64, static_config: &StaticConfiguration)
         -> Vec<Event>;
+    // Point in time, when process_every_second() has work to do.
+    // Valid until any other method mutating the node is called.
+    fn next_processing_time(&self, now: u64) -> u64 {
+        now + 1
+    }
     fn ok_to_delete_without_route(&self, _now: u64) -> bool {
         false
     }
-    fn peer_wireguard_configuration(&self) -> Option<Vec<String>>;
+    fn peer_wireguard_configuration(
+        &self,
+        static_config: &StaticConfiguration,
+    ) -> Option<Vec<String>>;
     fn analyze_advertisement(
         &mut self,
         now: u64,
@@ -75,37 +169,161 @@ pub trait Node {
         &mut self,
         pubkey_to_endpoint: &mut HashMap<String, SocketAddr>,
     );
+    // Called with the latest wireguard handshake of this node, if any
+    fn check_handshake(
+        &mut self,
+        _now: u64,
+        _static_config: &StaticConfiguration,
+        _latest_handshake: u64,
+    ) -> Vec<Event> {
+        vec![]
+    }
     fn process_local_contact(&mut self, _local: LocalContactPacket) {
         warn!("process_local_contact: unexpected for StaticPeer and DynamicPeer");
     }
+    // A rendezvous has provided the visible endpoint of the node and the time to
+    // start the NAT traversal
+    fn process_connect_request(&mut self, _endpoint: SocketAddr, _start_time: u64) -> Vec<Event> {
+        vec![]
+    }
+    // The NAT traversal to a distant node has not succeeded for long
+    fn traversal_failed(&self, _now: u64) -> bool {
+        false
+    }
+    // The attempts to reach a distant node directly
+    fn traversal(&self) -> Option<TraversalProgress> {
+        None
+    }
+    // A distant node, which still needs the local contact
+    fn wants_local_contact(&self) -> bool {
+        false
+    }
+    // The own endpoint as seen by the peers, e.g. to detect a node behind the same NAT
+    fn set_own_visible_endpoint(&mut self, _endpoint: SocketAddr) {}
+    // Advertise to the configured endpoint, which is resolved again. Only static peers
+    // have one.
+    fn resolve_endpoint(&self) -> Vec<Event> {
+        vec![]
+    }
+    // How to contact the node directly, e.g. for the export of the known peers
+    fn contact(&self) -> Option<PublicPeer> {
+        None
+    }
+    // Tier of a static peer
+    fn tier(&self) -> Option<u8> {
+        None
+    }
+    // A static peer in standby is not contacted, until the peers of lower tiers have
+    // not been reached for a while
+    fn set_standby(&mut self, _standby: bool) {}
+    // Advertise to a static peer on the next processing
+    fn readvertise(&mut self) {}
+    // State to be handed over on binary upgrade. Distant nodes are not included.
+    fn state(&self) -> Option<NodeState> {
+        None
+    }
+    fn restore_state(&mut self, _state: &NodeState) {}
 }
 
 #[derive(Debug)]
 pub struct StaticPeer {
     static_peer: PublicPeer,
     public_key: Option<PublicKeyWithTime>,
-    gateway_for: HashSet<Ipv4Addr>,
+    next_public_key: Option<PublicKeyWithTime>,
+    gateway_for: HashSet<IpAddr>,
     is_alive: bool,
     lastseen: u64,
     wg_tunnel_need_hop: Option<u64>,
-    send_advertisement_seconds_count_down: usize,
+    next_advertisement: u64,
+    next_routedb_check: u64,
     routedb_manager: RouteDBManager,
     current_ip: Option<IpAddr>,
+    // Of a link-local current_ip
+    current_scope_id: u32,
+    // The gateway of a forced relay
+    relay: Option<IpAddr>,
+    // Advertised by the peer. Tried in turn after static_peer.wg_port
+    extra_wg_ports: Vec<u16>,
+    // 0 for static_peer.wg_port, otherwise the index into extra_wg_ports plus one
+    path: usize,
+    last_tunnel_contact: u64,
+    standby: bool,
+    rtt: LinkRtt,
 }
 impl StaticPeer {
     pub fn from_public_peer(peer: &PublicPeer) -> Box<dyn Node> {
         Box::new(StaticPeer {
             static_peer: (*peer).clone(),
             public_key: None,
+            next_public_key: None,
             gateway_for: HashSet::new(),
             is_alive: false,
             lastseen: 0,
             wg_tunnel_need_hop: None,
-            send_advertisement_seconds_count_down: 0,
+            next_advertisement: 0,
+            next_routedb_check: 0,
             routedb_manager: RouteDBManager::default(),
             current_ip: None,
+            current_scope_id: peer.scope_id(),
+            relay: None,
+            extra_wg_ports: vec![],
+            path: 0,
+            last_tunnel_contact: 0,
+            standby: false,
+            rtt: LinkRtt::default(),
         })
     }
+    fn wg_port(&self) -> u16 {
+        match self.path {
+            0 => self.static_peer.wg_port,
+            path => self.extra_wg_ports[path - 1],
+        }
+    }
+    // Use the next wg port, if the peer has more than one
+    fn switch_path(&mut self, now: u64) -> Vec<Event> {
+        if self.extra_wg_ports.is_empty() {
+            return vec![];
+        }
+        self.path = (self.path + 1) % (self.extra_wg_ports.len() + 1);
+        self.last_tunnel_contact = now;
+        info!(target: &self.static_peer.wg_ip.to_string(), "tunnel is broken, switch to wg port {}", self.wg_port());
+        vec![
+            Event::UpdateWireguardConfiguration,
+            Event::SendAdvertisement {
+                addressed_to: AddressedTo::WireguardAddress,
+                to: SocketAddr::new(self.static_peer.wg_ip, self.static_peer.admin_port),
+                wg_ip: self.static_peer.wg_ip,
+            },
+        ]
+    }
+    // Resolve here the hostname (if not an IP) to make it work for dyndns hosts
+    fn advertise_to_endpoint(&self) -> Vec<Event> {
+        let mut events = vec![];
+        match self.static_peer.resolve_endpoint() {
+            Ok(endpoints) => {
+                trace!("ENDPOINTS: {:#?}", endpoints);
+                for ip in endpoints {
+                    // send to the endpoint with the admin_port as target
+                    let destination =
+                        scoped(ip, self.static_peer.admin_port, self.static_peer.scope_id());
+                    events.push(Event::SendAdvertisement {
+                        addressed_to: AddressedTo::StaticAddress,
+                        to: destination,
+                        wg_ip: self.static_peer.wg_ip,
+                    });
+                }
+            }
+            Err(e) => {
+                // An error here is not dramatic, perhaps DNS is not reachable in the
+                // moment. Just push out a warning and that's it
+                warn!(
+                    "Cannot get endpoint ip(s) for {}: {:?}",
+                    self.static_peer.endpoint, e
+                );
+            }
+        }
+        events
+    }
 }
 impl Node for StaticPeer {
     fn routedb_manager(&self) -> Option<&RouteDBManager> {
@@ -114,40 +332,113 @@ impl Node for StaticPeer {
     fn routedb_manager_mut(&mut self) -> Option<&mut RouteDBManager> {
         Some(&mut self.routedb_manager)
     }
-    fn get_gateway_for(&mut self) -> Option<&mut HashSet<Ipv4Addr>> {
+    fn link_rtt(&self) -> Option<&LinkRtt> {
+        Some(&self.rtt)
+    }
+    fn link_rtt_mut(&mut self) -> Option<&mut LinkRtt> {
+        Some(&mut self.rtt)
+    }
+    fn get_gateway_for(&mut self) -> Option<&mut HashSet<IpAddr>> {
         Some(&mut self.gateway_for)
     }
+    fn get_gateway(&self) -> Option<IpAddr> {
+        self.relay
+    }
+    fn set_gateway(&mut self, gateway: Option<IpAddr>) {
+        self.relay = gateway;
+    }
     fn local_admin_port(&self) -> u16 {
         self.static_peer.admin_port
     }
-    fn peer_wireguard_configuration(&self) -> Option<Vec<String>> {
+    fn peer_wireguard_configuration(
+        &self,
+        static_config: &StaticConfiguration,
+    ) -> Option<Vec<String>> {
         // Not considered here is, if the StaticPeer is not directly reachable.
         self.public_key.as_ref().map(|public_key| {
             let mut lines = vec![];
             let wg_ip = self.static_peer.wg_ip;
-            let wg_ipv6 = map_to_ipv6(&wg_ip);
             lines.push(format!("PublicKey = {}", &public_key.key));
-            lines.push(format!("AllowedIPs = {}/32", wg_ip));
-            lines.push(format!("AllowedIPs = {}/128", wg_ipv6));
+            // With a forced relay, the gateway's entry has the wg_ip
+            if self.relay.is_none() {
+                lines.push(format!("AllowedIPs = {}", host_net(wg_ip)));
+            }
+            if let Some(probe_ip) = static_config.nat_probe_address(&wg_ip) {
+                lines.push(format!("AllowedIPs = {}/128", probe_ip));
+            }
             for ip in self.gateway_for.iter() {
-                lines.push(format!("AllowedIPs = {}/32", ip));
+                lines.push(format!("AllowedIPs = {}", host_net(*ip)));
             }
             if let Some(ip) = self.current_ip.as_ref() {
-                let sa: SocketAddr = SocketAddr::new(*ip, self.static_peer.wg_port);
+                let sa = scoped(*ip, self.wg_port(), self.current_scope_id);
                 lines.push(format!("EndPoint = {}", sa));
             }
+            if let Some(seconds) = static_config.persistent_keepalive_for(false) {
+                lines.push(format!("PersistentKeepalive = {}", seconds));
+            }
             lines
         })
     }
     fn is_reachable(&self) -> bool {
         self.is_alive
     }
+    fn endpoint_class(&self) -> &'static str {
+        "static"
+    }
+    fn public_key(&self) -> Option<&PublicKeyWithTime> {
+        self.public_key.as_ref()
+    }
+    fn lastseen(&self) -> Option<u64> {
+        self.is_alive.then_some(self.lastseen)
+    }
+    fn contact(&self) -> Option<PublicPeer> {
+        Some(self.static_peer.clone())
+    }
+    fn tier(&self) -> Option<u8> {
+        Some(self.static_peer.tier)
+    }
+    fn set_standby(&mut self, standby: bool) {
+        if self.standby && !standby {
+            info!(target: &self.static_peer.wg_ip.to_string(), "contact static peer of tier {}", self.static_peer.tier);
+            self.next_advertisement = 0;
+        }
+        self.standby = standby;
+    }
+    fn readvertise(&mut self) {
+        self.next_advertisement = 0;
+    }
+    fn resolve_endpoint(&self) -> Vec<Event> {
+        self.advertise_to_endpoint()
+    }
+    fn check_handshake(
+        &mut self,
+        now: u64,
+        static_config: &StaticConfiguration,
+        latest_handshake: u64,
+    ) -> Vec<Event> {
+        if !self.is_alive
+            || static_config.persistent_keepalive_for(false).is_none()
+            || now.saturating_sub(latest_handshake) <= STALE_HANDSHAKE
+        {
+            return vec![];
+        }
+        // The tunnel is broken, so contact the static endpoint again
+        info!(target: &self.static_peer.wg_ip.to_string(), "stale handshake, advertise to endpoint");
+        let mut events = self.switch_path(now);
+        events.append(&mut self.advertise_to_endpoint());
+        events
+    }
     fn process_every_second(
         &mut self,
         now: u64,
         static_config: &StaticConfiguration,
     ) -> Vec<Event> {
         let mut events = vec![];
+        if let Some(public_key) = due_key(now, &mut self.next_public_key) {
+            info!(target: &self.static_peer.wg_ip.to_string(), "switch to announced public key");
+            self.public_key = Some(public_key);
+            events.push(Event::UpdateWireguardConfiguration);
+        }
         if self.is_alive && now - self.lastseen > 240 {
             // seems to be dead
             self.is_alive = false;
@@ -173,16 +464,19 @@ impl Node for StaticPeer {
             }
         }
 
+        if self.is_alive && now >= self.last_tunnel_contact + PATH_TIMEOUT {
+            // Reachable via the admin port, but not via the tunnel
+            events.append(&mut self.switch_path(now));
+        }
+
         if self.is_alive {
             // If StaticPeer is alive, then send all communications via the tunnel.
             // Not considered here is, if the StaticPeer is not directly reachable.
-            if self.send_advertisement_seconds_count_down == 0 {
-                self.send_advertisement_seconds_count_down = 60;
+            if now >= self.next_advertisement {
+                self.next_advertisement = now + 60;
 
                 let destination =
-                    SocketAddrV4::new(self.static_peer.wg_ip, self.static_peer.admin_port);
-
-                let destination = SocketAddr::V4(destination);
+                    SocketAddr::new(self.static_peer.wg_ip, self.static_peer.admin_port);
 
                 // Every 60s send an advertisement to the wireguard address
                 events.push(Event::SendAdvertisement {
@@ -191,62 +485,66 @@ impl Node for StaticPeer {
                     wg_ip: self.static_peer.wg_ip,
                 });
             }
-            if now % 10 == 0 && self.routedb_manager.is_outdated() {
-                // if the local copy is not matching with latest info from StaticPeer,
-                // then request an update.
-                let destination =
-                    SocketAddrV4::new(self.static_peer.wg_ip, self.static_peer.admin_port);
-                events.push(Event::SendRouteDatabaseRequest { to: destination });
+            if now >= self.next_routedb_check {
+                self.next_routedb_check = now + 10;
+                if self.routedb_manager.is_outdated() {
+                    // if the local copy is not matching with latest info from StaticPeer,
+                    // then request an update.
+                    let destination =
+                        SocketAddr::new(self.static_peer.wg_ip, self.static_peer.admin_port);
+                    events.push(Event::SendRouteDatabaseRequest { to: destination });
+                }
             }
-        } else {
+        } else if !self.standby {
             // If static peer is not alive, send every 60s an advertisement
             // to the known endpoint
-            if self.send_advertisement_seconds_count_down == 0 {
-                self.send_advertisement_seconds_count_down = 60;
-
-                // Resolve here the hostname (if not an IP) to make it work for dyndns hosts
-                match self.static_peer.endpoint.to_socket_addrs() {
-                    Ok(endpoints) => {
-                        trace!("ENDPOINTS: {:#?}", endpoints);
-                        for sa in endpoints {
-                            // send to the endpoint with the admin_port as target
-                            let destination = SocketAddr::new(sa.ip(), self.static_peer.admin_port);
-                            events.push(Event::SendAdvertisement {
-                                addressed_to: AddressedTo::StaticAddress,
-                                to: destination,
-                                wg_ip: self.static_peer.wg_ip,
-                            });
-                        }
-                    }
-                    Err(e) => {
-                        // An error here is not dramatic, perhaps DNS is not reachable in the
-                        // moment. Just push out a warning and that's it
-                        warn!(
-                            "Cannot get endpoint ip(s) for {}: {:?}",
-                            self.static_peer.endpoint, e
-                        );
-                    }
-                }
+            if now >= self.next_advertisement {
+                self.next_advertisement = now + 60;
+                events.append(&mut self.advertise_to_endpoint());
             }
         }
-        self.send_advertisement_seconds_count_down -= 1;
 
         events
     }
+    fn next_processing_time(&self, _now: u64) -> u64 {
+        let mut next = if self.is_alive || !self.standby {
+            self.next_advertisement
+        } else {
+            u64::MAX
+        };
+        if self.is_alive {
+            next = next.min(self.lastseen + 241).min(self.next_routedb_check);
+            if let Some(hop_time) = self.wg_tunnel_need_hop {
+                next = next.min(hop_time + 1);
+            }
+            if !self.extra_wg_ports.is_empty() {
+                next = next.min(self.last_tunnel_contact + PATH_TIMEOUT);
+            }
+        }
+        if let Some(next_public_key) = self.next_public_key.as_ref() {
+            next = next.min(next_public_key.priv_key_creation_time);
+        }
+        next
+    }
     fn analyze_advertisement(
         &mut self,
         now: u64,
         _static_config: &StaticConfiguration,
-        advertisement: AdvertisementPacket,
+        mut advertisement: AdvertisementPacket,
         src_addr: SocketAddr,
     ) -> (Option<Box<dyn Node>>, Vec<Event>) {
         let mut events = vec![];
+        let advertised_key = advertisement.public_key.key.clone();
+        let next_public_key = advertisement.next_public_key.take();
 
         // advertisement has been received. Store the advertised routedb_version
         self.routedb_manager
             .latest_version(advertisement.routedb_version);
 
         // btw the StaticPeer is actually alive
+        if !self.is_alive {
+            self.last_tunnel_contact = now;
+        }
         self.is_alive = true;
         self.lastseen = now;
 
@@ -254,6 +552,7 @@ impl Node for StaticPeer {
         match &advertisement.addressed_to {
             StaticAddress | ReplyFromStaticAddress => {
                 self.current_ip = Some(src_addr.ip());
+                self.current_scope_id = scope_id(&src_addr);
                 self.wg_tunnel_need_hop = Some(now + 240);
             }
             WireguardAddress
@@ -261,10 +560,29 @@ impl Node for StaticPeer {
             | WireguardV6Address
             | ReplyFromWireguardV6Address => {
                 self.wg_tunnel_need_hop = None;
+                self.last_tunnel_contact = now;
             }
             _ => (),
         }
 
+        if self.extra_wg_ports != advertisement.extra_wg_ports {
+            info!(target: &self.static_peer.wg_ip.to_string(), "extra wg ports {:?}", advertisement.extra_wg_ports);
+            self.extra_wg_ports = std::mem::take(&mut advertisement.extra_wg_ports);
+            if self.path > self.extra_wg_ports.len() {
+                self.path = 0;
+            }
+            events.push(Event::UpdateWireguardConfiguration);
+        }
+
+        if self.public_key.as_ref() != Some(&advertisement.public_key)
+            && self.next_public_key.as_ref() == Some(&advertisement.public_key)
+        {
+            // The peer has switched to the announced key a bit earlier
+            info!(target: "advertisement", "Advertisement with announced public key from {}", src_addr);
+            self.public_key = self.next_public_key.take();
+            events.push(Event::UpdateWireguardConfiguration);
+        }
+
         let mut reply_advertisement = false;
         if self.public_key.is_some() {
             // Check if public_key including creation time is same
@@ -315,6 +633,12 @@ impl Node for StaticPeer {
                 wg_ip: self.static_peer.wg_ip,
             });
         }
+        // The announcement is only valid together with an accepted public key
+        if let Some(public_key) = self.public_key.as_ref() {
+            if public_key.key == advertised_key {
+                self.next_public_key = announced_key(public_key, next_public_key.as_ref());
+            }
+        }
         (None, events)
     }
     fn update_from_wireguard_configuration(
@@ -323,9 +647,38 @@ impl Node for StaticPeer {
     ) {
         // Nothing to be done here for the moment
     }
+    fn state(&self) -> Option<NodeState> {
+        Some(NodeState::Static {
+            wg_ip: self.static_peer.wg_ip,
+            public_key: self.public_key.clone(),
+            is_alive: self.is_alive,
+            lastseen: self.lastseen,
+            current_ip: self.current_ip,
+            routedb: self.routedb_manager.state(),
+        })
+    }
+    fn restore_state(&mut self, state: &NodeState) {
+        if let NodeState::Static {
+            public_key,
+            is_alive,
+            lastseen,
+            current_ip,
+            routedb,
+            ..
+        } = state
+        {
+            self.public_key = public_key.clone();
+            self.is_alive = *is_alive;
+            self.lastseen = *lastseen;
+            self.current_ip = *current_ip;
+            // The scope id is not persisted
+            self.current_scope_id = self.static_peer.scope_id();
+            self.routedb_manager = RouteDBManager::from_state(routedb.as_ref());
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ConnectionType {
     Static {
         endpoint: SocketAddr,
@@ -341,7 +694,7 @@ pub enum ConnectionType {
     Passive,
 }
 impl ConnectionType {
-    fn endpoint(&self) -> Option<SocketAddr> {
+    pub fn endpoint(&self) -> Option<SocketAddr> {
         match self {
             ConnectionType::Passive => None,
             ConnectionType::Static { endpoint, .. } => Some(*endpoint),
@@ -349,7 +702,7 @@ impl ConnectionType {
             ConnectionType::Dynamic { endpoint } => *endpoint,
         }
     }
-    fn as_str(&self) -> &'static str {
+    pub fn as_str(&self) -> &'static str {
         match self {
             ConnectionType::Passive => "passive",
             ConnectionType::Static { .. } => "static",
@@ -359,23 +712,67 @@ impl ConnectionType {
     }
 }
 
+// Visible endpoints as learned from advertisements or wireguard.
+// A peer can be reachable by ipv4 and ipv6, so both are kept.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct VisibleEndpoints {
+    pub v4: Option<SocketAddr>,
+    pub v6: Option<SocketAddr>,
+}
+impl VisibleEndpoints {
+    pub fn learn(&mut self, endpoint: SocketAddr) {
+        match endpoint {
+            SocketAddr::V4(_) => self.v4 = Some(endpoint),
+            SocketAddr::V6(sa) => {
+                if let Some(ipv4) = sa.ip().to_ipv4_mapped() {
+                    self.v4 = Some(SocketAddr::new(ipv4.into(), sa.port()));
+                } else {
+                    self.v6 = Some(endpoint);
+                }
+            }
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_none() && self.v6.is_none()
+    }
+    pub fn select(&self, prefer_ipv6: bool) -> Option<SocketAddr> {
+        if prefer_ipv6 {
+            self.v6.or(self.v4)
+        } else {
+            self.v4.or(self.v6)
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct DynamicPeer {
     pub public_key: PublicKeyWithTime,
+    pub next_public_key: Option<PublicKeyWithTime>,
     pub local_wg_port: u16,
     pub local_admin_port: u16,
-    pub wg_ip: Ipv4Addr,
+    pub wg_ip: IpAddr,
     pub name: String,
     pub connection: ConnectionType,
     pub local_reachable_wg_endpoint: Option<SocketAddr>,
     pub local_reachable_admin_endpoint: Option<SocketAddr>,
-    pub dp_visible_wg_endpoint: Option<SocketAddr>,
-    pub gateway_for: HashSet<Ipv4Addr>,
+    pub dp_visible_wg_endpoints: VisibleEndpoints,
+    pub gateway_for: HashSet<IpAddr>,
     pub admin_port: u16,
     pub lastseen: u64,
+    next_ping: u64,
     routedb_manager: RouteDBManager,
+    // The gateway of a forced relay
+    relay: Option<IpAddr>,
+    rtt: LinkRtt,
 }
 impl DynamicPeer {
+    // Dynamic and passive connections have been established through NAT
+    fn is_behind_nat(&self) -> bool {
+        matches!(
+            self.connection,
+            ConnectionType::Dynamic { .. } | ConnectionType::Passive
+        )
+    }
     pub fn from_advertisement(
         now: u64,
         static_config: &StaticConfiguration,
@@ -385,7 +782,7 @@ impl DynamicPeer {
         let connection: ConnectionType;
         let mut local_reachable_admin_endpoint = None;
         let mut local_reachable_wg_endpoint = None;
-        let mut dp_visible_wg_endpoint = None;
+        let mut dp_visible_wg_endpoints = VisibleEndpoints::default();
 
         use AddressedTo::*;
         match &advertisement.addressed_to {
@@ -402,7 +799,7 @@ impl DynamicPeer {
             ReplyFromStaticAddress => {
                 // The peer is a static node. So the defined endpoint can be used.
                 if let Some(static_peer) = static_config.peers.get(&advertisement.wg_ip) {
-                    let endpoint = SocketAddr::new(src_addr.ip(), static_peer.wg_port);
+                    let endpoint = with_port(src_addr, static_peer.wg_port);
                     connection = ConnectionType::Static {
                         endpoint,
                         admin_endpoint: src_addr,
@@ -425,14 +822,14 @@ impl DynamicPeer {
                         }
                     }
                     if !is_local {
-                        dp_visible_wg_endpoint = Some(*visible_endpoint);
+                        dp_visible_wg_endpoints.learn(*visible_endpoint);
                     }
                 }
             }
             LocalAddress | ReplyFromLocalAddress => {
                 // The peer and myself are talking in the same subnet.
                 // So the peer's endpoint can be determined.
-                let endpoint = SocketAddr::new(src_addr.ip(), advertisement.local_wg_port);
+                let endpoint = with_port(src_addr, advertisement.local_wg_port);
                 connection = ConnectionType::Local {
                     endpoint,
                     admin_endpoint: src_addr,
@@ -461,18 +858,125 @@ impl DynamicPeer {
             local_admin_port: advertisement.local_admin_port,
             local_wg_port: advertisement.local_wg_port,
             public_key: advertisement.public_key.clone(),
+            next_public_key: announced_key(
+                &advertisement.public_key,
+                advertisement.next_public_key.as_ref(),
+            ),
             name: advertisement.name,
             connection,
             local_reachable_admin_endpoint,
             local_reachable_wg_endpoint,
-            dp_visible_wg_endpoint,
+            dp_visible_wg_endpoints,
             gateway_for: HashSet::new(),
             admin_port: src_addr.port(),
             lastseen: now,
+            next_ping: now + 29,
             routedb_manager,
+            relay: None,
+            rtt: LinkRtt::default(),
         })
     }
 }
+impl DynamicPeer {
+    // A restarted peer may use another admin port. The advertisement tells the
+    // local_admin_port and the source port tells the port seen via this path.
+    // Returns true, if a port has changed.
+    fn update_admin_port(
+        &mut self,
+        advertisement: &AdvertisementPacket,
+        src_addr: SocketAddr,
+    ) -> bool {
+        let target = self.wg_ip.to_string();
+        let mut changed = false;
+        let local_admin_port = advertisement.local_admin_port;
+        if local_admin_port != 0
+            && local_admin_port != advertisement.local_wg_port
+            && local_admin_port != self.local_admin_port
+        {
+            info!(target: &target, "local admin port changed from {} to {}", self.local_admin_port, local_admin_port);
+            self.local_admin_port = local_admin_port;
+            changed = true;
+        }
+        if src_addr.port() == 0 {
+            return changed;
+        }
+
+        use AddressedTo::*;
+        match advertisement.addressed_to {
+            WireguardAddress
+            | WireguardV6Address
+            | ReplyFromWireguardAddress
+            | ReplyFromWireguardV6Address => {
+                // Via the tunnel, the source has to be one of the peer's addresses
+                let via_tunnel = src_addr.ip() == self.wg_ip
+                    || nat_probe_address(&self.wg_ip).map(IpAddr::V6) == Some(src_addr.ip());
+                if via_tunnel && src_addr.port() != self.admin_port {
+                    info!(target: &target, "admin port changed from {} to {}", self.admin_port, src_addr.port());
+                    self.admin_port = src_addr.port();
+                    changed = true;
+                }
+            }
+            StaticAddress | ReplyFromStaticAddress | LocalAddress | ReplyFromLocalAddress => {
+                // A direct packet from the same host, but another port
+                if let ConnectionType::Static { admin_endpoint, .. }
+                | ConnectionType::Local { admin_endpoint, .. } = &mut self.connection
+                {
+                    if admin_endpoint.ip() == src_addr.ip() && *admin_endpoint != src_addr {
+                        info!(target: &target, "admin endpoint changed from {} to {}", admin_endpoint, src_addr);
+                        *admin_endpoint = src_addr;
+                        changed = true;
+                    }
+                }
+                if let Some(admin_endpoint) = self.local_reachable_admin_endpoint.as_mut() {
+                    if admin_endpoint.ip() == src_addr.ip() && *admin_endpoint != src_addr {
+                        *admin_endpoint = src_addr;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        changed
+    }
+    pub fn from_state(now: u64, state: &NodeState) -> Option<Self> {
+        match state {
+            NodeState::Dynamic {
+                wg_ip,
+                name,
+                public_key,
+                local_wg_port,
+                local_admin_port,
+                admin_port,
+                connection,
+                local_reachable_wg_endpoint,
+                local_reachable_admin_endpoint,
+                visible_wg_endpoints,
+                lastseen,
+                routedb,
+            } => Some(DynamicPeer {
+                wg_ip: *wg_ip,
+                local_admin_port: *local_admin_port,
+                local_wg_port: *local_wg_port,
+                public_key: public_key.clone(),
+                // An announced key is learned again with the next advertisement
+                next_public_key: None,
+                name: name.clone(),
+                connection: connection.clone(),
+                local_reachable_admin_endpoint: *local_reachable_admin_endpoint,
+                local_reachable_wg_endpoint: *local_reachable_wg_endpoint,
+                dp_visible_wg_endpoints: *visible_wg_endpoints,
+                gateway_for: HashSet::new(),
+                admin_port: *admin_port,
+                lastseen: *lastseen,
+                // Confirm the tunnel right away
+                next_ping: now,
+                routedb_manager: RouteDBManager::from_state(routedb.as_ref()),
+                relay: None,
+                rtt: LinkRtt::default(),
+            }),
+            NodeState::Static { .. } => None,
+        }
+    }
+}
 impl Node for DynamicPeer {
     fn routedb_manager(&self) -> Option<&RouteDBManager> {
         Some(&self.routedb_manager)
@@ -480,11 +984,23 @@ impl Node for DynamicPeer {
     fn routedb_manager_mut(&mut self) -> Option<&mut RouteDBManager> {
         Some(&mut self.routedb_manager)
     }
-    fn get_gateway_for(&mut self) -> Option<&mut HashSet<Ipv4Addr>> {
+    fn link_rtt(&self) -> Option<&LinkRtt> {
+        Some(&self.rtt)
+    }
+    fn link_rtt_mut(&mut self) -> Option<&mut LinkRtt> {
+        Some(&mut self.rtt)
+    }
+    fn get_gateway_for(&mut self) -> Option<&mut HashSet<IpAddr>> {
         Some(&mut self.gateway_for)
     }
+    fn get_gateway(&self) -> Option<IpAddr> {
+        self.relay
+    }
+    fn set_gateway(&mut self, gateway: Option<IpAddr>) {
+        self.relay = gateway;
+    }
     fn visible_wg_endpoint(&self) -> Option<SocketAddr> {
-        self.dp_visible_wg_endpoint
+        self.dp_visible_wg_endpoints.select(false)
     }
     fn local_admin_port(&self) -> u16 {
         self.local_admin_port
@@ -492,13 +1008,55 @@ impl Node for DynamicPeer {
     fn is_reachable(&self) -> bool {
         true
     }
-    fn peer_wireguard_configuration(&self) -> Option<Vec<String>> {
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+    fn public_key(&self) -> Option<&PublicKeyWithTime> {
+        Some(&self.public_key)
+    }
+    fn endpoint_class(&self) -> &'static str {
+        self.connection.as_str()
+    }
+    // Endpoints behind NAT are only mappings for this node, so not exported
+    fn contact(&self) -> Option<PublicPeer> {
+        match self.connection {
+            ConnectionType::Static {
+                endpoint,
+                admin_endpoint,
+            }
+            | ConnectionType::Local {
+                endpoint,
+                admin_endpoint,
+            } => Some(PublicPeer {
+                endpoint: endpoint.to_string(),
+                wg_port: endpoint.port(),
+                admin_port: admin_endpoint.port(),
+                wg_ip: self.wg_ip,
+                identity: None,
+                tier: DEFAULT_TIER,
+                resolver: EndpointResolver::Dns,
+            }),
+            ConnectionType::Dynamic { .. } | ConnectionType::Passive => None,
+        }
+    }
+    fn lastseen(&self) -> Option<u64> {
+        Some(self.lastseen)
+    }
+    fn peer_wireguard_configuration(
+        &self,
+        static_config: &StaticConfiguration,
+    ) -> Option<Vec<String>> {
         let mut lines = vec![];
         lines.push(format!("PublicKey = {}", &self.public_key.key));
-        lines.push(format!("AllowedIPs = {}/32", self.wg_ip));
-        lines.push(format!("AllowedIPs = {}/128", map_to_ipv6(&self.wg_ip)));
+        // With a forced relay, the gateway's entry has the wg_ip
+        if self.relay.is_none() {
+            lines.push(format!("AllowedIPs = {}", host_net(self.wg_ip)));
+        }
+        if let Some(probe_ip) = static_config.nat_probe_address(&self.wg_ip) {
+            lines.push(format!("AllowedIPs = {}/128", probe_ip));
+        }
         for ip in self.gateway_for.iter() {
-            lines.push(format!("AllowedIPs = {}/32", ip));
+            lines.push(format!("AllowedIPs = {}", host_net(*ip)));
         }
         if let Some(endpoint) = self.connection.endpoint() {
             debug!(target: "configuration", "peer {} uses {} endpoint {}", self.wg_ip, self.connection.as_str(), endpoint);
@@ -508,8 +1066,34 @@ impl Node for DynamicPeer {
             debug!(target: "configuration", "dynamic peer {} without endpoint", self.wg_ip);
             debug!(target: &self.wg_ip.to_string(), "is dynamic peer without endpoint");
         }
+        if let Some(seconds) = static_config.persistent_keepalive_for(self.is_behind_nat()) {
+            lines.push(format!("PersistentKeepalive = {}", seconds));
+        }
         Some(lines)
     }
+    fn check_handshake(
+        &mut self,
+        now: u64,
+        static_config: &StaticConfiguration,
+        latest_handshake: u64,
+    ) -> Vec<Event> {
+        // A passive peer is reached via the endpoint learned by wireguard
+        if (self.connection.endpoint().is_none() && self.dp_visible_wg_endpoints.is_empty())
+            || static_config
+                .persistent_keepalive_for(self.is_behind_nat())
+                .is_none()
+            || now.saturating_sub(latest_handshake) <= STALE_HANDSHAKE
+        {
+            return vec![];
+        }
+        // The advertisement via the tunnel makes wireguard retry the handshake
+        info!(target: &self.wg_ip.to_string(), "stale handshake, re-advertise");
+        vec![Event::SendAdvertisement {
+            addressed_to: AddressedTo::WireguardAddress,
+            to: SocketAddr::new(self.wg_ip, self.admin_port),
+            wg_ip: self.wg_ip,
+        }]
+    }
     fn process_every_second(
         &mut self,
         now: u64,
@@ -517,16 +1101,23 @@ impl Node for DynamicPeer {
     ) -> Vec<Event> {
         let mut events = vec![];
 
-        let dt = now - self.lastseen;
-        if dt % 30 == 29 {
+        if let Some(public_key) = due_key(now, &mut self.next_public_key) {
+            info!(target: &self.wg_ip.to_string(), "switch to announced public key");
+            self.public_key = public_key;
+            events.push(Event::UpdateWireguardConfiguration);
+        }
+
+        if now >= self.next_ping {
+            self.next_ping = now + 30;
+
             // Request routedb update, if outdated
             if self.routedb_manager.is_outdated() {
-                let destination = SocketAddrV4::new(self.wg_ip, self.admin_port);
+                let destination = SocketAddr::new(self.wg_ip, self.admin_port);
                 events.push(Event::SendRouteDatabaseRequest { to: destination });
             }
 
             // Pings are sent out only via the wireguard interface.
-            let destination = SocketAddr::V4(SocketAddrV4::new(self.wg_ip, self.admin_port));
+            let destination = SocketAddr::new(self.wg_ip, self.admin_port);
             events.push(Event::SendAdvertisement {
                 addressed_to: AddressedTo::WireguardAddress,
                 to: destination,
@@ -535,6 +1126,13 @@ impl Node for DynamicPeer {
         }
         events
     }
+    fn next_processing_time(&self, _now: u64) -> u64 {
+        let next = self.next_ping.min(self.lastseen + 121);
+        match self.next_public_key.as_ref() {
+            Some(next_public_key) => next.min(next_public_key.priv_key_creation_time),
+            None => next,
+        }
+    }
     fn ok_to_delete_without_route(&self, now: u64) -> bool {
         let dt = now - self.lastseen;
         dt > 120
@@ -548,6 +1146,18 @@ impl Node for DynamicPeer {
     ) -> (Option<Box<dyn Node>>, Vec<Event>) {
         let mut events = vec![];
         self.lastseen = now;
+        self.next_ping = now + 29;
+
+        if self.public_key != advertisement.public_key
+            && self.next_public_key.as_ref() == Some(&advertisement.public_key)
+        {
+            // The peer has switched to the announced key a bit earlier. So the peer and
+            // its routes are kept.
+            info!(target: "advertisement", "Advertisement with announced public key from {}", src_addr);
+            self.public_key = advertisement.public_key.clone();
+            self.next_public_key = None;
+            events.push(Event::UpdateWireguardConfiguration);
+        }
 
         // Check if public_key including creation time is same
         if self.public_key != advertisement.public_key {
@@ -584,9 +1194,16 @@ impl Node for DynamicPeer {
         } else {
             info!(target: "advertisement", "Advertisement from existing peer {}", src_addr);
 
+            self.next_public_key =
+                announced_key(&self.public_key, advertisement.next_public_key.as_ref());
             self.routedb_manager
                 .latest_version(advertisement.routedb_version);
 
+            if self.update_admin_port(&advertisement, src_addr) {
+                // local_admin_port is part of the route database
+                events.push(Event::UpdateRoutes);
+            }
+
             use crate::crypt_udp::AddressedTo::*;
             match advertisement.addressed_to {
                 StaticAddress => {
@@ -596,7 +1213,9 @@ impl Node for DynamicPeer {
                     warn!(target: "advertisement", "has not been sent via tunnel");
                     if advertisement.your_visible_wg_endpoint.is_some() {
                         events.push(Event::UpdateWireguardConfiguration);
-                        self.dp_visible_wg_endpoint = advertisement.my_visible_wg_endpoint;
+                        if let Some(endpoint) = advertisement.my_visible_wg_endpoint {
+                            self.dp_visible_wg_endpoints.learn(endpoint);
+                        }
                     }
                     events.push(Event::SendAdvertisement {
                         addressed_to: advertisement.addressed_to.reply(),
@@ -606,11 +1225,13 @@ impl Node for DynamicPeer {
                 }
                 ReplyFromStaticAddress => {
                     warn!(target: "advertisement", "reply has not been sent via tunnel");
-                    if self.dp_visible_wg_endpoint.is_none()
+                    if self.dp_visible_wg_endpoints.is_empty()
                         && advertisement.your_visible_wg_endpoint.is_some()
                     {
                         events.push(Event::UpdateWireguardConfiguration);
-                        self.dp_visible_wg_endpoint = advertisement.my_visible_wg_endpoint;
+                        if let Some(endpoint) = advertisement.my_visible_wg_endpoint {
+                            self.dp_visible_wg_endpoints.learn(endpoint);
+                        }
                     }
                 }
                 LocalAddress => {
@@ -632,38 +1253,77 @@ impl Node for DynamicPeer {
                 | ReplyFromWireguardAddress
                 | ReplyFromWireguardV6Address => {
                     // tunnel is ok. So check for visible wg endpoints
-                    if self.dp_visible_wg_endpoint.is_none() {
+                    if self.dp_visible_wg_endpoints.is_empty() {
                         events.push(Event::ReadWireguardConfiguration);
                     }
                 }
             }
         }
-        (None, vec![])
+        (None, events)
     }
     fn update_from_wireguard_configuration(
         &mut self,
         pubkey_to_endpoint: &mut HashMap<String, SocketAddr>,
     ) {
         if let Some(endpoint) = pubkey_to_endpoint.remove(&self.public_key.key) {
-            self.dp_visible_wg_endpoint = Some(endpoint);
+            self.dp_visible_wg_endpoints.learn(endpoint);
         }
     }
+    fn state(&self) -> Option<NodeState> {
+        Some(NodeState::Dynamic {
+            wg_ip: self.wg_ip,
+            name: self.name.clone(),
+            public_key: self.public_key.clone(),
+            local_wg_port: self.local_wg_port,
+            local_admin_port: self.local_admin_port,
+            admin_port: self.admin_port,
+            connection: self.connection.clone(),
+            local_reachable_wg_endpoint: self.local_reachable_wg_endpoint,
+            local_reachable_admin_endpoint: self.local_reachable_admin_endpoint,
+            visible_wg_endpoints: self.dp_visible_wg_endpoints,
+            lastseen: self.lastseen,
+            routedb: self.routedb_manager.state(),
+        })
+    }
 }
 
+// Interval for requesting the local contact of a distant node again
+pub const CONTACT_REFRESH: u64 = 600;
+// Interval for asking the gateway of a distant node for a rendezvous
+pub const CONNECT_REQUEST_INTERVAL: u64 = 300;
+// Duration of the simultaneous NAT traversal after a rendezvous
+pub const PUNCH_DURATION: u64 = 5;
+// Time after the first NAT traversal attempt, after which a relay is used
+pub const RELAY_FALLBACK: u64 = 600;
+
 #[derive(Debug)]
 pub struct DistantNode {
-    pub wg_ip: Ipv4Addr,
+    pub wg_ip: IpAddr,
     admin_port: u16,
     //hop_cnt: usize,
-    //gateway: Option<Ipv4Addr>,
+    //gateway: Option<IpAddr>,
     pub public_key: Option<PublicKeyWithTime>,
-    known_in_s: usize,
+    next_public_key: Option<PublicKeyWithTime>,
+    known_since: Option<u64>,
+    next_contact_request: u64,
     local_ip_list: Option<Vec<IpAddr>>,
     local_admin_port: Option<u16>,
     send_count: usize,
     can_send_to_visible_endpoint: bool,
-    pub visible_endpoint: Option<SocketAddr>,
-    gateway: Option<Ipv4Addr>,
+    pub visible_endpoints: VisibleEndpoints,
+    gateway: Option<IpAddr>,
+    next_connect_request: u64,
+    // Start time of the NAT traversal as per the last rendezvous
+    punch_at: Option<u64>,
+    own_visible_ip: Option<IpAddr>,
+    // Detected by the same visible ip as this node
+    behind_same_nat: bool,
+    // First attempt of the NAT traversal
+    traversal_since: Option<u64>,
+    // For the telemetry, including the attempts via the local ips
+    first_attempt: Option<u64>,
+    attempts: u32,
+    rendezvous: u32,
 }
 impl DistantNode {
     pub fn from(ri: &RouteInfo) -> Self {
@@ -673,32 +1333,100 @@ impl DistantNode {
             //hop_cnt: ri.hop_cnt,
             //gateway: ri.gateway,
             public_key: None,
-            known_in_s: 0,
+            next_public_key: None,
+            known_since: None,
+            next_contact_request: 0,
             local_ip_list: None,
             local_admin_port: None,
             send_count: 0,
             can_send_to_visible_endpoint: false,
-            visible_endpoint: None,
+            visible_endpoints: VisibleEndpoints::default(),
             gateway: None,
+            next_connect_request: 0,
+            punch_at: None,
+            own_visible_ip: None,
+            behind_same_nat: false,
+            traversal_since: None,
+            first_attempt: None,
+            attempts: 0,
+            rendezvous: 0,
+        }
+    }
+    fn contact_incomplete(&self) -> bool {
+        self.local_ip_list.is_none()
+            || self.public_key.is_none()
+            || self.visible_endpoints.is_empty()
+    }
+    // Without hairpinning, the NAT does not forward packets from the inside to its
+    // visible endpoint. Then only the local ips or the gateway can reach the node.
+    pub fn is_behind_same_nat(&self, static_config: &StaticConfiguration) -> bool {
+        !static_config.router_hairpinning
+            && self.own_visible_ip.is_some()
+            && self.visible_endpoints.v4.map(|ep| ep.ip()) == self.own_visible_ip
+    }
+    // The visible endpoint, which is worth to be tried
+    fn external_endpoint(&self, static_config: &StaticConfiguration) -> Option<SocketAddr> {
+        if self.is_behind_same_nat(static_config) {
+            self.visible_endpoints.v6
+        } else {
+            self.visible_endpoints
+                .select(static_config.prefer_ipv6_endpoints)
         }
     }
 }
 impl Node for DistantNode {
+    fn wants_local_contact(&self) -> bool {
+        self.contact_incomplete()
+    }
+    fn set_own_visible_endpoint(&mut self, endpoint: SocketAddr) {
+        if endpoint.is_ipv4() {
+            self.own_visible_ip = Some(endpoint.ip());
+        }
+    }
     fn process_local_contact(&mut self, local: LocalContactPacket) {
         debug!(target: &self.wg_ip.to_string(), "Received local contact packet");
-        self.send_count = 0;
+        // A refreshed contact does not restart the attempts to reach the local ips
+        if self.local_ip_list.as_ref() != Some(&local.local_ip_list)
+            || self.local_admin_port != Some(local.local_admin_port)
+        {
+            self.send_count = 0;
+        }
         self.local_ip_list = Some(local.local_ip_list);
         self.local_admin_port = Some(local.local_admin_port);
-        self.visible_endpoint = local.my_visible_wg_endpoint;
+        if let Some(endpoint) = local.my_visible_wg_endpoint {
+            self.visible_endpoints.learn(endpoint);
+        }
+        self.next_public_key = announced_key(&local.public_key, local.next_public_key.as_ref());
         self.public_key = Some(local.public_key);
     }
-    fn peer_wireguard_configuration(&self) -> Option<Vec<String>> {
+    fn process_connect_request(&mut self, endpoint: SocketAddr, start_time: u64) -> Vec<Event> {
+        info!(target: &self.wg_ip.to_string(), "rendezvous: punch to {} at {}", endpoint, start_time);
+        let before = self.visible_endpoints;
+        self.visible_endpoints.learn(endpoint);
+        self.punch_at = Some(start_time);
+        self.rendezvous += 1;
+        // The peer has asked for this rendezvous, so no own request is needed
+        self.next_connect_request = self
+            .next_connect_request
+            .max(start_time + CONNECT_REQUEST_INTERVAL);
+        if self.can_send_to_visible_endpoint && before != self.visible_endpoints {
+            vec![Event::UpdateWireguardConfiguration]
+        } else {
+            vec![]
+        }
+    }
+    fn peer_wireguard_configuration(
+        &self,
+        static_config: &StaticConfiguration,
+    ) -> Option<Vec<String>> {
+        // Without a nat probe address, the distant node is only reachable via the gateway
+        let probe_ip = static_config.nat_probe_address(&self.wg_ip)?;
         self.public_key.as_ref().map(
             |public_key| {
             let mut lines = vec![];
             lines.push(format!("PublicKey = {}", &public_key.key));
-            lines.push(format!("AllowedIPs = {}/128", map_to_ipv6(&self.wg_ip)));
-            if let Some(endpoint) = self.visible_endpoint.as_ref() {
+            lines.push(format!("AllowedIPs = {}/128", probe_ip));
+            if let Some(endpoint) = self.external_endpoint(static_config).as_ref() {
                 warn!("peer sends eventually local address as visible endpoint");
                 debug!(target: "configuration", "node {} uses visible (NAT) endpoint {}", self.wg_ip, endpoint);
                 debug!(target: &self.wg_ip.to_string(), "use visible (NAT) endpoint {}", endpoint);
@@ -710,7 +1438,7 @@ impl Node for DistantNode {
     fn process_every_second(
         &mut self,
         now: u64,
-        _static_config: &StaticConfiguration,
+        static_config: &StaticConfiguration,
     ) -> Vec<Event> {
         let mut events = vec![];
 
@@ -719,25 +1447,45 @@ impl Node for DistantNode {
         } else {
             ""
         };
-        self.known_in_s += 1;
+        let known_in_s = now - *self.known_since.get_or_insert(now) + 1;
 
-        if self.local_ip_list.is_none()
-            || self.public_key.is_none()
-            || self.visible_endpoint.is_none()
-        {
-            // have no data received or is not complete, so ask again
-            if self.known_in_s % 60 == 0 || self.known_in_s < 5 {
+        if let Some(public_key) = due_key(now, &mut self.next_public_key) {
+            info!(target: &self.wg_ip.to_string(), "switch to announced public key");
+            self.public_key = Some(public_key);
+            events.push(Event::UpdateWireguardConfiguration);
+        }
+
+        let behind_same_nat = self.is_behind_same_nat(static_config);
+        if behind_same_nat != self.behind_same_nat {
+            self.behind_same_nat = behind_same_nat;
+            if behind_same_nat {
+                // The local ips are tried again instead of the visible endpoint
+                info!(target: &self.wg_ip.to_string(), "behind the same NAT => try the local ips or relay");
+                self.send_count = 0;
+            }
+            events.push(Event::UpdateWireguardConfiguration);
+        }
+
+        if now >= self.next_contact_request {
+            let destination = SocketAddr::new(self.wg_ip, self.admin_port);
+            if self.contact_incomplete() {
+                // have no data received or is not complete, so ask again
                 // Send request for local contact
-                trace!(target: "nodes", "Alive node: {:?} for {} s {}", self.wg_ip, self.known_in_s, pk_available);
-                let destination = SocketAddrV4::new(self.wg_ip, self.admin_port);
-                events.push(Event::SendLocalContactRequest { to: destination });
+                trace!(target: "nodes", "Alive node: {:?} for {} s {}", self.wg_ip, known_in_s, pk_available);
+                self.next_contact_request = if known_in_s < 4 { now + 1 } else { now + 60 };
+            } else {
+                // Refresh to learn a rotated key of the distant node
+                self.next_contact_request = now + CONTACT_REFRESH;
             }
+            events.push(Event::SendLocalContactRequest { to: destination });
         }
         if self.send_count < 10 {
             // Try to reach local ip
             if let Some(ip_list) = self.local_ip_list.as_ref() {
                 if let Some(admin_port) = self.local_admin_port.as_ref() {
                     self.send_count += 1;
+                    self.first_attempt.get_or_insert(now);
+                    self.attempts += 1;
                     info!(target: &self.wg_ip.to_string(), "try to reach distant node via local subnet {}/10",self.send_count);
                     for ip in ip_list.iter() {
                         if let IpAddr::V4(ipv4) = ip {
@@ -745,16 +1493,51 @@ impl Node for DistantNode {
                                 continue;
                             }
                         }
-                        events.push(Event::SendAdvertisement {
-                            addressed_to: AddressedTo::LocalAddress,
-                            to: SocketAddr::new(*ip, *admin_port),
-                            wg_ip: self.wg_ip,
-                        });
+                        if ip.is_ipv6() && static_config.disable_ipv6 {
+                            continue;
+                        }
+                        // The interface of a link-local address is unknown, so try all
+                        let scope_ids = if needs_scope(ip) {
+                            static_config.link_scope_ids.clone()
+                        } else {
+                            vec![0]
+                        };
+                        for scope_id in scope_ids {
+                            events.push(Event::SendAdvertisement {
+                                addressed_to: AddressedTo::LocalAddress,
+                                to: scoped(*ip, *admin_port, scope_id),
+                                wg_ip: self.wg_ip,
+                            });
+                        }
                     }
                 }
             }
         }
-        let can_send = self.public_key.is_some() && self.visible_endpoint.is_some();
+        // Blind sends to the visible endpoint rarely pass two NATs, so ask the
+        // gateway for a rendezvous
+        if self.public_key.is_some()
+            && self.gateway.is_some()
+            && !behind_same_nat
+            && now >= self.next_connect_request
+        {
+            self.next_connect_request = now + CONNECT_REQUEST_INTERVAL;
+            if static_config.nat_probe_address(&self.wg_ip).is_some() {
+                info!(target: &self.wg_ip.to_string(), "ask gateway for rendezvous");
+                self.traversal_since.get_or_insert(now);
+                self.first_attempt.get_or_insert(now);
+                events.push(Event::SendConnectRequest { wg_ip: self.wg_ip });
+            }
+        }
+        let punching = match self.punch_at {
+            Some(start_time) if now >= start_time + PUNCH_DURATION => {
+                self.punch_at = None;
+                false
+            }
+            Some(start_time) => now >= start_time,
+            None => false,
+        };
+
+        let can_send = self.public_key.is_some() && self.external_endpoint(static_config).is_some();
 
         if can_send {
             if !self.can_send_to_visible_endpoint {
@@ -762,21 +1545,49 @@ impl Node for DistantNode {
                 events.push(Event::UpdateWireguardConfiguration);
             }
 
-            if now % 60 < 5 {
-                // TODO: Try to reach visible endpoint via wg ipv6
-                info!(target: &self.wg_ip.to_string(), "try to reach distant node via NAT traversal");
-                let wg_ipv6 = map_to_ipv6(&self.wg_ip);
-                let destination = SocketAddr::V6(SocketAddrV6::new(wg_ipv6, self.admin_port, 0, 0));
-                events.push(Event::SendAdvertisement {
-                    addressed_to: AddressedTo::WireguardV6Address,
-                    to: destination,
-                    wg_ip: self.wg_ip,
-                });
+            if now % 60 < 5 || punching {
+                if let Some(probe_ip) = static_config.nat_probe_address(&self.wg_ip) {
+                    info!(target: &self.wg_ip.to_string(), "try to reach distant node via NAT traversal");
+                    self.traversal_since.get_or_insert(now);
+                    self.first_attempt.get_or_insert(now);
+                    self.attempts += 1;
+                    let destination =
+                        SocketAddr::V6(SocketAddrV6::new(probe_ip, self.admin_port, 0, 0));
+                    events.push(Event::SendAdvertisement {
+                        addressed_to: AddressedTo::WireguardV6Address,
+                        to: destination,
+                        wg_ip: self.wg_ip,
+                    });
+                }
             }
         }
 
         events
     }
+    fn next_processing_time(&self, now: u64) -> u64 {
+        let mut next = (now + 60).min(self.next_contact_request);
+        if let Some(next_public_key) = self.next_public_key.as_ref() {
+            next = next.min(next_public_key.priv_key_creation_time);
+        }
+        if self.send_count < 10 && self.local_ip_list.is_some() && self.local_admin_port.is_some() {
+            next = now + 1;
+        }
+        if self.public_key.is_some() && !self.visible_endpoints.is_empty() {
+            // NAT traversal is tried in the first five seconds of each minute
+            if now % 60 < 4 {
+                next = now + 1;
+            } else {
+                next = next.min(now - now % 60 + 60);
+            }
+        }
+        if self.public_key.is_some() && self.gateway.is_some() {
+            next = next.min(self.next_connect_request);
+        }
+        if let Some(start_time) = self.punch_at {
+            next = next.min(start_time.max(now + 1));
+        }
+        next
+    }
     fn ok_to_delete_without_route(&self, _now: u64) -> bool {
         // only delete, if dropped from routing table
         false
@@ -804,7 +1615,7 @@ impl Node for DistantNode {
             events.push(Event::UpdateWireguardConfiguration);
 
             // if still not known, then ask wireguard
-            if dp.dp_visible_wg_endpoint.is_none() {
+            if dp.dp_visible_wg_endpoints.is_empty() {
                 events.push(Event::ReadWireguardConfiguration);
             }
 
@@ -828,7 +1639,7 @@ impl Node for DistantNode {
                     }
                 }
                 if !is_local {
-                    self.visible_endpoint = Some(endpoint);
+                    self.visible_endpoints.learn(endpoint);
                 }
             }
         }
@@ -839,10 +1650,29 @@ impl Node for DistantNode {
     fn is_distant_node(&self) -> bool {
         true
     }
-    fn get_gateway(&self) -> Option<Ipv4Addr> {
+    fn traversal_failed(&self, now: u64) -> bool {
+        self.traversal_since
+            .map(|since| now >= since + RELAY_FALLBACK)
+            .unwrap_or(false)
+    }
+    fn traversal(&self) -> Option<TraversalProgress> {
+        Some(TraversalProgress {
+            since: self.first_attempt,
+            attempts: self.attempts,
+            rendezvous: self.rendezvous,
+            has_visible_endpoint: !self.visible_endpoints.is_empty(),
+        })
+    }
+    fn endpoint_class(&self) -> &'static str {
+        "distant"
+    }
+    fn public_key(&self) -> Option<&PublicKeyWithTime> {
+        self.public_key.as_ref()
+    }
+    fn get_gateway(&self) -> Option<IpAddr> {
         self.gateway
     }
-    fn set_gateway(&mut self, gateway: Option<Ipv4Addr>) {
+    fn set_gateway(&mut self, gateway: Option<IpAddr>) {
         self.gateway = gateway;
     }
 }
@@ -0,0 +1,154 @@
+// Export and import of the known peers for the bootstrap of a node, which cannot
+// reach any static peer initially, e.g. behind a strict firewall.
+//
+// The file is readable json. It is signed with a key derived from the shared key, so
+// only a node of the own network can create a file, which is accepted on import. The
+// imported contacts are used as static peers in addition to network.yaml.
+//
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::configuration::PublicPeer;
+use crate::endpoint_resolver::EndpointResolver;
+use crate::error::*;
+use crate::identity::decode_identity;
+use crate::manager::NetworkManager;
+
+pub const PEER_DB_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerContact {
+    pub wg_ip: IpAddr,
+    pub endpoint: String,
+    pub wg_port: u16,
+    pub admin_port: u16,
+    // base64 of the identity, which signs the advertisements
+    pub identity: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerDatabase {
+    pub format_version: u32,
+    pub exported_by: IpAddr,
+    pub created: u64,
+    pub peers: Vec<PeerContact>,
+    // base64 of nonce and authentication tag over the json with an empty signature
+    #[serde(default)]
+    pub signature: String,
+}
+impl PeerDatabase {
+    pub fn of(now: u64, exported_by: IpAddr, network_manager: &NetworkManager) -> Self {
+        let peers = network_manager
+            .known_contacts()
+            .into_iter()
+            .map(|peer| PeerContact {
+                wg_ip: peer.wg_ip,
+                endpoint: peer.endpoint,
+                wg_port: peer.wg_port,
+                admin_port: peer.admin_port,
+                identity: peer.identity.map(base64::encode),
+            })
+            .collect();
+        PeerDatabase {
+            format_version: PEER_DB_VERSION,
+            exported_by,
+            created: now,
+            peers,
+            signature: String::new(),
+        }
+    }
+    fn signed_data(&self) -> BoxResult<Vec<u8>> {
+        let unsigned = PeerDatabase {
+            signature: String::new(),
+            ..self.clone()
+        };
+        Ok(serde_json::to_vec(&unsigned)?)
+    }
+    pub fn sign(&mut self, shared_key: &[u8]) -> BoxResult<()> {
+        let nonce_raw: [u8; 24] = rand::random();
+        let tag = cipher(shared_key)
+            .encrypt(
+                XNonce::from_slice(&nonce_raw),
+                Payload {
+                    msg: b"",
+                    aad: &self.signed_data()?,
+                },
+            )
+            .map_err(|e| format!("{:?}", e))?;
+        self.signature = base64::encode([&nonce_raw[..], &tag].concat());
+        Ok(())
+    }
+    // A file of another network or a modified file is rejected
+    pub fn verify(&self, shared_key: &[u8]) -> BoxResult<()> {
+        if self.format_version > PEER_DB_VERSION {
+            return strerror("Unsupported peer database version");
+        }
+        let raw = base64::decode(&self.signature)?;
+        if raw.len() != 24 + 16 {
+            return strerror("Invalid signature of peer database");
+        }
+        cipher(shared_key)
+            .decrypt(
+                XNonce::from_slice(&raw[..24]),
+                Payload {
+                    msg: &raw[24..],
+                    aad: &self.signed_data()?,
+                },
+            )
+            .map_err(|_| "Peer database is not signed for this network")?;
+        Ok(())
+    }
+    pub fn to_json(&self) -> BoxResult<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+    pub fn from_json(json: &str) -> BoxResult<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+    pub fn write<P: AsRef<Path>>(&self, fname: P) -> BoxResult<()> {
+        fs::write(fname, self.to_json()?)?;
+        Ok(())
+    }
+    // Read and verify against any of the given shared keys
+    pub fn read<P: AsRef<Path>>(fname: P, shared_keys: &[&[u8]]) -> BoxResult<Self> {
+        let peer_db = PeerDatabase::from_json(&fs::read_to_string(fname)?)?;
+        let mut result = strerror("No shared key");
+        for shared_key in shared_keys {
+            result = peer_db.verify(shared_key);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result.map(|_| peer_db)
+    }
+    // The contacts as static peers of the given tier
+    pub fn public_peers(&self, tier: u8) -> BoxResult<Vec<PublicPeer>> {
+        self.peers
+            .iter()
+            .map(|peer| {
+                Ok(PublicPeer {
+                    endpoint: peer.endpoint.clone(),
+                    wg_port: peer.wg_port,
+                    admin_port: peer.admin_port,
+                    wg_ip: peer.wg_ip,
+                    identity: peer.identity.as_deref().map(decode_identity).transpose()?,
+                    tier,
+                    resolver: EndpointResolver::Dns,
+                })
+            })
+            .collect()
+    }
+}
+
+fn cipher(shared_key: &[u8]) -> XChaCha20Poly1305 {
+    let hk = Hkdf::<Sha256>::new(Some(b"wg_netmanager peer db"), shared_key);
+    let mut key = [0u8; 32];
+    hk.expand(b"signature v1", &mut key).unwrap();
+    XChaCha20Poly1305::new(Key::from_slice(&key))
+}
@@ -0,0 +1,99 @@
+// Mapping of the wireguard public keys to the node names for external tools.
+//
+// `wg show` lists the peers by public key only. With `--peer-names <path>`, a json
+// file maps each public key of the wireguard configuration to the name and wg_ip of
+// the node. It is rewritten after every sync of the wireguard configuration, if the
+// mapping has changed, and removed on exit. E.g. to label the peers:
+//
+//      wg show wg0 latest-handshakes | while read key t; do
+//          echo "$(jq -r --arg k $key '.peers[$k].name' names.json) $t"; done
+//
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::StaticConfiguration;
+use crate::error::*;
+use crate::manager::NetworkManager;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerName {
+    // None, until the node has advertised its name
+    pub name: Option<String>,
+    pub wg_ip: IpAddr,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerNames {
+    pub wg_name: String,
+    // The own node
+    pub name: String,
+    pub wg_ip: IpAddr,
+    pub public_key: String,
+    // By public key
+    pub peers: BTreeMap<String, PeerName>,
+}
+impl PeerNames {
+    pub fn of(network_manager: &NetworkManager, static_config: &StaticConfiguration) -> Self {
+        let peers = network_manager
+            .peer_infos(0)
+            .into_iter()
+            .filter_map(|peer| {
+                let name = PeerName {
+                    name: peer.name,
+                    wg_ip: peer.wg_ip,
+                };
+                peer.public_key.map(|public_key| (public_key, name))
+            })
+            .collect();
+        PeerNames {
+            wg_name: static_config.wg_name.clone(),
+            name: static_config.name.clone(),
+            wg_ip: static_config.wg_ip,
+            public_key: static_config.my_public_key.key.clone(),
+            peers,
+        }
+    }
+}
+
+pub struct PeerNamesFile {
+    path: String,
+    written: Option<PeerNames>,
+}
+impl PeerNamesFile {
+    pub fn new<T: Into<String>>(path: T) -> Self {
+        PeerNamesFile {
+            path: path.into(),
+            written: None,
+        }
+    }
+    // Returns true, if the file has been written
+    pub fn update(&mut self, names: PeerNames) -> BoxResult<bool> {
+        if self.written.as_ref() == Some(&names) {
+            return Ok(false);
+        }
+        let path = Path::new(&self.path);
+        let tmp_path = path.with_file_name(format!(
+            ".{}.tmp",
+            path.file_name()
+                .ok_or("peer names path has no file name")?
+                .to_string_lossy()
+        ));
+        let mut buf = serde_json::to_vec_pretty(&names)?;
+        buf.push(b'\n');
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        self.written = Some(names);
+        Ok(true)
+    }
+    // The stale file is removed on exit
+    pub fn remove(&self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
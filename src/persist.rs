@@ -0,0 +1,137 @@
+// Encryption at rest for persisted state (peer cache, route database snapshots).
+//
+// File structure:
+//    4 Bytes   Magic "WGNM"
+//    1 Byte    Format version
+//   24 Bytes   Nonce
+//    n Bytes   Encrypted data incl. 16 Bytes authentication tag
+//
+// The key is derived from the network's shared key with HKDF, so the same key is
+// never used for both udp packets and files. Alternatively a local key file can
+// be used, which never leaves the node.
+//
+// On save, the previous file is kept as <name>.bak. On load, a corrupt or
+// unauthenticated file is moved aside to <name>.corrupt and the backup is tried.
+// Missing or unusable state is not an error, the caller just starts fresh.
+//
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use log::*;
+use sha2::Sha256;
+
+use crate::error::*;
+
+const MAGIC: &[u8; 4] = b"WGNM";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 4 + 1 + 24;
+
+pub struct PersistKey {
+    key: [u8; 32],
+}
+impl PersistKey {
+    pub fn from_shared_key(shared_key: &[u8]) -> Self {
+        let hk = Hkdf::<Sha256>::new(Some(b"wg_netmanager persist"), shared_key);
+        let mut key = [0u8; 32];
+        hk.expand(b"state at rest v1", &mut key).unwrap();
+        PersistKey { key }
+    }
+    // Use a local key file. If not existing, a random key is created.
+    pub fn from_key_file<P: AsRef<Path>>(fname: P) -> BoxResult<Self> {
+        let fname = fname.as_ref();
+        if let Ok(content) = fs::read_to_string(fname) {
+            let raw = base64::decode(content.trim())?;
+            if raw.len() != 32 {
+                return strerror("Invalid key length in key file");
+            }
+            let mut key = [0u8; 32];
+            key.copy_from_slice(&raw);
+            return Ok(PersistKey { key });
+        }
+        let key: [u8; 32] = rand::random();
+        write_private(fname, base64::encode(key).as_bytes())?;
+        info!(target: "persist", "Created new key file {}", fname.display());
+        Ok(PersistKey { key })
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> BoxResult<Vec<u8>> {
+        let nonce_raw: [u8; 24] = rand::random();
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&self.key));
+        let encrypted = cipher
+            .encrypt(XNonce::from_slice(&nonce_raw), plaintext)
+            .map_err(|e| format!("{:?}", e))?;
+        let mut buf = Vec::with_capacity(HEADER_LEN + encrypted.len());
+        buf.extend_from_slice(MAGIC);
+        buf.push(FORMAT_VERSION);
+        buf.extend_from_slice(&nonce_raw);
+        buf.extend_from_slice(&encrypted);
+        Ok(buf)
+    }
+    pub fn open(&self, buf: &[u8]) -> BoxResult<Vec<u8>> {
+        if buf.len() < HEADER_LEN || &buf[..4] != MAGIC {
+            return strerror("Not an encrypted state file");
+        }
+        if buf[4] != FORMAT_VERSION {
+            return strerror("Unsupported state file version");
+        }
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&self.key));
+        let plaintext = cipher
+            .decrypt(XNonce::from_slice(&buf[5..HEADER_LEN]), &buf[HEADER_LEN..])
+            .map_err(|e| format!("Decryption error {:?}", e))?;
+        Ok(plaintext)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, fname: P, plaintext: &[u8]) -> BoxResult<()> {
+        let fname = fname.as_ref();
+        let buf = self.seal(plaintext)?;
+        let tmp = with_suffix(fname, "tmp");
+        write_private(&tmp, &buf)?;
+        if fname.exists() {
+            fs::rename(fname, with_suffix(fname, "bak"))?;
+        }
+        fs::rename(&tmp, fname)?;
+        Ok(())
+    }
+    pub fn load<P: AsRef<Path>>(&self, fname: P) -> Option<Vec<u8>> {
+        let fname = fname.as_ref();
+        for candidate in [fname.to_path_buf(), with_suffix(fname, "bak")] {
+            let buf = match fs::read(&candidate) {
+                Ok(buf) => buf,
+                Err(_) => continue,
+            };
+            match self.open(&buf) {
+                Ok(plaintext) => return Some(plaintext),
+                Err(e) => {
+                    warn!(target: "persist", "Cannot load {}: {}", candidate.display(), e);
+                    fs::rename(&candidate, with_suffix(&candidate, "corrupt")).ok();
+                }
+            }
+        }
+        None
+    }
+}
+
+fn with_suffix(fname: &Path, suffix: &str) -> PathBuf {
+    let mut s = fname.as_os_str().to_os_string();
+    s.push(".");
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+pub(crate) fn write_private(fname: &Path, buf: &[u8]) -> BoxResult<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(fname)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    Ok(())
+}
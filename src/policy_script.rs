@@ -0,0 +1,208 @@
+// Policy decisions by an embedded rhai script.
+//
+// The script given with --policy-script may define any of these functions:
+//
+//   fn accept_peer(peer)              -> bool: false ignores the advertisement
+//   fn allow_route(route)             -> bool: false ignores the offered route
+//   fn select_gateway(to, candidates) -> the gateway to use for `to` or ()
+//
+// peer, route and the candidates are maps with the addresses as strings. Each call
+// is limited to TIME_LIMIT_MS and MAX_OPERATIONS, and the script has no access to
+// files or the network. A failing or timed out call falls back to the decision
+// without script, i.e. accept, allow resp. the best path.
+//
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::*;
+use rhai::{Array, Dynamic, Engine, FuncArgs, Map, Scope, AST};
+
+use crate::crypt_udp::AdvertisementPacket;
+use crate::error::*;
+use crate::routedb::RouteInfo;
+
+pub const TIME_LIMIT_MS: u64 = 20;
+pub const MAX_OPERATIONS: u64 = 1_000_000;
+
+// Name and number of parameters
+const HOOKS: [(&str, usize); 3] = [
+    ("accept_peer", 1),
+    ("allow_route", 1),
+    ("select_gateway", 2),
+];
+
+fn route_map(route: &RouteInfo) -> Map {
+    let mut map = Map::new();
+    map.insert("to".into(), route.to.to_string().into());
+    map.insert(
+        "gateway".into(),
+        route
+            .gateway
+            .map(|gateway| gateway.to_string().into())
+            .unwrap_or(Dynamic::UNIT),
+    );
+    map.insert("hop_cnt".into(), (route.hop_cnt as i64).into());
+    map.insert("cost".into(), (route.cost as i64).into());
+    map.insert(
+        "bandwidth_mbit".into(),
+        route
+            .bandwidth_mbit
+            .map(|mbit| (mbit as i64).into())
+            .unwrap_or(Dynamic::UNIT),
+    );
+    map.insert("exported_nets".into(), nets_array(&route.exported_nets));
+    map
+}
+
+fn nets_array(nets: &[ipnet::IpNet]) -> Dynamic {
+    nets.iter()
+        .map(|net| net.to_string().into())
+        .collect::<Array>()
+        .into()
+}
+
+pub struct PolicyScript {
+    engine: Engine,
+    ast: AST,
+    hooks: HashSet<&'static str>,
+    // Checked by the engine during a call
+    deadline: Arc<Mutex<Instant>>,
+}
+impl PolicyScript {
+    pub fn load(fname: &str) -> BoxResult<Self> {
+        let source = std::fs::read_to_string(fname)
+            .map_err(|e| format!("Cannot read policy script {}: {}", fname, e))?;
+        PolicyScript::from_source(&source).map_err(|e| format!("{}: {}", fname, e).into())
+    }
+    pub fn from_source(source: &str) -> BoxResult<Self> {
+        let deadline = Arc::new(Mutex::new(Instant::now()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(32);
+        engine.set_max_expr_depths(64, 32);
+        engine.set_max_string_size(10_000);
+        engine.set_max_array_size(10_000);
+        engine.set_max_map_size(10_000);
+        engine.on_print(|s| info!(target: "policy", "{}", s));
+        engine.on_debug(|s, _, _| debug!(target: "policy", "{}", s));
+        let progress_deadline = deadline.clone();
+        engine.on_progress(move |_| {
+            if Instant::now() > *progress_deadline.lock().unwrap() {
+                Some("time limit exceeded".into())
+            } else {
+                None
+            }
+        });
+        let ast = engine
+            .compile(source)
+            .map_err(|e| format!("Invalid policy script: {}", e))?;
+
+        let mut hooks = HashSet::new();
+        for f in ast.iter_functions() {
+            if let Some((name, params)) = HOOKS.iter().find(|(name, _)| *name == f.name) {
+                if f.params.len() != *params {
+                    return Err(format!(
+                        "{} of the policy script needs {} parameters",
+                        name, params
+                    )
+                    .into());
+                }
+                hooks.insert(*name);
+            }
+        }
+        if hooks.is_empty() {
+            return strerror(
+                "The policy script defines none of accept_peer, allow_route and select_gateway",
+            );
+        }
+        Ok(PolicyScript {
+            engine,
+            ast,
+            hooks,
+            deadline,
+        })
+    }
+    pub fn has_hook(&self, name: &str) -> bool {
+        self.hooks.contains(name)
+    }
+    // None for an undefined hook or a failed call
+    fn call(&self, name: &str, args: impl FuncArgs) -> Option<Dynamic> {
+        if !self.has_hook(name) {
+            return None;
+        }
+        *self.deadline.lock().unwrap() = Instant::now() + Duration::from_millis(TIME_LIMIT_MS);
+        match self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, name, args)
+        {
+            Ok(result) => Some(result),
+            Err(e) => {
+                warn!(target: "policy", "{} of the policy script failed: {}", name, e);
+                None
+            }
+        }
+    }
+    fn call_bool(&self, name: &str, args: impl FuncArgs) -> bool {
+        match self.call(name, args).map(|result| result.as_bool()) {
+            Some(Ok(decision)) => decision,
+            Some(Err(type_name)) => {
+                warn!(target: "policy", "{} of the policy script returned {} instead of bool", name, type_name);
+                true
+            }
+            None => true,
+        }
+    }
+    pub fn accept_peer(&self, advertisement: &AdvertisementPacket, src_addr: SocketAddr) -> bool {
+        if !self.has_hook("accept_peer") {
+            return true;
+        }
+        let mut peer = Map::new();
+        peer.insert("wg_ip".into(), advertisement.wg_ip.to_string().into());
+        peer.insert("name".into(), advertisement.name.clone().into());
+        peer.insert(
+            "public_key".into(),
+            advertisement.public_key.key.clone().into(),
+        );
+        peer.insert(
+            "identity".into(),
+            base64::encode(advertisement.identity).into(),
+        );
+        peer.insert("src_addr".into(), src_addr.to_string().into());
+        peer.insert(
+            "exported_nets".into(),
+            nets_array(&advertisement.exported_nets),
+        );
+        self.call_bool("accept_peer", (peer,))
+    }
+    pub fn allow_route(&self, route: &RouteInfo) -> bool {
+        if !self.has_hook("allow_route") {
+            return true;
+        }
+        self.call_bool("allow_route", (route_map(route),))
+    }
+    // A gateway, which is none of the candidates, is ignored
+    pub fn select_gateway(&self, to: IpAddr, candidates: &[RouteInfo]) -> Option<IpAddr> {
+        if !self.has_hook("select_gateway") {
+            return None;
+        }
+        let array = candidates
+            .iter()
+            .map(|route| route_map(route).into())
+            .collect::<Array>();
+        let result = self.call("select_gateway", (to.to_string(), array))?;
+        if result.is_unit() {
+            return None;
+        }
+        let gateway = result
+            .into_string()
+            .ok()
+            .and_then(|gateway| gateway.parse::<IpAddr>().ok())
+            .filter(|gateway| candidates.iter().any(|ri| ri.gateway == Some(*gateway)));
+        if gateway.is_none() {
+            warn!(target: "policy", "select_gateway of the policy script returned no candidate for {}", to);
+        }
+        gateway
+    }
+}
@@ -0,0 +1,87 @@
+// Strict separation of the admin port and the wireguard port of the peers.
+//
+// Both ports are advertised and observed side by side, so a mix-up results in a
+// wireguard endpoint pointing at the admin port of a node, where no handshake will
+// ever succeed. The advertised ports are cross-checked with the observed behavior:
+//
+//      admin:      the source of a decrypted admin packet, e.g. a probe reply
+//      wireguard:  the endpoint of a peer with a recent handshake, as per wireguard
+//
+// A wireguard endpoint is refused, if it has been observed as admin endpoint. An
+// endpoint on the advertised admin port of the node is refused as well, unless a
+// handshake has confirmed it. The latest observation of an endpoint wins.
+//
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+// Observations are forgotten after this long
+pub const OBSERVATION_TIMEOUT: u64 = 600;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Refusal {
+    ObservedAdminEndpoint,
+    AdvertisedAdminPort,
+}
+impl Refusal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Refusal::ObservedAdminEndpoint => "observed as admin endpoint",
+            Refusal::AdvertisedAdminPort => "advertised admin port",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PortValidation {
+    // Endpoint to time of the last observation
+    admin: HashMap<SocketAddr, u64>,
+    wireguard: HashMap<SocketAddr, u64>,
+}
+impl PortValidation {
+    pub fn observed_admin(&mut self, now: u64, src_addr: SocketAddr) {
+        self.wireguard.remove(&src_addr);
+        self.admin.insert(src_addr, now);
+    }
+    pub fn confirmed_wireguard(&mut self, now: u64, endpoint: SocketAddr) {
+        self.admin.remove(&endpoint);
+        self.wireguard.insert(endpoint, now);
+    }
+    pub fn expire(&mut self, now: u64) {
+        self.admin
+            .retain(|_, seen| now < *seen + OBSERVATION_TIMEOUT);
+        self.wireguard
+            .retain(|_, seen| now < *seen + OBSERVATION_TIMEOUT);
+    }
+    // Check a wireguard endpoint of a node with the given admin port
+    pub fn check(&self, endpoint: &SocketAddr, admin_port: u16) -> Result<(), Refusal> {
+        if self.admin.contains_key(endpoint) {
+            return Err(Refusal::ObservedAdminEndpoint);
+        }
+        if endpoint.port() == admin_port && !self.wireguard.contains_key(endpoint) {
+            return Err(Refusal::AdvertisedAdminPort);
+        }
+        Ok(())
+    }
+    // Drop the refused endpoint from the wireguard configuration lines of a peer
+    pub fn retain_endpoints(&self, wg_ip: &IpAddr, admin_port: u16, lines: &mut Vec<String>) {
+        lines.retain(|line| {
+            let Some(Ok(endpoint)) = line
+                .strip_prefix("EndPoint = ")
+                .map(|endpoint| endpoint.parse::<SocketAddr>())
+            else {
+                return true;
+            };
+            match self.check(&endpoint, admin_port) {
+                Ok(()) => true,
+                Err(refusal) => {
+                    warn!(target: "configuration", "Refuse endpoint {} of {}: {}", endpoint, wg_ip, refusal.as_str());
+                    false
+                }
+            }
+        });
+    }
+}
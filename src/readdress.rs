@@ -0,0 +1,56 @@
+// Change of the own wg_ip without restart.
+//
+// A changed wgIp in peer.yaml is applied on reload. The node uses the new wg_ip at once
+// and names the previous one as moved_from in its advertisements for READDRESS_GRACE
+// seconds. A peer, which receives such an advertisement signed by the identity of the
+// previous wg_ip, forgets the previous node right away. During the grace period, it still
+// accepts the previous address from the moved node, and the interface keeps it as host
+// address. So connections to the previous address are not cut immediately.
+//
+use std::net::IpAddr;
+
+use crate::configuration::{check_wg_ip, StaticConfiguration};
+use crate::error::*;
+
+// Longer than the ping interval of the peers, so every peer sees the advertisement
+pub const READDRESS_GRACE: u64 = 120;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreviousWgIp {
+    pub wg_ip: IpAddr,
+    // End of the grace period
+    pub until: u64,
+}
+
+// Returns the previous wg_ip
+pub fn readdress(
+    now: u64,
+    static_config: &mut StaticConfiguration,
+    wg_ip: IpAddr,
+) -> BoxResult<IpAddr> {
+    check_wg_ip(&static_config.subnet, &wg_ip)?;
+    if static_config.disable_ipv6 && wg_ip.is_ipv6() {
+        return strerror("An ipv6 wg_ip needs ipv6");
+    }
+    if static_config.previous_wg_ip.is_some() {
+        return strerror("The previous change of the wg_ip is still in its grace period");
+    }
+    let previous = static_config.wg_ip;
+    static_config.wg_ip = wg_ip;
+    static_config.previous_wg_ip = Some(PreviousWgIp {
+        wg_ip: previous,
+        until: now + READDRESS_GRACE,
+    });
+    Ok(previous)
+}
+
+// Called every second. Returns the previous wg_ip at the end of the grace period.
+pub fn grace_expired(now: u64, static_config: &mut StaticConfiguration) -> Option<IpAddr> {
+    match static_config.previous_wg_ip.as_ref() {
+        Some(previous) if now >= previous.until => static_config
+            .previous_wg_ip
+            .take()
+            .map(|previous| previous.wg_ip),
+        _ => None,
+    }
+}
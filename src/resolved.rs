@@ -0,0 +1,85 @@
+// Per-link DNS of the wireguard interface via systemd-resolved.
+//
+// The dns servers of network.yaml are set for the wireguard link with SetLinkDNS.
+// With domains, SetLinkDomains marks them as routing-only domains and the link is no
+// default route for DNS. So only names of the mesh are resolved via the mesh, and
+// resolv.conf is not touched. On shutdown, the link is reverted with RevertLink.
+//
+// systemd-resolved authorizes the calls by the credentials, which the bus has recorded
+// when connecting. So the connection is opened as root before dropping privileges,
+// and the link is registered and reverted by the user.
+//
+use std::net::IpAddr;
+
+use log::*;
+use zbus::blocking::Connection;
+
+use crate::configuration::MeshDns;
+use crate::error::*;
+
+const RESOLVED: &str = "org.freedesktop.resolve1";
+const RESOLVED_PATH: &str = "/org/freedesktop/resolve1";
+const RESOLVED_MANAGER: &str = "org.freedesktop.resolve1.Manager";
+
+const AF_INET: i32 = 2;
+const AF_INET6: i32 = 10;
+
+pub struct ResolvedLink {
+    connection: Connection,
+    ifindex: i32,
+}
+impl ResolvedLink {
+    pub fn connect() -> BoxResult<Connection> {
+        Ok(Connection::system()?)
+    }
+    // The interface needs to exist
+    pub fn register(connection: Connection, device_name: &str, dns: &MeshDns) -> BoxResult<Self> {
+        let ifindex = nix::net::if_::if_nametoindex(device_name)? as i32;
+        let link = ResolvedLink {
+            connection,
+            ifindex,
+        };
+        let servers = dns.servers.iter().map(link_address).collect::<Vec<_>>();
+        link.call("SetLinkDNS", &(ifindex, servers))?;
+        if !dns.domains.is_empty() {
+            link.call("SetLinkDomains", &(ifindex, link_domains(&dns.domains)))?;
+            link.call("SetLinkDefaultRoute", &(ifindex, false))?;
+        }
+        info!(target: "dns", "Registered {:?} for {:?} with systemd-resolved", dns.servers, dns.domains);
+        Ok(link)
+    }
+    pub fn revert(&self) -> BoxResult<()> {
+        self.call("RevertLink", &self.ifindex)?;
+        info!(target: "dns", "Reverted dns of the wireguard interface");
+        Ok(())
+    }
+    fn call<B>(&self, method: &str, body: &B) -> BoxResult<()>
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        self.connection.call_method(
+            Some(RESOLVED),
+            RESOLVED_PATH,
+            Some(RESOLVED_MANAGER),
+            method,
+            body,
+        )?;
+        Ok(())
+    }
+}
+
+// Address family and the address bytes as expected by SetLinkDNS
+pub fn link_address(ip: &IpAddr) -> (i32, Vec<u8>) {
+    match ip {
+        IpAddr::V4(ip) => (AF_INET, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (AF_INET6, ip.octets().to_vec()),
+    }
+}
+
+// Domains as routing-only domains for SetLinkDomains
+pub fn link_domains(domains: &[String]) -> Vec<(String, bool)> {
+    domains
+        .iter()
+        .map(|domain| (domain.clone(), true))
+        .collect()
+}
@@ -0,0 +1,167 @@
+// The per-peer phase of the route computation.
+//
+// The routes offered by each direct peer are turned into candidates for the own
+// routes. This needs only the route database of the peer and a read-only context, so
+// for large meshes the peers are processed in parallel via rayon. The candidates are
+// returned in the order of the peers, and the manager merges them sequentially. So
+// the resulting routes are the same as without parallel processing.
+//
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use log::*;
+use rayon::prelude::*;
+
+use crate::configuration::{area_of, nets_overlap, RouteMetric};
+use crate::routedb::RouteInfo;
+
+// Below this number of offered routes, the thread pool is not worth it
+pub const PARALLEL_MIN_ROUTES: usize = 2000;
+
+// Order of two paths according to the metric. Lower is better.
+pub fn path_rank(metric: RouteMetric, hop_cnt: usize, cost: u32) -> (u64, u64) {
+    match metric {
+        RouteMetric::Hops => (hop_cnt as u64, cost as u64),
+        RouteMetric::Cost => (cost as u64, hop_cnt as u64),
+    }
+}
+
+pub fn acceptable_nets(nets: &[IpNet], subnet: &IpNet, my_exported_nets: &[IpNet]) -> Vec<IpNet> {
+    nets.iter()
+        .map(|net| net.trunc())
+        .filter(|net| {
+            let ok = !nets_overlap(net, subnet) && !my_exported_nets.contains(net);
+            if !ok {
+                debug!(target: "routing", "Exported network {} is not acceptable => ignored", net);
+            }
+            ok
+        })
+        .collect()
+}
+
+pub struct RouteContext<'a> {
+    pub wg_ip: IpAddr,
+    pub metric: RouteMetric,
+    // Only set for a node within an area, which is no area border node
+    pub my_area: Option<u16>,
+    pub use_areas: bool,
+    pub areas: &'a BTreeMap<u16, IpNet>,
+    pub subnet: IpNet,
+    pub my_exported_nets: &'a [IpNet],
+    // Only distant nodes are accepted as gateway of a peer
+    pub distant_nodes: HashSet<IpAddr>,
+}
+
+pub struct PeerRoutes<'a> {
+    pub wg_ip: IpAddr,
+    // The link to the peer is the first part of all its paths
+    pub link_cost: u32,
+    pub link_bandwidth: Option<u32>,
+    pub tier: u8,
+    pub route_for: &'a HashMap<IpAddr, RouteInfo>,
+}
+impl PeerRoutes<'_> {
+    pub fn len(&self) -> usize {
+        self.route_for.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.route_for.is_empty()
+    }
+}
+
+// Rank of the path to an area, the tier of the gateway and the gateway
+pub type AreaRank = ((u64, u64), u8, IpAddr);
+
+#[derive(Debug, PartialEq)]
+pub enum Candidate<'a> {
+    // A path via the peer. offered is the entry of the peer's route database.
+    Route {
+        route: RouteInfo,
+        offered: &'a RouteInfo,
+    },
+    // The summary route of another area via the peer
+    Area {
+        area: u16,
+        rank: AreaRank,
+    },
+}
+
+pub fn candidates<'a>(ctx: &RouteContext, peer: &PeerRoutes<'a>) -> Vec<Candidate<'a>> {
+    let mut candidates = vec![];
+    for ri in peer.route_for.values() {
+        let cost = peer.link_cost.saturating_add(ri.cost);
+        if let Some(area) = ri.area_summary {
+            if ctx.use_areas && Some(area) != ctx.my_area && ctx.areas.contains_key(&area) {
+                let rank = (
+                    path_rank(ctx.metric, ri.hop_cnt + 1, cost),
+                    peer.tier,
+                    peer.wg_ip,
+                );
+                candidates.push(Candidate::Area { area, rank });
+            }
+            continue;
+        }
+        if ctx.use_areas {
+            let area = area_of(ctx.areas, &ri.to);
+            if area.is_some() && area != ctx.my_area {
+                trace!(target: "routing", "Route to {} in another area => ignore", ri.to);
+                continue;
+            }
+        }
+        if ri.to == ctx.wg_ip {
+            trace!(target: "routing", "Route to myself => ignore");
+            continue;
+        }
+        let mut hop_cnt = 1;
+        if let Some(gateway) = ri.gateway.as_ref() {
+            // Ignore routes to myself as gateway
+            if *gateway == ctx.wg_ip {
+                trace!(target: "routing", "Route to myself as gateway => ignore");
+                continue;
+            }
+            if !ctx.distant_nodes.contains(gateway) {
+                trace!(target: "routing", "Route using any of my peers as gateway => ignore");
+                continue;
+            }
+
+            hop_cnt = ri.hop_cnt + 1;
+        }
+
+        // to-host can be reached via the peer
+        trace!(target: "routing", "Include to routes: {} via {:?} and hop_cnt {}", ri.to, peer.wg_ip, hop_cnt);
+        let bandwidth_mbit = match (peer.link_bandwidth, ri.bandwidth_mbit) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let route = RouteInfo {
+            to: ri.to,
+            local_admin_port: ri.local_admin_port,
+            hop_cnt,
+            gateway: Some(peer.wg_ip),
+            area_summary: None,
+            exported_nets: acceptable_nets(&ri.exported_nets, &ctx.subnet, ctx.my_exported_nets),
+            cost,
+            bandwidth_mbit,
+        };
+        candidates.push(Candidate::Route { route, offered: ri });
+    }
+    candidates
+}
+
+// The candidates per peer in the order of peers
+pub fn all_candidates<'a>(
+    ctx: &RouteContext,
+    peers: &[PeerRoutes<'a>],
+    parallel: bool,
+) -> Vec<Vec<Candidate<'a>>> {
+    if parallel {
+        peers.par_iter().map(|peer| candidates(ctx, peer)).collect()
+    } else {
+        peers.iter().map(|peer| candidates(ctx, peer)).collect()
+    }
+}
+
+pub fn use_parallel(peers: &[PeerRoutes]) -> bool {
+    peers.len() > 1 && peers.iter().map(|peer| peer.len()).sum::<usize>() >= PARALLEL_MIN_ROUTES
+}
@@ -1,31 +1,61 @@
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 
+use ipnet::IpNet;
 use log::*;
 use serde::{Deserialize, Serialize};
 
-use crate::crypt_udp::RouteDatabasePacket;
+use crate::crypt_udp::{RouteDatabaseDeltaPacket, RouteDatabasePacket};
 use crate::event::Event;
+use crate::state_snapshot::RouteDBState;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct RouteInfo {
-    pub to: Ipv4Addr,
+    pub to: IpAddr,
     pub local_admin_port: u16,
     pub hop_cnt: usize,
-    pub gateway: Option<Ipv4Addr>,
+    pub gateway: Option<IpAddr>,
+    // Set, if all nodes of this area are reached via the sender. Then `to` is the
+    // network address of the area.
+    #[serde(default)]
+    pub area_summary: Option<u16>,
+    // Networks behind `to`, e.g. the LAN of a site gateway
+    #[serde(default)]
+    pub exported_nets: Vec<IpNet>,
+    // Cost of the path from the sender to `to`, see RoutingPolicy::link_cost
+    #[serde(default)]
+    pub cost: u32,
+    // Lowest configured bandwidth along the path
+    #[serde(default)]
+    pub bandwidth_mbit: Option<u32>,
 }
 
 #[derive(Default, Debug)]
 pub struct PeerRouteDB {
     pub version: usize,
     nr_entries: usize,
-    pub route_for: HashMap<Ipv4Addr, RouteInfo>,
+    pub route_for: HashMap<IpAddr, RouteInfo>,
+}
+
+// A route database, which is received in chunks
+#[derive(Debug)]
+struct IncomingRouteDB {
+    db: PeerRouteDB,
+    // Flags of the received chunks
+    received: Vec<bool>,
+}
+impl IncomingRouteDB {
+    fn missing_chunks(&self) -> Vec<usize> {
+        (0..self.received.len())
+            .filter(|chunk| !self.received[*chunk])
+            .collect()
+    }
 }
 
 #[derive(Default, Debug)]
 pub struct RouteDBManager {
     pub routedb: Option<PeerRouteDB>,
-    incoming_routedb: Option<PeerRouteDB>,
+    incoming_routedb: Option<IncomingRouteDB>,
     latest_routedb_version: Option<usize>,
 }
 impl RouteDBManager {
@@ -36,68 +66,115 @@ impl RouteDBManager {
     pub fn latest_version(&mut self, version: usize) {
         self.latest_routedb_version = Some(version);
     }
+    // Only a complete database is part of the state
+    pub fn state(&self) -> Option<RouteDBState> {
+        self.routedb.as_ref().map(|db| RouteDBState {
+            version: db.version,
+            routes: db.route_for.values().cloned().collect(),
+        })
+    }
+    pub fn from_state(state: Option<&RouteDBState>) -> Self {
+        let mut mgr = RouteDBManager::default();
+        if let Some(state) = state {
+            mgr.routedb = Some(PeerRouteDB {
+                version: state.version,
+                nr_entries: state.routes.len(),
+                route_for: state.routes.iter().map(|ri| (ri.to, ri.clone())).collect(),
+            });
+            mgr.latest_routedb_version = Some(state.version);
+        }
+        mgr
+    }
     pub fn invalidate(&mut self) {
         self.routedb = None;
         self.incoming_routedb = None;
         self.latest_routedb_version = None;
     }
     pub fn process_route_database(&mut self, req: RouteDatabasePacket) -> Vec<Event> {
-        let mut need_routes_update = false;
-        let mut events = vec![];
         debug!(target: "routing", "RouteDatabase: {:#?}", req.known_routes);
+        if req.chunk >= req.nr_chunks {
+            warn!(target: "routing", "Invalid route db chunk {}/{} => dropped", req.chunk, req.nr_chunks);
+            return vec![];
+        }
 
-        // The database will be received in one to many udp packages.
-        //
-        if let Some(mut incoming_routedb) = self.incoming_routedb.take() {
-            // route database exist and must not be complete
-            //
-            if req.nr_entries != incoming_routedb.nr_entries {
-                if req.routedb_version == incoming_routedb.version {
-                    // All ok, got more entries for the database
-                    for ri in req.known_routes {
-                        incoming_routedb.route_for.insert(ri.to, ri);
-                    }
-
-                    // Check, if the database is complete
-                    if req.nr_entries == incoming_routedb.route_for.len() {
-                        need_routes_update = true;
-                        self.routedb = Some(incoming_routedb);
-                    } else {
-                        // put back the route_db into the incoming_routedb
-                        self.incoming_routedb = Some(incoming_routedb);
-                    }
-                } else {
-                    // Received packet with wrong version info
-                    warn!(target: "routing", "Mismatch of route db version, so partial db is dropped");
+        // The database will be received in one to many udp packages, which may arrive
+        // in any order.
+        let mut incoming = match self.incoming_routedb.take() {
+            Some(incoming)
+                if incoming.db.version == req.routedb_version
+                    && incoming.db.nr_entries == req.nr_entries
+                    && incoming.received.len() == req.nr_chunks =>
+            {
+                incoming
+            }
+            other => {
+                if let Some(lost) = other {
+                    // A chunk of another database, so the missing chunks are lost
+                    warn!(target: "routing", "Chunks {:?} of route db version {} are lost, so partial db is dropped",
+                        lost.missing_chunks(), lost.db.version);
+                }
+                IncomingRouteDB {
+                    db: PeerRouteDB {
+                        version: req.routedb_version,
+                        nr_entries: req.nr_entries,
+                        route_for: HashMap::new(),
+                    },
+                    received: vec![false; req.nr_chunks],
                 }
-            } else {
-                warn!(target: "routing", "Another packet for complete database received, so strange db is dropped");
             }
+        };
+        if incoming.received[req.chunk] {
+            debug!(target: "routing", "Duplicate route db chunk {}/{}", req.chunk, req.nr_chunks);
         } else {
-            // First packet of a new route_db. So just store it
-            let routes = req
-                .known_routes
-                .iter()
-                .map(|e| (e.to, e.clone()))
-                .collect::<HashMap<Ipv4Addr, RouteInfo>>();
-            let incoming_routedb = PeerRouteDB {
-                version: req.routedb_version,
-                nr_entries: req.nr_entries,
-                route_for: routes,
-            };
+            incoming.received[req.chunk] = true;
+            for ri in req.known_routes {
+                incoming.db.route_for.insert(ri.to, ri);
+            }
+        }
 
-            // Perhaps the database is already complete ?
-            if req.nr_entries == incoming_routedb.route_for.len() {
-                need_routes_update = true;
-                self.routedb = Some(incoming_routedb);
-            } else {
-                // put back the route_db into the incoming_routedb
-                self.incoming_routedb = Some(incoming_routedb);
+        if !incoming.missing_chunks().is_empty() {
+            self.incoming_routedb = Some(incoming);
+            return vec![];
+        }
+        if incoming.db.route_for.len() != incoming.db.nr_entries {
+            warn!(target: "routing", "Route db has {} instead of {} entries, so db is dropped",
+                incoming.db.route_for.len(), incoming.db.nr_entries);
+            return vec![];
+        }
+        self.routedb = Some(incoming.db);
+        vec![Event::UpdateRoutes]
+    }
+    // Chunks of the database being received, which are still missing
+    pub fn missing_chunks(&self) -> Vec<usize> {
+        self.incoming_routedb
+            .as_ref()
+            .map(|incoming| incoming.missing_chunks())
+            .unwrap_or_default()
+    }
+    // A delta is only applied to the complete database of its base version. Otherwise
+    // it is dropped and the next request asks for the changes since the own version.
+    // If the result is inconsistent, the next request is for the full database.
+    pub fn process_route_database_delta(&mut self, delta: RouteDatabaseDeltaPacket) -> Vec<Event> {
+        let routedb = match self.routedb.as_mut() {
+            Some(routedb) if routedb.version == delta.base_version => routedb,
+            _ => {
+                warn!(target: "routing", "Route db delta for another version {} => dropped", delta.base_version);
+                return vec![];
             }
+        };
+        for to in delta.removed_routes.iter() {
+            routedb.route_for.remove(to);
+        }
+        for ri in delta.changed_routes {
+            routedb.route_for.insert(ri.to, ri);
         }
-        if need_routes_update {
-            events.push(Event::UpdateRoutes);
+        if routedb.route_for.len() != delta.nr_entries {
+            warn!(target: "routing", "Route db is inconsistent after delta, so db is dropped");
+            self.routedb = None;
+            return vec![];
         }
-        events
+        routedb.version = delta.routedb_version;
+        routedb.nr_entries = delta.nr_entries;
+        vec![Event::UpdateRoutes]
     }
 }
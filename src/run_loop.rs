@@ -1,27 +1,100 @@
-use std::net::{IpAddr, SocketAddr, SocketAddrV4};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::time;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
 use log::*;
+use tokio::sync::watch;
 
+use crate::address_lease::{apply_lease, renewal_due, renewal_request};
+use crate::alert::AlertManager;
 use crate::arch_def::Architecture;
+#[cfg(feature = "chaos")]
+use crate::chaos::*;
 use crate::configuration::*;
+use crate::crypt_udp::AsyncCryptUdp;
 use crate::crypt_udp::CryptUdp;
+use crate::crypt_udp::DecryptError;
+use crate::crypt_udp::ReplayError;
 use crate::crypt_udp::UdpPacket;
+use crate::crypt_udp::MAX_PACKET_SIZE;
 use crate::error::*;
-use crate::event::Event;
+use crate::event::{channel, Event, EventReceiver, EventSender};
+use crate::fleet::FleetGuard;
+use crate::ha_pair::{HaCoordinator, HaState};
+use crate::identity::*;
+use crate::key_rotation::*;
+use crate::log_sampling::sampled;
 use crate::manager::*;
-use crate::tui_display::TuiApp;
+use crate::metrics::MetricsExporter;
+use crate::name_server::{HostsFile, MeshNames, NameServer, DNS_PORT};
+use crate::peer_names::{PeerNames, PeerNamesFile};
+#[cfg(feature = "scripting")]
+use crate::policy_script::PolicyScript;
+use crate::readdress::grace_expired;
+use crate::state_file::StateFile;
+use crate::state_snapshot::StateSnapshot;
+use crate::status_file::StatusFile;
+use crate::status_page::StatusPage;
+use crate::tui_display::{PeerRow, TuiApp};
+#[cfg(feature = "webhook")]
+use crate::webhook::*;
 use crate::wg_dev::*;
 use crate::Arch;
 
-pub fn run(
-    static_config: &StaticConfiguration,
-    mut wg_dev: Box<dyn WireguardDevice>,
-) -> BoxResult<()> {
-    let (tx, rx) = channel();
+struct AdminSockets {
+    crypt_socket_v4: CryptUdp,
+    crypt_socket_v6: CryptUdp,
+    // The receiving sides, which are moved into the socket tasks
+    receivers: Vec<AsyncCryptUdp>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<Chaos>,
+    // The dns responder is bound before dropping privileges, too
+    name_server: Option<NameServer>,
+    status_page: Option<StatusPage>,
+    // The standby node of a hub pair sends only heartbeats
+    muted: bool,
+}
+impl AdminSockets {
+    fn raw_send_to(&mut self, buf: &[u8], destination: SocketAddr) {
+        if destination.is_ipv4() {
+            self.crypt_socket_v4.send_to(buf, destination).ok();
+        } else {
+            self.crypt_socket_v6.send_to(buf, destination).ok();
+        }
+    }
+    fn send_to(&mut self, buf: &[u8], destination: SocketAddr) {
+        if self.muted {
+            return;
+        }
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = self.chaos.as_mut() {
+            match chaos.packet_action(crate::util::now()) {
+                ChaosAction::Send => {}
+                ChaosAction::Drop => return,
+                ChaosAction::Duplicate => self.raw_send_to(buf, destination),
+                ChaosAction::Delay(delay) => {
+                    let socket = if destination.is_ipv4() {
+                        &self.crypt_socket_v4
+                    } else {
+                        &self.crypt_socket_v6
+                    };
+                    if let Ok(mut socket) = socket.try_clone() {
+                        let buf = buf.to_vec();
+                        std::thread::spawn(move || {
+                            std::thread::sleep(delay);
+                            socket.send_to(&buf, destination).ok();
+                        });
+                    }
+                    return;
+                }
+            }
+        }
+        self.raw_send_to(buf, destination);
+    }
+}
 
-    Arch::arch_specific_init(tx.clone());
+pub fn run(static_config: &StaticConfiguration, wg_dev: Box<dyn WireguardDevice>) -> BoxResult<()> {
+    let (tx, rx) = channel();
 
     let tx_handler = tx.clone();
     ctrlc::set_handler(move || {
@@ -32,33 +105,96 @@ pub fn run(
     })
     .expect("Error setting Ctrl-C handler");
 
+    run_with_channel(static_config, wg_dev, tx, rx)
+}
+
+// Like run(), but without taking over the process' signal handling.
+// The loop is terminated by sending Event::CtrlC via tx.
+pub fn run_with_channel(
+    static_config: &StaticConfiguration,
+    mut wg_dev: Box<dyn WireguardDevice>,
+    tx: EventSender,
+    rx: EventReceiver,
+) -> BoxResult<()> {
+    // Single threaded: the wireguard device is used by the main loop only, and while
+    // running via the DeviceWorker. The runtime is entered already here, because the
+    // sockets are registered on creation.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let _runtime_guard = runtime.enter();
+
+    Arch::arch_specific_init(tx.clone());
+
     let port = static_config.my_admin_port();
 
-    let (v4_socket_first, need_v4_socket, need_v6_socket) = Arch::ipv4v6_socket_setup();
+    let (v4_socket_first, need_v4_socket, need_v6_socket) = if static_config.disable_ipv6 {
+        (true, true, false)
+    } else {
+        Arch::ipv4v6_socket_setup()
+    };
 
     let mut opt_crypt_socket_v6 = None;
     let mut opt_crypt_socket_v4 = None;
 
-    if need_v4_socket && v4_socket_first {
-        debug!("bind to 0.0.0.0:{}", port);
-        opt_crypt_socket_v4 = Some(
-            CryptUdp::bind(IpAddr::V4("0.0.0.0".parse().unwrap()), port)?
-                .key(&static_config.shared_key)?,
-        );
-    }
-    if need_v6_socket {
-        debug!("bind to :::{}", port);
-        opt_crypt_socket_v6 = Some(
-            CryptUdp::bind(IpAddr::V6("::".parse().unwrap()), port)?
-                .key(&static_config.shared_key)?,
-        );
-    }
-    if need_v4_socket && !v4_socket_first {
-        debug!("bind to 0.0.0.0:{}", port);
-        opt_crypt_socket_v4 = Some(
-            CryptUdp::bind(IpAddr::V4("0.0.0.0".parse().unwrap()), port)?
-                .key(&static_config.shared_key)?,
-        );
+    // On takeover, continue with the sockets and the wireguard key of the previous instance
+    let opt_snapshot = match take_over(static_config)? {
+        Some((socket_v4, socket_v6, snapshot)) => {
+            opt_crypt_socket_v4 = Some(
+                socket_v4
+                    .key(&static_config.shared_key)?
+                    .accepted_keys(&static_config.accepted_shared_keys)?,
+            );
+            opt_crypt_socket_v6 = Some(
+                socket_v6
+                    .key(&static_config.shared_key)?
+                    .accepted_keys(&static_config.accepted_shared_keys)?,
+            );
+            Some(snapshot)
+        }
+        None => None,
+    };
+    let config_of_previous_instance;
+    let static_config = match opt_snapshot.as_ref() {
+        Some(snapshot) => {
+            let mut config = static_config.clone();
+            config.my_private_key = snapshot.my_private_key.clone();
+            config.my_public_key = snapshot.my_public_key.clone();
+            config.pending_key_pair = snapshot.pending_key_pair.clone();
+            if let Some(seed) = snapshot.identity_seed.as_ref() {
+                config.identity = NodeIdentity::from_seed(&decode_identity(seed)?);
+            }
+            config_of_previous_instance = config;
+            &config_of_previous_instance
+        }
+        None => static_config,
+    };
+
+    if opt_snapshot.is_none() {
+        if need_v4_socket && v4_socket_first {
+            debug!("bind to 0.0.0.0:{}", port);
+            opt_crypt_socket_v4 = Some(
+                CryptUdp::bind(IpAddr::V4("0.0.0.0".parse().unwrap()), port)?
+                    .key(&static_config.shared_key)?
+                    .accepted_keys(&static_config.accepted_shared_keys)?,
+            );
+        }
+        if need_v6_socket {
+            debug!("bind to :::{}", port);
+            opt_crypt_socket_v6 = Some(
+                CryptUdp::bind(IpAddr::V6("::".parse().unwrap()), port)?
+                    .key(&static_config.shared_key)?
+                    .accepted_keys(&static_config.accepted_shared_keys)?,
+            );
+        }
+        if need_v4_socket && !v4_socket_first {
+            debug!("bind to 0.0.0.0:{}", port);
+            opt_crypt_socket_v4 = Some(
+                CryptUdp::bind(IpAddr::V4("0.0.0.0".parse().unwrap()), port)?
+                    .key(&static_config.shared_key)?
+                    .accepted_keys(&static_config.accepted_shared_keys)?,
+            );
+        }
     }
 
     if opt_crypt_socket_v4.is_none() {
@@ -68,325 +204,1191 @@ pub fn run(
         opt_crypt_socket_v6 = opt_crypt_socket_v4.as_ref().map(|s| s.try_clone().unwrap());
     }
 
-    let crypt_socket_v4 = opt_crypt_socket_v4.unwrap();
+    let mut crypt_socket_v4 = opt_crypt_socket_v4.unwrap();
     let crypt_socket_v6 = opt_crypt_socket_v6.unwrap();
+    // A packet captured on one ip version cannot be replayed via the other one
+    crypt_socket_v4.share_replay_filter(&crypt_socket_v6);
+    crypt_socket_v4.share_counters(&crypt_socket_v6);
 
-    // Set up udp receiver thread for ipv4
+    let mut receivers = vec![];
     if need_v4_socket {
-        let tx_clone = tx.clone();
-        let crypt_socket_v4_clone = crypt_socket_v4
-            .try_clone()
-            .expect("couldn't clone the crypt_socket");
-        std::thread::spawn(move || loop {
-            let mut buf = [0; 2000];
-            match crypt_socket_v4_clone.recv_from(&mut buf) {
-                Ok((received, src_addr)) => {
-                    info!("received {} bytes from {:?}", received, src_addr);
-                    match bincode::deserialize::<UdpPacket>(&buf[..received]) {
-                        Ok(udp_packet) => {
-                            tx_clone.send(Event::Udp(udp_packet, src_addr)).unwrap();
-                        }
-                        Err(e) => {
-                            error!("Error in decode: {:?}", e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("{:?}", e);
-                }
-            }
-        });
+        receivers.push(crypt_socket_v4.try_clone_async()?);
     }
-
-    // Set up udp receiver thread for ipv6
     if need_v6_socket {
-        let tx_clone = tx.clone();
-        let crypt_socket_v6_clone = crypt_socket_v6
-            .try_clone()
-            .expect("couldn't clone the crypt_socket");
-        std::thread::spawn(move || loop {
-            let mut buf = [0; 2000];
-            match crypt_socket_v6_clone.recv_from(&mut buf) {
-                Ok((received, src_addr)) => {
-                    info!("received {} bytes from {:?}", received, src_addr);
-                    match bincode::deserialize::<UdpPacket>(&buf[..received]) {
-                        Ok(udp_packet) => {
-                            tx_clone.send(Event::Udp(udp_packet, src_addr)).unwrap();
-                        }
-                        Err(e) => {
-                            error!("Error in decode: {:?}", e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("{:?}", e);
-                }
-            }
-        });
+        receivers.push(crypt_socket_v6.try_clone_async()?);
+    }
+
+    // The listeners on privileged ports and the connection to systemd-resolved are
+    // set up as root. Afterwards root is not needed anymore: the interface, routes and
+    // peers are set up via the pre-authorized sudo, or with keep_net_admin by the
+    // commands inheriting CAP_NET_ADMIN.
+    #[cfg(target_os = "linux")]
+    if let Some(path) = static_config.upgrade_socket.as_ref() {
+        if let Err(e) = crate::upgrade::listen(path, tx.clone()) {
+            warn!(target: "upgrade", "Binary upgrades are not possible: {}", e);
+        }
     }
 
-    // Set up timer tick
-    let tx_clone = tx.clone();
-    std::thread::spawn(move || {
-        let interval_1s = time::Duration::from_millis(1000);
-        loop {
-            tx_clone.send(Event::TimerTick1s).unwrap();
-            std::thread::sleep(interval_1s);
+    #[cfg(unix)]
+    if let Some(path) = static_config.control_socket.as_ref() {
+        let listening = crate::control::ControlAccess::of(static_config)
+            .and_then(|access| crate::control::listen(path, access, tx.clone()));
+        if let Err(e) = listening {
+            warn!(target: "control", "Control socket is not available: {}", e);
         }
+    }
+
+    #[cfg(feature = "mdns")]
+    if static_config.mdns {
+        if let Err(e) = crate::mdns::start(static_config, tx.clone()) {
+            warn!(target: "mdns", "mDNS is not available: {}", e);
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "resolved"))]
+    let resolved_connection = static_config.dns.as_ref().and_then(|_| {
+        crate::resolved::ResolvedLink::connect()
+            .map_err(|e| warn!(target: "dns", "Cannot connect to systemd-resolved: {}", e))
+            .ok()
+    });
+
+    let name_server_socket = static_config.mesh_domain.as_ref().and_then(|_| {
+        NameServer::bind(SocketAddr::new(static_config.wg_ip, DNS_PORT))
+            .map_err(|e| warn!(target: "dns", "Cannot serve the names of the mesh: {}", e))
+            .ok()
     });
 
-    // in case there are dangling routes
-    if !static_config.use_existing_interface {
-        wg_dev.take_down_device().ok();
+    if let Some(user) = static_config.drop_privileges_to.as_ref() {
+        Arch::drop_privileges(user, static_config.keep_net_admin)?;
+        if static_config.keep_net_admin {
+            info!("Dropped privileges to user {} keeping CAP_NET_ADMIN", user);
+        } else {
+            info!("Dropped privileges to user {}", user);
+        }
+    }
 
-        wg_dev.create_device()?;
+    let config_with_foreign_peers;
+    let static_config = if static_config.use_existing_interface && wg_dev.check_device()? {
+        let mut config = static_config.clone();
+        config.foreign_peers = adopt_foreign_peers(&config, &*wg_dev);
+        config_with_foreign_peers = config;
+        &config_with_foreign_peers
     } else {
-        wg_dev.flush_all()?;
+        static_config
+    };
+
+    if opt_snapshot.is_some() {
+        info!(target: "upgrade", "Continue with interface {} of previous instance", static_config.wg_name);
+    } else {
+        if wg_dev.check_device()? {
+            static_config.check_interface_owner(&wg_dev.altnames().unwrap_or_default())?;
+        }
+
+        // in case there are dangling routes
+        if !static_config.use_existing_interface {
+            wg_dev.take_down_device().ok();
+
+            wg_dev.create_device()?;
+        } else {
+            wg_dev.flush_all()?;
+            for net in static_config.foreign_nets() {
+                if let Err(e) = wg_dev.add_net_route(net) {
+                    warn!(target: "wireguard", "Cannot restore the route to {} of an adopted peer: {}", net, e);
+                }
+            }
+        }
+
+        wg_dev.set_ip(
+            &static_config.wg_ip,
+            &static_config.subnet,
+            static_config.nat_probe_net(),
+        )?;
+
+        if static_config.interface_altname {
+            if let Some(altname) = static_config.altname() {
+                if !wg_dev.altnames().unwrap_or_default().contains(&altname) {
+                    wg_dev.add_altname(&altname)?;
+                }
+            }
+        }
     }
 
-    wg_dev.set_ip(&static_config.wg_ip, &static_config.subnet)?;
+    #[cfg(all(target_os = "linux", feature = "resolved"))]
+    let resolved_link = resolved_connection
+        .zip(static_config.dns.as_ref())
+        .and_then(|(connection, dns)| {
+            crate::resolved::ResolvedLink::register(connection, &static_config.wg_name, dns)
+                .map_err(
+                    |e| warn!(target: "dns", "Cannot register dns with systemd-resolved: {}", e),
+                )
+                .ok()
+        });
+
+    let name_server = name_server_socket.and_then(|socket| {
+        NameServer::serve(socket)
+            .map_err(|e| warn!(target: "dns", "Cannot serve the names of the mesh: {}", e))
+            .ok()
+    });
+    let status_page = static_config.status_page_port.and_then(|port| {
+        StatusPage::listen(
+            SocketAddr::new(static_config.wg_ip, port),
+            static_config.http_token.clone(),
+        )
+        .map_err(|e| warn!(target: "status", "Cannot serve the status page: {}", e))
+        .ok()
+    });
 
     let mut tui_app = if static_config.use_tui {
         TuiApp::init(tx.clone())?
     } else {
         TuiApp::off()
     };
+    tui_app.set_title(format!(
+        "{} - network {} on {}",
+        static_config.name,
+        static_config.network_name.as_deref().unwrap_or("-"),
+        static_config.wg_name
+    ));
 
-    let rc = main_loop(
-        static_config,
-        &*wg_dev,
+    let admin_sockets = AdminSockets {
         crypt_socket_v4,
         crypt_socket_v6,
+        receivers,
+        #[cfg(feature = "chaos")]
+        chaos: static_config
+            .chaos_seed
+            .map(|seed| Chaos::new(ChaosConfig::with_seed(seed))),
+        name_server,
+        status_page,
+        muted: static_config.ha_pair.is_some(),
+    };
+
+    let device = DeviceWorker::start(wg_dev)?;
+    let rc = runtime.block_on(main_loop(
+        static_config,
+        &device,
+        admin_sockets,
         tx,
         rx,
         &mut tui_app,
-    );
+        opt_snapshot,
+    ));
+    let wg_dev = device.stop()?;
 
-    if !static_config.use_existing_interface {
-        wg_dev.take_down_device().ok();
+    // After a handover, the interface and the upgrade socket belong to the new instance
+    let handed_over = matches!(rc, Ok(None));
+    #[cfg(all(target_os = "linux", feature = "resolved"))]
+    if !handed_over {
+        if let Some(Err(e)) = resolved_link.as_ref().map(|link| link.revert()) {
+            warn!(target: "dns", "Cannot revert dns of the wireguard interface: {}", e);
+        }
+    }
+    if !handed_over {
+        let teardown = match rc.as_ref() {
+            Ok(Some(teardown)) => teardown.clone(),
+            _ => static_config.teardown(None),
+        };
+        teardown.execute(&*wg_dev);
+    }
+    #[cfg(target_os = "linux")]
+    if !handed_over {
+        if let Some(path) = static_config.upgrade_socket.as_ref() {
+            crate::upgrade::remove_socket(path);
+        }
+    }
+    #[cfg(unix)]
+    if !handed_over {
+        if let Some(path) = static_config.control_socket.as_ref() {
+            crate::control::remove_socket(path);
+        }
     }
 
     tui_app.deinit()?;
 
-    rc
+    rc.map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+fn take_over(
+    static_config: &StaticConfiguration,
+) -> BoxResult<Option<(CryptUdp, CryptUdp, StateSnapshot)>> {
+    if !static_config.takeover {
+        return Ok(None);
+    }
+    let path = static_config
+        .upgrade_socket
+        .as_ref()
+        .ok_or("Takeover needs an upgrade socket")?;
+    let handover = crate::upgrade::take_over(path, static_config)?;
+    info!(target: "upgrade", "Took over from instance at {}", path);
+    Ok(Some((
+        CryptUdp::from_socket(handover.socket_v4),
+        CryptUdp::from_socket(handover.socket_v6),
+        handover.snapshot,
+    )))
+}
+#[cfg(not(target_os = "linux"))]
+fn take_over(
+    static_config: &StaticConfiguration,
+) -> BoxResult<Option<(CryptUdp, CryptUdp, StateSnapshot)>> {
+    if static_config.takeover {
+        return strerror("Takeover is not supported on this platform");
+    }
+    Ok(None)
+}
+
+// Receives admin packets until shutdown
+async fn receive_udp(socket: AsyncCryptUdp, tx: EventSender, mut shutdown: watch::Receiver<bool>) {
+    let mut buf = [0; MAX_PACKET_SIZE];
+    loop {
+        let received = tokio::select! {
+            _ = shutdown.changed() => break,
+            received = socket.recv_from(&mut buf) => received,
+        };
+        match received {
+            Ok((received, src_addr)) => {
+                sampled!(Level::Info, target: "udp", "received {} bytes from {:?}", received, src_addr);
+                match UdpPacket::decode(&buf[..received]) {
+                    Ok(udp_packet) => {
+                        if tx.send(Event::Udp(udp_packet, src_addr)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error in decode: {:?}", e);
+                    }
+                }
+            }
+            Err(e) => {
+                if let Some(e) = e.downcast_ref::<DecryptError>() {
+                    if tx.send(Event::DecryptFailure(e.src_addr)).is_err() {
+                        break;
+                    }
+                } else if let Some(e) = e.downcast_ref::<ReplayError>() {
+                    warn!(target: "udp", "{}", e);
+                } else {
+                    error!("{:?}", e);
+                }
+            }
+        }
+    }
+}
+
+// For tasks, which are not bound to a node: tui, alerts, status file, ...
+async fn timer_tick(tx: EventSender, mut shutdown: watch::Receiver<bool>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            _ = interval.tick() => {
+                if tx.send(Event::TimerTick1s).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// The handshakes are unix times, move them onto the mesh clock
+fn latest_handshakes(wg_dev: &dyn WireguardDevice) -> BoxResult<HashMap<String, u64>> {
+    Ok(wg_dev
+        .latest_handshakes()?
+        .into_iter()
+        .map(|(key, at)| (key, crate::util::from_wall_time(at)))
+        .collect())
 }
 
-fn main_loop(
+// Completes, when the node scheduled at the given time of the mesh clock is due
+async fn node_timer(at: Option<u64>) {
+    match at {
+        Some(at) => {
+            let delay = Duration::from_millis((at * 1000).saturating_sub(crate::util::now_ms()));
+            tokio::time::sleep(delay).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// The peers of other tooling to keep in the first configuration, see --adopt-peers
+fn adopt_foreign_peers(
     static_config: &StaticConfiguration,
     wg_dev: &dyn WireguardDevice,
-    mut crypt_socket_v4: CryptUdp,
-    mut crypt_socket_v6: CryptUdp,
-    tx: Sender<Event>,
-    rx: Receiver<Event>,
+) -> Vec<WgPeerConf> {
+    let peers = match wg_dev.retrieve_peers() {
+        Ok(peers) => static_config.foreign_peers_of(peers),
+        Err(e) => {
+            warn!(target: "wireguard", "Cannot read the peers of {}: {}", static_config.wg_name, e);
+            return vec![];
+        }
+    };
+    if peers.is_empty() {
+        return peers;
+    }
+    let keys = peers
+        .iter()
+        .map(|peer| peer.key())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if static_config.adopt_peers {
+        info!(target: "wireguard", "Adopt {} peers of other tooling: {}", peers.len(), keys);
+        peers
+    } else {
+        warn!(target: "wireguard", "Remove {} peers of other tooling, use --adopt-peers to keep them: {}", peers.len(), keys);
+        vec![]
+    }
+}
+
+async fn main_loop(
+    initial_config: &StaticConfiguration,
+    device: &DeviceWorker,
+    mut admin_sockets: AdminSockets,
+    tx: EventSender,
+    rx: EventReceiver,
     tui_app: &mut TuiApp,
-) -> BoxResult<()> {
-    let mut network_manager = NetworkManager::new(static_config);
+    opt_snapshot: Option<StateSnapshot>,
+) -> BoxResult<Option<Teardown>> {
+    // The socket and timer tasks are ended, before the main loop returns
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut tasks = admin_sockets
+        .receivers
+        .drain(..)
+        .map(|socket| tokio::spawn(receive_udp(socket, tx.clone(), shutdown_rx.clone())))
+        .collect::<Vec<_>>();
+    tasks.push(tokio::spawn(timer_tick(tx.clone(), shutdown_rx)));
+
+    let rc = event_loop(
+        initial_config,
+        device,
+        admin_sockets,
+        tx,
+        rx,
+        tui_app,
+        opt_snapshot,
+    )
+    .await;
+
+    shutdown_tx.send(true).ok();
+    for task in tasks {
+        task.await.ok();
+    }
+    rc
+}
+
+async fn event_loop(
+    initial_config: &StaticConfiguration,
+    device: &DeviceWorker,
+    mut admin_sockets: AdminSockets,
+    tx: EventSender,
+    mut rx: EventReceiver,
+    tui_app: &mut TuiApp,
+    opt_snapshot: Option<StateSnapshot>,
+) -> BoxResult<Option<Teardown>> {
+    // The static peers may change on reload via the control socket
+    // and the own key pair on key rotation
+    let mut static_config = initial_config.clone();
+    let mut network_manager = NetworkManager::new(&static_config);
+    #[cfg(feature = "scripting")]
+    if let Some(fname) = static_config.policy_script.as_deref() {
+        network_manager.set_policy_script(Some(PolicyScript::load(fname)?));
+    }
+    let mesh_events = network_manager.subscribe();
+    let mut opt_state_file = static_config
+        .state_file
+        .as_deref()
+        .map(|path| StateFile::new(path, &static_config.shared_key));
+    if let Some(snapshot) = opt_snapshot {
+        network_manager.restore_state(crate::util::now(), &snapshot)?;
+    } else if let Some(snapshot) = opt_state_file.as_mut().and_then(|sf| sf.load()) {
+        if let Err(e) = network_manager.restore_hints(crate::util::now(), &snapshot) {
+            warn!(target: "state", "Ignore state file: {}", e);
+        }
+    }
+
+    let mut opt_status_file = static_config.status_file.as_deref().map(StatusFile::new);
+    let mut opt_hosts_file = static_config
+        .hosts_file
+        .as_deref()
+        .map(|path| HostsFile::new(path, &static_config.wg_name));
+    let mut opt_peer_names = static_config.peer_names.as_deref().map(PeerNamesFile::new);
+    let mut opt_metrics = match static_config.metrics_port {
+        Some(port) => Some(MetricsExporter::listen(
+            SocketAddr::new(
+                static_config.metrics_address.unwrap_or(static_config.wg_ip),
+                port,
+            ),
+            static_config.http_token.clone(),
+        )?),
+        None => None,
+    };
+    let mut advertisements_sent: u64 = 0;
+    let mut conf_sequencer = ConfSequencer::default();
+    let mut alert_manager = AlertManager::new();
+    let mut fleet_guard = FleetGuard::default();
+    #[cfg(feature = "webhook")]
+    let opt_notifier =
+        (!static_config.webhooks.is_empty()).then(|| WebhookNotifier::new(&static_config.webhooks));
 
     // set up initial wireguard configuration without peers
     tx.send(Event::UpdateWireguardConfiguration).unwrap();
 
+    let mut opt_ha = static_config
+        .ha_pair
+        .clone()
+        .map(|pair| HaCoordinator::new(crate::util::now(), pair));
+
+    #[cfg(target_os = "linux")]
+    let mut sd_notifier = crate::systemd::Notifier::from_env();
+    #[cfg(target_os = "linux")]
+    if let Some(notifier) = sd_notifier.as_ref() {
+        notifier.ready();
+    }
+
     let mut tick_cnt = 0;
+    let mut names_changed = true;
     loop {
-        let evt = rx.recv();
+        // Each node is processed at its own time instead of all nodes on every tick
+        let evt = tokio::select! {
+            evt = rx.recv() => evt,
+            _ = node_timer(network_manager.next_due()) => Some(Event::NodesDue),
+        };
         //trace!(target: "loop", "{:?}", evt);
         match evt {
-            Err(e) => {
-                error!("Receive error: {:?}", e);
+            None => {
+                error!("Event channel is closed");
                 break;
             }
-            Ok(Event::CtrlC) => {
+            Some(Event::CtrlC) => {
+                #[cfg(target_os = "linux")]
+                if let Some(notifier) = sd_notifier.as_ref() {
+                    notifier.stopping();
+                }
+                // The direct peers need not wait for the timeout
+                let buf = bincode::serialize(&UdpPacket::Goodbye).unwrap();
+                for destination in network_manager.direct_peer_admin_addresses() {
+                    admin_sockets.send_to(&buf, destination);
+                }
                 break;
             }
-            Ok(Event::TimerTick1s) => {
+            Some(Event::TimerTick1s) => {
+                if static_config.use_tui {
+                    tui_app.update_node_classes(network_manager.node_classes());
+                }
                 tui_app.draw()?;
 
                 if tick_cnt % 30 == 2 {
                     // every 30s
                     network_manager.stats();
+                    #[cfg(feature = "chaos")]
+                    if let Some(chaos) = admin_sockets.chaos.as_ref() {
+                        chaos.output_stats();
+                    }
                 }
 
                 let now = crate::util::now();
-                let events = network_manager.process_all_nodes_every_second(now, static_config);
+
+                // Only pinged as long as the main loop is processing events
+                #[cfg(target_os = "linux")]
+                if let Some(notifier) = sd_notifier.as_mut() {
+                    notifier.ping_watchdog(now);
+                }
+
+                #[cfg(feature = "chaos")]
+                if let Some(chaos) = admin_sockets.chaos.as_mut() {
+                    chaos.check_convergence(now);
+                    if chaos.restart_route_computation(now) {
+                        tx.send(Event::UpdateRoutes).unwrap();
+                    }
+                }
+
+                let events = network_manager.process_timeouts(now);
                 for evt in events.into_iter() {
                     tx.send(evt).unwrap();
                 }
 
-                tick_cnt += 1;
-            }
-            Ok(Event::Udp(udp_packet, src_addr)) => {
-                let src_addr = match src_addr {
-                    SocketAddr::V4(_) => src_addr,
-                    SocketAddr::V6(sa) => {
-                        if let Some(ipv4) = sa.ip().to_ipv4() {
-                            SocketAddr::V4(SocketAddrV4::new(ipv4, sa.port()))
-                        } else {
-                            src_addr
+                if tick_cnt % 30 == 17 {
+                    // every 30s detect broken tunnels of peers with keepalive
+                    match device.call(latest_handshakes).await {
+                        Ok(latest_handshakes) => {
+                            let events = network_manager.check_handshakes(
+                                now,
+                                &static_config,
+                                &latest_handshakes,
+                            );
+                            for evt in events.into_iter() {
+                                tx.send(evt).unwrap();
+                            }
+                        }
+                        Err(e) => {
+                            debug!(target: "wireguard", "Cannot get latest handshakes: {:?}", e)
                         }
                     }
-                };
+                }
+
+                if let Some(previous) = grace_expired(now, &mut static_config) {
+                    info!("Grace period of the previous wg_ip {} has ended", previous);
+                    let prefix_len = host_net(previous).prefix_len();
+                    if let Err(e) = device
+                        .call(move |wg_dev| wg_dev.del_ip(&previous, prefix_len))
+                        .await
+                    {
+                        warn!("Cannot remove the previous wg_ip: {}", e);
+                    }
+                }
+                if let Some(ha) = opt_ha.as_mut() {
+                    if let Some(state) = ha.process_timer(now) {
+                        ha_state_changed(state, &mut admin_sockets, &mut network_manager);
+                    }
+                    let buf = bincode::serialize(&ha.heartbeat()).unwrap();
+                    admin_sockets.raw_send_to(&buf, ha.partner());
+                }
+                if renewal_due(now, &static_config) && tick_cnt % 10 == 3 {
+                    // every 10s until renewed
+                    let buf = bincode::serialize(&renewal_request(&static_config)).unwrap();
+                    for peer in static_config.peers.values() {
+                        debug!(target: "lease", "Send AddressRequest to {}", peer.wg_ip);
+                        admin_sockets.send_to(&buf, SocketAddr::new(peer.wg_ip, peer.admin_port));
+                    }
+                }
+
+                match rotate_keys(now, &mut static_config, || {
+                    device.call_blocking(|wg_dev| wg_dev.create_key_pair())
+                }) {
+                    Ok(Some(KeyRotationStep::Announced)) => {
+                        info!("Announce new key pair, switch in {}s", KEY_ROTATION_OVERLAP);
+                    }
+                    Ok(Some(KeyRotationStep::Switched)) => {
+                        info!("Switch to new key pair");
+                        tx.send(Event::UpdateWireguardConfiguration).unwrap();
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!("Cannot create new key pair: {}", e),
+                }
+
+                #[cfg_attr(not(feature = "webhook"), allow(unused_variables))]
+                let raised = alert_manager.check(now, &network_manager);
+                #[cfg(feature = "webhook")]
+                if let Some(notifier) = opt_notifier.as_ref() {
+                    for event in raised.iter().filter_map(WebhookEvent::from_alert) {
+                        notifier.notify(Notification::new(now, &static_config, event));
+                    }
+                }
+                if static_config.use_tui {
+                    tui_app.set_alerts(alert_manager.alerts().into_iter().cloned().collect());
+                    tui_app.set_latencies(
+                        network_manager
+                            .peer_infos(now)
+                            .into_iter()
+                            .filter_map(|p| {
+                                let rtt_ms = p.rtt_ms?;
+                                Some((p.name.unwrap_or_else(|| p.wg_ip.to_string()), rtt_ms))
+                            })
+                            .collect(),
+                    );
+                    let (handshakes, transfer) = device
+                        .call(|wg_dev| {
+                            Ok((
+                                latest_handshakes(wg_dev).unwrap_or_default(),
+                                wg_dev.transfer().unwrap_or_default(),
+                            ))
+                        })
+                        .await
+                        .unwrap_or_default();
+                    tui_app.set_peers(PeerRow::of(
+                        now,
+                        network_manager.peer_infos(now),
+                        &handshakes,
+                        &transfer,
+                    ));
+                }
+
+                if let Some(status_file) = opt_status_file.as_mut() {
+                    if let Err(e) = status_file.update(now, &network_manager, &static_config) {
+                        warn!("Cannot write status file: {}", e);
+                    }
+                }
+                if let Some(status_page) = admin_sockets.status_page.as_mut() {
+                    status_page.update(now, &network_manager, &static_config);
+                }
+                if let Some(state_file) = opt_state_file.as_mut() {
+                    if let Err(e) = state_file.save_on_change(now, &network_manager, &static_config)
+                    {
+                        warn!(target: "state", "Cannot write state file: {}", e);
+                    }
+                }
+                if let Some(metrics) = opt_metrics.as_mut().filter(|m| m.is_due(now)) {
+                    let handshakes = device.call(latest_handshakes).await;
+                    metrics.update(
+                        now,
+                        &network_manager,
+                        admin_sockets.crypt_socket_v4.counters().counts(),
+                        advertisements_sent,
+                        || handshakes,
+                    );
+                }
+
+                tick_cnt += 1;
+            }
+            Some(Event::NodesDue) => {
+                let now = crate::util::now();
+                let events = network_manager.process_due_nodes(now, &static_config);
+                for evt in events.into_iter() {
+                    tx.send(evt).unwrap();
+                }
+            }
+            Some(Event::DecryptFailure(src_addr)) => {
+                let src_addr = unmap_ipv4(src_addr);
+                debug!(target: "udp", "Cannot decrypt packet from {}", src_addr);
+                let now = crate::util::now();
+                if let Some(hint) =
+                    network_manager.decrypt_failure(now, src_addr, &static_config.peers)
+                {
+                    warn!(target: "udp", "{}", hint);
+                }
+            }
+            Some(Event::Udp(udp_packet, src_addr)) => {
+                let src_addr = unmap_ipv4(src_addr);
+                network_manager.decrypt_success(crate::util::now(), src_addr);
 
                 use UdpPacket::*;
-                let events: Vec<Event>;
-                match udp_packet {
-                    Advertisement(ad) => {
-                        debug!(target: &ad.wg_ip.to_string(), "Received advertisement from {:?}", src_addr);
+                let events: Vec<Event> = match udp_packet {
+                    HaHeartbeat(heartbeat) => {
+                        trace!(target: "ha", "Received {:?} from {}", heartbeat, src_addr);
                         let now = crate::util::now();
-                        events =
-                            network_manager.analyze_advertisement(now, static_config, ad, src_addr);
-                    }
-                    RouteDatabaseRequest => match src_addr {
-                        SocketAddr::V4(destination) => {
-                            info!(target: "routing", "RouteDatabaseRequest from {:?}", src_addr);
-                            debug!(target: &destination.ip().to_string(), "Received database request");
-                            events = vec![Event::SendRouteDatabase { to: destination }];
+                        if let Some(ha) = opt_ha.as_mut() {
+                            if let Some(state) = ha.process_heartbeat(now, heartbeat, src_addr) {
+                                ha_state_changed(state, &mut admin_sockets, &mut network_manager);
+                            }
                         }
-                        SocketAddr::V6(source) => {
-                            error!(target: "routing", "Expected IPV4 and not IPV6 address {:?}", source);
-                            events = vec![];
-                        }
-                    },
+                        vec![]
+                    }
+                    _ if admin_sockets.muted => {
+                        trace!(target: "ha", "Standby => ignore packet from {}", src_addr);
+                        vec![]
+                    }
+                    Advertisement(ad) => {
+                        sampled!(Level::Debug, target: &ad.wg_ip.to_string(), "Received advertisement from {:?}", src_addr);
+                        let now = crate::util::now();
+                        network_manager.analyze_advertisement(now, &static_config, ad, src_addr)
+                    }
+                    RouteDatabaseRequest => {
+                        info!(target: "routing", "RouteDatabaseRequest from {:?}", src_addr);
+                        debug!(target: &src_addr.ip().to_string(), "Received database request");
+                        vec![Event::SendRouteDatabase {
+                            to: src_addr,
+                            since: None,
+                        }]
+                    }
+                    RouteDatabaseDeltaRequest { since } => {
+                        info!(target: "routing", "RouteDatabaseDeltaRequest since {} from {:?}", since, src_addr);
+                        vec![Event::SendRouteDatabase {
+                            to: src_addr,
+                            since: Some(since),
+                        }]
+                    }
                     RouteDatabase(db) => {
                         info!(target: "routing", "RouteDatabase from {}", src_addr);
                         debug!(target: &src_addr.ip().to_string(), "Received route database, version = {}", db.routedb_version);
-                        events = network_manager
+                        network_manager
                             .process_route_database(db)
-                            .unwrap_or_default();
+                            .unwrap_or_default()
+                    }
+                    RouteDatabaseDelta(delta) => {
+                        info!(target: "routing", "RouteDatabaseDelta from {}", src_addr);
+                        debug!(target: &src_addr.ip().to_string(), "Received route database delta, version = {}", delta.routedb_version);
+                        network_manager
+                            .process_route_database_delta(delta)
+                            .unwrap_or_default()
+                    }
+                    LocalContactRequest => {
+                        info!(target: "probing", "LocalContactRequest from {:?}", src_addr);
+                        debug!(target: &src_addr.ip().to_string(), "Received local contact request");
+                        let now = crate::util::now();
+                        let mut events = vec![Event::SendLocalContact { to: src_addr }];
+                        events.append(&mut network_manager.local_contact_requested(now, src_addr));
+                        events
                     }
-                    LocalContactRequest => match src_addr {
-                        SocketAddr::V4(destination) => {
-                            info!(target: "probing", "LocalContactRequest from {:?}", src_addr);
-                            debug!(target: &destination.ip().to_string(), "Received local contact request");
-                            events = vec![Event::SendLocalContact { to: destination }];
-                        }
-                        SocketAddr::V6(source) => {
-                            error!(target: "probing", "Expected IPV4 and not IPV6 address {:?}", source);
-                            events = vec![];
-                        }
-                    },
                     LocalContact(contact) => {
                         debug!(target: "probing", "Received contact info: {:#?}", contact);
                         debug!(target: &contact.wg_ip.to_string(), "Received local contacts");
-                        network_manager.process_local_contact(contact);
-                        events = vec![];
+                        let now = crate::util::now();
+                        network_manager.process_local_contact(now, contact)
                     }
-                }
+                    AuditRequest => {
+                        info!(target: "audit", "AuditRequest from {:?}", src_addr);
+                        vec![Event::SendAudit { to: src_addr }]
+                    }
+                    Audit(_) => {
+                        // Only the audit command is interested in this
+                        debug!(target: "audit", "Ignore audit reply from {:?}", src_addr);
+                        vec![]
+                    }
+                    FleetCommand(command) => {
+                        let now = crate::util::now();
+                        let (reply, events) = fleet_guard.process(
+                            now,
+                            crate::util::wall_now(),
+                            &command,
+                            src_addr,
+                            &mut network_manager,
+                            &mut static_config,
+                        );
+                        if let Some(reply) = reply {
+                            let buf = bincode::serialize(&UdpPacket::FleetResult(reply)).unwrap();
+                            admin_sockets.send_to(&buf, src_addr);
+                        }
+                        events
+                    }
+                    FleetResult(_) => {
+                        // Only the fleet command is interested in this
+                        debug!(target: "fleet", "Ignore fleet result from {:?}", src_addr);
+                        vec![]
+                    }
+                    Ping { sent_ms } => vec![Event::SendPong {
+                        to: src_addr,
+                        sent_ms,
+                    }],
+                    Pong { sent_ms } => {
+                        let rtt_ms = crate::util::now_ms().saturating_sub(sent_ms);
+                        debug!(target: &src_addr.ip().to_string(), "Round trip time {} ms", rtt_ms);
+                        network_manager
+                            .process_pong(src_addr.ip(), u32::try_from(rtt_ms).unwrap_or(u32::MAX))
+                    }
+                    Goodbye => network_manager.process_goodbye(&static_config, src_addr),
+                    ConnectRequest(request) => {
+                        debug!(target: "probing", "Received {:?} from {}", request, src_addr);
+                        let now = crate::util::now();
+                        network_manager.process_connect_request(now, request)
+                    }
+                    AddressRequest(request) => {
+                        debug!(target: "lease", "Received {:?} from {}", request, src_addr);
+                        let now = crate::util::now();
+                        network_manager.process_address_request(
+                            now,
+                            &static_config,
+                            request,
+                            src_addr,
+                        )
+                    }
+                    AddressConflict(conflict) => {
+                        debug!(target: "conflict", "Received {:?} from {}", conflict, src_addr);
+                        let now = crate::util::now();
+                        network_manager.process_address_conflict(
+                            now,
+                            &static_config,
+                            conflict,
+                            src_addr,
+                        );
+                        vec![]
+                    }
+                    AddressLease(lease) => {
+                        debug!(target: "lease", "Received {:?} from {}", lease, src_addr);
+                        let now = crate::util::now();
+                        match apply_lease(now, &mut static_config, &lease) {
+                            Ok(Some(previous)) => {
+                                warn!(target: "lease", "{} is in conflict, changed to {}", previous, lease.wg_ip);
+                                network_manager.readdress(&static_config, previous)
+                            }
+                            Ok(None) => vec![],
+                            Err(e) => {
+                                warn!(target: "lease", "Cannot change to leased {}: {}", lease.wg_ip, e);
+                                vec![]
+                            }
+                        }
+                    }
+                };
                 for evt in events {
                     tx.send(evt).unwrap();
                 }
             }
-            Ok(Event::SendAdvertisement {
+            Some(Event::SendAdvertisement {
                 addressed_to,
                 to: destination,
                 wg_ip,
             }) => {
-                debug!(target: &wg_ip.to_string(),"Send advertisement to {:?}", destination);
+                sampled!(Level::Debug, target: &wg_ip.to_string(), "Send advertisement to {:?}", destination);
                 let routedb_version = network_manager.db_version();
                 let my_visible_wg_endpoint =
                     network_manager.my_visible_wg_endpoint.as_ref().copied();
                 let my_local_wg_port = network_manager.my_local_wg_port;
+                let now = crate::util::now();
+                let force_relay_s = network_manager.force_relay_request(now, &wg_ip);
+                let revocations = network_manager.revocations_to_gossip(now);
                 let opt_node = network_manager.node_for(&wg_ip);
                 let advertisement = UdpPacket::advertisement_from_config(
-                    static_config,
+                    &static_config,
                     routedb_version,
                     addressed_to,
                     opt_node,
                     my_local_wg_port,
                     my_visible_wg_endpoint,
+                    force_relay_s,
+                    revocations,
                 );
                 let buf = bincode::serialize(&advertisement).unwrap();
-                info!(target: "advertisement", "Send advertisement to {}", destination);
-                if destination.is_ipv4() {
-                    crypt_socket_v4.send_to(&buf, destination).ok();
-                } else {
-                    crypt_socket_v6.send_to(&buf, destination).ok();
+                sampled!(Level::Info, target: "advertisement", "Send advertisement to {}", destination);
+                admin_sockets.send_to(&buf, destination);
+                advertisements_sent += 1;
+            }
+            #[cfg(feature = "mdns")]
+            Some(Event::MdnsPeer(service)) => {
+                let endpoints = service.admin_endpoints();
+                for evt in network_manager.local_peer_discovered(service.wg_ip, &endpoints) {
+                    info!(target: "mdns", "Contact {} found via mDNS", service.wg_ip);
+                    tx.send(evt).unwrap();
                 }
             }
-            Ok(Event::SendRouteDatabaseRequest { to: destination }) => {
+            Some(Event::SendRouteDatabaseRequest { to: destination }) => {
                 debug!(target: &destination.ip().to_string(), "Send route database request to {:?}", destination);
-                let request = UdpPacket::route_database_request();
+                let request = match network_manager.known_routedb_version(&destination.ip()) {
+                    Some(since) => UdpPacket::route_database_delta_request(since),
+                    None => UdpPacket::route_database_request(),
+                };
                 let buf = bincode::serialize(&request).unwrap();
-                info!(target: "routing", "Send RouteDatabaseRequest to {}", destination);
-                crypt_socket_v4
-                    .send_to(&buf, SocketAddr::V4(destination))
-                    .ok();
+                info!(target: "routing", "Send {:?} to {}", request, destination);
+                admin_sockets.send_to(&buf, destination);
             }
-            Ok(Event::SendRouteDatabase { to: destination }) => {
+            Some(Event::SendRouteDatabase {
+                to: destination,
+                since,
+            }) => {
                 debug!(target: &destination.ip().to_string(), "Send route database to {:?}", destination);
-                let packages = network_manager.provide_route_database();
+                let packages = match since {
+                    Some(since) => {
+                        network_manager.provide_route_database_delta(destination.ip(), since)
+                    }
+                    None => network_manager.provide_route_database(destination.ip()),
+                };
                 for p in packages {
                     let buf = bincode::serialize(&p).unwrap();
                     info!(target: "routing", "Send RouteDatabase to {}", destination);
-                    crypt_socket_v4
-                        .send_to(&buf, SocketAddr::V4(destination))
-                        .ok();
+                    admin_sockets.send_to(&buf, destination);
+                }
+            }
+            Some(Event::SendPing { to: destination }) => {
+                let ping = UdpPacket::Ping {
+                    sent_ms: crate::util::now_ms(),
+                };
+                let buf = bincode::serialize(&ping).unwrap();
+                admin_sockets.send_to(&buf, destination);
+            }
+            Some(Event::SendPong {
+                to: destination,
+                sent_ms,
+            }) => {
+                let buf = bincode::serialize(&UdpPacket::Pong { sent_ms }).unwrap();
+                admin_sockets.send_to(&buf, destination);
+            }
+            Some(Event::SendConnectRequest { wg_ip }) => {
+                if let Some((destination, request)) = network_manager.connect_request(&wg_ip) {
+                    let buf = bincode::serialize(&request).unwrap();
+                    info!(target: "probing", "Send rendezvous request for {} to {}", wg_ip, destination);
+                    admin_sockets.send_to(&buf, destination);
                 }
             }
-            Ok(Event::SendLocalContactRequest { to: destination }) => {
+            Some(Event::SendAddressLease {
+                to: destination,
+                lease,
+            }) => {
+                let buf = bincode::serialize(&UdpPacket::AddressLease(lease)).unwrap();
+                admin_sockets.send_to(&buf, destination);
+            }
+            Some(Event::SendAddressConflict {
+                to: destination,
+                conflict,
+            }) => {
+                let buf = bincode::serialize(&UdpPacket::AddressConflict(conflict)).unwrap();
+                info!(target: "conflict", "Send address conflict to {}", destination);
+                admin_sockets.send_to(&buf, destination);
+            }
+            Some(Event::ForwardConnectRequest {
+                to: destination,
+                request,
+            }) => {
+                let buf = bincode::serialize(&UdpPacket::ConnectRequest(request)).unwrap();
+                info!(target: "probing", "Forward rendezvous request to {}", destination);
+                admin_sockets.send_to(&buf, destination);
+            }
+            Some(Event::SendLocalContactRequest { to: destination }) => {
                 debug!(target: &destination.ip().to_string(), "Send local contact request to {:?}", destination);
                 let request = UdpPacket::local_contact_request();
                 let buf = bincode::serialize(&request).unwrap();
                 info!(target: "probing", "Send LocalContactRequest to {}", destination);
-                crypt_socket_v4
-                    .send_to(&buf, SocketAddr::V4(destination))
-                    .ok();
+                admin_sockets.send_to(&buf, destination);
             }
-            Ok(Event::SendLocalContact { to: destination }) => {
+            Some(Event::SendLocalContact { to: destination }) => {
                 debug!(target: &destination.ip().to_string(), "Send local contacts to {:?}", destination);
                 let local_contact = UdpPacket::local_contact_from_config(
-                    static_config,
+                    &static_config,
                     network_manager.my_local_wg_port,
                     network_manager.my_visible_wg_endpoint,
                 );
                 trace!(target: "probing", "local contact to {:#?}", local_contact);
                 let buf = bincode::serialize(&local_contact).unwrap();
                 info!(target: "probing", "Send local contact to {}", destination);
-                crypt_socket_v4
-                    .send_to(&buf, SocketAddr::V4(destination))
-                    .ok();
+                admin_sockets.send_to(&buf, destination);
+            }
+            Some(Event::SendLocalContactOf {
+                wg_ip,
+                to: destination,
+            }) => {
+                if let Some(local) = network_manager.local_contact_of(&wg_ip) {
+                    let local_contact = UdpPacket::LocalContact(local.clone());
+                    let buf = bincode::serialize(&local_contact).unwrap();
+                    info!(target: "probing", "Send local contact of {} to {}", wg_ip, destination);
+                    admin_sockets.send_to(&buf, destination);
+                }
+            }
+            Some(Event::SendAudit { to: destination }) => {
+                let audit = network_manager.provide_audit(&static_config);
+                let buf = bincode::serialize(&audit).unwrap();
+                info!(target: "audit", "Send audit to {}", destination);
+                admin_sockets.send_to(&buf, destination);
             }
-            Ok(Event::WireguardPortHop) => {
+            Some(Event::Rekey) => {
+                let now = crate::util::now();
+                match rekey(now, &mut static_config, || {
+                    device.call_blocking(|wg_dev| wg_dev.create_key_pair())
+                }) {
+                    Ok(()) => info!("Announce new key pair, switch in {}s", KEY_ROTATION_OVERLAP),
+                    Err(e) => error!("Cannot create new key pair: {}", e),
+                }
+            }
+            Some(Event::WireguardPortHop) => {
                 let mut new_port = network_manager.my_local_wg_port;
                 new_port = (new_port - 10000 + 1) % (65535 - 10000) + 10000;
                 trace!(target: "hopping", "Perform wireguard port hop to {}", new_port);
                 network_manager.my_local_wg_port = new_port;
             }
-            Ok(Event::UpdateWireguardConfiguration) => {
+            Some(Event::UpdateWireguardConfiguration) => {
                 info!("Update peers");
                 let conf = static_config.to_wg_configuration(&network_manager);
-                info!(target: "wireguard", "Configuration as peer\n{}\n", conf);
-                wg_dev.sync_conf(&conf)?;
+                // Updates queued meanwhile supersede this one until the apply event is processed
+                if !conf_sequencer.has_pending() {
+                    tx.send(Event::ApplyWireguardConfiguration).unwrap();
+                }
+                let seq = conf_sequencer.submit(conf);
+                debug!(target: "wireguard", "Queued configuration #{}", seq);
             }
-            Ok(Event::ReadWireguardConfiguration) => {
-                let pubkey_to_endpoint = wg_dev.retrieve_conf()?;
+            Some(Event::ApplyWireguardConfiguration) => {
+                if let Some((seq, conf)) = conf_sequencer.take_latest() {
+                    info!(target: "wireguard", "Configuration #{} as peer\n{}\n", seq, conf);
+                    device.call(move |wg_dev| wg_dev.sync_conf(&conf)).await?;
+                    conf_sequencer.applied(seq);
+                    if let Some(peer_names) = opt_peer_names.as_mut() {
+                        let names = PeerNames::of(&network_manager, &static_config);
+                        if let Err(e) = peer_names.update(names) {
+                            warn!(target: "wireguard", "Cannot write peer names: {}", e);
+                        }
+                    }
+                    if conf_sequencer.skipped() > 0 {
+                        trace!(target: "wireguard", "{} superseded configurations skipped", conf_sequencer.skipped());
+                    }
+                }
+            }
+            Some(Event::ReadWireguardConfiguration) => {
+                let pubkey_to_endpoint = device.call(|wg_dev| wg_dev.retrieve_conf()).await?;
                 network_manager.current_wireguard_configuration(pubkey_to_endpoint);
             }
-            Ok(Event::UpdateRoutes) => {
+            Some(Event::Readdress { previous }) => {
+                names_changed = true;
+                let config = static_config.clone();
+                let readdressed = device
+                    .call(move |wg_dev| readdress_interface(wg_dev, &config, previous))
+                    .await;
+                if let Err(e) = readdressed {
+                    error!(
+                        "Cannot change the address of {}: {}",
+                        static_config.wg_name, e
+                    );
+                }
+            }
+            Some(Event::UpdateRoutes) => {
                 let changes = network_manager.get_route_changes();
-                for rc in changes {
-                    use RouteChange::*;
-                    debug!("{:?}", rc);
-                    match rc {
-                        AddRoute { to, gateway } => {
-                            debug!(target: &to.to_string(), "add route with gateway {:?}", gateway);
-                            wg_dev.add_route(to, gateway)?;
-                        }
-                        ReplaceRoute { to, gateway } => {
-                            debug!(target: &to.to_string(), "replace route with gateway {:?}", gateway);
-                            wg_dev.replace_route(to, gateway)?;
-                        }
-                        DelRoute { to, gateway } => {
-                            debug!(target: &to.to_string(), "del route with gateway {:?}", gateway);
-                            wg_dev.del_route(to, gateway)?;
-                        }
-                    }
+                #[cfg(feature = "chaos")]
+                if let Some(chaos) = admin_sockets.chaos.as_mut() {
+                    chaos.routes_changed(crate::util::now(), changes.len());
+                }
+                device
+                    .call(move |wg_dev| apply_route_changes(wg_dev, changes))
+                    .await?;
+                if static_config.use_tui {
+                    tui_app.set_routes(static_config.wg_ip, network_manager.routes());
                 }
                 tx.send(Event::UpdateWireguardConfiguration).unwrap();
             }
-            Ok(Event::TuiApp(evt)) => {
-                tui_app.process_event(evt);
+            Some(Event::TuiApp(evt)) => {
+                if let Some((wg_ip, action)) = tui_app.process_event(evt) {
+                    for evt in action.events(wg_ip, &mut network_manager, &static_config) {
+                        tx.send(evt).unwrap();
+                    }
+                }
                 tui_app.draw()?;
             }
+            #[cfg(unix)]
+            Some(Event::Control(cmd)) => {
+                debug!(target: "control", "{:?}", cmd.request);
+                let (response, events) = crate::control::execute(
+                    crate::util::now(),
+                    &cmd.request,
+                    &mut network_manager,
+                    &mut static_config,
+                );
+                cmd.reply.send(response).ok();
+                for evt in events {
+                    tx.send(evt).unwrap();
+                }
+            }
+            #[cfg(target_os = "linux")]
+            Some(Event::Handover(stream)) => {
+                info!(target: "upgrade", "New instance requests takeover");
+                let snapshot = network_manager.state_snapshot(&static_config);
+                match crate::upgrade::hand_over(
+                    stream,
+                    admin_sockets.crypt_socket_v4.as_raw_fd(),
+                    admin_sockets.crypt_socket_v6.as_raw_fd(),
+                    &snapshot,
+                ) {
+                    Ok(()) => {
+                        info!(target: "upgrade", "Handed over to new instance");
+                        // The status file is continued by the new instance
+                        return Ok(None);
+                    }
+                    Err(e) => error!(target: "upgrade", "Handover failed: {}", e),
+                }
+            }
+        }
+        for mesh_evt in mesh_events.try_iter() {
+            info!(target: "mesh", "{:?}", mesh_evt);
+            names_changed = true;
+            alert_manager.process_mesh_event(crate::util::now(), &mesh_evt);
+            #[cfg(feature = "webhook")]
+            if let Some(notifier) = opt_notifier.as_ref() {
+                if let Some(event) = WebhookEvent::from_mesh_event(&mesh_evt) {
+                    let now = crate::util::now();
+                    notifier.notify(Notification::new(now, &static_config, event));
+                }
+            }
+        }
+        // The names follow every change of the peers or routes
+        if names_changed && (admin_sockets.name_server.is_some() || opt_hosts_file.is_some()) {
+            names_changed = false;
+            let names = MeshNames::of(crate::util::now(), &network_manager, &static_config);
+            if let Some(name_server) = admin_sockets.name_server.as_ref() {
+                name_server.update(&names);
+            }
+            if let Some(hosts_file) = opt_hosts_file.as_mut() {
+                if let Err(e) = hosts_file.update(&names) {
+                    warn!(target: "dns", "Cannot write hosts file: {}", e);
+                }
+            }
+        }
+    }
+    if let Some(status_file) = opt_status_file.as_ref() {
+        status_file.remove();
+    }
+    if let Some(hosts_file) = opt_hosts_file.as_ref() {
+        hosts_file.remove();
+    }
+    if let Some(peer_names) = opt_peer_names.as_ref() {
+        peer_names.remove();
+    }
+    Ok(Some(static_config.teardown(Some(&network_manager))))
+}
+
+// The route changes of one UpdateRoutes event in a single call of the DeviceWorker
+fn apply_route_changes(wg_dev: &dyn WireguardDevice, changes: Vec<RouteChange>) -> BoxResult<()> {
+    for rc in changes {
+        use RouteChange::*;
+        debug!("{:?}", rc);
+        match rc {
+            AddRoute {
+                to,
+                gateway,
+                attributes,
+            } => {
+                debug!(target: &to.to_string(), "add route with gateway {:?}", gateway);
+                wg_dev.add_route(to, gateway, &attributes)?;
+            }
+            ReplaceRoute {
+                to,
+                gateway,
+                attributes,
+                reason,
+            } => {
+                debug!(target: &to.to_string(), "replace route with gateway {:?}: {}", gateway, reason);
+                wg_dev.replace_route(to, gateway, &attributes)?;
+            }
+            DelRoute {
+                to,
+                gateway,
+                attributes,
+                reason,
+            } => {
+                debug!(target: &to.to_string(), "del route with gateway {:?}: {}", gateway, reason);
+                wg_dev.del_route(to, gateway, &attributes)?;
+            }
+            AddNetRoute { net } => {
+                debug!(target: "routing", "add route to exported network {}", net);
+                wg_dev.add_net_route(net)?;
+            }
+            DelNetRoute { net } => {
+                debug!(target: "routing", "del route to exported network {}", net);
+                wg_dev.del_net_route(net)?;
+            }
         }
     }
     Ok(())
 }
+
+// Only the active node of a hub pair talks to the mesh
+fn ha_state_changed(
+    state: HaState,
+    admin_sockets: &mut AdminSockets,
+    network_manager: &mut NetworkManager,
+) {
+    admin_sockets.muted = state == HaState::Standby;
+    if state == HaState::Active {
+        network_manager.readvertise();
+    }
+}
+
+// The new wg_ip replaces the previous one, which is kept as host address for the grace
+// period. So the new wg_ip is the preferred source address right away.
+fn readdress_interface(
+    wg_dev: &dyn WireguardDevice,
+    static_config: &StaticConfiguration,
+    previous: IpAddr,
+) -> BoxResult<()> {
+    let prefix_len = static_config.subnet.prefix_len();
+    wg_dev.del_ip(&previous, prefix_len)?;
+    wg_dev.add_ip(&static_config.wg_ip, prefix_len)?;
+    wg_dev.add_ip(&previous, host_net(previous).prefix_len())?;
+    // The nat probe address is derived from the wg_ip
+    if !static_config.disable_ipv6 {
+        if let Some(probe_net) = nat_probe_net(&previous, &static_config.subnet) {
+            wg_dev.del_ip(&probe_net.addr().into(), probe_net.prefix_len())?;
+        }
+        if let Some(probe_net) = static_config.nat_probe_net() {
+            wg_dev.add_ip(&probe_net.addr().into(), probe_net.prefix_len())?;
+        }
+    }
+    info!(
+        "Interface {} readdressed to {}",
+        static_config.wg_name, static_config.wg_ip
+    );
+    Ok(())
+}
+
+// The dual stack ipv6 socket reports ipv4 senders as ipv4-mapped ipv6 addresses
+fn unmap_ipv4(src_addr: SocketAddr) -> SocketAddr {
+    match src_addr {
+        SocketAddr::V4(_) => src_addr,
+        SocketAddr::V6(sa) => {
+            if let Some(ipv4) = sa.ip().to_ipv4() {
+                SocketAddr::new(ipv4.into(), sa.port())
+            } else {
+                src_addr
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+// The known nodes and routes, which are kept over a restart in the state file.
+//
+// The redacted state snapshot is saved encrypted (see persist) on a change of a
+// peer or a route, at most every SAVE_INTERVAL seconds. On startup it is loaded
+// as hints by restore_hints() of the manager. A missing, corrupt or foreign file
+// only means to start without hints.
+//
+use std::path::PathBuf;
+
+use log::*;
+
+use crate::configuration::StaticConfiguration;
+use crate::error::*;
+use crate::manager::NetworkManager;
+use crate::persist::PersistKey;
+use crate::state_snapshot::{diff, StateSnapshot};
+
+pub const SAVE_INTERVAL: u64 = 10;
+
+pub struct StateFile {
+    path: PathBuf,
+    key: PersistKey,
+    saved: Option<StateSnapshot>,
+    last_save: u64,
+}
+impl StateFile {
+    pub fn new<P: Into<PathBuf>>(path: P, shared_key: &[u8]) -> Self {
+        StateFile {
+            path: path.into(),
+            key: PersistKey::from_shared_key(shared_key),
+            saved: None,
+            last_save: 0,
+        }
+    }
+    pub fn load(&mut self) -> Option<StateSnapshot> {
+        let buf = self.key.load(&self.path)?;
+        match StateSnapshot::from_json(&buf) {
+            Ok(snapshot) => {
+                self.saved = Some(snapshot.clone());
+                Some(snapshot)
+            }
+            Err(e) => {
+                warn!(target: "state", "Ignore {}: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+    // Returns true, if the file has been written
+    pub fn save_on_change(
+        &mut self,
+        now: u64,
+        network_manager: &NetworkManager,
+        static_config: &StaticConfiguration,
+    ) -> BoxResult<bool> {
+        if now < self.last_save + SAVE_INTERVAL {
+            return Ok(false);
+        }
+        let snapshot = StateSnapshot {
+            created: crate::util::to_wall_time(now),
+            ..network_manager.state_snapshot(static_config).redacted()
+        };
+        if let Some(saved) = self.saved.as_ref() {
+            if diff(saved, &snapshot).is_empty() {
+                return Ok(false);
+            }
+        }
+        self.key.save(&self.path, &snapshot.to_json()?)?;
+        self.last_save = now;
+        self.saved = Some(snapshot);
+        Ok(true)
+    }
+}
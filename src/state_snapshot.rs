@@ -0,0 +1,403 @@
+// The state of a running network manager, which is handed over to the new
+// instance on a binary upgrade.
+//
+// Only the information needed to continue without connectivity loss is included:
+// the wireguard key, the direct peers with their endpoints and the route databases.
+// Distant nodes are recreated from the route databases.
+//
+// Without the secrets, a snapshot is saved for post-mortems. Two of them are
+// compared with diff().
+//
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::{PendingKeyPair, PublicKeyWithTime, StaticConfiguration};
+use crate::error::*;
+use crate::node::{ConnectionType, VisibleEndpoints};
+use crate::routedb::RouteInfo;
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct RouteDBState {
+    pub version: usize,
+    pub routes: Vec<RouteInfo>,
+}
+impl RouteDBState {
+    pub fn new(version: usize, routes: Vec<RouteInfo>) -> Self {
+        RouteDBState { version, routes }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum NodeState {
+    Static {
+        wg_ip: IpAddr,
+        public_key: Option<PublicKeyWithTime>,
+        is_alive: bool,
+        lastseen: u64,
+        current_ip: Option<IpAddr>,
+        routedb: Option<RouteDBState>,
+    },
+    Dynamic {
+        wg_ip: IpAddr,
+        name: String,
+        public_key: PublicKeyWithTime,
+        local_wg_port: u16,
+        local_admin_port: u16,
+        admin_port: u16,
+        connection: ConnectionType,
+        local_reachable_wg_endpoint: Option<SocketAddr>,
+        local_reachable_admin_endpoint: Option<SocketAddr>,
+        visible_wg_endpoints: VisibleEndpoints,
+        lastseen: u64,
+        routedb: Option<RouteDBState>,
+    },
+}
+impl NodeState {
+    pub fn wg_ip(&self) -> IpAddr {
+        match self {
+            NodeState::Static { wg_ip, .. } => *wg_ip,
+            NodeState::Dynamic { wg_ip, .. } => *wg_ip,
+        }
+    }
+    fn class(&self) -> &'static str {
+        match self {
+            NodeState::Static { .. } => "static",
+            NodeState::Dynamic { connection, .. } => connection.as_str(),
+        }
+    }
+    fn endpoint(&self) -> Option<String> {
+        match self {
+            NodeState::Static { current_ip, .. } => current_ip.map(|ip| ip.to_string()),
+            NodeState::Dynamic {
+                connection,
+                visible_wg_endpoints,
+                ..
+            } => connection
+                .endpoint()
+                .or_else(|| visible_wg_endpoints.select(false))
+                .map(|endpoint| endpoint.to_string()),
+        }
+    }
+    fn public_key(&self) -> Option<&str> {
+        match self {
+            NodeState::Static { public_key, .. } => public_key.as_ref().map(|pk| pk.key.as_str()),
+            NodeState::Dynamic { public_key, .. } => Some(&public_key.key),
+        }
+    }
+    fn is_alive(&self) -> bool {
+        match self {
+            NodeState::Static { is_alive, .. } => *is_alive,
+            NodeState::Dynamic { .. } => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct StateSnapshot {
+    pub version: u32,
+    pub wg_ip: IpAddr,
+    pub wg_name: String,
+    pub my_private_key: String,
+    pub my_public_key: PublicKeyWithTime,
+    // A key rotation in progress
+    #[serde(default)]
+    pub pending_key_pair: Option<PendingKeyPair>,
+    // Seed of the identity, so the peers keep accepting the advertisements
+    #[serde(default)]
+    pub identity_seed: Option<String>,
+    pub my_local_wg_port: u16,
+    pub my_visible_wg_endpoint: Option<SocketAddr>,
+    pub routedb: RouteDBState,
+    pub nodes: Vec<NodeState>,
+    // Unix time of a saved snapshot, 0 for a handover
+    #[serde(default)]
+    pub created: u64,
+}
+impl StateSnapshot {
+    // The own keys and identity of static_config without nodes and routes
+    pub fn new(static_config: &StaticConfiguration) -> Self {
+        StateSnapshot {
+            version: SNAPSHOT_VERSION,
+            wg_ip: static_config.wg_ip,
+            wg_name: static_config.wg_name.clone(),
+            my_private_key: static_config.my_private_key.clone(),
+            my_public_key: static_config.my_public_key.clone(),
+            pending_key_pair: static_config.pending_key_pair.clone(),
+            identity_seed: Some(base64::encode(static_config.identity.seed())),
+            my_local_wg_port: static_config.wg_port,
+            my_visible_wg_endpoint: None,
+            routedb: RouteDBState::new(0, vec![]),
+            nodes: vec![],
+            created: 0,
+        }
+    }
+    pub fn to_json(&self) -> BoxResult<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+    pub fn from_json(buf: &[u8]) -> BoxResult<Self> {
+        let snapshot: StateSnapshot = serde_json::from_slice(buf)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!("Unsupported state snapshot version {}", snapshot.version).into());
+        }
+        Ok(snapshot)
+    }
+    // Without the private keys and the identity seed
+    pub fn redacted(mut self) -> Self {
+        self.my_private_key = String::new();
+        self.identity_seed = None;
+        if let Some(pending) = self.pending_key_pair.as_mut() {
+            pending.private_key = String::new();
+        }
+        self
+    }
+    pub fn write<P: AsRef<Path>>(&self, fname: P) -> BoxResult<()> {
+        fs::write(fname, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+    pub fn read<P: AsRef<Path>>(fname: P) -> BoxResult<Self> {
+        StateSnapshot::from_json(&fs::read(fname)?)
+    }
+}
+
+// A change between two snapshots as printed by `wg_netmanager diff-state`
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateChange {
+    VisibleEndpoint {
+        from: Option<SocketAddr>,
+        to: Option<SocketAddr>,
+    },
+    PublicKey,
+    PeerAppeared {
+        wg_ip: IpAddr,
+        class: &'static str,
+        endpoint: Option<String>,
+    },
+    PeerDisappeared {
+        wg_ip: IpAddr,
+        class: &'static str,
+    },
+    PeerConnection {
+        wg_ip: IpAddr,
+        from: &'static str,
+        to: &'static str,
+    },
+    PeerEndpoint {
+        wg_ip: IpAddr,
+        from: Option<String>,
+        to: Option<String>,
+    },
+    PeerPublicKey {
+        wg_ip: IpAddr,
+    },
+    PeerAlive {
+        wg_ip: IpAddr,
+        alive: bool,
+    },
+    RouteAdded {
+        to: IpAddr,
+        gateway: Option<IpAddr>,
+    },
+    RouteDeleted {
+        to: IpAddr,
+        gateway: Option<IpAddr>,
+    },
+    RouteMoved {
+        to: IpAddr,
+        from: Option<IpAddr>,
+        gateway: Option<IpAddr>,
+    },
+}
+impl fmt::Display for StateChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opt = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+        let via = |gateway: &Option<IpAddr>| match gateway {
+            Some(gateway) => format!("via {}", gateway),
+            None => "direct".to_string(),
+        };
+        use StateChange::*;
+        match self {
+            VisibleEndpoint { from, to } => write!(
+                f,
+                "~ own visible endpoint {} -> {}",
+                opt(&from.map(|e| e.to_string())),
+                opt(&to.map(|e| e.to_string()))
+            ),
+            PublicKey => write!(f, "~ own public key changed"),
+            PeerAppeared {
+                wg_ip,
+                class,
+                endpoint,
+            } => write!(f, "+ peer {} ({}) at {}", wg_ip, class, opt(endpoint)),
+            PeerDisappeared { wg_ip, class } => write!(f, "- peer {} ({})", wg_ip, class),
+            PeerConnection { wg_ip, from, to } => {
+                write!(f, "~ peer {} connection {} -> {}", wg_ip, from, to)
+            }
+            PeerEndpoint { wg_ip, from, to } => {
+                write!(f, "~ peer {} endpoint {} -> {}", wg_ip, opt(from), opt(to))
+            }
+            PeerPublicKey { wg_ip } => write!(f, "~ peer {} public key changed", wg_ip),
+            PeerAlive { wg_ip, alive } => {
+                let state = if *alive { "alive" } else { "dead" };
+                write!(f, "~ peer {} {}", wg_ip, state)
+            }
+            RouteAdded { to, gateway } => write!(f, "+ route {} {}", to, via(gateway)),
+            RouteDeleted { to, gateway } => write!(f, "- route {} (was {})", to, via(gateway)),
+            RouteMoved { to, from, gateway } => {
+                write!(f, "~ route {} {} (was {})", to, via(gateway), via(from))
+            }
+        }
+    }
+}
+
+// The changes from old to new: the node itself, then the peers and the routes
+// ordered by wg_ip
+pub fn diff(old: &StateSnapshot, new: &StateSnapshot) -> Vec<StateChange> {
+    let mut changes = vec![];
+    if old.my_visible_wg_endpoint != new.my_visible_wg_endpoint {
+        changes.push(StateChange::VisibleEndpoint {
+            from: old.my_visible_wg_endpoint,
+            to: new.my_visible_wg_endpoint,
+        });
+    }
+    if old.my_public_key.key != new.my_public_key.key {
+        changes.push(StateChange::PublicKey);
+    }
+
+    let nodes_of = |snapshot: &StateSnapshot| {
+        snapshot
+            .nodes
+            .iter()
+            .map(|node| (node.wg_ip(), node.clone()))
+            .collect::<BTreeMap<_, _>>()
+    };
+    let old_nodes = nodes_of(old);
+    let new_nodes = nodes_of(new);
+    for (wg_ip, old_node) in old_nodes.iter() {
+        if !new_nodes.contains_key(wg_ip) {
+            changes.push(StateChange::PeerDisappeared {
+                wg_ip: *wg_ip,
+                class: old_node.class(),
+            });
+        }
+    }
+    for (wg_ip, new_node) in new_nodes.iter() {
+        let wg_ip = *wg_ip;
+        let old_node = match old_nodes.get(&wg_ip) {
+            Some(old_node) => old_node,
+            None => {
+                changes.push(StateChange::PeerAppeared {
+                    wg_ip,
+                    class: new_node.class(),
+                    endpoint: new_node.endpoint(),
+                });
+                continue;
+            }
+        };
+        if old_node.class() != new_node.class() {
+            changes.push(StateChange::PeerConnection {
+                wg_ip,
+                from: old_node.class(),
+                to: new_node.class(),
+            });
+        }
+        if old_node.endpoint() != new_node.endpoint() {
+            changes.push(StateChange::PeerEndpoint {
+                wg_ip,
+                from: old_node.endpoint(),
+                to: new_node.endpoint(),
+            });
+        }
+        if old_node.public_key() != new_node.public_key() {
+            changes.push(StateChange::PeerPublicKey { wg_ip });
+        }
+        if old_node.is_alive() != new_node.is_alive() {
+            changes.push(StateChange::PeerAlive {
+                wg_ip,
+                alive: new_node.is_alive(),
+            });
+        }
+    }
+
+    let gateways_of = |snapshot: &StateSnapshot| {
+        snapshot
+            .routedb
+            .routes
+            .iter()
+            .map(|ri| (ri.to, ri.gateway))
+            .collect::<BTreeMap<_, _>>()
+    };
+    let old_routes = gateways_of(old);
+    let new_routes = gateways_of(new);
+    for (to, gateway) in old_routes.iter() {
+        if !new_routes.contains_key(to) {
+            changes.push(StateChange::RouteDeleted {
+                to: *to,
+                gateway: *gateway,
+            });
+        }
+    }
+    for (to, gateway) in new_routes.iter() {
+        match old_routes.get(to) {
+            None => changes.push(StateChange::RouteAdded {
+                to: *to,
+                gateway: *gateway,
+            }),
+            Some(from) if from != gateway => changes.push(StateChange::RouteMoved {
+                to: *to,
+                from: *from,
+                gateway: *gateway,
+            }),
+            Some(_) => {}
+        }
+    }
+    changes
+}
+
+// The report of `wg_netmanager diff-state`. Snapshots of two nodes show the
+// differences of their views.
+pub fn diff_report(old: &StateSnapshot, new: &StateSnapshot) -> String {
+    let time = |created: u64| {
+        chrono::Local
+            .timestamp_opt(created as i64, 0)
+            .single()
+            .filter(|_| created > 0)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "unknown time".to_string())
+    };
+    let mut report = if old.wg_ip == new.wg_ip {
+        format!(
+            "State of {} from {} to {}\n",
+            new.wg_ip,
+            time(old.created),
+            time(new.created)
+        )
+    } else {
+        format!(
+            "State of {} at {} and of {} at {}\n",
+            old.wg_ip,
+            time(old.created),
+            new.wg_ip,
+            time(new.created)
+        )
+    };
+    let changes = diff(old, new);
+    if changes.is_empty() {
+        report.push_str("No changes\n");
+    }
+    for change in changes {
+        report.push_str(&format!("{}\n", change));
+    }
+    report
+}
@@ -0,0 +1,146 @@
+// Machine-readable status of the running daemon for other tools.
+//
+// The json file is rewritten every INTERVAL seconds. It is written to a temporary
+// file first and then renamed, so readers never see a partially written file.
+// A consumer can detect a stale file (e.g. the daemon has died) via `generated`.
+//
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::configuration::StaticConfiguration;
+use crate::error::*;
+use crate::manager::{NetworkManager, PeerInfo, RouteWithdrawal};
+use crate::nat_telemetry::TraversalReport;
+use crate::routedb::RouteInfo;
+
+// Increased on incompatible changes of the file format
+pub const STATUS_FORMAT_VERSION: u32 = 1;
+const INTERVAL: u64 = 5;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Health {
+    // At least one node is reachable
+    pub connected: bool,
+    pub static_peers: usize,
+    pub static_peers_alive: usize,
+    pub alive_peers: usize,
+    pub decrypt_failures: u64,
+    // Static peers with a likely shared key mismatch
+    pub key_mismatch: Vec<IpAddr>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Status {
+    pub format_version: u32,
+    pub version: &'static str,
+    // Unix time of writing
+    pub generated: u64,
+    pub name: String,
+    pub network: Option<String>,
+    pub wg_name: String,
+    pub wg_ip: IpAddr,
+    pub subnet: String,
+    pub routedb_version: usize,
+    pub health: Health,
+    pub peers: Vec<PeerInfo>,
+    pub routes: Vec<RouteInfo>,
+    // Why routes have been deleted or replaced
+    pub route_withdrawals: Vec<RouteWithdrawal>,
+    pub nat_traversal: TraversalReport,
+}
+impl Status {
+    pub fn of(
+        now: u64,
+        network_manager: &NetworkManager,
+        static_config: &StaticConfiguration,
+    ) -> Self {
+        let peers = network_manager.peer_infos(now);
+        let decrypt_monitor = network_manager.decrypt_monitor();
+        let health = Health {
+            connected: peers.iter().any(|p| p.alive),
+            static_peers: peers
+                .iter()
+                .filter(|p| p.endpoint_class == "static")
+                .count(),
+            static_peers_alive: peers
+                .iter()
+                .filter(|p| p.endpoint_class == "static" && p.alive)
+                .count(),
+            alive_peers: peers.iter().filter(|p| p.alive).count(),
+            decrypt_failures: decrypt_monitor.total_failures(),
+            key_mismatch: decrypt_monitor.hints().iter().map(|h| h.wg_ip).collect(),
+        };
+        Status {
+            format_version: STATUS_FORMAT_VERSION,
+            version: env!("CARGO_PKG_VERSION"),
+            generated: now,
+            name: static_config.name.clone(),
+            network: static_config.network_name.clone(),
+            wg_name: static_config.wg_name.clone(),
+            wg_ip: static_config.wg_ip,
+            subnet: static_config.subnet.to_string(),
+            routedb_version: network_manager.db_version(),
+            health,
+            peers,
+            routes: network_manager.routes(),
+            route_withdrawals: network_manager.route_withdrawals(),
+            nat_traversal: network_manager.nat_telemetry().report(),
+        }
+    }
+}
+
+pub struct StatusFile {
+    path: String,
+    last_written: Option<u64>,
+}
+impl StatusFile {
+    pub fn new<T: Into<String>>(path: T) -> Self {
+        StatusFile {
+            path: path.into(),
+            last_written: None,
+        }
+    }
+    // Called every second. Only every INTERVAL seconds the file is written.
+    pub fn update(
+        &mut self,
+        now: u64,
+        network_manager: &NetworkManager,
+        static_config: &StaticConfiguration,
+    ) -> BoxResult<bool> {
+        if let Some(last_written) = self.last_written {
+            if now < last_written + INTERVAL {
+                return Ok(false);
+            }
+        }
+        // On error, retry only after INTERVAL, too
+        self.last_written = Some(now);
+        self.write(&Status::of(now, network_manager, static_config))?;
+        Ok(true)
+    }
+    pub fn write(&self, status: &Status) -> BoxResult<()> {
+        let path = Path::new(&self.path);
+        let tmp_path = path.with_file_name(format!(
+            ".{}.tmp",
+            path.file_name()
+                .ok_or("status file path has no file name")?
+                .to_string_lossy()
+        ));
+        let mut buf = serde_json::to_vec_pretty(status)?;
+        buf.push(b'\n');
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+    // The stale file is removed on exit
+    pub fn remove(&self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
@@ -0,0 +1,258 @@
+// A read-only html status page for the members of the mesh.
+//
+// The listener is bound to the wireguard ip only, so the page is not exposed to the
+// outside. It shows the same Status as the status file, rendered by the main loop
+// every INTERVAL seconds like the metrics. Each node shows its own view, e.g. of a
+// hub via http://10.1.1.1:<port>/.
+//
+use std::fmt::Write as _;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+use log::*;
+
+use crate::configuration::StaticConfiguration;
+use crate::error::*;
+use crate::http_server;
+use crate::manager::NetworkManager;
+use crate::status_file::Status;
+
+const INTERVAL: u64 = 5;
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn opt<T: ToString>(value: Option<T>) -> String {
+    value
+        .map(|v| escape(&v.to_string()))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn table(out: &mut String, header: &[&str], rows: Vec<Vec<String>>) {
+    out.push_str("<table>\n<tr>");
+    for column in header {
+        write!(out, "<th>{}</th>", column).unwrap();
+    }
+    out.push_str("</tr>\n");
+    for row in rows {
+        out.push_str("<tr>");
+        for cell in row {
+            write!(out, "<td>{}</td>", cell).unwrap();
+        }
+        out.push_str("</tr>\n");
+    }
+    out.push_str("</table>\n");
+}
+
+pub fn render(status: &Status) -> String {
+    let title = format!("{} ({})", escape(&status.name), status.wg_ip);
+    let mut out = String::new();
+    write!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta http-equiv=\"refresh\" content=\"{}\">\n<title>{}</title>\n\
+         <style>body {{ font-family: monospace; }} td, th {{ padding: 0 1em; text-align: left; }}</style>\n\
+         </head>\n<body>\n<h1>{}</h1>\n",
+        INTERVAL, title, title
+    )
+    .unwrap();
+    let health = &status.health;
+    writeln!(
+        out,
+        "<p>wg_netmanager {} on {}, network {}, subnet {}, routedb version {}</p>",
+        escape(status.version),
+        escape(&status.wg_name),
+        opt(status.network.as_ref()),
+        escape(&status.subnet),
+        status.routedb_version
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "<p>{}: {} of {} static peers and {} peers alive, {} decrypt failures</p>",
+        if health.connected {
+            "connected"
+        } else {
+            "not connected"
+        },
+        health.static_peers_alive,
+        health.static_peers,
+        health.alive_peers,
+        health.decrypt_failures
+    )
+    .unwrap();
+    if !health.key_mismatch.is_empty() {
+        let peers = health
+            .key_mismatch
+            .iter()
+            .map(|wg_ip| wg_ip.to_string())
+            .collect::<Vec<_>>();
+        writeln!(
+            out,
+            "<p>Likely shared key mismatch: {}</p>",
+            peers.join(", ")
+        )
+        .unwrap();
+    }
+
+    out.push_str("<h2>Peers</h2>\n");
+    let peers = status
+        .peers
+        .iter()
+        .map(|p| {
+            vec![
+                opt(p.name.as_ref()),
+                p.wg_ip.to_string(),
+                escape(&p.endpoint_class),
+                opt(p.endpoint),
+                if p.alive { "alive" } else { "dead" }.to_string(),
+                opt(p.lastseen_s_ago.map(|s| format!("{}s", s))),
+                opt(p.hop_cnt),
+                opt(p.gateway),
+                opt(p.rtt_ms.map(|ms| format!("{} ms", ms))),
+            ]
+        })
+        .collect();
+    table(
+        &mut out,
+        &[
+            "Name",
+            "Wireguard IP",
+            "Connection",
+            "Endpoint",
+            "State",
+            "Last seen",
+            "Hops",
+            "Gateway",
+            "RTT",
+        ],
+        peers,
+    );
+
+    out.push_str("<h2>Routes</h2>\n");
+    let routes = status
+        .routes
+        .iter()
+        .map(|ri| {
+            let nets = ri
+                .exported_nets
+                .iter()
+                .map(|net| net.to_string())
+                .collect::<Vec<_>>();
+            vec![
+                ri.to.to_string(),
+                ri.gateway
+                    .map(|gateway| gateway.to_string())
+                    .unwrap_or_else(|| "direct".to_string()),
+                ri.hop_cnt.to_string(),
+                ri.cost.to_string(),
+                if nets.is_empty() {
+                    "-".to_string()
+                } else {
+                    nets.join(", ")
+                },
+            ]
+        })
+        .collect();
+    table(
+        &mut out,
+        &["To", "Gateway", "Hops", "Cost", "Networks"],
+        routes,
+    );
+
+    out.push_str("<h2>Withdrawn routes</h2>\n");
+    let withdrawals = status
+        .route_withdrawals
+        .iter()
+        .map(|withdrawal| {
+            vec![
+                withdrawal.to.to_string(),
+                withdrawal
+                    .gateway
+                    .map(|gateway| gateway.to_string())
+                    .unwrap_or_else(|| "direct".to_string()),
+                if withdrawal.deleted {
+                    "deleted"
+                } else {
+                    "replaced"
+                }
+                .to_string(),
+                withdrawal.reason.to_string(),
+                format!("{}s ago", status.generated.saturating_sub(withdrawal.at)),
+            ]
+        })
+        .collect();
+    table(
+        &mut out,
+        &["To", "Gateway", "Change", "Reason", "When"],
+        withdrawals,
+    );
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+pub struct StatusPage {
+    page: Arc<Mutex<String>>,
+    local_addr: SocketAddr,
+    last_rendered: Option<u64>,
+}
+impl StatusPage {
+    // With a token, the browser needs to send it as bearer token
+    pub fn listen(addr: SocketAddr, token: Option<String>) -> BoxResult<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        info!(target: "status", "Serve the status page on http://{}/", local_addr);
+        let page = Arc::new(Mutex::new(String::new()));
+        let page_clone = page.clone();
+        http_server::listen(
+            listener,
+            "status",
+            "text/html; charset=utf-8",
+            token,
+            move |request| {
+                if request.method == "GET" && request.path == "/" {
+                    ("200 OK", page_clone.lock().unwrap().clone())
+                } else {
+                    ("404 Not Found", String::new())
+                }
+            },
+        );
+        Ok(StatusPage {
+            page,
+            local_addr,
+            last_rendered: None,
+        })
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+    // Called every second. Only every INTERVAL seconds the page is rendered.
+    pub fn update(
+        &mut self,
+        now: u64,
+        network_manager: &NetworkManager,
+        static_config: &StaticConfiguration,
+    ) -> bool {
+        if let Some(last_rendered) = self.last_rendered {
+            if now < last_rendered + INTERVAL {
+                return false;
+            }
+        }
+        self.last_rendered = Some(now);
+        let status = Status::of(now, network_manager, static_config);
+        *self.page.lock().unwrap() = render(&status);
+        true
+    }
+}
@@ -0,0 +1,133 @@
+// Supervision by systemd.
+//
+// The unit file written by `install` has Type=notify and a watchdog. The daemon reports
+// READY=1 via $NOTIFY_SOCKET, when the main loop is entered, and STOPPING=1 on
+// shutdown. While the main loop is running, it pings the watchdog every half of
+// WATCHDOG_USEC, so a hanging main loop is restarted by systemd. Without
+// $NOTIFY_SOCKET, e.g. started from a shell, nothing is sent.
+//
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::Path;
+
+use log::*;
+
+use crate::configuration::StaticConfiguration;
+use crate::error::*;
+use crate::util::{absolute_path, install_file};
+
+pub const UNIT_FILE: &str = "/etc/systemd/system/wg_netmanager.service";
+pub const WATCHDOG_SEC: u64 = 30;
+
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    // in seconds, half of WATCHDOG_USEC
+    watchdog_interval: Option<u64>,
+    last_ping: u64,
+}
+impl Notifier {
+    pub fn from_env() -> Option<Self> {
+        let notify_socket = std::env::var("NOTIFY_SOCKET").ok()?;
+        // The watchdog may be meant for another process
+        let for_me = std::env::var("WATCHDOG_PID")
+            .map(|pid| pid == std::process::id().to_string())
+            .unwrap_or(true);
+        let watchdog_usec = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .filter(|_| for_me)
+            .and_then(|usec| usec.parse().ok());
+        Notifier::new(&notify_socket, watchdog_usec)
+            .map_err(|e| warn!(target: "systemd", "Cannot notify systemd: {}", e))
+            .ok()
+    }
+    // A leading @ denotes an abstract socket
+    pub fn new(notify_socket: &str, watchdog_usec: Option<u64>) -> BoxResult<Self> {
+        let addr = match notify_socket.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(notify_socket)?,
+        };
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog_interval: watchdog_usec.map(|usec| (usec / 2_000_000).max(1)),
+            last_ping: 0,
+        })
+    }
+    pub fn watchdog_interval(&self) -> Option<u64> {
+        self.watchdog_interval
+    }
+    pub fn notify(&self, state: &str) -> BoxResult<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+    pub fn ready(&self) {
+        if let Err(e) = self.notify("READY=1") {
+            warn!(target: "systemd", "Cannot notify systemd: {}", e);
+        }
+    }
+    pub fn stopping(&self) {
+        if let Err(e) = self.notify("STOPPING=1") {
+            warn!(target: "systemd", "Cannot notify systemd: {}", e);
+        }
+    }
+    // Called every second. Returns true, if the watchdog has been pinged.
+    pub fn ping_watchdog(&mut self, now: u64) -> bool {
+        let interval = match self.watchdog_interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+        if now < self.last_ping + interval {
+            return false;
+        }
+        self.last_ping = now;
+        if let Err(e) = self.notify("WATCHDOG=1") {
+            warn!(target: "systemd", "Cannot ping the watchdog: {}", e);
+        }
+        true
+    }
+}
+
+pub fn unit_file(static_config: &StaticConfiguration, exe: &str, kill: Option<&str>) -> String {
+    let network_yaml = absolute_path(&static_config.network_yaml_filename);
+    let peer_yaml = static_config
+        .peer_yaml_filename
+        .as_deref()
+        .map(absolute_path);
+
+    let mut lines: Vec<String> = vec![];
+    lines.push("[Unit]".to_string());
+    lines.push("Description=The Wireguard network manager".to_string());
+    lines.push(format!("ConditionPathExists={}", network_yaml));
+    let mut exec_start = format!("{} -c {}", exe, network_yaml);
+    if let Some(fname) = peer_yaml.as_ref() {
+        lines.push(format!("ConditionPathExists={}", fname));
+        exec_start.push_str(&format!(" -p {}", fname));
+    }
+    lines.push("After=network.target".to_string());
+    lines.push("".to_string());
+    lines.push("[Service]".to_string());
+    lines.push("Type=notify".to_string());
+    lines.push("NotifyAccess=main".to_string());
+    lines.push(format!("ExecStart={}", exec_start));
+    if let Some(kill) = kill {
+        lines.push(format!("ExecStop={} -HUP $MAINPID", kill));
+    }
+    lines.push(format!("WatchdogSec={}", WATCHDOG_SEC));
+    lines.push("Restart=always".to_string());
+    lines.push("RestartSec=1".to_string());
+    lines.push("".to_string());
+    lines.push("[Install]".to_string());
+    lines.push("WantedBy=multi-user.target".to_string());
+    lines.push("".to_string());
+    lines.join("\n")
+}
+
+pub fn install_unit_file(
+    path: &Path,
+    content: &str,
+    force: bool,
+    dry_run: bool,
+) -> BoxResult<bool> {
+    install_file(path, content, force, dry_run)
+}
@@ -0,0 +1,526 @@
+// Simulation of a mesh in one process, e.g. for tests of the convergence under packet
+// loss, latency, NAT and partitions.
+//
+// Each SimNode runs a NetworkManager like the run loop does, but with a
+// MockWireguardDevice and a MemoryUdp socket on a shared MemoryNetwork. The Simulator
+// advances the mesh clock in ticks of 100ms without waiting, so minutes of the mesh
+// pass within a second. The conditions of the links are set per pair of nodes. The
+// losses are derived from the seed, so a failing run can be reproduced.
+//
+// A route is correct, if the gateways lead hop by hop to the destination, and on each
+// hop the wireguard configuration sends the packets to the next node.
+//
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+
+use log::*;
+
+use crate::configuration::*;
+use crate::crypt_udp::UdpPacket;
+use crate::error::*;
+use crate::event::Event;
+use crate::manager::*;
+use crate::memory_udp::*;
+use crate::wg_dev::*;
+
+pub const ADMIN_PORT: u16 = 50501;
+pub const WG_PORT: u16 = 50001;
+
+const TICK_MS: u64 = 100;
+
+// A node as run by the run loop, but with the device and the socket in memory
+pub struct SimNode {
+    pub static_config: StaticConfiguration,
+    pub mgr: NetworkManager,
+    pub wg_dev: MockWireguardDevice,
+    // None after stop
+    socket: Option<MemoryUdp>,
+    events: VecDeque<Event>,
+}
+impl SimNode {
+    pub fn new(network: &MemoryNetwork, static_config: StaticConfiguration) -> BoxResult<Self> {
+        let underlay = *static_config
+            .ip_list
+            .first()
+            .ok_or("a node needs an underlay address")?;
+        let mut wg_dev = MockWireguardDevice::new();
+        wg_dev.create_device()?;
+        wg_dev.set_ip(
+            &static_config.wg_ip,
+            &static_config.subnet,
+            static_config.nat_probe_net(),
+        )?;
+        let socket = MemoryUdp::bind(network, underlay, static_config.admin_port)?
+            .key(&static_config.shared_key)?
+            .tunnel(&wg_dev);
+        Ok(SimNode {
+            mgr: NetworkManager::new(&static_config),
+            static_config,
+            wg_dev,
+            socket: Some(socket),
+            events: VecDeque::from(vec![Event::UpdateWireguardConfiguration]),
+        })
+    }
+    pub fn wg_ip(&self) -> IpAddr {
+        self.static_config.wg_ip
+    }
+    pub fn public_key(&self) -> &str {
+        &self.static_config.my_public_key.key
+    }
+    pub fn is_up(&self) -> bool {
+        self.socket.is_some()
+    }
+    // The node vanishes without goodbye, like on power loss
+    pub fn stop(&mut self) {
+        self.socket = None;
+        self.events.clear();
+        let _ = self.wg_dev.take_down_device();
+    }
+    // The gateway to wg_ip, None if there is no route
+    pub fn route_to(&self, now: u64, wg_ip: IpAddr) -> Option<Option<IpAddr>> {
+        self.mgr
+            .peer_infos(now)
+            .into_iter()
+            .find(|p| p.wg_ip == wg_ip && p.hop_cnt.is_some())
+            .map(|p| p.gateway)
+    }
+    pub fn send(&mut self, packet: &UdpPacket, to: SocketAddr) {
+        if let Some(socket) = self.socket.as_mut() {
+            let buf = bincode::serialize(packet).unwrap();
+            if let Err(e) = socket.send_to(&buf, to) {
+                debug!(target: "udp", "Cannot send to {}: {}", to, e);
+            }
+        }
+    }
+    pub fn every_second(&mut self, now: u64) {
+        if self.is_up() {
+            let events = self
+                .mgr
+                .process_all_nodes_every_second(now, &self.static_config);
+            self.events.extend(events);
+        }
+    }
+    // latest_handshakes as the run loop does every 30s
+    pub fn check_handshakes(&mut self, now: u64) {
+        if self.is_up() {
+            let latest_handshakes = self
+                .wg_dev
+                .latest_handshakes()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(key, at)| (key, crate::util::from_wall_time(at)))
+                .collect::<HashMap<_, _>>();
+            let events = self
+                .mgr
+                .check_handshakes(now, &self.static_config, &latest_handshakes);
+            self.events.extend(events);
+        }
+    }
+    // Processes the received packets and the events, returns true if anything has been done
+    pub fn step(&mut self, now: u64) -> bool {
+        let mut busy = false;
+        let mut buf = vec![0; 65536];
+        loop {
+            let received = match self.socket.as_ref() {
+                Some(socket) => socket.try_recv_from(&mut buf),
+                None => return false,
+            };
+            match received {
+                Ok(Some((len, src_addr))) => {
+                    busy = true;
+                    match UdpPacket::decode(&buf[..len]) {
+                        Ok(packet) => {
+                            let events = self.dispatch(now, packet, src_addr);
+                            self.events.extend(events);
+                        }
+                        Err(e) => debug!(target: "udp", "Invalid packet from {}: {}", src_addr, e),
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    busy = true;
+                    debug!(target: "udp", "Cannot receive: {}", e);
+                }
+            }
+        }
+        while let Some(evt) = self.events.pop_front() {
+            busy = true;
+            self.process(now, evt);
+        }
+        busy
+    }
+    fn dispatch(&mut self, now: u64, packet: UdpPacket, src_addr: SocketAddr) -> Vec<Event> {
+        self.mgr.decrypt_success(now, src_addr);
+        match packet {
+            UdpPacket::Advertisement(ad) => {
+                self.mgr
+                    .analyze_advertisement(now, &self.static_config, ad, src_addr)
+            }
+            UdpPacket::RouteDatabaseRequest => vec![Event::SendRouteDatabase {
+                to: src_addr,
+                since: None,
+            }],
+            UdpPacket::RouteDatabaseDeltaRequest { since } => vec![Event::SendRouteDatabase {
+                to: src_addr,
+                since: Some(since),
+            }],
+            UdpPacket::RouteDatabase(db) => self.mgr.process_route_database(db).unwrap_or_default(),
+            UdpPacket::RouteDatabaseDelta(delta) => self
+                .mgr
+                .process_route_database_delta(delta)
+                .unwrap_or_default(),
+            UdpPacket::LocalContactRequest => {
+                let mut events = vec![Event::SendLocalContact { to: src_addr }];
+                events.append(&mut self.mgr.local_contact_requested(now, src_addr));
+                events
+            }
+            UdpPacket::LocalContact(contact) => self.mgr.process_local_contact(now, contact),
+            UdpPacket::Ping { sent_ms } => vec![Event::SendPong {
+                to: src_addr,
+                sent_ms,
+            }],
+            UdpPacket::Pong { .. } => self.mgr.process_pong(src_addr.ip(), 1),
+            UdpPacket::Goodbye => self.mgr.process_goodbye(&self.static_config, src_addr),
+            UdpPacket::ConnectRequest(request) => self.mgr.process_connect_request(now, request),
+            _ => vec![],
+        }
+    }
+    fn process(&mut self, now: u64, evt: Event) {
+        match evt {
+            Event::SendAdvertisement {
+                addressed_to,
+                to,
+                wg_ip,
+            } => {
+                let routedb_version = self.mgr.db_version();
+                let my_visible_wg_endpoint = self.mgr.my_visible_wg_endpoint;
+                let my_local_wg_port = self.mgr.my_local_wg_port;
+                let force_relay_s = self.mgr.force_relay_request(now, &wg_ip);
+                let revocations = self.mgr.revocations_to_gossip(now);
+                let opt_node = self.mgr.node_for(&wg_ip);
+                let ad = UdpPacket::advertisement_from_config(
+                    &self.static_config,
+                    routedb_version,
+                    addressed_to,
+                    opt_node,
+                    my_local_wg_port,
+                    my_visible_wg_endpoint,
+                    force_relay_s,
+                    revocations,
+                );
+                self.send(&ad, to);
+            }
+            Event::SendRouteDatabaseRequest { to } => {
+                let request = match self.mgr.known_routedb_version(&to.ip()) {
+                    Some(since) => UdpPacket::route_database_delta_request(since),
+                    None => UdpPacket::route_database_request(),
+                };
+                self.send(&request, to);
+            }
+            Event::SendRouteDatabase { to, since } => {
+                let packets = match since {
+                    Some(since) => self.mgr.provide_route_database_delta(to.ip(), since),
+                    None => self.mgr.provide_route_database(to.ip()),
+                };
+                for p in packets {
+                    self.send(&p, to);
+                }
+            }
+            Event::SendLocalContactRequest { to } => {
+                self.send(&UdpPacket::local_contact_request(), to)
+            }
+            Event::SendLocalContact { to } => {
+                let local_contact = UdpPacket::local_contact_from_config(
+                    &self.static_config,
+                    self.mgr.my_local_wg_port,
+                    self.mgr.my_visible_wg_endpoint,
+                );
+                self.send(&local_contact, to);
+            }
+            Event::SendLocalContactOf { wg_ip, to } => {
+                if let Some(local) = self.mgr.local_contact_of(&wg_ip) {
+                    let local_contact = UdpPacket::LocalContact(local.clone());
+                    self.send(&local_contact, to);
+                }
+            }
+            Event::SendPing { to } => self.send(&UdpPacket::Ping { sent_ms: 0 }, to),
+            Event::SendPong { to, sent_ms } => self.send(&UdpPacket::Pong { sent_ms }, to),
+            Event::SendConnectRequest { wg_ip } => {
+                if let Some((to, request)) = self.mgr.connect_request(&wg_ip) {
+                    self.send(&request, to);
+                }
+            }
+            Event::ForwardConnectRequest { to, request } => {
+                self.send(&UdpPacket::ConnectRequest(request), to)
+            }
+            Event::UpdateWireguardConfiguration => {
+                let conf = self.static_config.to_wg_configuration(&self.mgr);
+                self.wg_dev.sync_conf(&conf).unwrap();
+            }
+            Event::ReadWireguardConfiguration => {
+                let endpoints = self.wg_dev.retrieve_conf().unwrap();
+                self.mgr.current_wireguard_configuration(endpoints);
+            }
+            Event::UpdateRoutes => {
+                for rc in self.mgr.get_route_changes() {
+                    use RouteChange::*;
+                    let result = match rc {
+                        AddRoute {
+                            to,
+                            gateway,
+                            attributes,
+                        } => self.wg_dev.add_route(to, gateway, &attributes),
+                        ReplaceRoute {
+                            to,
+                            gateway,
+                            attributes,
+                            ..
+                        } => self.wg_dev.replace_route(to, gateway, &attributes),
+                        DelRoute {
+                            to,
+                            gateway,
+                            attributes,
+                            ..
+                        } => self.wg_dev.del_route(to, gateway, &attributes),
+                        AddNetRoute { net } => self.wg_dev.add_net_route(net),
+                        DelNetRoute { net } => self.wg_dev.del_net_route(net),
+                    };
+                    // The manager must not add a route twice or delete an unknown one
+                    result.expect("route change");
+                }
+                self.events.push_back(Event::UpdateWireguardConfiguration);
+            }
+            _ => {}
+        }
+    }
+}
+
+// The nodes share the subnet 10.1.1.0/24 and the shared key
+pub struct Simulator {
+    network: MemoryNetwork,
+    nodes: Vec<SimNode>,
+    static_peers: HashMap<IpAddr, PublicPeer>,
+    start: u64,
+    now_ms: u64,
+}
+impl Simulator {
+    pub fn new(seed: u64) -> Self {
+        let start = crate::util::now();
+        let network = MemoryNetwork::with_seed(seed);
+        network.advance_to(start * 1000);
+        Simulator {
+            network,
+            nodes: vec![],
+            static_peers: HashMap::new(),
+            start,
+            now_ms: start * 1000,
+        }
+    }
+    pub fn network(&self) -> &MemoryNetwork {
+        &self.network
+    }
+    // The mesh clock in seconds
+    pub fn now(&self) -> u64 {
+        self.now_ms / 1000
+    }
+    // Seconds since the start of the simulation
+    pub fn elapsed(&self) -> u64 {
+        self.now() - self.start
+    }
+    pub fn node(&self, i: usize) -> &SimNode {
+        &self.nodes[i]
+    }
+    pub fn node_mut(&mut self, i: usize) -> &mut SimNode {
+        &mut self.nodes[i]
+    }
+    pub fn nodes(&self) -> &[SimNode] {
+        &self.nodes
+    }
+    // A node knows the static peers, which have been added before, and itself
+    pub fn add_static_node(&mut self, name: &str, wg_ip: IpAddr, underlay: IpAddr) -> usize {
+        self.static_peers.insert(
+            wg_ip,
+            PublicPeer {
+                endpoint: SocketAddr::new(underlay, WG_PORT).to_string(),
+                wg_port: WG_PORT,
+                admin_port: ADMIN_PORT,
+                wg_ip,
+                identity: None,
+                tier: 1,
+                resolver: Default::default(),
+            },
+        );
+        self.add_node(name, wg_ip, underlay)
+    }
+    pub fn add_node(&mut self, name: &str, wg_ip: IpAddr, underlay: IpAddr) -> usize {
+        let static_config = self.config(name, wg_ip, underlay);
+        let node = SimNode::new(&self.network, static_config).unwrap();
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+    fn config(&self, name: &str, wg_ip: IpAddr, underlay: IpAddr) -> StaticConfiguration {
+        let (private_key, public_key) = crate::crypto::generate_key_pair();
+        StaticConfiguration::builder()
+            .name(name)
+            .ip_list(vec![underlay])
+            .wg_ip(wg_ip)
+            .wg_name("wgsim")
+            .wg_port(WG_PORT)
+            .wg_hopping(false)
+            .admin_port(ADMIN_PORT)
+            .subnet("10.1.1.0/24".parse().unwrap())
+            .shared_key(vec![7; 32])
+            .my_private_key(private_key)
+            .my_public_key(PublicKeyWithTime {
+                key: public_key,
+                priv_key_creation_time: 10,
+            })
+            .peers(self.static_peers.clone())
+            .use_tui(false)
+            .use_existing_interface(false)
+            .network_yaml_filename("network.yaml")
+            .build()
+    }
+    fn underlay(&self, i: usize) -> IpAddr {
+        self.nodes[i].static_config.ip_list[0]
+    }
+    // The nodes reach the others via public_ip
+    pub fn add_nat(&mut self, public_ip: IpAddr, nat_type: NatType, nodes: &[usize]) {
+        let inside = nodes.iter().map(|i| self.underlay(*i)).collect::<Vec<_>>();
+        self.network.add_nat(public_ip, nat_type, &inside);
+    }
+    // For all links without own conditions
+    pub fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.network.set_conditions(conditions);
+    }
+    pub fn set_link(&mut self, a: usize, b: usize, conditions: LinkConditions) {
+        self.network
+            .set_link(self.underlay(a), self.underlay(b), conditions);
+    }
+    // The links between the groups are down. Nodes in no group keep their links.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        for (n, group) in groups.iter().enumerate() {
+            for other in groups.iter().skip(n + 1) {
+                for a in group.iter() {
+                    for b in other.iter() {
+                        self.set_link(*a, *b, LinkConditions::down());
+                    }
+                }
+            }
+        }
+    }
+    // Removes all conditions of single links, e.g. of a partition
+    pub fn heal(&mut self) {
+        self.network.clear_links();
+    }
+    pub fn stop_node(&mut self, i: usize) {
+        self.nodes[i].stop();
+    }
+    pub fn run_for(&mut self, seconds: u64) {
+        for _ in 0..seconds * 1000 / TICK_MS {
+            self.tick();
+        }
+    }
+    // Returns the seconds until all routes are correct, None if not within max_seconds
+    pub fn run_until_converged(&mut self, max_seconds: u64) -> Option<u64> {
+        for elapsed in 0..=max_seconds {
+            if self.is_converged() {
+                return Some(elapsed);
+            }
+            if elapsed < max_seconds {
+                self.run_for(1);
+            }
+        }
+        None
+    }
+    fn tick(&mut self) {
+        self.now_ms += TICK_MS;
+        self.network.advance_to(self.now_ms);
+        let now = self.now();
+        if self.now_ms.is_multiple_of(1000) {
+            let check_handshakes = (now - self.start) % 30 == 17;
+            for node in self.nodes.iter_mut() {
+                node.every_second(now);
+                if check_handshakes {
+                    node.check_handshakes(now);
+                }
+            }
+        }
+        for _ in 0..100 {
+            let mut busy = false;
+            for node in self.nodes.iter_mut() {
+                busy |= node.step(now);
+            }
+            if !busy {
+                break;
+            }
+        }
+    }
+    pub fn is_converged(&self) -> bool {
+        self.route_errors().is_empty()
+    }
+    // Every running node has a correct route to every other running node and none
+    // to the stopped nodes
+    pub fn route_errors(&self) -> Vec<String> {
+        let now = self.now();
+        let by_wg_ip = self
+            .nodes
+            .iter()
+            .filter(|node| node.is_up())
+            .map(|node| (node.wg_ip(), node))
+            .collect::<HashMap<_, _>>();
+        let mut errors = vec![];
+        for from in by_wg_ip.values() {
+            for to in by_wg_ip.keys().filter(|to| **to != from.wg_ip()) {
+                if let Err(e) = Self::check_path(now, &by_wg_ip, from, *to) {
+                    errors.push(format!("{} to {}: {}", from.wg_ip(), to, e));
+                }
+            }
+        }
+        // The routes to stopped nodes must be gone
+        for from in by_wg_ip.values() {
+            for stopped in self.nodes.iter().filter(|node| !node.is_up()) {
+                if from.route_to(now, stopped.wg_ip()).is_some() {
+                    errors.push(format!(
+                        "{} to {}: route to stopped node",
+                        from.wg_ip(),
+                        stopped.wg_ip()
+                    ));
+                }
+            }
+        }
+        errors.sort();
+        errors
+    }
+    fn check_path(
+        now: u64,
+        by_wg_ip: &HashMap<IpAddr, &SimNode>,
+        from: &SimNode,
+        to: IpAddr,
+    ) -> Result<(), String> {
+        let mut node = from;
+        let mut visited = HashSet::new();
+        while node.wg_ip() != to {
+            if !visited.insert(node.wg_ip()) {
+                return Err(format!("loop at {}", node.wg_ip()));
+            }
+            let next = match node.route_to(now, to) {
+                Some(gateway) => gateway.unwrap_or(to),
+                None => return Err(format!("no route at {}", node.wg_ip())),
+            };
+            let next_node = by_wg_ip
+                .get(&next)
+                .ok_or_else(|| format!("gateway {} of {} is down", next, node.wg_ip()))?;
+            let state = node.wg_dev.state();
+            if state.peer_for(&to).as_deref() != Some(next_node.public_key()) {
+                return Err(format!(
+                    "wireguard of {} does not send to {}",
+                    node.wg_ip(),
+                    next
+                ));
+            }
+            node = next_node;
+        }
+        Ok(())
+    }
+}
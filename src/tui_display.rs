@@ -1,7 +1,9 @@
+use std::collections::{BTreeMap, HashMap};
 use std::io;
-use std::sync::mpsc;
+use std::net::{IpAddr, SocketAddr};
 use std::thread;
 
+use chrono::TimeZone;
 use log::*;
 
 use crossterm::event::{read, Event, KeyCode};
@@ -16,19 +18,278 @@ use tui::backend::CrosstermBackend;
 use tui::layout::{Constraint, Direction, Layout, Rect};
 use tui::style::{Color, Modifier, Style};
 use tui::text::{Span, Spans};
-use tui::widgets::{Block, Borders, Tabs};
+use tui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState, Tabs};
 use tui::Frame;
 use tui::Terminal;
 use tui_logger::*;
 
+use crate::alert::{Alert, Severity};
+use crate::configuration::StaticConfiguration;
+use crate::crypt_udp::AddressedTo;
 use crate::error::*;
 use crate::event;
+use crate::manager::{NetworkManager, PeerInfo};
+use crate::routedb::RouteInfo;
+
+// First tab shows all log targets. The others only the nodes of the given
+// connection classes (see Node::endpoint_class()).
+const CLASS_TABS: [(&str, &[&str]); 4] = [
+    ("static", &["static"]),
+    ("local", &["local"]),
+    ("dynamic/NAT", &["dynamic", "passive"]),
+    ("distant/relayed", &["distant"]),
+];
+// The last two tabs show the table of the peers and of the routes instead of the log
+const PEERS_TAB: &str = "peers";
+const ROUTES_TAB: &str = "routes";
+const PEER_COLUMNS: [(&str, u16); 9] = [
+    ("name", 16),
+    ("wg_ip", 16),
+    ("type", 10),
+    ("endpoint", 24),
+    ("handshake", 10),
+    ("hops", 5),
+    ("gateway", 16),
+    ("rx", 9),
+    ("tx", 9),
+];
+const ROUTE_COLUMNS: [(&str, u16); 4] = [("to", 16), ("gateway", 16), ("hops", 5), ("metric", 7)];
+// Beyond this, the alerts pane is truncated
+const MAX_ALERT_LINES: usize = 5;
+// Beyond this, the latency pane is truncated
+const MAX_LATENCY_LINES: usize = 5;
 
 pub struct TuiApp {
     terminal: Option<Terminal<CrosstermBackend<io::Stdout>>>,
     states: Vec<TuiWidgetState>,
     tabs: Vec<String>,
     selected_tab: usize,
+    node_tab: HashMap<IpAddr, usize>,
+    title: String,
+    alerts: Vec<Alert>,
+    // Name or wg_ip and round trip time of the direct peers
+    latencies: Vec<(String, u32)>,
+    peers: Vec<PeerRow>,
+    peer_table: TableState,
+    routes: Vec<RouteRow>,
+    topology: Vec<String>,
+}
+
+// A line of the peers tab
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerRow {
+    pub name: Option<String>,
+    pub wg_ip: IpAddr,
+    pub endpoint_class: String,
+    pub endpoint: Option<SocketAddr>,
+    pub handshake_s_ago: Option<u64>,
+    // None, if there is no route
+    pub hop_cnt: Option<usize>,
+    pub gateway: Option<IpAddr>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+impl PeerRow {
+    // The handshakes in mesh time and the transfer per public key as reported by the
+    // wireguard device
+    pub fn of(
+        now: u64,
+        peers: Vec<PeerInfo>,
+        handshakes: &HashMap<String, u64>,
+        transfer: &HashMap<String, (u64, u64)>,
+    ) -> Vec<PeerRow> {
+        peers
+            .into_iter()
+            .map(|peer| {
+                let key = peer.public_key.as_deref().unwrap_or_default();
+                let (rx_bytes, tx_bytes) = transfer.get(key).copied().unwrap_or_default();
+                PeerRow {
+                    handshake_s_ago: handshakes
+                        .get(key)
+                        .filter(|at| **at > 0)
+                        .map(|at| now.saturating_sub(*at)),
+                    name: peer.name,
+                    wg_ip: peer.wg_ip,
+                    endpoint_class: peer.endpoint_class,
+                    endpoint: peer.endpoint,
+                    hop_cnt: peer.hop_cnt,
+                    gateway: peer.gateway,
+                    rx_bytes,
+                    tx_bytes,
+                }
+            })
+            .collect()
+    }
+    // The cells in the order of the columns
+    pub fn cells(&self) -> Vec<String> {
+        let or_dash = |s: Option<String>| s.unwrap_or_else(|| "-".to_string());
+        vec![
+            or_dash(self.name.clone()),
+            self.wg_ip.to_string(),
+            self.endpoint_class.clone(),
+            or_dash(self.endpoint.map(|e| e.to_string())),
+            or_dash(self.handshake_s_ago.map(|s| format!("{}s", s))),
+            or_dash(self.hop_cnt.map(|h| h.to_string())),
+            or_dash(self.gateway.map(|g| g.to_string())),
+            format_bytes(self.rx_bytes),
+            format_bytes(self.tx_bytes),
+        ]
+    }
+}
+
+// A line of the routes tab
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteRow {
+    pub to: IpAddr,
+    pub gateway: Option<IpAddr>,
+    pub hop_cnt: usize,
+    pub cost: u32,
+}
+impl RouteRow {
+    pub fn of(routes: &[RouteInfo]) -> Vec<RouteRow> {
+        routes
+            .iter()
+            .map(|ri| RouteRow {
+                to: ri.to,
+                gateway: ri.gateway,
+                hop_cnt: ri.hop_cnt,
+                cost: ri.cost,
+            })
+            .collect()
+    }
+    pub fn cells(&self) -> Vec<String> {
+        vec![
+            self.to.to_string(),
+            self.gateway
+                .map(|g| g.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            self.hop_cnt.to_string(),
+            self.cost.to_string(),
+        ]
+    }
+}
+
+// The nodes reached via each gateway as tree below this node, e.g.
+//
+//    10.1.1.2
+//    +- 10.1.1.1
+//    |  `- 10.1.1.3
+//    `- 10.1.1.4
+//
+pub fn topology(my_wg_ip: IpAddr, routes: &[RouteInfo]) -> Vec<String> {
+    let mut via: BTreeMap<IpAddr, Vec<IpAddr>> = BTreeMap::new();
+    for ri in routes.iter().filter(|ri| ri.to != my_wg_ip) {
+        match ri.gateway {
+            None => via.entry(ri.to).or_default(),
+            Some(gateway) => {
+                let nodes = via.entry(gateway).or_default();
+                nodes.push(ri.to);
+                nodes
+            }
+        };
+    }
+    let mut lines = vec![my_wg_ip.to_string()];
+    let last_gateway = via.len().saturating_sub(1);
+    for (i, (gateway, mut nodes)) in via.into_iter().enumerate() {
+        let (branch, indent) = if i == last_gateway {
+            ("`-", "   ")
+        } else {
+            ("+-", "|  ")
+        };
+        lines.push(format!("{} {}", branch, gateway));
+        nodes.sort();
+        let last_node = nodes.len().saturating_sub(1);
+        for (j, node) in nodes.into_iter().enumerate() {
+            let branch = if j == last_node { "`-" } else { "+-" };
+            lines.push(format!("{}{} {}", indent, branch, node));
+        }
+    }
+    lines
+}
+
+// With binary prefixes and one decimal, e.g. 1.5M
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+// Keys on the peers tab for the selected peer
+const PEER_ACTION_KEYS: [(char, PeerAction); 4] = [
+    ('a', PeerAction::Advertise),
+    ('r', PeerAction::RequestRouteDatabase),
+    ('d', PeerAction::Drop),
+    ('e', PeerAction::ResolveEndpoint),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAction {
+    Advertise,
+    RequestRouteDatabase,
+    // Forget the peer like the control method drop-peer. It is added again with its
+    // next advertisement.
+    Drop,
+    // Only for static peers with a hostname as endpoint
+    ResolveEndpoint,
+}
+impl PeerAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PeerAction::Advertise => "advertise",
+            PeerAction::RequestRouteDatabase => "request routes",
+            PeerAction::Drop => "drop",
+            PeerAction::ResolveEndpoint => "resolve endpoint",
+        }
+    }
+    // The events to be processed by the run loop
+    pub fn events(
+        &self,
+        wg_ip: IpAddr,
+        network_manager: &mut NetworkManager,
+        static_config: &StaticConfiguration,
+    ) -> Vec<event::Event> {
+        use crate::event::Event;
+        let Some(node) = network_manager.all_nodes.get(&wg_ip) else {
+            warn!(target: &wg_ip.to_string(), "{}: unknown peer", self.name());
+            return vec![];
+        };
+        info!(target: &wg_ip.to_string(), "{} requested via the tui", self.name());
+        let to = SocketAddr::new(wg_ip, node.local_admin_port());
+        match self {
+            PeerAction::Advertise => vec![Event::SendAdvertisement {
+                addressed_to: AddressedTo::WireguardAddress,
+                to,
+                wg_ip,
+            }],
+            PeerAction::RequestRouteDatabase if node.routedb_manager().is_none() => {
+                warn!(target: &wg_ip.to_string(), "{}: no direct peer", self.name());
+                vec![]
+            }
+            PeerAction::RequestRouteDatabase => vec![Event::SendRouteDatabaseRequest { to }],
+            PeerAction::Drop => {
+                if network_manager.drop_peer(&wg_ip, static_config) {
+                    vec![Event::UpdateRoutes]
+                } else {
+                    vec![]
+                }
+            }
+            PeerAction::ResolveEndpoint => {
+                let events = node.resolve_endpoint();
+                if events.is_empty() {
+                    warn!(target: &wg_ip.to_string(), "{}: no endpoint to resolve", self.name());
+                }
+                events
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -47,18 +308,41 @@ pub enum TuiAppEvent {
     FocusKey,
     TabKey,
     BackTabKey,
+    CharKey(char),
 }
 
 impl TuiApp {
+    // Without a terminal nothing is drawn, but the keys are processed as usual
     pub fn off() -> Self {
+        Self::with_terminal(None)
+    }
+    fn with_terminal(terminal: Option<Terminal<CrosstermBackend<io::Stdout>>>) -> Self {
+        let mut tabs = vec!["all".to_string()];
+        let mut states = vec![TuiWidgetState::new().set_default_display_level(LevelFilter::Info)];
+        for (name, _) in CLASS_TABS.iter() {
+            tabs.push(name.to_string());
+            states.push(TuiWidgetState::new().set_default_display_level(LevelFilter::Off));
+        }
+        for tab in [PEERS_TAB, ROUTES_TAB] {
+            tabs.push(tab.to_string());
+            states.push(TuiWidgetState::new().set_default_display_level(LevelFilter::Off));
+        }
         TuiApp {
-            terminal: None,
-            states: vec![],
-            tabs: vec![],
+            terminal,
+            states,
+            tabs,
             selected_tab: 0,
+            node_tab: HashMap::new(),
+            title: String::new(),
+            alerts: vec![],
+            latencies: vec![],
+            peers: vec![],
+            peer_table: TableState::default(),
+            routes: vec![],
+            topology: vec![],
         }
     }
-    pub fn init(tx: mpsc::Sender<event::Event>) -> BoxResult<Self> {
+    pub fn init(tx: event::EventSender) -> BoxResult<Self> {
         enable_raw_mode()?;
         let mut stdout = io::stdout();
         execute!(
@@ -126,21 +410,16 @@ impl TuiApp {
                         KeyCode::BackTab => {
                             tx.send(TuiApp(BackTabKey)).unwrap();
                         }
+                        KeyCode::Char(c) if PEER_ACTION_KEYS.iter().any(|(key, _)| *key == c) => {
+                            tx.send(TuiApp(CharKey(c))).unwrap();
+                        }
                         _ => {}
                     }
                 }
             }
         });
 
-        Ok(TuiApp {
-            terminal: Some(terminal),
-            states: vec![],
-            tabs: vec!["1", "2", "3", "4"]
-                .into_iter()
-                .map(|t| t.into())
-                .collect(),
-            selected_tab: 0,
-        })
+        Ok(Self::with_terminal(Some(terminal)))
     }
     pub fn deinit(&mut self) -> BoxResult<()> {
         if let Some(terminal) = self.terminal.as_mut() {
@@ -155,8 +434,64 @@ impl TuiApp {
         }
         Ok(())
     }
-    pub fn process_event(&mut self, evt: TuiAppEvent) {
+    // Move the log target of each node to the tab of its current connection class
+    pub fn update_node_classes(&mut self, classes: Vec<(IpAddr, &'static str)>) {
+        if self.terminal.is_none() {
+            return;
+        }
+        for (wg_ip, class) in classes {
+            let tab = match CLASS_TABS.iter().position(|(_, cl)| cl.contains(&class)) {
+                Some(pos) => pos + 1,
+                None => continue,
+            };
+            let old_tab = self.node_tab.insert(wg_ip, tab);
+            if old_tab == Some(tab) {
+                continue;
+            }
+            let target = wg_ip.to_string();
+            if let Some(old_tab) = old_tab {
+                self.set_level_for_target(old_tab, &target, LevelFilter::Off);
+            }
+            self.set_level_for_target(tab, &target, LevelFilter::Info);
+        }
+    }
+    fn set_level_for_target(&mut self, tab: usize, target: &str, level: LevelFilter) {
+        let state = std::mem::take(&mut self.states[tab]);
+        self.states[tab] = state.set_level_for_target(target, level);
+    }
+    // Returns the action on the selected peer, if a key for it has been pressed
+    pub fn process_event(&mut self, evt: TuiAppEvent) -> Option<(IpAddr, PeerAction)> {
         use TuiAppEvent::*;
+        if let CharKey(c) = evt {
+            if !self.is_peers_tab() {
+                return None;
+            }
+            let peer = self.peers.get(self.peer_table.selected()?)?;
+            let (_, action) = PEER_ACTION_KEYS.iter().find(|(key, _)| *key == c)?;
+            return Some((peer.wg_ip, *action));
+        }
+        // The table of the peers scrolls by the selection
+        if self.is_peers_tab() {
+            let last = self.peers.len().saturating_sub(1);
+            let selected = self.peer_table.selected();
+            match evt {
+                UpKey => {
+                    self.peer_table
+                        .select(Some(selected.unwrap_or(0).saturating_sub(1)));
+                    return None;
+                }
+                DownKey => {
+                    self.peer_table
+                        .select(Some(selected.map(|i| (i + 1).min(last)).unwrap_or(0)));
+                    return None;
+                }
+                EscapeKey => {
+                    self.peer_table.select(None);
+                    return None;
+                }
+                _ => {}
+            }
+        }
         let widget_evt: Option<TuiWidgetEvent> = match evt {
             SpaceKey => Some(TuiWidgetEvent::SpaceKey),
             EscapeKey => Some(TuiWidgetEvent::EscapeKey),
@@ -178,10 +513,40 @@ impl TuiApp {
                 self.selected_tab = (self.selected_tab + self.tabs.len() - 1) % self.tabs.len();
                 None
             }
+            CharKey(_) => None,
         };
         if let Some(widget_evt) = widget_evt {
             self.states[self.selected_tab].transition(&widget_evt);
         }
+        None
+    }
+    // Shown above the tabs, e.g. which network is managed
+    pub fn set_title(&mut self, title: String) {
+        self.title = title;
+    }
+    pub fn set_alerts(&mut self, alerts: Vec<Alert>) {
+        self.alerts = alerts;
+    }
+    pub fn set_latencies(&mut self, latencies: Vec<(String, u32)>) {
+        self.latencies = latencies;
+    }
+    pub fn set_peers(&mut self, peers: Vec<PeerRow>) {
+        if let Some(selected) = self.peer_table.selected() {
+            let last = peers.len().checked_sub(1);
+            self.peer_table.select(last.map(|last| selected.min(last)));
+        }
+        self.peers = peers;
+    }
+    // On each change of the route database, as the routes are not refreshed each second
+    pub fn set_routes(&mut self, my_wg_ip: IpAddr, routes: Vec<RouteInfo>) {
+        self.topology = topology(my_wg_ip, &routes);
+        self.routes = RouteRow::of(&routes);
+    }
+    fn is_tab(&self, tab: &str) -> bool {
+        self.tabs.get(self.selected_tab).map(String::as_str) == Some(tab)
+    }
+    fn is_peers_tab(&self) -> bool {
+        self.is_tab(PEERS_TAB)
     }
     pub fn draw(&mut self) -> BoxResult<()> {
         if let Some(mut terminal) = self.terminal.take() {
@@ -207,18 +572,85 @@ fn draw_frame<B: Backend>(t: &mut Frame<B>, size: Rect, app: &mut TuiApp) {
             .push(TuiWidgetState::new().set_default_display_level(LevelFilter::Info));
     }
 
-    let constraints = vec![Constraint::Length(3), Constraint::Min(3)];
+    let alert_lines = app.alerts.len().clamp(1, MAX_ALERT_LINES);
+    let latency_lines = app.latencies.len().clamp(1, MAX_LATENCY_LINES);
+    let constraints = vec![
+        Constraint::Length(3),
+        Constraint::Length(alert_lines as u16 + 2),
+        Constraint::Length(latency_lines as u16 + 2),
+        Constraint::Min(3),
+    ];
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints(constraints)
         .split(size);
 
     let tabs = Tabs::new(tabs)
-        .block(Block::default().borders(Borders::ALL))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(app.title.as_str()),
+        )
         .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
         .select(sel);
     t.render_widget(tabs, chunks[0]);
 
+    let mut lines = app
+        .alerts
+        .iter()
+        .take(MAX_ALERT_LINES)
+        .map(|alert| {
+            let color = match alert.severity {
+                Severity::Critical => Color::Red,
+                Severity::Warning => Color::Yellow,
+            };
+            let since = chrono::Local
+                .timestamp_opt(alert.since as i64, 0)
+                .single()
+                .map(|t| t.format("%H:%M:%S").to_string())
+                .unwrap_or_default();
+            Spans::from(vec![
+                Span::raw(format!("{} ", since)),
+                Span::styled(format!("{:?}", alert.severity), Style::default().fg(color)),
+                Span::raw(format!(" {}", alert.message)),
+            ])
+        })
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        lines.push(Spans::from(vec![Span::raw("none")]));
+    }
+    let alerts = Paragraph::new(lines).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(format!("alerts ({})", app.alerts.len())),
+    );
+    t.render_widget(alerts, chunks[1]);
+
+    let mut lines = app
+        .latencies
+        .iter()
+        .take(MAX_LATENCY_LINES)
+        .map(|(peer, rtt_ms)| Spans::from(vec![Span::raw(format!("{:>6}ms {}", rtt_ms, peer))]))
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        lines.push(Spans::from(vec![Span::raw("none")]));
+    }
+    let latencies = Paragraph::new(lines).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(format!("round trip times ({})", app.latencies.len())),
+    );
+    t.render_widget(latencies, chunks[2]);
+
+    if app.is_peers_tab() {
+        draw_peers(t, chunks[3], app);
+        return;
+    }
+    if app.is_tab(ROUTES_TAB) {
+        draw_routes(t, chunks[3], app);
+        return;
+    }
+
     let tui_sm = TuiLoggerSmartWidget::default()
         .style_error(Style::default().fg(Color::Red))
         .style_debug(Style::default().fg(Color::Green))
@@ -232,5 +664,78 @@ fn draw_frame<B: Backend>(t: &mut Frame<B>, size: Rect, app: &mut TuiApp) {
         .output_file(true)
         .output_line(true)
         .state(&app.states[sel]);
-    t.render_widget(tui_sm, chunks[1]);
+    t.render_widget(tui_sm, chunks[3]);
+}
+fn draw_peers<B: Backend>(t: &mut Frame<B>, area: Rect, app: &mut TuiApp) {
+    let header = Row::new(PEER_COLUMNS.iter().map(|(name, _)| *name))
+        .style(Style::default().add_modifier(Modifier::BOLD));
+    let rows = app.peers.iter().map(|peer| {
+        let style = match peer.hop_cnt {
+            None => Style::default().fg(Color::Red),
+            Some(0) => Style::default(),
+            Some(_) => Style::default().fg(Color::Yellow),
+        };
+        Row::new(peer.cells().into_iter().map(Cell::from)).style(style)
+    });
+    let widths = PEER_COLUMNS
+        .iter()
+        .map(|(_, width)| Constraint::Length(*width))
+        .collect::<Vec<_>>();
+    let table = Table::new(rows)
+        .header(header)
+        .block(Block::default().borders(Borders::ALL).title(format!(
+            "peers ({}) - {}",
+            app.peers.len(),
+            peer_action_help()
+        )))
+        .widths(&widths)
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    t.render_stateful_widget(table, area, &mut app.peer_table);
+}
+fn peer_action_help() -> String {
+    PEER_ACTION_KEYS
+        .iter()
+        .map(|(key, action)| format!("{}: {}", key, action.name()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+fn draw_routes<B: Backend>(t: &mut Frame<B>, area: Rect, app: &TuiApp) {
+    let table_width = ROUTE_COLUMNS
+        .iter()
+        .map(|(_, width)| width + 1)
+        .sum::<u16>()
+        + 2;
+    let chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(vec![Constraint::Length(table_width), Constraint::Min(10)])
+        .split(area);
+
+    let header = Row::new(ROUTE_COLUMNS.iter().map(|(name, _)| *name))
+        .style(Style::default().add_modifier(Modifier::BOLD));
+    let rows = app
+        .routes
+        .iter()
+        .map(|route| Row::new(route.cells().into_iter().map(Cell::from)));
+    let widths = ROUTE_COLUMNS
+        .iter()
+        .map(|(_, width)| Constraint::Length(*width))
+        .collect::<Vec<_>>();
+    let table = Table::new(rows)
+        .header(header)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("routes ({})", app.routes.len())),
+        )
+        .widths(&widths);
+    t.render_widget(table, chunks[0]);
+
+    let lines = app
+        .topology
+        .iter()
+        .map(|line| Spans::from(vec![Span::raw(line.as_str())]))
+        .collect::<Vec<_>>();
+    let topology =
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("topology"));
+    t.render_widget(topology, chunks[1]);
 }
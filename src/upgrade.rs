@@ -0,0 +1,161 @@
+// Zero-downtime binary upgrade.
+//
+// The running instance listens on a unix socket. A new instance started with
+// --takeover connects to it. The old instance sends its bound admin sockets via
+// SCM_RIGHTS together with its StateSnapshot. After the new instance has confirmed,
+// the old one exits without taking down the wireguard interface. Without
+// confirmation, the old instance just continues.
+//
+// Message from old to new instance:
+//    4 Bytes   Magic "WGNU"
+//    4 Bytes   Length of the snapshot (little endian)
+//              + SCM_RIGHTS with the ipv4 and the ipv6 admin socket
+//    n Bytes   StateSnapshot as json
+//
+// The reply of the new instance is "OK".
+//
+use std::fs;
+use std::io::{Read, Write};
+use std::net::UdpSocket;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use log::*;
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use nix::sys::uio::IoVec;
+
+use crate::configuration::StaticConfiguration;
+use crate::error::*;
+use crate::event::{Event, EventSender};
+use crate::state_snapshot::StateSnapshot;
+
+const MAGIC: &[u8; 4] = b"WGNU";
+const ACK: &[u8; 2] = b"OK";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Handover {
+    pub socket_v4: UdpSocket,
+    pub socket_v6: UdpSocket,
+    pub snapshot: StateSnapshot,
+}
+
+// Accept upgrade requests in the background. Each connection is passed to the run loop.
+pub fn listen(path: &str, tx: EventSender) -> BoxResult<()> {
+    let path = Path::new(path);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // The admin port is bound already, so no other instance uses this socket
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    debug!(target: "upgrade", "Listen for upgrade requests on {}", path.display());
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if tx.send(Event::Handover(stream)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!(target: "upgrade", "{}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+pub fn remove_socket(path: &str) {
+    fs::remove_file(path).ok();
+}
+
+// Executed by the old instance. On success, it is expected to exit.
+pub fn hand_over(
+    mut stream: UnixStream,
+    socket_v4: RawFd,
+    socket_v6: RawFd,
+    snapshot: &StateSnapshot,
+) -> BoxResult<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let json = snapshot.to_json()?;
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    let fds = [socket_v4, socket_v6];
+    let n = sendmsg(
+        stream.as_raw_fd(),
+        &[IoVec::from_slice(&header)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    if n != header.len() {
+        return strerror("Cannot send handover header");
+    }
+    stream.write_all(&json)?;
+
+    let mut ack = [0u8; 2];
+    stream.read_exact(&mut ack)?;
+    if &ack != ACK {
+        return strerror("New instance has not confirmed the handover");
+    }
+    Ok(())
+}
+
+// Executed by the new instance before binding any socket
+pub fn take_over(path: &str, static_config: &StaticConfiguration) -> BoxResult<Handover> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| format!("No running instance to take over at {}: {}", path, e))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut header = [0u8; 8];
+    let mut cmsg_buffer = nix::cmsg_space!([RawFd; 2]);
+    let msg = recvmsg(
+        stream.as_raw_fd(),
+        &[IoVec::from_mut_slice(&mut header)],
+        Some(&mut cmsg_buffer),
+        MsgFlags::empty(),
+    )?;
+    let mut fds = vec![];
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(received) = cmsg {
+            fds.extend(received);
+        }
+    }
+    // Safety: the file descriptors have just been received and are owned by nobody else
+    let mut sockets = fds
+        .into_iter()
+        .map(|fd| unsafe { UdpSocket::from_raw_fd(fd) })
+        .collect::<Vec<_>>();
+    if msg.bytes != header.len() || &header[..4] != MAGIC || sockets.len() != 2 {
+        return strerror("Malformed handover message");
+    }
+
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let mut json = vec![0u8; len];
+    stream.read_exact(&mut json)?;
+    let snapshot = StateSnapshot::from_json(&json)?;
+    if snapshot.wg_ip != static_config.wg_ip || snapshot.wg_name != static_config.wg_name {
+        return Err(format!(
+            "Running instance manages {} on {} and not {} on {}",
+            snapshot.wg_ip, snapshot.wg_name, static_config.wg_ip, static_config.wg_name
+        )
+        .into());
+    }
+
+    stream.write_all(ACK)?;
+    let socket_v6 = sockets.pop().unwrap();
+    let socket_v4 = sockets.pop().unwrap();
+    Ok(Handover {
+        socket_v4,
+        socket_v6,
+        snapshot,
+    })
+}
@@ -0,0 +1,124 @@
+// wireguard-go and boringtun serve an interface from an own process. It is started in
+// the foreground, so its pid is known and kept in a pid file per interface. On
+// shutdown only this process is terminated, and not the ones of other tunnels.
+//
+// A process left over by a crashed instance is detected at startup via the pid file or
+// its command line. It is adopted and terminated like an own one, before the interface
+// is created again.
+//
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::error::*;
+
+pub const PID_DIR: &str = "/var/run/wireguard";
+pub const BINARIES: [&str; 2] = ["wireguard-go", "boringtun"];
+// A failing binary exits within this time, e.g. if the interface name is invalid
+const STARTUP: Duration = Duration::from_millis(500);
+
+// Executes a command like the execute_command of the device, e.g. via sudo
+pub type Run<'a> = &'a dyn Fn(Vec<&str>, Option<&str>) -> BoxResult<Output>;
+
+pub fn pid_file(device_name: &str) -> PathBuf {
+    PathBuf::from(PID_DIR).join(format!("{}.wg_netmanager.pid", device_name))
+}
+
+pub fn command_line(binary: &str, device_name: &str) -> Vec<String> {
+    vec![
+        binary.to_string(),
+        "--foreground".to_string(),
+        device_name.to_string(),
+    ]
+}
+
+// The command lines of the processes serving the interface, either started by
+// command_line() or daemonized by an older version
+pub fn pgrep_pattern(device_name: &str) -> String {
+    format!(
+        "^([^ ]*/)?({})( -f| --foreground)? {}$",
+        BINARIES.join("|"),
+        device_name
+    )
+}
+
+// Prefix is e.g. sudo, if needed. Returns the pid.
+pub fn start(binary: &str, device_name: &str, prefix: &[&str], run: Run) -> BoxResult<u32> {
+    let mut args = prefix.to_vec();
+    let line = command_line(binary, device_name);
+    args.extend(line.iter().map(String::as_str));
+    trace!(target: "shell", "{:?}", args);
+    let mut child = Command::new(args.remove(0))
+        .args(args)
+        // wireguard-go refuses to run on linux without. Via sudo, it needs env_keep.
+        .env("WG_I_PREFER_BUGGY_USERSPACE_TO_POLISHED_KMOD", "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let started = Instant::now();
+    while started.elapsed() < STARTUP {
+        if let Some(status) = child.try_wait()? {
+            return Err(format!("{} exited with {}", binary, status).into());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let pid = child.id();
+    let fname = pid_file(device_name);
+    run(vec!["mkdir", "-p", PID_DIR], None)?;
+    run(
+        vec!["tee", &fname.to_string_lossy()],
+        Some(&format!("{}\n", pid)),
+    )?;
+    info!("Started {} with pid {} for {}", binary, pid, device_name);
+    Ok(pid)
+}
+
+// The running processes of the interface, including orphaned ones
+pub fn find(device_name: &str, run: Run) -> Vec<u32> {
+    let mut pids = vec![];
+    if let Some(pid) = std::fs::read_to_string(pid_file(device_name))
+        .ok()
+        .and_then(|content| content.trim().parse::<u32>().ok())
+    {
+        // The pid may have been reused meanwhile
+        let pid_s = pid.to_string();
+        if let Ok(output) = run(vec!["ps", "-o", "command=", "-p", &pid_s], None) {
+            if String::from_utf8_lossy(&output.stdout).contains(device_name) {
+                pids.push(pid);
+            }
+        }
+    }
+    let pattern = pgrep_pattern(device_name);
+    // pgrep fails, if there is no match
+    if let Ok(output) = run(vec!["pgrep", "-f", &pattern], None) {
+        for pid in String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.trim().parse::<u32>().ok())
+        {
+            if !pids.contains(&pid) {
+                pids.push(pid);
+            }
+        }
+    }
+    pids
+}
+
+pub fn stop(device_name: &str, run: Run) {
+    for pid in find(device_name, run) {
+        info!(
+            "Terminate userspace wireguard process {} of {}",
+            pid, device_name
+        );
+        if let Err(e) = run(vec!["kill", &pid.to_string()], None) {
+            warn!("Cannot terminate process {}: {}", pid, e);
+        }
+    }
+    let fname = pid_file(device_name);
+    if fname.exists() {
+        let _ = run(vec!["rm", "-f", &fname.to_string_lossy()], None);
+    }
+}
@@ -1,8 +1,117 @@
-use std::time::SystemTime;
+use std::net::{Ipv6Addr, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime};
 
+use crate::error::BoxResult;
+
+// Two clocks are used:
+//
+//      wall clock      timestamps compared with other nodes, e.g. of the udp frames
+//      mesh clock      all intervals and timers, e.g. lastseen of the nodes
+//
+// The mesh clock is the wall clock at startup advanced by the monotonic clock. So it
+// reads like unix time, but a step of the wall clock, e.g. by NTP, neither expires all
+// peers at once nor stalls the timers. Unix times from the outside, like the wireguard
+// handshakes or the start time of a rendezvous, are converted with from_wall_time()
+// and to_wall_time().
+//
+struct MeshClock {
+    wall_ms_at_start: u64,
+    start: Instant,
+}
+
+static MESH_CLOCK: OnceLock<MeshClock> = OnceLock::new();
+// Simulated steps of the wall clock in ms
+static WALL_CLOCK_STEP: AtomicI64 = AtomicI64::new(0);
+
+fn mesh_clock() -> &'static MeshClock {
+    MESH_CLOCK.get_or_init(|| MeshClock {
+        wall_ms_at_start: wall_now_ms(),
+        start: Instant::now(),
+    })
+}
+
+// The mesh clock in seconds
 pub fn now() -> u64 {
-    SystemTime::now()
+    now_ms() / 1000
+}
+
+// For the round trip times
+pub fn now_ms() -> u64 {
+    let clock = mesh_clock();
+    clock.wall_ms_at_start + clock.start.elapsed().as_millis() as u64
+}
+
+// The wall clock in seconds
+pub fn wall_now() -> u64 {
+    wall_now_ms() / 1000
+}
+
+fn wall_now_ms() -> u64 {
+    let ms = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
-        .as_secs()
+        .as_millis() as u64;
+    ms.saturating_add_signed(WALL_CLOCK_STEP.load(Ordering::Relaxed))
+}
+
+// Wall clock minus mesh clock in seconds, zero until the wall clock is stepped
+fn wall_clock_offset() -> i64 {
+    (wall_now_ms() as i64 - now_ms() as i64 + 500).div_euclid(1000)
+}
+
+// A unix time on the mesh clock with the same age
+pub fn from_wall_time(wall_time: u64) -> u64 {
+    wall_time.saturating_add_signed(-wall_clock_offset())
+}
+
+pub fn to_wall_time(mesh_time: u64) -> u64 {
+    mesh_time.saturating_add_signed(wall_clock_offset())
+}
+
+// Step the wall clock like NTP does, for tests
+#[cfg(any(test, feature = "testing"))]
+pub fn step_wall_clock(seconds: i64) {
+    WALL_CLOCK_STEP.fetch_add(seconds * 1000, Ordering::Relaxed);
+}
+
+// False on systems without ipv6, e.g. linux booted with ipv6.disable=1
+pub fn ipv6_available() -> bool {
+    UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).is_ok()
+}
+
+// For files referenced by a service definition, which is not started in the cwd
+pub fn absolute_path(fname: &str) -> String {
+    std::fs::canonicalize(fname)
+        .ok()
+        .and_then(|path| path.to_str().map(|s| s.to_string()))
+        .unwrap_or_else(|| fname.to_string())
+}
+
+// Returns true, if the file has been written. An existing file with other content
+// is only replaced with force.
+pub fn install_file(path: &Path, content: &str, force: bool, dry_run: bool) -> BoxResult<bool> {
+    if let Ok(existing) = std::fs::read_to_string(path) {
+        if existing == content {
+            println!("{} is up to date", path.display());
+            return Ok(false);
+        }
+        if !force {
+            return Err(format!(
+                "{} exists with other content, use --force to replace it",
+                path.display()
+            )
+            .into());
+        }
+    }
+    if dry_run {
+        println!("Would write {}:", path.display());
+        println!("{}", content);
+        return Ok(false);
+    }
+    std::fs::write(path, content)?;
+    println!("Written {}", path.display());
+    Ok(true)
 }
@@ -0,0 +1,207 @@
+// Notification of mesh events via webhooks.
+//
+// Peer up/down, route changes and key conflicts are POSTed as json to the configured
+// urls. The field `text` carries a readable message, so e.g. a slack incoming webhook
+// can be used as is. Each url is served by an own thread, so a slow or unreachable
+// receiver delays neither the run loop nor the other urls. Failed posts are retried
+// with exponential backoff. While a receiver is down, at most QUEUE_LEN notifications
+// are kept and newer ones are dropped.
+//
+use std::net::IpAddr;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use log::*;
+use serde::Serialize;
+
+use crate::alert::{Alert, AlertKind};
+use crate::configuration::StaticConfiguration;
+use crate::mesh_event::MeshEvent;
+
+const QUEUE_LEN: usize = 100;
+const MAX_ATTEMPTS: u32 = 6;
+const FIRST_RETRY_S: u64 = 2;
+const MAX_RETRY_S: u64 = 60;
+const TIMEOUT_S: u64 = 10;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    PeerUp {
+        peer: IpAddr,
+        name: Option<String>,
+    },
+    PeerDown {
+        peer: IpAddr,
+    },
+    RouteChange {
+        to: IpAddr,
+        gateway: Option<IpAddr>,
+        // added, replaced or removed
+        change: &'static str,
+    },
+    KeyConflict {
+        peer: IpAddr,
+        message: String,
+    },
+}
+impl WebhookEvent {
+    pub fn from_mesh_event(evt: &MeshEvent) -> Option<Self> {
+        let route_change = |to: &IpAddr, gateway: Option<IpAddr>, change| {
+            Some(WebhookEvent::RouteChange {
+                to: *to,
+                gateway,
+                change,
+            })
+        };
+        match evt {
+            MeshEvent::PeerUp { wg_ip, name } => Some(WebhookEvent::PeerUp {
+                peer: *wg_ip,
+                name: name.clone(),
+            }),
+            MeshEvent::PeerDown { wg_ip } => Some(WebhookEvent::PeerDown { peer: *wg_ip }),
+            MeshEvent::RouteAdded { to, gateway } => route_change(to, *gateway, "added"),
+            MeshEvent::RouteReplaced { to, gateway } => route_change(to, *gateway, "replaced"),
+            MeshEvent::RouteRemoved { to } => route_change(to, None, "removed"),
+            MeshEvent::EndpointChanged { .. }
+            | MeshEvent::KeyRotated { .. }
+            | MeshEvent::PeerMoved { .. } => None,
+        }
+    }
+    pub fn from_alert(alert: &Alert) -> Option<Self> {
+        match alert.kind {
+            AlertKind::KeyConflict(wg_ip) | AlertKind::WgIpConflict(wg_ip) => {
+                Some(WebhookEvent::KeyConflict {
+                    peer: wg_ip,
+                    message: alert.message.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+    fn text(&self) -> String {
+        match self {
+            WebhookEvent::PeerUp {
+                peer,
+                name: Some(name),
+            } => format!("peer {} ({}) is up", peer, name),
+            WebhookEvent::PeerUp { peer, name: None } => format!("peer {} is up", peer),
+            WebhookEvent::PeerDown { peer } => format!("peer {} is down", peer),
+            WebhookEvent::RouteChange {
+                to,
+                gateway: Some(gateway),
+                change,
+            } => format!("route to {} via {} {}", to, gateway, change),
+            WebhookEvent::RouteChange { to, change, .. } => format!("route to {} {}", to, change),
+            WebhookEvent::KeyConflict { message, .. } => message.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Notification {
+    // The reporting node
+    pub node: String,
+    pub wg_ip: IpAddr,
+    pub network: Option<String>,
+    // Unix time
+    pub time: u64,
+    pub text: String,
+    #[serde(flatten)]
+    pub event: WebhookEvent,
+}
+impl Notification {
+    pub fn new(now: u64, static_config: &StaticConfiguration, event: WebhookEvent) -> Self {
+        Notification {
+            node: static_config.name.clone(),
+            wg_ip: static_config.wg_ip,
+            network: static_config.network_name.clone(),
+            time: now,
+            text: format!(
+                "{} ({}): {}",
+                static_config.name,
+                static_config.wg_ip,
+                event.text()
+            ),
+            event,
+        }
+    }
+}
+
+// Delay before the given retry, starting with 1
+pub fn retry_delay(retry: u32) -> Duration {
+    let delay = FIRST_RETRY_S.saturating_mul(1u64 << (retry - 1).min(16));
+    Duration::from_secs(delay.min(MAX_RETRY_S))
+}
+
+pub struct WebhookNotifier {
+    workers: Vec<(String, SyncSender<Notification>)>,
+}
+impl WebhookNotifier {
+    pub fn new(urls: &[String]) -> Self {
+        let workers = urls
+            .iter()
+            .map(|url| {
+                let (tx, rx) = sync_channel(QUEUE_LEN);
+                let worker_url = url.clone();
+                thread::spawn(move || worker(worker_url, rx));
+                (url.clone(), tx)
+            })
+            .collect();
+        WebhookNotifier { workers }
+    }
+    pub fn notify(&self, notification: Notification) {
+        for (url, tx) in self.workers.iter() {
+            match tx.try_send(notification.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!(target: "webhook", "Queue for {} is full => drop {}", url, notification.text);
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    error!(target: "webhook", "Worker for {} has died", url);
+                }
+            }
+        }
+    }
+}
+
+fn worker(url: String, rx: Receiver<Notification>) {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(TIMEOUT_S))
+        .build();
+    for notification in rx {
+        let body = match serde_json::to_string(&notification) {
+            Ok(body) => body,
+            Err(e) => {
+                error!(target: "webhook", "Cannot serialize notification: {}", e);
+                continue;
+            }
+        };
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = agent
+                .post(&url)
+                .set("Content-Type", "application/json")
+                .send_string(&body);
+            match result {
+                Ok(_) => {
+                    debug!(target: "webhook", "Posted to {}: {}", url, notification.text);
+                    break;
+                }
+                // A client error will not go away with a retry, except too many requests
+                Err(ureq::Error::Status(code, _)) if (400..500).contains(&code) && code != 429 => {
+                    warn!(target: "webhook", "{} rejected notification with status {}", url, code);
+                    break;
+                }
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    let delay = retry_delay(attempt);
+                    info!(target: "webhook", "Post to {} failed: {} => retry in {:?}", url, e, delay);
+                    thread::sleep(delay);
+                }
+                Err(e) => {
+                    warn!(target: "webhook", "Post to {} failed {} times: {} => drop {}", url, MAX_ATTEMPTS, e, notification.text);
+                }
+            }
+        }
+    }
+}
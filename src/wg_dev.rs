@@ -1,29 +1,363 @@
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 
-use ipnet::Ipv4Net;
+use ipnet::{IpNet, Ipv6Net};
+use log::*;
+use serde::{Deserialize, Serialize};
 
 use crate::error::*;
+use crate::link_local::{needs_scope, split_scope};
 
-pub trait WireguardDevice {
+// Optional attributes of a host route. A route with attributes is installed even to a
+// direct peer, which otherwise is covered by the subnet route.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RouteAttributes {
+    // Locked mtu, so only this destination is clamped and not the whole interface.
+    // Not all platforms support this.
+    pub mtu: Option<u32>,
+}
+impl RouteAttributes {
+    pub fn is_empty(&self) -> bool {
+        self.mtu.is_none()
+    }
+}
+
+// Send, so the device can be used by the DeviceWorker thread.
+// The trait is part of the stable api. Methods added later have a default body, so
+// devices of other crates keep compiling.
+pub trait WireguardDevice: Send {
     fn check_device(&self) -> BoxResult<bool>;
     fn create_device(&self) -> BoxResult<()>;
     fn take_down_device(&self) -> BoxResult<()>;
-    fn set_ip(&mut self, ip: &Ipv4Addr, subnet: &Ipv4Net) -> BoxResult<()>;
-    fn add_route(&self, host: Ipv4Addr, gateway: Option<Ipv4Addr>) -> BoxResult<()>;
-    fn replace_route(&self, host: Ipv4Addr, gateway: Option<Ipv4Addr>) -> BoxResult<()>;
-    fn del_route(&self, host: Ipv4Addr, gateway: Option<Ipv4Addr>) -> BoxResult<()>;
+    // probe_net is the additional ipv6 address for NAT traversal, if any
+    fn set_ip(&mut self, ip: &IpAddr, subnet: &IpNet, probe_net: Option<Ipv6Net>) -> BoxResult<()>;
+    fn add_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()>;
+    fn replace_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()>;
+    fn del_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()>;
+    // Route to a network exported by a node, e.g. the LAN behind a site gateway.
+    // It is not covered by the subnet route.
+    fn add_net_route(&self, _net: IpNet) -> BoxResult<()> {
+        strerror("Routes to exported networks are not supported by this device")
+    }
+    fn del_net_route(&self, _net: IpNet) -> BoxResult<()> {
+        strerror("Routes to exported networks are not supported by this device")
+    }
     fn set_conf(&self, conf: &str) -> BoxResult<()>;
     fn sync_conf(&self, conf: &str) -> BoxResult<()>;
     fn flush_all(&self) -> BoxResult<()>;
     fn retrieve_conf(&self) -> BoxResult<HashMap<String, SocketAddr>>;
-    fn create_key_pair(&self) -> BoxResult<(String, String)>;
+    // The peers as configured on the interface, e.g. by other tooling. Not all backends
+    // report them.
+    fn retrieve_peers(&self) -> BoxResult<Vec<WgPeerConf>> {
+        Ok(vec![])
+    }
+    // Generated in-process, so neither wg nor root is needed
+    fn create_key_pair(&self) -> BoxResult<(String, String)> {
+        Ok(crate::crypto::generate_key_pair())
+    }
+    // Unix time of the latest handshake per public key. Peers without handshake are missing
+    fn latest_handshakes(&self) -> BoxResult<HashMap<String, u64>> {
+        Ok(HashMap::new())
+    }
+    // Received and sent bytes per public key. Not all backends report them.
+    fn transfer(&self) -> BoxResult<HashMap<String, (u64, u64)>> {
+        Ok(HashMap::new())
+    }
+    // Interface altnames are not supported on all platforms
+    fn altnames(&self) -> BoxResult<Vec<String>> {
+        Ok(vec![])
+    }
+    fn add_altname(&self, _altname: &str) -> BoxResult<()> {
+        Ok(())
+    }
+    // Addresses are changed at runtime on a new wg_ip. Not supported on all platforms.
+    fn add_ip(&self, _ip: &IpAddr, _prefix_len: u8) -> BoxResult<()> {
+        strerror("Changing the address of the interface is not supported on this platform")
+    }
+    fn del_ip(&self, _ip: &IpAddr, _prefix_len: u8) -> BoxResult<()> {
+        strerror("Changing the address of the interface is not supported on this platform")
+    }
+}
+
+// Desired wireguard configurations with sequence numbers. Bursts of updates may be
+// submitted faster than a slow sync_conf applies them. Only the latest submitted
+// configuration is handed out, the intermediate ones are skipped. A configuration
+// older than the applied one is never handed out, so they cannot be applied out of order.
+#[derive(Default, Debug)]
+pub struct ConfSequencer {
+    next_seq: u64,
+    pending: Option<(u64, String)>,
+    applied_seq: Option<u64>,
+    skipped: u64,
+}
+impl ConfSequencer {
+    pub fn submit(&mut self, conf: String) -> u64 {
+        self.next_seq += 1;
+        if self.pending.replace((self.next_seq, conf)).is_some() {
+            self.skipped += 1;
+        }
+        self.next_seq
+    }
+    pub fn has_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+    // The latest configuration to be applied, if any
+    pub fn take_latest(&mut self) -> Option<(u64, String)> {
+        let (seq, conf) = self.pending.take()?;
+        if self.is_stale(seq) {
+            self.skipped += 1;
+            return None;
+        }
+        Some((seq, conf))
+    }
+    // Returns false, if a newer configuration has been applied already
+    pub fn applied(&mut self, seq: u64) -> bool {
+        if self.is_stale(seq) {
+            return false;
+        }
+        self.applied_seq = Some(seq);
+        true
+    }
+    pub fn is_stale(&self, seq: u64) -> bool {
+        self.applied_seq
+            .map(|applied| seq <= applied)
+            .unwrap_or(false)
+    }
+    pub fn applied_seq(&self) -> Option<u64> {
+        self.applied_seq
+    }
+    // Number of configurations, which have been superseded before being applied
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+type DeviceJob = Box<dyn FnOnce(&dyn WireguardDevice) + Send>;
+
+// Runs the calls of the wireguard device in an own thread. Most backends spawn ip, wg
+// or sudo, which would stall the single threaded runtime of the main loop, so the
+// admin sockets are not read meanwhile. The thread is started by the main loop's
+// thread after dropping the privileges and inherits the remaining capabilities.
+// The errors cross the thread as text.
+pub struct DeviceWorker {
+    jobs: std::sync::mpsc::Sender<DeviceJob>,
+    thread: std::thread::JoinHandle<Box<dyn WireguardDevice>>,
+}
+impl DeviceWorker {
+    pub fn start(wg_dev: Box<dyn WireguardDevice>) -> BoxResult<Self> {
+        let (jobs, rx) = std::sync::mpsc::channel::<DeviceJob>();
+        let thread = std::thread::Builder::new()
+            .name("wg_dev".to_string())
+            .spawn(move || {
+                for job in rx {
+                    job(&*wg_dev);
+                }
+                wg_dev
+            })?;
+        Ok(DeviceWorker { jobs, thread })
+    }
+    fn submit<T, F, R>(&self, f: F, reply: R) -> BoxResult<()>
+    where
+        F: FnOnce(&dyn WireguardDevice) -> BoxResult<T> + Send + 'static,
+        R: FnOnce(Result<T, String>) + Send + 'static,
+    {
+        self.jobs
+            .send(Box::new(move |wg_dev| {
+                reply(f(wg_dev).map_err(|e| e.to_string()))
+            }))
+            .map_err(|_| "The device worker has ended".into())
+    }
+    // The main loop keeps running, until the call has returned
+    pub async fn call<T, F>(&self, f: F) -> BoxResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn WireguardDevice) -> BoxResult<T> + Send + 'static,
+    {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.submit(f, move |result| {
+            tx.send(result).ok();
+        })?;
+        match rx.await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err("The device worker has ended".into()),
+        }
+    }
+    // For the calls without processes, e.g. create_key_pair()
+    pub fn call_blocking<T, F>(&self, f: F) -> BoxResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn WireguardDevice) -> BoxResult<T> + Send + 'static,
+    {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.submit(f, move |result| {
+            tx.send(result).ok();
+        })?;
+        match rx.recv() {
+            Ok(result) => Ok(result?),
+            Err(_) => Err("The device worker has ended".into()),
+        }
+    }
+    // Returns the device after the queued calls, e.g. for the teardown
+    pub fn stop(self) -> BoxResult<Box<dyn WireguardDevice>> {
+        drop(self.jobs);
+        self.thread
+            .join()
+            .map_err(|_| "The device worker has panicked".into())
+    }
 }
 
-pub fn map_to_ipv6(ipv4: &Ipv4Addr) -> Ipv6Addr {
-    let mut segments = ipv4.to_ipv6_mapped().segments();
-    segments[0] = 0xfd00;
-    Ipv6Addr::from(segments)
+// The steps of the teardown in this order. Deleting only the link lets the routes die
+// implicitly, which races with other daemons watching the link events.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum TeardownStep {
+    // The host routes and the routes to the exported networks
+    Routes,
+    // The peers of the network. Adopted peers of other tooling are kept.
+    Peers,
+    // The wg_ip and the nat probe address
+    Addresses,
+    // Never on an existing interface
+    Link,
+}
+impl TeardownStep {
+    pub const ALL: [TeardownStep; 4] = [
+        TeardownStep::Routes,
+        TeardownStep::Peers,
+        TeardownStep::Addresses,
+        TeardownStep::Link,
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            TeardownStep::Routes => "routes",
+            TeardownStep::Peers => "peers",
+            TeardownStep::Addresses => "addresses",
+            TeardownStep::Link => "link",
+        }
+    }
+}
+impl std::str::FromStr for TeardownStep {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TeardownStep::ALL
+            .into_iter()
+            .find(|step| step.name() == s)
+            .ok_or_else(|| format!("unknown teardown step {}", s))
+    }
+}
+
+// What is removed on shutdown, see StaticConfiguration::teardown(). The same for all
+// backends, as only the trait methods are used.
+#[derive(Debug, Clone, Default)]
+pub struct Teardown {
+    // The routes via a gateway precede the direct ones
+    pub routes: Vec<(IpAddr, Option<IpAddr>, RouteAttributes)>,
+    pub nets: Vec<IpNet>,
+    // The wireguard configuration without the peers of the network
+    pub conf: String,
+    pub addresses: Vec<(IpAddr, u8)>,
+    pub skip: Vec<TeardownStep>,
+}
+impl Teardown {
+    // A failed step is logged and the next one is executed anyway. Returns the executed steps.
+    pub fn execute(&self, wg_dev: &dyn WireguardDevice) -> Vec<TeardownStep> {
+        let mut executed = vec![];
+        for step in TeardownStep::ALL {
+            if self.skip.contains(&step) {
+                info!(target: "wireguard", "Teardown: skip {}", step.name());
+                continue;
+            }
+            let errors = match step {
+                TeardownStep::Routes => self
+                    .nets
+                    .iter()
+                    .map(|net| wg_dev.del_net_route(*net))
+                    .chain(self.routes.iter().map(|(to, gateway, attributes)| {
+                        wg_dev.del_route(*to, *gateway, attributes)
+                    }))
+                    .filter_map(|result| result.err())
+                    .collect::<Vec<_>>(),
+                TeardownStep::Peers => wg_dev.sync_conf(&self.conf).err().into_iter().collect(),
+                TeardownStep::Addresses => self
+                    .addresses
+                    .iter()
+                    .filter_map(|(ip, prefix_len)| wg_dev.del_ip(ip, *prefix_len).err())
+                    .collect(),
+                TeardownStep::Link => wg_dev.take_down_device().err().into_iter().collect(),
+            };
+            if errors.is_empty() {
+                info!(target: "wireguard", "Teardown: {} removed", step.name());
+            } else {
+                for e in errors.iter() {
+                    warn!(target: "wireguard", "Teardown: cannot remove {}: {}", step.name(), e);
+                }
+            }
+            executed.push(step);
+        }
+        executed
+    }
+}
+
+// The host route to a node: /32 for ipv4 and /128 for ipv6
+pub fn host_net(host: IpAddr) -> IpNet {
+    IpNet::from(host)
+}
+
+// On an ipv4 overlay, every node has an additional address in fd00::/96, which is only
+// listed in the wireguard peer entry of the node itself. This allows to try NAT traversal
+// to a distant node, while its wg_ip is still routed via the gateway.
+// On an ipv6 overlay, there is no such address.
+pub fn nat_probe_address(wg_ip: &IpAddr) -> Option<Ipv6Addr> {
+    match wg_ip {
+        IpAddr::V4(ipv4) => {
+            let mut segments = ipv4.to_ipv6_mapped().segments();
+            segments[0] = 0xfd00;
+            Some(Ipv6Addr::from(segments))
+        }
+        IpAddr::V6(_) => None,
+    }
+}
+// The nat probe address of this node with the prefix length of the ipv4 overlay
+pub fn nat_probe_net(wg_ip: &IpAddr, subnet: &IpNet) -> Option<Ipv6Net> {
+    nat_probe_address(wg_ip).and_then(|ip| Ipv6Net::new(ip, 96 + subnet.prefix_len()).ok())
+}
+
+// wireguard reports the endpoints either as ipv4, ipv6 or ipv4 mapped into ipv6 with scope id.
+// Normalize them, so ipv4 endpoints are always SocketAddr::V4. A link-local endpoint
+// keeps the scope id, if the interface is known here.
+pub fn parse_wg_endpoint(endpoint: &str) -> BoxResult<SocketAddr> {
+    let sa: SocketAddr = v6_strip_interface(endpoint)?.parse()?;
+    match sa {
+        SocketAddr::V6(mut sa_v6) => match sa_v6.ip().to_ipv4_mapped() {
+            Some(ipv4) => Ok(SocketAddr::new(ipv4.into(), sa_v6.port())),
+            None => {
+                if needs_scope(&sa.ip()) {
+                    if let Ok((_, Some(scope_id))) = split_scope(endpoint) {
+                        sa_v6.set_scope_id(scope_id);
+                    }
+                }
+                Ok(sa_v6.into())
+            }
+        },
+        SocketAddr::V4(_) => Ok(sa),
+    }
 }
 
 // wireguard returns an address like this and the %-part has to be removed:[fe80::3bac:744c:f807:a5a2%br-wan]:50001
@@ -42,3 +376,347 @@ pub fn v6_strip_interface(sa: &str) -> BoxResult<String> {
         Err(format!("invalid address: {}", sa).into())
     }
 }
+
+// Output of wg show <device> latest-handshakes: public key and unix time per line.
+// A time of 0 means no handshake yet.
+pub fn parse_latest_handshakes(output: &str) -> HashMap<String, u64> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut flds = line.split_whitespace();
+            let key = flds.next()?;
+            let timestamp = flds.next()?.parse::<u64>().ok()?;
+            (timestamp > 0).then(|| (key.to_string(), timestamp))
+        })
+        .collect()
+}
+
+pub fn decode_key(key: &str) -> BoxResult<[u8; 32]> {
+    let raw = base64::decode(key.trim())?;
+    if raw.len() != 32 {
+        return strerror("Invalid wireguard key length");
+    }
+    let mut buf = [0u8; 32];
+    buf.copy_from_slice(&raw);
+    Ok(buf)
+}
+
+// The subset of the wg configuration file format, which is produced by
+// StaticConfiguration::to_wg_configuration()
+pub struct WgConf {
+    pub private_key: Option<[u8; 32]>,
+    pub listen_port: Option<u16>,
+    pub peers: Vec<WgPeerConf>,
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WgPeerConf {
+    pub public_key: [u8; 32],
+    // Only set for peers of other tooling
+    pub preshared_key: Option<[u8; 32]>,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<IpNet>,
+    pub persistent_keepalive: Option<u16>,
+}
+impl WgConf {
+    pub fn parse(conf: &str) -> BoxResult<Self> {
+        let ini = ini::Ini::load_from_str(conf)?;
+        let mut wg_conf = WgConf {
+            private_key: None,
+            listen_port: None,
+            peers: vec![],
+        };
+        for (key, value) in ini.section(Some("Interface")).iter().flat_map(|s| s.iter()) {
+            match key.to_lowercase().as_str() {
+                "privatekey" => wg_conf.private_key = Some(decode_key(value)?),
+                "listenport" => wg_conf.listen_port = Some(value.parse()?),
+                _ => warn!(target: "wireguard", "Unsupported interface setting {}", key),
+            }
+        }
+        for peer_ini in ini.section_all(Some("Peer")) {
+            let mut public_key = None;
+            let mut preshared_key = None;
+            let mut endpoint = None;
+            let mut allowed_ips = vec![];
+            let mut persistent_keepalive = None;
+            for (key, value) in peer_ini.iter() {
+                match key.to_lowercase().as_str() {
+                    "publickey" => public_key = Some(decode_key(value)?),
+                    "presharedkey" => preshared_key = Some(decode_key(value)?),
+                    "endpoint" => endpoint = value.to_socket_addrs()?.next(),
+                    "allowedips" => {
+                        for net in value.split(',') {
+                            allowed_ips.push(net.trim().parse::<IpNet>()?);
+                        }
+                    }
+                    "persistentkeepalive" => {
+                        persistent_keepalive = Some(value.parse::<u16>()?).filter(|s| *s > 0)
+                    }
+                    _ => warn!(target: "wireguard", "Unsupported peer setting {}", key),
+                }
+            }
+            match public_key {
+                Some(public_key) => wg_conf.peers.push(WgPeerConf {
+                    public_key,
+                    preshared_key,
+                    endpoint,
+                    allowed_ips,
+                    persistent_keepalive,
+                }),
+                None => return strerror("Peer without public key"),
+            }
+        }
+        Ok(wg_conf)
+    }
+}
+impl WgPeerConf {
+    pub fn key(&self) -> String {
+        base64::encode(self.public_key)
+    }
+    // The lines of the [Peer] section, as parsed by WgConf::parse()
+    pub fn to_conf_lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("PublicKey = {}", self.key())];
+        if let Some(preshared_key) = self.preshared_key.as_ref() {
+            lines.push(format!("PresharedKey = {}", base64::encode(preshared_key)));
+        }
+        if let Some(endpoint) = self.endpoint.as_ref() {
+            lines.push(format!("Endpoint = {}", endpoint));
+        }
+        for net in self.allowed_ips.iter() {
+            lines.push(format!("AllowedIPs = {}", net));
+        }
+        if let Some(seconds) = self.persistent_keepalive {
+            lines.push(format!("PersistentKeepalive = {}", seconds));
+        }
+        lines
+    }
+}
+
+// What a MockWireguardDevice has been told so far
+#[derive(Debug, Clone, Default)]
+pub struct MockDeviceState {
+    pub exists: bool,
+    pub ip: Option<IpAddr>,
+    pub subnet: Option<IpNet>,
+    pub probe_net: Option<Ipv6Net>,
+    // Added at runtime via add_ip
+    pub extra_ips: Vec<(IpAddr, u8)>,
+    pub routes: HashMap<IpAddr, (Option<IpAddr>, RouteAttributes)>,
+    pub net_routes: Vec<IpNet>,
+    pub conf: Option<String>,
+    // Number of set_conf and sync_conf calls
+    pub conf_updates: usize,
+    pub altnames: Vec<String>,
+    // Per public key, see record_handshake() and record_transfer()
+    pub handshakes: HashMap<String, u64>,
+    pub transfer: HashMap<String, (u64, u64)>,
+    // The current endpoint per public key like wireguard: set by the configuration
+    // and updated by record_endpoint(), e.g. on roaming of the peer.
+    pub endpoints: HashMap<String, SocketAddr>,
+}
+impl MockDeviceState {
+    // The addresses of the interface
+    pub fn ips(&self) -> Vec<IpAddr> {
+        self.ip
+            .iter()
+            .copied()
+            .chain(self.probe_net.map(|net| IpAddr::V6(net.addr())))
+            .chain(self.extra_ips.iter().map(|(ip, _)| *ip))
+            .collect()
+    }
+    // The parsed wireguard configuration
+    pub fn wg_conf(&self) -> Option<WgConf> {
+        self.conf
+            .as_deref()
+            .and_then(|conf| WgConf::parse(conf).ok())
+    }
+    pub fn listen_port(&self) -> Option<u16> {
+        self.wg_conf()?.listen_port
+    }
+    pub fn public_key(&self) -> Option<String> {
+        let private_key = self.wg_conf()?.private_key?;
+        crate::crypto::public_key_of(&base64::encode(private_key)).ok()
+    }
+    // The public key of the peer, whose allowed ips contain ip
+    pub fn peer_for(&self, ip: &IpAddr) -> Option<String> {
+        self.wg_conf()?
+            .peers
+            .iter()
+            .find(|peer| peer.allowed_ips.iter().any(|net| net.contains(ip)))
+            .map(|peer| base64::encode(peer.public_key))
+    }
+}
+
+// WireguardDevice without any interface, e.g. for tests without root. It only
+// records the calls. The clones share the state, so a test keeps a clone to inspect
+// the device handed over to the run loop.
+#[derive(Debug, Clone, Default)]
+pub struct MockWireguardDevice {
+    state: std::sync::Arc<std::sync::Mutex<MockDeviceState>>,
+}
+impl MockWireguardDevice {
+    pub fn new() -> Self {
+        MockWireguardDevice::default()
+    }
+    pub fn state(&self) -> MockDeviceState {
+        self.state.lock().unwrap().clone()
+    }
+    pub fn record_handshake(&self, public_key: &str, timestamp: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.handshakes.insert(public_key.to_string(), timestamp);
+    }
+    pub fn record_endpoint(&self, public_key: &str, endpoint: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.endpoints.insert(public_key.to_string(), endpoint);
+    }
+    pub fn record_transfer(&self, public_key: &str, rx_bytes: u64, tx_bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let transfer = state.transfer.entry(public_key.to_string()).or_default();
+        transfer.0 += rx_bytes;
+        transfer.1 += tx_bytes;
+    }
+    fn with_state<T>(&self, f: impl FnOnce(&mut MockDeviceState) -> T) -> T {
+        f(&mut self.state.lock().unwrap())
+    }
+}
+impl WireguardDevice for MockWireguardDevice {
+    fn check_device(&self) -> BoxResult<bool> {
+        Ok(self.with_state(|state| state.exists))
+    }
+    fn create_device(&self) -> BoxResult<()> {
+        self.with_state(|state| state.exists = true);
+        Ok(())
+    }
+    fn take_down_device(&self) -> BoxResult<()> {
+        self.with_state(|state| *state = MockDeviceState::default());
+        Ok(())
+    }
+    fn set_ip(&mut self, ip: &IpAddr, subnet: &IpNet, probe_net: Option<Ipv6Net>) -> BoxResult<()> {
+        self.with_state(|state| {
+            state.ip = Some(*ip);
+            state.subnet = Some(*subnet);
+            state.probe_net = probe_net;
+        });
+        Ok(())
+    }
+    fn add_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        self.with_state(|state| {
+            if state.routes.contains_key(&host) {
+                return Err(format!("Route to {} exists already", host).into());
+            }
+            state.routes.insert(host, (gateway, *attributes));
+            Ok(())
+        })
+    }
+    fn replace_route(
+        &self,
+        host: IpAddr,
+        gateway: Option<IpAddr>,
+        attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        self.with_state(|state| state.routes.insert(host, (gateway, *attributes)));
+        Ok(())
+    }
+    fn del_route(
+        &self,
+        host: IpAddr,
+        _gateway: Option<IpAddr>,
+        _attributes: &RouteAttributes,
+    ) -> BoxResult<()> {
+        match self.with_state(|state| state.routes.remove(&host)) {
+            Some(_) => Ok(()),
+            None => Err(format!("No route to {}", host).into()),
+        }
+    }
+    fn add_net_route(&self, net: IpNet) -> BoxResult<()> {
+        self.with_state(|state| {
+            if !state.net_routes.contains(&net) {
+                state.net_routes.push(net);
+            }
+        });
+        Ok(())
+    }
+    fn del_net_route(&self, net: IpNet) -> BoxResult<()> {
+        self.with_state(|state| state.net_routes.retain(|n| *n != net));
+        Ok(())
+    }
+    fn set_conf(&self, conf: &str) -> BoxResult<()> {
+        self.sync_conf(conf)
+    }
+    fn sync_conf(&self, conf: &str) -> BoxResult<()> {
+        let wg_conf = WgConf::parse(conf)?;
+        self.with_state(|state| {
+            // Like wg syncconf, only a changed endpoint replaces the current one
+            let previous = state.wg_conf();
+            let mut endpoints = HashMap::new();
+            for peer in wg_conf.peers.iter() {
+                let key = base64::encode(peer.public_key);
+                let previous_endpoint = previous
+                    .as_ref()
+                    .and_then(|p| p.peers.iter().find(|pp| pp.public_key == peer.public_key))
+                    .and_then(|pp| pp.endpoint);
+                let endpoint = match peer.endpoint {
+                    Some(endpoint) if Some(endpoint) != previous_endpoint => Some(endpoint),
+                    _ => state.endpoints.get(&key).copied().or(peer.endpoint),
+                };
+                if let Some(endpoint) = endpoint {
+                    endpoints.insert(key, endpoint);
+                }
+            }
+            state.endpoints = endpoints;
+            state.conf = Some(conf.to_string());
+            state.conf_updates += 1;
+        });
+        Ok(())
+    }
+    fn flush_all(&self) -> BoxResult<()> {
+        self.with_state(|state| {
+            state.routes.clear();
+            state.net_routes.clear();
+        });
+        Ok(())
+    }
+    fn retrieve_conf(&self) -> BoxResult<HashMap<String, SocketAddr>> {
+        Ok(self.with_state(|state| state.endpoints.clone()))
+    }
+    fn retrieve_peers(&self) -> BoxResult<Vec<WgPeerConf>> {
+        Ok(self
+            .state()
+            .wg_conf()
+            .map(|conf| conf.peers)
+            .unwrap_or_default())
+    }
+    fn latest_handshakes(&self) -> BoxResult<HashMap<String, u64>> {
+        Ok(self.with_state(|state| state.handshakes.clone()))
+    }
+    fn transfer(&self) -> BoxResult<HashMap<String, (u64, u64)>> {
+        Ok(self.with_state(|state| state.transfer.clone()))
+    }
+    fn altnames(&self) -> BoxResult<Vec<String>> {
+        Ok(self.with_state(|state| state.altnames.clone()))
+    }
+    fn add_altname(&self, altname: &str) -> BoxResult<()> {
+        self.with_state(|state| state.altnames.push(altname.to_string()));
+        Ok(())
+    }
+    fn add_ip(&self, ip: &IpAddr, prefix_len: u8) -> BoxResult<()> {
+        self.with_state(|state| state.extra_ips.push((*ip, prefix_len)));
+        Ok(())
+    }
+    fn del_ip(&self, ip: &IpAddr, prefix_len: u8) -> BoxResult<()> {
+        self.with_state(|state| {
+            state.extra_ips.retain(|e| *e != (*ip, prefix_len));
+            if state.ip == Some(*ip) {
+                state.ip = None;
+            }
+            if state.probe_net.map(|net| IpAddr::V6(net.addr())) == Some(*ip) {
+                state.probe_net = None;
+            }
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,415 @@
+// The wireguard protocol in-process on top of a tun interface.
+//
+// There is no kernel wireguard on android, and the userspace backend on linux does
+// without it, too. The noise implementation of boringtun is used as library. Three
+// threads read the tun, read the udp socket and drive the wireguard timers. All of
+// them share the peer list. The configuration is applied from the same text as for
+// wg setconf/syncconf.
+//
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use defguard_boringtun::noise::handshake::parse_handshake_anon;
+use defguard_boringtun::noise::{Packet, Tunn, TunnResult};
+use defguard_boringtun::x25519::{PublicKey, StaticSecret};
+use ipnet::IpNet;
+use log::*;
+
+use crate::error::*;
+use crate::wg_dev::WgConf;
+
+const MAX_PACKET: usize = 65536;
+const TIMER_INTERVAL: Duration = Duration::from_millis(250);
+const POLL_TIMEOUT_MS: i32 = 500;
+
+struct Peer {
+    public_key: [u8; 32],
+    index: u32,
+    tunn: Tunn,
+    endpoint: Option<SocketAddr>,
+    allowed_ips: Vec<IpNet>,
+}
+
+#[derive(Default)]
+struct Tunnel {
+    private_key: Option<StaticSecret>,
+    listen_port: Option<u16>,
+    socket: Option<Arc<UdpSocket>>,
+    peers: Vec<Peer>,
+    next_index: u32,
+}
+impl Tunnel {
+    fn send_to(&self, packet: &[u8], destination: SocketAddr) {
+        if let Some(socket) = self.socket.as_ref() {
+            // The socket is ipv6 and reaches ipv4 endpoints via mapped addresses
+            let destination = match destination {
+                SocketAddr::V4(sa) => SocketAddr::new(sa.ip().to_ipv6_mapped().into(), sa.port()),
+                SocketAddr::V6(_) => destination,
+            };
+            if let Err(e) = socket.send_to(packet, destination) {
+                debug!(target: "wireguard", "Cannot send to {}: {}", destination, e);
+            }
+        }
+    }
+    fn peer_for_destination(&mut self, dst: IpAddr) -> Option<&mut Peer> {
+        self.peers
+            .iter_mut()
+            .filter_map(|peer| {
+                let prefix_len = peer
+                    .allowed_ips
+                    .iter()
+                    .filter(|net| net.contains(&dst))
+                    .map(|net| net.prefix_len())
+                    .max()?;
+                Some((prefix_len, peer))
+            })
+            .max_by_key(|(prefix_len, _)| *prefix_len)
+            .map(|(_, peer)| peer)
+    }
+    fn peer_index_for_packet(&self, datagram: &[u8]) -> Option<usize> {
+        let receiver_idx = match Tunn::parse_incoming_packet(datagram).ok()? {
+            Packet::HandshakeInit(init) => {
+                let private_key = self.private_key.as_ref()?;
+                let public_key = PublicKey::from(private_key);
+                let half_handshake = parse_handshake_anon(private_key, &public_key, &init).ok()?;
+                return self
+                    .peers
+                    .iter()
+                    .position(|peer| peer.public_key == half_handshake.peer_static_public);
+            }
+            Packet::HandshakeResponse(response) => response.receiver_idx,
+            Packet::PacketCookieReply(reply) => reply.receiver_idx,
+            Packet::PacketData(data) => data.receiver_idx,
+        };
+        // Tunn uses the upper 24 bits of the session index for the peer index
+        self.peers
+            .iter()
+            .position(|peer| peer.index == receiver_idx >> 8)
+    }
+    fn handle_tun_packet(&mut self, packet: &[u8]) {
+        let Some(dst) = Tunn::dst_address(packet) else {
+            return;
+        };
+        let mut out = vec![0u8; MAX_PACKET];
+        let Some(peer) = self.peer_for_destination(dst) else {
+            trace!(target: "wireguard", "No peer for {}", dst);
+            return;
+        };
+        let endpoint = peer.endpoint;
+        if let TunnResult::WriteToNetwork(datagram) = peer.tunn.encapsulate(packet, &mut out) {
+            if let Some(endpoint) = endpoint {
+                self.send_to(datagram, endpoint);
+            }
+        }
+    }
+    fn handle_datagram(&mut self, datagram: &[u8], src_addr: SocketAddr, tun: &File) {
+        let Some(i) = self.peer_index_for_packet(datagram) else {
+            trace!(target: "wireguard", "Drop packet of unknown peer from {}", src_addr);
+            return;
+        };
+        let mut out = vec![0u8; MAX_PACKET];
+        let mut to_network = vec![];
+        let peer = &mut self.peers[i];
+        match peer
+            .tunn
+            .decapsulate(Some(src_addr.ip()), datagram, &mut out)
+        {
+            TunnResult::Done => {}
+            TunnResult::Err(e) => {
+                debug!(target: "wireguard", "Invalid packet from {}: {:?}", src_addr, e);
+                return;
+            }
+            TunnResult::WriteToNetwork(packet) => {
+                to_network.push(packet.to_vec());
+                // Flush the packets queued during the handshake
+                while let TunnResult::WriteToNetwork(packet) =
+                    peer.tunn.decapsulate(None, &[], &mut out)
+                {
+                    to_network.push(packet.to_vec());
+                }
+            }
+            TunnResult::WriteToTunnelV4(packet, src) => {
+                if peer
+                    .allowed_ips
+                    .iter()
+                    .any(|net| net.contains(&IpAddr::V4(src)))
+                {
+                    (&*tun).write_all(packet).ok();
+                }
+            }
+            TunnResult::WriteToTunnelV6(packet, src) => {
+                if peer
+                    .allowed_ips
+                    .iter()
+                    .any(|net| net.contains(&IpAddr::V6(src)))
+                {
+                    (&*tun).write_all(packet).ok();
+                }
+            }
+        }
+        // Valid packet, so follow a roaming peer
+        peer.endpoint = Some(normalize(src_addr));
+        for packet in to_network {
+            self.send_to(&packet, src_addr);
+        }
+    }
+    fn update_timers(&mut self) {
+        let mut out = vec![0u8; MAX_PACKET];
+        let mut to_network = vec![];
+        for peer in self.peers.iter_mut() {
+            match peer.tunn.update_timers(&mut out) {
+                TunnResult::WriteToNetwork(packet) => {
+                    if let Some(endpoint) = peer.endpoint {
+                        to_network.push((packet.to_vec(), endpoint));
+                    }
+                }
+                TunnResult::Err(e) => {
+                    trace!(target: "wireguard", "Timer error: {:?}", e);
+                }
+                _ => {}
+            }
+        }
+        for (packet, endpoint) in to_network {
+            self.send_to(&packet, endpoint);
+        }
+    }
+}
+
+fn normalize(sa: SocketAddr) -> SocketAddr {
+    match sa {
+        SocketAddr::V6(sa_v6) => match sa_v6.ip().to_ipv4_mapped() {
+            Some(ipv4) => SocketAddr::new(ipv4.into(), sa_v6.port()),
+            None => sa,
+        },
+        SocketAddr::V4(_) => sa,
+    }
+}
+
+pub struct UserspaceWireguard {
+    name: String,
+    tun: Arc<File>,
+    tunnel: Arc<Mutex<Tunnel>>,
+    running: Arc<AtomicBool>,
+}
+impl UserspaceWireguard {
+    // The threads are started with the first configuration
+    pub fn new<T: Into<String>>(name: T, tun: File) -> Self {
+        UserspaceWireguard {
+            name: name.into(),
+            tun: Arc::new(tun),
+            tunnel: Arc::new(Mutex::new(Tunnel::default())),
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+    fn start_threads(&self) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        debug!("Start wireguard threads for {}", self.name);
+
+        let tun = self.tun.clone();
+        let tunnel = self.tunnel.clone();
+        let running = self.running.clone();
+        std::thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET];
+            while running.load(Ordering::SeqCst) {
+                let mut pollfd = libc::pollfd {
+                    fd: tun.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // Safety: pollfd is valid for the duration of the call
+                if unsafe { libc::poll(&mut pollfd, 1, POLL_TIMEOUT_MS) } <= 0 {
+                    continue;
+                }
+                match (&*tun).read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => tunnel.lock().unwrap().handle_tun_packet(&buf[..n]),
+                    Err(e) => {
+                        error!(target: "wireguard", "tun read failed: {}", e);
+                        break;
+                    }
+                }
+            }
+            debug!("tun reader stopped");
+        });
+
+        let tun = self.tun.clone();
+        let tunnel = self.tunnel.clone();
+        let running = self.running.clone();
+        std::thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET];
+            while running.load(Ordering::SeqCst) {
+                // The socket is replaced on listen port changes
+                let Some(socket) = tunnel.lock().unwrap().socket.clone() else {
+                    std::thread::sleep(TIMER_INTERVAL);
+                    continue;
+                };
+                if let Ok((n, src_addr)) = socket.recv_from(&mut buf) {
+                    tunnel
+                        .lock()
+                        .unwrap()
+                        .handle_datagram(&buf[..n], src_addr, &tun);
+                }
+            }
+            debug!("udp reader stopped");
+        });
+
+        let tunnel = self.tunnel.clone();
+        let running = self.running.clone();
+        std::thread::spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                tunnel.lock().unwrap().update_timers();
+                std::thread::sleep(TIMER_INTERVAL);
+            }
+            debug!("wireguard timers stopped");
+        });
+    }
+    // set_new like wg setconf, otherwise like wg syncconf
+    pub fn update_conf(&self, conf: &str, set_new: bool) -> BoxResult<()> {
+        debug!(target: "wireguard", "Update configuration: {}", conf);
+        let conf = WgConf::parse(conf)?;
+        let mut tunnel = self.tunnel.lock().unwrap();
+
+        if let Some(private_key) = conf.private_key {
+            let changed = tunnel
+                .private_key
+                .as_ref()
+                .map(|key| key.to_bytes() != private_key)
+                .unwrap_or(true);
+            if changed {
+                // The sessions are bound to the old key
+                tunnel.private_key = Some(StaticSecret::from(private_key));
+                tunnel.peers.clear();
+            }
+        }
+        if let Some(port) = conf
+            .listen_port
+            .filter(|port| tunnel.listen_port != Some(*port))
+        {
+            let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port))?;
+            socket.set_read_timeout(Some(Duration::from_millis(POLL_TIMEOUT_MS as u64)))?;
+            debug!(target: "wireguard", "Listen on port {}", port);
+            tunnel.socket = Some(Arc::new(socket));
+            tunnel.listen_port = conf.listen_port;
+        }
+        let private_key = tunnel
+            .private_key
+            .clone()
+            .ok_or("wireguard configuration without private key")?;
+
+        let mut old_peers = tunnel
+            .peers
+            .drain(..)
+            .map(|peer| (peer.public_key, peer))
+            .collect::<HashMap<_, _>>();
+        for peer_conf in conf.peers {
+            let peer = match old_peers.remove(&peer_conf.public_key) {
+                Some(mut peer) => {
+                    if peer_conf.endpoint.is_some() || set_new {
+                        peer.endpoint = peer_conf.endpoint;
+                    }
+                    peer.allowed_ips = peer_conf.allowed_ips;
+                    // Tunn has no setter for the keepalive, so a new session is needed
+                    if peer.tunn.persistent_keepalive() != peer_conf.persistent_keepalive {
+                        peer.tunn = Tunn::new(
+                            private_key.clone(),
+                            PublicKey::from(peer_conf.public_key),
+                            None,
+                            peer_conf.persistent_keepalive,
+                            peer.index,
+                            None,
+                        );
+                    }
+                    peer
+                }
+                None => {
+                    let index = tunnel.next_index;
+                    tunnel.next_index = (index + 1) & 0x00ff_ffff;
+                    Peer {
+                        public_key: peer_conf.public_key,
+                        index,
+                        tunn: Tunn::new(
+                            private_key.clone(),
+                            PublicKey::from(peer_conf.public_key),
+                            None,
+                            peer_conf.persistent_keepalive,
+                            index,
+                            None,
+                        ),
+                        endpoint: peer_conf.endpoint,
+                        allowed_ips: peer_conf.allowed_ips,
+                    }
+                }
+            };
+            tunnel.peers.push(peer);
+        }
+        drop(tunnel);
+
+        self.start_threads();
+        Ok(())
+    }
+    // The threads end within POLL_TIMEOUT_MS
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        let mut tunnel = self.tunnel.lock().unwrap();
+        tunnel.peers.clear();
+        tunnel.socket = None;
+        tunnel.listen_port = None;
+    }
+    pub fn endpoints(&self) -> HashMap<String, SocketAddr> {
+        let mut pubkey_to_endpoint = HashMap::new();
+        for peer in self.tunnel.lock().unwrap().peers.iter() {
+            if let Some(endpoint) = peer.endpoint {
+                let pub_key = base64::encode(peer.public_key);
+                trace!("{} is endpoint of {}", endpoint, pub_key);
+                pubkey_to_endpoint.insert(pub_key, endpoint);
+            }
+        }
+        pubkey_to_endpoint
+    }
+    pub fn latest_handshakes(&self) -> HashMap<String, u64> {
+        let now = crate::util::wall_now();
+        self.tunnel
+            .lock()
+            .unwrap()
+            .peers
+            .iter()
+            .filter_map(|peer| {
+                let elapsed = peer.tunn.time_since_last_handshake()?;
+                Some((
+                    base64::encode(peer.public_key),
+                    now.saturating_sub(elapsed.as_secs()),
+                ))
+            })
+            .collect()
+    }
+    // Received and sent bytes per public key
+    pub fn transfer(&self) -> HashMap<String, (u64, u64)> {
+        self.tunnel
+            .lock()
+            .unwrap()
+            .peers
+            .iter()
+            .map(|peer| {
+                let (_, tx_bytes, rx_bytes, _, _) = peer.tunn.stats();
+                (
+                    base64::encode(peer.public_key),
+                    (rx_bytes as u64, tx_bytes as u64),
+                )
+            })
+            .collect()
+    }
+}
+impl Drop for UserspaceWireguard {
+    fn drop(&mut self) {
+        // The last thread closes the tun
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
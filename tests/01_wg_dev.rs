@@ -2,13 +2,14 @@
 mod tests {
     use wg_netmanager::arch_def::*;
     use wg_netmanager::configuration::*;
+    use wg_netmanager::wg_dev::nat_probe_net;
     use wg_netmanager::Arch;
 
     #[allow(dead_code)]
     fn demo_config() -> StaticConfigurationBuilder {
         StaticConfiguration::builder()
             .name("test")
-            .wg_ip("10.1.1.1".parse::<std::net::Ipv4Addr>().unwrap())
+            .wg_ip("10.1.1.1".parse::<std::net::IpAddr>().unwrap())
             .wg_name("wgx")
     }
 
@@ -53,9 +54,10 @@ mod tests {
         let dev_present_after = wg_dev.check_device().unwrap();
         assert!(dev_present_after);
 
-        let subnet: ipnet::Ipv4Net = "10.202.0.0/16".parse().unwrap();
+        let subnet: ipnet::IpNet = "10.202.0.0/16".parse().unwrap();
+        let ip = "10.202.1.1".parse().unwrap();
         wg_dev
-            .set_ip(&"10.202.1.1".parse().unwrap(), &subnet)
+            .set_ip(&ip, &subnet, nat_probe_net(&ip, &subnet))
             .unwrap();
 
         wg_dev.take_down_device().unwrap();
@@ -78,9 +80,10 @@ mod tests {
         let dev_present_after = wg_dev.check_device().unwrap();
         assert!(dev_present_after);
 
-        let subnet: ipnet::Ipv4Net = "10.203.0.0/16".parse().unwrap();
+        let subnet: ipnet::IpNet = "10.203.0.0/16".parse().unwrap();
+        let ip = "10.203.1.1".parse().unwrap();
         wg_dev
-            .set_ip(&"10.203.1.1".parse().unwrap(), &subnet)
+            .set_ip(&ip, &subnet, nat_probe_net(&ip, &subnet))
             .unwrap();
 
         wg_dev
@@ -97,4 +100,46 @@ mod tests {
         let dev_present_final = wg_dev.check_device().unwrap();
         assert!(!dev_present_final);
     }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn test_userspace_process() {
+        use std::cell::RefCell;
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::{ExitStatus, Output};
+        use wg_netmanager::userspace_process::*;
+
+        assert_eq!(
+            pid_file("wgtest9").to_string_lossy(),
+            "/var/run/wireguard/wgtest9.wg_netmanager.pid"
+        );
+        assert_eq!(
+            command_line("wireguard-go", "wgtest9"),
+            ["wireguard-go", "--foreground", "wgtest9"]
+        );
+
+        // Two orphans found via their command lines
+        let executed = RefCell::new(vec![]);
+        let run = |args: Vec<&str>, _: Option<&str>| {
+            executed.borrow_mut().push(args.join(" "));
+            let stdout = if args[0] == "pgrep" {
+                assert_eq!(args[2], pgrep_pattern("wgtest9"));
+                b"123\n456\n".to_vec()
+            } else {
+                vec![]
+            };
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout,
+                stderr: vec![],
+            })
+        };
+        assert_eq!(find("wgtest9", &run), vec![123, 456]);
+        executed.borrow_mut().clear();
+        stop("wgtest9", &run);
+        assert_eq!(
+            executed.borrow()[1..],
+            ["kill 123".to_string(), "kill 456".to_string()]
+        );
+    }
 }
@@ -1,38 +1,41 @@
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
-    use std::net::Ipv4Addr;
+    use std::net::{IpAddr, SocketAddr};
 
     use log::*;
 
     use wg_netmanager::configuration::*;
     use wg_netmanager::crypt_udp::*;
     use wg_netmanager::event::*;
+    use wg_netmanager::identity::*;
     use wg_netmanager::manager::*;
+    use wg_netmanager::mesh_event::*;
+    use wg_netmanager::node::{PATH_TIMEOUT, RELAY_FALLBACK};
+    use wg_netmanager::readdress::*;
+    use wg_netmanager::routedb::{RouteDBManager, RouteInfo};
 
     fn get_test_config() -> StaticConfiguration {
-        StaticConfiguration {
-            name: "Test".to_string(),
-            ip_list: vec![],
-            wg_name: "wg_test".to_string(),
-            wg_ip: "10.1.1.1".parse().unwrap(),
-            wg_port: 50000,
-            admin_port: 50001,
-            subnet: "10.1.1.1/8".parse().unwrap(),
-            shared_key: vec![],
-            my_private_key: "".to_string(),
-            my_public_key: PublicKeyWithTime {
-                key: "".to_string(),
-                priv_key_creation_time: 0,
-            },
-            peers: HashMap::new(),
-            use_tui: false,
-            use_existing_interface: false,
-            network_yaml_filename: "".to_string(),
-            is_static: true,
-            wg_hopping: false,
-            peer_yaml_filename: None,
-        }
+        // A static node without peers
+        let mut static_config = StaticConfiguration::builder()
+            .name("Test")
+            .ip_list(vec![])
+            .wg_ip("10.1.1.1".parse::<IpAddr>().unwrap())
+            .wg_name("wg_test")
+            .wg_port(50000)
+            .wg_hopping(false)
+            .admin_port(50001)
+            .subnet("10.1.1.1/8".parse().unwrap())
+            .shared_key(vec![])
+            .my_private_key("")
+            .my_public_key(PublicKeyWithTime::new("", 0))
+            .use_tui(false)
+            .use_existing_interface(false)
+            .network_yaml_filename("")
+            .identity(NodeIdentity::from_seed(&[1; 32]))
+            .build();
+        static_config.is_static = true;
+        static_config
     }
 
     #[test]
@@ -42,40 +45,239 @@ mod tests {
         assert_eq!(mgr.get_route_changes().len(), 0);
     }
 
+    #[test]
+    fn test_node_schedule() {
+        let mut config = get_test_config();
+        let peer_ip: IpAddr = "10.1.1.2".parse().unwrap();
+        config.peers.insert(
+            peer_ip,
+            PublicPeer::new(peer_ip, "127.0.0.1:50002", 50002, 50502),
+        );
+        let mut mgr = NetworkManager::new(&config);
+        assert_eq!(mgr.next_due(), Some(0));
+        assert!(!mgr.process_due_nodes(100, &config).is_empty());
+
+        // Not processed again before its next processing time
+        let next = mgr.next_due().unwrap();
+        assert!(next > 100);
+        assert!(mgr.process_due_nodes(next - 1, &config).is_empty());
+        assert_eq!(mgr.next_due(), Some(next));
+        assert!(mgr.process_timeouts(next - 1).is_empty());
+    }
+
+    #[test]
+    fn test_static_peer_tiers() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let mut config = get_test_config();
+        for (wg_ip, tier) in [("10.1.1.2", 1), ("10.1.1.3", 2)] {
+            let mut peer = PublicPeer::new(ip(wg_ip), "127.0.0.1:50002", 50002, 50502);
+            peer.tier = tier;
+            config.peers.insert(ip(wg_ip), peer);
+        }
+        let contacted = |events: Vec<Event>| {
+            let mut contacted = events
+                .into_iter()
+                .filter_map(|evt| match evt {
+                    Event::SendAdvertisement {
+                        addressed_to: AddressedTo::StaticAddress,
+                        wg_ip,
+                        ..
+                    } => Some(wg_ip),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            contacted.dedup();
+            contacted
+        };
+        let now = wg_netmanager::util::now();
+        let mut mgr = NetworkManager::new(&config);
+        assert_eq!(mgr.bootstrap_tier(), 1);
+        assert_eq!(
+            contacted(mgr.process_all_nodes_every_second(now, &config)),
+            vec![ip("10.1.1.2")]
+        );
+
+        // Fall back to tier 2, if tier 1 is not reached
+        mgr.process_timeouts(now + TIER_FALLBACK - 1);
+        assert_eq!(mgr.bootstrap_tier(), 1);
+        mgr.process_timeouts(now + TIER_FALLBACK);
+        assert_eq!(mgr.bootstrap_tier(), 2);
+        assert!(
+            contacted(mgr.process_due_nodes(now + TIER_FALLBACK, &config))
+                .contains(&ip("10.1.1.3"))
+        );
+
+        // Back to tier 1, as soon as it is reached
+        let ad = advertisement_of(ip("10.1.1.2"));
+        mgr.analyze_advertisement(now, &config, ad, "127.0.0.1:50502".parse().unwrap());
+        mgr.process_timeouts(now + TIER_FALLBACK + 1);
+        assert_eq!(mgr.bootstrap_tier(), 1);
+
+        // The gateway of the lower tier wins between paths of equal length
+        let ad = advertisement_of(ip("10.1.1.3"));
+        mgr.analyze_advertisement(now, &config, ad, "127.0.0.1:50502".parse().unwrap());
+        for sender in ["10.1.1.3", "10.1.1.2"] {
+            mgr.process_route_database(RouteDatabasePacket {
+                sender: ip(sender),
+                routedb_version: 1,
+                nr_entries: 1,
+                chunk: 0,
+                nr_chunks: 1,
+                known_routes: vec![RouteInfo {
+                    to: ip("10.1.1.50"),
+                    local_admin_port: 0,
+                    hop_cnt: 0,
+                    gateway: None,
+                    area_summary: None,
+                    exported_nets: vec![],
+                    cost: 0,
+                    bandwidth_mbit: None,
+                }],
+            });
+        }
+        mgr.get_route_changes();
+        let gateway = mgr
+            .peer_infos(now)
+            .into_iter()
+            .find(|p| p.wg_ip == ip("10.1.1.50"))
+            .and_then(|p| p.gateway);
+        assert_eq!(gateway, Some(ip("10.1.1.2")));
+    }
+
+    #[test]
+    fn test_configuration_output() {
+        let mut config = get_test_config();
+        config.my_private_key = "MyPrivKey".to_string();
+        config.shared_key = vec![1, 2, 3];
+
+        let yaml = config.output(false).to_yaml().unwrap();
+        assert!(!yaml.contains("MyPrivKey"));
+        assert!(!yaml.contains("AQID"));
+        assert_eq!(yaml, config.output(false).to_yaml().unwrap());
+
+        let json = config.output(true).to_json().unwrap();
+        assert!(json.contains("MyPrivKey"));
+        assert!(json.contains("AQID"));
+
+        let wg = config.initial_wg_configuration(false);
+        assert!(wg.starts_with("[Interface]\nPrivateKey = <redacted>\n"));
+    }
+
+    #[test]
+    fn test_interface_per_network() {
+        assert_eq!(interface_name_for_network("home"), "wghome");
+        assert_eq!(
+            interface_name_for_network("corporate.eastcoast"),
+            interface_name_for_network("corporate.eastside")
+        );
+
+        let mut config = get_test_config();
+        config.network_name = Some("home".to_string());
+        assert_eq!(config.altname(), Some("wgnm-home".to_string()));
+        assert!(config.check_interface_owner(&[]).is_ok());
+        assert!(config
+            .check_interface_owner(&["wgnm-home".to_string(), "other".to_string()])
+            .is_ok());
+        assert!(config
+            .check_interface_owner(&["wgnm-office".to_string()])
+            .is_err());
+    }
+
+    #[test]
+    fn test_mesh_dns() {
+        let network = |dns: &str| {
+            NetworkConfiguration::from_yaml_str(&format!(
+                "network:\n  sharedKey: AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\n  subnet: 10.1.0.0/16\n{}\
+                 peers:\n  - endPoint: 127.0.0.1:40000\n    wgIp: 10.1.0.1\n    adminPort: 40001\n",
+                dns
+            ))
+        };
+        assert_eq!(network("").unwrap().dns, None);
+        assert!(network("  dns:\n    servers: []\n").is_err());
+        assert!(network("  dns:\n    servers:\n      - no ip\n").is_err());
+        let dns = network("  dns:\n    servers:\n      - 10.1.0.1\n    domains:\n      - ~mesh\n")
+            .unwrap()
+            .dns
+            .unwrap();
+        assert_eq!(dns.servers, vec!["10.1.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(dns.domains, vec!["mesh".to_string()]);
+    }
+
+    #[test]
+    fn test_port_allocation() {
+        let network = |allocation: &str| {
+            NetworkConfiguration::from_yaml_str(&format!(
+                "network:\n  sharedKey: AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\n  subnet: 10.1.0.0/16\n{}\
+                 peers:\n  - endPoint: 127.0.0.1:40000\n    wgIp: 10.1.0.1\n    adminPort: 40001\n",
+                allocation
+            ))
+        };
+        let a: IpAddr = "10.1.1.7".parse().unwrap();
+        let b: IpAddr = "10.1.2.7".parse().unwrap();
+
+        let net = network("").unwrap();
+        assert_eq!(net.port_allocation, PortAllocation::default());
+        assert_eq!(net.ports_for(&a), Some((50007, 50507)));
+        assert_eq!(net.ports_for(&a), net.ports_for(&b));
+        // A static peer uses the ports from network.yaml
+        assert_eq!(
+            net.ports_for(&"10.1.0.1".parse().unwrap()),
+            Some((40000, 40001))
+        );
+
+        let net = network(
+            "  portAllocation:\n    strategy: hash\n    range: 1000\n    adminPort: 51000\n",
+        )
+        .unwrap();
+        let (wg_port, admin_port) = net.ports_for(&a).unwrap();
+        assert!((50000..51000).contains(&wg_port));
+        assert_eq!(admin_port, wg_port + 1000);
+        assert_ne!(net.ports_for(&a), net.ports_for(&b));
+
+        let net = network("  portAllocation:\n    strategy: fixed\n").unwrap();
+        assert_eq!(net.ports_for(&a), Some((50000, 50500)));
+        assert_eq!(net.ports_for(&b), Some((50000, 50500)));
+
+        let net = network("  portAllocation:\n    strategy: explicit\n").unwrap();
+        assert_eq!(net.ports_for(&a), None);
+
+        // The default range of 500 overlaps with the admin ports
+        assert!(network("  portAllocation:\n    strategy: hash\n    adminPort: 50100\n").is_err());
+        assert!(network("  portAllocation:\n    strategy: random\n").is_err());
+    }
+
     #[test]
     fn test_with_one_dynamic_peer() {
         //wg_netmanager::error::set_up_logging(log::LevelFilter::Trace, None);
 
-        let peer_ip: Ipv4Addr = "10.1.1.2".parse().unwrap();
+        let peer_ip: IpAddr = "10.1.1.2".parse().unwrap();
 
-        let public_key = PublicKeyWithTime {
-            key: "".to_string(),
-            priv_key_creation_time: 0,
-        };
-        let static_config = StaticConfiguration {
-            name: "test".to_string(),
-            ip_list: vec![],
-            wg_ip: "10.1.1.1".parse().unwrap(),
-            wg_name: "wg0".to_string(),
-            wg_port: 55555,
-            admin_port: 50000,
-            subnet: "192.168.1.1/24".parse().unwrap(),
-            shared_key: vec![],
-            my_private_key: "".to_string(),
-            my_public_key: public_key.clone(),
-            peers: HashMap::new(),
-            use_tui: false,
-            use_existing_interface: true,
-            network_yaml_filename: "".to_string(),
-            is_static: true,
-            wg_hopping: false,
-            peer_yaml_filename: None,
-        };
-        let mut mgr = NetworkManager::new(&static_config);
-
-        let ad = AdvertisementPacket {
+        let public_key = PublicKeyWithTime::new("", 0);
+        let mut static_config = StaticConfiguration::builder()
+            .name("test")
+            .ip_list(vec![])
+            .wg_ip("10.1.1.1".parse::<IpAddr>().unwrap())
+            .wg_name("wg0")
+            .wg_port(55555)
+            .wg_hopping(false)
+            .admin_port(50000)
+            .subnet("192.168.1.1/24".parse().unwrap())
+            .shared_key(vec![])
+            .my_private_key("")
+            .my_public_key(public_key.clone())
+            .use_tui(false)
+            .use_existing_interface(true)
+            .network_yaml_filename("")
+            .identity(NodeIdentity::from_seed(&[1; 32]))
+            .build();
+        static_config.is_static = true;
+        let mut mgr = NetworkManager::new(&static_config);
+        let mesh_events = mgr.subscribe();
+
+        let mut ad = AdvertisementPacket {
             addressed_to: AddressedTo::StaticAddress,
             public_key,
+            next_public_key: None,
             local_wg_port: 0,
             local_admin_port: 0,
             wg_ip: peer_ip,
@@ -83,7 +285,15 @@ mod tests {
             your_visible_wg_endpoint: Some("192.168.1.1:1".parse().unwrap()),
             my_visible_wg_endpoint: Some("192.168.1.2:1".parse().unwrap()),
             routedb_version: 0,
+            exported_nets: vec![],
+            extra_wg_ports: vec![],
+            force_relay_s: 0,
+            revocations: vec![],
+            moved_from: None,
+            identity: [0; 32],
+            signature: vec![],
         };
+        ad.sign(&peer_identity());
         let now = wg_netmanager::util::now();
 
         let events =
@@ -91,17 +301,35 @@ mod tests {
 
         trace!("{:#?}", events);
         for evt in events {
-            match evt {
-                Event::UpdateRoutes => {}
-                _ => {}
-            }
+            if let Event::UpdateRoutes = evt {}
         }
 
         assert_eq!(mgr.get_route_changes().len(), 1);
         assert_eq!(mgr.get_route_changes().len(), 0);
+        assert_eq!(
+            mesh_events.try_iter().collect::<Vec<_>>(),
+            vec![
+                MeshEvent::PeerUp {
+                    wg_ip: peer_ip,
+                    name: Some("test".to_string())
+                },
+                MeshEvent::RouteAdded {
+                    to: peer_ip,
+                    gateway: None
+                },
+            ]
+        );
+
+        let lookup = mgr.route_lookup(now, "test").unwrap();
+        assert_eq!(lookup.to, peer_ip);
+        assert_eq!(lookup.gateway, None);
+        assert_eq!(lookup.endpoint_class, "passive");
+        assert!(lookup.alive);
+        assert_eq!(Some(lookup), mgr.route_lookup(now, "10.1.1.2"));
+        assert!(mgr.route_lookup(now, "10.1.1.3").is_none());
 
         println!("ROUTE");
-        for udp in mgr.provide_route_database() {
+        for udp in mgr.provide_route_database(peer_ip) {
             use UdpPacket::*;
             match udp {
                 Advertisement(_) => {}
@@ -111,6 +339,15 @@ mod tests {
                 }
                 LocalContactRequest => {}
                 LocalContact(_) => {}
+                AuditRequest => {}
+                Audit(_) => {}
+                RouteDatabaseDeltaRequest { .. } => {}
+                RouteDatabaseDelta(_) => {}
+                Ping { .. } | Pong { .. } => {}
+                ConnectRequest(_) => {}
+                AddressRequest(_) | AddressLease(_) | HaHeartbeat(_) | Goodbye
+                | AddressConflict(_) => {}
+                FleetCommand(_) | FleetResult(_) => {}
             }
         }
 
@@ -121,5 +358,1542 @@ mod tests {
 
         assert_eq!(mgr.get_route_changes().len(), 1);
         assert_eq!(mgr.get_route_changes().len(), 0);
+        assert_eq!(
+            mesh_events.try_iter().collect::<Vec<_>>(),
+            vec![
+                MeshEvent::PeerDown { wg_ip: peer_ip },
+                MeshEvent::RouteRemoved { to: peer_ip },
+            ]
+        );
+    }
+
+    fn peer_identity() -> NodeIdentity {
+        NodeIdentity::from_seed(&[2; 32])
+    }
+
+    fn advertisement_of(peer_ip: IpAddr) -> AdvertisementPacket {
+        let mut ad = AdvertisementPacket {
+            addressed_to: AddressedTo::StaticAddress,
+            public_key: PublicKeyWithTime::new("peer", 0),
+            next_public_key: None,
+            local_wg_port: 0,
+            local_admin_port: 0,
+            wg_ip: peer_ip,
+            name: "test".to_string(),
+            your_visible_wg_endpoint: None,
+            my_visible_wg_endpoint: None,
+            routedb_version: 0,
+            exported_nets: vec![],
+            extra_wg_ports: vec![],
+            force_relay_s: 0,
+            revocations: vec![],
+            moved_from: None,
+            identity: [0; 32],
+            signature: vec![],
+        };
+        ad.sign(&peer_identity());
+        ad
+    }
+
+    #[test]
+    fn test_ipv6_overlay() {
+        let peer_ip: IpAddr = "fd42::2".parse().unwrap();
+        let mut static_config = get_test_config();
+        static_config.wg_ip = "fd42::1".parse().unwrap();
+        static_config.subnet = "fd42::/64".parse().unwrap();
+        let mut mgr = NetworkManager::new(&static_config);
+
+        let now = wg_netmanager::util::now();
+        let ad = advertisement_of(peer_ip);
+        mgr.analyze_advertisement(now, &static_config, ad, "[2001:db8::2]:2".parse().unwrap());
+
+        let changes = mgr.get_route_changes();
+        assert_eq!(changes.len(), 1);
+        assert!(
+            matches!(changes[0], RouteChange::AddRoute { to, gateway: None, .. } if to == peer_ip)
+        );
+
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("AllowedIPs = fd42::2/128"));
+        assert!(!conf.contains("fd00"));
+    }
+
+    #[test]
+    fn test_disable_ipv6() {
+        let peer_ip: IpAddr = "10.1.1.2".parse().unwrap();
+        let mut static_config = get_test_config();
+        assert!(static_config.nat_probe_net().is_some());
+        static_config.disable_ipv6 = true;
+        assert!(static_config.nat_probe_net().is_none());
+        let mut mgr = NetworkManager::new(&static_config);
+
+        let now = wg_netmanager::util::now();
+        let ad = advertisement_of(peer_ip);
+        mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        assert_eq!(mgr.get_route_changes().len(), 1);
+
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("AllowedIPs = 10.1.1.2/32"));
+        assert!(!conf.contains("fd00"));
+    }
+
+    fn ping_destinations(mgr: &mut NetworkManager, now: u64) -> Vec<std::net::SocketAddr> {
+        mgr.process_all_nodes_every_second(now, &get_test_config())
+            .into_iter()
+            .filter_map(|evt| match evt {
+                Event::SendAdvertisement { to, .. } => Some(to),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_persistent_keepalive() {
+        let peer_ip: IpAddr = "10.1.1.2".parse().unwrap();
+        let mut static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+
+        let now = wg_netmanager::util::now();
+        let ad = advertisement_of(peer_ip);
+        mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        let mut pubkey_to_endpoint = HashMap::new();
+        pubkey_to_endpoint.insert("peer".to_string(), "192.168.1.1:50000".parse().unwrap());
+        mgr.current_wireguard_configuration(pubkey_to_endpoint);
+
+        // The passive peer is behind NAT, so it gets the default keepalive
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("PersistentKeepalive = 25"));
+        let mut handshakes = HashMap::new();
+        handshakes.insert("peer".to_string(), now - 10);
+        assert!(mgr
+            .check_handshakes(now, &static_config, &handshakes)
+            .is_empty());
+        handshakes.insert("peer".to_string(), now - 200);
+        let events = mgr.check_handshakes(now, &static_config, &handshakes);
+        assert!(matches!(
+            events[..],
+            [Event::SendAdvertisement { wg_ip, .. }] if wg_ip == peer_ip
+        ));
+
+        static_config.persistent_keepalive = Some(0);
+        assert!(!static_config
+            .to_wg_configuration(&mgr)
+            .contains("PersistentKeepalive"));
+        assert!(mgr
+            .check_handshakes(now, &static_config, &handshakes)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_changed_admin_port() {
+        let peer_ip: IpAddr = "10.1.1.2".parse().unwrap();
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+        let advertisement = |addressed_to: AddressedTo, local_admin_port: u16| {
+            let mut ad = advertisement_of(peer_ip);
+            ad.addressed_to = addressed_to;
+            ad.local_wg_port = 50002;
+            ad.local_admin_port = local_admin_port;
+            ad.sign(&peer_identity());
+            ad
+        };
+
+        let now = wg_netmanager::util::now();
+        let ad = advertisement(AddressedTo::StaticAddress, 50502);
+        mgr.analyze_advertisement(now, &static_config, ad, "10.1.1.2:50502".parse().unwrap());
+        mgr.get_route_changes();
+        assert_eq!(
+            ping_destinations(&mut mgr, now + 30),
+            vec!["10.1.1.2:50502".parse().unwrap()]
+        );
+
+        // The peer has restarted with another admin port and pings via the tunnel
+        let ad = advertisement(AddressedTo::WireguardAddress, 50602);
+        let events = mgr.analyze_advertisement(
+            now + 31,
+            &static_config,
+            ad,
+            "10.1.1.2:50602".parse().unwrap(),
+        );
+        assert!(events.iter().any(|evt| matches!(evt, Event::UpdateRoutes)));
+        assert_eq!(
+            ping_destinations(&mut mgr, now + 61),
+            vec!["10.1.1.2:50602".parse().unwrap()]
+        );
+        mgr.get_route_changes();
+        assert_eq!(mgr.routes()[0].local_admin_port, 50602);
+
+        // Implausible ports are ignored
+        let ad = advertisement(AddressedTo::WireguardAddress, 50002);
+        mgr.analyze_advertisement(
+            now + 62,
+            &static_config,
+            ad,
+            "10.1.1.9:50702".parse().unwrap(),
+        );
+        let ad = advertisement(AddressedTo::WireguardAddress, 0);
+        mgr.analyze_advertisement(now + 62, &static_config, ad, "10.1.1.2:0".parse().unwrap());
+        mgr.get_route_changes();
+        assert_eq!(mgr.routes()[0].local_admin_port, 50602);
+        assert_eq!(
+            ping_destinations(&mut mgr, now + 92),
+            vec!["10.1.1.2:50602".parse().unwrap()]
+        );
+    }
+    #[test]
+    fn test_path_mtu() {
+        let peer_ip: IpAddr = "10.1.1.2".parse().unwrap();
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+
+        let now = wg_netmanager::util::now();
+        let ad = advertisement_of(peer_ip);
+        mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        assert_eq!(mgr.get_route_changes().len(), 1);
+
+        assert!(mgr.set_path_mtu(peer_ip, Some(1280)));
+        assert!(!mgr.set_path_mtu(peer_ip, Some(1280)));
+        let changes = mgr.get_route_changes();
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(
+            changes[0],
+            RouteChange::ReplaceRoute { gateway: None, attributes, .. } if attributes.mtu == Some(1280)
+        ));
+        assert_eq!(mgr.get_route_changes().len(), 0);
+
+        assert!(mgr.set_path_mtu(peer_ip, None));
+        let changes = mgr.get_route_changes();
+        assert!(matches!(
+            changes[0],
+            RouteChange::ReplaceRoute { attributes, .. } if attributes.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_pinned_route() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let mut static_config = get_test_config();
+        static_config
+            .pinned_routes
+            .insert(ip("10.1.1.50"), ip("10.1.1.3"));
+        let mut mgr = NetworkManager::new(&static_config);
+
+        let now = wg_netmanager::util::now();
+        for peer in ["10.1.1.2", "10.1.1.3"] {
+            let ad = advertisement_of(ip(peer));
+            mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        }
+        let route = |to: &str, hop_cnt: usize, gateway: Option<&str>| RouteInfo {
+            to: ip(to),
+            local_admin_port: 0,
+            hop_cnt,
+            gateway: gateway.map(ip),
+            area_summary: None,
+            exported_nets: vec![],
+            cost: 0,
+            bandwidth_mbit: None,
+        };
+        let routedb = |sender: &str, routedb_version: usize, known_routes: Vec<RouteInfo>| {
+            RouteDatabasePacket {
+                sender: ip(sender),
+                routedb_version,
+                nr_entries: known_routes.len(),
+                chunk: 0,
+                nr_chunks: 1,
+                known_routes,
+            }
+        };
+        // 10.1.1.2 reaches 10.1.1.50 directly, 10.1.1.3 only via 10.1.1.60
+        mgr.process_route_database(routedb("10.1.1.2", 1, vec![route("10.1.1.50", 0, None)]));
+        mgr.process_route_database(routedb(
+            "10.1.1.3",
+            1,
+            vec![
+                route("10.1.1.60", 0, None),
+                route("10.1.1.50", 1, Some("10.1.1.60")),
+            ],
+        ));
+        mgr.get_route_changes();
+        mgr.get_route_changes();
+        let gateway_to = |mgr: &NetworkManager, to: &str| {
+            mgr.peer_infos(now)
+                .into_iter()
+                .find(|p| p.wg_ip == ip(to))
+                .map(|p| (p.gateway, p.pinned_gateway))
+        };
+        assert_eq!(
+            gateway_to(&mgr, "10.1.1.50"),
+            Some((Some(ip("10.1.1.3")), Some(ip("10.1.1.3"))))
+        );
+        assert_eq!(
+            gateway_to(&mgr, "10.1.1.60"),
+            Some((Some(ip("10.1.1.3")), None))
+        );
+
+        // Fall back to the shortest path, while the pinned gateway offers no route
+        mgr.process_route_database(routedb("10.1.1.3", 2, vec![route("10.1.1.60", 0, None)]));
+        let changes = mgr.get_route_changes();
+        assert!(matches!(
+            changes[..],
+            [RouteChange::ReplaceRoute { to, gateway, .. }] if to == ip("10.1.1.50") && gateway == Some(ip("10.1.1.2"))
+        ));
+        assert_eq!(
+            gateway_to(&mgr, "10.1.1.50"),
+            Some((Some(ip("10.1.1.2")), Some(ip("10.1.1.3"))))
+        );
+        let diff = mgr.last_route_diff().unwrap();
+        assert_eq!(diff.routedb_version, mgr.db_version());
+        assert_eq!(
+            diff.summary(),
+            "0 added, 1 replaced, 0 deleted: ~10.1.1.50 via 10.1.1.2 (was via 10.1.1.3, gateway_lost)"
+        );
+    }
+
+    #[test]
+    fn test_route_withdrawals() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+        let now = wg_netmanager::util::now();
+        for peer in ["10.1.1.2", "10.1.1.3"] {
+            let ad = advertisement_of(ip(peer));
+            mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        }
+        let route = |to: &str, hop_cnt: usize, gateway: Option<&str>| RouteInfo {
+            to: ip(to),
+            local_admin_port: 0,
+            hop_cnt,
+            gateway: gateway.map(ip),
+            area_summary: None,
+            exported_nets: vec![],
+            cost: 0,
+            bandwidth_mbit: None,
+        };
+        let routedb = |sender: &str, routedb_version: usize, known_routes: Vec<RouteInfo>| {
+            RouteDatabasePacket {
+                sender: ip(sender),
+                routedb_version,
+                nr_entries: known_routes.len(),
+                chunk: 0,
+                nr_chunks: 1,
+                known_routes,
+            }
+        };
+        let reasons = |changes: Vec<RouteChange>| {
+            changes
+                .into_iter()
+                .filter_map(|change| match change {
+                    RouteChange::ReplaceRoute {
+                        to,
+                        gateway,
+                        reason,
+                        ..
+                    } => Some(('~', to, gateway, reason)),
+                    RouteChange::DelRoute {
+                        to,
+                        gateway,
+                        reason,
+                        ..
+                    } => Some(('-', to, gateway, reason)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        mgr.process_route_database(routedb(
+            "10.1.1.3",
+            1,
+            vec![
+                route("10.1.1.60", 0, None),
+                route("10.1.1.50", 1, Some("10.1.1.60")),
+            ],
+        ));
+        mgr.get_route_changes();
+        mgr.get_route_changes();
+        assert!(mgr.route_withdrawals().is_empty());
+
+        mgr.process_route_database(routedb("10.1.1.2", 1, vec![route("10.1.1.50", 0, None)]));
+        assert_eq!(
+            reasons(mgr.get_route_changes()),
+            vec![(
+                '~',
+                ip("10.1.1.50"),
+                Some(ip("10.1.1.2")),
+                RouteReason::BetterPath
+            )]
+        );
+        mgr.process_route_database(routedb("10.1.1.2", 2, vec![]));
+        assert_eq!(
+            reasons(mgr.get_route_changes()),
+            vec![(
+                '~',
+                ip("10.1.1.50"),
+                Some(ip("10.1.1.3")),
+                RouteReason::GatewayLost
+            )]
+        );
+        mgr.process_route_database(routedb("10.1.1.3", 2, vec![route("10.1.1.60", 0, None)]));
+        assert_eq!(
+            reasons(mgr.get_route_changes()),
+            vec![(
+                '-',
+                ip("10.1.1.50"),
+                Some(ip("10.1.1.3")),
+                RouteReason::PeerDead
+            )]
+        );
+        assert_eq!(
+            mgr.last_route_diff().unwrap().summary(),
+            "0 added, 0 replaced, 1 deleted: -10.1.1.50 via 10.1.1.3 (peer_dead)"
+        );
+
+        mgr.process_goodbye(&static_config, "10.1.1.2:0".parse().unwrap());
+        assert_eq!(
+            reasons(mgr.get_route_changes()),
+            vec![('-', ip("10.1.1.2"), None, RouteReason::PeerDead)]
+        );
+        let withdrawals = mgr.route_withdrawals();
+        assert_eq!(
+            withdrawals
+                .iter()
+                .map(|w| (w.to, w.gateway, w.deleted, w.reason))
+                .collect::<Vec<_>>(),
+            vec![
+                (ip("10.1.1.2"), None, true, RouteReason::PeerDead),
+                (
+                    ip("10.1.1.50"),
+                    Some(ip("10.1.1.3")),
+                    true,
+                    RouteReason::PeerDead
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_route_diff() {
+        let changes = (1..=5)
+            .map(|i| RouteChange::AddRoute {
+                to: format!("10.1.1.{}", i).parse().unwrap(),
+                gateway: None,
+                attributes: Default::default(),
+            })
+            .chain([RouteChange::DelNetRoute {
+                net: "192.168.5.0/24".parse().unwrap(),
+            }])
+            .collect::<Vec<_>>();
+        let diff = RouteDiff::of(100, 7, &changes, &HashMap::new());
+        assert_eq!(diff.added.len(), 5);
+        assert_eq!(
+            diff.summary(),
+            "5 added, 0 replaced, 1 deleted: +10.1.1.1 direct, +10.1.1.2 direct, +10.1.1.3 direct, + 2 more, -192.168.5.0/24 direct"
+        );
+        assert!(diff.details().contains("+10.1.1.5 direct"));
+        assert!(RouteDiff::of(100, 7, &[], &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_route_cost() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let network = NetworkConfiguration::from_yaml_str(
+            "network:\n  sharedKey: AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\n  subnet: 10.1.1.0/24\n  \
+             routing:\n    metric: cost\n    links:\n      - between: [10.1.1.2, 10.1.1.1]\n        \
+             weight: 500\n        bandwidthMbit: 2\n\
+             peers:\n  - endPoint: 127.0.0.1:40000\n    wgIp: 10.1.1.1\n    adminPort: 40001\n",
+        )
+        .unwrap();
+        assert_eq!(network.routing.metric, RouteMetric::Cost);
+        assert_eq!(
+            network
+                .routing
+                .link_cost(&ip("10.1.1.1"), &ip("10.1.1.2"), Some(100)),
+            (500 + 100 + 500, Some(2))
+        );
+        assert!(NetworkConfiguration::from_yaml_str(
+            "network:\n  sharedKey: AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\n  subnet: 10.1.1.0/24\n  \
+             routing:\n    metric: fastest\n\
+             peers:\n  - endPoint: 127.0.0.1:40000\n    wgIp: 10.1.1.1\n    adminPort: 40001\n",
+        )
+        .is_err());
+
+        let mut static_config = get_test_config();
+        static_config.routing = network.routing;
+        let mut mgr = NetworkManager::new(&static_config);
+        let now = wg_netmanager::util::now();
+        for peer in ["10.1.1.2", "10.1.1.3"] {
+            let ad = advertisement_of(ip(peer));
+            mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        }
+        let route = |to: &str, hop_cnt: usize, gateway: Option<&str>, cost: u32| RouteInfo {
+            to: ip(to),
+            local_admin_port: 0,
+            hop_cnt,
+            gateway: gateway.map(ip),
+            area_summary: None,
+            exported_nets: vec![],
+            cost,
+            bandwidth_mbit: None,
+        };
+        let routedb = |sender: &str, known_routes: Vec<RouteInfo>| RouteDatabasePacket {
+            sender: ip(sender),
+            routedb_version: 1,
+            nr_entries: known_routes.len(),
+            chunk: 0,
+            nr_chunks: 1,
+            known_routes,
+        };
+        // 10.1.1.2 is a satellite link with one hop to 10.1.1.50, 10.1.1.3 is fast
+        mgr.process_route_database(routedb("10.1.1.2", vec![route("10.1.1.50", 0, None, 10)]));
+        mgr.process_route_database(routedb(
+            "10.1.1.3",
+            vec![
+                route("10.1.1.60", 0, None, 10),
+                route("10.1.1.50", 1, Some("10.1.1.60"), 20),
+            ],
+        ));
+        // The first pass learns 10.1.1.60 as gateway
+        mgr.get_route_changes();
+        mgr.get_route_changes();
+        let via = |mgr: &NetworkManager, to: &str| {
+            mgr.peer_infos(now)
+                .into_iter()
+                .find(|p| p.wg_ip == ip(to))
+                .map(|p| (p.gateway, p.cost))
+        };
+        assert_eq!(
+            via(&mgr, "10.1.1.50"),
+            Some((Some(ip("10.1.1.3")), Some(DEFAULT_LINK_WEIGHT + 20)))
+        );
+
+        // Pings to the direct peers measure the round trip time
+        let pings = mgr
+            .process_timeouts(now)
+            .into_iter()
+            .filter(|evt| matches!(evt, Event::SendPing { .. }))
+            .count();
+        assert_eq!(pings, 2);
+        assert!(mgr.process_timeouts(now + 1).is_empty());
+        assert!(matches!(
+            mgr.process_pong(ip("10.1.1.3"), 1200)[..],
+            [Event::UpdateRoutes]
+        ));
+        mgr.get_route_changes();
+        assert_eq!(
+            via(&mgr, "10.1.1.50"),
+            Some((Some(ip("10.1.1.2")), Some(500 + 500 + 10)))
+        );
+        // Jitter does not change the cost
+        assert!(mgr.process_pong(ip("10.1.1.3"), 1300).is_empty());
+        assert_eq!(mgr.rtt_ms(&ip("10.1.1.3")), Some(1200));
+        // The latency table shows the smoothed measurement
+        let rtt = |mgr: &NetworkManager, to: &str| {
+            mgr.peer_infos(now)
+                .into_iter()
+                .find(|p| p.wg_ip == ip(to))
+                .and_then(|p| p.rtt_ms)
+        };
+        assert_eq!(rtt(&mgr, "10.1.1.3"), Some(1225));
+        assert_eq!(rtt(&mgr, "10.1.1.50"), None);
+        assert!(mgr.process_pong(ip("10.1.1.50"), 1).is_empty());
+    }
+
+    #[test]
+    fn test_force_relay() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+
+        let now = wg_netmanager::util::now();
+        for peer in ["10.1.1.2", "10.1.1.3"] {
+            let ad = advertisement_of(ip(peer));
+            mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        }
+        mgr.process_route_database(RouteDatabasePacket {
+            sender: ip("10.1.1.3"),
+            routedb_version: 1,
+            nr_entries: 1,
+            chunk: 0,
+            nr_chunks: 1,
+            known_routes: vec![RouteInfo {
+                to: ip("10.1.1.2"),
+                local_admin_port: 0,
+                hop_cnt: 0,
+                gateway: None,
+                area_summary: None,
+                exported_nets: vec![],
+                cost: 0,
+                bandwidth_mbit: None,
+            }],
+        });
+        mgr.get_route_changes();
+        let gateway_of = |changes: Vec<RouteChange>| match changes[..] {
+            [RouteChange::ReplaceRoute { to, gateway, .. }] if to == ip("10.1.1.2") => gateway,
+            _ => panic!("unexpected route changes {:?}", changes),
+        };
+
+        assert!(mgr.force_relay(ip("10.1.1.2"), Some(now + 60)));
+        assert_eq!(mgr.force_relay_request(now, &ip("10.1.1.2")), 60);
+        assert_eq!(gateway_of(mgr.get_route_changes()), Some(ip("10.1.1.3")));
+        // Only the gateway's entry routes the relayed peer
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert_eq!(conf.matches("AllowedIPs = 10.1.1.2/32").count(), 1);
+
+        // Expired
+        let events = mgr.process_all_nodes_every_second(now + 60, &static_config);
+        assert!(events.iter().any(|evt| matches!(evt, Event::UpdateRoutes)));
+        assert_eq!(gateway_of(mgr.get_route_changes()), None);
+
+        // Forced by the peer
+        let force_relay_ad = |force_relay_s: u64| {
+            let mut ad = advertisement_of(ip("10.1.1.2"));
+            ad.force_relay_s = force_relay_s;
+            ad.sign(&peer_identity());
+            ad
+        };
+        let src_addr = "192.168.1.1:2".parse().unwrap();
+        let events =
+            mgr.analyze_advertisement(now + 61, &static_config, force_relay_ad(30), src_addr);
+        assert!(events.iter().any(|evt| matches!(evt, Event::UpdateRoutes)));
+        assert_eq!(mgr.force_relay_request(now + 61, &ip("10.1.1.2")), 0);
+        assert_eq!(gateway_of(mgr.get_route_changes()), Some(ip("10.1.1.3")));
+        let events =
+            mgr.analyze_advertisement(now + 62, &static_config, force_relay_ad(0), src_addr);
+        assert!(events.iter().any(|evt| matches!(evt, Event::UpdateRoutes)));
+        assert_eq!(gateway_of(mgr.get_route_changes()), None);
+    }
+
+    #[test]
+    fn test_goodbye() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let static_config = get_test_config();
+        let now = wg_netmanager::util::now();
+        let mut mgr = NetworkManager::new(&static_config);
+        let mut ad = advertisement_of(ip("10.1.1.2"));
+        ad.local_admin_port = 50002;
+        ad.sign(&peer_identity());
+        mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        mgr.get_route_changes();
+        let via_tunnel: std::net::SocketAddr = "10.1.1.2:50002".parse().unwrap();
+        assert_eq!(mgr.direct_peer_admin_addresses(), vec![via_tunnel]);
+
+        // Only trusted via the wireguard tunnel of a direct peer
+        assert!(mgr
+            .process_goodbye(&static_config, "192.168.1.1:2".parse().unwrap())
+            .is_empty());
+        assert!(mgr
+            .process_goodbye(&static_config, "10.1.1.9:50009".parse().unwrap())
+            .is_empty());
+        assert!(mgr.knows_peer(&ip("10.1.1.2")));
+
+        let events = mgr.process_goodbye(&static_config, via_tunnel);
+        assert!(events.iter().any(|evt| matches!(evt, Event::UpdateRoutes)));
+        assert!(events
+            .iter()
+            .any(|evt| matches!(evt, Event::UpdateWireguardConfiguration)));
+        assert!(!mgr.knows_peer(&ip("10.1.1.2")));
+        assert!(mgr.direct_peer_admin_addresses().is_empty());
+        assert!(matches!(
+            mgr.get_route_changes()[..],
+            [RouteChange::DelRoute { to, .. }] if to == ip("10.1.1.2")
+        ));
+    }
+
+    #[test]
+    fn test_revocation() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let static_config = get_test_config();
+        let now = wg_netmanager::util::now();
+        let src_addr = "192.168.1.1:2".parse().unwrap();
+        let lost = NodeIdentity::from_seed(&[3; 32]);
+        let lost_ad = || {
+            let mut ad = advertisement_of(ip("10.1.1.3"));
+            ad.public_key.key = "lost".to_string();
+            ad.sign(&lost);
+            ad
+        };
+
+        let mut mgr = NetworkManager::new(&static_config);
+        mgr.analyze_advertisement(
+            now,
+            &static_config,
+            advertisement_of(ip("10.1.1.2")),
+            src_addr,
+        );
+        mgr.analyze_advertisement(now, &static_config, lost_ad(), src_addr);
+        assert!(mgr.revoke_peer(now, &ip("10.1.1.9")).is_none());
+        assert!(mgr.revoke_peer(now, &static_config.wg_ip).is_none());
+        let revocation = mgr.revoke_peer(now, &ip("10.1.1.3")).unwrap();
+        assert_eq!(revocation.public_key.as_deref(), Some("lost"));
+        assert_eq!(revocation.identity, Some(lost.public()));
+        assert!(!mgr.knows_peer(&ip("10.1.1.3")));
+        assert!(mgr.knows_peer(&ip("10.1.1.2")));
+        // The lost device cannot rejoin
+        assert!(mgr
+            .analyze_advertisement(now, &static_config, lost_ad(), src_addr)
+            .is_empty());
+        assert!(!mgr.knows_peer(&ip("10.1.1.3")));
+
+        // Gossiped for a limited time only
+        assert_eq!(mgr.revocations_to_gossip(now), vec![revocation.clone()]);
+        assert!(mgr
+            .revocations_to_gossip(now + REVOCATION_GOSSIP)
+            .is_empty());
+
+        let mut other = NetworkManager::new(&static_config);
+        other.analyze_advertisement(now, &static_config, lost_ad(), src_addr);
+        assert!(other.knows_peer(&ip("10.1.1.3")));
+        let mut ad = advertisement_of(ip("10.1.1.2"));
+        ad.revocations = mgr.revocations_to_gossip(now);
+        ad.sign(&peer_identity());
+        let events = other.analyze_advertisement(now, &static_config, ad, src_addr);
+        assert!(events.iter().any(|evt| matches!(evt, Event::UpdateRoutes)));
+        assert!(!other.knows_peer(&ip("10.1.1.3")));
+        assert!(!other.learn_revocations(&static_config, &[revocation]));
+        // A revocation of this node is ignored
+        let own = Revocation {
+            wg_ip: ip("10.1.1.4"),
+            public_key: Some(static_config.my_public_key.key.clone()),
+            identity: None,
+            revoked_at: now,
+        };
+        assert!(!other.learn_revocations(&static_config, &[own]));
+        assert_eq!(other.revocations().len(), 1);
+    }
+
+    #[test]
+    fn test_multipath() {
+        let peer_ip: IpAddr = "10.1.1.2".parse().unwrap();
+        let mut static_config = get_test_config();
+        static_config.persistent_keepalive = Some(25);
+        static_config.peers.insert(
+            peer_ip,
+            PublicPeer::new(peer_ip, "192.168.1.2:50002", 50002, 50502),
+        );
+        let mut mgr = NetworkManager::new(&static_config);
+        let now = wg_netmanager::util::now();
+        let ad_of = |addressed_to: AddressedTo| {
+            let mut ad = advertisement_of(peer_ip);
+            ad.addressed_to = addressed_to;
+            ad.extra_wg_ports = vec![50102];
+            ad.sign(&peer_identity());
+            ad
+        };
+        let events = mgr.analyze_advertisement(
+            now,
+            &static_config,
+            ad_of(AddressedTo::ReplyFromStaticAddress),
+            "192.168.1.2:50502".parse().unwrap(),
+        );
+        assert!(events
+            .iter()
+            .any(|evt| matches!(evt, Event::UpdateWireguardConfiguration)));
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("EndPoint = 192.168.1.2:50002"));
+
+        // The tunnel via the wg port is up. The time of an advertisement to a known
+        // node is taken from the clock.
+        mgr.analyze_advertisement(
+            now,
+            &static_config,
+            ad_of(AddressedTo::WireguardAddress),
+            SocketAddr::new(peer_ip, 50502),
+        );
+        mgr.process_all_nodes_every_second(now + PATH_TIMEOUT - 1, &static_config);
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("EndPoint = 192.168.1.2:50002"));
+
+        // No advertisement via the tunnel anymore, so the extra port is tried
+        let events = mgr.process_all_nodes_every_second(now + PATH_TIMEOUT + 1, &static_config);
+        assert!(events
+            .iter()
+            .any(|evt| matches!(evt, Event::UpdateWireguardConfiguration)));
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("EndPoint = 192.168.1.2:50102"));
+
+        // A stale handshake switches back
+        let mut handshakes = HashMap::new();
+        handshakes.insert("peer".to_string(), now);
+        mgr.check_handshakes(now + 220, &static_config, &handshakes);
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("EndPoint = 192.168.1.2:50002"));
+    }
+
+    #[test]
+    fn test_route_database_delta() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+        let now = wg_netmanager::util::now();
+        for peer in ["10.1.1.2", "10.1.1.3"] {
+            let ad = advertisement_of(ip(peer));
+            mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        }
+        mgr.get_route_changes();
+        let routedb_of_3 = |known_routes: Vec<RouteInfo>| RouteDatabasePacket {
+            sender: ip("10.1.1.3"),
+            routedb_version: 1,
+            nr_entries: known_routes.len(),
+            chunk: 0,
+            nr_chunks: 1,
+            known_routes,
+        };
+
+        // The peer 10.1.1.2 starts with the full database
+        let mut peer_db = RouteDBManager::default();
+        for udp in mgr.provide_route_database(ip("10.1.1.2")) {
+            if let UdpPacket::RouteDatabase(req) = udp {
+                peer_db.process_route_database(req);
+            }
+        }
+        let base = mgr.db_version();
+        assert_eq!(peer_db.routedb.as_ref().unwrap().version, base);
+
+        mgr.process_route_database(routedb_of_3(vec![RouteInfo {
+            to: ip("10.1.1.60"),
+            local_admin_port: 0,
+            hop_cnt: 0,
+            gateway: None,
+            area_summary: None,
+            exported_nets: vec![],
+            cost: 0,
+            bandwidth_mbit: None,
+        }]));
+        mgr.get_route_changes();
+        let delta_since = |mgr: &NetworkManager, since: usize| match &mgr
+            .provide_route_database_delta(ip("10.1.1.2"), since)[..]
+        {
+            [UdpPacket::RouteDatabaseDelta(delta)] => RouteDatabaseDeltaPacket {
+                changed_routes: delta.changed_routes.clone(),
+                removed_routes: delta.removed_routes.clone(),
+                ..*delta
+            },
+            _ => panic!("no delta"),
+        };
+        let delta = delta_since(&mgr, base);
+        assert_eq!(delta.changed_routes.len(), 1);
+        assert_eq!(delta.changed_routes[0].to, ip("10.1.1.60"));
+        assert!(delta.removed_routes.is_empty());
+        assert!(matches!(
+            peer_db.process_route_database_delta(delta)[..],
+            [Event::UpdateRoutes]
+        ));
+        let routedb = peer_db.routedb.as_ref().unwrap();
+        assert_eq!(routedb.version, mgr.db_version());
+        assert_eq!(routedb.route_for.len(), 3);
+
+        // A removed route
+        let base = mgr.db_version();
+        mgr.process_route_database(routedb_of_3(vec![]));
+        mgr.get_route_changes();
+        let delta = delta_since(&mgr, base);
+        assert!(delta.changed_routes.is_empty());
+        assert_eq!(delta.removed_routes, vec![ip("10.1.1.60")]);
+        // Applied only to the base version
+        let mut outdated = delta_since(&mgr, base);
+        outdated.base_version = base - 1;
+        assert!(peer_db.process_route_database_delta(outdated).is_empty());
+        peer_db.process_route_database_delta(delta);
+        assert_eq!(peer_db.routedb.as_ref().unwrap().route_for.len(), 2);
+        peer_db.latest_version(mgr.db_version());
+        assert!(!peer_db.is_outdated());
+
+        // An inconsistent result needs the full database
+        let mut delta = delta_since(&mgr, base);
+        delta.base_version = mgr.db_version();
+        delta.nr_entries = 5;
+        assert!(peer_db.process_route_database_delta(delta).is_empty());
+        assert!(peer_db.routedb.is_none());
+        // as well as a version, which is not in the history anymore
+        assert!(matches!(
+            mgr.provide_route_database_delta(ip("10.1.1.2"), 0)[..],
+            [UdpPacket::RouteDatabase(_)]
+        ));
+    }
+
+    #[test]
+    fn test_gateway_grace() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let mut static_config = get_test_config();
+        static_config.is_static = false;
+        static_config.gateway_grace = 30;
+        let mut mgr = NetworkManager::new(&static_config);
+        assert!(!mgr.gateway_ready());
+        let now = wg_netmanager::util::now();
+        for peer in ["10.1.1.2", "10.1.1.3"] {
+            let mut ad = advertisement_of(ip(peer));
+            ad.addressed_to = AddressedTo::LocalAddress;
+            ad.sign(&peer_identity());
+            mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        }
+        mgr.get_route_changes();
+        let nr_routes = |mgr: &NetworkManager| match &mgr.provide_route_database(ip("10.1.1.2"))[..]
+        {
+            [UdpPacket::RouteDatabase(db)] => db.known_routes.len(),
+            _ => panic!("no route database"),
+        };
+        mgr.process_timeouts(now);
+        assert_eq!(nr_routes(&mgr), 0);
+        mgr.process_timeouts(now + 29);
+        assert!(!mgr.gateway_ready());
+
+        // The new version makes the peers fetch the full database
+        let version = mgr.db_version();
+        mgr.process_timeouts(now + 30);
+        assert!(mgr.gateway_ready());
+        assert_eq!(nr_routes(&mgr), 2);
+        assert_eq!(mgr.db_version(), version + 1);
+        assert!(matches!(
+            mgr.provide_route_database_delta(ip("10.1.1.2"), version)[..],
+            [UdpPacket::RouteDatabase(_)]
+        ));
+
+        // A static peer needs no warm-up
+        let mut static_config = get_test_config();
+        static_config.gateway_grace = 30;
+        assert!(NetworkManager::new(&static_config).gateway_ready());
+    }
+
+    #[test]
+    fn test_route_database_chunks() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let routes = (0..200)
+            .map(|i| RouteInfo {
+                to: IpAddr::V6(std::net::Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, i)),
+                local_admin_port: 50001,
+                hop_cnt: 2,
+                gateway: Some(ip("fd00::1:1")),
+                area_summary: None,
+                exported_nets: vec![],
+                cost: 0,
+                bandwidth_mbit: None,
+            })
+            .collect::<Vec<_>>();
+        let chunks_of = |version: usize| {
+            UdpPacket::make_route_database(ip("10.1.1.3"), version, 200, routes.iter().collect())
+                .into_iter()
+                .map(|p| {
+                    assert!(bincode::serialized_size(&p).unwrap() < 1300);
+                    match p {
+                        UdpPacket::RouteDatabase(chunk) => chunk,
+                        _ => unreachable!(),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut chunks = chunks_of(1);
+        let nr_chunks = chunks.len();
+        assert!(nr_chunks > 1);
+
+        // Out of order and duplicated chunks
+        let mut peer_db = RouteDBManager::default();
+        let last = chunks.remove(0);
+        let duplicate = chunks_of(1).remove(1);
+        for chunk in chunks.into_iter().rev() {
+            assert!(peer_db.process_route_database(chunk).is_empty());
+        }
+        assert!(peer_db.process_route_database(duplicate).is_empty());
+        assert_eq!(peer_db.missing_chunks(), vec![0]);
+        assert!(matches!(
+            peer_db.process_route_database(last)[..],
+            [Event::UpdateRoutes]
+        ));
+        assert_eq!(peer_db.routedb.as_ref().unwrap().route_for.len(), 200);
+
+        // A lost chunk is detected with the next database
+        let mut peer_db = RouteDBManager::default();
+        let mut chunks = chunks_of(1);
+        chunks.pop();
+        for chunk in chunks {
+            peer_db.process_route_database(chunk);
+        }
+        assert_eq!(peer_db.missing_chunks(), vec![nr_chunks - 1]);
+        let mut chunks = chunks_of(2);
+        let last = chunks.pop().unwrap();
+        assert!(peer_db.process_route_database(last).is_empty());
+        assert_eq!(peer_db.missing_chunks().len(), nr_chunks - 1);
+        for chunk in chunks {
+            peer_db.process_route_database(chunk);
+        }
+        assert!(peer_db.missing_chunks().is_empty());
+        assert_eq!(peer_db.routedb.as_ref().unwrap().version, 2);
+
+        // Chunks, which do not add up to the number of entries
+        let mut peer_db = RouteDBManager::default();
+        for mut chunk in chunks_of(3) {
+            chunk.nr_entries = 201;
+            peer_db.process_route_database(chunk);
+        }
+        assert!(peer_db.routedb.is_none());
+    }
+
+    #[test]
+    fn test_local_contact_push() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let static_config = get_test_config();
+        let now = wg_netmanager::util::now();
+
+        // As gateway of the spokes 10.1.1.2 and 10.1.1.3
+        let mut mgr = NetworkManager::new(&static_config);
+        for peer in ["10.1.1.2", "10.1.1.3"] {
+            let ad = advertisement_of(ip(peer));
+            mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        }
+        let subscriber = "10.1.1.2:50001".parse().unwrap();
+        assert!(mgr.local_contact_requested(now, subscriber).is_empty());
+        let mut ad = advertisement_of(ip("10.1.1.3"));
+        ad.addressed_to = AddressedTo::WireguardAddress;
+        ad.sign(&peer_identity());
+        let spoke = "10.1.1.3:50001".parse().unwrap();
+        let events = mgr.analyze_advertisement(now, &static_config, ad, spoke);
+        assert!(events
+            .iter()
+            .any(|evt| matches!(evt, Event::SendLocalContactRequest { to } if *to == spoke)));
+
+        let contact = LocalContactPacket {
+            public_key: PublicKeyWithTime::new("peer", 0),
+            next_public_key: None,
+            local_ip_list: vec![ip("192.168.10.3")],
+            local_wg_port: 50000,
+            local_admin_port: 50001,
+            my_visible_wg_endpoint: None,
+            wg_ip: ip("10.1.1.3"),
+            name: "spoke".to_string(),
+        };
+        let events = mgr.process_local_contact(now, contact.clone());
+        assert!(matches!(
+            events[..],
+            [Event::SendLocalContactOf { wg_ip, to }] if wg_ip == ip("10.1.1.3") && to == subscriber
+        ));
+        assert_eq!(mgr.local_contact_of(&ip("10.1.1.3")), Some(&contact));
+        assert!(mgr.process_local_contact(now + 1, contact).is_empty());
+        // A renewed subscription gets the known contacts
+        assert!(mgr.local_contact_requested(now + 1, subscriber).is_empty());
+        assert_eq!(
+            mgr.local_contact_requested(now + CONTACT_SUBSCRIPTION + 1, subscriber)
+                .len(),
+            1
+        );
+
+        // As spoke, subscribe at the gateway of a distant node
+        let mut mgr = NetworkManager::new(&static_config);
+        let ad = advertisement_of(ip("10.1.1.2"));
+        mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        mgr.process_route_database(RouteDatabasePacket {
+            sender: ip("10.1.1.2"),
+            routedb_version: 1,
+            nr_entries: 1,
+            chunk: 0,
+            nr_chunks: 1,
+            known_routes: vec![RouteInfo {
+                to: ip("10.1.1.50"),
+                local_admin_port: 0,
+                hop_cnt: 0,
+                gateway: None,
+                area_summary: None,
+                exported_nets: vec![],
+                cost: 0,
+                bandwidth_mbit: None,
+            }],
+        });
+        mgr.get_route_changes();
+        let contact_requests = |mgr: &mut NetworkManager, now: u64| {
+            mgr.process_all_nodes_every_second(now, &static_config)
+                .into_iter()
+                .filter_map(|evt| match evt {
+                    Event::SendLocalContactRequest { to } => Some(to.ip()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let requests = contact_requests(&mut mgr, now);
+        assert!(requests.contains(&ip("10.1.1.2")));
+        assert!(requests.contains(&ip("10.1.1.50")));
+        assert_eq!(contact_requests(&mut mgr, now + 1), vec![ip("10.1.1.50")]);
+    }
+
+    #[test]
+    fn test_connect_request() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let static_config = get_test_config();
+        let now = wg_netmanager::util::now();
+        let now = now - now % 60 + 30;
+        let endpoint2: SocketAddr = "1.2.3.4:40002".parse().unwrap();
+        let endpoint3: SocketAddr = "5.6.7.8:40003".parse().unwrap();
+
+        // As relay with both peers as direct peers
+        let mut mgr = NetworkManager::new(&static_config);
+        for peer in ["10.1.1.2", "10.1.1.3"] {
+            let mut ad = advertisement_of(ip(peer));
+            ad.public_key.key = peer.to_string();
+            ad.local_admin_port = 50001;
+            ad.sign(&peer_identity());
+            mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        }
+        mgr.current_wireguard_configuration(HashMap::from([
+            ("10.1.1.2".to_string(), endpoint2),
+            ("10.1.1.3".to_string(), endpoint3),
+        ]));
+        let request = ConnectRequestPacket {
+            from: ip("10.1.1.2"),
+            to: ip("10.1.1.3"),
+            from_endpoint: Some("192.168.10.2:40002".parse().unwrap()),
+            to_endpoint: None,
+            start_time: 0,
+            ttl: CONNECT_REQUEST_TTL,
+        };
+        let completed = ConnectRequestPacket {
+            from_endpoint: Some(endpoint2),
+            to_endpoint: Some(endpoint3),
+            start_time: now + CONNECT_DELAY,
+            ttl: CONNECT_REQUEST_TTL - 1,
+            ..request.clone()
+        };
+        let destinations = mgr
+            .process_connect_request(now, request.clone())
+            .into_iter()
+            .map(|evt| match evt {
+                Event::ForwardConnectRequest { to, request } => {
+                    assert_eq!(request, completed);
+                    to
+                }
+                _ => panic!("unexpected {:?}", evt),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            destinations,
+            vec![
+                "10.1.1.3:50001".parse::<SocketAddr>().unwrap(),
+                "10.1.1.2:50001".parse().unwrap()
+            ]
+        );
+        let exhausted = ConnectRequestPacket { ttl: 0, ..request };
+        assert!(mgr.process_connect_request(now, exhausted).is_empty());
+
+        // As peer behind NAT, ask the gateway of a distant node
+        let mut mgr = NetworkManager::new(&static_config);
+        let mut ad = advertisement_of(ip("10.1.1.2"));
+        ad.local_admin_port = 50001;
+        ad.sign(&peer_identity());
+        mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        mgr.process_route_database(RouteDatabasePacket {
+            sender: ip("10.1.1.2"),
+            routedb_version: 1,
+            nr_entries: 1,
+            chunk: 0,
+            nr_chunks: 1,
+            known_routes: vec![RouteInfo {
+                to: ip("10.1.1.50"),
+                local_admin_port: 50001,
+                hop_cnt: 0,
+                gateway: None,
+                area_summary: None,
+                exported_nets: vec![],
+                cost: 0,
+                bandwidth_mbit: None,
+            }],
+        });
+        mgr.get_route_changes();
+        mgr.get_route_changes();
+        mgr.process_local_contact(
+            now,
+            LocalContactPacket {
+                public_key: PublicKeyWithTime::new("distant", 0),
+                next_public_key: None,
+                local_ip_list: vec![],
+                local_wg_port: 50000,
+                local_admin_port: 50001,
+                my_visible_wg_endpoint: None,
+                wg_ip: ip("10.1.1.50"),
+                name: "distant".to_string(),
+            },
+        );
+        let events = mgr.process_all_nodes_every_second(now, &static_config);
+        assert!(events.iter().any(
+            |evt| matches!(evt, Event::SendConnectRequest { wg_ip } if *wg_ip == ip("10.1.1.50"))
+        ));
+        let (to, udp) = mgr.connect_request(&ip("10.1.1.50")).unwrap();
+        assert_eq!(to, "10.1.1.2:50001".parse().unwrap());
+        assert!(matches!(
+            udp,
+            UdpPacket::ConnectRequest(ConnectRequestPacket { from, to, .. })
+                if from == static_config.wg_ip && to == ip("10.1.1.50")
+        ));
+
+        // The rendezvous provides the endpoint and both peers punch at the start time
+        let rendezvous = ConnectRequestPacket {
+            from: ip("10.1.1.50"),
+            to: static_config.wg_ip,
+            from_endpoint: Some(endpoint3),
+            to_endpoint: Some(endpoint2),
+            start_time: now + CONNECT_DELAY,
+            ttl: 0,
+        };
+        assert!(mgr.process_connect_request(now, rendezvous).is_empty());
+        let punches = |mgr: &mut NetworkManager, now: u64| {
+            mgr.process_all_nodes_every_second(now, &static_config)
+                .into_iter()
+                .filter(|evt| {
+                    matches!(
+                        evt,
+                        Event::SendAdvertisement {
+                            addressed_to: AddressedTo::WireguardV6Address,
+                            ..
+                        }
+                    )
+                })
+                .count()
+        };
+        assert_eq!(punches(&mut mgr, now + 1), 0);
+        assert_eq!(punches(&mut mgr, now + CONNECT_DELAY), 1);
+        assert_eq!(punches(&mut mgr, now + CONNECT_DELAY + 10), 0);
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains(&format!("EndPoint = {}", endpoint3)));
+    }
+
+    #[test]
+    fn test_relay_fallback() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let static_config = get_test_config();
+        // Known peers are refreshed with the current time, so start in the past
+        let now = wg_netmanager::util::now() - RELAY_FALLBACK;
+        let distant = ip("10.1.1.50");
+
+        // Both direct peers are connected to the distant node
+        let mut mgr = NetworkManager::new(&static_config);
+        for peer in ["10.1.1.2", "10.1.1.3"] {
+            let ad = advertisement_of(ip(peer));
+            mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+            mgr.process_route_database(RouteDatabasePacket {
+                sender: ip(peer),
+                routedb_version: 1,
+                nr_entries: 1,
+                chunk: 0,
+                nr_chunks: 1,
+                known_routes: vec![RouteInfo {
+                    to: distant,
+                    local_admin_port: 50001,
+                    hop_cnt: 0,
+                    gateway: None,
+                    area_summary: None,
+                    exported_nets: vec![],
+                    cost: 0,
+                    bandwidth_mbit: None,
+                }],
+            });
+        }
+        mgr.get_route_changes();
+        mgr.get_route_changes();
+        mgr.process_local_contact(
+            now,
+            LocalContactPacket {
+                public_key: PublicKeyWithTime::new("distant", 0),
+                next_public_key: None,
+                local_ip_list: vec![],
+                local_wg_port: 50000,
+                local_admin_port: 50001,
+                my_visible_wg_endpoint: None,
+                wg_ip: distant,
+                name: "distant".to_string(),
+            },
+        );
+        mgr.process_all_nodes_every_second(now, &static_config);
+        let events = mgr.process_all_nodes_every_second(now + 1, &static_config);
+        assert!(!events.iter().any(|evt| matches!(evt, Event::UpdateRoutes)));
+        assert!(mgr.relays().is_empty());
+
+        // Without NAT traversal for long, the relay is pinned as gateway
+        for peer in ["10.1.1.2", "10.1.1.3"] {
+            let ad = advertisement_of(ip(peer));
+            mgr.analyze_advertisement(
+                now + RELAY_FALLBACK,
+                &static_config,
+                ad,
+                "192.168.1.1:2".parse().unwrap(),
+            );
+        }
+        let events = mgr.process_all_nodes_every_second(now + RELAY_FALLBACK, &static_config);
+        assert!(events.iter().any(|evt| matches!(evt, Event::UpdateRoutes)));
+        let relay = mgr.relays()[&distant];
+        assert!(relay == ip("10.1.1.2") || relay == ip("10.1.1.3"));
+        mgr.get_route_changes();
+        mgr.get_route_changes();
+        let info = mgr
+            .peer_infos(now)
+            .into_iter()
+            .find(|peer| peer.wg_ip == distant)
+            .unwrap();
+        assert_eq!(info.gateway, Some(relay));
+        assert_eq!(info.relay, Some(relay));
+        let events = mgr.process_all_nodes_every_second(now + RELAY_FALLBACK + 1, &static_config);
+        assert!(!events.iter().any(|evt| matches!(evt, Event::UpdateRoutes)));
+    }
+
+    #[test]
+    fn test_areas() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let network = |areas: &str| {
+            NetworkConfiguration::from_yaml_str(&format!(
+                "network:\n  sharedKey: AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\n  subnet: 10.1.1.0/24\n  areas:\n{}\
+                 peers:\n  - endPoint: 127.0.0.1:40000\n    wgIp: 10.1.1.1\n    adminPort: 40001\n",
+                areas
+            ))
+        };
+        assert!(network("    1: 10.1.2.0/25\n").is_err());
+        assert!(network("    1: 10.1.1.0/24\n    2: 10.1.1.128/25\n").is_err());
+        let areas = network("    1: 10.1.1.0/25\n    2: 10.1.1.130/25\n")
+            .unwrap()
+            .areas;
+        assert_eq!(areas[&2], "10.1.1.128/25".parse().unwrap());
+
+        let now = wg_netmanager::util::now();
+        let routes_for = |mgr: &NetworkManager, requester: &str| {
+            let mut routes = vec![];
+            for udp in mgr.provide_route_database(ip(requester)) {
+                if let UdpPacket::RouteDatabase(req) = udp {
+                    routes.extend(req.known_routes);
+                }
+            }
+            routes.sort_by_key(|ri| ri.to);
+            routes
+                .into_iter()
+                .map(|ri| (ri.to, ri.hop_cnt, ri.area_summary))
+                .collect::<Vec<_>>()
+        };
+
+        // The border node provides one summary per other area
+        let mut static_config = get_test_config();
+        static_config.areas = areas.clone();
+        let mut border = NetworkManager::new(&static_config);
+        for peer in ["10.1.1.2", "10.1.1.130"] {
+            let ad = advertisement_of(ip(peer));
+            border.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        }
+        border.get_route_changes();
+        assert_eq!(
+            routes_for(&border, "10.1.1.2"),
+            vec![(ip("10.1.1.2"), 0, None), (ip("10.1.1.128"), 0, Some(2)),]
+        );
+
+        // A node within an area uses the summary instead of the routes to the other area
+        let mut static_config = get_test_config();
+        static_config.is_static = false;
+        static_config.areas = areas;
+        static_config.peers.insert(
+            ip("10.1.1.2"),
+            PublicPeer::new(ip("10.1.1.2"), "192.168.1.2:50000", 50000, 50001),
+        );
+        let mut mgr = NetworkManager::new(&static_config);
+        let ad = advertisement_of(ip("10.1.1.2"));
+        mgr.analyze_advertisement(
+            now,
+            &static_config,
+            ad,
+            "192.168.1.2:50001".parse().unwrap(),
+        );
+        let route = |to: &str, hop_cnt: usize, gateway: Option<&str>, area_summary| RouteInfo {
+            to: ip(to),
+            local_admin_port: 0,
+            hop_cnt,
+            gateway: gateway.map(ip),
+            area_summary,
+            exported_nets: vec![],
+            cost: 0,
+            bandwidth_mbit: None,
+        };
+        let known_routes = vec![
+            route("10.1.1.3", 0, None, None),
+            route("10.1.1.130", 0, None, None),
+            route("10.1.1.128", 0, None, Some(2)),
+        ];
+        mgr.process_route_database(RouteDatabasePacket {
+            sender: ip("10.1.1.2"),
+            routedb_version: 1,
+            nr_entries: known_routes.len(),
+            chunk: 0,
+            nr_chunks: 1,
+            known_routes,
+        });
+        mgr.get_route_changes();
+        mgr.get_route_changes();
+        assert_eq!(mgr.area_gateways().get(&2), Some(&ip("10.1.1.2")));
+        let wg_ips = mgr
+            .peer_infos(now)
+            .into_iter()
+            .map(|p| p.wg_ip)
+            .collect::<Vec<_>>();
+        assert!(wg_ips.contains(&ip("10.1.1.3")));
+        assert!(!wg_ips.contains(&ip("10.1.1.130")));
+        assert!(static_config
+            .to_wg_configuration(&mgr)
+            .contains("AllowedIPs = 10.1.1.128/25"));
+    }
+
+    #[test]
+    fn test_exported_nets() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let net = |s: &str| -> ipnet::IpNet { s.parse().unwrap() };
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+        let now = wg_netmanager::util::now();
+        let src_addr = "192.168.1.1:2".parse().unwrap();
+
+        // A network overlapping the subnet is ignored
+        let mut ad = advertisement_of(ip("10.1.1.2"));
+        ad.exported_nets = vec![net("192.168.10.0/24"), net("10.2.0.0/16")];
+        ad.sign(&peer_identity());
+        mgr.analyze_advertisement(now, &static_config, ad, src_addr);
+        let changes = mgr.get_route_changes();
+        assert!(matches!(
+            changes[..],
+            [RouteChange::AddRoute { .. }, RouteChange::AddNetRoute { net: added }] if added == net("192.168.10.0/24")
+        ));
+        assert!(static_config
+            .to_wg_configuration(&mgr)
+            .contains("AllowedIPs = 192.168.10.0/24"));
+
+        // The network behind a distant node is reached via its gateway
+        mgr.analyze_advertisement(
+            now,
+            &static_config,
+            advertisement_of(ip("10.1.1.3")),
+            src_addr,
+        );
+        let known_routes = vec![RouteInfo {
+            to: ip("10.1.1.4"),
+            local_admin_port: 0,
+            hop_cnt: 0,
+            gateway: None,
+            area_summary: None,
+            exported_nets: vec![net("192.168.20.0/24")],
+            cost: 0,
+            bandwidth_mbit: None,
+        }];
+        mgr.process_route_database(RouteDatabasePacket {
+            sender: ip("10.1.1.3"),
+            routedb_version: 1,
+            nr_entries: known_routes.len(),
+            chunk: 0,
+            nr_chunks: 1,
+            known_routes,
+        });
+        mgr.get_route_changes();
+        assert_eq!(
+            mgr.exported_net_hops().get(&net("192.168.20.0/24")),
+            Some(&ip("10.1.1.3"))
+        );
+        assert_eq!(
+            mgr.exported_nets_via(&ip("10.1.1.3")),
+            vec![net("192.168.20.0/24")]
+        );
+
+        // Withdrawn by the next advertisement
+        let events = mgr.analyze_advertisement(
+            now,
+            &static_config,
+            advertisement_of(ip("10.1.1.2")),
+            src_addr,
+        );
+        assert!(events.iter().any(|e| matches!(e, Event::UpdateRoutes)));
+        let changes = mgr.get_route_changes();
+        assert!(matches!(
+            changes[..],
+            [RouteChange::DelNetRoute { net: removed }] if removed == net("192.168.10.0/24")
+        ));
+    }
+
+    #[test]
+    fn test_readdress() {
+        let previous: IpAddr = "10.1.1.3".parse().unwrap();
+        let moved: IpAddr = "10.1.1.9".parse().unwrap();
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+        let mesh_events = mgr.subscribe();
+        let now = wg_netmanager::util::now();
+        mgr.analyze_advertisement(
+            now,
+            &static_config,
+            advertisement_of(previous),
+            "192.168.1.3:50001".parse().unwrap(),
+        );
+        assert!(mgr.knows_peer(&previous));
+
+        // Another identity cannot claim the move
+        let mut ad = advertisement_of("10.1.1.10".parse().unwrap());
+        ad.moved_from = Some(previous);
+        ad.sign(&NodeIdentity::from_seed(&[3; 32]));
+        mgr.analyze_advertisement(
+            now,
+            &static_config,
+            ad,
+            "192.168.1.9:50001".parse().unwrap(),
+        );
+        assert!(mgr.knows_peer(&previous));
+        assert_eq!(mgr.previous_wg_ip_of(&"10.1.1.10".parse().unwrap()), None);
+
+        let mut ad = advertisement_of(moved);
+        ad.moved_from = Some(previous);
+        ad.sign(&peer_identity());
+        let events = mgr.analyze_advertisement(
+            now,
+            &static_config,
+            ad,
+            "192.168.1.3:50001".parse().unwrap(),
+        );
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, Event::UpdateWireguardConfiguration)));
+        assert!(!mgr.knows_peer(&previous));
+        assert_eq!(mgr.previous_wg_ip_of(&moved), Some(previous));
+        assert!(mesh_events.try_iter().any(|evt| evt
+            == MeshEvent::PeerMoved {
+                from: previous,
+                to: moved
+            }));
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("AllowedIPs = 10.1.1.3/32"), "{}", conf);
+
+        let events = mgr.process_timeouts(now + READDRESS_GRACE);
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, Event::UpdateWireguardConfiguration)));
+        assert_eq!(mgr.previous_wg_ip_of(&moved), None);
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(!conf.contains("AllowedIPs = 10.1.1.3/32"), "{}", conf);
+    }
+
+    #[test]
+    fn test_own_readdress() {
+        let mut static_config = get_test_config();
+        static_config.subnet = "10.1.1.0/24".parse().unwrap();
+        let now = 1000;
+        assert!(readdress(now, &mut static_config, "10.2.1.1".parse().unwrap()).is_err());
+        assert!(readdress(now, &mut static_config, "10.255.255.255".parse().unwrap()).is_err());
+        let previous = readdress(now, &mut static_config, "10.1.1.5".parse().unwrap()).unwrap();
+        assert_eq!(previous, "10.1.1.1".parse::<IpAddr>().unwrap());
+        assert!(readdress(now, &mut static_config, "10.1.1.6".parse().unwrap()).is_err());
+
+        let advertisement = UdpPacket::advertisement_from_config(
+            &static_config,
+            0,
+            AddressedTo::WireguardAddress,
+            None,
+            0,
+            None,
+            0,
+            vec![],
+        );
+        assert!(matches!(
+            advertisement,
+            UdpPacket::Advertisement(ad) if ad.wg_ip == static_config.wg_ip && ad.moved_from == Some(previous)
+        ));
+
+        assert_eq!(
+            grace_expired(now + READDRESS_GRACE - 1, &mut static_config),
+            None
+        );
+        assert_eq!(
+            grace_expired(now + READDRESS_GRACE, &mut static_config),
+            Some(previous)
+        );
+        assert!(static_config.previous_wg_ip.is_none());
     }
 }
@@ -0,0 +1,158 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::net::{IpAddr, SocketAddr};
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::LocalContactPacket;
+    use wg_netmanager::event::Event;
+    use wg_netmanager::node::{DistantNode, Node, VisibleEndpoints};
+    use wg_netmanager::routedb::RouteInfo;
+    use wg_netmanager::wg_dev::*;
+
+    #[test]
+    fn test_parse_wg_endpoint() {
+        let sa = parse_wg_endpoint("192.168.1.1:50001").unwrap();
+        assert_eq!(sa, "192.168.1.1:50001".parse::<SocketAddr>().unwrap());
+
+        let sa = parse_wg_endpoint("[fe80::3bac:744c:f807:a5a2%br-wan]:50001").unwrap();
+        assert_eq!(
+            sa,
+            "[fe80::3bac:744c:f807:a5a2]:50001"
+                .parse::<SocketAddr>()
+                .unwrap()
+        );
+
+        let sa = parse_wg_endpoint("[::ffff:10.0.0.1]:50001").unwrap();
+        assert_eq!(sa, "10.0.0.1:50001".parse::<SocketAddr>().unwrap());
+
+        assert!(parse_wg_endpoint("[fe80::1%a%b]:1").is_err());
+    }
+
+    #[test]
+    fn test_parse_latest_handshakes() {
+        let handshakes = parse_latest_handshakes("key1=\t1700000000\nkey2=\t0\ninvalid\n");
+        assert_eq!(handshakes.len(), 1);
+        assert_eq!(handshakes["key1="], 1700000000);
+
+        let key = base64::encode([1u8; 32]);
+        let conf = WgConf::parse(&format!(
+            "[Peer]\nPublicKey = {}\nPersistentKeepalive = 25\n\n[Peer]\nPublicKey = {}\nPersistentKeepalive = 0\n",
+            key, key
+        ))
+        .unwrap();
+        assert_eq!(conf.peers[0].persistent_keepalive, Some(25));
+        assert_eq!(conf.peers[1].persistent_keepalive, None);
+    }
+
+    #[test]
+    fn test_conf_sequencer() {
+        let mut sequencer = ConfSequencer::default();
+        assert_eq!(sequencer.take_latest(), None);
+
+        // A burst is applied as the latest configuration only
+        assert_eq!(sequencer.submit("a".to_string()), 1);
+        assert_eq!(sequencer.submit("b".to_string()), 2);
+        assert_eq!(sequencer.submit("c".to_string()), 3);
+        assert!(sequencer.has_pending());
+        assert_eq!(sequencer.take_latest(), Some((3, "c".to_string())));
+        assert!(!sequencer.has_pending());
+        assert_eq!(sequencer.skipped(), 2);
+
+        // Submitted while #3 is being applied
+        assert_eq!(sequencer.submit("d".to_string()), 4);
+        assert!(sequencer.applied(3));
+        let (seq, conf) = sequencer.take_latest().unwrap();
+        assert_eq!((seq, conf.as_str()), (4, "d"));
+        assert!(sequencer.applied(seq));
+
+        // An older configuration is never applied after a newer one
+        assert!(!sequencer.applied(3));
+        assert!(sequencer.is_stale(4));
+        assert!(!sequencer.is_stale(5));
+        assert_eq!(sequencer.applied_seq(), Some(4));
+    }
+
+    #[test]
+    fn test_visible_endpoints() {
+        let mut ve = VisibleEndpoints::default();
+        assert!(ve.is_empty());
+        assert_eq!(ve.select(true), None);
+
+        ve.learn("[2001:db8::1]:50000".parse().unwrap());
+        assert_eq!(ve.select(false), ve.v6);
+
+        ve.learn("[::ffff:1.2.3.4]:50000".parse().unwrap());
+        assert_eq!(ve.v4, Some("1.2.3.4:50000".parse().unwrap()));
+        assert_eq!(ve.select(false), ve.v4);
+        assert_eq!(ve.select(true), ve.v6);
+    }
+
+    fn config(router_hairpinning: bool) -> StaticConfiguration {
+        common::builder()
+            .disable_ipv6(false)
+            .router_hairpinning(router_hairpinning)
+            .build()
+    }
+
+    // Reported by the gateway with the same visible ip as this node
+    fn distant_node_behind(visible_endpoint: &str) -> DistantNode {
+        let mut node = DistantNode::from(&RouteInfo {
+            to: "10.1.1.3".parse().unwrap(),
+            local_admin_port: 50503,
+            hop_cnt: 1,
+            gateway: Some("10.1.1.1".parse().unwrap()),
+            area_summary: None,
+            exported_nets: vec![],
+            cost: 0,
+            bandwidth_mbit: None,
+        });
+        node.set_own_visible_endpoint("203.0.113.1:50002".parse().unwrap());
+        node.set_gateway(Some("10.1.1.1".parse().unwrap()));
+        node.process_local_contact(LocalContactPacket {
+            public_key: PublicKeyWithTime::new("distant", 1),
+            next_public_key: None,
+            local_ip_list: vec!["192.168.0.3".parse().unwrap()],
+            local_wg_port: 50003,
+            local_admin_port: 50503,
+            my_visible_wg_endpoint: Some(visible_endpoint.parse().unwrap()),
+            wg_ip: "10.1.1.3".parse().unwrap(),
+            name: "distant".to_string(),
+        });
+        node
+    }
+
+    #[test]
+    fn test_hairpin_nat() {
+        let static_config = config(false);
+        let mut node = distant_node_behind("203.0.113.1:40000");
+        assert!(node.is_behind_same_nat(&static_config));
+        // Only the local ips are tried
+        let conf = node.peer_wireguard_configuration(&static_config).unwrap();
+        assert!(!conf.iter().any(|line| line.starts_with("EndPoint")));
+        let events = node.process_every_second(1_000_020, &static_config);
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Event::SendConnectRequest { .. })));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            Event::SendAdvertisement { to, .. } if to.ip() == "192.168.0.3".parse::<IpAddr>().unwrap()
+        )));
+
+        // Behind another NAT
+        let node = distant_node_behind("198.51.100.7:40000");
+        assert!(!node.is_behind_same_nat(&static_config));
+        let conf = node.peer_wireguard_configuration(&static_config).unwrap();
+        assert!(conf.contains(&"EndPoint = 198.51.100.7:40000".to_string()));
+
+        // The router forwards to its visible endpoint, so it is used as usual
+        let static_config = config(true);
+        let node = distant_node_behind("203.0.113.1:40000");
+        assert!(!node.is_behind_same_nat(&static_config));
+        let conf = node.peer_wireguard_configuration(&static_config).unwrap();
+        assert!(conf.contains(&"EndPoint = 203.0.113.1:40000".to_string()));
+    }
+}
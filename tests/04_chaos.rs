@@ -0,0 +1,43 @@
+#![cfg(feature = "chaos")]
+
+#[cfg(test)]
+mod tests {
+    use wg_netmanager::chaos::*;
+
+    #[test]
+    fn test_chaos_is_reproducible() {
+        let mut chaos_1 = Chaos::new(ChaosConfig::with_seed(42));
+        let mut chaos_2 = Chaos::new(ChaosConfig::with_seed(42));
+        for now in 0..1000 {
+            assert_eq!(chaos_1.packet_action(now), chaos_2.packet_action(now));
+            assert_eq!(
+                chaos_1.restart_route_computation(now),
+                chaos_2.restart_route_computation(now)
+            );
+        }
+        assert!(chaos_1.stats().dropped > 0);
+        assert!(chaos_1.stats().delayed > 0);
+        assert!(chaos_1.stats().duplicated > 0);
+    }
+
+    #[test]
+    fn test_chaos_convergence() {
+        let mut config = ChaosConfig::with_seed(1);
+        config.drop_percent = 100;
+        let mut chaos = Chaos::new(config);
+
+        assert_eq!(chaos.check_convergence(0), None);
+        assert_eq!(chaos.packet_action(10), ChaosAction::Drop);
+        chaos.routes_changed(15, 2);
+        assert_eq!(chaos.check_convergence(30), None);
+        assert_eq!(chaos.check_convergence(45), Some(5));
+        assert_eq!(chaos.stats().convergences, 1);
+
+        assert_eq!(chaos.packet_action(100), ChaosAction::Drop);
+        for now in 100..500 {
+            chaos.routes_changed(now, 1);
+            assert_eq!(chaos.check_convergence(now), None);
+        }
+        assert_eq!(chaos.stats().convergence_failures, 1);
+    }
+}
@@ -0,0 +1,92 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::collections::HashMap;
+
+    use wg_netmanager::audit::*;
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::routedb::RouteInfo;
+
+    fn audit(sender: &str, version: usize, routes: &[(&str, Option<&str>)]) -> AuditPacket {
+        AuditPacket {
+            sender: common::ip(sender),
+            name: sender.to_string(),
+            public_key: PublicKeyWithTime::new(format!("key-{}", sender), 0),
+            routedb_version: version,
+            known_routes: routes
+                .iter()
+                .map(|(to, gateway)| RouteInfo {
+                    to: common::ip(to),
+                    local_admin_port: 50001,
+                    hop_cnt: 0,
+                    gateway: gateway.map(common::ip),
+                    area_summary: None,
+                    exported_nets: vec![],
+                    cost: 0,
+                    bandwidth_mbit: None,
+                })
+                .collect(),
+            known_nodes: vec![],
+        }
+    }
+
+    #[test]
+    fn test_consistent_mesh() {
+        let mut reports = HashMap::new();
+        reports.insert(
+            common::ip("10.1.1.1"),
+            audit("10.1.1.1", 1, &[("10.1.1.2", None)]),
+        );
+        reports.insert(
+            common::ip("10.1.1.2"),
+            audit("10.1.1.2", 1, &[("10.1.1.1", None)]),
+        );
+        assert_eq!(check(&reports), vec![]);
+    }
+
+    #[test]
+    fn test_inconsistent_mesh() {
+        let mut reports = HashMap::new();
+        let mut a = audit("10.1.1.1", 3, &[("10.1.1.2", None), ("10.1.1.3", None)]);
+        a.known_nodes.push(AuditNodeInfo {
+            wg_ip: common::ip("10.1.1.2"),
+            name: Some("other".to_string()),
+            public_key: Some("key-10.1.1.2".to_string()),
+            routedb_version: Some(1),
+        });
+        reports.insert(common::ip("10.1.1.1"), a);
+        reports.insert(
+            common::ip("10.1.1.2"),
+            audit("10.1.1.2", 2, &[("10.1.1.1", Some("10.1.1.3"))]),
+        );
+
+        let findings = check(&reports);
+        assert!(findings.contains(&AuditFinding::NotReachable {
+            wg_ip: common::ip("10.1.1.3")
+        }));
+        assert!(findings.contains(&AuditFinding::MissingRoute {
+            on: common::ip("10.1.1.2"),
+            to: common::ip("10.1.1.3")
+        }));
+        assert!(findings.contains(&AuditFinding::AsymmetricGateway {
+            direct: common::ip("10.1.1.1"),
+            via_gateway: common::ip("10.1.1.2"),
+            gateway: common::ip("10.1.1.3")
+        }));
+        assert!(findings.contains(&AuditFinding::VersionSkew {
+            on: common::ip("10.1.1.1"),
+            of: common::ip("10.1.1.2"),
+            copy_version: 1,
+            current_version: 2
+        }));
+        assert!(findings.contains(&AuditFinding::ConflictingClaim {
+            wg_ip: common::ip("10.1.1.2"),
+            names: vec!["10.1.1.2".to_string(), "other".to_string()],
+            public_keys: vec!["key-10.1.1.2".to_string()]
+        }));
+        assert_eq!(findings.len(), 5);
+    }
+}
@@ -0,0 +1,51 @@
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use wg_netmanager::persist::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = PersistKey::from_shared_key(&[1; 32]);
+        let sealed = key.seal(b"route db").unwrap();
+        assert_eq!(key.open(&sealed).unwrap(), b"route db");
+
+        let other_key = PersistKey::from_shared_key(&[2; 32]);
+        assert!(other_key.open(&sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(key.open(&tampered).is_err());
+        assert!(key.open(b"WGNM").is_err());
+    }
+
+    #[test]
+    fn test_save_and_load_with_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("state");
+        let key = PersistKey::from_shared_key(&[1; 32]);
+
+        assert_eq!(key.load(&fname), None);
+
+        key.save(&fname, b"first").unwrap();
+        key.save(&fname, b"second").unwrap();
+        assert_eq!(key.load(&fname).unwrap(), b"second");
+
+        // corrupt file falls back to the backup
+        fs::write(&fname, b"garbage").unwrap();
+        assert_eq!(key.load(&fname).unwrap(), b"first");
+        assert!(dir.path().join("state.corrupt").exists());
+    }
+
+    #[test]
+    fn test_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("persist.key");
+        let key = PersistKey::from_key_file(&fname).unwrap();
+        let sealed = key.seal(b"peers").unwrap();
+
+        let key = PersistKey::from_key_file(&fname).unwrap();
+        assert_eq!(key.open(&sealed).unwrap(), b"peers");
+    }
+}
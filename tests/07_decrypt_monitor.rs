@@ -0,0 +1,93 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, SocketAddr};
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::decrypt_monitor::*;
+
+    fn peers() -> HashMap<IpAddr, PublicPeer> {
+        let wg_ip: IpAddr = "10.1.1.1".parse().unwrap();
+        let mut peers = HashMap::new();
+        peers.insert(
+            wg_ip,
+            PublicPeer::new(wg_ip, "127.0.0.1:50000", 50000, 50001),
+        );
+        peers
+    }
+
+    #[test]
+    fn test_key_mismatch_hint() {
+        let peers = peers();
+        let mut monitor = DecryptMonitor::new(3);
+        let static_peer: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+        let unknown: SocketAddr = "192.0.2.1:50001".parse().unwrap();
+
+        assert_eq!(monitor.record_failure(0, static_peer, &peers), None);
+        assert_eq!(monitor.record_failure(1, static_peer, &peers), None);
+        let hint = monitor.record_failure(2, static_peer, &peers).unwrap();
+        assert_eq!(hint.wg_ip, "10.1.1.1".parse::<IpAddr>().unwrap());
+        assert!(hint.to_string().contains("shared key mismatch"));
+        // not repeated immediately
+        assert_eq!(monitor.record_failure(3, static_peer, &peers), None);
+        assert_eq!(monitor.hints().len(), 1);
+
+        // unknown sources are only counted
+        for t in 0..5 {
+            assert_eq!(monitor.record_failure(t, unknown, &peers), None);
+        }
+        assert_eq!(monitor.total_failures(), 9);
+
+        monitor.record_success(static_peer);
+        assert!(monitor.hints().is_empty());
+    }
+
+    #[test]
+    fn test_window_and_disabled() {
+        let peers = peers();
+        let static_peer: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+
+        let mut monitor = DecryptMonitor::new(2);
+        assert_eq!(monitor.record_failure(0, static_peer, &peers), None);
+        // outside of the window, so counting starts again
+        assert_eq!(monitor.record_failure(100, static_peer, &peers), None);
+        assert!(monitor.record_failure(101, static_peer, &peers).is_some());
+
+        let mut monitor = DecryptMonitor::new(0);
+        for t in 0..20 {
+            assert_eq!(monitor.record_failure(t, static_peer, &peers), None);
+        }
+        assert_eq!(monitor.total_failures(), 20);
+    }
+
+    #[test]
+    fn test_accepted_keys() {
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let old_key = vec![1; 32];
+        let new_key = vec![2; 32];
+        let mut sender = CryptUdp::bind(localhost, 0).unwrap().key(&new_key).unwrap();
+        let receiver = CryptUdp::bind(localhost, 0)
+            .unwrap()
+            .key(&old_key)
+            .unwrap()
+            .accepted_keys(&[new_key])
+            .unwrap();
+        let timeout = Some(std::time::Duration::from_secs(5));
+        receiver.set_read_timeout(timeout).unwrap();
+        let to = SocketAddr::new(localhost, receiver.local_addr().unwrap().port());
+
+        // The receiver still sends with the old key, but accepts the new one
+        let mut buf = [0u8; 1500];
+        sender.send_to(b"hello", to).unwrap();
+        let (len, _) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        let receiver = receiver.accepted_keys(&[]).unwrap();
+        sender.send_to(b"hello", to).unwrap();
+        let err = receiver.recv_from(&mut buf).unwrap_err();
+        assert!(err.downcast_ref::<DecryptError>().is_some());
+
+        assert!(receiver.accepted_keys(&[vec![0; 16]]).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+mod common;
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use crate::common;
+
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::event::{channel, Event};
+    use wg_netmanager::manager::*;
+    use wg_netmanager::node::{ConnectionType, VisibleEndpoints};
+    use wg_netmanager::routedb::RouteInfo;
+    use wg_netmanager::state_snapshot::*;
+
+    fn snapshot(static_config: &StaticConfiguration) -> StateSnapshot {
+        let route = RouteInfo {
+            to: "10.1.1.9".parse().unwrap(),
+            local_admin_port: 50509,
+            hop_cnt: 0,
+            gateway: None,
+            area_summary: None,
+            exported_nets: vec![],
+            cost: 0,
+            bandwidth_mbit: None,
+        };
+        let mut snapshot = StateSnapshot::new(static_config);
+        snapshot.my_local_wg_port = 50100;
+        snapshot.my_visible_wg_endpoint = Some("192.0.2.2:50100".parse().unwrap());
+        snapshot.routedb = RouteDBState::new(7, vec![]);
+        snapshot.nodes = vec![
+            NodeState::Static {
+                wg_ip: "10.1.1.1".parse().unwrap(),
+                public_key: Some(PublicKeyWithTime::new("static", 2)),
+                is_alive: true,
+                lastseen: 100,
+                current_ip: Some("127.0.0.1".parse().unwrap()),
+                routedb: Some(RouteDBState::new(3, vec![route])),
+            },
+            NodeState::Dynamic {
+                wg_ip: "10.1.1.3".parse().unwrap(),
+                name: "dynamic".to_string(),
+                public_key: PublicKeyWithTime::new("dynamic", 3),
+                local_wg_port: 50003,
+                local_admin_port: 50503,
+                admin_port: 50503,
+                connection: ConnectionType::Dynamic {
+                    endpoint: Some("192.0.2.3:50003".parse().unwrap()),
+                },
+                local_reachable_wg_endpoint: None,
+                local_reachable_admin_endpoint: None,
+                visible_wg_endpoints: VisibleEndpoints::default(),
+                lastseen: 100,
+                routedb: None,
+            },
+        ];
+        snapshot
+    }
+
+    #[test]
+    fn test_restore_state() {
+        let static_config = common::static_config();
+        let snapshot = snapshot(&static_config);
+
+        let mut mgr = NetworkManager::new(&static_config);
+        mgr.restore_state(100, &snapshot).unwrap();
+        assert_eq!(mgr.my_local_wg_port, 50100);
+        assert!(mgr.knows_peer(&"10.1.1.3".parse().unwrap()));
+        assert_eq!(mgr.state_snapshot(&static_config), snapshot);
+
+        // The distant node is recreated from the static peer's route database
+        mgr.get_route_changes();
+        assert!(mgr.knows_peer(&"10.1.1.9".parse().unwrap()));
+
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("PublicKey = static"));
+        assert!(conf.contains("PublicKey = dynamic"));
+
+        let mut other = snapshot.clone();
+        other.wg_ip = "10.1.1.4".parse().unwrap();
+        assert!(NetworkManager::new(&static_config)
+            .restore_state(100, &other)
+            .is_err());
+    }
+
+    #[test]
+    fn test_handover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wgtest.upgrade");
+        let path = path.to_str().unwrap().to_string();
+        let static_config = common::static_config();
+        let snapshot = snapshot(&static_config);
+
+        let (tx, mut rx) = channel();
+        wg_netmanager::upgrade::listen(&path, tx).unwrap();
+
+        let new_instance = {
+            let path = path.clone();
+            let static_config = static_config.clone();
+            std::thread::spawn(move || {
+                wg_netmanager::upgrade::take_over(&path, &static_config).unwrap()
+            })
+        };
+
+        let socket_v4 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket_v6 = UdpSocket::bind("[::1]:0").unwrap();
+        let stream = match rx.blocking_recv().unwrap() {
+            Event::Handover(stream) => stream,
+            evt => panic!("unexpected {:?}", evt),
+        };
+        wg_netmanager::upgrade::hand_over(
+            stream,
+            socket_v4.as_raw_fd(),
+            socket_v6.as_raw_fd(),
+            &snapshot,
+        )
+        .unwrap();
+
+        let handover = new_instance.join().unwrap();
+        assert_eq!(handover.snapshot, snapshot);
+        assert_eq!(
+            handover.socket_v4.local_addr().unwrap(),
+            socket_v4.local_addr().unwrap()
+        );
+        assert_eq!(
+            handover.socket_v6.local_addr().unwrap(),
+            socket_v6.local_addr().unwrap()
+        );
+    }
+    #[test]
+    fn test_state_diff() {
+        let route = |to: &str, gateway: Option<&str>| RouteInfo {
+            to: common::ip(to),
+            local_admin_port: 50509,
+            hop_cnt: gateway.map(|_| 1).unwrap_or(0),
+            gateway: gateway.map(common::ip),
+            area_summary: None,
+            exported_nets: vec![],
+            cost: 0,
+            bandwidth_mbit: None,
+        };
+        let static_config = common::static_config();
+        let mut old = snapshot(&static_config);
+        old.routedb.routes = vec![route("10.1.1.9", None), route("10.1.1.8", Some("10.1.1.1"))];
+
+        // The saved state has no secrets
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("state1.json");
+        old.clone().redacted().write(&fname).unwrap();
+        let saved = StateSnapshot::read(&fname).unwrap();
+        assert!(saved.my_private_key.is_empty());
+        assert!(saved.identity_seed.is_none());
+        assert!(diff(&old, &saved).is_empty());
+        assert!(diff_report(&old, &saved).ends_with("No changes\n"));
+
+        let mut new = old.clone();
+        new.created = 200;
+        new.my_visible_wg_endpoint = None;
+        if let NodeState::Static { is_alive, .. } = &mut new.nodes[0] {
+            *is_alive = false;
+        }
+        if let NodeState::Dynamic { connection, .. } = &mut new.nodes[1] {
+            *connection = ConnectionType::Dynamic {
+                endpoint: Some("192.0.2.4:50003".parse().unwrap()),
+            };
+        }
+        new.nodes.remove(0);
+        new.routedb.routes = vec![route("10.1.1.8", Some("10.1.1.3")), route("10.1.1.7", None)];
+        let changes = diff(&old, &new)
+            .iter()
+            .map(|change| change.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                "~ own visible endpoint 192.0.2.2:50100 -> -",
+                "- peer 10.1.1.1 (static)",
+                "~ peer 10.1.1.3 endpoint 192.0.2.3:50003 -> 192.0.2.4:50003",
+                "- route 10.1.1.9 (was direct)",
+                "+ route 10.1.1.7 direct",
+                "~ route 10.1.1.8 via 10.1.1.3 (was via 10.1.1.1)",
+            ]
+        );
+        let report = diff_report(&old, &new);
+        assert!(report.starts_with("State of 10.1.1.2 from "));
+        assert_eq!(report.lines().count(), 1 + changes.len());
+    }
+}
@@ -0,0 +1,287 @@
+mod common;
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::common;
+
+    use serde_json::json;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::control::*;
+    use wg_netmanager::event::{channel, Event};
+    use wg_netmanager::manager::*;
+
+    fn static_config(network_yaml_filename: &str) -> StaticConfiguration {
+        common::builder()
+            .peers(common::static_peers())
+            .network_yaml_filename(network_yaml_filename)
+            .build()
+    }
+
+    fn request(method: &str, params: serde_json::Value) -> ControlRequest {
+        ControlRequest {
+            method: method.to_string(),
+            params,
+            id: json!(1),
+        }
+    }
+
+    #[test]
+    fn test_execute() {
+        let dir = tempfile::tempdir().unwrap();
+        let network_yaml = dir.path().join("network.yaml");
+        let mut static_config = static_config(network_yaml.to_str().unwrap());
+        let mut mgr = NetworkManager::new(&static_config);
+
+        let (response, _) = execute(
+            0,
+            &request("peers", json!(null)),
+            &mut mgr,
+            &mut static_config,
+        );
+        let peers = response.result.unwrap();
+        assert_eq!(peers[0]["wg_ip"], "10.1.1.1");
+        assert_eq!(peers[0]["endpoint_class"], "static");
+
+        let peers: Vec<PeerInfo> = serde_json::from_value(peers).unwrap();
+        let table = status_table(&peers);
+        let lines = table.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("NAME  WG_IP     CONNECTION  ENDPOINT"));
+        assert!(lines[1].starts_with("-     10.1.1.1  static"));
+        let relayed = PeerInfo {
+            gateway: Some("10.1.1.3".parse().unwrap()),
+            relay: Some("10.1.1.3".parse().unwrap()),
+            ..peers[0].clone()
+        };
+        assert!(status_table(&[relayed]).contains("10.1.1.3 (relay)"));
+
+        let (response, _) = execute(
+            0,
+            &request("status", json!(null)),
+            &mut mgr,
+            &mut static_config,
+        );
+        let status = response.result.unwrap();
+        assert_eq!(status["wg_ip"], "10.1.1.2");
+        // No routes have been changed yet
+        assert!(status["last_route_diff"].is_null());
+
+        let (response, _) = execute(
+            0,
+            &request("snapshot", json!(null)),
+            &mut mgr,
+            &mut static_config,
+        );
+        let snapshot = response.result.unwrap();
+        assert_eq!(snapshot["my_private_key"], "");
+        assert!(snapshot["identity_seed"].is_null());
+
+        let (response, _) = execute(
+            0,
+            &request("unknown", json!(null)),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.error.unwrap().code, METHOD_NOT_FOUND);
+
+        let (response, _) = execute(
+            0,
+            &request("log-sampling", json!({"every": 10})),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.result.unwrap(), json!(10));
+        let (response, _) = execute(
+            0,
+            &request("log-sampling", json!({"every": 0})),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.error.unwrap().code, INVALID_PARAMS);
+        let (response, _) = execute(
+            0,
+            &request("log-sampling", json!(null)),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.result.unwrap(), json!(10));
+
+        let params = json!({"wg_ip": "10.1.1.9"});
+        let (response, events) = execute(
+            0,
+            &request("drop-peer", params),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.result.unwrap(), json!(false));
+        assert!(events.is_empty());
+
+        let (response, events) = execute(
+            0,
+            &request("force-relay", json!({"peer": "10.1.1.1", "on": true})),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.result.unwrap()["forced_relay_s"], 300);
+        assert_eq!(events.len(), 2);
+        let (response, _) = execute(
+            0,
+            &request("force-relay", json!({"peer": "10.1.1.1", "on": false})),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.result.unwrap()["forced_relay_s"], 0);
+        let (response, _) = execute(
+            0,
+            &request("force-relay", json!({"peer": "unknown", "on": true})),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.error.unwrap().code, INVALID_PARAMS);
+        // No key of the static peer is known yet
+        let (response, events) = execute(
+            0,
+            &request("revoke-and-rekey", json!({"peer": "10.1.1.1"})),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.error.unwrap().code, INVALID_PARAMS);
+        assert!(events.is_empty());
+
+        // Replace the static peer
+        std::fs::write(
+            &network_yaml,
+            "network:\n  sharedKey: AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\n  subnet: 10.1.1.0/24\n\
+             peers:\n  - endPoint: 127.0.0.1:50003\n    wgIp: 10.1.1.3\n    wgPort: 50003\n    adminPort: 50503\n",
+        )
+        .unwrap();
+        let (response, events) = execute(
+            0,
+            &request("reload-config", json!(null)),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.result.unwrap()["peers"], 1);
+        assert_eq!(events.len(), 1);
+        assert!(!mgr.knows_peer(&"10.1.1.1".parse().unwrap()));
+        assert!(mgr.knows_peer(&"10.1.1.3".parse().unwrap()));
+
+        // Add a static peer and change its endpoint via network.yaml
+        let params =
+            json!({"wg_ip": "10.1.1.4", "endpoint": "127.0.0.1:50004", "admin_port": 50504});
+        let (response, events) = execute(
+            0,
+            &request("add-peer", params.clone()),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.result.unwrap()["peers"], 2);
+        assert_eq!(events.len(), 1);
+        assert!(mgr.knows_peer(&"10.1.1.4".parse().unwrap()));
+        let (response, _) = execute(
+            0,
+            &request("add-peer", params),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert_eq!(response.error.unwrap().code, INVALID_PARAMS);
+        let params = json!({"wg_ip": "10.1.1.4", "endpoint": "127.0.0.2:50004"});
+        execute(
+            0,
+            &request("set-endpoint", params),
+            &mut mgr,
+            &mut static_config,
+        );
+        let peer = &static_config.peers[&"10.1.1.4".parse().unwrap()];
+        assert_eq!(peer.endpoint, "127.0.0.2:50004");
+
+        // A different shared key is not applied at runtime
+        std::fs::write(
+            &network_yaml,
+            "network:\n  sharedKey: AQAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\n  subnet: 10.1.1.0/24\n\
+             peers: []\n",
+        )
+        .unwrap();
+        let (response, _) = execute(
+            0,
+            &request("reload-config", json!(null)),
+            &mut mgr,
+            &mut static_config,
+        );
+        assert!(response.error.is_some());
+        assert_eq!(static_config.peers.len(), 2);
+    }
+
+    #[test]
+    fn test_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wgtest.control");
+        let path = path.to_str().unwrap().to_string();
+
+        let (tx, mut rx) = channel();
+        listen(&path, ControlAccess::default(), tx).unwrap();
+        let run_loop = std::thread::spawn(move || match rx.blocking_recv().unwrap() {
+            Event::Control(cmd) => {
+                let mut static_config = static_config("network.yaml");
+                let mut mgr = NetworkManager::new(&static_config);
+                let (response, _) = execute(0, &cmd.request, &mut mgr, &mut static_config);
+                cmd.reply.send(response).unwrap();
+            }
+            evt => panic!("unexpected {:?}", evt),
+        });
+
+        let response = wg_netmanager::control::request(&path, "version", json!(null)).unwrap();
+        run_loop.join().unwrap();
+        assert_eq!(response.result.unwrap(), json!(env!("CARGO_PKG_VERSION")));
+        assert_eq!(response.id, json!(1));
+    }
+
+    #[test]
+    fn test_permission() {
+        assert_eq!(
+            request("peers", json!(null)).required_permission(),
+            Permission::ReadOnly
+        );
+        assert_eq!(
+            request("log-sampling", json!(null)).required_permission(),
+            Permission::ReadOnly
+        );
+        assert_eq!(
+            request("log-sampling", json!({"every": 10})).required_permission(),
+            Permission::Admin
+        );
+        assert_eq!(
+            request("drop-peer", json!({"wg_ip": "10.1.1.1"})).required_permission(),
+            Permission::Admin
+        );
+        assert_eq!(
+            request("reload-config", json!(null)).required_permission(),
+            Permission::Admin
+        );
+        assert!(Permission::ReadOnly < Permission::Admin);
+
+        let uid = nix::unistd::Uid::effective().as_raw();
+        let gid = nix::unistd::Gid::effective();
+        let group = nix::unistd::Group::from_gid(gid).unwrap().unwrap().name;
+        let stranger = uid.wrapping_add(12345);
+        let other_gid = gid.as_raw().wrapping_add(12345);
+
+        let access = ControlAccess::default();
+        assert_eq!(access.permission(uid, other_gid), Some(Permission::Admin));
+        assert_eq!(access.permission(0, other_gid), Some(Permission::Admin));
+        assert_eq!(access.permission(stranger, gid.as_raw()), None);
+
+        let access = ControlAccess::new(Some(&group), None).unwrap();
+        assert_eq!(
+            access.permission(stranger, gid.as_raw()),
+            Some(Permission::ReadOnly)
+        );
+        assert_eq!(access.permission(stranger, other_gid), None);
+        let access = ControlAccess::new(None, Some(&group)).unwrap();
+        assert_eq!(
+            access.permission(stranger, gid.as_raw()),
+            Some(Permission::Admin)
+        );
+        assert!(ControlAccess::new(Some("no-such-group-wgtest"), None).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use wg_netmanager::manager::*;
+    use wg_netmanager::status_file::*;
+
+    #[test]
+    fn test_status_file() {
+        let static_config = common::static_config();
+        let mgr = NetworkManager::new(&static_config);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status.json");
+        let mut status_file = StatusFile::new(path.to_str().unwrap());
+
+        assert!(status_file.update(100, &mgr, &static_config).unwrap());
+        // Rate limited
+        assert!(!status_file.update(101, &mgr, &static_config).unwrap());
+        assert!(status_file.update(105, &mgr, &static_config).unwrap());
+
+        let status: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(status["format_version"], STATUS_FORMAT_VERSION);
+        assert_eq!(status["generated"], 105);
+        assert_eq!(status["wg_ip"], "10.1.1.2");
+        assert_eq!(status["health"]["connected"], false);
+        assert_eq!(status["health"]["static_peers"], 1);
+        assert_eq!(status["peers"][0]["wg_ip"], "10.1.1.1");
+        // Only the status file itself is left
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        status_file.remove();
+        assert!(!path.exists());
+    }
+}
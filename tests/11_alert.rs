@@ -0,0 +1,55 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::net::IpAddr;
+
+    use wg_netmanager::alert::*;
+    use wg_netmanager::manager::*;
+    use wg_netmanager::mesh_event::MeshEvent;
+
+    #[test]
+    fn test_alerts() {
+        let static_config = common::static_config();
+        let mgr = NetworkManager::new(&static_config);
+        let mut alerts = AlertManager::new();
+
+        // The unreachable static peer is tolerated for a grace period
+        alerts.check(100, &mgr);
+        assert!(alerts.alerts().is_empty());
+        alerts.check(130, &mgr);
+        assert_eq!(alerts.alerts()[0].kind, AlertKind::Partitioned);
+        assert_eq!(alerts.alerts()[0].since, 130);
+
+        let other: IpAddr = "10.1.1.3".parse().unwrap();
+        for i in 0..3 {
+            alerts.process_mesh_event(
+                131,
+                &MeshEvent::KeyRotated {
+                    wg_ip: other,
+                    public_key: format!("key{}", i),
+                },
+            );
+        }
+        for _ in 0..31 {
+            alerts.process_mesh_event(131, &MeshEvent::RouteRemoved { to: other });
+        }
+        alerts.check(131, &mgr);
+        let kinds = alerts.alerts().iter().map(|a| a.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                AlertKind::Partitioned,
+                AlertKind::KeyConflict(other),
+                AlertKind::RouteChurn
+            ]
+        );
+
+        // Both clear after the window
+        alerts.check(191, &mgr);
+        let kinds = alerts.alerts().iter().map(|a| a.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![AlertKind::Partitioned]);
+    }
+}
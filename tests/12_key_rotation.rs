@@ -0,0 +1,204 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::net::IpAddr;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::event::Event;
+    use wg_netmanager::identity::NodeIdentity;
+    use wg_netmanager::key_rotation::*;
+    use wg_netmanager::manager::*;
+
+    // This node is a static peer, so it accepts new peers
+    fn get_test_config(key_lifetime: Option<u64>) -> StaticConfiguration {
+        common::static_peer_builder()
+            .my_public_key(common::public_key(1000))
+            .key_lifetime(key_lifetime)
+            .build()
+    }
+
+    fn key(key: &str, priv_key_creation_time: u64) -> PublicKeyWithTime {
+        PublicKeyWithTime::new(key.to_string(), priv_key_creation_time)
+    }
+
+    #[test]
+    fn test_rotate_keys() {
+        let new_pair = || Ok(("private2".to_string(), "public2".to_string()));
+        let mut static_config = get_test_config(None);
+        assert_eq!(
+            rotate_keys(1_000_000, &mut static_config, new_pair).unwrap(),
+            None
+        );
+
+        let mut static_config = get_test_config(Some(3600));
+        let announce = 1000 + 3600 - KEY_ROTATION_OVERLAP;
+        assert_eq!(
+            rotate_keys(announce - 1, &mut static_config, new_pair).unwrap(),
+            None
+        );
+        assert_eq!(
+            rotate_keys(announce, &mut static_config, new_pair).unwrap(),
+            Some(KeyRotationStep::Announced)
+        );
+        // Both keys are advertised, the old one is still in use
+        assert_eq!(static_config.my_public_key.key, "public");
+        assert_eq!(
+            static_config.next_public_key(),
+            Some(&key("public2", 1000 + 3600))
+        );
+        assert_eq!(
+            rotate_keys(4599, &mut static_config, new_pair).unwrap(),
+            None
+        );
+
+        assert_eq!(
+            rotate_keys(4600, &mut static_config, new_pair).unwrap(),
+            Some(KeyRotationStep::Switched)
+        );
+        assert_eq!(static_config.my_private_key, "private2");
+        assert_eq!(static_config.my_public_key, key("public2", 4600));
+        assert_eq!(static_config.next_public_key(), None);
+        assert_eq!(
+            rotate_keys(4601, &mut static_config, new_pair).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rekey() {
+        let mut static_config = get_test_config(None);
+        rekey(2000, &mut static_config, || {
+            Ok(("private2".to_string(), "public2".to_string()))
+        })
+        .unwrap();
+        let switch = 2000 + KEY_ROTATION_OVERLAP;
+        assert_eq!(
+            static_config.next_public_key(),
+            Some(&key("public2", switch))
+        );
+        // A pending announcement is replaced
+        rekey(2000, &mut static_config, || {
+            Ok(("private3".to_string(), "public3".to_string()))
+        })
+        .unwrap();
+        assert_eq!(
+            static_config.next_public_key(),
+            Some(&key("public3", switch))
+        );
+
+        // Switched even without key_lifetime
+        let no_pair = || panic!("no new key pair");
+        assert_eq!(
+            rotate_keys(switch - 1, &mut static_config, no_pair).unwrap(),
+            None
+        );
+        assert_eq!(
+            rotate_keys(switch, &mut static_config, no_pair).unwrap(),
+            Some(KeyRotationStep::Switched)
+        );
+        assert_eq!(static_config.my_private_key, "private3");
+        assert_eq!(
+            rotate_keys(switch + 1, &mut static_config, no_pair).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_announced_key() {
+        let peer_ip: IpAddr = "10.1.1.2".parse().unwrap();
+        let static_config = get_test_config(None);
+        let mut mgr = NetworkManager::new(&static_config);
+        let now = wg_netmanager::util::now();
+        let identity = NodeIdentity::from_seed(&[2; 32]);
+        let advertisement =
+            |addressed_to: AddressedTo, next_public_key: Option<PublicKeyWithTime>| {
+                let mut ad = AdvertisementPacket {
+                    addressed_to,
+                    public_key: key("peer", now),
+                    next_public_key,
+                    local_wg_port: 50002,
+                    local_admin_port: 50502,
+                    wg_ip: peer_ip,
+                    name: "peer".to_string(),
+                    your_visible_wg_endpoint: None,
+                    my_visible_wg_endpoint: None,
+                    routedb_version: 0,
+                    exported_nets: vec![],
+                    extra_wg_ports: vec![],
+                    force_relay_s: 0,
+                    revocations: vec![],
+                    moved_from: None,
+                    identity: [0; 32],
+                    signature: vec![],
+                };
+                ad.sign(&identity);
+                ad
+            };
+        let public_key_of_peer = |mgr: &NetworkManager| {
+            mgr.peer_infos(now)
+                .into_iter()
+                .find(|p| p.wg_ip == peer_ip)
+                .and_then(|p| p.public_key)
+        };
+
+        let ad = advertisement(AddressedTo::StaticAddress, None);
+        mgr.analyze_advertisement(
+            now,
+            &static_config,
+            ad,
+            "192.168.1.1:50502".parse().unwrap(),
+        );
+        assert_eq!(mgr.get_route_changes().len(), 1);
+
+        // Announcements, which do not replace the current key later, are ignored
+        let ad = advertisement(AddressedTo::WireguardAddress, Some(key("peer2", now - 50)));
+        mgr.analyze_advertisement(
+            now + 10,
+            &static_config,
+            ad,
+            "10.1.1.2:50502".parse().unwrap(),
+        );
+        let events = mgr.process_all_nodes_every_second(now + 50, &static_config);
+        assert!(!events
+            .iter()
+            .any(|evt| matches!(evt, Event::UpdateWireguardConfiguration)));
+
+        let ad = advertisement(AddressedTo::WireguardAddress, Some(key("peer2", now + 100)));
+        mgr.analyze_advertisement(
+            now + 20,
+            &static_config,
+            ad,
+            "10.1.1.2:50502".parse().unwrap(),
+        );
+        let events = mgr.process_all_nodes_every_second(now + 99, &static_config);
+        assert!(!events
+            .iter()
+            .any(|evt| matches!(evt, Event::UpdateWireguardConfiguration)));
+        assert_eq!(public_key_of_peer(&mgr), Some("peer".to_string()));
+
+        // Switch at the announced time without dropping the peer or its route
+        let events = mgr.process_all_nodes_every_second(now + 100, &static_config);
+        assert!(events
+            .iter()
+            .any(|evt| matches!(evt, Event::UpdateWireguardConfiguration)));
+        assert!(!events.iter().any(|evt| matches!(evt, Event::UpdateRoutes)));
+        assert_eq!(public_key_of_peer(&mgr), Some("peer2".to_string()));
+        assert_eq!(mgr.get_route_changes().len(), 0);
+
+        let mut ad = advertisement(AddressedTo::WireguardAddress, None);
+        ad.public_key = key("peer2", now + 100);
+        ad.sign(&identity);
+        let events = mgr.analyze_advertisement(
+            now + 101,
+            &static_config,
+            ad,
+            "10.1.1.2:50502".parse().unwrap(),
+        );
+        assert!(!events.iter().any(|evt| matches!(evt, Event::UpdateRoutes)));
+        assert_eq!(mgr.get_route_changes().len(), 0);
+    }
+}
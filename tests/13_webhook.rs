@@ -0,0 +1,116 @@
+#![cfg(feature = "webhook")]
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{IpAddr, TcpListener};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use wg_netmanager::alert::*;
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::mesh_event::MeshEvent;
+    use wg_netmanager::webhook::*;
+
+    fn get_test_config() -> StaticConfiguration {
+        common::builder()
+            .wg_ip(common::ip("10.1.1.1"))
+            .wg_port(50001)
+            .admin_port(50501)
+            .build()
+    }
+
+    #[test]
+    fn test_notification() {
+        let static_config = get_test_config();
+        let peer: IpAddr = "10.1.1.2".parse().unwrap();
+
+        assert_eq!(
+            WebhookEvent::from_mesh_event(&MeshEvent::EndpointChanged {
+                wg_ip: peer,
+                endpoint: None
+            }),
+            None
+        );
+        let event = WebhookEvent::from_mesh_event(&MeshEvent::RouteAdded {
+            to: "10.1.1.3".parse().unwrap(),
+            gateway: Some(peer),
+        })
+        .unwrap();
+        let json = serde_json::to_value(Notification::new(100, &static_config, event)).unwrap();
+        assert_eq!(json["event"], "route_change");
+        assert_eq!(json["change"], "added");
+        assert_eq!(json["gateway"], "10.1.1.2");
+        assert_eq!(json["node"], "test");
+        assert_eq!(json["time"], 100);
+        assert_eq!(
+            json["text"],
+            "test (10.1.1.1): route to 10.1.1.3 via 10.1.1.2 added"
+        );
+
+        let alert = Alert {
+            kind: AlertKind::KeyConflict(peer),
+            severity: Severity::Critical,
+            message: "wg_ip used twice ?".to_string(),
+            since: 100,
+        };
+        let json = serde_json::to_value(WebhookEvent::from_alert(&alert).unwrap()).unwrap();
+        assert_eq!(json["event"], "key_conflict");
+        assert_eq!(json["peer"], "10.1.1.2");
+        let alert = Alert {
+            kind: AlertKind::RouteChurn,
+            ..alert
+        };
+        assert_eq!(WebhookEvent::from_alert(&alert), None);
+
+        assert_eq!(retry_delay(1), Duration::from_secs(2));
+        assert_eq!(retry_delay(3), Duration::from_secs(8));
+        assert_eq!(retry_delay(100), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_post() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(len) = line.strip_prefix("content-length:") {
+                    content_length = len.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            tx.send(body).unwrap();
+        });
+
+        let notifier = WebhookNotifier::new(&[url]);
+        let event = WebhookEvent::from_mesh_event(&MeshEvent::PeerDown {
+            wg_ip: "10.1.1.2".parse().unwrap(),
+        })
+        .unwrap();
+        notifier.notify(Notification::new(100, &get_test_config(), event));
+
+        let body = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["event"], "peer_down");
+        assert_eq!(json["text"], "test (10.1.1.1): peer 10.1.1.2 is down");
+    }
+}
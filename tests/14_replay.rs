@@ -0,0 +1,106 @@
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr, UdpSocket};
+    use std::time::Duration;
+
+    use wg_netmanager::crypt_udp::*;
+
+    #[test]
+    fn test_replay_filter() {
+        let mut filter = ReplayFilter::default();
+        assert!(filter.check(100, [1; 24]));
+        assert!(filter.check(101, [2; 24]));
+        assert!(!filter.check(105, [1; 24]));
+
+        // Forgotten only after the timestamp check would reject the packet
+        assert!(!filter.check(100 + 2 * MAX_TIME_DIFF, [1; 24]));
+        assert!(filter.check(101 + 2 * MAX_TIME_DIFF, [3; 24]));
+        assert_eq!(filter.len(), 2);
+        assert!(filter.check(200, [1; 24]));
+        assert_eq!(filter.len(), 1);
+    }
+
+    #[test]
+    fn test_replayed_packet() {
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let key = vec![1; 32];
+
+        // The attacker captures the encrypted packet
+        let capture = UdpSocket::bind(SocketAddr::new(localhost, 0)).unwrap();
+        capture
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut sender = CryptUdp::bind(localhost, 0).unwrap().key(&key).unwrap();
+        sender
+            .send_to(b"advertisement", capture.local_addr().unwrap())
+            .unwrap();
+        let mut packet = [0u8; 1500];
+        let (len, _) = capture.recv_from(&mut packet).unwrap();
+
+        let receiver = CryptUdp::bind(localhost, 0).unwrap().key(&key).unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let to = receiver.local_addr().unwrap();
+        let mut buf = [0u8; 1500];
+        capture.send_to(&packet[..len], to).unwrap();
+        let (received, _) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..received], b"advertisement");
+
+        // Replayed from another address
+        let attacker = UdpSocket::bind(SocketAddr::new(localhost, 0)).unwrap();
+        attacker.send_to(&packet[..len], to).unwrap();
+        let err = receiver.recv_from(&mut buf).unwrap_err();
+        assert!(err.downcast_ref::<ReplayError>().is_some());
+
+        // A socket sharing the filter rejects the packet, too
+        let mut other = CryptUdp::bind(localhost, 0).unwrap().key(&key).unwrap();
+        other
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        other.share_replay_filter(&receiver);
+        attacker
+            .send_to(&packet[..len], other.local_addr().unwrap())
+            .unwrap();
+        let err = other.recv_from(&mut buf).unwrap_err();
+        assert!(err.downcast_ref::<ReplayError>().is_some());
+    }
+
+    #[test]
+    fn test_async_receive() {
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let key = vec![1; 32];
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let receiver = CryptUdp::bind(localhost, 0).unwrap().key(&key).unwrap();
+            let async_receiver = receiver.try_clone_async().unwrap();
+            let mut sender = CryptUdp::bind(localhost, 0).unwrap().key(&key).unwrap();
+            sender
+                .send_to(b"advertisement", receiver.local_addr().unwrap())
+                .unwrap();
+
+            let mut buf = [0u8; 1500];
+            let (received, src_addr) =
+                tokio::time::timeout(Duration::from_secs(5), async_receiver.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(&buf[..received], b"advertisement");
+            assert_eq!(src_addr, sender.local_addr().unwrap());
+            // Counted for the socket and all its clones
+            assert_eq!(receiver.counters().counts().received, 1);
+            assert_eq!(sender.counters().counts().sent, 1);
+
+            // The async clone makes the sending socket non-blocking, a burst is sent anyway
+            let _async_sender = sender.try_clone_async().unwrap();
+            for _ in 0..1000 {
+                sender
+                    .send_to(&[0; 1200], receiver.local_addr().unwrap())
+                    .unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,96 @@
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use wg_netmanager::network_file::*;
+
+    const NETWORK_YAML: &str = "# Example network
+#
+# with a comment block
+
+network:
+  name: lab
+  sharedKey: AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+  subnet: 10.1.1.0/24
+  portAllocation:
+    strategy: fixed
+    wgPort: 50000
+    adminPort: 50500
+
+peers:
+  - endPoint: 192.168.1.70:50000
+    adminPort: 55555
+    wgIp: 10.1.1.1
+    location: basement
+  - endPoint: 192.168.1.212:50000
+    adminPort: 55555
+    wgIp: 10.1.1.2
+";
+
+    #[test]
+    fn test_round_trip() {
+        let network_file = NetworkFile::from_yaml_str(NETWORK_YAML).unwrap();
+        assert_eq!(network_file.network.name, Some("lab".to_string()));
+        assert_eq!(network_file.peers.len(), 2);
+
+        let content = network_file.to_yaml_string().unwrap();
+        assert!(content.starts_with("# Example network\n#\n# with a comment block\n\nnetwork:\n"));
+        assert!(content.contains("location: basement"));
+        assert!(content.contains("strategy: fixed"));
+        let reloaded = NetworkFile::from_yaml_str(&content).unwrap();
+        assert_eq!(reloaded, network_file);
+        assert_eq!(reloaded.to_yaml_string().unwrap(), content);
+
+        let network = network_file.configuration().unwrap();
+        assert_eq!(network.name, Some("lab".to_string()));
+        assert_eq!(network.peers.len(), 2);
+        assert_eq!(
+            network.ports_for(&"10.1.1.9".parse().unwrap()),
+            Some((50000, 50500))
+        );
+    }
+
+    #[test]
+    fn test_edit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("network.yaml");
+        std::fs::write(&path, NETWORK_YAML).unwrap();
+        let wg_ip: IpAddr = "10.1.1.3".parse().unwrap();
+
+        let mut network_file = NetworkFile::load(&path).unwrap();
+        network_file
+            .add_peer(StaticPeerEntry::new(wg_ip, "hub.example.com:50000", 50500))
+            .unwrap();
+        assert!(network_file
+            .add_peer(StaticPeerEntry::new(wg_ip, "other:50000", 50500))
+            .is_err());
+        network_file
+            .set_endpoint(&"10.1.1.1".parse().unwrap(), "192.168.1.71:50001")
+            .unwrap();
+        assert!(network_file
+            .set_endpoint(&"10.1.1.9".parse().unwrap(), "192.168.1.9:50000")
+            .is_err());
+        assert!(network_file.remove_peer(&"10.1.1.2".parse().unwrap()));
+        network_file.save(&path).unwrap();
+
+        let network_file = NetworkFile::load(&path).unwrap();
+        let wg_ips = network_file
+            .peers
+            .iter()
+            .map(|p| p.wg_ip.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(wg_ips, vec!["10.1.1.1", "10.1.1.3"]);
+        let network = network_file.configuration().unwrap();
+        assert_eq!(network.peers[&wg_ip].endpoint, "hub.example.com:50000");
+        assert_eq!(
+            network.ports_for(&"10.1.1.1".parse().unwrap()),
+            Some((50001, 55555))
+        );
+
+        // An invalid file is never written
+        let mut network_file = network_file;
+        network_file.network.subnet = "no subnet".to_string();
+        assert!(network_file.save(&path).is_err());
+        assert!(NetworkFile::load(&path).is_ok());
+    }
+}
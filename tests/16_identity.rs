@@ -0,0 +1,111 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::identity::*;
+    use wg_netmanager::manager::*;
+
+    // This node is a static peer, so it accepts new peers
+    fn get_test_config(mut peers: HashMap<IpAddr, PublicPeer>) -> StaticConfiguration {
+        peers.extend(common::static_peers());
+        common::static_peer_builder().peers(peers).build()
+    }
+
+    fn advertisement(wg_ip: IpAddr, key: &str, identity: &NodeIdentity) -> AdvertisementPacket {
+        let mut ad = AdvertisementPacket {
+            addressed_to: AddressedTo::StaticAddress,
+            public_key: PublicKeyWithTime::new(key.to_string(), 1),
+            next_public_key: None,
+            local_wg_port: 50002,
+            local_admin_port: 50502,
+            wg_ip,
+            name: "peer".to_string(),
+            your_visible_wg_endpoint: None,
+            my_visible_wg_endpoint: None,
+            routedb_version: 0,
+            exported_nets: vec![],
+            extra_wg_ports: vec![],
+            force_relay_s: 0,
+            revocations: vec![],
+            moved_from: None,
+            identity: [0; 32],
+            signature: vec![],
+        };
+        ad.sign(identity);
+        ad
+    }
+
+    fn public_key_of(mgr: &NetworkManager, wg_ip: IpAddr) -> Option<String> {
+        mgr.peer_infos(0)
+            .into_iter()
+            .find(|p| p.wg_ip == wg_ip)
+            .and_then(|p| p.public_key)
+    }
+
+    #[test]
+    fn test_identity_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("identity");
+        let identity = NodeIdentity::from_file(&fname).unwrap();
+        assert_eq!(
+            NodeIdentity::from_file(&fname).unwrap().public(),
+            identity.public()
+        );
+        assert!(!format!("{:?}", identity).contains(&base64::encode(identity.seed())));
+
+        let signature = identity.sign(b"data");
+        assert!(verify(&identity.public(), b"data", &signature));
+        assert!(!verify(&identity.public(), b"other", &signature));
+        assert!(!verify(&identity.public(), b"data", &signature[1..]));
+    }
+
+    #[test]
+    fn test_impersonation() {
+        let peer_ip: IpAddr = "10.1.1.2".parse().unwrap();
+        let now = wg_netmanager::util::now();
+        let src_addr = "192.168.1.2:50502".parse().unwrap();
+        let peer = NodeIdentity::from_seed(&[2; 32]);
+        let attacker = NodeIdentity::from_seed(&[3; 32]);
+        let static_config = get_test_config(HashMap::new());
+        let mut mgr = NetworkManager::new(&static_config);
+
+        // A tampered advertisement is rejected
+        let mut ad = advertisement(peer_ip, "peer", &peer);
+        ad.name = "tampered".to_string();
+        assert!(mgr
+            .analyze_advertisement(now, &static_config, ad, src_addr)
+            .is_empty());
+        assert!(!mgr.knows_peer(&peer_ip));
+
+        let ad = advertisement(peer_ip, "peer", &peer);
+        mgr.analyze_advertisement(now, &static_config, ad, src_addr);
+        assert_eq!(public_key_of(&mgr, peer_ip), Some("peer".to_string()));
+
+        // Another identity cannot take over the wg_ip of a reachable node
+        let ad = advertisement(peer_ip, "attacker", &attacker);
+        assert!(mgr
+            .analyze_advertisement(now, &static_config, ad, src_addr)
+            .is_empty());
+        assert_eq!(public_key_of(&mgr, peer_ip), Some("peer".to_string()));
+
+        // A static peer is pinned to the identity given in network.yaml
+        let mut peers = HashMap::new();
+        let mut static_peer = PublicPeer::new(peer_ip, "192.168.1.2:50002", 50002, 50502);
+        static_peer.identity = Some(peer.public());
+        peers.insert(peer_ip, static_peer);
+        let static_config = get_test_config(peers);
+        let mut mgr = NetworkManager::new(&static_config);
+        let ad = advertisement(peer_ip, "attacker", &attacker);
+        mgr.analyze_advertisement(now, &static_config, ad, src_addr);
+        assert_eq!(public_key_of(&mgr, peer_ip), None);
+        let ad = advertisement(peer_ip, "peer", &peer);
+        mgr.analyze_advertisement(now, &static_config, ad, src_addr);
+        assert_eq!(public_key_of(&mgr, peer_ip), Some("peer".to_string()));
+    }
+}
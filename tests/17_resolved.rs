@@ -0,0 +1,21 @@
+#![cfg(all(target_os = "linux", feature = "resolved"))]
+
+#[cfg(test)]
+mod tests {
+    use wg_netmanager::resolved::*;
+
+    #[test]
+    fn test_link_dns() {
+        assert_eq!(
+            link_address(&"10.1.1.1".parse().unwrap()),
+            (2, vec![10, 1, 1, 1])
+        );
+        let (family, address) = link_address(&"fd00::1".parse().unwrap());
+        assert_eq!(family, 10);
+        assert_eq!(address.len(), 16);
+        assert_eq!(
+            link_domains(&["mesh".to_string()]),
+            vec![("mesh".to_string(), true)]
+        );
+    }
+}
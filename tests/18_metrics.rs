@@ -0,0 +1,92 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::{Duration, Instant};
+
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::manager::*;
+    use wg_netmanager::metrics::*;
+
+    fn scrape(exporter: &MetricsExporter, path: &str) -> String {
+        let mut stream = TcpStream::connect(exporter.local_addr()).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_metrics() {
+        let static_config = common::builder()
+            .peers(common::static_peers())
+            .metrics_port(Some(0))
+            .build();
+        let mgr = NetworkManager::new(&static_config);
+        let udp = UdpCounts {
+            sent: 3,
+            received: 2,
+            decrypt_failures: 1,
+        };
+
+        let metrics = Metrics::of(100, &mgr, udp, 4, &HashMap::new());
+        assert_eq!(metrics.nodes.get("static"), Some(&1));
+        assert_eq!(metrics.manager, ManagerCounters::default());
+        assert!(metrics.handshake_ages.is_empty());
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE wg_netmanager_nodes gauge\n"));
+        assert!(text.contains("wg_netmanager_nodes{class=\"static\"} 1\n"));
+        assert!(text.contains("wg_netmanager_advertisements_sent_total 4\n"));
+        assert!(text.contains("wg_netmanager_udp_decrypt_failures_total 1\n"));
+        assert!(text.contains("wg_netmanager_route_changes_per_minute 0\n"));
+
+        let mut metrics = metrics;
+        metrics
+            .handshake_ages
+            .push((common::ip("10.1.1.1"), "say \"hi\"".to_string(), 7));
+        assert!(metrics.to_prometheus().contains(
+            "wg_netmanager_handshake_age_seconds{wg_ip=\"10.1.1.1\",name=\"say \\\"hi\\\"\"} 7\n"
+        ));
+
+        let addr = SocketAddr::new(common::ip("127.0.0.1"), static_config.metrics_port.unwrap());
+        let mut exporter = MetricsExporter::listen(addr, None).unwrap();
+        assert!(exporter.update(100, &mgr, udp, 4, || Ok(HashMap::new())));
+        // Rate limited, so wg is not queried
+        assert!(!exporter.update(101, &mgr, udp, 5, || panic!("not rendered")));
+        let response = scrape(&exporter, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("wg_netmanager_advertisements_sent_total 4\n"));
+        assert!(scrape(&exporter, "/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // A client without request does not block the others
+        let _stalled = TcpStream::connect(exporter.local_addr()).unwrap();
+        let start = Instant::now();
+        assert!(scrape(&exporter, "/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // Bound to an ipv6 address
+        let mut exporter = MetricsExporter::listen("[::1]:0".parse().unwrap(), None).unwrap();
+        assert!(exporter.local_addr().is_ipv6());
+        assert!(exporter.update(100, &mgr, udp, 4, || Ok(HashMap::new())));
+        assert!(scrape(&exporter, "/metrics").contains("wg_netmanager_nodes"));
+
+        let mut exporter = MetricsExporter::listen(addr, Some("secret".to_string())).unwrap();
+        assert!(exporter.update(100, &mgr, udp, 4, || Ok(HashMap::new())));
+        let response = scrape(&exporter, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(!response.contains("wg_netmanager_"));
+        let mut stream = TcpStream::connect(exporter.local_addr()).unwrap();
+        write!(
+            stream,
+            "GET /metrics HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
@@ -0,0 +1,78 @@
+#[cfg(test)]
+mod tests {
+    use wg_netmanager::crypt_udp::*;
+
+    // Recalculate the CRC after a modification of the frame
+    fn seal(mut frame: Vec<u8>) -> Vec<u8> {
+        let padded = frame.len() - 16;
+        let crc_gen = crc::Crc::<u64>::new(&crc::CRC_64_ECMA_182);
+        let crc_result = crc_gen.checksum(&frame[..padded + 8]);
+        frame[padded + 8..].copy_from_slice(&crc_result.to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_frame_layout() {
+        let frame = encode_frame(b"hello", 0x0102030405060708).unwrap();
+        assert_eq!(frame.len(), 24);
+        assert_eq!(&frame[..5], b"hello");
+        assert_eq!(frame[5], FRAME_VERSION);
+        assert_eq!(&frame[6..8], &[5, 0]);
+        assert_eq!(&frame[8..16], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(
+            decode_frame(&frame).unwrap(),
+            Frame {
+                version: FRAME_VERSION,
+                timestamp: 0x0102030405060708,
+                payload: b"hello",
+            }
+        );
+
+        // No room for the version byte, so padded to the next octet
+        let frame = encode_frame(b"123456", 1).unwrap();
+        assert_eq!(frame.len(), 32);
+        assert_eq!(decode_frame(&frame).unwrap().payload, b"123456");
+
+        let long = vec![0; u16::MAX as usize + 1];
+        assert!(encode_frame(&long, 1).is_err());
+
+        // usize fields of the packets are serialized as u64 on every target
+        let packet = bincode::serialize(&UdpPacket::route_database_delta_request(5)).unwrap();
+        assert_eq!(&packet[packet.len() - 8..], &5u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_frame_compatibility() {
+        // Frames sent before the version byte has been introduced
+        let mut frame = encode_frame(b"hello", 7).unwrap();
+        frame[5] = 0;
+        let frame = seal(frame);
+        let decoded = decode_frame(&frame).unwrap();
+        assert_eq!(decoded.version, 0);
+        assert_eq!(decoded.payload, b"hello");
+
+        let mut frame = vec![0; 24];
+        frame[..6].copy_from_slice(b"123456");
+        frame[6..8].copy_from_slice(&6u16.to_le_bytes());
+        let frame = seal(frame);
+        let decoded = decode_frame(&frame).unwrap();
+        assert_eq!(decoded.version, 0);
+        assert_eq!(decoded.payload, b"123456");
+
+        // Frames of a newer version are rejected
+        let mut frame = encode_frame(b"hello", 7).unwrap();
+        frame[5] = FRAME_VERSION + 1;
+        assert!(decode_frame(&seal(frame)).is_err());
+
+        // A length beyond the padding is rejected
+        let mut frame = encode_frame(b"hello", 7).unwrap();
+        frame[6..8].copy_from_slice(&7u16.to_le_bytes());
+        assert!(decode_frame(&seal(frame)).is_err());
+
+        let mut frame = encode_frame(b"hello", 7).unwrap();
+        frame[0] ^= 1;
+        assert!(decode_frame(&frame).is_err());
+        assert!(decode_frame(&frame[..16]).is_err());
+        assert!(decode_frame(&frame[..23]).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+#![cfg(feature = "mdns")]
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::net::IpAddr;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::event::Event;
+    use wg_netmanager::manager::*;
+    use wg_netmanager::mdns::*;
+
+    fn get_test_config() -> StaticConfiguration {
+        common::builder()
+            .ip_list(vec![
+                "127.0.0.1".parse().unwrap(),
+                "192.168.1.2".parse().unwrap(),
+                "fd00::2".parse().unwrap(),
+            ])
+            .mdns(true)
+            .build()
+    }
+
+    #[test]
+    fn test_mdns_records() {
+        let static_config = get_test_config();
+        let service = MdnsService::from_config(&static_config);
+        assert_eq!(service.instance, "test@10.1.1.2");
+        assert_eq!(service.host, "wgnetmgr-10-1-1-2.local");
+        assert_eq!(service.mesh, mesh_fingerprint(&[0; 32]));
+        assert_ne!(service.mesh, mesh_fingerprint(&[1; 32]));
+        assert_eq!(
+            service.admin_endpoints(),
+            vec![
+                "192.168.1.2:50502".parse().unwrap(),
+                "[fd00::2]:50502".parse().unwrap()
+            ]
+        );
+
+        let packet = decode(&service.announcement()).unwrap();
+        assert!(!packet.asks_for_service);
+        assert_eq!(packet.services, vec![service.clone()]);
+        assert!(decode(&query()).unwrap().asks_for_service);
+        assert!(decode(&query()).unwrap().services.is_empty());
+
+        // Other responders compress the names. Here the name of the SRV record points
+        // to the same name in the PTR record.
+        let mut compressed = service.announcement();
+        let first = |data: &[u8], from: usize| {
+            from + data[from..].windows(5).position(|w| w == b"test@").unwrap() - 1
+        };
+        let ptr_data = first(&compressed, 0);
+        let srv_name = first(&compressed, ptr_data + 2);
+        let name_len = 1 + service.instance.len() + SERVICE.len() + 2;
+        let pointer = [0xc0 | (ptr_data >> 8) as u8, ptr_data as u8];
+        compressed.splice(srv_name..srv_name + name_len, pointer);
+        assert_eq!(decode(&compressed).unwrap().services, vec![service]);
+
+        assert!(decode(&[0; 11]).is_err());
+        let mut truncated = MdnsService::from_config(&static_config).announcement();
+        truncated.truncate(40);
+        assert!(decode(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_mdns_bootstrap() {
+        let static_config = get_test_config();
+        let mgr = NetworkManager::new(&static_config);
+        let peer: IpAddr = "10.1.1.3".parse().unwrap();
+        let endpoints = vec!["192.168.1.3:50503".parse().unwrap()];
+        let events = mgr.local_peer_discovered(peer, &endpoints);
+        assert!(matches!(
+            events[..],
+            [Event::SendAdvertisement { to, wg_ip, .. }] if to == endpoints[0] && wg_ip == peer
+        ));
+        assert!(mgr
+            .local_peer_discovered(static_config.wg_ip, &endpoints)
+            .is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::identity::*;
+    use wg_netmanager::manager::*;
+    use wg_netmanager::peer_db::*;
+
+    fn get_test_config(imported_peers: Vec<PublicPeer>) -> StaticConfiguration {
+        let static_ip = common::ip("10.1.1.1");
+        let mut static_peer = common::static_peer(static_ip);
+        static_peer.endpoint = "192.168.1.1:50001".to_string();
+        static_peer.tier = 2;
+        let mut peers = HashMap::new();
+        peers.insert(static_ip, static_peer);
+        common::builder()
+            .peers(peers)
+            .imported_peers(imported_peers)
+            .build()
+    }
+
+    fn local_advertisement(wg_ip: IpAddr, identity: &NodeIdentity) -> AdvertisementPacket {
+        let mut ad = AdvertisementPacket {
+            addressed_to: AddressedTo::LocalAddress,
+            public_key: PublicKeyWithTime::new("peer", 1),
+            next_public_key: None,
+            local_wg_port: 50003,
+            local_admin_port: 50503,
+            wg_ip,
+            name: "peer".to_string(),
+            your_visible_wg_endpoint: None,
+            my_visible_wg_endpoint: None,
+            routedb_version: 0,
+            exported_nets: vec![],
+            extra_wg_ports: vec![],
+            force_relay_s: 0,
+            revocations: vec![],
+            moved_from: None,
+            identity: [0; 32],
+            signature: vec![],
+        };
+        ad.sign(identity);
+        ad
+    }
+
+    #[test]
+    fn test_peer_db_export_import() {
+        let local_ip: IpAddr = "10.1.1.3".parse().unwrap();
+        let local_identity = NodeIdentity::from_seed(&[3; 32]);
+        let static_config = get_test_config(vec![]);
+        let mut mgr = NetworkManager::new(&static_config);
+        let now = wg_netmanager::util::now();
+        mgr.analyze_advertisement(
+            now,
+            &static_config,
+            local_advertisement(local_ip, &local_identity),
+            "192.168.1.3:50503".parse().unwrap(),
+        );
+
+        let mut peer_db = PeerDatabase::of(now, static_config.wg_ip, &mgr);
+        assert_eq!(peer_db.peers.len(), 2);
+        assert_eq!(peer_db.peers[0].endpoint, "192.168.1.1:50001");
+        assert_eq!(
+            peer_db.peers[1],
+            PeerContact {
+                wg_ip: local_ip,
+                endpoint: "192.168.1.3:50003".to_string(),
+                wg_port: 50003,
+                admin_port: 50503,
+                identity: Some(base64::encode(local_identity.public())),
+            }
+        );
+
+        peer_db.sign(&static_config.shared_key).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("peers.json");
+        peer_db.write(&fname).unwrap();
+        assert_eq!(PeerDatabase::read(&fname, &[&[0; 32]]).unwrap(), peer_db);
+        assert!(PeerDatabase::read(&fname, &[&[1; 32]]).is_err());
+        assert!(PeerDatabase::read(&fname, &[&[1; 32], &[0; 32]]).is_ok());
+
+        let mut tampered = peer_db.clone();
+        tampered.peers[1].endpoint = "1.2.3.4:50003".to_string();
+        assert!(tampered.verify(&static_config.shared_key).is_err());
+
+        // A new node uses the contacts as static peers, but keeps the ones of network.yaml
+        let mut imported = peer_db.public_peers(2).unwrap();
+        imported[0].endpoint = "ignored:1".to_string();
+        let mut own = imported[1].clone();
+        own.wg_ip = static_config.wg_ip;
+        imported.push(own);
+        let static_config = get_test_config(imported);
+        assert_eq!(static_config.peers.len(), 2);
+        assert_eq!(
+            static_config.peers[&"10.1.1.1".parse().unwrap()].endpoint,
+            "192.168.1.1:50001"
+        );
+        assert_eq!(
+            static_config.peers[&local_ip].identity,
+            Some(local_identity.public())
+        );
+        assert!(!static_config.is_static);
+        assert_eq!(NetworkManager::new(&static_config).node_classes().len(), 2);
+    }
+}
@@ -0,0 +1,52 @@
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+
+    fn local_contact(nr_ips: usize) -> UdpPacket {
+        UdpPacket::LocalContact(LocalContactPacket {
+            public_key: PublicKeyWithTime::new("public", 1),
+            next_public_key: None,
+            local_ip_list: (0..nr_ips)
+                .map(|i| IpAddr::from([192, 168, 1, i as u8]))
+                .collect(),
+            local_wg_port: 50000,
+            local_admin_port: 50001,
+            my_visible_wg_endpoint: None,
+            wg_ip: "10.1.1.1".parse().unwrap(),
+            name: "test".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_packet_limits() {
+        let buf = bincode::serialize(&local_contact(2)).unwrap();
+        assert!(matches!(
+            UdpPacket::decode(&buf).unwrap(),
+            UdpPacket::LocalContact(local) if local.local_ip_list.len() == 2
+        ));
+        let buf = bincode::serialize(&local_contact(MAX_IP_LIST + 1)).unwrap();
+        assert!(UdpPacket::decode(&buf).is_err());
+
+        // A corrupted length of the public key is rejected without allocation
+        let mut buf = bincode::serialize(&local_contact(2)).unwrap();
+        buf[4..12].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        assert!(UdpPacket::decode(&buf).is_err());
+
+        let db = UdpPacket::RouteDatabase(RouteDatabasePacket {
+            sender: "10.1.1.1".parse().unwrap(),
+            routedb_version: 1,
+            nr_entries: 0,
+            chunk: 0,
+            nr_chunks: usize::MAX,
+            known_routes: vec![],
+        });
+        assert!(UdpPacket::decode(&bincode::serialize(&db).unwrap()).is_err());
+
+        assert!(UdpPacket::decode(&[0; MAX_PACKET_SIZE + 1]).is_err());
+        let ping = bincode::serialize(&UdpPacket::Ping { sent_ms: 5 }).unwrap();
+        assert!(UdpPacket::decode(&ping).is_ok());
+    }
+}
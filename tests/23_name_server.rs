@@ -0,0 +1,176 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::net::{IpAddr, UdpSocket};
+    use std::time::Duration;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::identity::*;
+    use wg_netmanager::manager::*;
+    use wg_netmanager::name_server::*;
+
+    fn get_test_config() -> StaticConfiguration {
+        common::builder()
+            .name("Test Node")
+            .mesh_domain(Some("mesh".to_string()))
+            .build()
+    }
+
+    fn local_advertisement(wg_ip: IpAddr, name: &str) -> AdvertisementPacket {
+        let mut ad = AdvertisementPacket {
+            addressed_to: AddressedTo::LocalAddress,
+            public_key: PublicKeyWithTime::new(format!("key {}", wg_ip), 1),
+            next_public_key: None,
+            local_wg_port: 50003,
+            local_admin_port: 50503,
+            wg_ip,
+            name: name.to_string(),
+            your_visible_wg_endpoint: None,
+            my_visible_wg_endpoint: None,
+            routedb_version: 0,
+            exported_nets: vec![],
+            extra_wg_ports: vec![],
+            force_relay_s: 0,
+            revocations: vec![],
+            moved_from: None,
+            identity: [0; 32],
+            signature: vec![],
+        };
+        ad.sign(&NodeIdentity::generate());
+        ad
+    }
+
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&[1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.extend_from_slice(&[0, 0, qtype as u8, 0, 1]);
+        packet
+    }
+
+    fn rcode(response: &[u8]) -> u8 {
+        response[3] & 0x0f
+    }
+
+    fn answers(response: &[u8]) -> u16 {
+        u16::from_be_bytes([response[6], response[7]])
+    }
+
+    #[test]
+    fn test_mesh_names() {
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+        let now = wg_netmanager::util::now();
+        for (wg_ip, name) in [("10.1.1.3", "laptop"), ("10.1.1.4", "nas_1")] {
+            mgr.analyze_advertisement(
+                now,
+                &static_config,
+                local_advertisement(wg_ip.parse().unwrap(), name),
+                format!("192.168.1.{}:50503", &wg_ip[7..]).parse().unwrap(),
+            );
+        }
+        let names = MeshNames::of(now, &mgr, &static_config);
+        assert_eq!(
+            names.hosts.keys().collect::<Vec<_>>(),
+            vec!["laptop", "nas-1", "test-node"]
+        );
+        assert_eq!(
+            names.hosts_fragment(),
+            "10.1.1.3\tlaptop.mesh laptop\n\
+             10.1.1.4\tnas-1.mesh nas-1\n\
+             10.1.1.2\ttest-node.mesh test-node\n"
+        );
+
+        let response = names.answer(&query(7, "Laptop.mesh", 1)).unwrap();
+        assert_eq!(&response[0..2], &[0, 7]);
+        assert_eq!(rcode(&response), 0);
+        assert_eq!(answers(&response), 1);
+        assert_eq!(&response[response.len() - 4..], &[10, 1, 1, 3]);
+        let response = names.answer(&query(8, "laptop.mesh", 28)).unwrap();
+        assert_eq!((rcode(&response), answers(&response)), (0, 0));
+        let response = names.answer(&query(9, "phone.mesh", 1)).unwrap();
+        assert_eq!((rcode(&response), answers(&response)), (3, 0));
+        let response = names.answer(&query(10, "example.com", 1)).unwrap();
+        assert_eq!((rcode(&response), answers(&response)), (5, 0));
+        assert!(names.answer(&response).is_err());
+        assert!(names.answer(&[0; 11]).is_err());
+
+        let name_server = NameServer::listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        name_server.update(&names);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+            .send_to(&query(11, "nas-1.mesh", 1), name_server.local_addr())
+            .unwrap();
+        let mut buf = [0; 512];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(answers(&buf[..len]), 1);
+        assert_eq!(&buf[len - 4..len], &[10, 1, 1, 4]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("mesh.hosts");
+        let mut hosts_file = HostsFile::new(fname.to_string_lossy(), "wgtest");
+        assert!(hosts_file.update(&names).unwrap());
+        assert!(!hosts_file.update(&names).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&fname).unwrap(),
+            format!(
+                "# BEGIN wg_netmanager wgtest\n{}# END wg_netmanager wgtest\n",
+                names.hosts_fragment()
+            )
+        );
+        hosts_file.remove();
+        assert!(!fname.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_bind_before_interface() {
+        // The wg ip is not yet assigned, when binding before dropping privileges
+        let socket = NameServer::bind("10.254.253.252:0".parse().unwrap()).unwrap();
+        let name_server = NameServer::serve(socket).unwrap();
+        assert_eq!(name_server.local_addr().ip().to_string(), "10.254.253.252");
+    }
+
+    #[test]
+    fn test_hosts_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("hosts");
+        let original = "127.0.0.1\tlocalhost\n# BEGIN wg_netmanager other\n10.2.0.1\tx\n\
+                        # END wg_netmanager other\n::1\tlocalhost";
+        std::fs::write(&fname, original).unwrap();
+        let mut hosts_file = HostsFile::new(fname.to_string_lossy(), "wgtest");
+        let mut names = MeshNames::default();
+        names
+            .hosts
+            .insert("laptop".to_string(), vec!["10.1.1.3".parse().unwrap()]);
+        assert!(hosts_file.update(&names).unwrap());
+        names
+            .hosts
+            .insert("nas".to_string(), vec!["10.1.1.4".parse().unwrap()]);
+        assert!(hosts_file.update(&names).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&fname).unwrap(),
+            format!(
+                "{}\n# BEGIN wg_netmanager wgtest\n10.1.1.3\tlaptop\n10.1.1.4\tnas\n\
+                 # END wg_netmanager wgtest\n",
+                original
+            )
+        );
+
+        // an incomplete block of this instance does not swallow other lines
+        let mangled = "# BEGIN wg_netmanager wgtest\n127.0.0.1\tlocalhost\n";
+        std::fs::write(&fname, mangled).unwrap();
+        hosts_file.remove();
+        assert_eq!(std::fs::read_to_string(&fname).unwrap(), mangled);
+    }
+}
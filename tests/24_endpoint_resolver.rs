@@ -0,0 +1,74 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use wg_netmanager::endpoint_resolver::*;
+
+    #[derive(serde::Deserialize)]
+    struct Peer {
+        #[serde(default)]
+        resolver: EndpointResolver,
+    }
+
+    fn parse(yaml: &str) -> Result<EndpointResolver, serde_yaml::Error> {
+        serde_yaml::from_str::<Peer>(yaml).map(|peer| peer.resolver)
+    }
+
+    fn resolver(yaml: &str) -> EndpointResolver {
+        parse(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_hosts_file_resolver() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("endpoints");
+        std::fs::write(
+            &fname,
+            "# static endpoints\n192.0.2.1 hub.example.org hub\n\n\
+             2001:db8::1 Hub.example.org # v6\n192.0.2.2 other\n",
+        )
+        .unwrap();
+        let hosts_file = resolver(&format!("resolver:\n  hostsFile: {}", fname.display()));
+        assert_eq!(
+            hosts_file,
+            EndpointResolver::HostsFile(fname.to_string_lossy().to_string())
+        );
+        assert_eq!(
+            hosts_file.resolve("hub.example.org:50000").unwrap(),
+            vec![common::ip("192.0.2.1"), common::ip("2001:db8::1")]
+        );
+        assert!(hosts_file.resolve("unknown:50000").is_err());
+        // No lookup for ips
+        assert_eq!(
+            hosts_file.resolve("[2001:db8::5]:50000").unwrap(),
+            vec![common::ip("2001:db8::5")]
+        );
+
+        assert_eq!(resolver("other: 1"), EndpointResolver::Dns);
+        assert_eq!(resolver("resolver: dns"), EndpointResolver::Dns);
+        assert!(parse("resolver: etcd").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_resolver() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("lookup");
+        std::fs::write(
+            &fname,
+            "#!/bin/sh\n[ \"$1\" = hub ] || exit 1\necho 192.0.2.1\necho\necho 192.0.2.3\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&fname, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let command = resolver(&format!("resolver:\n  command: {}", fname.display()));
+        assert_eq!(
+            command.resolve("hub:50000").unwrap(),
+            vec![common::ip("192.0.2.1"), common::ip("192.0.2.3")]
+        );
+        assert!(command.resolve("unknown:50000").is_err());
+    }
+}
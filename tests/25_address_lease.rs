@@ -0,0 +1,175 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use wg_netmanager::address_lease::*;
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::event::Event;
+    use wg_netmanager::manager::*;
+
+    fn get_test_config(wg_ip: &str, static_peer: &str, admin_port: u16) -> StaticConfiguration {
+        let mut peer = common::static_peer(common::ip(static_peer));
+        peer.endpoint = format!("127.0.0.1:{}", admin_port);
+        peer.wg_port = admin_port;
+        peer.admin_port = admin_port;
+        let mut peers = HashMap::new();
+        peers.insert(peer.wg_ip, peer);
+        common::builder()
+            .wg_ip(common::ip(wg_ip))
+            .wg_port(50000)
+            .admin_port(50500)
+            .peers(peers)
+            .build()
+    }
+
+    fn request(identity: u8, requested: Option<&str>) -> AddressRequestPacket {
+        AddressRequestPacket {
+            identity: [identity; 32],
+            requested: requested.map(common::ip),
+        }
+    }
+
+    #[test]
+    fn test_offer() {
+        let subnet = "10.1.1.0/29".parse().unwrap();
+        let mut leases = AddressLeases::default();
+        let taken = common::ip("10.1.1.1");
+        let in_use = |wg_ip: &IpAddr| *wg_ip == taken;
+
+        let first = leases.offer(0, &subnet, &request(1, None), in_use).unwrap();
+        assert_eq!(first.lease_time, LEASE_TIME);
+        assert!(first.wg_ip != taken && subnet.contains(&first.wg_ip));
+        // Same identity, same address. Another one gets another address.
+        let again = leases
+            .offer(10, &subnet, &request(1, None), in_use)
+            .unwrap();
+        assert_eq!(again.wg_ip, first.wg_ip);
+        let other = leases
+            .offer(
+                10,
+                &subnet,
+                &request(2, Some(&first.wg_ip.to_string())),
+                in_use,
+            )
+            .unwrap();
+        assert!(other.wg_ip != first.wg_ip && other.wg_ip != taken);
+        // A free requested address is kept on renewal, one in use is replaced
+        let renewed = leases
+            .offer(20, &subnet, &request(3, Some("10.1.1.6")), in_use)
+            .unwrap();
+        assert_eq!(renewed.wg_ip, common::ip("10.1.1.6"));
+        let moved = leases
+            .offer(20, &subnet, &request(4, Some("10.1.1.1")), in_use)
+            .unwrap();
+        assert!(moved.wg_ip != taken);
+
+        // Neither network nor broadcast address, and then the subnet is exhausted
+        let mut nr_leases = 4;
+        while let Some(lease) = leases.offer(30, &subnet, &request(10 + nr_leases, None), in_use) {
+            assert!(lease.wg_ip != common::ip("10.1.1.0") && lease.wg_ip != common::ip("10.1.1.7"));
+            nr_leases += 1;
+        }
+        assert_eq!(nr_leases, 5);
+        // until the leases have expired
+        assert!(leases
+            .offer(30 + LEASE_TIME, &subnet, &request(99, None), in_use)
+            .is_some());
+    }
+
+    #[test]
+    fn test_address_request() {
+        let now = wg_netmanager::util::now();
+        let request = request(1, None);
+        let src_addr = "192.168.1.7:50500".parse().unwrap();
+
+        let static_config = get_test_config("10.1.1.5", "10.1.1.1", 50501);
+        let mut mgr = NetworkManager::new(&static_config);
+        assert!(mgr
+            .process_address_request(now, &static_config, request.clone(), src_addr)
+            .is_empty());
+
+        let static_config = get_test_config("10.1.1.1", "10.1.1.1", 50501);
+        let mut mgr = NetworkManager::new(&static_config);
+        let events = mgr.process_address_request(now, &static_config, request, src_addr);
+        let lease = match events.as_slice() {
+            [Event::SendAddressLease { to, lease }] if *to == src_addr => lease.clone(),
+            _ => panic!("no lease in {:?}", events),
+        };
+        assert!(lease.wg_ip != common::ip("10.1.1.1"));
+    }
+
+    #[test]
+    fn test_renewal() {
+        let now = wg_netmanager::util::now();
+        let mut static_config = get_test_config("10.1.1.5", "10.1.1.1", 50501);
+        let mut lease = AddressLeasePacket {
+            identity: static_config.identity.public(),
+            wg_ip: common::ip("10.1.1.5"),
+            lease_time: LEASE_TIME,
+        };
+        // Without wgIp auto, leases are ignored
+        assert_eq!(apply_lease(now, &mut static_config, &lease).unwrap(), None);
+        assert!(!renewal_due(now, &static_config));
+
+        static_config.wg_ip_lease = Some(now + LEASE_TIME / 2 + 1);
+        assert!(!renewal_due(now, &static_config));
+        assert!(renewal_due(now + 1, &static_config));
+        assert!(matches!(
+            renewal_request(&static_config),
+            UdpPacket::AddressRequest(request) if request == AddressRequestPacket {
+                identity: static_config.identity.public(),
+                requested: Some(common::ip("10.1.1.5")),
+            }
+        ));
+        assert_eq!(apply_lease(now, &mut static_config, &lease).unwrap(), None);
+        assert_eq!(static_config.wg_ip_lease, Some(now + LEASE_TIME));
+
+        lease.wg_ip = common::ip("10.1.1.9");
+        assert_eq!(
+            apply_lease(now, &mut static_config, &lease).unwrap(),
+            Some(common::ip("10.1.1.5"))
+        );
+        assert_eq!(static_config.wg_ip, common::ip("10.1.1.9"));
+        lease.identity = [7; 32];
+        lease.wg_ip = common::ip("10.1.1.10");
+        assert_eq!(apply_lease(now, &mut static_config, &lease).unwrap(), None);
+        assert_eq!(static_config.wg_ip, common::ip("10.1.1.9"));
+    }
+
+    #[test]
+    fn test_request_lease() {
+        let mut server = CryptUdp::bind(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)
+            .unwrap()
+            .key(&[0; 32])
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+        let static_config = get_test_config("10.1.1.1", "10.1.1.1", port);
+        let peers = static_config.peers.clone();
+        let handle = std::thread::spawn(move || {
+            let mut mgr = NetworkManager::new(&static_config);
+            let mut buf = [0; MAX_PACKET_SIZE];
+            let (len, src_addr) = server.recv_from(&mut buf).unwrap();
+            let UdpPacket::AddressRequest(request) = UdpPacket::decode(&buf[..len]).unwrap() else {
+                panic!("no address request");
+            };
+            let now = wg_netmanager::util::now();
+            for evt in mgr.process_address_request(now, &static_config, request, src_addr) {
+                if let Event::SendAddressLease { to, lease } = evt {
+                    let buf = bincode::serialize(&UdpPacket::AddressLease(lease)).unwrap();
+                    server.send_to(&buf, to).unwrap();
+                }
+            }
+        });
+        let lease = request_lease(&peers, &[0; 32], &[3; 32]).unwrap();
+        handle.join().unwrap();
+        assert_eq!(lease.identity, [3; 32]);
+        assert!(lease.wg_ip != common::ip("10.1.1.1"));
+
+        assert!(request_lease(&HashMap::new(), &[0; 32], &[3; 32]).is_err());
+    }
+}
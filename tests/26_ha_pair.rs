@@ -0,0 +1,106 @@
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::ha_pair::*;
+
+    fn coordinator(now: u64, partner: &str, priority: u8, notify: Option<String>) -> HaCoordinator {
+        HaCoordinator::new(
+            now,
+            HaPair {
+                partner: partner.parse().unwrap(),
+                priority,
+                notify,
+            },
+        )
+    }
+
+    fn heartbeat(from: &HaCoordinator) -> HaHeartbeatPacket {
+        match from.heartbeat() {
+            UdpPacket::HaHeartbeat(heartbeat) => heartbeat,
+            _ => panic!("not a heartbeat"),
+        }
+    }
+
+    const A: &str = "192.168.1.10:50001";
+    const B: &str = "192.168.1.11:50001";
+
+    #[test]
+    fn test_ha_pair() {
+        let a_addr: SocketAddr = A.parse().unwrap();
+        let b_addr: SocketAddr = B.parse().unwrap();
+        let mut a = coordinator(0, B, 200, None);
+        let mut b = coordinator(0, A, 100, None);
+        assert_eq!((a.state(), b.state()), (HaState::Standby, HaState::Standby));
+
+        // Both standby: the higher priority takes over at once
+        assert_eq!(b.process_heartbeat(1, heartbeat(&a), a_addr), None);
+        assert_eq!(
+            a.process_heartbeat(1, heartbeat(&b), b_addr),
+            Some(HaState::Active)
+        );
+        for now in 2..10 {
+            assert_eq!(b.process_heartbeat(now, heartbeat(&a), a_addr), None);
+            assert_eq!(b.process_timer(now), None);
+        }
+
+        // Failover after the heartbeats of the active node stop
+        assert_eq!(b.process_timer(9 + HA_TIMEOUT - 1), None);
+        assert_eq!(b.process_timer(9 + HA_TIMEOUT), Some(HaState::Active));
+        assert!(b.is_active());
+
+        // No preemption by the restarted node of higher priority
+        let mut a = coordinator(20, B, 200, None);
+        for now in 20..30 {
+            assert_eq!(a.process_heartbeat(now, heartbeat(&b), b_addr), None);
+            assert_eq!(a.process_timer(now), None);
+        }
+        assert_eq!(a.state(), HaState::Standby);
+
+        // Split brain: the lower priority gives way
+        let mut a = coordinator(0, B, 200, None);
+        assert_eq!(a.process_timer(HA_TIMEOUT), Some(HaState::Active));
+        assert_eq!(
+            b.process_heartbeat(30, heartbeat(&a), a_addr),
+            Some(HaState::Standby)
+        );
+        assert_eq!(a.process_heartbeat(30, heartbeat(&b), b_addr), None);
+        assert!(a.is_active());
+
+        // Only the partner's address is accepted
+        let mut c = coordinator(0, B, 100, None);
+        assert_eq!(
+            c.process_heartbeat(1, heartbeat(&b), "192.168.1.99:50001".parse().unwrap()),
+            None
+        );
+        assert_eq!(c.process_timer(HA_TIMEOUT), Some(HaState::Active));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ha_notify() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("notify");
+        let state_fname = dir.path().join("state");
+        std::fs::write(
+            &fname,
+            format!("#!/bin/sh\necho $1 > {}\n", state_fname.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&fname, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut a = coordinator(0, B, 100, Some(fname.to_string_lossy().to_string()));
+        assert_eq!(a.process_timer(HA_TIMEOUT), Some(HaState::Active));
+        for _ in 0..100 {
+            if let Ok(state) = std::fs::read_to_string(&state_fname) {
+                if state == "active\n" {
+                    return;
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        panic!("notify command has not been called");
+    }
+}
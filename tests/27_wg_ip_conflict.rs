@@ -0,0 +1,235 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::net::{IpAddr, SocketAddr};
+
+    use wg_netmanager::alert::*;
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::event::Event;
+    use wg_netmanager::identity::*;
+    use wg_netmanager::manager::*;
+
+    fn get_test_config() -> StaticConfiguration {
+        common::static_peer_builder()
+            .my_public_key(common::public_key(10))
+            .build()
+    }
+
+    fn advertisement(
+        wg_ip: IpAddr,
+        key: &str,
+        created: u64,
+        identity: &NodeIdentity,
+    ) -> AdvertisementPacket {
+        let mut ad = AdvertisementPacket {
+            addressed_to: AddressedTo::StaticAddress,
+            public_key: PublicKeyWithTime::new(key.to_string(), created),
+            next_public_key: None,
+            local_wg_port: 50002,
+            local_admin_port: 50502,
+            wg_ip,
+            name: "peer".to_string(),
+            your_visible_wg_endpoint: None,
+            my_visible_wg_endpoint: None,
+            routedb_version: 0,
+            exported_nets: vec![],
+            extra_wg_ports: vec![],
+            force_relay_s: 0,
+            revocations: vec![],
+            moved_from: None,
+            identity: [0; 32],
+            signature: vec![],
+        };
+        ad.sign(identity);
+        ad
+    }
+
+    fn peer_info(mgr: &NetworkManager, wg_ip: IpAddr) -> PeerInfo {
+        mgr.peer_infos(0)
+            .into_iter()
+            .find(|p| p.wg_ip == wg_ip)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_bound_identity_is_kept() {
+        let peer_ip: IpAddr = "10.1.1.2".parse().unwrap();
+        let now = wg_netmanager::util::now();
+        let first_addr: SocketAddr = "192.168.1.2:50502".parse().unwrap();
+        let second_addr: SocketAddr = "192.168.1.3:50502".parse().unwrap();
+        let first = NodeIdentity::from_seed(&[2; 32]);
+        let second = NodeIdentity::from_seed(&[3; 32]);
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+
+        let ad = advertisement(peer_ip, "first", 20, &first);
+        mgr.analyze_advertisement(now, &static_config, ad, first_addr);
+        assert!(mgr.wg_ip_conflicts().is_empty());
+
+        // Another identity is ignored
+        let ad = advertisement(peer_ip, "second", 30, &second);
+        assert!(mgr
+            .analyze_advertisement(now, &static_config, ad, second_addr)
+            .is_empty());
+        let info = peer_info(&mgr, peer_ip);
+        assert_eq!(info.public_key, Some("first".to_string()));
+        assert_eq!(info.quarantined_key, Some("second".to_string()));
+        #[cfg(unix)]
+        assert!(wg_netmanager::control::status_table(&[info]).contains("(wg_ip conflict)"));
+
+        let mut alerts = AlertManager::new();
+        let raised = alerts.check(now, &mgr);
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].kind, AlertKind::WgIpConflict(peer_ip));
+        assert_eq!(raised[0].severity, Severity::Critical);
+
+        // A claimed older key does not take over, the bound identity is kept
+        let third = NodeIdentity::from_seed(&[4; 32]);
+        let ad = advertisement(peer_ip, "third", 0, &third);
+        assert!(mgr
+            .analyze_advertisement(now, &static_config, ad, second_addr)
+            .is_empty());
+        let info = peer_info(&mgr, peer_ip);
+        assert_eq!(info.public_key, Some("first".to_string()));
+        assert_eq!(info.quarantined_key, Some("third".to_string()));
+        let ad = advertisement(peer_ip, "first", 20, &first);
+        mgr.analyze_advertisement(now, &static_config, ad, first_addr);
+        assert_eq!(
+            peer_info(&mgr, peer_ip).public_key,
+            Some("first".to_string())
+        );
+
+        // until the quarantined node has been silent for long enough
+        mgr.process_timeouts(now + CONFLICT_QUARANTINE - 1);
+        assert_eq!(mgr.wg_ip_conflicts().len(), 1);
+        mgr.process_timeouts(now + CONFLICT_QUARANTINE);
+        assert!(mgr.wg_ip_conflicts().is_empty());
+        assert!(alerts.check(now + CONFLICT_QUARANTINE, &mgr).is_empty());
+        assert!(alerts.alerts().is_empty());
+    }
+
+    #[test]
+    fn test_own_wg_ip_conflict() {
+        let now = wg_netmanager::util::now();
+        let src_addr = "192.168.1.2:50502".parse().unwrap();
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+
+        // The own identity, e.g. of a warm-spare partner, is no conflict
+        let ad = advertisement(static_config.wg_ip, "partner", 20, &static_config.identity);
+        assert!(mgr
+            .analyze_advertisement(now, &static_config, ad, src_addr)
+            .is_empty());
+        assert!(mgr.wg_ip_conflicts().is_empty());
+
+        let other = NodeIdentity::from_seed(&[2; 32]);
+        let ad = advertisement(static_config.wg_ip, "other", 5, &other);
+        assert!(mgr
+            .analyze_advertisement(now, &static_config, ad, src_addr)
+            .is_empty());
+        assert!(!mgr.knows_peer(&static_config.wg_ip));
+        // Even with a claimed older key
+        let conflict = &mgr.wg_ip_conflicts()[&static_config.wg_ip];
+        assert_eq!(conflict.kept_key, "public");
+        assert_eq!(conflict.quarantined_key, "other");
+    }
+
+    #[test]
+    fn test_alternating_keys_of_clones() {
+        let peer_ip: IpAddr = "10.1.1.2".parse().unwrap();
+        let now = wg_netmanager::util::now();
+        let first_addr: SocketAddr = "192.168.1.2:50502".parse().unwrap();
+        let clone_addr: SocketAddr = "192.168.1.3:50502".parse().unwrap();
+        // A cloned peer.yaml shares the identity, but the keys are generated per node
+        let identity = NodeIdentity::from_seed(&[2; 32]);
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+
+        // A key rotation is no conflict
+        for (dt, key, created, src_addr) in [
+            (0, "first", 20, first_addr),
+            (1, "clone", 5, clone_addr),
+            (2, "first", 20, first_addr),
+        ] {
+            let ad = advertisement(peer_ip, key, created, &identity);
+            let events = mgr.analyze_advertisement(now + dt, &static_config, ad, src_addr);
+            assert!(!events
+                .iter()
+                .any(|e| matches!(e, Event::SendAddressConflict { .. })));
+        }
+        assert!(mgr.wg_ip_conflicts().is_empty());
+
+        // but the next switch is
+        let ad = advertisement(peer_ip, "clone", 5, &identity);
+        let events = mgr.analyze_advertisement(now + 3, &static_config, ad, clone_addr);
+        let expected = AddressConflictPacket {
+            wg_ip: peer_ip,
+            kept_key: "first".to_string(),
+            quarantined_key: "clone".to_string(),
+        };
+        let claimants = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::SendAddressConflict { to, conflict } => {
+                    assert_eq!(*conflict, expected);
+                    Some(*to)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(claimants, vec![first_addr, clone_addr]);
+        let conflict = &mgr.wg_ip_conflicts()[&peer_ip];
+        assert_eq!(conflict.kept_key, "first");
+        assert_eq!(conflict.quarantined_key, "clone");
+
+        // Frozen on the key seen first, though the clone claims an older one
+        let ad = advertisement(peer_ip, "clone", 5, &identity);
+        assert!(mgr
+            .analyze_advertisement(now + 4, &static_config, ad, clone_addr)
+            .is_empty());
+        let ad = advertisement(peer_ip, "first", 20, &identity);
+        mgr.analyze_advertisement(now + 5, &static_config, ad, first_addr);
+        assert_eq!(
+            peer_info(&mgr, peer_ip).public_key,
+            Some("first".to_string())
+        );
+        let mut alerts = AlertManager::new();
+        let raised = alerts.check(now + 5, &mgr);
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].kind, AlertKind::WgIpConflict(peer_ip));
+    }
+
+    #[test]
+    fn test_address_conflict_packet() {
+        let now = wg_netmanager::util::now();
+        let src_addr = "192.168.1.2:50502".parse().unwrap();
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+
+        // Not about this node
+        let packet = AddressConflictPacket {
+            wg_ip: static_config.wg_ip,
+            kept_key: "other".to_string(),
+            quarantined_key: "unknown".to_string(),
+        };
+        mgr.process_address_conflict(now, &static_config, packet, src_addr);
+        assert!(mgr.wg_ip_conflicts().is_empty());
+
+        let packet = AddressConflictPacket {
+            wg_ip: static_config.wg_ip,
+            kept_key: "other".to_string(),
+            quarantined_key: "public".to_string(),
+        };
+        mgr.process_address_conflict(now, &static_config, packet, src_addr);
+        let conflict = &mgr.wg_ip_conflicts()[&static_config.wg_ip];
+        assert_eq!(conflict.kept_key, "other");
+        assert_eq!(conflict.quarantined_key, "public");
+        assert_eq!(conflict.until, now + CONFLICT_QUARANTINE);
+        let mut alerts = AlertManager::new();
+        assert_eq!(alerts.check(now, &mgr).len(), 1);
+    }
+}
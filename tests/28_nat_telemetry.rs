@@ -0,0 +1,127 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use wg_netmanager::crypt_udp::AddressedTo;
+    use wg_netmanager::metrics::Metrics;
+    use wg_netmanager::nat_telemetry::*;
+    use wg_netmanager::node::{DistantNode, Node};
+    use wg_netmanager::routedb::RouteInfo;
+
+    fn progress(since: u64, rendezvous: u32, has_visible_endpoint: bool) -> TraversalProgress {
+        TraversalProgress {
+            since: Some(since),
+            attempts: 3,
+            rendezvous,
+            has_visible_endpoint,
+        }
+    }
+
+    #[test]
+    fn test_outcomes() {
+        let mut telemetry = NatTelemetry::default();
+        let v6 = AddressedTo::ReplyFromWireguardV6Address;
+        telemetry.success(110, common::ip("10.1.1.2"), progress(100, 1, true), &v6);
+        telemetry.success(120, common::ip("10.1.1.3"), progress(100, 0, true), &v6);
+        telemetry.success(
+            101,
+            common::ip("10.1.1.4"),
+            progress(100, 0, false),
+            &AddressedTo::LocalAddress,
+        );
+        // Not a traversal and no attempt of this node
+        telemetry.success(
+            101,
+            common::ip("10.1.1.5"),
+            progress(100, 0, false),
+            &AddressedTo::StaticAddress,
+        );
+        telemetry.success(
+            111,
+            common::ip("10.1.1.6"),
+            TraversalProgress::default(),
+            &v6,
+        );
+
+        telemetry.failure(700, common::ip("10.1.1.7"), progress(100, 0, false));
+        telemetry.failure(700, common::ip("10.1.1.8"), progress(100, 0, true));
+        telemetry.failure(700, common::ip("10.1.1.9"), progress(100, 2, true));
+        telemetry.failure(710, common::ip("10.1.1.9"), progress(100, 3, true));
+
+        let counts = telemetry
+            .counts()
+            .into_iter()
+            .map(|c| (c.candidate, c.outcome, c.count, c.duration_s))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            vec![
+                (Candidate::Local, Outcome::Success, 1, 1),
+                (Candidate::Local, Outcome::NoVisibleEndpoint, 1, 600),
+                (Candidate::Visible, Outcome::Success, 1, 20),
+                (Candidate::Visible, Outcome::NoRendezvous, 1, 600),
+                (Candidate::Rendezvous, Outcome::Success, 1, 10),
+                (Candidate::Rendezvous, Outcome::Timeout, 1, 600),
+            ]
+        );
+        let report = telemetry.report();
+        assert_eq!(report.recent.len(), 6);
+        assert_eq!(report.recent[0].wg_ip, common::ip("10.1.1.2"));
+        assert_eq!(report.recent[0].attempts, 3);
+
+        // A failed node may be recorded again, after it has not been distant
+        telemetry.retain_failed(|wg_ip| *wg_ip != common::ip("10.1.1.9"));
+        telemetry.failure(720, common::ip("10.1.1.9"), progress(100, 3, true));
+        for i in 0..RECENT_ATTEMPTS {
+            telemetry.success(
+                200,
+                common::ip("10.1.1.10"),
+                progress(i as u64, 1, true),
+                &v6,
+            );
+        }
+        let report = telemetry.report();
+        assert_eq!(report.recent.len(), RECENT_ATTEMPTS);
+        assert_eq!(report.recent[0].started, 0);
+        assert!(report.counts.contains(&TraversalCount {
+            candidate: Candidate::Rendezvous,
+            outcome: Outcome::Timeout,
+            count: 2,
+            duration_s: 1220,
+        }));
+
+        let metrics = Metrics {
+            nat_traversals: report.counts,
+            ..Default::default()
+        };
+        let page = metrics.to_prometheus();
+        assert!(page.contains(
+            "wg_netmanager_nat_traversals_total{candidate=\"rendezvous\",outcome=\"timeout\"} 2\n"
+        ));
+        assert!(page.contains(
+            "wg_netmanager_nat_traversal_seconds_total{candidate=\"local\",outcome=\"success\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn test_distant_node_progress() {
+        let mut node = DistantNode::from(&RouteInfo {
+            to: common::ip("10.1.1.50"),
+            local_admin_port: 50001,
+            hop_cnt: 1,
+            gateway: Some(common::ip("10.1.1.2")),
+            area_summary: None,
+            exported_nets: vec![],
+            cost: 0,
+            bandwidth_mbit: None,
+        });
+        assert_eq!(node.traversal(), Some(TraversalProgress::default()));
+        node.process_connect_request("192.0.2.1:50000".parse().unwrap(), 100);
+        let progress = node.traversal().unwrap();
+        assert_eq!(progress.rendezvous, 1);
+        assert!(progress.has_visible_endpoint);
+        assert_eq!(progress.since, None);
+    }
+}
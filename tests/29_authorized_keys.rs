@@ -0,0 +1,140 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::net::SocketAddr;
+
+    use wg_netmanager::authorized_keys::*;
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::event::Event;
+    use wg_netmanager::identity::*;
+    use wg_netmanager::manager::*;
+
+    fn get_test_config(authorized_keys: &str) -> StaticConfiguration {
+        let mut peers = common::static_peers();
+        let static_peer = common::ip("10.1.1.3");
+        peers.insert(static_peer, common::static_peer(static_peer));
+        common::static_peer_builder()
+            .peers(peers)
+            .authorized_keys(Some(authorized_keys.to_string()))
+            .build()
+    }
+
+    fn advertisement(wg_ip: &str, name: &str, identity: &NodeIdentity) -> AdvertisementPacket {
+        let mut ad = AdvertisementPacket {
+            addressed_to: AddressedTo::StaticAddress,
+            public_key: PublicKeyWithTime::new(format!("key of {}", name), 1),
+            next_public_key: None,
+            local_wg_port: 50002,
+            local_admin_port: 50502,
+            wg_ip: wg_ip.parse().unwrap(),
+            name: name.to_string(),
+            your_visible_wg_endpoint: None,
+            my_visible_wg_endpoint: None,
+            routedb_version: 0,
+            exported_nets: vec![],
+            extra_wg_ports: vec![],
+            force_relay_s: 0,
+            revocations: vec![],
+            moved_from: None,
+            identity: [0; 32],
+            signature: vec![],
+        };
+        ad.sign(identity);
+        ad
+    }
+
+    #[test]
+    fn test_authorized_keys_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("authorized_keys");
+        let known = NodeIdentity::from_seed(&[2; 32]);
+        std::fs::write(
+            &fname,
+            format!(
+                "# approved nodes\n\n{} laptop # since monday\n",
+                base64::encode(known.public())
+            ),
+        )
+        .unwrap();
+        let mut approval = JoinApproval::new(fname.to_str().unwrap());
+        assert!(approval.is_authorized(&known.public()));
+        assert!(!approval.is_authorized(&[3; 32]));
+
+        let ad = advertisement("10.1.1.4", "phone", &NodeIdentity::from_seed(&[4; 32]));
+        let src_addr: SocketAddr = "192.168.1.4:50502".parse().unwrap();
+        approval.park(100, &ad, src_addr);
+        approval.park(110, &ad, src_addr);
+        let pending = approval.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].first_seen, pending[0].last_seen), (100, 110));
+        assert!(approval.approve("tablet").is_err());
+        let (approved, reply) = approval.approve("phone").unwrap();
+        assert!(matches!(reply, AddressedTo::ReplyFromStaticAddress));
+        assert_eq!(approved.endpoint, src_addr);
+        assert!(approval.pending().is_empty());
+
+        let approval = JoinApproval::new(fname.to_str().unwrap());
+        assert!(approval.is_authorized(&ad.identity));
+        assert!(std::fs::read_to_string(&fname)
+            .unwrap()
+            .ends_with(&format!("{} phone\n", base64::encode(ad.identity))));
+
+        // Pending nodes are forgotten after a while
+        let mut approval = JoinApproval::new(fname.to_str().unwrap());
+        let ad = advertisement("10.1.1.5", "tv", &NodeIdentity::from_seed(&[5; 32]));
+        approval.park(100, &ad, src_addr);
+        approval.expire(100 + PENDING_TIMEOUT - 1);
+        assert_eq!(approval.pending().len(), 1);
+        approval.expire(100 + PENDING_TIMEOUT);
+        assert!(approval.pending().is_empty());
+
+        std::fs::write(&fname, "no-identity laptop\n").unwrap();
+        assert!(approval.reload().is_err());
+    }
+
+    #[test]
+    fn test_join_approval() {
+        let now = wg_netmanager::util::now();
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("authorized_keys");
+        let static_config = get_test_config(fname.to_str().unwrap());
+        let mut mgr = NetworkManager::new(&static_config);
+        let src_addr: SocketAddr = "192.168.1.2:50502".parse().unwrap();
+        let identity = NodeIdentity::from_seed(&[2; 32]);
+
+        let ad = advertisement("10.1.1.2", "laptop", &identity);
+        assert!(mgr
+            .analyze_advertisement(now, &static_config, ad, src_addr)
+            .is_empty());
+        assert!(!mgr.knows_peer(&"10.1.1.2".parse().unwrap()));
+        let pending = mgr.pending_peers();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].identity, base64::encode(identity.public()));
+
+        // Static peers need no approval
+        let ad = advertisement("10.1.1.3", "hub", &NodeIdentity::from_seed(&[3; 32]));
+        mgr.analyze_advertisement(now, &static_config, ad, src_addr);
+        let hub = mgr
+            .peer_infos(now)
+            .into_iter()
+            .find(|p| p.wg_ip.to_string() == "10.1.1.3")
+            .unwrap();
+        assert_eq!(hub.public_key, Some("key of hub".to_string()));
+
+        let (approved, events) = mgr.approve_peer("10.1.1.2").unwrap();
+        assert_eq!(approved.name, "laptop");
+        assert!(matches!(
+            events.as_slice(),
+            [Event::SendAdvertisement { to, .. }] if *to == src_addr
+        ));
+        assert!(mgr.pending_peers().is_empty());
+        let ad = advertisement("10.1.1.2", "laptop", &identity);
+        mgr.analyze_advertisement(now, &static_config, ad, src_addr);
+        assert!(mgr.knows_peer(&"10.1.1.2".parse().unwrap()));
+        assert_eq!(mgr.reload_authorized_keys().unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,112 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::identity::*;
+    use wg_netmanager::manager::*;
+    use wg_netmanager::port_validation::*;
+
+    fn get_test_config() -> StaticConfiguration {
+        let mut peer = common::static_peer(common::ip("10.1.1.2"));
+        peer.endpoint = "192.168.1.2:50002".to_string();
+        peer.wg_port = 50002;
+        peer.admin_port = 50502;
+        let mut peers = common::static_peers();
+        peers.insert(peer.wg_ip, peer);
+        common::static_peer_builder().peers(peers).build()
+    }
+
+    fn advertisement() -> AdvertisementPacket {
+        let mut ad = AdvertisementPacket {
+            addressed_to: AddressedTo::ReplyFromStaticAddress,
+            public_key: PublicKeyWithTime::new("peer", 1),
+            next_public_key: None,
+            local_wg_port: 50002,
+            local_admin_port: 50502,
+            wg_ip: "10.1.1.2".parse().unwrap(),
+            name: "peer".to_string(),
+            your_visible_wg_endpoint: None,
+            my_visible_wg_endpoint: None,
+            routedb_version: 0,
+            exported_nets: vec![],
+            extra_wg_ports: vec![],
+            force_relay_s: 0,
+            revocations: vec![],
+            moved_from: None,
+            identity: [0; 32],
+            signature: vec![],
+        };
+        ad.sign(&NodeIdentity::from_seed(&[2; 32]));
+        ad
+    }
+
+    #[test]
+    fn test_check() {
+        let endpoint: SocketAddr = "192.168.1.2:50502".parse().unwrap();
+        let mut validation = PortValidation::default();
+        assert_eq!(
+            validation.check(&endpoint, 50502),
+            Err(Refusal::AdvertisedAdminPort)
+        );
+        assert_eq!(validation.check(&endpoint, 50503), Ok(()));
+
+        // A handshake confirms the endpoint, until it is seen as admin endpoint again
+        validation.confirmed_wireguard(100, endpoint);
+        assert_eq!(validation.check(&endpoint, 50502), Ok(()));
+        validation.observed_admin(110, endpoint);
+        assert_eq!(
+            validation.check(&endpoint, 50503),
+            Err(Refusal::ObservedAdminEndpoint)
+        );
+        validation.expire(110 + OBSERVATION_TIMEOUT - 1);
+        assert!(validation.check(&endpoint, 50503).is_err());
+        validation.expire(110 + OBSERVATION_TIMEOUT);
+        assert_eq!(validation.check(&endpoint, 50503), Ok(()));
+
+        assert!(UdpPacket::Advertisement(advertisement()).validate().is_ok());
+        let mut ad = advertisement();
+        ad.extra_wg_ports = vec![50502];
+        assert!(UdpPacket::Advertisement(ad).validate().is_err());
+        let mut ad = advertisement();
+        ad.local_wg_port = 50502;
+        assert!(UdpPacket::Advertisement(ad).validate().is_err());
+    }
+
+    #[test]
+    fn test_refused_endpoint() {
+        let now = wg_netmanager::util::now();
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+        let endpoint: SocketAddr = "192.168.1.2:50002".parse().unwrap();
+        mgr.analyze_advertisement(
+            now,
+            &static_config,
+            advertisement(),
+            "192.168.1.2:50502".parse().unwrap(),
+        );
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("EndPoint = 192.168.1.2:50002"));
+
+        // Admin packets from the wg port of network.yaml reveal a mix-up
+        mgr.decrypt_success(now, endpoint);
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("PublicKey = peer"));
+        assert!(!conf.contains("EndPoint"));
+
+        // until wireguard has a handshake with the endpoint
+        mgr.current_wireguard_configuration(HashMap::from([("peer".to_string(), endpoint)]));
+        mgr.check_handshakes(
+            now + 10,
+            &static_config,
+            &HashMap::from([("peer".to_string(), now + 5)]),
+        );
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("EndPoint = 192.168.1.2:50002"));
+    }
+}
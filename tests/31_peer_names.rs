@@ -0,0 +1,66 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::identity::*;
+    use wg_netmanager::manager::*;
+    use wg_netmanager::peer_names::*;
+
+    fn get_test_config() -> StaticConfiguration {
+        common::static_peer_builder().name("hub").build()
+    }
+
+    #[test]
+    fn test_peer_names_file() {
+        let now = wg_netmanager::util::now();
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+        let mut ad = AdvertisementPacket {
+            addressed_to: AddressedTo::StaticAddress,
+            public_key: PublicKeyWithTime::new("key of laptop", 1),
+            next_public_key: None,
+            local_wg_port: 50002,
+            local_admin_port: 50502,
+            wg_ip: "10.1.1.2".parse().unwrap(),
+            name: "laptop".to_string(),
+            your_visible_wg_endpoint: None,
+            my_visible_wg_endpoint: None,
+            routedb_version: 0,
+            exported_nets: vec![],
+            extra_wg_ports: vec![],
+            force_relay_s: 0,
+            revocations: vec![],
+            moved_from: None,
+            identity: [0; 32],
+            signature: vec![],
+        };
+        ad.sign(&NodeIdentity::from_seed(&[2; 32]));
+        mgr.analyze_advertisement(
+            now,
+            &static_config,
+            ad,
+            "192.168.1.2:50502".parse().unwrap(),
+        );
+
+        let names = PeerNames::of(&mgr, &static_config);
+        assert_eq!(names.public_key, "public");
+        assert_eq!(names.peers.len(), 1);
+        let laptop = &names.peers["key of laptop"];
+        assert_eq!(laptop.name, Some("laptop".to_string()));
+        assert_eq!(laptop.wg_ip.to_string(), "10.1.1.2");
+
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("names.json");
+        let mut file = PeerNamesFile::new(fname.to_str().unwrap());
+        assert!(file.update(names.clone()).unwrap());
+        assert!(!file.update(names.clone()).unwrap());
+        let content = std::fs::read_to_string(&fname).unwrap();
+        assert_eq!(serde_json::from_str::<PeerNames>(&content).unwrap(), names);
+        file.remove();
+        assert!(!fname.exists());
+    }
+}
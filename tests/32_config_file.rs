@@ -0,0 +1,194 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use wg_netmanager::config_file::*;
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::endpoint_resolver::EndpointResolver;
+
+    const NETWORK_YAML: &str = "network:
+  sharedKey: AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+  subnet: 10.1.0.0/16
+  areas:
+    1: 10.1.1.0/24
+  persistentKeepalive: 15
+  colour: blue
+peers:
+  - endPoint: 192.168.1.70:50000
+    adminPort: 50500
+    wgIp: 10.1.0.1
+    resolver:
+      hostsFile: /etc/wg_netmanager/endpoints
+  - endPoint: hub.example.org:50001
+    adminPort: 50501
+    wgIp: 10.1.0.2
+    tier: 0
+    location: basement
+";
+
+    const NETWORK_TOML: &str = r#"
+[network]
+sharedKey = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+subnet = "10.1.0.0/16"
+areas = { "1" = "10.1.1.0/24" }
+persistentKeepalive = 15
+colour = "blue"
+
+[[peers]]
+endPoint = "192.168.1.70:50000"
+adminPort = 50500
+wgIp = "10.1.0.1"
+resolver = { hostsFile = "/etc/wg_netmanager/endpoints" }
+
+[[peers]]
+endPoint = "hub.example.org:50001"
+adminPort = 50501
+wgIp = "10.1.0.2"
+tier = 0
+location = "basement"
+"#;
+
+    #[test]
+    fn test_yaml_and_toml() {
+        let yaml: NetworkConfig = parse(NETWORK_YAML, ConfigFormat::Yaml).unwrap();
+        let toml: NetworkConfig = parse(NETWORK_TOML, ConfigFormat::Toml).unwrap();
+        assert_eq!(yaml, toml);
+        assert_eq!(
+            yaml.unknown_keys(),
+            vec![
+                "network.colour".to_string(),
+                "peers[1].location".to_string()
+            ]
+        );
+
+        let network = NetworkConfiguration::from_config(toml).unwrap();
+        assert_eq!(network.persistent_keepalive, Some(15));
+        assert_eq!(network.areas[&1], "10.1.1.0/24".parse().unwrap());
+        let hub = &network.peers[&common::ip("10.1.0.1")];
+        assert_eq!(
+            (hub.wg_port, hub.admin_port, hub.tier),
+            (50000, 50500, DEFAULT_TIER)
+        );
+        assert_eq!(
+            hub.resolver,
+            EndpointResolver::HostsFile("/etc/wg_netmanager/endpoints".to_string())
+        );
+        assert_eq!(network.peers[&common::ip("10.1.0.2")].tier, 0);
+
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("network.toml");
+        std::fs::write(&fname, NETWORK_TOML).unwrap();
+        let network = load_network_config(fname.to_str().unwrap()).unwrap();
+        assert_eq!(network.peers.len(), 2);
+        let missing = dir.path().join("network.yaml");
+        assert!(load_network_config(missing.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |content: &str, format| {
+            parse::<NetworkConfig>(content, format)
+                .unwrap_err()
+                .to_string()
+        };
+        let yaml = NETWORK_YAML.replace("adminPort: 50501", "adminPort: high");
+        let e = error(&yaml, ConfigFormat::Yaml);
+        assert!(e.starts_with("peers[1].adminPort: invalid type"), "{}", e);
+        assert!(e.ends_with("at line 15 column 16"), "{}", e);
+        let toml = NETWORK_TOML.replace("adminPort = 50501", "adminPort = \"high\"");
+        let e = error(&toml, ConfigFormat::Toml);
+        assert!(e.starts_with("peers[1].adminPort: invalid type"), "{}", e);
+        assert!(!e.contains("at line"), "{}", e);
+        // Syntax errors of TOML have the location
+        assert!(error("[network", ConfigFormat::Toml).contains("line 1"));
+
+        let yaml = NETWORK_YAML.replace("wgIp: 10.1.0.2", "wgIp: 10.1.0.300");
+        assert!(error(&yaml, ConfigFormat::Yaml).starts_with("peers[1].wgIp"));
+        let yaml = NETWORK_YAML.replace("    adminPort: 50500\n", "");
+        assert!(error(&yaml, ConfigFormat::Yaml).contains("missing field `adminPort`"));
+    }
+
+    #[test]
+    fn test_peer_config() {
+        let conf = PeerConfig::default();
+        assert_eq!(
+            conf.decrypt_failure_threshold,
+            DEFAULT_DECRYPT_FAILURE_THRESHOLD
+        );
+        assert!(!conf.disable_ipv6);
+        assert!(conf.name.is_none());
+
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("peer.toml");
+        let fname = fname.to_str().unwrap();
+        assert_eq!(load_peer_config(fname).unwrap(), conf);
+        std::fs::write(
+            fname,
+            "name = \"laptop\"\nwgIp = \"auto\"\nexportedNets = [\"192.168.10.0/24\"]\n\
+             haPartner = \"192.168.1.2:50500\"\nwgInterfce = \"wg0\"\n\n\
+             [pinnedRoutes]\n\"10.1.1.50\" = \"10.1.1.1\"\n",
+        )
+        .unwrap();
+        let conf = load_peer_config(fname).unwrap();
+        assert_eq!(conf.name, Some("laptop".to_string()));
+        assert_eq!(conf.wg_ip, Some("auto".to_string()));
+        assert_eq!(conf.exported_nets, vec!["192.168.10.0/24".parse().unwrap()]);
+        assert_eq!(
+            conf.pinned_routes[&common::ip("10.1.1.50")],
+            common::ip("10.1.1.1")
+        );
+        assert_eq!(conf.ha_partner, Some("192.168.1.2:50500".parse().unwrap()));
+        assert_eq!(conf.unknown_keys(), vec!["wgInterfce".to_string()]);
+        assert!(conf.wg_interface.is_none());
+
+        std::fs::write(fname, "metricsPort = 70000\n").unwrap();
+        let e = load_peer_config(fname).unwrap_err().to_string();
+        assert!(e.starts_with(&format!("{}: metricsPort:", fname)), "{}", e);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_secrets() {
+        use std::os::unix::fs::PermissionsExt;
+
+        const KEY: &str = "AQAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        std::env::set_var("WG_NETMANAGER_TEST_KEY", KEY);
+        let yaml = NETWORK_YAML.replace(
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+            "${WG_NETMANAGER_TEST_KEY}",
+        );
+        let network = NetworkConfiguration::from_yaml_str(&yaml).unwrap();
+        assert_eq!(network.shared_key[0], 1);
+        let yaml = yaml.replace("WG_NETMANAGER_TEST_KEY", "WG_NETMANAGER_UNSET");
+        let e = NetworkConfiguration::from_yaml_str(&yaml).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "network.sharedKey: environment variable WG_NETMANAGER_UNSET is not set"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("shared_key");
+        std::fs::write(&secret, format!("{}\n", KEY)).unwrap();
+        std::fs::set_permissions(&secret, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let yaml = NETWORK_YAML.replace(
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+            &format!("file:{}", secret.display()),
+        );
+        let network = NetworkConfiguration::from_yaml_str(&yaml).unwrap();
+        assert_eq!(network.shared_key[0], 1);
+        std::fs::set_permissions(&secret, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let e = NetworkConfiguration::from_yaml_str(&yaml).unwrap_err();
+        assert!(e.to_string().contains("is accessible by others"), "{}", e);
+
+        let fname = dir.path().join("peer.yaml");
+        std::fs::write(
+            &fname,
+            "webhooks:\n  - ${WG_NETMANAGER_TEST_KEY}\n  - https://example.org/hook\n",
+        )
+        .unwrap();
+        let conf = load_peer_config(fname.to_str().unwrap()).unwrap();
+        assert_eq!(conf.webhooks, vec![KEY, "https://example.org/hook"]);
+    }
+}
@@ -0,0 +1,23 @@
+#[cfg(test)]
+mod tests {
+    use wg_netmanager::log_sampling::*;
+
+    #[test]
+    fn test_log_sampler() {
+        assert_eq!(every(), DEFAULT_LOG_SAMPLE);
+        set_every(3);
+        let sampler = LogSampler::new();
+        let logged = (0..7).filter_map(|_| sampler.sample()).collect::<Vec<_>>();
+        assert_eq!(logged, vec![1, 4, 7]);
+
+        // Each call site counts on its own
+        let other = LogSampler::new();
+        assert_eq!(other.sample(), Some(1));
+
+        // 0 logs every occurrence like 1
+        set_every(0);
+        assert_eq!(every(), 1);
+        assert_eq!(sampler.sample(), Some(8));
+        assert_eq!(sampler.sample(), Some(9));
+    }
+}
@@ -0,0 +1,94 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use ipnet::IpNet;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::wg_dev::{nat_probe_address, nat_probe_net};
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_host_addresses() {
+        let subnet = net("10.1.1.0/24");
+        assert!(is_host_address(&subnet, &common::ip("10.1.1.1")));
+        assert!(is_host_address(&subnet, &common::ip("10.1.1.254")));
+        let e = check_wg_ip(&subnet, &common::ip("10.1.1.0")).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "10.1.1.0 is the network address of subnet 10.1.1.0/24"
+        );
+        let e = check_wg_ip(&subnet, &common::ip("10.1.1.255")).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "10.1.1.255 is the broadcast address of subnet 10.1.1.0/24"
+        );
+        assert!(!is_host_address(&subnet, &common::ip("10.1.2.1")));
+        assert!(!is_host_address(&subnet, &common::ip("fd42::1")));
+
+        // Point-to-point subnets have no network and broadcast address
+        for wg_ip in ["10.1.1.2", "10.1.1.3"] {
+            assert!(is_host_address(&net("10.1.1.2/31"), &common::ip(wg_ip)));
+        }
+        assert!(!is_host_address(
+            &net("10.1.1.2/31"),
+            &common::ip("10.1.1.4")
+        ));
+        assert!(is_host_address(
+            &net("10.1.1.7/32"),
+            &common::ip("10.1.1.7")
+        ));
+
+        // ipv6 has no broadcast, but the subnet-router anycast address
+        let subnet = net("fd42::/64");
+        assert!(!is_host_address(&subnet, &common::ip("fd42::")));
+        assert!(is_host_address(
+            &subnet,
+            &common::ip("fd42::ffff:ffff:ffff:ffff")
+        ));
+        for wg_ip in ["fd42::", "fd42::1"] {
+            assert!(is_host_address(&net("fd42::/127"), &common::ip(wg_ip)));
+        }
+    }
+
+    #[test]
+    fn test_nat_probe_edges() {
+        assert_eq!(
+            nat_probe_address(&common::ip("10.1.1.255")),
+            Some("fd00::ffff:a01:1ff".parse().unwrap())
+        );
+        assert_eq!(nat_probe_address(&common::ip("fd42::1")), None);
+        let probe_net = nat_probe_net(&common::ip("10.1.1.1"), &net("10.1.1.0/24")).unwrap();
+        assert_eq!(probe_net.prefix_len(), 120);
+        for edge in ["10.1.1.0", "10.1.1.255"] {
+            assert!(probe_net.contains(&nat_probe_address(&common::ip(edge)).unwrap()));
+        }
+        assert!(!probe_net.contains(&nat_probe_address(&common::ip("10.1.2.0")).unwrap()));
+        let probe_net = nat_probe_net(&common::ip("10.1.1.7"), &net("10.1.1.7/32")).unwrap();
+        assert_eq!(probe_net.prefix_len(), 128);
+    }
+
+    #[test]
+    fn test_static_peer_at_network_address() {
+        let yaml = "network:
+  sharedKey: AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+  subnet: 10.1.1.0/24
+peers:
+  - endPoint: 192.168.1.70:50000
+    adminPort: 50500
+    wgIp: 10.1.1.0
+";
+        let e = NetworkConfiguration::from_yaml_str(yaml).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "peer 10.1.1.0: 10.1.1.0 is the network address of subnet 10.1.1.0/24"
+        );
+        let yaml = yaml.replace("10.1.1.0/24", "10.1.1.0/31");
+        assert!(NetworkConfiguration::from_yaml_str(&yaml).is_ok());
+    }
+}
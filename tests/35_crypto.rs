@@ -0,0 +1,31 @@
+#[cfg(test)]
+mod tests {
+    use wg_netmanager::crypto::*;
+
+    #[test]
+    fn test_public_key_of() {
+        // RFC 7748, section 6.1
+        let private_key = "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=";
+        assert_eq!(
+            public_key_of(private_key).unwrap(),
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo="
+        );
+        assert_eq!(
+            public_key_of(&format!("{}\n", private_key)).unwrap(),
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo="
+        );
+        assert!(public_key_of("AAAA").is_err());
+        assert!(public_key_of("no base64").is_err());
+    }
+
+    #[test]
+    fn test_generate_key_pair() {
+        let (private_key, public_key) = generate_key_pair();
+        let raw_priv_key = base64::decode(&private_key).unwrap();
+        assert_eq!(raw_priv_key.len(), 32);
+        assert_eq!(raw_priv_key[0] & 7, 0);
+        assert_eq!(raw_priv_key[31] & 0xc0, 0x40);
+        assert_eq!(public_key_of(&private_key).unwrap(), public_key);
+        assert_ne!(generate_key_pair().0, private_key);
+    }
+}
@@ -0,0 +1,32 @@
+#[cfg(test)]
+mod tests {
+    use wg_netmanager::util::*;
+
+    // One test only, because the step of the wall clock is global
+    #[test]
+    fn test_wall_clock_steps() {
+        let start = now();
+        assert!(wall_now().abs_diff(start) <= 1);
+        assert_eq!(from_wall_time(1_000_000), 1_000_000);
+        assert_eq!(to_wall_time(1_000_000), 1_000_000);
+
+        // NTP steps the wall clock one hour ahead
+        step_wall_clock(3600);
+        assert!(wall_now() >= start + 3600);
+        assert!(now() - start <= 1);
+        assert!(from_wall_time(wall_now()).abs_diff(now()) <= 1);
+        assert!(to_wall_time(now()).abs_diff(wall_now()) <= 1);
+        // The age of a handshake after the step is kept
+        let handshake = wall_now() - 100;
+        assert!((now() - from_wall_time(handshake)).abs_diff(100) <= 1);
+        assert_eq!(from_wall_time(0), 0);
+
+        // And two hours back
+        step_wall_clock(-7200);
+        assert!(wall_now() + 3600 <= start + 1);
+        assert!(now() >= start);
+        assert!(from_wall_time(wall_now()).abs_diff(now()) <= 1);
+        let handshake = wall_now() - 100;
+        assert!((now() - from_wall_time(handshake)).abs_diff(100) <= 1);
+    }
+}
@@ -0,0 +1,124 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::manager::*;
+    use wg_netmanager::node::{ConnectionType, VisibleEndpoints};
+    use wg_netmanager::routedb::RouteInfo;
+    use wg_netmanager::state_file::*;
+    use wg_netmanager::state_snapshot::*;
+
+    // A static peer with a distant node behind it and a dynamic peer
+    fn snapshot(static_config: &StaticConfiguration) -> StateSnapshot {
+        let route = RouteInfo {
+            to: "10.1.1.9".parse().unwrap(),
+            local_admin_port: 50509,
+            hop_cnt: 0,
+            gateway: None,
+            area_summary: None,
+            exported_nets: vec![],
+            cost: 0,
+            bandwidth_mbit: None,
+        };
+        let mut snapshot = StateSnapshot::new(static_config);
+        snapshot.identity_seed = None;
+        snapshot.my_local_wg_port = 50100;
+        snapshot.routedb = RouteDBState::new(7, vec![]);
+        snapshot.nodes = vec![
+            NodeState::Static {
+                wg_ip: "10.1.1.1".parse().unwrap(),
+                public_key: Some(PublicKeyWithTime::new("static", 2)),
+                is_alive: true,
+                lastseen: 100,
+                current_ip: Some("127.0.0.1".parse().unwrap()),
+                routedb: Some(RouteDBState::new(3, vec![route])),
+            },
+            NodeState::Dynamic {
+                wg_ip: "10.1.1.3".parse().unwrap(),
+                name: "dynamic".to_string(),
+                public_key: PublicKeyWithTime::new("dynamic", 3),
+                local_wg_port: 50003,
+                local_admin_port: 50503,
+                admin_port: 50503,
+                connection: ConnectionType::Dynamic {
+                    endpoint: Some("192.0.2.3:50003".parse().unwrap()),
+                },
+                local_reachable_wg_endpoint: None,
+                local_reachable_admin_endpoint: None,
+                visible_wg_endpoints: VisibleEndpoints::default(),
+                lastseen: 100,
+                routedb: None,
+            },
+        ];
+        snapshot
+    }
+
+    #[test]
+    fn test_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wgtest.state");
+        let static_config = common::static_config();
+        let mut mgr = NetworkManager::new(&static_config);
+        mgr.restore_state(100, &snapshot(&static_config)).unwrap();
+
+        let mut state_file = StateFile::new(&path, &static_config.shared_key);
+        assert!(state_file.load().is_none());
+        assert!(state_file
+            .save_on_change(1000, &mgr, &static_config)
+            .unwrap());
+        // Not again within the interval and not without a change
+        assert!(!state_file
+            .save_on_change(1001, &mgr, &static_config)
+            .unwrap());
+        assert!(!state_file
+            .save_on_change(1000 + SAVE_INTERVAL, &mgr, &static_config)
+            .unwrap());
+
+        // Encrypted and without the secrets
+        let saved = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&saved).contains("dynamic"));
+        let loaded = StateFile::new(&path, &static_config.shared_key)
+            .load()
+            .unwrap();
+        assert_eq!(loaded.nodes.len(), 2);
+        assert!(loaded.my_private_key.is_empty());
+        assert!(StateFile::new(&path, &[1; 32]).load().is_none());
+    }
+
+    #[test]
+    fn test_restore_hints() {
+        let static_config = common::static_config();
+        let snapshot = snapshot(&static_config);
+
+        let mut mgr = NetworkManager::new(&static_config);
+        mgr.restore_hints(2000, &snapshot).unwrap();
+        assert!(mgr.knows_peer(&"10.1.1.3".parse().unwrap()));
+        let restored = mgr.state_snapshot(&static_config);
+        // The own route database is not taken over
+        assert_ne!(restored.routedb.version, 7);
+        assert!(restored.routedb.routes.is_empty());
+        for state in restored.nodes.iter() {
+            match state {
+                NodeState::Static { is_alive, .. } => assert!(!is_alive),
+                NodeState::Dynamic { lastseen, .. } => assert_eq!(*lastseen, 2000),
+                _ => unreachable!(),
+            }
+        }
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("PublicKey = static"));
+        assert!(conf.contains("PublicKey = dynamic"));
+
+        // The distant node is recreated from the static peer's route database
+        mgr.get_route_changes();
+        assert!(mgr.knows_peer(&"10.1.1.9".parse().unwrap()));
+
+        let mut other = snapshot.clone();
+        other.wg_ip = "10.1.1.4".parse().unwrap();
+        assert!(NetworkManager::new(&static_config)
+            .restore_hints(2000, &other)
+            .is_err());
+    }
+}
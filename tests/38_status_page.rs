@@ -0,0 +1,62 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use wg_netmanager::manager::*;
+    use wg_netmanager::status_file::*;
+    use wg_netmanager::status_page::*;
+
+    fn fetch(page: &StatusPage, request_line: &str) -> String {
+        let mut stream = TcpStream::connect(page.local_addr()).unwrap();
+        write!(stream, "{}\r\nHost: localhost\r\n\r\n", request_line).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_status_page() {
+        let static_config = common::builder()
+            .name("<hub>")
+            .peers(common::static_peers())
+            .status_page_port(Some(0))
+            .build();
+        let mgr = NetworkManager::new(&static_config);
+
+        let html = render(&Status::of(100, &mgr, &static_config));
+        assert!(html.starts_with("<!DOCTYPE html>\n"));
+        assert!(html.contains("<title>&lt;hub&gt; (10.1.1.2)</title>"));
+        assert!(!html.contains("<hub>"));
+        assert!(html.contains("not connected: 0 of 1 static peers"));
+        assert!(html.contains("<td>10.1.1.1</td><td>static</td>"));
+        assert!(html.contains("<h2>Routes</h2>"));
+
+        let mut page = StatusPage::listen("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        assert!(page.update(100, &mgr, &static_config));
+        assert!(!page.update(101, &mgr, &static_config));
+        let response = fetch(&page, "GET / HTTP/1.1");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.ends_with(&html));
+        assert!(fetch(&page, "GET /other HTTP/1.1").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        // Read-only
+        assert!(fetch(&page, "POST / HTTP/1.1").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let mut page =
+            StatusPage::listen("127.0.0.1:0".parse().unwrap(), Some("secret".to_string())).unwrap();
+        assert!(page.update(100, &mgr, &static_config));
+        assert!(fetch(&page, "GET / HTTP/1.1").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(
+            fetch(&page, "GET / HTTP/1.1\r\nAuthorization: Bearer wrong")
+                .starts_with("HTTP/1.1 401 Unauthorized\r\n")
+        );
+        let response = fetch(&page, "GET / HTTP/1.1\r\nauthorization: bearer secret");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&html));
+    }
+}
@@ -0,0 +1,85 @@
+mod common;
+
+#[cfg(target_os = "linux")]
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    use wg_netmanager::systemd::*;
+
+    fn recv(socket: &UnixDatagram) -> String {
+        let mut buf = [0u8; 64];
+        let n = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_nonblocking(true).unwrap();
+
+        let mut notifier = Notifier::new(path.to_str().unwrap(), Some(30_000_000)).unwrap();
+        assert_eq!(notifier.watchdog_interval(), Some(15));
+        notifier.ready();
+        assert_eq!(recv(&socket), "READY=1");
+        assert!(notifier.ping_watchdog(100));
+        assert_eq!(recv(&socket), "WATCHDOG=1");
+        assert!(!notifier.ping_watchdog(114));
+        assert!(socket.recv(&mut [0u8; 64]).is_err());
+        assert!(notifier.ping_watchdog(115));
+        assert_eq!(recv(&socket), "WATCHDOG=1");
+        notifier.stopping();
+        assert_eq!(recv(&socket), "STOPPING=1");
+
+        // No watchdog configured
+        let mut notifier = Notifier::new(path.to_str().unwrap(), None).unwrap();
+        assert!(!notifier.ping_watchdog(100));
+
+        // Abstract socket
+        let name = format!("wg_netmanager_test_{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(&name).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        Notifier::new(&format!("@{}", name), None).unwrap().ready();
+        assert_eq!(recv(&socket), "READY=1");
+    }
+
+    #[test]
+    fn test_unit_file() {
+        let static_config = common::builder()
+            .network_yaml_filename("/etc/wg_netmanager/network.yaml")
+            .peer_yaml_filename("/etc/wg_netmanager/peer.yaml")
+            .build();
+        let content = unit_file(&static_config, "/usr/bin/wg_netmanager", None);
+        assert!(content.contains("\nType=notify\n"));
+        assert!(content.contains(&format!("\nWatchdogSec={}\n", WATCHDOG_SEC)));
+        assert!(content.contains(
+            "\nExecStart=/usr/bin/wg_netmanager -c /etc/wg_netmanager/network.yaml -p /etc/wg_netmanager/peer.yaml\n"
+        ));
+        assert!(!content.contains("ExecStop"));
+        assert!(
+            unit_file(&static_config, "/usr/bin/wg_netmanager", Some("/bin/kill"))
+                .contains("\nExecStop=/bin/kill -HUP $MAINPID\n")
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wg_netmanager.service");
+        assert!(!install_unit_file(&path, &content, false, true).unwrap());
+        assert!(!path.exists());
+        assert!(install_unit_file(&path, &content, false, false).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+        assert!(!install_unit_file(&path, &content, false, false).unwrap());
+
+        // Another unit file is only replaced with --force
+        let other = content.replace("RestartSec=1", "RestartSec=5");
+        assert!(install_unit_file(&path, &other, false, false).is_err());
+        assert!(!install_unit_file(&path, &other, true, true).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+        assert!(install_unit_file(&path, &other, true, false).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), other);
+    }
+}
@@ -0,0 +1,118 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::collections::{BTreeMap, HashMap};
+    use std::net::IpAddr;
+
+    use wg_netmanager::configuration::RouteMetric;
+    use wg_netmanager::route_engine::*;
+    use wg_netmanager::routedb::RouteInfo;
+
+    fn route(to: &str, hop_cnt: usize, gateway: Option<&str>) -> (IpAddr, RouteInfo) {
+        let ri = RouteInfo {
+            to: common::ip(to),
+            local_admin_port: 0,
+            hop_cnt,
+            gateway: gateway.map(common::ip),
+            area_summary: None,
+            exported_nets: vec![
+                "10.1.1.0/24".parse().unwrap(),
+                "192.168.5.0/24".parse().unwrap(),
+            ],
+            cost: 5,
+            bandwidth_mbit: Some(100),
+        };
+        (ri.to, ri)
+    }
+
+    fn context(areas: &BTreeMap<u16, ipnet::IpNet>) -> RouteContext<'_> {
+        RouteContext {
+            wg_ip: common::ip("10.1.1.1"),
+            metric: RouteMetric::Hops,
+            my_area: None,
+            use_areas: false,
+            areas,
+            subnet: "10.1.1.0/24".parse().unwrap(),
+            my_exported_nets: &[],
+            distant_nodes: [common::ip("10.1.1.50")].into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn test_candidates() {
+        let areas = BTreeMap::new();
+        let ctx = context(&areas);
+        let route_for = [
+            route("10.1.1.50", 0, None),
+            route("10.1.1.60", 1, Some("10.1.1.50")),
+            // Not to myself, not via myself and not via a direct peer
+            route("10.1.1.1", 0, None),
+            route("10.1.1.61", 1, Some("10.1.1.1")),
+            route("10.1.1.62", 1, Some("10.1.1.3")),
+        ]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+        let peer = PeerRoutes {
+            wg_ip: common::ip("10.1.1.2"),
+            link_cost: 10,
+            link_bandwidth: Some(50),
+            tier: 1,
+            route_for: &route_for,
+        };
+        let mut routes = candidates(&ctx, &peer)
+            .into_iter()
+            .map(|candidate| match candidate {
+                Candidate::Route { route, .. } => route,
+                Candidate::Area { .. } => panic!("no areas"),
+            })
+            .collect::<Vec<_>>();
+        routes.sort_by_key(|ri| ri.to);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].to, common::ip("10.1.1.50"));
+        assert_eq!(routes[0].hop_cnt, 1);
+        assert_eq!(routes[1].hop_cnt, 2);
+        for ri in routes.iter() {
+            assert_eq!(ri.gateway, Some(common::ip("10.1.1.2")));
+            assert_eq!(ri.cost, 15);
+            assert_eq!(ri.bandwidth_mbit, Some(50));
+            // Networks within the subnet are not acceptable
+            assert_eq!(ri.exported_nets, vec!["192.168.5.0/24".parse().unwrap()]);
+        }
+    }
+
+    #[test]
+    fn test_parallel_is_deterministic() {
+        let areas = BTreeMap::new();
+        let ctx = context(&areas);
+        let dbs = (0..8)
+            .map(|peer| {
+                (0..500)
+                    .map(|n| {
+                        let to = format!("10.2.{}.{}", n / 250, n % 250 + 1);
+                        let gateway = (n % 3 == 0).then_some("10.1.1.50");
+                        route(&to, (n + peer) % 4, gateway)
+                    })
+                    .collect::<HashMap<_, _>>()
+            })
+            .collect::<Vec<_>>();
+        let peers = dbs
+            .iter()
+            .enumerate()
+            .map(|(i, route_for)| PeerRoutes {
+                wg_ip: common::ip(&format!("10.1.1.{}", i + 2)),
+                link_cost: i as u32,
+                link_bandwidth: None,
+                tier: 1,
+                route_for,
+            })
+            .collect::<Vec<_>>();
+        assert!(use_parallel(&peers));
+        assert!(!use_parallel(&peers[..1]));
+        let sequential = all_candidates(&ctx, &peers, false);
+        assert_eq!(sequential.len(), 8);
+        assert!(sequential.iter().all(|candidates| candidates.len() == 500));
+        assert_eq!(sequential, all_candidates(&ctx, &peers, true));
+    }
+}
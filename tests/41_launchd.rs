@@ -0,0 +1,56 @@
+mod common;
+
+#[cfg(unix)]
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use wg_netmanager::launchd::*;
+
+    #[test]
+    fn test_plist() {
+        let static_config = common::builder()
+            .network_yaml_filename("/etc/wg_netmanager/network.yaml")
+            .peer_yaml_filename("/etc/wg_netmanager/peer & co.yaml")
+            .build();
+        let content = plist(&static_config, "/usr/local/bin/wg_netmanager");
+        assert!(content.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n"));
+        assert!(content.contains(&format!("\t<string>{}</string>\n", LABEL)));
+        assert!(content.contains(
+            "\t<array>\n\
+             \t\t<string>/usr/local/bin/wg_netmanager</string>\n\
+             \t\t<string>-c</string>\n\
+             \t\t<string>/etc/wg_netmanager/network.yaml</string>\n\
+             \t\t<string>-p</string>\n\
+             \t\t<string>/etc/wg_netmanager/peer &amp; co.yaml</string>\n\
+             \t</array>\n"
+        ));
+        assert!(content.contains("\t<key>KeepAlive</key>\n\t<true/>\n"));
+        assert!(content.contains("/opt/homebrew/bin"));
+        assert!(content.ends_with("</plist>\n"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wg_netmanager.plist");
+        assert!(!install_plist(&path, &content, false, true).unwrap());
+        assert!(!path.exists());
+
+        // launchd needs root:wheel as owner
+        let is_root = std::fs::metadata(dir.path()).unwrap().uid() == 0;
+        if !is_root {
+            assert!(install_plist(&path, &content, false, false).is_err());
+            return;
+        }
+        assert!(install_plist(&path, &content, false, false).unwrap());
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o644);
+        assert_eq!((metadata.uid(), metadata.gid()), (0, 0));
+        assert!(!install_plist(&path, &content, false, false).unwrap());
+
+        let other = content.replace("KeepAlive", "KeepAliveNot");
+        assert!(install_plist(&path, &other, false, false).is_err());
+        assert!(install_plist(&path, &other, true, false).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), other);
+    }
+}
@@ -0,0 +1,51 @@
+#[cfg(target_os = "linux")]
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use wg_netmanager::{Arch, Architecture};
+
+    const CHILD: &str = "WG_NETMANAGER_TEST_DROP_PRIVILEGES";
+
+    fn capabilities(status: &str, set: &str) -> u64 {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(set))
+            .map(|caps| u64::from_str_radix(caps.trim(), 16).unwrap())
+            .unwrap()
+    }
+
+    // The privileges are dropped in a child process, which reruns this test
+    #[test]
+    fn test_keep_net_admin() {
+        let net_admin = 1 << 12;
+        if std::env::var(CHILD).is_ok() {
+            Arch::drop_privileges("nobody", true).unwrap();
+            let status = std::fs::read_to_string("/proc/thread-self/status").unwrap();
+            assert_eq!(capabilities(&status, "CapEff:"), net_admin);
+            assert_eq!(capabilities(&status, "CapAmb:"), net_admin);
+            // Inherited by the commands
+            let output = Command::new("cat")
+                .arg("/proc/self/status")
+                .output()
+                .unwrap();
+            let status = String::from_utf8_lossy(&output.stdout);
+            assert_eq!(capabilities(&status, "CapEff:"), net_admin);
+            assert!(!status.contains("Uid:\t0\t"));
+            return;
+        }
+        if !nix::unistd::Uid::effective().is_root() {
+            return;
+        }
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::test_keep_net_admin"])
+            .env(CHILD, "1")
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+}
@@ -0,0 +1,82 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use wg_netmanager::*;
+
+    // The rustdoc json needs the nightly toolchain. After an intended change of the
+    // api, update the snapshot with: UPDATE_EXPECT=1 cargo test --test 43_public_api
+    // Arch differs per platform, so the snapshot is taken on linux.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_public_api() {
+        use std::fmt::Write;
+
+        // Unstable internals of the derives, which change with the nightly toolchain
+        const NIGHTLY_INTERNALS: [&str; 4] = [
+            "core::clone::TrivialClone",
+            "core::marker::StructuralPartialEq",
+            "::assert_fields_are_eq(",
+            "::assert_receiver_is_total_eq(",
+        ];
+
+        // The items of the facade are defined in hidden modules. Without the hidden
+        // items, rustdoc would only list the re-exports, but not their signatures.
+        let rustdoc_json = rustdoc_json::Builder::default()
+            .toolchain("nightly")
+            .manifest_path(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))
+            .env(
+                "RUSTDOCFLAGS",
+                "-Z unstable-options --document-hidden-items",
+            )
+            .quiet(true)
+            .build()
+            .unwrap();
+        // Blanket impls depend on the versions of the dependencies
+        let public_api = public_api::Builder::from_rustdoc_json(rustdoc_json)
+            .omit_blanket_impls(true)
+            .build()
+            .unwrap();
+
+        // Only the items at the crate root and below belong to the facade. The sorted
+        // items of a hidden module follow its `pub mod` line.
+        let root = public_api
+            .items()
+            .find(|item| item.parent_id().is_none())
+            .unwrap()
+            .id();
+        let mut in_facade = true;
+        let mut snapshot = String::new();
+        for item in public_api.items() {
+            let text = item.to_string();
+            if item.parent_id() == Some(root) {
+                in_facade = !text.starts_with("pub mod ");
+            }
+            if in_facade
+                && !NIGHTLY_INTERNALS
+                    .iter()
+                    .any(|internal| text.contains(internal))
+            {
+                writeln!(snapshot, "{}", text).unwrap();
+            }
+        }
+        expect_test::expect_file!["public-api.txt"].assert_eq(&snapshot);
+    }
+
+    #[test]
+    fn test_daemon_lifecycle() {
+        let static_config = common::builder()
+            .wg_ip(common::ip("10.1.1.1"))
+            .wg_port(50431)
+            .admin_port(50931)
+            .build();
+        let daemon = Daemon::start_with(static_config, |_| {
+            Ok(Box::new(common::FailingDevice("no device".to_string())))
+        })
+        .unwrap();
+        let e = daemon.join().unwrap_err();
+        assert_eq!(e.to_string(), "no device");
+    }
+}
@@ -0,0 +1,114 @@
+#[cfg(all(target_os = "linux", feature = "userspace"))]
+mod tests {
+    use std::fs::File;
+    use std::net::UdpSocket;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    use wg_netmanager::crypto::generate_key_pair;
+    use wg_netmanager::wg_userspace::*;
+
+    struct Node {
+        wireguard: UserspaceWireguard,
+        // The other end of the tun
+        tun: UnixDatagram,
+        private_key: String,
+        public_key: String,
+        port: u16,
+    }
+    impl Node {
+        // A datagram socket pair keeps the packet boundaries like a tun
+        fn new(name: &str) -> Self {
+            let (tun, engine_end) = UnixDatagram::pair().unwrap();
+            tun.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let (private_key, public_key) = generate_key_pair();
+            let port = UdpSocket::bind("[::]:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            Node {
+                wireguard: UserspaceWireguard::new(name, File::from(OwnedFd::from(engine_end))),
+                tun,
+                private_key,
+                public_key,
+                port,
+            }
+        }
+        fn configure(&self, peer: &Node, allowed_ip: &str) {
+            let conf = format!(
+                "[Interface]\nPrivateKey = {}\nListenPort = {}\n\n\
+                 [Peer]\nPublicKey = {}\nAllowedIPs = {}\nEndpoint = 127.0.0.1:{}\n",
+                self.private_key, self.port, peer.public_key, allowed_ip, peer.port
+            );
+            self.wireguard.update_conf(&conf, true).unwrap();
+        }
+    }
+
+    fn ipv4_packet(src: [u8; 4], dst: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let len = (20 + payload.len()) as u16;
+        let mut packet = vec![
+            0x45,
+            0,
+            (len >> 8) as u8,
+            len as u8,
+            0,
+            0,
+            0,
+            0,
+            64,
+            17,
+            0,
+            0,
+        ];
+        packet.extend(src);
+        packet.extend(dst);
+        packet.extend(payload);
+        packet
+    }
+
+    #[test]
+    fn test_packet_through_tunnel() {
+        let node_1 = Node::new("wgtest1");
+        let node_2 = Node::new("wgtest2");
+        node_1.configure(&node_2, "10.1.1.2/32");
+        node_2.configure(&node_1, "10.1.1.1/32");
+        assert!(node_1.wireguard.latest_handshakes().is_empty());
+
+        // The first packet is queued until the handshake is done
+        let packet = ipv4_packet([10, 1, 1, 1], [10, 1, 1, 2], b"hello");
+        node_1.tun.send(&packet).unwrap();
+        let mut buf = [0u8; 1500];
+        let n = node_2.tun.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &packet[..]);
+
+        // and back
+        let packet = ipv4_packet([10, 1, 1, 2], [10, 1, 1, 1], b"world");
+        node_2.tun.send(&packet).unwrap();
+        let n = node_1.tun.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &packet[..]);
+
+        // A source outside of the allowed ips is dropped
+        let packet = ipv4_packet([10, 1, 1, 3], [10, 1, 1, 1], b"spoofed");
+        node_2.tun.send(&packet).unwrap();
+        node_1
+            .tun
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        assert!(node_1.tun.recv(&mut buf).is_err());
+
+        assert!(node_1
+            .wireguard
+            .latest_handshakes()
+            .contains_key(&node_2.public_key));
+        let endpoints = node_1.wireguard.endpoints();
+        assert_eq!(endpoints[&node_2.public_key].port(), node_2.port);
+        let (rx_bytes, tx_bytes) = node_1.wireguard.transfer()[&node_2.public_key];
+        assert!(rx_bytes > 0);
+        assert!(tx_bytes > 0);
+
+        node_1.wireguard.stop();
+        assert!(node_1.wireguard.endpoints().is_empty());
+    }
+}
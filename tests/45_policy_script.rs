@@ -0,0 +1,189 @@
+#![cfg(feature = "scripting")]
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::net::IpAddr;
+    use std::time::Instant;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::identity::*;
+    use wg_netmanager::manager::*;
+    use wg_netmanager::policy_script::*;
+    use wg_netmanager::routedb::RouteInfo;
+
+    // A static peer, which accepts dynamic peers
+    fn get_test_config() -> StaticConfiguration {
+        common::static_peer_builder()
+            .my_public_key(common::public_key(10))
+            .build()
+    }
+
+    fn advertisement_of(peer_ip: IpAddr, name: &str) -> AdvertisementPacket {
+        let mut ad = AdvertisementPacket {
+            addressed_to: AddressedTo::StaticAddress,
+            public_key: PublicKeyWithTime::new(format!("key of {}", peer_ip), 0),
+            next_public_key: None,
+            local_wg_port: 0,
+            local_admin_port: 0,
+            wg_ip: peer_ip,
+            name: name.to_string(),
+            your_visible_wg_endpoint: None,
+            my_visible_wg_endpoint: None,
+            routedb_version: 0,
+            exported_nets: vec![],
+            extra_wg_ports: vec![],
+            force_relay_s: 0,
+            revocations: vec![],
+            moved_from: None,
+            identity: [0; 32],
+            signature: vec![],
+        };
+        ad.sign(&NodeIdentity::from_seed(&[2; 32]));
+        ad
+    }
+
+    fn route(to: &str, hop_cnt: usize, gateway: Option<&str>) -> RouteInfo {
+        RouteInfo {
+            to: common::ip(to),
+            local_admin_port: 0,
+            hop_cnt,
+            gateway: gateway.map(common::ip),
+            area_summary: None,
+            exported_nets: vec![],
+            cost: 0,
+            bandwidth_mbit: None,
+        }
+    }
+
+    fn routedb(sender: &str, known_routes: Vec<RouteInfo>) -> RouteDatabasePacket {
+        RouteDatabasePacket {
+            sender: common::ip(sender),
+            routedb_version: 1,
+            nr_entries: known_routes.len(),
+            chunk: 0,
+            nr_chunks: 1,
+            known_routes,
+        }
+    }
+
+    fn gateway_to(mgr: &NetworkManager, to: &str) -> Option<Option<IpAddr>> {
+        mgr.peer_infos(wg_netmanager::util::now())
+            .into_iter()
+            .find(|p| p.wg_ip == common::ip(to))
+            .map(|p| p.gateway)
+    }
+
+    #[test]
+    fn test_invalid_scripts() {
+        assert!(PolicyScript::from_source("fn accept_peer(peer) { true").is_err());
+        assert!(PolicyScript::from_source("fn helper(x) { x }").is_err());
+        assert!(PolicyScript::from_source("fn allow_route() { true }").is_err());
+        assert!(PolicyScript::load("/nonexistent/policy.rhai").is_err());
+
+        let script = PolicyScript::from_source("fn allow_route(route) { true }").unwrap();
+        assert!(script.has_hook("allow_route"));
+        assert!(!script.has_hook("select_gateway"));
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let script = PolicyScript::from_source(
+            "fn allow_route(route) { loop {} }\nfn accept_peer(peer) { 42 }",
+        )
+        .unwrap();
+        let start = Instant::now();
+        // Falls back to allow resp. accept
+        assert!(script.allow_route(&route("10.1.1.2", 1, None)));
+        assert!(start.elapsed().as_millis() < 1000);
+        let ad = advertisement_of(common::ip("10.1.1.2"), "peer");
+        assert!(script.accept_peer(&ad, "192.168.1.1:2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_accept_peer() {
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+        let script = PolicyScript::from_source(
+            r#"fn accept_peer(peer) { peer.name != "evil" && peer.src_addr.starts_with("192.168.") }"#,
+        )
+        .unwrap();
+        mgr.set_policy_script(Some(script));
+
+        let now = wg_netmanager::util::now();
+        for (peer, name, src_addr) in [
+            ("10.1.1.2", "good", "192.168.1.1:2"),
+            ("10.1.1.3", "evil", "192.168.1.1:3"),
+            ("10.1.1.4", "good", "10.0.0.1:4"),
+        ] {
+            let ad = advertisement_of(common::ip(peer), name);
+            mgr.analyze_advertisement(now, &static_config, ad, src_addr.parse().unwrap());
+        }
+        assert!(gateway_to(&mgr, "10.1.1.2").is_some());
+        assert!(gateway_to(&mgr, "10.1.1.3").is_none());
+        assert!(gateway_to(&mgr, "10.1.1.4").is_none());
+    }
+
+    #[test]
+    fn test_routes() {
+        let static_config = get_test_config();
+        let mut mgr = NetworkManager::new(&static_config);
+        let script = PolicyScript::from_source(
+            r#"
+            fn allow_route(route) { route.to != "10.1.1.70" }
+            fn select_gateway(to, candidates) {
+                for c in candidates {
+                    if c.gateway == "10.1.1.3" { return c.gateway; }
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        mgr.set_policy_script(Some(script));
+
+        let now = wg_netmanager::util::now();
+        for peer in ["10.1.1.2", "10.1.1.3"] {
+            let ad = advertisement_of(common::ip(peer), "peer");
+            mgr.analyze_advertisement(now, &static_config, ad, "192.168.1.1:2".parse().unwrap());
+        }
+        // 10.1.1.2 reaches 10.1.1.50 directly, 10.1.1.3 only via 10.1.1.60
+        mgr.process_route_database(routedb(
+            "10.1.1.2",
+            vec![route("10.1.1.50", 0, None), route("10.1.1.70", 0, None)],
+        ));
+        mgr.process_route_database(routedb(
+            "10.1.1.3",
+            vec![
+                route("10.1.1.60", 0, None),
+                route("10.1.1.50", 1, Some("10.1.1.60")),
+            ],
+        ));
+        mgr.get_route_changes();
+        mgr.get_route_changes();
+        assert_eq!(
+            gateway_to(&mgr, "10.1.1.50"),
+            Some(Some(common::ip("10.1.1.3")))
+        );
+        assert_eq!(
+            gateway_to(&mgr, "10.1.1.60"),
+            Some(Some(common::ip("10.1.1.3")))
+        );
+        assert_eq!(gateway_to(&mgr, "10.1.1.70"), None);
+
+        // Without script the shortest path
+        mgr.set_policy_script(None);
+        mgr.get_route_changes();
+        assert_eq!(
+            gateway_to(&mgr, "10.1.1.50"),
+            Some(Some(common::ip("10.1.1.2")))
+        );
+        assert_eq!(
+            gateway_to(&mgr, "10.1.1.70"),
+            Some(Some(common::ip("10.1.1.2")))
+        );
+    }
+}
@@ -0,0 +1,63 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::sync::Arc;
+
+    use wg_netmanager::*;
+
+    fn get_test_config(backend: Backend) -> StaticConfiguration {
+        common::builder()
+            .wg_ip(common::ip("10.1.1.1"))
+            .wg_name("wgmock")
+            .wg_port(50432)
+            .admin_port(50932)
+            .backend(backend)
+            .build()
+    }
+
+    #[test]
+    fn test_backend_names() {
+        for backend in Backend::ALL {
+            assert_eq!(backend.name().parse::<Backend>(), Ok(backend));
+            let yaml = serde_yaml::to_string(&backend).unwrap();
+            assert!(yaml.contains(backend.name()));
+            assert_eq!(serde_yaml::from_str::<Backend>(&yaml).unwrap(), backend);
+        }
+        assert_eq!(Backend::default(), Backend::Auto);
+        assert!("wireguard-go".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn test_existing_backend() {
+        assert!(!get_test_config(Backend::Auto).use_existing_interface);
+        assert!(get_test_config(Backend::Existing).use_existing_interface);
+    }
+
+    #[test]
+    fn test_registered_backend() {
+        register_backend(
+            Backend::WgQuick,
+            Arc::new(|wg_name| Ok(Box::new(common::FailingDevice(format!("mock {}", wg_name))))),
+        );
+        let wg_dev = Arch::get_wg_dev_for_backend("wgmock", Backend::WgQuick).unwrap();
+        assert_eq!(
+            wg_dev.check_device().unwrap_err().to_string(),
+            "mock wgmock"
+        );
+
+        // The daemon selects the device by the configured backend
+        let daemon = Daemon::start(get_test_config(Backend::WgQuick)).unwrap();
+        let e = daemon.join().unwrap_err();
+        assert_eq!(e.to_string(), "mock wgmock");
+
+        unregister_backend(Backend::WgQuick);
+        // The built-in backend again, if available on this platform
+        if let Ok(wg_dev) = Arch::get_wg_dev_for_backend("wgmock", Backend::WgQuick) {
+            if let Err(e) = wg_dev.check_device() {
+                assert!(!e.to_string().starts_with("mock"));
+            }
+        }
+    }
+}
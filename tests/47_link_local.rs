@@ -0,0 +1,158 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::net::{IpAddr, SocketAddr};
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::endpoint_resolver::EndpointResolver;
+    use wg_netmanager::event::Event;
+    use wg_netmanager::identity::*;
+    use wg_netmanager::link_local::*;
+    use wg_netmanager::manager::*;
+
+    fn get_test_config(endpoint: &str) -> StaticConfiguration {
+        let mut peer = common::static_peer(common::ip("10.1.1.2"));
+        peer.endpoint = endpoint.to_string();
+        peer.wg_port = 50002;
+        peer.admin_port = 50502;
+        let mut peers = std::collections::HashMap::new();
+        peers.insert(peer.wg_ip, peer);
+        common::builder()
+            .ip_list(vec![common::ip("fe80::1")])
+            .link_scope_ids(vec![3, 7])
+            .wg_ip(common::ip("10.1.1.1"))
+            .wg_port(50001)
+            .admin_port(50501)
+            .my_public_key(common::public_key(10))
+            .peers(peers)
+            .build()
+    }
+
+    fn advertisement_of(peer_ip: IpAddr, addressed_to: AddressedTo) -> AdvertisementPacket {
+        let mut ad = AdvertisementPacket {
+            addressed_to,
+            public_key: PublicKeyWithTime::new("peer", 0),
+            next_public_key: None,
+            local_wg_port: 50002,
+            local_admin_port: 50502,
+            wg_ip: peer_ip,
+            name: "peer".to_string(),
+            your_visible_wg_endpoint: None,
+            my_visible_wg_endpoint: None,
+            routedb_version: 0,
+            exported_nets: vec![],
+            extra_wg_ports: vec![],
+            force_relay_s: 0,
+            revocations: vec![],
+            moved_from: None,
+            identity: [0; 32],
+            signature: vec![],
+        };
+        ad.sign(&NodeIdentity::from_seed(&[2; 32]));
+        ad
+    }
+
+    #[test]
+    fn test_scoped_addresses() {
+        assert!(needs_scope(&common::ip("fe80::1")));
+        assert!(needs_scope(&common::ip("ff02::1")));
+        assert!(!needs_scope(&common::ip("2001:db8::1")));
+        assert!(!needs_scope(&common::ip("ff05::1")));
+        assert!(!needs_scope(&common::ip("192.168.1.1")));
+
+        let sa = scoped(common::ip("fe80::1"), 50001, 7);
+        assert_eq!(sa, "[fe80::1%7]:50001".parse::<SocketAddr>().unwrap());
+        assert_eq!(scope_id(&with_port(sa, 50002)), 7);
+        assert_eq!(
+            scoped(common::ip("2001:db8::1"), 50001, 7),
+            "[2001:db8::1]:50001".parse::<SocketAddr>().unwrap()
+        );
+        assert!(check_scope(&sa).is_ok());
+        assert!(check_scope(&"[fe80::1]:50001".parse().unwrap()).is_err());
+        assert!(check_scope(&"[2001:db8::1]:50001".parse().unwrap()).is_ok());
+
+        // Not sent without knowing the interface
+        let mut socket = CryptUdp::bind(common::ip("0.0.0.0"), 0)
+            .unwrap()
+            .key(&[0; 32])
+            .unwrap();
+        let e = socket
+            .send_to(b"x", "[fe80::1]:50001".parse().unwrap())
+            .unwrap_err();
+        assert!(e.to_string().contains("link-local"));
+    }
+
+    #[test]
+    fn test_split_scope() {
+        assert_eq!(
+            split_scope("[fe80::1%7]:50001").unwrap(),
+            ("[fe80::1]:50001".to_string(), Some(7))
+        );
+        assert_eq!(
+            split_scope("fe80::1%7").unwrap(),
+            ("fe80::1".to_string(), Some(7))
+        );
+        assert_eq!(
+            split_scope("10.0.0.1:50001").unwrap(),
+            ("10.0.0.1:50001".to_string(), None)
+        );
+        assert!(split_scope("[fe80::1%a%b]:1").is_err());
+        assert!(split_scope("[fe80::1%]:1").is_err());
+        assert!(split_scope("[fe80::1%no-such-interface]:1").is_err());
+
+        let ips = EndpointResolver::Dns.resolve("[fe80::1%7]:50001").unwrap();
+        assert_eq!(ips, vec![common::ip("fe80::1")]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_interface_names() {
+        use std::net::SocketAddrV6;
+        use wg_netmanager::wg_dev::parse_wg_endpoint;
+
+        let lo = interface_index("lo").unwrap();
+        assert!(lo > 0);
+        let sa = parse_wg_endpoint("[fe80::1%lo]:50001").unwrap();
+        assert_eq!(
+            sa,
+            SocketAddrV6::new("fe80::1".parse().unwrap(), 50001, 0, lo).into()
+        );
+        let config = get_test_config("[fe80::2%lo]:50002");
+        assert_eq!(config.peers[&common::ip("10.1.1.2")].scope_id(), lo);
+    }
+
+    #[test]
+    fn test_static_peer_on_the_link() {
+        // The static peer is reached via all nodes on the link of interface 7
+        let static_config = get_test_config("[ff02::1%7]:50002");
+        let mut mgr = NetworkManager::new(&static_config);
+        let now = wg_netmanager::util::now();
+        let destinations = mgr
+            .process_all_nodes_every_second(now, &static_config)
+            .into_iter()
+            .filter_map(|evt| match evt {
+                Event::SendAdvertisement {
+                    addressed_to: AddressedTo::StaticAddress,
+                    to,
+                    ..
+                } => Some(to),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(destinations, vec![scoped(common::ip("ff02::1"), 50502, 7)]);
+
+        // The reply comes from its link-local address, which is then the endpoint
+        let src_addr = scoped(common::ip("fe80::2"), 50502, 7);
+        mgr.analyze_advertisement(
+            now,
+            &static_config,
+            advertisement_of(common::ip("10.1.1.2"), AddressedTo::ReplyFromStaticAddress),
+            src_addr,
+        );
+        let conf = static_config.to_wg_configuration(&mgr);
+        assert!(conf.contains("EndPoint = [fe80::2%7]:50002"), "{}", conf);
+    }
+}
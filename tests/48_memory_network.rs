@@ -0,0 +1,241 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::net::SocketAddr;
+
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::memory_udp::*;
+    use wg_netmanager::wg_dev::*;
+
+    const ADMIN_PORT: u16 = 50501;
+
+    #[test]
+    fn test_mock_device() {
+        let mut wg_dev = MockWireguardDevice::new();
+        let observer = wg_dev.clone();
+        assert!(!wg_dev.check_device().unwrap());
+        wg_dev.create_device().unwrap();
+        wg_dev
+            .set_ip(
+                &common::ip("10.1.1.2"),
+                &"10.1.1.0/24".parse().unwrap(),
+                None,
+            )
+            .unwrap();
+        let attributes = RouteAttributes::default();
+        wg_dev
+            .add_route(
+                common::ip("10.1.1.3"),
+                Some(common::ip("10.1.1.1")),
+                &attributes,
+            )
+            .unwrap();
+        assert!(wg_dev
+            .add_route(
+                common::ip("10.1.1.3"),
+                Some(common::ip("10.1.1.1")),
+                &attributes
+            )
+            .is_err());
+        assert!(wg_dev.sync_conf("[Peer]\nEndpoint = nowhere").is_err());
+
+        let state = observer.state();
+        assert!(state.exists);
+        assert_eq!(state.ips(), vec![common::ip("10.1.1.2")]);
+        assert_eq!(
+            state.routes[&common::ip("10.1.1.3")].0,
+            Some(common::ip("10.1.1.1"))
+        );
+        assert_eq!(state.conf_updates, 0);
+
+        wg_dev.take_down_device().unwrap();
+        assert!(!observer.check_device().unwrap());
+        assert!(observer.state().routes.is_empty());
+    }
+
+    #[test]
+    fn test_device_worker() {
+        let wg_dev = MockWireguardDevice::new();
+        let observer = wg_dev.clone();
+        let device = DeviceWorker::start(Box::new(wg_dev)).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            device.call(|wg_dev| wg_dev.create_device()).await.unwrap();
+            let attributes = RouteAttributes::default();
+            let add_route = move |wg_dev: &dyn WireguardDevice| {
+                wg_dev.add_route(common::ip("10.1.1.3"), None, &attributes)
+            };
+            device.call(add_route).await.unwrap();
+            // The error of the device is passed on
+            let e = device.call(add_route).await.unwrap_err();
+            assert!(!e.to_string().is_empty());
+        });
+        assert!(device
+            .call_blocking(|wg_dev| wg_dev.create_key_pair())
+            .is_ok());
+        assert!(observer
+            .state()
+            .routes
+            .contains_key(&common::ip("10.1.1.3")));
+
+        let wg_dev = device.stop().unwrap();
+        assert!(wg_dev.check_device().unwrap());
+    }
+
+    #[test]
+    fn test_memory_udp() {
+        let network = MemoryNetwork::new();
+        let mut a = MemoryUdp::bind(&network, common::ip("192.168.0.1"), ADMIN_PORT)
+            .unwrap()
+            .key(&[1; 32])
+            .unwrap();
+        let b = MemoryUdp::bind(&network, common::ip("192.168.0.2"), ADMIN_PORT)
+            .unwrap()
+            .key(&[1; 32])
+            .unwrap();
+        let c = MemoryUdp::bind(&network, common::ip("192.168.0.3"), ADMIN_PORT)
+            .unwrap()
+            .key(&[2; 32])
+            .unwrap();
+        assert!(MemoryUdp::bind(&network, common::ip("192.168.0.3"), ADMIN_PORT).is_err());
+
+        let mut buf = vec![0; 1500];
+        assert!(b.try_recv_from(&mut buf).unwrap().is_none());
+        a.send_to(b"hello", b.local_addr().unwrap()).unwrap();
+        let (len, src_addr) = b.try_recv_from(&mut buf).unwrap().unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(src_addr, a.local_addr().unwrap());
+
+        // Another shared key
+        a.send_to(b"hello", c.local_addr().unwrap()).unwrap();
+        let e = c.try_recv_from(&mut buf).unwrap_err();
+        assert!(e.downcast_ref::<DecryptError>().is_some());
+        assert_eq!(c.counters().counts().decrypt_failures, 1);
+
+        // Nobody there, resp. no tunnel to the overlay address
+        a.send_to(b"hello", "192.168.0.4:50501".parse().unwrap())
+            .unwrap();
+        a.send_to(b"hello", "10.1.1.2:50501".parse().unwrap())
+            .unwrap();
+        assert_eq!(network.dropped(), 2);
+        assert_eq!(a.counters().counts().sent, 4);
+    }
+
+    fn socket(network: &MemoryNetwork, addr: &str) -> MemoryUdp {
+        MemoryUdp::bind(network, common::ip(addr), ADMIN_PORT)
+            .unwrap()
+            .key(&[1; 32])
+            .unwrap()
+    }
+
+    fn received(socket: &MemoryUdp) -> Option<SocketAddr> {
+        let mut buf = vec![0; 1500];
+        socket
+            .try_recv_from(&mut buf)
+            .unwrap()
+            .map(|(_, src_addr)| src_addr)
+    }
+
+    #[test]
+    fn test_nat() {
+        let network = MemoryNetwork::new();
+        let mut inside = socket(&network, "10.0.0.2");
+        let mut outside = socket(&network, "192.168.0.1");
+        let mut other = socket(&network, "192.168.0.2");
+        network.add_nat(
+            common::ip("203.0.113.1"),
+            NatType::PortRestricted,
+            &[common::ip("10.0.0.2")],
+        );
+
+        // Not reachable before the mapping and not via the private address
+        let public: SocketAddr = "203.0.113.1:50501".parse().unwrap();
+        outside.send_to(b"hello", public).unwrap();
+        outside
+            .send_to(b"hello", inside.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(received(&inside), None);
+
+        // The port is kept, and only the contacted host may answer
+        inside
+            .send_to(b"hello", outside.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(received(&outside), Some(public));
+        outside.send_to(b"hello", public).unwrap();
+        other.send_to(b"hello", public).unwrap();
+        assert_eq!(received(&inside), Some(outside.local_addr().unwrap()));
+        assert_eq!(received(&inside), None);
+
+        // Another port per destination
+        let network = MemoryNetwork::new();
+        let mut inside = socket(&network, "10.0.0.2");
+        let outside = socket(&network, "192.168.0.1");
+        let other = socket(&network, "192.168.0.2");
+        network.add_nat(
+            common::ip("203.0.113.1"),
+            NatType::Symmetric,
+            &[common::ip("10.0.0.2")],
+        );
+        inside
+            .send_to(b"hello", outside.local_addr().unwrap())
+            .unwrap();
+        inside
+            .send_to(b"hello", other.local_addr().unwrap())
+            .unwrap();
+        assert_ne!(
+            received(&outside).unwrap().port(),
+            received(&other).unwrap().port()
+        );
+    }
+
+    #[test]
+    fn test_link_conditions() {
+        let network = MemoryNetwork::with_seed(1);
+        let mut a = socket(&network, "192.168.0.1");
+        let b = socket(&network, "192.168.0.2");
+        let to = b.local_addr().unwrap();
+        network.advance_to(1000);
+
+        network.set_link(
+            common::ip("192.168.0.2"),
+            common::ip("192.168.0.1"),
+            LinkConditions::down(),
+        );
+        a.send_to(b"hello", to).unwrap();
+        assert_eq!(received(&b), None);
+
+        network.set_link(
+            common::ip("192.168.0.1"),
+            common::ip("192.168.0.2"),
+            LinkConditions {
+                latency_ms: 200,
+                ..Default::default()
+            },
+        );
+        a.send_to(b"hello", to).unwrap();
+        assert_eq!(network.in_flight(), 1);
+        network.advance_to(1100);
+        assert_eq!(received(&b), None);
+        network.advance_to(1200);
+        assert!(received(&b).is_some());
+
+        network.clear_links();
+        network.set_conditions(LinkConditions {
+            loss_percent: 50,
+            ..Default::default()
+        });
+        for _ in 0..100 {
+            a.send_to(b"hello", to).unwrap();
+        }
+        let mut cnt = 0;
+        while received(&b).is_some() {
+            cnt += 1;
+        }
+        assert!(cnt > 20 && cnt < 80, "{} of 100 received", cnt);
+    }
+}
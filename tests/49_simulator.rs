@@ -0,0 +1,158 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::net::SocketAddr;
+
+    use wg_netmanager::crypt_udp::UdpPacket;
+    use wg_netmanager::memory_udp::*;
+    use wg_netmanager::testing::*;
+
+    // A hub and n dynamic nodes on one lan
+    fn mesh(seed: u64, n: usize) -> Simulator {
+        let mut sim = Simulator::new(seed);
+        sim.add_static_node("hub", common::ip("10.1.1.1"), common::ip("192.168.0.1"));
+        for i in 2..n + 2 {
+            sim.add_node(
+                &format!("node{}", i),
+                common::ip(&format!("10.1.1.{}", i)),
+                common::ip(&format!("192.168.0.{}", i)),
+            );
+        }
+        sim
+    }
+
+    fn converged_within(sim: &mut Simulator, max_seconds: u64) -> u64 {
+        match sim.run_until_converged(max_seconds) {
+            Some(seconds) => seconds,
+            None => panic!("not converged: {:#?}", sim.route_errors()),
+        }
+    }
+
+    #[test]
+    fn test_convergence() {
+        let mut sim = mesh(1, 3);
+        converged_within(&mut sim, 10);
+        for node in sim.nodes() {
+            assert!(!node.wg_dev.state().handshakes.is_empty());
+        }
+
+        // The admin packets of one node reach another via the tunnel
+        let delivered = sim.network().delivered();
+        let to = SocketAddr::new(common::ip("10.1.1.3"), ADMIN_PORT);
+        sim.node_mut(1).send(&UdpPacket::Ping { sent_ms: 0 }, to);
+        assert_eq!(sim.network().delivered(), delivered + 1);
+    }
+
+    #[test]
+    fn test_node_leaves() {
+        let mut sim = mesh(2, 3);
+        converged_within(&mut sim, 60);
+        sim.stop_node(3);
+        assert!(!sim.is_converged());
+        // The peers wait for the timeout
+        let seconds = converged_within(&mut sim, 200);
+        assert!(seconds >= 120, "dropped after {}s", seconds);
+        assert!(sim
+            .node(1)
+            .route_to(sim.now(), common::ip("10.1.1.4"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_packet_loss() {
+        let mut sim = mesh(3, 4);
+        sim.set_conditions(LinkConditions {
+            loss_percent: 30,
+            ..Default::default()
+        });
+        converged_within(&mut sim, 300);
+        assert!(sim.network().dropped() > 0);
+        // Stays converged
+        sim.run_for(120);
+        assert_eq!(sim.route_errors(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_latency() {
+        let mut sim = mesh(4, 3);
+        sim.set_conditions(LinkConditions {
+            latency_ms: 300,
+            jitter_ms: 200,
+            ..Default::default()
+        });
+        let seconds = converged_within(&mut sim, 30);
+        assert!(seconds >= 1);
+        sim.run_for(60);
+        assert!(sim.is_converged());
+    }
+
+    #[test]
+    fn test_partition() {
+        let mut sim = mesh(5, 4);
+        converged_within(&mut sim, 60);
+
+        // 10.1.1.4 is cut off from all others
+        sim.partition(&[&[0, 1, 2, 4], &[3]]);
+        let mut dropped_after = None;
+        for seconds in 0..200 {
+            let now = sim.now();
+            let knows_cut_off = [0, 1, 2, 4]
+                .iter()
+                .any(|i| sim.node(*i).route_to(now, common::ip("10.1.1.4")).is_some());
+            if !knows_cut_off {
+                dropped_after = Some(seconds);
+                break;
+            }
+            sim.run_for(1);
+        }
+        let dropped_after = dropped_after.expect("the partition has not been detected");
+        assert!(dropped_after >= 120, "dropped after {}s", dropped_after);
+        // The majority keeps correct routes among each other
+        assert!(sim.route_errors().iter().all(|e| e.contains("10.1.1.4")));
+
+        sim.heal();
+        converged_within(&mut sim, 300);
+    }
+
+    #[test]
+    fn test_cone_nat() {
+        let mut sim = mesh(6, 1);
+        let b = sim.add_node("natted", common::ip("10.1.1.10"), common::ip("10.0.0.2"));
+        sim.add_nat(common::ip("203.0.113.1"), NatType::PortRestricted, &[b]);
+        converged_within(&mut sim, 10);
+        // Directly connected via the mapping of the NAT after the NAT traversal
+        sim.run_for(60);
+        assert_eq!(
+            sim.node(b).route_to(sim.now(), common::ip("10.1.1.2")),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn test_symmetric_nat() {
+        let mut sim = mesh(7, 0);
+        let b = sim.add_node("b", common::ip("10.1.1.10"), common::ip("10.0.0.2"));
+        let c = sim.add_node("c", common::ip("10.1.1.11"), common::ip("10.0.1.2"));
+        sim.add_nat(common::ip("203.0.113.1"), NatType::Symmetric, &[b]);
+        sim.add_nat(common::ip("203.0.113.2"), NatType::Symmetric, &[c]);
+        converged_within(&mut sim, 10);
+        // Still relayed by the hub, after the NAT traversal has failed
+        sim.run_for(700);
+        assert_eq!(sim.route_errors(), Vec::<String>::new());
+        let now = sim.now();
+        assert_eq!(
+            sim.node(b).route_to(now, common::ip("10.1.1.11")),
+            Some(Some(common::ip("10.1.1.1")))
+        );
+        assert_eq!(
+            sim.node(c).route_to(now, common::ip("10.1.1.10")),
+            Some(Some(common::ip("10.1.1.1")))
+        );
+        let delivered = sim.network().delivered();
+        let to = SocketAddr::new(common::ip("10.1.1.11"), ADMIN_PORT);
+        sim.node_mut(b).send(&UdpPacket::Ping { sent_ms: 0 }, to);
+        assert_eq!(sim.network().delivered(), delivered + 1);
+    }
+}
@@ -0,0 +1,91 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::manager::*;
+    use wg_netmanager::wg_dev::*;
+
+    const FOREIGN_KEY: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+    const LEFTOVER_KEY: &str = "HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=";
+    const PSK: &str = "FpCyhws9cxwWoV4xELtfJvjJN+zQVRPISllRWgeopVE=";
+
+    fn get_test_config() -> StaticConfiguration {
+        let (private_key, public_key) = wg_netmanager::crypto::generate_key_pair();
+        common::builder()
+            .wg_port(50001)
+            .admin_port(50501)
+            .my_private_key(private_key)
+            .my_public_key(PublicKeyWithTime::new(public_key, 10))
+            .use_existing_interface(true)
+            .adopt_peers(true)
+            .build()
+    }
+
+    // As configured by other tooling, with a leftover of a previous run
+    fn existing_conf() -> String {
+        format!(
+            "[Interface]\nListenPort = 51820\n\n\
+             [Peer]\nPublicKey = {}\nPresharedKey = {}\nEndpoint = 192.168.5.1:51820\n\
+             AllowedIPs = 10.9.0.0/24, 0.0.0.0/0\nPersistentKeepalive = 25\n\n\
+             [Peer]\nPublicKey = {}\nAllowedIPs = 10.1.1.7/32\n",
+            FOREIGN_KEY, PSK, LEFTOVER_KEY
+        )
+    }
+
+    #[test]
+    fn test_peer_conf_lines() {
+        let conf = WgConf::parse(&existing_conf()).unwrap();
+        let peer = &conf.peers[0];
+        assert_eq!(peer.key(), FOREIGN_KEY);
+        assert_eq!(peer.preshared_key, Some(decode_key(PSK).unwrap()));
+        assert_eq!(peer.persistent_keepalive, Some(25));
+
+        // Parsed again unchanged
+        let section = format!("[Peer]\n{}", peer.to_conf_lines().join("\n"));
+        assert_eq!(&WgConf::parse(&section).unwrap().peers[0], peer);
+        assert!(conf.peers[1].preshared_key.is_none());
+    }
+
+    #[test]
+    fn test_foreign_peers() {
+        let mut static_config = get_test_config();
+        let peers = WgConf::parse(&existing_conf()).unwrap().peers;
+        let foreign_peers = static_config.foreign_peers_of(peers);
+        assert_eq!(foreign_peers.len(), 1);
+        assert_eq!(foreign_peers[0].key(), FOREIGN_KEY);
+
+        static_config.foreign_peers = foreign_peers;
+        // Without the default route
+        assert_eq!(
+            static_config.foreign_nets(),
+            vec!["10.9.0.0/24".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_adopted_peers_are_kept() {
+        let mut static_config = get_test_config();
+        let wg_dev = MockWireguardDevice::new();
+        wg_dev.sync_conf(&existing_conf()).unwrap();
+        static_config.foreign_peers =
+            static_config.foreign_peers_of(wg_dev.retrieve_peers().unwrap());
+
+        let mgr = NetworkManager::new(&static_config);
+        let conf = static_config.to_wg_configuration(&mgr);
+        wg_dev.sync_conf(&conf).unwrap();
+        let peers = wg_dev.retrieve_peers().unwrap();
+        assert_eq!(peers, static_config.foreign_peers);
+        assert!(conf.contains(&format!("PresharedKey = {}", PSK)));
+        assert!(!conf.contains(LEFTOVER_KEY));
+
+        // Without adoption, the existing peers are removed
+        static_config.foreign_peers = vec![];
+        wg_dev
+            .sync_conf(&static_config.to_wg_configuration(&mgr))
+            .unwrap();
+        assert!(wg_dev.retrieve_peers().unwrap().is_empty());
+    }
+}
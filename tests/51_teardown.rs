@@ -0,0 +1,105 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use wg_netmanager::testing::*;
+    use wg_netmanager::wg_dev::*;
+
+    const FOREIGN_PEER: &str = "[Peer]\nPublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\nAllowedIPs = 10.9.0.0/24\n";
+
+    fn converged_mesh() -> Simulator {
+        let mut sim = Simulator::new(1);
+        sim.add_static_node("hub", common::ip("10.1.1.1"), common::ip("192.168.0.1"));
+        sim.add_node("node2", common::ip("10.1.1.2"), common::ip("192.168.0.2"));
+        sim.add_node("node3", common::ip("10.1.1.3"), common::ip("192.168.0.3"));
+        assert!(sim.run_until_converged(10).is_some());
+        sim
+    }
+
+    #[test]
+    fn test_teardown_steps() {
+        let sim = converged_mesh();
+        let node = sim.node(1);
+        let teardown = node.static_config.teardown(Some(&node.mgr));
+        assert_eq!(teardown.routes.len(), 2);
+        assert_eq!(teardown.conf, "[Interface]");
+        assert_eq!(teardown.addresses[0], (common::ip("10.1.1.2"), 24));
+        assert!(teardown.skip.is_empty());
+
+        let executed = teardown.execute(&node.wg_dev);
+        assert_eq!(executed, TeardownStep::ALL.to_vec());
+        assert!(!node.wg_dev.check_device().unwrap());
+    }
+
+    #[test]
+    fn test_gateway_routes_first() {
+        let mut sim = converged_mesh();
+        // 10.1.1.3 is only reached via the hub
+        sim.set_link(1, 2, wg_netmanager::memory_udp::LinkConditions::down());
+        sim.run_for(300);
+        let node = sim.node(1);
+        assert_eq!(
+            node.route_to(sim.now(), common::ip("10.1.1.3")),
+            Some(Some(common::ip("10.1.1.1")))
+        );
+        let routes = node.static_config.teardown(Some(&node.mgr)).routes;
+        assert_eq!(routes[0].0, common::ip("10.1.1.3"));
+        assert_eq!(
+            routes[1],
+            (common::ip("10.1.1.1"), None, RouteAttributes::default())
+        );
+    }
+
+    #[test]
+    fn test_existing_interface() {
+        let mut sim = converged_mesh();
+        let node = sim.node_mut(1);
+        node.static_config.use_existing_interface = true;
+        node.static_config.foreign_peers = WgConf::parse(FOREIGN_PEER).unwrap().peers;
+
+        let teardown = node.static_config.teardown(Some(&node.mgr));
+        let executed = teardown.execute(&node.wg_dev);
+        assert_eq!(
+            executed,
+            vec![
+                TeardownStep::Routes,
+                TeardownStep::Peers,
+                TeardownStep::Addresses
+            ]
+        );
+        let state = node.wg_dev.state();
+        assert!(state.exists);
+        assert!(state.routes.is_empty());
+        assert!(state.ips().is_empty());
+        // Only the adopted peer is left
+        assert_eq!(
+            node.wg_dev.retrieve_peers().unwrap(),
+            node.static_config.foreign_peers
+        );
+    }
+
+    #[test]
+    fn test_skip_steps() {
+        let mut sim = converged_mesh();
+        let node = sim.node_mut(1);
+        node.static_config.use_existing_interface = true;
+        node.static_config.teardown_skip = vec![TeardownStep::Peers, TeardownStep::Addresses];
+        let peers = node.wg_dev.retrieve_peers().unwrap();
+        assert!(!peers.is_empty());
+
+        let executed = node
+            .static_config
+            .teardown(Some(&node.mgr))
+            .execute(&node.wg_dev);
+        assert_eq!(executed, vec![TeardownStep::Routes]);
+        let state = node.wg_dev.state();
+        assert!(state.routes.is_empty());
+        assert_eq!(state.ip, Some(common::ip("10.1.1.2")));
+        assert_eq!(node.wg_dev.retrieve_peers().unwrap(), peers);
+
+        assert_eq!("addresses".parse(), Ok(TeardownStep::Addresses));
+        assert!("everything".parse::<TeardownStep>().is_err());
+    }
+}
@@ -0,0 +1,132 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+
+    use wg_netmanager::event::Event;
+    use wg_netmanager::testing::*;
+    use wg_netmanager::tui_display::*;
+    use wg_netmanager::wg_dev::WireguardDevice;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0B");
+        assert_eq!(format_bytes(1023), "1023B");
+        assert_eq!(format_bytes(1536), "1.5K");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.0M");
+        assert_eq!(format_bytes(u64::MAX), "16384.0P");
+    }
+
+    #[test]
+    fn test_peer_rows() {
+        let mut sim = Simulator::new(1);
+        sim.add_static_node("hub", common::ip("10.1.1.1"), common::ip("192.168.0.1"));
+        sim.add_node("node2", common::ip("10.1.1.2"), common::ip("192.168.0.2"));
+        assert!(sim.run_until_converged(10).is_some());
+        // Until the admin packets have passed the tunnel
+        sim.run_for(30);
+
+        let now = sim.now();
+        let node = sim.node(1);
+        let handshakes = node
+            .wg_dev
+            .latest_handshakes()
+            .unwrap()
+            .into_iter()
+            .map(|(key, at)| (key, wg_netmanager::util::from_wall_time(at)))
+            .collect::<HashMap<_, _>>();
+        let transfer = node.wg_dev.transfer().unwrap();
+        let rows = PeerRow::of(now, node.mgr.peer_infos(now), &handshakes, &transfer);
+
+        let hub = rows
+            .iter()
+            .find(|row| row.wg_ip == common::ip("10.1.1.1"))
+            .unwrap();
+        assert_eq!(hub.hop_cnt, Some(0));
+        assert_eq!(hub.gateway, None);
+        assert!(hub.handshake_s_ago.is_some_and(|s| s <= 10));
+        assert!(hub.rx_bytes > 0 && hub.tx_bytes > 0);
+
+        let cells = hub.cells();
+        assert_eq!(cells[1], "10.1.1.1");
+        assert_eq!(cells[6], "-");
+    }
+
+    fn row(wg_ip: &str) -> PeerRow {
+        PeerRow {
+            name: None,
+            wg_ip: common::ip(wg_ip),
+            endpoint_class: "static".to_string(),
+            endpoint: None,
+            handshake_s_ago: None,
+            hop_cnt: Some(0),
+            gateway: None,
+            rx_bytes: 0,
+            tx_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn test_action_keys() {
+        let mut app = TuiApp::off();
+        app.set_peers(vec![row("10.1.1.1"), row("10.1.1.3")]);
+        // Not on the peers tab
+        assert_eq!(app.process_event(TuiAppEvent::CharKey('d')), None);
+
+        // The peers tab is the one before the last
+        app.process_event(TuiAppEvent::BackTabKey);
+        app.process_event(TuiAppEvent::BackTabKey);
+        // Without a selection
+        assert_eq!(app.process_event(TuiAppEvent::CharKey('d')), None);
+
+        app.process_event(TuiAppEvent::DownKey);
+        app.process_event(TuiAppEvent::DownKey);
+        assert_eq!(
+            app.process_event(TuiAppEvent::CharKey('d')),
+            Some((common::ip("10.1.1.3"), PeerAction::Drop))
+        );
+        assert_eq!(
+            app.process_event(TuiAppEvent::CharKey('a')),
+            Some((common::ip("10.1.1.3"), PeerAction::Advertise))
+        );
+        assert_eq!(app.process_event(TuiAppEvent::CharKey('x')), None);
+    }
+
+    #[test]
+    fn test_action_events() {
+        let mut sim = Simulator::new(2);
+        sim.add_static_node("hub", common::ip("10.1.1.1"), common::ip("192.168.0.1"));
+        sim.add_node("node2", common::ip("10.1.1.2"), common::ip("192.168.0.2"));
+        assert!(sim.run_until_converged(10).is_some());
+
+        let node = sim.node_mut(1);
+        let hub = common::ip("10.1.1.1");
+        let mut events_of = |action: PeerAction, wg_ip: IpAddr| {
+            action.events(wg_ip, &mut node.mgr, &node.static_config)
+        };
+        assert!(matches!(
+            events_of(PeerAction::Advertise, hub)[..],
+            [Event::SendAdvertisement { wg_ip, .. }] if wg_ip == hub
+        ));
+        assert!(matches!(
+            events_of(PeerAction::RequestRouteDatabase, hub)[..],
+            [Event::SendRouteDatabaseRequest { to }] if to.ip() == hub
+        ));
+        // Advertised to the endpoint of network.yaml
+        assert!(matches!(
+            events_of(PeerAction::ResolveEndpoint, hub)[..],
+            [Event::SendAdvertisement { to, .. }] if to.ip() == common::ip("192.168.0.1")
+        ));
+        assert!(events_of(PeerAction::Advertise, common::ip("10.1.1.9")).is_empty());
+
+        assert!(matches!(
+            events_of(PeerAction::Drop, hub)[..],
+            [Event::UpdateRoutes]
+        ));
+        // A static peer is kept, but needs to be reached again
+        assert!(!node.mgr.all_nodes[&hub].is_reachable());
+    }
+}
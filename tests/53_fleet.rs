@@ -0,0 +1,234 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use std::net::SocketAddr;
+
+    use wg_netmanager::configuration::*;
+    use wg_netmanager::crypt_udp::*;
+    use wg_netmanager::fleet::*;
+    use wg_netmanager::identity::*;
+    use wg_netmanager::manager::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn get_test_config(admins: Vec<PublicIdentity>, commands: &[&str]) -> StaticConfiguration {
+        let (private_key, public_key) = wg_netmanager::crypto::generate_key_pair();
+        common::builder()
+            .wg_port(50001)
+            .admin_port(50501)
+            .my_private_key(private_key)
+            .my_public_key(PublicKeyWithTime::new(public_key, 10))
+            .fleet_admins(admins)
+            .fleet_commands(commands.iter().map(|c| c.to_string()).collect())
+            .build()
+    }
+
+    fn check(
+        guard: &mut FleetGuard,
+        static_config: &StaticConfiguration,
+        packet: &FleetCommandPacket,
+    ) -> Rejection {
+        guard.check(NOW, static_config, packet).unwrap_err()
+    }
+    fn dropped(reason: &str) -> Rejection {
+        Rejection::Dropped(reason.to_string())
+    }
+    fn refused(reason: &str) -> Rejection {
+        Rejection::Refused(reason.to_string())
+    }
+
+    fn status_to(to: &str, admin: &NodeIdentity) -> FleetCommandPacket {
+        FleetCommandPacket::new(common::ip(to), NOW, FleetCommand::Status, admin)
+    }
+
+    #[test]
+    fn test_signature() {
+        let admin = NodeIdentity::generate();
+        let packet = status_to("10.1.1.2", &admin);
+        assert!(packet.has_valid_signature());
+
+        let mut changed = packet.clone();
+        changed.command = FleetCommand::ReloadConfig;
+        assert!(!changed.has_valid_signature());
+        let mut changed = packet.clone();
+        changed.to = common::ip("10.1.1.3");
+        assert!(!changed.has_valid_signature());
+        let mut changed = packet;
+        changed.identity = NodeIdentity::generate().public();
+        assert!(!changed.has_valid_signature());
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let admin = NodeIdentity::generate();
+        let packet = status_to("10.1.1.2", &admin);
+        let buf = bincode::serialize(&UdpPacket::FleetCommand(packet.clone())).unwrap();
+        match UdpPacket::decode(&buf).unwrap() {
+            UdpPacket::FleetCommand(decoded) => assert_eq!(decoded, packet),
+            other => panic!("decoded as {:?}", other),
+        }
+
+        let reply = FleetResultPacket {
+            sender: common::ip("10.1.1.2"),
+            nonce: 7,
+            result: Err("rejected: replayed".to_string()),
+        };
+        let buf = bincode::serialize(&UdpPacket::FleetResult(reply.clone())).unwrap();
+        match UdpPacket::decode(&buf).unwrap() {
+            UdpPacket::FleetResult(decoded) => assert_eq!(decoded, reply),
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rejections() {
+        let admin = NodeIdentity::generate();
+        let static_config = get_test_config(vec![admin.public()], &["status"]);
+        let mut guard = FleetGuard::default();
+
+        let disabled = get_test_config(vec![], &["status"]);
+        assert_eq!(
+            check(&mut guard, &disabled, &status_to("10.1.1.2", &admin)),
+            dropped("fleet commands are disabled")
+        );
+        let stranger = NodeIdentity::generate();
+        assert_eq!(
+            check(
+                &mut guard,
+                &static_config,
+                &status_to("10.1.1.2", &stranger)
+            ),
+            dropped("unknown administrator")
+        );
+        let mut forged = status_to("10.1.1.2", &stranger);
+        forged.identity = admin.public();
+        assert_eq!(
+            check(&mut guard, &static_config, &forged),
+            dropped("invalid signature")
+        );
+        assert_eq!(
+            check(&mut guard, &static_config, &status_to("10.1.1.3", &admin)),
+            refused("addressed to 10.1.1.3")
+        );
+        let old = FleetCommandPacket::new(
+            common::ip("10.1.1.2"),
+            NOW - MAX_AGE - 1,
+            FleetCommand::Status,
+            &admin,
+        );
+        assert!(matches!(
+            check(&mut guard, &static_config, &old),
+            Rejection::Refused(reason) if reason.starts_with("issued at")
+        ));
+
+        // A refused command does not use up its nonce
+        let reload = FleetCommandPacket::new(
+            common::ip("10.1.1.2"),
+            NOW,
+            FleetCommand::ReloadConfig,
+            &admin,
+        );
+        assert_eq!(
+            check(&mut guard, &static_config, &reload),
+            refused("reload-config is not whitelisted")
+        );
+        let whitelisted = get_test_config(vec![admin.public()], &["reload-config"]);
+        assert_eq!(guard.check(NOW, &whitelisted, &reload), Ok(()));
+
+        let packet = status_to("10.1.1.2", &admin);
+        assert_eq!(guard.check(NOW, &static_config, &packet), Ok(()));
+        assert_eq!(
+            check(&mut guard, &static_config, &packet),
+            refused("replayed")
+        );
+    }
+
+    #[test]
+    fn test_process() {
+        let admin = NodeIdentity::generate();
+        let mut static_config = get_test_config(vec![admin.public()], &["status", "log-level"]);
+        let mut network_manager = NetworkManager::new(&static_config);
+        let mut guard = FleetGuard::default();
+        let src_addr: SocketAddr = "10.1.1.1:50000".parse().unwrap();
+
+        let packet = status_to("10.1.1.2", &admin);
+        let (reply, events) = guard.process(
+            NOW,
+            NOW,
+            &packet,
+            src_addr,
+            &mut network_manager,
+            &mut static_config,
+        );
+        assert!(events.is_empty());
+        let reply = reply.unwrap();
+        assert_eq!(reply.sender, common::ip("10.1.1.2"));
+        assert_eq!(reply.nonce, packet.nonce);
+        let status: serde_json::Value = serde_json::from_str(&reply.result.unwrap()).unwrap();
+        assert_eq!(status["name"], "test");
+        assert_eq!(status["wg_ip"], "10.1.1.2");
+
+        // A replay is answered with the reason
+        let (reply, _) = guard.process(
+            NOW,
+            NOW,
+            &packet,
+            src_addr,
+            &mut network_manager,
+            &mut static_config,
+        );
+        assert_eq!(reply.unwrap().result, Err("rejected: replayed".to_string()));
+
+        // Others than the administrators get no answer
+        let stranger = status_to("10.1.1.2", &NodeIdentity::generate());
+        let (reply, events) = guard.process(
+            NOW,
+            NOW,
+            &stranger,
+            src_addr,
+            &mut network_manager,
+            &mut static_config,
+        );
+        assert!(reply.is_none());
+        assert!(events.is_empty());
+
+        // After a step of the wall clock, the mesh clock differs, and only the wall
+        // clock is compared with the time of issue
+        let packet = status_to("10.1.1.2", &admin);
+        let (reply, _) = guard.process(
+            NOW - 3600,
+            NOW,
+            &packet,
+            src_addr,
+            &mut network_manager,
+            &mut static_config,
+        );
+        assert!(reply.unwrap().result.is_ok());
+    }
+
+    #[test]
+    fn test_set_log_level() {
+        let admin = NodeIdentity::generate();
+        let mut static_config = get_test_config(vec![admin.public()], &["log-level"]);
+        let mut network_manager = NetworkManager::new(&static_config);
+        let previous = log::max_level();
+
+        let command = FleetCommand::SetLogLevel {
+            level: "debug".to_string(),
+        };
+        let (result, _) = execute(NOW, &command, &mut network_manager, &mut static_config);
+        assert_eq!(result, Ok("log level DEBUG".to_string()));
+        assert_eq!(log::max_level(), log::LevelFilter::Debug);
+
+        let command = FleetCommand::SetLogLevel {
+            level: "chatty".to_string(),
+        };
+        let (result, _) = execute(NOW, &command, &mut network_manager, &mut static_config);
+        assert_eq!(result, Err("unknown log level chatty".to_string()));
+        assert_eq!(log::max_level(), log::LevelFilter::Debug);
+        log::set_max_level(previous);
+    }
+}
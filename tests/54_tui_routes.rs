@@ -0,0 +1,60 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+
+    use wg_netmanager::routedb::RouteInfo;
+    use wg_netmanager::tui_display::*;
+
+    fn route(to: &str, gateway: Option<&str>, hop_cnt: usize, cost: u32) -> RouteInfo {
+        RouteInfo {
+            to: common::ip(to),
+            local_admin_port: 50000,
+            hop_cnt,
+            gateway: gateway.map(common::ip),
+            area_summary: None,
+            exported_nets: vec![],
+            cost,
+            bandwidth_mbit: None,
+        }
+    }
+
+    fn routes() -> Vec<RouteInfo> {
+        vec![
+            route("10.1.1.1", None, 0, 10),
+            route("10.1.1.3", Some("10.1.1.1"), 1, 20),
+            route("10.1.1.4", None, 0, 10),
+            route("10.1.1.5", Some("10.1.1.1"), 2, 35),
+        ]
+    }
+
+    #[test]
+    fn test_route_rows() {
+        let rows = RouteRow::of(&routes());
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0].cells(), vec!["10.1.1.1", "-", "0", "10"]);
+        assert_eq!(rows[3].cells(), vec!["10.1.1.5", "10.1.1.1", "2", "35"]);
+    }
+
+    #[test]
+    fn test_topology() {
+        assert_eq!(
+            topology(common::ip("10.1.1.2"), &routes()),
+            vec![
+                "10.1.1.2",
+                "+- 10.1.1.1",
+                "|  +- 10.1.1.3",
+                "|  `- 10.1.1.5",
+                "`- 10.1.1.4",
+            ]
+        );
+        // A gateway is shown, even if its own route is missing
+        let routes = vec![route("10.1.1.3", Some("10.1.1.1"), 1, 20)];
+        assert_eq!(
+            topology(common::ip("10.1.1.2"), &routes),
+            vec!["10.1.1.2", "`- 10.1.1.1", "   `- 10.1.1.3"]
+        );
+        assert_eq!(topology(common::ip("10.1.1.2"), &[]), vec!["10.1.1.2"]);
+    }
+}
@@ -0,0 +1,108 @@
+// Fixtures shared by the integration tests, included with `mod common;`.
+//
+// builder() is the node 10.1.1.2 on wgtest without peers. A test adapts it with the
+// setters of the builder, which override the values below.
+//
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::{IpNet, Ipv6Net};
+
+use wg_netmanager::configuration::*;
+use wg_netmanager::error::BoxResult;
+use wg_netmanager::wg_dev::{RouteAttributes, WireguardDevice};
+
+pub fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+pub fn public_key(priv_key_creation_time: u64) -> PublicKeyWithTime {
+    PublicKeyWithTime::new("public", priv_key_creation_time)
+}
+
+// Reachable via localhost with wg port 50001 and admin port 50501
+pub fn static_peer(wg_ip: IpAddr) -> PublicPeer {
+    PublicPeer::new(wg_ip, "127.0.0.1:50001", 50001, 50501)
+}
+
+// The static peer 10.1.1.1
+pub fn static_peers() -> HashMap<IpAddr, PublicPeer> {
+    let wg_ip = ip("10.1.1.1");
+    let mut peers = HashMap::new();
+    peers.insert(wg_ip, static_peer(wg_ip));
+    peers
+}
+
+pub fn builder() -> StaticConfigurationBuilder {
+    StaticConfiguration::builder()
+        .name("test")
+        .ip_list(vec![])
+        .wg_ip(ip("10.1.1.2"))
+        .wg_name("wgtest")
+        .wg_port(50002)
+        .wg_hopping(false)
+        .admin_port(50502)
+        .subnet("10.1.1.0/24".parse().unwrap())
+        .shared_key(vec![0; 32])
+        .my_private_key("private")
+        .my_public_key(public_key(1))
+        .peers(HashMap::new())
+        .use_tui(false)
+        .use_existing_interface(false)
+        .network_yaml_filename("network.yaml")
+}
+
+// The node 10.1.1.2 with the static peer 10.1.1.1
+pub fn static_config() -> StaticConfiguration {
+    builder().peers(static_peers()).build()
+}
+
+// The static peer 10.1.1.1 itself
+pub fn static_peer_builder() -> StaticConfigurationBuilder {
+    builder()
+        .wg_ip(ip("10.1.1.1"))
+        .wg_port(50001)
+        .admin_port(50501)
+        .peers(static_peers())
+}
+
+// Fails on the first use with the given error, so the daemon stops right away. Only the
+// methods without default body are implemented.
+pub struct FailingDevice(pub String);
+impl WireguardDevice for FailingDevice {
+    fn check_device(&self) -> BoxResult<bool> {
+        Err(self.0.clone().into())
+    }
+    fn create_device(&self) -> BoxResult<()> {
+        unreachable!()
+    }
+    fn take_down_device(&self) -> BoxResult<()> {
+        unreachable!()
+    }
+    fn set_ip(&mut self, _: &IpAddr, _: &IpNet, _: Option<Ipv6Net>) -> BoxResult<()> {
+        unreachable!()
+    }
+    fn add_route(&self, _: IpAddr, _: Option<IpAddr>, _: &RouteAttributes) -> BoxResult<()> {
+        unreachable!()
+    }
+    fn replace_route(&self, _: IpAddr, _: Option<IpAddr>, _: &RouteAttributes) -> BoxResult<()> {
+        unreachable!()
+    }
+    fn del_route(&self, _: IpAddr, _: Option<IpAddr>, _: &RouteAttributes) -> BoxResult<()> {
+        unreachable!()
+    }
+    fn set_conf(&self, _: &str) -> BoxResult<()> {
+        unreachable!()
+    }
+    fn sync_conf(&self, _: &str) -> BoxResult<()> {
+        unreachable!()
+    }
+    fn flush_all(&self) -> BoxResult<()> {
+        unreachable!()
+    }
+    fn retrieve_conf(&self) -> BoxResult<HashMap<String, SocketAddr>> {
+        unreachable!()
+    }
+}